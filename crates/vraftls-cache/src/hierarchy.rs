@@ -1,16 +1,46 @@
 //! Multi-level cache hierarchy
 
 use moka::future::Cache;
+use moka::Expiry;
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use vraftls_core::{FileId, FileVersion};
 
+/// Characters of a line sharing a position bucket
+pub const POSITION_BUCKET_WIDTH: u32 = 16;
+
 /// Cache key
 #[derive(Clone, Debug, Hash, Eq, PartialEq)]
 pub struct CacheKey {
     pub file_id: FileId,
     pub file_version: FileVersion,
     pub cache_type: CacheType,
+
+    /// Where in the file, for data computed at a position; `None` for data
+    /// of the whole file
+    pub bucket: Option<PositionBucket>,
+}
+
+/// Positions of a file whose data share a cache entry
+///
+/// An entry holds the data of the last position asked in its bucket;
+/// callers check it still applies to theirs.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub struct PositionBucket {
+    pub line: u32,
+    pub column: u32,
+}
+
+impl PositionBucket {
+    /// Bucket of a zero-based line and character
+    pub fn of(line: u32, character: u32) -> Self {
+        Self {
+            line,
+            column: character / POSITION_BUCKET_WIDTH,
+        }
+    }
 }
 
 /// Type of cached data
@@ -21,6 +51,10 @@ pub enum CacheType {
     Symbols,
     Diagnostics,
     Completions,
+    Hover,
+    InlayHints,
+    FoldingRanges,
+    SelectionRanges,
 }
 
 /// Cached entry
@@ -31,21 +65,105 @@ pub enum CacheEntry {
     Symbols(Vec<u8>),
     Diagnostics(Vec<u8>),
     Completions(Vec<u8>),
+    Hover(Vec<u8>),
+    InlayHints(Vec<u8>),
+    FoldingRanges(Vec<u8>),
+    SelectionRanges(Vec<u8>),
+}
+
+impl CacheEntry {
+    /// Entry of the given type holding serialized data
+    pub fn new(cache_type: &CacheType, data: Vec<u8>) -> Self {
+        match cache_type {
+            CacheType::Ast => Self::Ast(data),
+            CacheType::Types => Self::Types(data),
+            CacheType::Symbols => Self::Symbols(data),
+            CacheType::Diagnostics => Self::Diagnostics(data),
+            CacheType::Completions => Self::Completions(data),
+            CacheType::Hover => Self::Hover(data),
+            CacheType::InlayHints => Self::InlayHints(data),
+            CacheType::FoldingRanges => Self::FoldingRanges(data),
+            CacheType::SelectionRanges => Self::SelectionRanges(data),
+        }
+    }
+
+    /// Serialized data of the entry
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Self::Ast(data)
+            | Self::Types(data)
+            | Self::Symbols(data)
+            | Self::Diagnostics(data)
+            | Self::Completions(data)
+            | Self::Hover(data)
+            | Self::InlayHints(data)
+            | Self::FoldingRanges(data)
+            | Self::SelectionRanges(data) => data,
+        }
+    }
+}
+
+/// Expires entries after the TTL set when they were written
+struct TtlExpiry {
+    /// TTL in milliseconds; 0 keeps entries until evicted
+    ttl_millis: Arc<AtomicU64>,
+}
+
+impl TtlExpiry {
+    fn ttl(&self) -> Option<Duration> {
+        match self.ttl_millis.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        }
+    }
+}
+
+impl Expiry<CacheKey, CacheEntry> for TtlExpiry {
+    fn expire_after_create(&self, _key: &CacheKey, _value: &CacheEntry, _created_at: Instant) -> Option<Duration> {
+        self.ttl()
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &CacheKey,
+        _value: &CacheEntry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        self.ttl()
+    }
 }
 
 /// Multi-level cache
 pub struct CacheHierarchy {
     /// L1: In-memory hot cache
     l1: Cache<CacheKey, CacheEntry>,
+
+    /// TTL of entries written from now on, in milliseconds
+    ttl_millis: Arc<AtomicU64>,
 }
 
 impl CacheHierarchy {
     pub fn new(max_entries: u64) -> Self {
+        let ttl_millis = Arc::new(AtomicU64::new(0));
         Self {
-            l1: Cache::new(max_entries),
+            l1: Cache::builder()
+                .max_capacity(max_entries)
+                .expire_after(TtlExpiry {
+                    ttl_millis: ttl_millis.clone(),
+                })
+                .build(),
+            ttl_millis,
         }
     }
 
+    /// Expire entries written from now on after `ttl`; `None` keeps them
+    /// until evicted
+    pub fn set_ttl(&self, ttl: Option<Duration>) {
+        let millis = ttl.map_or(0, |ttl| (ttl.as_millis() as u64).max(1));
+        self.ttl_millis.store(millis, Ordering::Relaxed);
+    }
+
     pub async fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
         self.l1.get(key).await
     }
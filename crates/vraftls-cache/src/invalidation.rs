@@ -1,7 +1,6 @@
 //! Cache invalidation
 
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
 use tokio::sync::RwLock;
 use vraftls_core::FileId;
 
@@ -0,0 +1,246 @@
+//! Bootstrap of a new cluster
+//!
+//! Every node of a new cluster runs the bootstrap with the same expected node
+//! count. Once discovery reports that many nodes and all of them answer
+//! heartbeats, the node with the lowest ID becomes the coordinator: it
+//! initializes the metadata group with every node as a voter, then places and
+//! initializes the first data group, with election priorities following the
+//! placement policy's leader preference. The other nodes only wait and learn
+//! the result through the metadata group. Initializing a group twice is a
+//! no-op, so a restarted coordinator finds the cluster formed and stops there.
+
+use crate::discovery::ServiceDiscovery;
+use crate::membership::{ClusterMembership, NodeStatus};
+use crate::metadata::ClusterMetadata;
+use crate::metadata_state_machine::MetadataProposer;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use vraftls_core::{ClusterConfig, NodeId, RaftGroupId, Result, VRaftError};
+use vraftls_raft::VRaftNode;
+
+/// Initializes the Raft instances of groups hosted on this node
+pub trait GroupInitializer: Send + Sync {
+    /// Initialize a group with its first voters; `Ok(false)` if it already was
+    fn initialize_group(
+        &self,
+        group_id: RaftGroupId,
+        voters: BTreeMap<NodeId, VRaftNode>,
+    ) -> impl Future<Output = Result<bool>> + Send;
+}
+
+/// Bootstrap settings
+#[derive(Clone, Debug)]
+pub struct BootstrapConfig {
+    /// Nodes the new cluster starts with
+    pub expected_nodes: usize,
+
+    /// Replicas of the first data group
+    pub group_replicas: usize,
+
+    /// Interval between discovery polls
+    pub poll_interval: Duration,
+
+    /// How long to wait for the expected nodes and the metadata leader
+    pub timeout: Duration,
+}
+
+impl From<&ClusterConfig> for BootstrapConfig {
+    fn from(config: &ClusterConfig) -> Self {
+        Self {
+            expected_nodes: config.expected_nodes.unwrap_or(1),
+            group_replicas: config.group_replicas,
+            poll_interval: Duration::from_secs(1),
+            timeout: config.bootstrap_timeout,
+        }
+    }
+}
+
+/// Result of a bootstrap run
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootstrapOutcome {
+    /// Node that formed the cluster
+    pub coordinator: NodeId,
+
+    /// Nodes the cluster was formed with
+    pub nodes: Vec<NodeId>,
+
+    /// Data group created by this run; `None` on other nodes, or if the
+    /// cluster already had one
+    pub data_group: Option<RaftGroupId>,
+}
+
+/// Forms a new cluster without manual initialization
+pub struct Bootstrapper<D, I> {
+    config: BootstrapConfig,
+    discovery: D,
+    initializer: I,
+    membership: Arc<ClusterMembership>,
+    metadata: Arc<ClusterMetadata>,
+    proposer: MetadataProposer,
+}
+
+impl<D: ServiceDiscovery, I: GroupInitializer> Bootstrapper<D, I> {
+    pub fn new(
+        config: BootstrapConfig,
+        discovery: D,
+        initializer: I,
+        membership: Arc<ClusterMembership>,
+        metadata: Arc<ClusterMetadata>,
+        proposer: MetadataProposer,
+    ) -> Self {
+        Self {
+            config,
+            discovery,
+            initializer,
+            membership,
+            metadata,
+            proposer,
+        }
+    }
+
+    /// Wait for the expected nodes and, on the coordinator, form the cluster
+    pub async fn run(&self) -> Result<BootstrapOutcome> {
+        let deadline = Instant::now() + self.config.timeout;
+        let peers = self.wait_for_peers(deadline).await?;
+        let coordinator = elect_coordinator(&peers)
+            .ok_or_else(|| VRaftError::InvalidConfig("no nodes to bootstrap".to_string()))?;
+        let mut outcome = BootstrapOutcome {
+            coordinator,
+            nodes: peers.iter().map(|(id, _)| *id).collect(),
+            data_group: None,
+        };
+
+        if coordinator != self.membership.local_node_id() {
+            tracing::info!(%coordinator, "waiting for the coordinator to form the cluster");
+            return Ok(outcome);
+        }
+
+        let voters = peers
+            .into_iter()
+            .map(|(id, addr)| (id, VRaftNode::new(addr.to_string())))
+            .collect();
+        if self.initializer.initialize_group(RaftGroupId::METADATA, voters).await? {
+            tracing::info!(nodes = ?outcome.nodes, "initialized metadata group");
+        }
+
+        // Wait for the metadata leader; committing the allocation also
+        // applies whatever a previous run left in the log
+        let group_id = self.with_leader(deadline, || self.proposer.allocate_group_id()).await?;
+        if !self.metadata.groups().await.is_empty() {
+            tracing::info!("cluster already has data groups, bootstrap done");
+            return Ok(outcome);
+        }
+        outcome.data_group = Some(self.create_group(group_id, deadline).await?);
+        Ok(outcome)
+    }
+
+    /// Poll discovery until the expected nodes are known and healthy
+    async fn wait_for_peers(&self, deadline: Instant) -> Result<Vec<(NodeId, SocketAddr)>> {
+        let local = self.membership.local_node_id();
+        let mut ticker = tokio::time::interval(self.config.poll_interval);
+        loop {
+            ticker.tick().await;
+
+            let mut peers = match self.discovery.discover().await {
+                Ok(peers) => peers,
+                Err(e) => {
+                    tracing::warn!(error = %e, "discovery failed during bootstrap");
+                    Vec::new()
+                }
+            };
+            self.membership.sync_discovered(&peers);
+            if let Some(node) = self.membership.get_node(local) {
+                peers.push((local, node.addr));
+            }
+            peers.sort_by_key(|(id, _)| *id);
+            peers.dedup_by_key(|(id, _)| *id);
+
+            let ready = peers
+                .iter()
+                .filter(|(id, _)| {
+                    *id == local || self.membership.get_node(*id).is_some_and(|n| n.status == NodeStatus::Healthy)
+                })
+                .count();
+            if ready >= self.config.expected_nodes {
+                return Ok(peers);
+            }
+            if Instant::now() >= deadline {
+                tracing::error!(ready, expected = self.config.expected_nodes, "bootstrap timed out");
+                return Err(VRaftError::Timeout);
+            }
+            tracing::debug!(ready, expected = self.config.expected_nodes, "waiting for nodes");
+        }
+    }
+
+    /// Place the first data group, initialize it and record its nodes
+    async fn create_group(&self, group_id: RaftGroupId, deadline: Instant) -> Result<RaftGroupId> {
+        let replicas = self.config.group_replicas.min(self.config.expected_nodes).max(1);
+        let members = self.membership.all_nodes();
+        let nodes = self.metadata.place_group(&members, replicas)?;
+        if !nodes.contains(&self.membership.local_node_id()) {
+            return Err(VRaftError::Internal(format!(
+                "coordinator not placed on group {} ({:?})",
+                group_id, nodes
+            )));
+        }
+
+        // The most preferred leader gets the highest election priority
+        let preference = self.metadata.leader_preference(&members, &nodes);
+        let voters = preference
+            .iter()
+            .enumerate()
+            .filter_map(|(rank, id)| {
+                let node = members.iter().find(|n| n.id == *id)?;
+                let priority = u8::try_from(preference.len() - rank).unwrap_or(u8::MAX);
+                Some((*id, VRaftNode::new(node.addr.to_string()).with_priority(priority)))
+            })
+            .collect();
+        self.initializer.initialize_group(group_id, voters).await?;
+        self.with_leader(deadline, || self.proposer.assign_group(group_id, nodes.clone()))
+            .await?;
+
+        tracing::info!(%group_id, ?nodes, "created first data group");
+        Ok(group_id)
+    }
+
+    /// Retry a metadata proposal while the metadata group has no leader
+    async fn with_leader<T, F, Fut>(&self, deadline: Instant, propose: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        loop {
+            match propose().await {
+                Err(VRaftError::NotLeader { leader: None }) if Instant::now() < deadline => {
+                    tokio::time::sleep(self.config.poll_interval).await;
+                }
+                Err(VRaftError::NotLeader { leader: None }) => return Err(VRaftError::Timeout),
+                result => return result,
+            }
+        }
+    }
+}
+
+/// The node that forms the cluster: the lowest ID, so all nodes agree
+pub fn elect_coordinator(peers: &[(NodeId, SocketAddr)]) -> Option<NodeId> {
+    peers.iter().map(|(id, _)| *id).min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elect_coordinator() {
+        let peer = |id: u64| (NodeId::new(id), format!("127.0.0.1:{}", 8000 + id).parse().unwrap());
+
+        assert_eq!(elect_coordinator(&[peer(3), peer(1), peer(2)]), Some(NodeId::new(1)));
+        assert_eq!(elect_coordinator(&[peer(7)]), Some(NodeId::new(7)));
+        assert_eq!(elect_coordinator(&[]), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! Node decommissioning
+//!
+//! Retiring a node takes three steps: it is marked `Leaving`, so it receives
+//! no new replicas; its leaderships and replicas are moved to other nodes;
+//! and only once it hosts nothing can it be removed from the cluster.
+
+use crate::membership::{ClusterMembership, ClusterNode, NodeStatus};
+use crate::rebalance::{ReplicaMove, ReplicaMover};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{NodeId, RaftGroupId, Result, VRaftError};
+
+/// Hands group leadership to other voters
+pub trait LeadershipTransfer: Send + Sync {
+    /// Give up leadership of a group if this node holds it
+    ///
+    /// Returns whether this node was the leader.
+    fn transfer_leadership(&self, group_id: RaftGroupId) -> impl std::future::Future<Output = Result<bool>> + Send;
+}
+
+/// Progress of a node's drain
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DrainStatus {
+    pub node_id: NodeId,
+    pub status: NodeStatus,
+
+    /// Groups the node still hosts a replica of
+    pub remaining: Vec<RaftGroupId>,
+}
+
+impl DrainStatus {
+    /// Whether the node can be removed
+    pub fn is_drained(&self) -> bool {
+        self.status == NodeStatus::Leaving && self.remaining.is_empty()
+    }
+}
+
+/// Drains nodes before they leave the cluster
+pub struct Decommissioner<M> {
+    membership: Arc<ClusterMembership>,
+    mover: M,
+}
+
+impl<M: ReplicaMover + LeadershipTransfer> Decommissioner<M> {
+    pub fn new(membership: Arc<ClusterMembership>, mover: M) -> Self {
+        Self { membership, mover }
+    }
+
+    /// Drain progress of a node
+    pub fn status(&self, node_id: NodeId) -> Result<DrainStatus> {
+        let node = self
+            .membership
+            .get_node(node_id)
+            .ok_or(VRaftError::NodeUnreachable(node_id))?;
+        Ok(DrainStatus {
+            node_id,
+            status: node.status,
+            remaining: node.raft_groups,
+        })
+    }
+
+    /// Mark a node as leaving
+    pub fn start(&self, node_id: NodeId) -> Result<DrainStatus> {
+        self.status(node_id)?;
+        self.membership.mark_leaving(node_id);
+        tracing::info!(%node_id, "decommissioning node");
+        self.status(node_id)
+    }
+
+    /// Move leaderships and replicas off a leaving node
+    ///
+    /// Only groups led by this node can be moved from here; the others are
+    /// moved by their own leaders and stay in `remaining` until then.
+    pub async fn drain(&self, node_id: NodeId) -> Result<DrainStatus> {
+        let status = self.status(node_id)?;
+        if status.status != NodeStatus::Leaving {
+            return Err(VRaftError::InvalidConfig(format!("node {} is not leaving", node_id)));
+        }
+
+        // Hand off leaderships first so the groups keep serving while replicas move
+        if node_id == self.membership.local_node_id() {
+            for group_id in &status.remaining {
+                self.mover.transfer_leadership(*group_id).await?;
+            }
+        }
+
+        for group_id in status.remaining {
+            let Some(to) = drain_target(&self.membership.all_nodes(), group_id) else {
+                tracing::warn!(%node_id, %group_id, "no node can take the replica");
+                continue;
+            };
+            let replica_move = ReplicaMove {
+                group_id,
+                from: node_id,
+                to,
+            };
+            match self.mover.move_replica(&replica_move).await {
+                Ok(()) => {
+                    self.membership.move_replica(group_id, node_id, to);
+                    tracing::info!(%node_id, %group_id, %to, "moved replica off leaving node");
+                }
+                Err(VRaftError::NotLeader { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.status(node_id)
+    }
+
+    /// Remove a drained node from the cluster
+    pub fn remove(&self, node_id: NodeId) -> Result<()> {
+        let status = self.status(node_id)?;
+        if !status.is_drained() {
+            return Err(VRaftError::NodeNotDrained {
+                node: node_id,
+                replicas: status.remaining.len(),
+            });
+        }
+
+        self.membership.remove_node(node_id);
+        tracing::info!(%node_id, "removed decommissioned node");
+        Ok(())
+    }
+
+    /// Nodes currently leaving the cluster
+    pub fn leaving_nodes(&self) -> Vec<NodeId> {
+        self.membership
+            .all_nodes()
+            .into_iter()
+            .filter(|n| n.status == NodeStatus::Leaving)
+            .map(|n| n.id)
+            .collect()
+    }
+}
+
+/// Healthy node with the fewest replicas that does not host the group yet
+pub fn drain_target(nodes: &[ClusterNode], group_id: RaftGroupId) -> Option<NodeId> {
+    nodes
+        .iter()
+        .filter(|n| n.is_placeable() && !n.raft_groups.contains(&group_id))
+        .min_by_key(|n| (n.raft_groups.len(), n.id))
+        .map(|n| n.id)
+}
+
+/// Keep draining leaving nodes until the task is aborted
+pub fn spawn_drainer<M: ReplicaMover + LeadershipTransfer + 'static>(
+    decommissioner: Arc<Decommissioner<M>>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            for node_id in decommissioner.leaving_nodes() {
+                if let Err(e) = decommissioner.drain(node_id).await {
+                    tracing::warn!(%node_id, error = %e, "drain failed");
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: u64, status: NodeStatus, groups: &[u64]) -> ClusterNode {
+        ClusterNode {
+            status,
+            raft_groups: groups.iter().copied().map(RaftGroupId::new).collect(),
+            ..ClusterNode::new(NodeId::new(id), format!("127.0.0.1:{}", 8000 + id).parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_drain_target() {
+        let nodes = vec![
+            node(1, NodeStatus::Leaving, &[1, 2]),
+            node(2, NodeStatus::Healthy, &[1]),
+            node(3, NodeStatus::Healthy, &[2, 3]),
+            node(4, NodeStatus::Down, &[]),
+        ];
+        assert_eq!(drain_target(&nodes, RaftGroupId::new(1)), Some(NodeId::new(3)));
+        assert_eq!(drain_target(&nodes, RaftGroupId::new(2)), Some(NodeId::new(2)));
+        assert_eq!(drain_target(&nodes, RaftGroupId::new(4)), Some(NodeId::new(2)));
+    }
+}
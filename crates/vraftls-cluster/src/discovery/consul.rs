@@ -0,0 +1,281 @@
+//! Consul discovery backend
+//!
+//! Each node registers itself as an instance of a Consul service with a TTL
+//! health check, which it keeps passing while it runs. Peers are read from
+//! the catalog; the node ID travels in the instance's service metadata.
+
+use super::ServiceDiscovery;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{NodeId, Result, VRaftError};
+
+/// Service metadata key holding the node ID
+const NODE_ID_META: &str = "vraftls_node_id";
+
+/// Settings of the Consul discovery backend
+#[derive(Clone, Debug)]
+pub struct ConsulConfig {
+    /// Consul agent URL
+    pub agent: String,
+
+    /// Service name shared by every node of the cluster
+    pub service: String,
+
+    /// ACL token
+    pub token: Option<String>,
+
+    /// TTL of the health check; the node reports at a third of it
+    pub check_ttl: Duration,
+
+    /// Consul removes instances whose check stays critical this long
+    pub deregister_after: Duration,
+
+    /// Only return instances whose health checks pass
+    pub passing_only: bool,
+}
+
+impl ConsulConfig {
+    pub fn new(agent: impl Into<String>, service: impl Into<String>) -> Self {
+        Self {
+            agent: agent.into(),
+            service: service.into(),
+            token: None,
+            check_ttl: Duration::from_secs(10),
+            deregister_after: Duration::from_secs(60),
+            passing_only: true,
+        }
+    }
+}
+
+/// Registers with and discovers peers through Consul
+pub struct ConsulDiscovery {
+    config: ConsulConfig,
+    client: reqwest::Client,
+
+    /// Task keeping this node's health check passing
+    keepalive: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl ConsulDiscovery {
+    pub fn new(config: ConsulConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("Failed to create HTTP client");
+
+        Self {
+            config,
+            client,
+            keepalive: Mutex::new(None),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/v1/{}", self.config.agent.trim_end_matches('/'), path)
+    }
+
+    fn service_id(&self, node_id: NodeId) -> String {
+        format!("{}-{}", self.config.service, node_id)
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, self.url(path));
+        match &self.config.token {
+            Some(token) => request.header("X-Consul-Token", token),
+            None => request,
+        }
+    }
+
+    async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let response = request
+            .send()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(VRaftError::ConnectionFailed(format!(
+                "HTTP {} from consul",
+                response.status()
+            )));
+        }
+        Ok(response)
+    }
+}
+
+impl ServiceDiscovery for ConsulDiscovery {
+    async fn discover(&self) -> Result<Vec<(NodeId, SocketAddr)>> {
+        let path = if self.config.passing_only {
+            format!("health/service/{}?passing=true", self.config.service)
+        } else {
+            format!("health/service/{}", self.config.service)
+        };
+        let entries: Vec<HealthEntry> = Self::send(self.request(reqwest::Method::GET, &path))
+            .await?
+            .json()
+            .await
+            .map_err(|e| VRaftError::Serialization(e.to_string()))?;
+
+        Ok(nodes_from_entries(entries))
+    }
+
+    async fn register(&self, node_id: NodeId, addr: SocketAddr) -> Result<()> {
+        let service_id = self.service_id(node_id);
+        let registration = Registration {
+            id: service_id.clone(),
+            name: self.config.service.clone(),
+            address: addr.ip().to_string(),
+            port: addr.port(),
+            meta: HashMap::from([(NODE_ID_META.to_string(), node_id.to_string())]),
+            check: Check {
+                check_id: format!("service:{}", service_id),
+                ttl: format!("{}s", self.config.check_ttl.as_secs().max(1)),
+                deregister_critical_service_after: format!("{}s", self.config.deregister_after.as_secs().max(1)),
+            },
+        };
+        Self::send(self.request(reqwest::Method::PUT, "agent/service/register").json(&registration)).await?;
+
+        // Report healthy at a third of the TTL, so one lost update is tolerated
+        let pass = self.request(reqwest::Method::PUT, &format!("agent/check/pass/service:{}", service_id));
+        let interval = self.config.check_ttl / 3;
+        let keepalive = tokio::spawn(async move {
+            loop {
+                if let Some(request) = pass.try_clone() {
+                    if let Err(e) = Self::send(request).await {
+                        tracing::warn!(error = %e, "failed to pass consul health check");
+                    }
+                }
+                tokio::time::sleep(interval).await;
+            }
+        });
+        if let Some(previous) = self.keepalive.lock().unwrap().replace(keepalive) {
+            previous.abort();
+        }
+
+        tracing::info!(service_id, %addr, "registered with consul");
+        Ok(())
+    }
+
+    async fn deregister(&self, node_id: NodeId) -> Result<()> {
+        if let Some(keepalive) = self.keepalive.lock().unwrap().take() {
+            keepalive.abort();
+        }
+
+        let path = format!("agent/service/deregister/{}", self.service_id(node_id));
+        Self::send(self.request(reqwest::Method::PUT, &path)).await?;
+        Ok(())
+    }
+}
+
+impl Drop for ConsulDiscovery {
+    fn drop(&mut self) {
+        if let Some(keepalive) = self.keepalive.lock().unwrap().take() {
+            keepalive.abort();
+        }
+    }
+}
+
+/// Instances carrying a node ID, with the service address or else the agent's
+fn nodes_from_entries(entries: Vec<HealthEntry>) -> Vec<(NodeId, SocketAddr)> {
+    let mut nodes: Vec<_> = entries
+        .into_iter()
+        .filter_map(|entry| {
+            let node_id = entry.service.meta.as_ref()?.get(NODE_ID_META)?.parse().ok()?;
+            let address = if entry.service.address.is_empty() {
+                entry.node.address
+            } else {
+                entry.service.address
+            };
+            let ip: IpAddr = address.parse().ok()?;
+            Some((NodeId::new(node_id), SocketAddr::new(ip, entry.service.port)))
+        })
+        .collect();
+    nodes.sort();
+    nodes.dedup();
+    nodes
+}
+
+// Subset of the Consul agent API
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Registration {
+    #[serde(rename = "ID")]
+    id: String,
+    name: String,
+    address: String,
+    port: u16,
+    meta: HashMap<String, String>,
+    check: Check,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Check {
+    #[serde(rename = "CheckID")]
+    check_id: String,
+    #[serde(rename = "TTL")]
+    ttl: String,
+    deregister_critical_service_after: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    node: CatalogNode,
+    service: CatalogService,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CatalogNode {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CatalogService {
+    #[serde(default)]
+    address: String,
+    port: u16,
+    /// `null` for instances registered without metadata
+    #[serde(default)]
+    meta: Option<HashMap<String, String>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nodes_from_entries() {
+        let entries: Vec<HealthEntry> = serde_json::from_str(
+            r#"[
+                {"Node":{"Node":"agent-b","Address":"10.0.0.2"},
+                 "Service":{"ID":"vraftls-2","Service":"vraftls","Address":"","Port":8082,"Meta":{"vraftls_node_id":"2"}},
+                 "Checks":[{"Status":"passing"}]},
+                {"Node":{"Node":"agent-a","Address":"10.0.0.1"},
+                 "Service":{"ID":"vraftls-1","Service":"vraftls","Address":"192.168.0.1","Port":8081,"Meta":{"vraftls_node_id":"1"}},
+                 "Checks":[{"Status":"passing"}]},
+                {"Node":{"Node":"agent-c","Address":"10.0.0.3"},
+                 "Service":{"ID":"other","Service":"vraftls","Address":"10.0.0.3","Port":8083,"Meta":null},
+                 "Checks":[]},
+                {"Node":{"Node":"agent-d","Address":"10.0.0.4"},
+                 "Service":{"ID":"vraftls-4","Service":"vraftls","Address":"db.internal","Port":8084,"Meta":{"vraftls_node_id":"4"}},
+                 "Checks":[]}
+            ]"#,
+        )
+        .unwrap();
+
+        // Instances without a node ID or with a hostname address are skipped
+        assert_eq!(
+            nodes_from_entries(entries),
+            vec![
+                (NodeId::new(1), "192.168.0.1:8081".parse().unwrap()),
+                (NodeId::new(2), "10.0.0.2:8082".parse().unwrap()),
+            ]
+        );
+    }
+}
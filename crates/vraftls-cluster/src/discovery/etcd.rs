@@ -0,0 +1,380 @@
+//! etcd discovery backend
+//!
+//! Each node stores its address under `<prefix><node_id>`, attached to a
+//! lease it keeps alive while running; a crashed node's key disappears when
+//! the lease expires. Peers watch the prefix and feed changes into
+//! `ClusterMembership`. The backend talks to etcd's v3 JSON gateway.
+
+use super::ServiceDiscovery;
+use crate::membership::ClusterMembership;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{NodeId, Result, VRaftError};
+
+/// Settings of the etcd discovery backend
+#[derive(Clone, Debug)]
+pub struct EtcdConfig {
+    /// etcd client URL
+    pub endpoint: String,
+
+    /// Key prefix shared by every node of the cluster
+    pub prefix: String,
+
+    /// Auth token (from `/v3/auth/authenticate`)
+    pub token: Option<String>,
+
+    /// TTL of the registration lease; it is renewed at a third of it
+    pub lease_ttl: Duration,
+
+    /// Delay before re-establishing a dropped watch
+    pub retry_backoff: Duration,
+}
+
+impl EtcdConfig {
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            prefix: "/vraftls/nodes/".to_string(),
+            token: None,
+            lease_ttl: Duration::from_secs(10),
+            retry_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Registration of this node
+struct Registration {
+    lease_id: String,
+    keepalive: JoinHandle<()>,
+}
+
+/// Registers with and discovers peers through etcd
+pub struct EtcdDiscovery {
+    config: EtcdConfig,
+    client: reqwest::Client,
+    registration: Mutex<Option<Registration>>,
+}
+
+impl EtcdDiscovery {
+    pub fn new(config: EtcdConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            registration: Mutex::new(None),
+        }
+    }
+
+    fn request(&self, path: &str, body: serde_json::Value) -> reqwest::RequestBuilder {
+        let request = self
+            .client
+            .post(format!("{}/v3/{}", self.config.endpoint.trim_end_matches('/'), path))
+            .json(&body);
+        match &self.config.token {
+            Some(token) => request.header("Authorization", token),
+            None => request,
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, path: &str, body: serde_json::Value) -> Result<T> {
+        let response = self
+            .request(path, body)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(VRaftError::ConnectionFailed(format!(
+                "HTTP {} from etcd {}",
+                response.status(),
+                path
+            )));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| VRaftError::Serialization(e.to_string()))
+    }
+
+    fn key(&self, node_id: NodeId) -> String {
+        format!("{}{}", self.config.prefix, node_id)
+    }
+
+    /// Grant a lease and store this node's address under it
+    async fn put_with_lease(&self, node_id: NodeId, addr: SocketAddr) -> Result<String> {
+        let lease: LeaseGrantResponse = self
+            .call("lease/grant", json!({ "TTL": self.config.lease_ttl.as_secs().max(1) }))
+            .await?;
+        let _: serde_json::Value = self
+            .call(
+                "kv/put",
+                json!({
+                    "key": base64::encode(self.key(node_id).as_bytes()),
+                    "value": base64::encode(addr.to_string().as_bytes()),
+                    "lease": lease.id,
+                }),
+            )
+            .await?;
+        Ok(lease.id)
+    }
+
+    /// Current peers and the store revision they were read at
+    async fn range(&self) -> Result<(Vec<(NodeId, SocketAddr)>, u64)> {
+        let response: RangeResponse = self
+            .call(
+                "kv/range",
+                json!({
+                    "key": base64::encode(self.config.prefix.as_bytes()),
+                    "range_end": base64::encode(&prefix_end(self.config.prefix.as_bytes())),
+                }),
+            )
+            .await?;
+
+        let mut nodes: Vec<_> = response
+            .kvs
+            .iter()
+            .filter_map(|kv| {
+                let key = String::from_utf8(base64::decode(&kv.key)?).ok()?;
+                let node_id = key.strip_prefix(&self.config.prefix)?.parse().ok()?;
+                let addr = String::from_utf8(base64::decode(&kv.value)?).ok()?.parse().ok()?;
+                Some((NodeId::new(node_id), addr))
+            })
+            .collect();
+        nodes.sort();
+        Ok((nodes, response.header.revision.parse().unwrap_or(0)))
+    }
+
+    /// Keep `membership` in sync with the registered nodes
+    ///
+    /// The prefix is listed, then watched from the listed revision; every
+    /// change re-reads the prefix. Errors are retried after a backoff.
+    pub fn spawn_watch(self: Arc<Self>, membership: Arc<ClusterMembership>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.watch(&membership).await {
+                    tracing::warn!(error = %e, prefix = %self.config.prefix, "etcd watch failed");
+                }
+                tokio::time::sleep(self.config.retry_backoff).await;
+            }
+        })
+    }
+
+    async fn watch(&self, membership: &ClusterMembership) -> Result<()> {
+        let (nodes, revision) = self.range().await?;
+        membership.sync_discovered(&nodes);
+
+        let mut response = self
+            .request(
+                "watch",
+                json!({
+                    "create_request": {
+                        "key": base64::encode(self.config.prefix.as_bytes()),
+                        "range_end": base64::encode(&prefix_end(self.config.prefix.as_bytes())),
+                        "start_revision": revision + 1,
+                    }
+                }),
+            )
+            .send()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?;
+
+        // The gateway streams one JSON object per line
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?
+        {
+            buffer.extend_from_slice(&chunk);
+            let mut changed = false;
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if let Ok(message) = serde_json::from_slice::<WatchMessage>(&line) {
+                    changed |= message.result.is_some_and(|r| !r.events.is_empty());
+                }
+            }
+            if changed {
+                let (nodes, _) = self.range().await?;
+                membership.sync_discovered(&nodes);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServiceDiscovery for EtcdDiscovery {
+    async fn discover(&self) -> Result<Vec<(NodeId, SocketAddr)>> {
+        Ok(self.range().await?.0)
+    }
+
+    async fn register(&self, node_id: NodeId, addr: SocketAddr) -> Result<()> {
+        let lease_id = self.put_with_lease(node_id, addr).await?;
+
+        let discovery = Self::new(self.config.clone());
+        let interval = self.config.lease_ttl / 3;
+        let mut current = lease_id.clone();
+        let keepalive = tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let renewed = discovery
+                    .call::<KeepAliveMessage>("lease/keepalive", json!({ "ID": current }))
+                    .await
+                    .map(|m| m.result.and_then(|r| r.ttl).is_some_and(|ttl| ttl != "0"));
+
+                // An expired lease took the key with it; register again
+                match renewed {
+                    Ok(true) => {}
+                    Ok(false) => match discovery.put_with_lease(node_id, addr).await {
+                        Ok(lease_id) => current = lease_id,
+                        Err(e) => tracing::warn!(error = %e, "failed to re-register with etcd"),
+                    },
+                    Err(e) => tracing::warn!(error = %e, "failed to renew etcd lease"),
+                }
+            }
+        });
+
+        if let Some(previous) = self
+            .registration
+            .lock()
+            .unwrap()
+            .replace(Registration { lease_id, keepalive })
+        {
+            previous.keepalive.abort();
+        }
+        tracing::info!(%node_id, %addr, "registered with etcd");
+        Ok(())
+    }
+
+    async fn deregister(&self, node_id: NodeId) -> Result<()> {
+        let registration = self.registration.lock().unwrap().take();
+        if let Some(registration) = registration {
+            registration.keepalive.abort();
+            let _: serde_json::Value = self.call("lease/revoke", json!({ "ID": registration.lease_id })).await?;
+        }
+
+        let _: serde_json::Value = self
+            .call("kv/deleterange", json!({ "key": base64::encode(self.key(node_id).as_bytes()) }))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return end;
+        }
+    }
+    // Every byte was 0xff: range to the end of the keyspace
+    vec![0]
+}
+
+// Subset of the etcd v3 JSON gateway
+
+#[derive(Deserialize)]
+struct LeaseGrantResponse {
+    #[serde(rename = "ID")]
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct RangeResponse {
+    header: ResponseHeader,
+    #[serde(default)]
+    kvs: Vec<KeyValue>,
+}
+
+#[derive(Deserialize)]
+struct ResponseHeader {
+    #[serde(default)]
+    revision: String,
+}
+
+#[derive(Deserialize)]
+struct KeyValue {
+    key: String,
+    #[serde(default)]
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct WatchMessage {
+    result: Option<WatchResult>,
+}
+
+#[derive(Deserialize)]
+struct WatchResult {
+    #[serde(default)]
+    events: Vec<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct KeepAliveMessage {
+    result: Option<KeepAliveResult>,
+}
+
+#[derive(Deserialize)]
+struct KeepAliveResult {
+    #[serde(rename = "TTL")]
+    ttl: Option<String>,
+}
+
+// Base64 helper (standard alphabet, as used by the JSON gateway)
+mod base64 {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    pub fn encode(bytes: &[u8]) -> String {
+        let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+
+    pub fn decode(text: &str) -> Option<Vec<u8>> {
+        let text = text.trim_end_matches('=');
+        let mut out = Vec::with_capacity(text.len() * 3 / 4);
+        let (mut n, mut bits) = (0u32, 0);
+        for c in text.bytes() {
+            n = n << 6 | ALPHABET.iter().position(|a| *a == c)? as u32;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                out.push((n >> bits) as u8);
+            }
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        for text in ["", "f", "fo", "foo", "/vraftls/nodes/12"] {
+            let encoded = base64::encode(text.as_bytes());
+            assert_eq!(base64::decode(&encoded).unwrap(), text.as_bytes());
+        }
+        assert_eq!(base64::encode(b"foob"), "Zm9vYg==");
+
+        assert_eq!(prefix_end(b"/nodes/"), b"/nodes0");
+        assert_eq!(prefix_end(b"a\xff"), b"b");
+    }
+}
@@ -0,0 +1,376 @@
+//! Kubernetes discovery backend
+//!
+//! Peers are the ready endpoints of a Service, read from its EndpointSlices.
+//! Each endpoint's pod carries its node ID in an annotation, so pods can be
+//! rescheduled onto new IPs without renumbering the cluster.
+
+use super::ServiceDiscovery;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use vraftls_core::{NodeId, Result, VRaftError};
+
+/// Directory holding the pod's service account credentials
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Label linking an EndpointSlice to its Service
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+/// Settings of the Kubernetes discovery backend
+#[derive(Clone, Debug)]
+pub struct KubernetesConfig {
+    /// API server URL
+    pub api_server: String,
+
+    /// Namespace of the Service
+    pub namespace: String,
+
+    /// Service whose endpoints are the cluster nodes
+    pub service: String,
+
+    /// Bearer token for the API server
+    pub token: Option<String>,
+
+    /// PEM-encoded CA certificate of the API server
+    pub ca_cert: Option<Vec<u8>>,
+
+    /// Pod annotation holding the node ID
+    pub node_id_annotation: String,
+
+    /// Name of the Service port used for cluster traffic (first port if unset)
+    pub port_name: Option<String>,
+
+    /// Delay before re-establishing a dropped watch
+    pub retry_backoff: Duration,
+}
+
+impl KubernetesConfig {
+    pub fn new(api_server: impl Into<String>, namespace: impl Into<String>, service: impl Into<String>) -> Self {
+        Self {
+            api_server: api_server.into(),
+            namespace: namespace.into(),
+            service: service.into(),
+            token: None,
+            ca_cert: None,
+            node_id_annotation: "vraftls.io/node-id".to_string(),
+            port_name: None,
+            retry_backoff: Duration::from_secs(5),
+        }
+    }
+
+    /// Configuration for a pod, using its service account
+    pub fn in_cluster(service: impl Into<String>) -> Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| VRaftError::InvalidConfig("not running in Kubernetes".to_string()))?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+        let dir = Path::new(SERVICE_ACCOUNT_DIR);
+        let namespace = std::fs::read_to_string(dir.join("namespace"))?;
+
+        let mut config = Self::new(format!("https://{}:{}", host, port), namespace.trim(), service);
+        config.token = Some(std::fs::read_to_string(dir.join("token"))?.trim().to_string());
+        config.ca_cert = Some(std::fs::read(dir.join("ca.crt"))?);
+        Ok(config)
+    }
+}
+
+/// Discovers peers from the endpoints of a Kubernetes Service
+#[derive(Clone)]
+pub struct KubernetesDiscovery {
+    config: KubernetesConfig,
+    client: reqwest::Client,
+}
+
+impl KubernetesDiscovery {
+    pub fn new(config: KubernetesConfig) -> Result<Self> {
+        let mut builder = reqwest::Client::builder().connect_timeout(Duration::from_secs(10));
+        if let Some(pem) = &config.ca_cert {
+            let cert = reqwest::Certificate::from_pem(pem).map_err(|e| VRaftError::InvalidConfig(e.to_string()))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        let client = builder.build().map_err(|e| VRaftError::InvalidConfig(e.to_string()))?;
+
+        Ok(Self { config, client })
+    }
+
+    fn slices_url(&self) -> String {
+        format!(
+            "{}/apis/discovery.k8s.io/v1/namespaces/{}/endpointslices?labelSelector={}%3D{}",
+            self.config.api_server.trim_end_matches('/'),
+            self.config.namespace,
+            SERVICE_NAME_LABEL,
+            self.config.service
+        )
+    }
+
+    fn request(&self, url: &str) -> reqwest::RequestBuilder {
+        let request = self.client.get(url);
+        match &self.config.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T> {
+        let response = self
+            .request(url)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(VRaftError::ConnectionFailed(format!(
+                "HTTP {} from {}",
+                response.status(),
+                url
+            )));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| VRaftError::Serialization(e.to_string()))
+    }
+
+    /// Read the Service's EndpointSlices, with their resource version
+    async fn list_slices(&self) -> Result<(Vec<EndpointSlice>, String)> {
+        let list: List<EndpointSlice> = self.get(&self.slices_url()).await?;
+        Ok((list.items, list.metadata.resource_version.unwrap_or_default()))
+    }
+
+    /// Annotation values of every pod in the namespace
+    async fn pod_node_ids(&self) -> Result<HashMap<String, String>> {
+        let url = format!(
+            "{}/api/v1/namespaces/{}/pods",
+            self.config.api_server.trim_end_matches('/'),
+            self.config.namespace
+        );
+        let pods: List<Pod> = self.get(&url).await?;
+
+        Ok(pods
+            .items
+            .into_iter()
+            .filter_map(|pod| {
+                let node_id = pod.metadata.annotations.get(&self.config.node_id_annotation)?.clone();
+                Some((pod.metadata.name, node_id))
+            })
+            .collect())
+    }
+
+    /// Follow the Service's endpoints, publishing the peer list whenever it changes
+    ///
+    /// The task re-lists and re-watches after errors; it stops once every
+    /// receiver is dropped.
+    pub fn spawn_watch(self) -> (watch::Receiver<Vec<(NodeId, SocketAddr)>>, JoinHandle<()>) {
+        let (tx, rx) = watch::channel(Vec::new());
+        let handle = tokio::spawn(async move {
+            while !tx.is_closed() {
+                if let Err(e) = self.watch(&tx).await {
+                    tracing::warn!(error = %e, service = %self.config.service, "kubernetes endpoint watch failed");
+                }
+                tokio::time::sleep(self.config.retry_backoff).await;
+            }
+        });
+        (rx, handle)
+    }
+
+    /// List, then watch until the API server closes the stream
+    async fn watch(&self, tx: &watch::Sender<Vec<(NodeId, SocketAddr)>>) -> Result<()> {
+        let (slices, version) = self.list_slices().await?;
+        let pods = self.pod_node_ids().await?;
+        publish(tx, nodes_from_slices(&slices, &pods, &self.config));
+
+        let url = format!("{}&watch=true&resourceVersion={}", self.slices_url(), version);
+        let mut response = self
+            .request(&url)
+            .send()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(VRaftError::ConnectionFailed(format!("HTTP {} from {}", response.status(), url)));
+        }
+
+        // Events arrive as newline-delimited JSON; any event triggers a re-list,
+        // since pod annotations may have changed along with the endpoints
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?
+        {
+            buffer.extend_from_slice(&chunk);
+            if !buffer.contains(&b'\n') {
+                continue;
+            }
+            let tail = buffer.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+            buffer.drain(..tail);
+
+            let (slices, _) = self.list_slices().await?;
+            let pods = self.pod_node_ids().await?;
+            publish(tx, nodes_from_slices(&slices, &pods, &self.config));
+            if tx.is_closed() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ServiceDiscovery for KubernetesDiscovery {
+    async fn discover(&self) -> Result<Vec<(NodeId, SocketAddr)>> {
+        let (slices, _) = self.list_slices().await?;
+        let pods = self.pod_node_ids().await?;
+        Ok(nodes_from_slices(&slices, &pods, &self.config))
+    }
+
+    async fn register(&self, _node_id: NodeId, _addr: SocketAddr) -> Result<()> {
+        // Kubernetes adds the pod to the Service once it is ready
+        Ok(())
+    }
+
+    async fn deregister(&self, _node_id: NodeId) -> Result<()> {
+        // Kubernetes removes the pod from the Service when it terminates
+        Ok(())
+    }
+}
+
+/// Send a peer list if it differs from the last one
+fn publish(tx: &watch::Sender<Vec<(NodeId, SocketAddr)>>, nodes: Vec<(NodeId, SocketAddr)>) {
+    tx.send_if_modified(|current| {
+        let changed = *current != nodes;
+        *current = nodes;
+        changed
+    });
+}
+
+/// Ready endpoints whose pod has a node ID annotation
+fn nodes_from_slices(
+    slices: &[EndpointSlice],
+    pod_node_ids: &HashMap<String, String>,
+    config: &KubernetesConfig,
+) -> Vec<(NodeId, SocketAddr)> {
+    let mut nodes: Vec<_> = slices
+        .iter()
+        .flat_map(|slice| {
+            let port = slice
+                .ports
+                .iter()
+                .find(|p| config.port_name.is_none() || p.name == config.port_name)
+                .and_then(|p| p.port);
+            slice.endpoints.iter().filter_map(move |endpoint| {
+                if endpoint.conditions.ready == Some(false) {
+                    return None;
+                }
+                let pod = endpoint.target_ref.as_ref().filter(|r| r.kind == "Pod")?;
+                let node_id = pod_node_ids.get(&pod.name)?.parse().ok()?;
+                let ip: IpAddr = endpoint.addresses.first()?.parse().ok()?;
+                Some((NodeId::new(node_id), SocketAddr::new(ip, port?)))
+            })
+        })
+        .collect();
+    nodes.sort();
+    nodes.dedup();
+    nodes
+}
+
+// Subset of the Kubernetes API objects read by this backend
+
+#[derive(Deserialize)]
+struct List<T> {
+    #[serde(default)]
+    metadata: ListMeta,
+    #[serde(default = "Vec::new")]
+    items: Vec<T>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListMeta {
+    resource_version: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct EndpointSlice {
+    #[serde(default)]
+    endpoints: Vec<Endpoint>,
+    #[serde(default)]
+    ports: Vec<EndpointPort>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Endpoint {
+    addresses: Vec<String>,
+    #[serde(default)]
+    conditions: EndpointConditions,
+    target_ref: Option<ObjectReference>,
+}
+
+#[derive(Default, Deserialize)]
+struct EndpointConditions {
+    ready: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ObjectReference {
+    #[serde(default)]
+    kind: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct EndpointPort {
+    name: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct Pod {
+    metadata: PodMeta,
+}
+
+#[derive(Deserialize)]
+struct PodMeta {
+    name: String,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nodes_from_slices() {
+        let slices: List<EndpointSlice> = serde_json::from_str(
+            r#"{"metadata":{"resourceVersion":"42"},"items":[{
+                "ports":[{"name":"admin","port":9000},{"name":"cluster","port":8080}],
+                "endpoints":[
+                    {"addresses":["10.0.0.2"],"conditions":{"ready":true},"targetRef":{"kind":"Pod","name":"node-b"}},
+                    {"addresses":["10.0.0.1"],"targetRef":{"kind":"Pod","name":"node-a"}},
+                    {"addresses":["10.0.0.3"],"conditions":{"ready":false},"targetRef":{"kind":"Pod","name":"node-c"}},
+                    {"addresses":["10.0.0.4"],"targetRef":{"kind":"Pod","name":"unannotated"}}
+                ]}]}"#,
+        )
+        .unwrap();
+        let pods = HashMap::from([
+            ("node-a".to_string(), "1".to_string()),
+            ("node-b".to_string(), "2".to_string()),
+            ("node-c".to_string(), "3".to_string()),
+        ]);
+        let mut config = KubernetesConfig::new("https://k8s", "default", "vraftls");
+        config.port_name = Some("cluster".to_string());
+
+        let nodes = nodes_from_slices(&slices.items, &pods, &config);
+        assert_eq!(
+            nodes,
+            vec![
+                (NodeId::new(1), "10.0.0.1:8080".parse().unwrap()),
+                (NodeId::new(2), "10.0.0.2:8080".parse().unwrap()),
+            ]
+        );
+    }
+}
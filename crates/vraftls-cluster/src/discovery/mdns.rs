@@ -0,0 +1,404 @@
+//! mDNS discovery backend for local development clusters
+//!
+//! Nodes advertise themselves with DNS-SD records (RFC 6763) over multicast
+//! DNS (RFC 6762): a PTR record for the service type, plus SRV, TXT and A
+//! records for the node's instance. The TXT record carries the node ID.
+//! Browsing sends a one-shot query from an ephemeral port, so responders
+//! answer by unicast and several nodes can run on the same machine.
+
+use super::ServiceDiscovery;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use vraftls_core::{NodeId, Result};
+
+/// mDNS multicast group and port
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// Pause after a failed receive, doubled on each further failure up to
+/// `MAX_RECEIVE_BACKOFF`, so a dead socket does not spin the responder
+const MIN_RECEIVE_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Settings of the mDNS discovery backend
+#[derive(Clone, Debug)]
+pub struct MdnsConfig {
+    /// DNS-SD service type
+    pub service_type: String,
+
+    /// How long to collect answers when browsing
+    pub browse_timeout: Duration,
+
+    /// TTL of advertised records, in seconds
+    pub ttl: u32,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            service_type: "_vraftls._tcp.local".to_string(),
+            browse_timeout: Duration::from_secs(1),
+            ttl: 120,
+        }
+    }
+}
+
+/// This node's advertisement
+struct Advertisement {
+    node_id: NodeId,
+    addr: SocketAddr,
+    responder: JoinHandle<()>,
+    socket: Arc<UdpSocket>,
+}
+
+/// Finds peers on the local network through multicast DNS
+pub struct MdnsDiscovery {
+    config: MdnsConfig,
+    advertisement: Mutex<Option<Advertisement>>,
+}
+
+impl MdnsDiscovery {
+    pub fn new(config: MdnsConfig) -> Self {
+        Self {
+            config,
+            advertisement: Mutex::new(None),
+        }
+    }
+
+    /// Bind the shared mDNS port and join the multicast group
+    fn responder_socket() -> std::io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // Every responder on the host binds 5353
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, MDNS_PORT).into())?;
+
+        let socket = UdpSocket::from_std(socket.into())?;
+        socket.join_multicast_v4(MDNS_GROUP, Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+        Ok(socket)
+    }
+
+    /// Answer queries for the service type until aborted
+    async fn respond(socket: Arc<UdpSocket>, config: MdnsConfig, node_id: NodeId, addr: SocketAddr) {
+        let mut buf = vec![0u8; 9000];
+        let mut backoff = MIN_RECEIVE_BACKOFF;
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => {
+                    backoff = MIN_RECEIVE_BACKOFF;
+                    received
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, retry_in = ?backoff, "mdns receive failed");
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECEIVE_BACKOFF);
+                    continue;
+                }
+            };
+            let Some(message) = dns::Message::parse(&buf[..len]) else {
+                continue;
+            };
+            if message.is_response || !message.questions.iter().any(|q| q.eq_ignore_ascii_case(&config.service_type)) {
+                continue;
+            }
+
+            // Legacy (non-5353) queriers expect a unicast reply with their query ID
+            let (id, target) = if from.port() == MDNS_PORT {
+                (0, SocketAddr::from((MDNS_GROUP, MDNS_PORT)))
+            } else {
+                (message.id, from)
+            };
+            let packet = dns::announcement(id, &config.service_type, node_id, addr, config.ttl);
+            if let Err(e) = socket.send_to(&packet, target).await {
+                tracing::debug!(error = %e, "failed to answer mdns query");
+            }
+        }
+    }
+}
+
+impl ServiceDiscovery for MdnsDiscovery {
+    async fn discover(&self) -> Result<Vec<(NodeId, SocketAddr)>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.set_multicast_loop_v4(true)?;
+        socket
+            .send_to(&dns::query(1, &self.config.service_type), (MDNS_GROUP, MDNS_PORT))
+            .await?;
+
+        let mut nodes = HashMap::new();
+        let mut buf = vec![0u8; 9000];
+        let deadline = tokio::time::Instant::now() + self.config.browse_timeout;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, from) = received?;
+            if let Some(message) = dns::Message::parse(&buf[..len]) {
+                for (node_id, addr) in message.instances(&self.config.service_type, from.ip()) {
+                    nodes.insert(node_id, addr);
+                }
+            }
+        }
+
+        let mut nodes: Vec<_> = nodes.into_iter().collect();
+        nodes.sort();
+        Ok(nodes)
+    }
+
+    async fn register(&self, node_id: NodeId, addr: SocketAddr) -> Result<()> {
+        let socket = Arc::new(Self::responder_socket()?);
+
+        // Announce right away so browsing peers need not wait for a query
+        let packet = dns::announcement(0, &self.config.service_type, node_id, addr, self.config.ttl);
+        socket.send_to(&packet, (MDNS_GROUP, MDNS_PORT)).await?;
+
+        let responder = tokio::spawn(Self::respond(socket.clone(), self.config.clone(), node_id, addr));
+        let advertisement = Advertisement {
+            node_id,
+            addr,
+            responder,
+            socket,
+        };
+        if let Some(previous) = self.advertisement.lock().unwrap().replace(advertisement) {
+            previous.responder.abort();
+        }
+        Ok(())
+    }
+
+    async fn deregister(&self, _node_id: NodeId) -> Result<()> {
+        let advertisement = self.advertisement.lock().unwrap().take();
+        if let Some(advertisement) = advertisement {
+            advertisement.responder.abort();
+
+            // A zero TTL tells caches to drop the records ("goodbye" packet)
+            let packet = dns::announcement(0, &self.config.service_type, advertisement.node_id, advertisement.addr, 0);
+            advertisement.socket.send_to(&packet, (MDNS_GROUP, MDNS_PORT)).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MdnsDiscovery {
+    fn drop(&mut self) {
+        if let Some(advertisement) = self.advertisement.lock().unwrap().take() {
+            advertisement.responder.abort();
+        }
+    }
+}
+
+/// Minimal DNS message encoding and decoding for DNS-SD
+mod dns {
+    use super::*;
+
+    /// Records of a received message relevant to discovery
+    #[derive(Default)]
+    pub struct Message {
+        pub id: u16,
+        pub is_response: bool,
+        pub questions: Vec<String>,
+        ptr: Vec<(String, String)>,
+        srv: HashMap<String, (u16, String)>,
+        txt: HashMap<String, Vec<String>>,
+        a: HashMap<String, Ipv4Addr>,
+    }
+
+    impl Message {
+        pub fn parse(packet: &[u8]) -> Option<Self> {
+            let mut message = Self {
+                id: read_u16(packet, 0)?,
+                is_response: read_u16(packet, 2)? & 0x8000 != 0,
+                ..Default::default()
+            };
+            let questions = read_u16(packet, 4)?;
+            let records = read_u16(packet, 6)? as usize + read_u16(packet, 8)? as usize + read_u16(packet, 10)? as usize;
+
+            let mut pos = 12;
+            for _ in 0..questions {
+                let (name, next) = read_name(packet, pos)?;
+                message.questions.push(name);
+                pos = next + 4;
+            }
+            for _ in 0..records {
+                let (name, next) = read_name(packet, pos)?;
+                let kind = read_u16(packet, next)?;
+                let len = read_u16(packet, next + 8)? as usize;
+                let data = next + 10;
+                let rdata = packet.get(data..data + len)?;
+                match kind {
+                    TYPE_PTR => message.ptr.push((name, read_name(packet, data)?.0)),
+                    TYPE_SRV => {
+                        let port = read_u16(packet, data + 4)?;
+                        message.srv.insert(name, (port, read_name(packet, data + 6)?.0));
+                    }
+                    TYPE_TXT => {
+                        message.txt.insert(name, read_txt(rdata));
+                    }
+                    TYPE_A if len == 4 => {
+                        message.a.insert(name, Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+                    }
+                    _ => {}
+                }
+                pos = data + len;
+            }
+            Some(message)
+        }
+
+        /// Advertised instances of `service_type`, with `source` standing in
+        /// for a missing address record
+        pub fn instances(&self, service_type: &str, source: IpAddr) -> Vec<(NodeId, SocketAddr)> {
+            self.ptr
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(service_type))
+                .filter_map(|(_, instance)| {
+                    let node_id = self
+                        .txt
+                        .get(instance)?
+                        .iter()
+                        .find_map(|entry| entry.strip_prefix("node_id="))?
+                        .parse()
+                        .ok()?;
+                    let (port, host) = self.srv.get(instance)?;
+                    let ip = self.a.get(host).map_or(source, |ip| IpAddr::V4(*ip));
+                    Some((NodeId::new(node_id), SocketAddr::new(ip, *port)))
+                })
+                .collect()
+        }
+    }
+
+    /// PTR query for a service type
+    pub fn query(id: u16, service_type: &str) -> Vec<u8> {
+        let mut packet = header(id, 0, 1, 0);
+        write_name(&mut packet, service_type);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    /// Response advertising a node's instance
+    pub fn announcement(id: u16, service_type: &str, node_id: NodeId, addr: SocketAddr, ttl: u32) -> Vec<u8> {
+        let instance = format!("node-{}.{}", node_id, service_type);
+        let host = format!("vraftls-{}.local", node_id);
+        let ipv4 = match addr.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+            _ => None,
+        };
+
+        let mut packet = header(id, 0x8400, 0, 3 + ipv4.is_some() as u16);
+        record(&mut packet, service_type, TYPE_PTR, ttl, |data| write_name(data, &instance));
+        record(&mut packet, &instance, TYPE_SRV, ttl, |data| {
+            data.extend_from_slice(&[0, 0, 0, 0]);
+            data.extend_from_slice(&addr.port().to_be_bytes());
+            write_name(data, &host);
+        });
+        record(&mut packet, &instance, TYPE_TXT, ttl, |data| {
+            let entry = format!("node_id={}", node_id);
+            data.push(entry.len() as u8);
+            data.extend_from_slice(entry.as_bytes());
+        });
+        if let Some(ip) = ipv4 {
+            record(&mut packet, &host, TYPE_A, ttl, |data| data.extend_from_slice(&ip.octets()));
+        }
+        packet
+    }
+
+    fn header(id: u16, flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(256);
+        for field in [id, flags, questions, answers, 0, 0] {
+            packet.extend_from_slice(&field.to_be_bytes());
+        }
+        packet
+    }
+
+    fn record(packet: &mut Vec<u8>, name: &str, kind: u16, ttl: u32, rdata: impl FnOnce(&mut Vec<u8>)) {
+        write_name(packet, name);
+        packet.extend_from_slice(&kind.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet.extend_from_slice(&ttl.to_be_bytes());
+        let mut data = Vec::new();
+        rdata(&mut data);
+        packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        packet.extend_from_slice(&data);
+    }
+
+    fn write_name(packet: &mut Vec<u8>, name: &str) {
+        for label in name.trim_end_matches('.').split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+    }
+
+    fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+        Some(u16::from_be_bytes(packet.get(pos..pos + 2)?.try_into().ok()?))
+    }
+
+    /// Read a possibly compressed name; returns it and the position after it
+    fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+        let mut labels = Vec::new();
+        let mut end = None;
+        // Bounds pointer loops in malformed packets
+        for _ in 0..128 {
+            let len = *packet.get(pos)? as usize;
+            if len == 0 {
+                return Some((labels.join("."), end.unwrap_or(pos + 1)));
+            }
+            if len & 0xc0 == 0xc0 {
+                end.get_or_insert(pos + 2);
+                pos = (read_u16(packet, pos)? & 0x3fff) as usize;
+                continue;
+            }
+            labels.push(String::from_utf8_lossy(packet.get(pos + 1..pos + 1 + len)?).into_owned());
+            pos += 1 + len;
+        }
+        None
+    }
+
+    fn read_txt(mut data: &[u8]) -> Vec<String> {
+        let mut entries = Vec::new();
+        while let Some((&len, rest)) = data.split_first() {
+            let len = (len as usize).min(rest.len());
+            entries.push(String::from_utf8_lossy(&rest[..len]).into_owned());
+            data = &rest[len..];
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announcement_round_trip() {
+        let service = "_vraftls._tcp.local";
+        let packet = dns::announcement(0, service, NodeId::new(3), "192.168.1.5:8083".parse().unwrap(), 120);
+        let message = dns::Message::parse(&packet).unwrap();
+        assert!(message.is_response);
+        assert_eq!(
+            message.instances(service, "10.0.0.1".parse().unwrap()),
+            vec![(NodeId::new(3), "192.168.1.5:8083".parse().unwrap())]
+        );
+        assert!(message.instances("_other._tcp.local", "10.0.0.1".parse().unwrap()).is_empty());
+
+        // Nodes listening on every interface are reached via the sender's address
+        let packet = dns::announcement(0, service, NodeId::new(4), "0.0.0.0:8084".parse().unwrap(), 120);
+        let message = dns::Message::parse(&packet).unwrap();
+        assert_eq!(
+            message.instances(service, "10.0.0.1".parse().unwrap()),
+            vec![(NodeId::new(4), "10.0.0.1:8084".parse().unwrap())]
+        );
+
+        let query = dns::Message::parse(&dns::query(7, service)).unwrap();
+        assert!(!query.is_response);
+        assert_eq!(query.id, 7);
+        assert_eq!(query.questions, vec![service.to_string()]);
+    }
+}
@@ -0,0 +1,179 @@
+//! Service discovery
+
+mod consul;
+mod etcd;
+mod kubernetes;
+mod mdns;
+
+pub use consul::{ConsulConfig, ConsulDiscovery};
+pub use etcd::{EtcdConfig, EtcdDiscovery};
+pub use kubernetes::{KubernetesConfig, KubernetesDiscovery};
+pub use mdns::{MdnsConfig, MdnsDiscovery};
+
+use crate::membership::ClusterMembership;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{DiscoveryConfig, NodeId, Result};
+
+/// Service discovery mechanism
+pub trait ServiceDiscovery: Send + Sync {
+    /// Discover cluster nodes
+    fn discover(&self) -> impl std::future::Future<Output = Result<Vec<(NodeId, SocketAddr)>>> + Send;
+
+    /// Register this node
+    fn register(&self, node_id: NodeId, addr: SocketAddr) -> impl std::future::Future<Output = Result<()>> + Send;
+
+    /// Deregister this node
+    fn deregister(&self, node_id: NodeId) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+impl<D: ServiceDiscovery> ServiceDiscovery for Arc<D> {
+    fn discover(&self) -> impl std::future::Future<Output = Result<Vec<(NodeId, SocketAddr)>>> + Send {
+        self.as_ref().discover()
+    }
+
+    fn register(&self, node_id: NodeId, addr: SocketAddr) -> impl std::future::Future<Output = Result<()>> + Send {
+        self.as_ref().register(node_id, addr)
+    }
+
+    fn deregister(&self, node_id: NodeId) -> impl std::future::Future<Output = Result<()>> + Send {
+        self.as_ref().deregister(node_id)
+    }
+}
+
+/// Static list discovery (for development/testing)
+pub struct StaticDiscovery {
+    nodes: Vec<(NodeId, SocketAddr)>,
+}
+
+impl StaticDiscovery {
+    pub fn new(nodes: Vec<(NodeId, SocketAddr)>) -> Self {
+        Self { nodes }
+    }
+}
+
+impl ServiceDiscovery for StaticDiscovery {
+    async fn discover(&self) -> Result<Vec<(NodeId, SocketAddr)>> {
+        Ok(self.nodes.clone())
+    }
+
+    async fn register(&self, _node_id: NodeId, _addr: SocketAddr) -> Result<()> {
+        // No-op for static discovery
+        Ok(())
+    }
+
+    async fn deregister(&self, _node_id: NodeId) -> Result<()> {
+        // No-op for static discovery
+        Ok(())
+    }
+}
+
+/// Discovery backend chosen by a node's configuration
+pub enum NodeDiscovery {
+    Static(StaticDiscovery),
+    Mdns(MdnsDiscovery),
+    Consul(ConsulDiscovery),
+    Etcd(Arc<EtcdDiscovery>),
+    Kubernetes(KubernetesDiscovery),
+}
+
+impl NodeDiscovery {
+    /// Build the configured backend; `peers` are the statically known nodes
+    pub fn from_config(config: &DiscoveryConfig, peers: Vec<(NodeId, SocketAddr)>) -> Result<Self> {
+        Ok(match config {
+            DiscoveryConfig::Static => Self::Static(StaticDiscovery::new(peers)),
+            DiscoveryConfig::Mdns { service_type } => {
+                let mut mdns = MdnsConfig::default();
+                if let Some(service_type) = service_type {
+                    mdns.service_type = service_type.clone();
+                }
+                Self::Mdns(MdnsDiscovery::new(mdns))
+            }
+            DiscoveryConfig::Consul { agent, service, token } => {
+                let mut consul = ConsulConfig::new(agent.clone(), service.clone());
+                consul.token = token.clone();
+                Self::Consul(ConsulDiscovery::new(consul))
+            }
+            DiscoveryConfig::Etcd { endpoint, prefix, token } => {
+                let mut etcd = EtcdConfig::new(endpoint.clone());
+                if let Some(prefix) = prefix {
+                    etcd.prefix = prefix.clone();
+                }
+                etcd.token = token.clone();
+                Self::Etcd(Arc::new(EtcdDiscovery::new(etcd)))
+            }
+            DiscoveryConfig::Kubernetes { service, port_name } => {
+                let mut kubernetes = KubernetesConfig::in_cluster(service.clone())?;
+                kubernetes.port_name = port_name.clone();
+                Self::Kubernetes(KubernetesDiscovery::new(kubernetes)?)
+            }
+        })
+    }
+
+    /// Keep `membership` in sync with the discovered nodes
+    ///
+    /// etcd and Kubernetes changes arrive through their watches. A static
+    /// list is applied once, as nodes joining later are not on it; other
+    /// backends are polled every `interval`.
+    pub fn spawn_sync(self: Arc<Self>, membership: Arc<ClusterMembership>, interval: Duration) -> JoinHandle<()> {
+        match self.as_ref() {
+            Self::Etcd(etcd) => etcd.clone().spawn_watch(membership),
+            Self::Kubernetes(kubernetes) => {
+                let (mut nodes, _watch) = kubernetes.clone().spawn_watch();
+                tokio::spawn(async move {
+                    // The watch stops once `nodes` is dropped
+                    while nodes.changed().await.is_ok() {
+                        let discovered = nodes.borrow_and_update().clone();
+                        membership.sync_discovered(&discovered);
+                    }
+                })
+            }
+            _ => tokio::spawn(async move {
+                loop {
+                    match self.discover().await {
+                        Ok(nodes) => membership.sync_discovered(&nodes),
+                        Err(e) => tracing::warn!(error = %e, "service discovery failed"),
+                    }
+                    if matches!(*self, Self::Static(_)) {
+                        return;
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+            }),
+        }
+    }
+}
+
+impl ServiceDiscovery for NodeDiscovery {
+    async fn discover(&self) -> Result<Vec<(NodeId, SocketAddr)>> {
+        match self {
+            Self::Static(discovery) => discovery.discover().await,
+            Self::Mdns(discovery) => discovery.discover().await,
+            Self::Consul(discovery) => discovery.discover().await,
+            Self::Etcd(discovery) => discovery.discover().await,
+            Self::Kubernetes(discovery) => discovery.discover().await,
+        }
+    }
+
+    async fn register(&self, node_id: NodeId, addr: SocketAddr) -> Result<()> {
+        match self {
+            Self::Static(discovery) => discovery.register(node_id, addr).await,
+            Self::Mdns(discovery) => discovery.register(node_id, addr).await,
+            Self::Consul(discovery) => discovery.register(node_id, addr).await,
+            Self::Etcd(discovery) => discovery.register(node_id, addr).await,
+            Self::Kubernetes(discovery) => discovery.register(node_id, addr).await,
+        }
+    }
+
+    async fn deregister(&self, node_id: NodeId) -> Result<()> {
+        match self {
+            Self::Static(discovery) => discovery.deregister(node_id).await,
+            Self::Mdns(discovery) => discovery.deregister(node_id).await,
+            Self::Consul(discovery) => discovery.deregister(node_id).await,
+            Self::Etcd(discovery) => discovery.deregister(node_id).await,
+            Self::Kubernetes(discovery) => discovery.deregister(node_id).await,
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Cluster event bus
+//!
+//! Membership, metadata and Raft leadership changes are published as
+//! `ClusterEvent`s, so the LSP router and the cache layer can react to them
+//! instead of polling. Subscribers that fall behind miss the oldest events
+//! and see `RecvError::Lagged`.
+
+use crate::membership::NodeStatus;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use vraftls_core::{NodeId, RaftGroupId};
+use vraftls_raft::VRaftRaft;
+
+/// Events kept for slow subscribers
+const EVENT_CAPACITY: usize = 1024;
+
+/// Something changed in the cluster
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ClusterEvent {
+    /// A node was added to the membership
+    NodeJoined { node_id: NodeId },
+
+    /// A node was removed from the membership
+    NodeLeft { node_id: NodeId },
+
+    /// A node stopped answering heartbeats reliably
+    NodeSuspect { node_id: NodeId },
+
+    /// A node flapped too often and is kept out of placement
+    NodeQuarantined { node_id: NodeId },
+
+    /// A quarantined node has been stable long enough
+    NodeReleased { node_id: NodeId },
+
+    /// A node's status changed
+    NodeStatusChanged {
+        node_id: NodeId,
+        from: NodeStatus,
+        to: NodeStatus,
+    },
+
+    /// A group elected a new leader, or lost its leader
+    LeaderChanged {
+        group_id: RaftGroupId,
+        leader: Option<NodeId>,
+    },
+
+    /// A group was given its first nodes
+    GroupCreated {
+        group_id: RaftGroupId,
+        nodes: Vec<NodeId>,
+    },
+
+    /// A group's replica moved between nodes
+    GroupMoved {
+        group_id: RaftGroupId,
+        from: NodeId,
+        to: NodeId,
+    },
+
+    /// A group's replica set changed by other than a single move
+    ReplicasChanged {
+        group_id: RaftGroupId,
+        removed: Vec<NodeId>,
+        added: Vec<NodeId>,
+    },
+
+    /// A group lost quorum or has several leaders
+    GroupDegraded { group_id: RaftGroupId, reason: String },
+
+    /// A degraded group is healthy again
+    GroupRecovered { group_id: RaftGroupId },
+}
+
+/// Publishes cluster events; clones share subscribers
+#[derive(Clone)]
+pub struct ClusterEvents {
+    sender: broadcast::Sender<ClusterEvent>,
+}
+
+impl ClusterEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender }
+    }
+
+    /// Receive events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ClusterEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Publish an event
+    pub fn emit(&self, event: ClusterEvent) {
+        tracing::debug!(?event, "cluster event");
+        // Nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// Publish a status change with the events it implies
+    pub fn status_changed(&self, node_id: NodeId, from: NodeStatus, to: NodeStatus) {
+        if to == NodeStatus::Suspect {
+            self.emit(ClusterEvent::NodeSuspect { node_id });
+        }
+        self.emit(ClusterEvent::NodeStatusChanged { node_id, from, to });
+    }
+}
+
+impl Default for ClusterEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Publish `LeaderChanged` whenever the group's leader changes
+///
+/// Stops once the Raft instance shuts down.
+pub fn spawn_leader_watch(group_id: RaftGroupId, raft: VRaftRaft, events: ClusterEvents) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut metrics = raft.metrics();
+        let mut leader = None;
+        loop {
+            let current = metrics.borrow_and_update().current_leader;
+            if current != leader {
+                leader = current;
+                events.emit(ClusterEvent::LeaderChanged {
+                    group_id,
+                    leader: leader.map(NodeId::new),
+                });
+            }
+            if metrics.changed().await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_changed() {
+        let events = ClusterEvents::new();
+        let mut rx = events.subscribe();
+        let node_id = NodeId::new(2);
+
+        events.status_changed(node_id, NodeStatus::Healthy, NodeStatus::Suspect);
+        assert_eq!(rx.try_recv().unwrap(), ClusterEvent::NodeSuspect { node_id });
+        assert!(matches!(rx.try_recv().unwrap(), ClusterEvent::NodeStatusChanged { .. }));
+        assert!(rx.try_recv().is_err());
+    }
+}
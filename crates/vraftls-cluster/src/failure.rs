@@ -1,39 +1,423 @@
 //! Failure detection
+//!
+//! Detectors implement `FailureDetection` and are selected per cluster:
+//!
+//! - Fixed timeout: a node is down once it has been silent for a set time.
+//! - Phi accrual (Hayashibara et al.): instead of a fixed timeout, each
+//!   node's heartbeat inter-arrival times are tracked and the silence since
+//!   its last heartbeat is turned into a suspicion level `phi`. A phi of 1
+//!   means roughly a 10% chance the node is still alive and merely late, 2
+//!   means 1%, and so on, so thresholds adapt to each link's jitter.
+//! - Quorum confirmed: wraps another detector and only declares a node down
+//!   once enough peers also fail to reach it, so a single bad link does not
+//!   flap membership.
 
+use crate::membership::NodeStatus;
+use dashmap::DashMap;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::time::Instant;
-use vraftls_core::NodeId;
+use vraftls_core::{ClusterConfig, FailureDetectionKind, NodeId};
 
-/// Failure detector using heartbeats
-pub struct FailureDetector {
-    /// Timeout before marking node as suspect
-    suspicion_timeout: Duration,
+/// Decides from heartbeats whether nodes are healthy, suspect or down
+pub trait FailureDetection: Send + Sync {
+    /// Record a heartbeat received at `at`
+    fn heartbeat_at(&self, node: NodeId, at: Instant);
 
-    /// Timeout before marking node as failed
-    failure_timeout: Duration,
+    /// Status of a node at `now`; `None` if it never sent a heartbeat
+    fn status_at(&self, node: NodeId, now: Instant) -> Option<NodeStatus>;
+
+    /// Forget a node's history
+    fn remove(&self, node: NodeId);
+
+    /// Record the nodes a peer cannot reach; only detectors that confirm
+    /// failures with peers use it
+    fn peer_report(&self, _reporter: NodeId, _unreachable: &[NodeId]) {}
+
+    /// Suspicion level (phi) of a node at `now`; `None` if it never sent a
+    /// heartbeat or the detector does not grade suspicion
+    fn suspicion_at(&self, _node: NodeId, _now: Instant) -> Option<f64> {
+        None
+    }
+
+    /// Record a heartbeat from a node
+    fn heartbeat(&self, node: NodeId) {
+        self.heartbeat_at(node, Instant::now());
+    }
+
+    /// Status of a node; `None` if it never sent a heartbeat
+    fn status(&self, node: NodeId) -> Option<NodeStatus> {
+        self.status_at(node, Instant::now())
+    }
+
+    /// Suspicion level (phi) of a node
+    fn suspicion(&self, node: NodeId) -> Option<f64> {
+        self.suspicion_at(node, Instant::now())
+    }
+
+    /// Check if a node should be marked as suspect
+    fn is_suspect(&self, node: NodeId) -> bool {
+        self.status(node).is_some_and(|s| s != NodeStatus::Healthy)
+    }
+
+    /// Check if a node should be marked as failed
+    fn is_failed(&self, node: NodeId) -> bool {
+        self.status(node) == Some(NodeStatus::Down)
+    }
+}
+
+/// The detector selected in the configuration
+pub fn failure_detector(config: &ClusterConfig) -> Arc<dyn FailureDetection> {
+    match config.failure_detection {
+        FailureDetectionKind::FixedTimeout => Arc::new(FixedTimeoutDetector::new(config.failure_timeout)),
+        FailureDetectionKind::PhiAccrual => Arc::new(PhiAccrualDetector::new(config.into())),
+        FailureDetectionKind::QuorumConfirmed => Arc::new(QuorumConfirmedDetector::new(
+            Arc::new(PhiAccrualDetector::new(config.into())),
+            config.failure_confirmations,
+        )),
+    }
 }
 
-impl FailureDetector {
-    pub fn new(suspicion_timeout: Duration, failure_timeout: Duration) -> Self {
+/// Down after a fixed silence, suspect after half of it
+pub struct FixedTimeoutDetector {
+    timeout: Duration,
+    last: DashMap<NodeId, Instant>,
+}
+
+impl FixedTimeoutDetector {
+    pub fn new(timeout: Duration) -> Self {
         Self {
-            suspicion_timeout,
-            failure_timeout,
+            timeout,
+            last: DashMap::new(),
         }
     }
+}
 
-    /// Check if a node should be marked as suspect
-    pub fn is_suspect(&self, last_heartbeat: Instant) -> bool {
-        last_heartbeat.elapsed() > self.suspicion_timeout
+impl FailureDetection for FixedTimeoutDetector {
+    fn heartbeat_at(&self, node: NodeId, at: Instant) {
+        let mut last = self.last.entry(node).or_insert(at);
+        if at > *last {
+            *last = at;
+        }
     }
 
-    /// Check if a node should be marked as failed
-    pub fn is_failed(&self, last_heartbeat: Instant) -> bool {
-        last_heartbeat.elapsed() > self.failure_timeout
+    fn status_at(&self, node: NodeId, now: Instant) -> Option<NodeStatus> {
+        let silence = now.saturating_duration_since(*self.last.get(&node)?);
+        Some(if silence >= self.timeout {
+            NodeStatus::Down
+        } else if silence >= self.timeout / 2 {
+            NodeStatus::Suspect
+        } else {
+            NodeStatus::Healthy
+        })
+    }
+
+    fn remove(&self, node: NodeId) {
+        self.last.remove(&node);
+    }
+}
+
+/// Settings of the phi-accrual failure detector
+#[derive(Clone, Debug)]
+pub struct PhiAccrualConfig {
+    /// Phi above which a node is suspect
+    pub suspect_phi: f64,
+
+    /// Phi above which a node is down
+    pub down_phi: f64,
+
+    /// Number of inter-arrival samples kept per node
+    pub window_size: usize,
+
+    /// Lower bound of the standard deviation, so a very regular history does
+    /// not make the detector overly sensitive
+    pub min_std_deviation: Duration,
+
+    /// Extra silence tolerated on top of the mean interval (e.g. GC pauses)
+    pub acceptable_pause: Duration,
+
+    /// Interval assumed before any sample has been collected
+    pub first_heartbeat_estimate: Duration,
+}
+
+impl Default for PhiAccrualConfig {
+    fn default() -> Self {
+        Self {
+            suspect_phi: 5.0,
+            down_phi: 8.0,
+            window_size: 100,
+            min_std_deviation: Duration::from_millis(100),
+            acceptable_pause: Duration::ZERO,
+            first_heartbeat_estimate: Duration::from_secs(1),
+        }
+    }
+}
+
+impl From<&ClusterConfig> for PhiAccrualConfig {
+    fn from(config: &ClusterConfig) -> Self {
+        Self {
+            suspect_phi: config.suspect_phi,
+            down_phi: config.down_phi,
+            window_size: config.phi_window_size,
+            first_heartbeat_estimate: config.heartbeat_interval,
+            ..Self::default()
+        }
+    }
+}
+
+/// Heartbeat arrivals of one node
+struct HeartbeatHistory {
+    last: Instant,
+
+    /// Inter-arrival times in milliseconds
+    intervals: VecDeque<f64>,
+}
+
+impl HeartbeatHistory {
+    fn mean_and_std(&self, config: &PhiAccrualConfig) -> (f64, f64) {
+        let min_std = config.min_std_deviation.as_secs_f64() * 1000.0;
+        if self.intervals.is_empty() {
+            let estimate = config.first_heartbeat_estimate.as_secs_f64() * 1000.0;
+            // Akka's choice: a quarter of the estimate as initial deviation
+            return (estimate, (estimate / 4.0).max(min_std));
+        }
+
+        let n = self.intervals.len() as f64;
+        let mean = self.intervals.iter().sum::<f64>() / n;
+        let variance = self.intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / n;
+        (mean, variance.sqrt().max(min_std))
+    }
+}
+
+/// Phi-accrual failure detector over all known nodes
+pub struct PhiAccrualDetector {
+    config: PhiAccrualConfig,
+    histories: DashMap<NodeId, HeartbeatHistory>,
+}
+
+impl PhiAccrualDetector {
+    pub fn new(config: PhiAccrualConfig) -> Self {
+        Self {
+            config,
+            histories: DashMap::new(),
+        }
+    }
+
+    /// Suspicion level of a node, `None` if it never sent a heartbeat
+    pub fn phi(&self, node: NodeId) -> Option<f64> {
+        self.phi_at(node, Instant::now())
+    }
+
+    /// Suspicion level of a node at `now`
+    pub fn phi_at(&self, node: NodeId, now: Instant) -> Option<f64> {
+        let history = self.histories.get(&node)?;
+        let (mean, std) = history.mean_and_std(&self.config);
+        let elapsed = now.saturating_duration_since(history.last).as_secs_f64() * 1000.0;
+        Some(phi(elapsed, mean + self.config.acceptable_pause.as_secs_f64() * 1000.0, std))
     }
 }
 
-impl Default for FailureDetector {
+impl FailureDetection for PhiAccrualDetector {
+    fn heartbeat_at(&self, node: NodeId, at: Instant) {
+        let mut history = self.histories.entry(node).or_insert_with(|| HeartbeatHistory {
+            last: at,
+            intervals: VecDeque::new(),
+        });
+        if at > history.last {
+            let interval = (at - history.last).as_secs_f64() * 1000.0;
+            if history.intervals.len() >= self.config.window_size {
+                history.intervals.pop_front();
+            }
+            history.intervals.push_back(interval);
+            history.last = at;
+        }
+    }
+
+    fn status_at(&self, node: NodeId, now: Instant) -> Option<NodeStatus> {
+        let phi = self.phi_at(node, now)?;
+        Some(if phi >= self.config.down_phi {
+            NodeStatus::Down
+        } else if phi >= self.config.suspect_phi {
+            NodeStatus::Suspect
+        } else {
+            NodeStatus::Healthy
+        })
+    }
+
+    fn remove(&self, node: NodeId) {
+        self.histories.remove(&node);
+    }
+
+    fn suspicion_at(&self, node: NodeId, now: Instant) -> Option<f64> {
+        self.phi_at(node, now)
+    }
+}
+
+impl Default for PhiAccrualDetector {
     fn default() -> Self {
-        Self::new(Duration::from_secs(5), Duration::from_secs(10))
+        Self::new(PhiAccrualConfig::default())
+    }
+}
+
+/// Declares a node down only once enough nodes fail to reach it
+///
+/// Until then a node the inner detector finds down is only suspect.
+pub struct QuorumConfirmedDetector {
+    inner: Arc<dyn FailureDetection>,
+
+    /// Nodes, this one included, that must agree a node is down
+    confirmations: usize,
+
+    /// Nodes each peer reported unreachable in its last heartbeat
+    reports: DashMap<NodeId, BTreeSet<NodeId>>,
+}
+
+impl QuorumConfirmedDetector {
+    pub fn new(inner: Arc<dyn FailureDetection>, confirmations: usize) -> Self {
+        Self {
+            inner,
+            confirmations,
+            reports: DashMap::new(),
+        }
+    }
+
+    /// Peers that currently report a node unreachable
+    pub fn agreeing_peers(&self, node: NodeId) -> usize {
+        self.reports.iter().filter(|r| r.contains(&node)).count()
+    }
+}
+
+impl FailureDetection for QuorumConfirmedDetector {
+    fn heartbeat_at(&self, node: NodeId, at: Instant) {
+        self.inner.heartbeat_at(node, at);
+    }
+
+    fn status_at(&self, node: NodeId, now: Instant) -> Option<NodeStatus> {
+        match self.inner.status_at(node, now)? {
+            NodeStatus::Down if 1 + self.agreeing_peers(node) < self.confirmations => Some(NodeStatus::Suspect),
+            status => Some(status),
+        }
+    }
+
+    fn remove(&self, node: NodeId) {
+        self.inner.remove(node);
+        self.reports.remove(&node);
+    }
+
+    fn peer_report(&self, reporter: NodeId, unreachable: &[NodeId]) {
+        self.reports.insert(reporter, unreachable.iter().copied().collect());
+    }
+
+    fn suspicion_at(&self, node: NodeId, now: Instant) -> Option<f64> {
+        self.inner.suspicion_at(node, now)
+    }
+}
+
+/// Phi for `elapsed` ms of silence given the interval distribution,
+/// using the logistic approximation of the normal CDF
+fn phi(elapsed: f64, mean: f64, std: f64) -> f64 {
+    let y = (elapsed - mean) / std;
+    let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+    if elapsed > mean {
+        -(e / (1.0 + e)).log10()
+    } else {
+        -(1.0 - 1.0 / (1.0 + e)).log10()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phi_grows_with_silence() {
+        let detector = PhiAccrualDetector::default();
+        let node = NodeId::new(2);
+        let start = Instant::now();
+        assert!(detector.phi_at(node, start).is_none());
+
+        // Heartbeats every second, with some jitter
+        for (i, jitter) in [0, 50, -30, 20, -10, 40, 0, -50, 30, 10].iter().enumerate() {
+            let at = start + Duration::from_millis(((i as i64 + 1) * 1000 + jitter) as u64);
+            detector.heartbeat_at(node, at);
+        }
+        let last = start + Duration::from_millis(10_010);
+
+        let on_time = detector.phi_at(node, last + Duration::from_millis(900)).unwrap();
+        let late = detector.phi_at(node, last + Duration::from_millis(1300)).unwrap();
+        assert!(on_time < late);
+        assert_eq!(detector.status_at(node, last + Duration::from_millis(1000)), Some(NodeStatus::Healthy));
+        assert_eq!(detector.status_at(node, last + Duration::from_millis(1500)), Some(NodeStatus::Suspect));
+        assert_eq!(detector.status_at(node, last + Duration::from_secs(3)), Some(NodeStatus::Down));
+    }
+
+    #[test]
+    fn test_fixed_timeout() {
+        let detector = FixedTimeoutDetector::new(Duration::from_secs(10));
+        let node = NodeId::new(2);
+        let start = Instant::now();
+        assert_eq!(detector.status_at(node, start), None);
+
+        detector.heartbeat_at(node, start);
+        assert_eq!(detector.status_at(node, start + Duration::from_secs(4)), Some(NodeStatus::Healthy));
+        assert_eq!(detector.status_at(node, start + Duration::from_secs(5)), Some(NodeStatus::Suspect));
+        assert_eq!(detector.status_at(node, start + Duration::from_secs(10)), Some(NodeStatus::Down));
+    }
+
+    #[test]
+    fn test_detector_from_config() {
+        let mut config = ClusterConfig {
+            heartbeat_interval: Duration::from_millis(100),
+            suspect_phi: 1.0,
+            down_phi: 2.0,
+            ..ClusterConfig::default()
+        };
+        let node = NodeId::new(2);
+        let start = Instant::now();
+
+        // The first interval is estimated from the configured heartbeat interval
+        config.failure_detection = FailureDetectionKind::PhiAccrual;
+        let detector = failure_detector(&config);
+        assert_eq!(detector.suspicion_at(node, start), None);
+        detector.heartbeat_at(node, start);
+        assert_eq!(detector.status_at(node, start + Duration::from_millis(200)), Some(NodeStatus::Healthy));
+        assert_eq!(detector.status_at(node, start + Duration::from_millis(300)), Some(NodeStatus::Suspect));
+        assert_eq!(detector.status_at(node, start + Duration::from_millis(400)), Some(NodeStatus::Down));
+
+        // The quorum-confirmed detector reports its inner detector's suspicion
+        config.failure_detection = FailureDetectionKind::QuorumConfirmed;
+        let detector = failure_detector(&config);
+        detector.heartbeat_at(node, start);
+        let phi = detector.suspicion_at(node, start + Duration::from_millis(400)).unwrap();
+        assert!(phi >= config.down_phi);
+        assert_eq!(detector.status_at(node, start + Duration::from_millis(400)), Some(NodeStatus::Suspect));
+
+        // A fixed timeout does not grade suspicion
+        config.failure_detection = FailureDetectionKind::FixedTimeout;
+        let detector = failure_detector(&config);
+        detector.heartbeat_at(node, start);
+        assert_eq!(detector.suspicion_at(node, start), None);
+    }
+
+    #[test]
+    fn test_quorum_confirmed() {
+        let detector = QuorumConfirmedDetector::new(Arc::new(FixedTimeoutDetector::new(Duration::from_secs(10))), 3);
+        let node = NodeId::new(2);
+        let start = Instant::now();
+        detector.heartbeat_at(node, start);
+        let later = start + Duration::from_secs(20);
+
+        // Only this node lost it
+        assert_eq!(detector.status_at(node, later), Some(NodeStatus::Suspect));
+
+        detector.peer_report(NodeId::new(3), &[node]);
+        assert_eq!(detector.status_at(node, later), Some(NodeStatus::Suspect));
+        detector.peer_report(NodeId::new(4), &[node]);
+        assert_eq!(detector.status_at(node, later), Some(NodeStatus::Down));
+
+        // A peer reaching it again withdraws its confirmation
+        detector.peer_report(NodeId::new(4), &[]);
+        assert_eq!(detector.status_at(node, later), Some(NodeStatus::Suspect));
     }
 }
@@ -0,0 +1,131 @@
+//! Consistent hash ring for partition placement
+//!
+//! Each Raft group owns a number of virtual nodes on the ring proportional to
+//! its weight; a partition key belongs to the group owning the first virtual
+//! node at or after the key's hash. Adding or removing a group only moves the
+//! keys next to its own virtual nodes. The ring hash is keyed identically on
+//! every node, so all nodes agree on placement.
+
+use hashring::HashRing;
+use std::collections::BTreeMap;
+use vraftls_core::{PartitionKey, RaftGroupId};
+
+/// Virtual nodes per unit of weight
+pub const DEFAULT_VNODES_PER_WEIGHT: u32 = 64;
+
+/// One of a group's points on the ring
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+struct VirtualNode {
+    group_id: RaftGroupId,
+    index: u32,
+}
+
+/// Maps partition keys to Raft groups
+#[derive(Clone, Debug)]
+pub struct PlacementRing {
+    ring: HashRing<VirtualNode>,
+    weights: BTreeMap<RaftGroupId, u32>,
+    vnodes_per_weight: u32,
+}
+
+impl PlacementRing {
+    pub fn new() -> Self {
+        Self::with_vnodes_per_weight(DEFAULT_VNODES_PER_WEIGHT)
+    }
+
+    pub fn with_vnodes_per_weight(vnodes_per_weight: u32) -> Self {
+        Self {
+            ring: HashRing::new(),
+            weights: BTreeMap::new(),
+            vnodes_per_weight: vnodes_per_weight.max(1),
+        }
+    }
+
+    /// Add a group, or change its weight
+    ///
+    /// A weight of zero removes the group.
+    pub fn set_group(&mut self, group_id: RaftGroupId, weight: u32) {
+        let previous = self.weights.get(&group_id).copied().unwrap_or(0);
+        let (from, to) = (
+            previous * self.vnodes_per_weight,
+            weight * self.vnodes_per_weight,
+        );
+
+        // Only the virtual nodes beyond the smaller count change hands
+        for index in to..from {
+            self.ring.remove(&VirtualNode { group_id, index });
+        }
+        for index in from..to {
+            self.ring.add(VirtualNode { group_id, index });
+        }
+
+        if weight == 0 {
+            self.weights.remove(&group_id);
+        } else {
+            self.weights.insert(group_id, weight);
+        }
+    }
+
+    /// Remove a group
+    pub fn remove_group(&mut self, group_id: RaftGroupId) {
+        self.set_group(group_id, 0);
+    }
+
+    /// Weight of a group (0 if absent)
+    pub fn weight(&self, group_id: RaftGroupId) -> u32 {
+        self.weights.get(&group_id).copied().unwrap_or(0)
+    }
+
+    /// Groups on the ring
+    pub fn groups(&self) -> impl Iterator<Item = RaftGroupId> + '_ {
+        self.weights.keys().copied()
+    }
+
+    /// Group a partition key belongs to
+    pub fn group_for(&self, key: &PartitionKey) -> Option<RaftGroupId> {
+        self.ring.get(key).map(|vnode| vnode.group_id)
+    }
+}
+
+impl Default for PlacementRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weighted_placement_and_movement() {
+        let keys: Vec<_> = (0..2000)
+            .map(|i| PartitionKey::from_bytes(format!("/src/file{}.rs", i).into_bytes()))
+            .collect();
+        let mut ring = PlacementRing::new();
+        for group in 1..=3 {
+            ring.set_group(RaftGroupId::new(group), 1);
+        }
+        ring.set_group(RaftGroupId::new(4), 3);
+
+        let before: Vec<_> = keys.iter().map(|k| ring.group_for(k).unwrap()).collect();
+        let heavy = before.iter().filter(|g| **g == RaftGroupId::new(4)).count();
+        // Group 4 has half of the total weight
+        assert!(
+            heavy > 800 && heavy < 1200,
+            "weighted group got {} keys",
+            heavy
+        );
+
+        // Adding a group only moves keys onto the new group
+        ring.set_group(RaftGroupId::new(5), 1);
+        for (key, old) in keys.iter().zip(&before) {
+            let new = ring.group_for(key).unwrap();
+            assert!(new == *old || new == RaftGroupId::new(5));
+        }
+
+        ring.remove_group(RaftGroupId::new(5));
+        let after: Vec<_> = keys.iter().map(|k| ring.group_for(k).unwrap()).collect();
+        assert_eq!(before, after);
+    }
+}
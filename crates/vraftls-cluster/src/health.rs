@@ -0,0 +1,288 @@
+//! Quorum-loss and split-brain detection
+//!
+//! For every group hosted on this node the monitor checks whether a majority
+//! of its voters is still reachable, and whether more than one node claims to
+//! lead it. Raft elects at most one leader per term, so only claims in the
+//! highest term seen conflict at once. A claim from a lower term is usually
+//! the previous leader's last heartbeat after a handoff; it counts only once
+//! it outlives `STALE_CLAIM_CHECKS` checks, as a partitioned leader that
+//! rejoins still believing it leads would. Degraded groups are recorded in
+//! `ClusterMetadata`, where writes are rejected until the group recovers.
+
+use crate::events::{ClusterEvent, ClusterEvents};
+use crate::leader_balance::{GroupLeadership, LeaderSource};
+use crate::membership::{ClusterMembership, ClusterNode, NodeStatus};
+use crate::metadata::ClusterMetadata;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{NodeId, RaftGroupId, Timestamp};
+
+/// Checks a leadership claim from an older term may outlive before it
+/// counts as a conflicting leader
+pub const STALE_CLAIM_CHECKS: u32 = 3;
+
+/// Why a group is degraded
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DegradedReason {
+    /// Fewer than a majority of the voters are reachable
+    QuorumLost { reachable: usize, voters: usize },
+
+    /// Several nodes claim leadership, with the terms they claim it in
+    SplitBrain { leaders: BTreeMap<NodeId, u64> },
+}
+
+impl fmt::Display for DegradedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QuorumLost { reachable, voters } => {
+                write!(f, "quorum lost, {} of {} voters reachable", reachable, voters)
+            }
+            Self::SplitBrain { leaders } => write!(f, "split brain, leaders (node: term) {:?}", leaders),
+        }
+    }
+}
+
+/// A group that cannot safely accept writes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DegradedGroup {
+    pub group_id: RaftGroupId,
+    pub reason: DegradedReason,
+
+    /// When the group was first found degraded
+    pub since: Timestamp,
+}
+
+/// Watches the groups hosted on this node
+pub struct GroupHealthMonitor<S> {
+    membership: Arc<ClusterMembership>,
+    metadata: Arc<ClusterMetadata>,
+    source: S,
+    events: ClusterEvents,
+
+    /// Consecutive checks each older-term claim was seen in, by group and node
+    stale_claims: Mutex<HashMap<(RaftGroupId, NodeId), u32>>,
+}
+
+impl<S: LeaderSource> GroupHealthMonitor<S> {
+    pub fn new(membership: Arc<ClusterMembership>, metadata: Arc<ClusterMetadata>, source: S) -> Self {
+        Self {
+            events: membership.events().clone(),
+            membership,
+            metadata,
+            source,
+            stale_claims: Mutex::default(),
+        }
+    }
+
+    /// Re-assess every hosted group; returns the degraded ones
+    pub async fn check(&self) -> Vec<DegradedGroup> {
+        let local = self.membership.local_node_id();
+        let nodes = self.membership.all_nodes();
+        let mut degraded = Vec::new();
+
+        for group in self.source.leaderships().await {
+            let previous = self.metadata.degraded_group(group.group_id).await;
+            let claims = leader_claims(local, &group, &nodes);
+            let lingering = track_stale_claims(&mut self.stale_claims.lock().unwrap(), group.group_id, &claims);
+            match assess_group(local, &group, &nodes, &lingering) {
+                Some(reason) => {
+                    if previous.as_ref().map(|p| &p.reason) != Some(&reason) {
+                        tracing::error!(group_id = %group.group_id, %reason, "raft group degraded");
+                        self.events.emit(ClusterEvent::GroupDegraded {
+                            group_id: group.group_id,
+                            reason: reason.to_string(),
+                        });
+                    }
+                    let entry = DegradedGroup {
+                        group_id: group.group_id,
+                        reason,
+                        since: previous.map_or_else(Timestamp::now, |p| p.since),
+                    };
+                    self.metadata.mark_degraded(entry.clone()).await;
+                    degraded.push(entry);
+                }
+                None if previous.is_some() => {
+                    tracing::info!(group_id = %group.group_id, "raft group recovered");
+                    self.metadata.clear_degraded(group.group_id).await;
+                    self.events.emit(ClusterEvent::GroupRecovered {
+                        group_id: group.group_id,
+                    });
+                }
+                None => {}
+            }
+        }
+        degraded
+    }
+}
+
+/// Leadership claims of reachable nodes for a group with their terms,
+/// including this node's own view
+pub fn leader_claims(local: NodeId, group: &GroupLeadership, nodes: &[ClusterNode]) -> BTreeMap<NodeId, u64> {
+    let mut leaders = BTreeMap::new();
+    if group.leader == Some(local) {
+        leaders.insert(local, group.term);
+    }
+    for node in nodes.iter().filter(|n| n.id != local && n.status != NodeStatus::Down) {
+        if let Some(claim) = node.leading.iter().find(|c| c.group_id == group.group_id) {
+            leaders.insert(node.id, claim.term);
+        }
+    }
+    leaders
+}
+
+/// Count the consecutive checks each older-term claim of a group has
+/// lasted; returns the nodes whose claims lasted `STALE_CLAIM_CHECKS`
+pub fn track_stale_claims(
+    counts: &mut HashMap<(RaftGroupId, NodeId), u32>,
+    group_id: RaftGroupId,
+    claims: &BTreeMap<NodeId, u64>,
+) -> BTreeSet<NodeId> {
+    let top = claims.values().copied().max().unwrap_or(0);
+    counts.retain(|(group, node), _| *group != group_id || claims.get(node).is_some_and(|term| *term < top));
+
+    let mut lingering = BTreeSet::new();
+    for (node, _) in claims.iter().filter(|(_, term)| **term < top) {
+        let checks = counts.entry((group_id, *node)).or_default();
+        *checks += 1;
+        if *checks >= STALE_CLAIM_CHECKS {
+            lingering.insert(*node);
+        }
+    }
+    lingering
+}
+
+/// Whether a group is degraded, from this node's view of the cluster
+///
+/// Claims in the highest term conflict, as do the older-term claims of
+/// `lingering` nodes.
+pub fn assess_group(
+    local: NodeId,
+    group: &GroupLeadership,
+    nodes: &[ClusterNode],
+    lingering: &BTreeSet<NodeId>,
+) -> Option<DegradedReason> {
+    let status = |id: NodeId| nodes.iter().find(|n| n.id == id).map(|n| &n.status);
+
+    let claims = leader_claims(local, group, nodes);
+    let top = claims.values().copied().max().unwrap_or(0);
+    let leaders: BTreeMap<_, _> = claims
+        .into_iter()
+        .filter(|(node, term)| *term == top || lingering.contains(node))
+        .collect();
+    if leaders.len() > 1 {
+        return Some(DegradedReason::SplitBrain { leaders });
+    }
+
+    let reachable = group
+        .voters
+        .iter()
+        .filter(|v| **v == local || matches!(status(**v), Some(NodeStatus::Healthy | NodeStatus::Leaving)))
+        .count();
+    if !group.voters.is_empty() && reachable * 2 <= group.voters.len() {
+        return Some(DegradedReason::QuorumLost {
+            reachable,
+            voters: group.voters.len(),
+        });
+    }
+
+    None
+}
+
+/// Check group health until the task is aborted
+pub fn spawn_health_monitor<S: LeaderSource + 'static>(
+    monitor: Arc<GroupHealthMonitor<S>>,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            monitor.check().await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::membership::LeaderClaim;
+
+    fn node(id: u64, status: NodeStatus, leading: Option<u64>) -> ClusterNode {
+        ClusterNode {
+            status,
+            raft_groups: vec![RaftGroupId::new(1)],
+            leading: leading
+                .map(|term| LeaderClaim {
+                    group_id: RaftGroupId::new(1),
+                    term,
+                })
+                .into_iter()
+                .collect(),
+            ..ClusterNode::new(NodeId::new(id), format!("127.0.0.1:{}", 8000 + id).parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_assess_group() {
+        let local = NodeId::new(1);
+        let group = GroupLeadership {
+            group_id: RaftGroupId::new(1),
+            leader: Some(local),
+            voters: [1, 2, 3].into_iter().map(NodeId::new).collect(),
+            term: 5,
+        };
+
+        let none = BTreeSet::new();
+
+        let healthy = [node(2, NodeStatus::Healthy, None), node(3, NodeStatus::Suspect, None)];
+        assert_eq!(assess_group(local, &group, &healthy, &none), None);
+
+        let lost = [node(2, NodeStatus::Down, None), node(3, NodeStatus::Suspect, None)];
+        assert_eq!(
+            assess_group(local, &group, &lost, &none),
+            Some(DegradedReason::QuorumLost { reachable: 1, voters: 3 })
+        );
+
+        // The previous leader's claim from an older term lingers briefly
+        // after every leadership change
+        let handoff = [node(2, NodeStatus::Healthy, None), node(3, NodeStatus::Healthy, Some(4))];
+        assert_eq!(assess_group(local, &group, &handoff, &none), None);
+
+        // Two leaders in one term can never be legitimate
+        let same_term = [node(2, NodeStatus::Healthy, None), node(3, NodeStatus::Healthy, Some(5))];
+        assert!(matches!(
+            assess_group(local, &group, &same_term, &none),
+            Some(DegradedReason::SplitBrain { .. })
+        ));
+
+        // Node 3 rejoined and keeps believing it leads from an older term
+        let lingering = BTreeSet::from([NodeId::new(3)]);
+        let Some(DegradedReason::SplitBrain { leaders }) = assess_group(local, &group, &handoff, &lingering) else {
+            panic!("split brain not detected");
+        };
+        assert_eq!(leaders.get(&NodeId::new(3)), Some(&4));
+    }
+
+    #[test]
+    fn test_track_stale_claims() {
+        let group = RaftGroupId::new(1);
+        let mut counts = HashMap::new();
+        let handoff = BTreeMap::from([(NodeId::new(1), 5), (NodeId::new(3), 4)]);
+
+        for _ in 1..STALE_CLAIM_CHECKS {
+            assert!(track_stale_claims(&mut counts, group, &handoff).is_empty());
+        }
+        assert_eq!(
+            track_stale_claims(&mut counts, group, &handoff),
+            BTreeSet::from([NodeId::new(3)])
+        );
+
+        // A claim that stops resets its count
+        track_stale_claims(&mut counts, group, &BTreeMap::from([(NodeId::new(1), 5)]));
+        assert!(track_stale_claims(&mut counts, group, &handoff).is_empty());
+    }
+}
@@ -0,0 +1,292 @@
+//! Heartbeats between cluster nodes
+//!
+//! Every node serves `HEARTBEAT_PATH`; the heartbeat service polls it on each
+//! known peer, feeds the replies to the failure detector and moves nodes
+//! between Healthy, Suspect and Down in `ClusterMembership`, which publishes
+//! the changes as cluster events.
+
+use crate::failure::FailureDetection;
+use crate::membership::{ClusterMembership, LeaderClaim, NodeStats, NodeStatus};
+use crate::version::{NodeVersion, MIN_PROTOCOL_VERSION};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::{JoinHandle, JoinSet};
+use vraftls_core::{ClusterConfig, NodeId};
+
+/// HTTP path answering heartbeats
+pub const HEARTBEAT_PATH: &str = "/cluster/heartbeat";
+
+/// Reply to a heartbeat
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeartbeatResponse {
+    /// ID of the answering node
+    pub node_id: NodeId,
+
+    /// Region of the answering node
+    #[serde(default)]
+    pub region: Option<String>,
+
+    /// Zone of the answering node
+    #[serde(default)]
+    pub zone: Option<String>,
+
+    /// Rack of the answering node
+    #[serde(default)]
+    pub rack: Option<String>,
+
+    /// Groups the answering node believes it leads
+    #[serde(default)]
+    pub leading: Vec<LeaderClaim>,
+
+    /// Load of the answering node
+    #[serde(default)]
+    pub stats: NodeStats,
+
+    /// Whether the answering node is in maintenance
+    #[serde(default)]
+    pub maintenance: bool,
+
+    /// Whether the answering node is leaving the cluster
+    #[serde(default)]
+    pub leaving: bool,
+
+    /// Nodes the answering node finds suspect or down
+    #[serde(default)]
+    pub unreachable: Vec<NodeId>,
+
+    /// Version of the answering node; `None` from builds that predate
+    /// version reporting, which speak the first protocol version
+    #[serde(default)]
+    pub version: Option<NodeVersion>,
+}
+
+/// Settings of the heartbeat service
+#[derive(Clone, Debug)]
+pub struct HeartbeatConfig {
+    /// Interval between heartbeat rounds
+    pub interval: Duration,
+
+    /// Timeout of a single heartbeat request
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+impl From<&ClusterConfig> for HeartbeatConfig {
+    fn from(config: &ClusterConfig) -> Self {
+        Self {
+            interval: config.heartbeat_interval,
+            ..Self::default()
+        }
+    }
+}
+
+/// Sends and evaluates heartbeats for every known peer
+pub struct HeartbeatService {
+    membership: Arc<ClusterMembership>,
+    detector: Arc<dyn FailureDetection>,
+    config: HeartbeatConfig,
+    client: reqwest::Client,
+}
+
+impl HeartbeatService {
+    pub fn new(membership: Arc<ClusterMembership>, detector: Arc<dyn FailureDetection>, config: HeartbeatConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .expect("Failed to create HTTP client");
+
+        Self {
+            membership,
+            detector,
+            config,
+            client,
+        }
+    }
+
+    /// Run heartbeat rounds until the task is aborted
+    pub fn spawn(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                self.round().await;
+            }
+        })
+    }
+
+    /// Ping every peer once, then re-evaluate their statuses
+    pub async fn round(&self) {
+        let local = self.membership.local_node_id();
+        let peers: Vec<_> = self
+            .membership
+            .all_nodes()
+            .into_iter()
+            .filter(|node| node.id != local)
+            .collect();
+
+        let mut pings = JoinSet::new();
+        for peer in &peers {
+            let client = self.client.clone();
+            let (id, addr) = (peer.id, peer.addr);
+            pings.spawn(async move { (id, ping(&client, addr).await) });
+        }
+        while let Some(Ok((id, reply))) = pings.join_next().await {
+            match reply {
+                // A node answering with another ID moved; it is not this peer
+                Ok(response) if response.node_id == id && !is_compatible(response.version.as_ref()) => {
+                    // Not counted as a heartbeat: the node never becomes healthy
+                    tracing::warn!(node_id = %id, version = ?response.version, "incompatible protocol version");
+                    self.membership.set_version(id, response.version);
+                }
+                Ok(response) if response.node_id == id => {
+                    self.membership.set_version(id, response.version);
+                    if response.leaving {
+                        self.membership.mark_leaving(id);
+                    }
+                    // Before the heartbeat, so a node back for maintenance
+                    // does not briefly count as healthy
+                    if let Err(e) = self.membership.set_maintenance(id, response.maintenance) {
+                        tracing::debug!(node_id = %id, error = %e, "maintenance not applied");
+                    }
+                    self.detector.heartbeat(id);
+                    self.detector.peer_report(id, &response.unreachable);
+                    self.membership.update_heartbeat(id);
+                    self.membership.set_failure_domain(id, response.region, response.zone, response.rack);
+                    self.membership.set_leading(id, response.leading);
+                    self.membership.set_stats(id, response.stats);
+                }
+                Ok(response) => {
+                    tracing::warn!(node_id = %id, answered = %response.node_id, "heartbeat answered by another node")
+                }
+                Err(e) => tracing::debug!(node_id = %id, error = %e, "heartbeat failed"),
+            }
+        }
+
+        for peer in peers {
+            let Some(now) = self.membership.get_node(peer.id) else {
+                continue;
+            };
+            self.membership.set_suspicion(peer.id, self.detector.suspicion(peer.id));
+            // Nodes that were never reached stay as they are, and nodes in
+            // maintenance are expected to miss heartbeats
+            match (now.status.clone(), self.detector.status(peer.id)) {
+                (NodeStatus::Healthy, Some(NodeStatus::Suspect)) => self.membership.mark_suspect(peer.id),
+                (NodeStatus::Healthy | NodeStatus::Suspect, Some(NodeStatus::Down)) => self.membership.mark_down(peer.id),
+                _ => {}
+            }
+
+            if let Some(after) = self.membership.get_node(peer.id) {
+                if after.status != peer.status {
+                    tracing::info!(node_id = %peer.id, from = ?peer.status, to = ?after.status, "node status changed");
+                }
+            }
+        }
+
+        self.membership.release_stable();
+    }
+}
+
+/// Whether a peer's protocol version is one this build talks to
+fn is_compatible(version: Option<&NodeVersion>) -> bool {
+    match version {
+        Some(version) => NodeVersion::current().is_compatible(version),
+        None => MIN_PROTOCOL_VERSION <= 1,
+    }
+}
+
+/// Send one heartbeat
+async fn ping(client: &reqwest::Client, addr: SocketAddr) -> reqwest::Result<HeartbeatResponse> {
+    client
+        .get(format!("http://{}{}", addr, HEARTBEAT_PATH))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failure::FixedTimeoutDetector;
+    use crate::membership::ClusterNode;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use axum::Json;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Serve heartbeats as node 2 while `up` is set
+    async fn stub_peer(up: Arc<AtomicBool>) -> SocketAddr {
+        let app = axum::Router::new().route(
+            HEARTBEAT_PATH,
+            get(move || {
+                let up = up.clone();
+                async move {
+                    if !up.load(Ordering::SeqCst) {
+                        return Err(StatusCode::SERVICE_UNAVAILABLE);
+                    }
+                    Ok(Json(HeartbeatResponse {
+                        node_id: NodeId::new(2),
+                        region: None,
+                        zone: None,
+                        rack: None,
+                        leading: Vec::new(),
+                        stats: NodeStats::default(),
+                        maintenance: false,
+                        leaving: false,
+                        unreachable: Vec::new(),
+                        version: Some(NodeVersion::current()),
+                    }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_round_moves_silent_peer_to_suspect_then_down() {
+        let up = Arc::new(AtomicBool::new(true));
+        let addr = stub_peer(up.clone()).await;
+        let membership = Arc::new(ClusterMembership::new(NodeId::new(1)));
+        membership.upsert_node(ClusterNode::new(NodeId::new(2), addr));
+
+        // Suspect after 200ms of silence, down after 400ms
+        let service = HeartbeatService::new(
+            membership.clone(),
+            Arc::new(FixedTimeoutDetector::new(Duration::from_millis(400))),
+            HeartbeatConfig::default(),
+        );
+        let status = || membership.get_node(NodeId::new(2)).unwrap().status;
+
+        service.round().await;
+        assert_eq!(status(), NodeStatus::Healthy);
+
+        up.store(false, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(220)).await;
+        service.round().await;
+        assert_eq!(status(), NodeStatus::Suspect);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        service.round().await;
+        assert_eq!(status(), NodeStatus::Down);
+
+        // A heartbeat brings the node back
+        up.store(true, Ordering::SeqCst);
+        service.round().await;
+        assert_eq!(status(), NodeStatus::Healthy);
+    }
+}
@@ -0,0 +1,153 @@
+//! Leader balancing
+//!
+//! After failures recover, leaderships tend to pile up on the nodes that
+//! stayed up. Each node periodically compares how many of its groups it
+//! leads against the other voters of those groups and, if it leads more than
+//! `max_imbalance` beyond the least loaded of them, steps aside in one group.
+
+use crate::decommission::LeadershipTransfer;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{ClusterConfig, NodeId, RaftGroupId, Result};
+
+/// Leader and voters of a group, as seen from this node
+#[derive(Clone, Debug)]
+pub struct GroupLeadership {
+    pub group_id: RaftGroupId,
+    pub leader: Option<NodeId>,
+    pub voters: Vec<NodeId>,
+
+    /// Current Raft term
+    pub term: u64,
+}
+
+/// Reports the leadership of the groups hosted on this node
+pub trait LeaderSource: Send + Sync {
+    fn leaderships(&self) -> impl std::future::Future<Output = Vec<GroupLeadership>> + Send;
+}
+
+/// Leader balancing settings
+#[derive(Clone, Debug)]
+pub struct LeaderBalanceConfig {
+    /// Leaderships a node may hold beyond the least loaded voter
+    pub max_imbalance: usize,
+
+    /// Interval between checks
+    pub interval: Duration,
+}
+
+impl From<&ClusterConfig> for LeaderBalanceConfig {
+    fn from(config: &ClusterConfig) -> Self {
+        Self {
+            max_imbalance: config.leader_max_imbalance,
+            interval: config.leader_balance_interval,
+        }
+    }
+}
+
+/// Spreads leaderships across voters
+pub struct LeaderBalancer<S> {
+    local: NodeId,
+    source: S,
+    config: LeaderBalanceConfig,
+}
+
+impl<S: LeaderSource + LeadershipTransfer> LeaderBalancer<S> {
+    pub fn new(local: NodeId, source: S, config: LeaderBalanceConfig) -> Self {
+        Self { local, source, config }
+    }
+
+    /// Step aside in one group if this node leads too many
+    ///
+    /// Returns the group given up.
+    pub async fn balance_once(&self) -> Result<Option<RaftGroupId>> {
+        let groups = self.source.leaderships().await;
+        let Some(group_id) = pick_transfer(self.local, &groups, self.config.max_imbalance) else {
+            return Ok(None);
+        };
+
+        tracing::info!(%group_id, "giving up leadership to balance leaders");
+        self.source.transfer_leadership(group_id).await?;
+        Ok(Some(group_id))
+    }
+}
+
+/// Leaderships per voter; voters leading nothing count as zero
+pub fn leader_counts(groups: &[GroupLeadership]) -> BTreeMap<NodeId, usize> {
+    let mut counts = BTreeMap::new();
+    for group in groups {
+        for voter in &group.voters {
+            counts.entry(*voter).or_insert(0);
+        }
+        if let Some(leader) = group.leader {
+            *counts.entry(leader).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+/// Group `local` should give up, if it leads more than `max_imbalance`
+/// beyond another voter of that group
+pub fn pick_transfer(local: NodeId, groups: &[GroupLeadership], max_imbalance: usize) -> Option<RaftGroupId> {
+    let counts = leader_counts(groups);
+    let own = counts.get(&local).copied().unwrap_or(0);
+
+    groups
+        .iter()
+        .filter(|g| g.leader == Some(local))
+        .filter_map(|g| {
+            let least = g
+                .voters
+                .iter()
+                .filter(|v| **v != local)
+                .map(|v| counts.get(v).copied().unwrap_or(0))
+                .min()?;
+            (own > least + max_imbalance).then_some((least, g.group_id))
+        })
+        .min()
+        .map(|(_, group_id)| group_id)
+}
+
+/// Run leader balancing until the task is aborted
+pub fn spawn_leader_balancer<S: LeaderSource + LeadershipTransfer + 'static>(
+    balancer: Arc<LeaderBalancer<S>>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(balancer.config.interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = balancer.balance_once().await {
+                tracing::warn!(error = %e, "leader balancing failed");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group(id: u64, leader: u64, voters: &[u64]) -> GroupLeadership {
+        GroupLeadership {
+            group_id: RaftGroupId::new(id),
+            leader: Some(NodeId::new(leader)),
+            voters: voters.iter().copied().map(NodeId::new).collect(),
+            term: 1,
+        }
+    }
+
+    #[test]
+    fn test_pick_transfer() {
+        let local = NodeId::new(1);
+        let groups = vec![group(1, 1, &[1, 2, 3]), group(2, 1, &[1, 2, 3]), group(3, 1, &[1, 2])];
+        assert_eq!(pick_transfer(local, &groups, 1), Some(RaftGroupId::new(1)));
+        assert_eq!(pick_transfer(local, &groups, 3), None);
+
+        // Balanced: every node leads one group
+        let groups = vec![group(1, 1, &[1, 2, 3]), group(2, 2, &[1, 2, 3]), group(3, 3, &[1, 2, 3])];
+        assert_eq!(pick_transfer(local, &groups, 0), None);
+    }
+}
@@ -0,0 +1,175 @@
+//! Graceful leave
+//!
+//! A node shutting down for good should not let its groups find out by
+//! missing heartbeats: every group it leads would hold an election at once.
+//! Leaving instead announces the intent by marking the node `Leaving`, which
+//! peers learn with the next heartbeat and which keeps new replicas away,
+//! hands off every leadership, waits until other voters have taken over,
+//! and only then deregisters the node from service discovery.
+
+use crate::decommission::LeadershipTransfer;
+use crate::discovery::ServiceDiscovery;
+use crate::leader_balance::LeaderSource;
+use crate::membership::ClusterMembership;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::time::Instant;
+use vraftls_core::{ClusterConfig, RaftGroupId, Result};
+
+/// Graceful leave settings
+#[derive(Clone, Debug)]
+pub struct LeaveConfig {
+    /// How long to wait for other voters to take over
+    pub timeout: Duration,
+
+    /// Interval between checks for the new leaders
+    pub poll_interval: Duration,
+}
+
+impl From<&ClusterConfig> for LeaveConfig {
+    fn from(config: &ClusterConfig) -> Self {
+        Self {
+            timeout: config.leave_timeout,
+            poll_interval: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Result of a graceful leave
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaveOutcome {
+    /// Groups whose leadership another voter took over
+    pub handed_off: Vec<RaftGroupId>,
+
+    /// Groups still led by this node when the wait timed out
+    pub still_leading: Vec<RaftGroupId>,
+}
+
+impl ClusterMembership {
+    /// Leave the cluster gracefully
+    ///
+    /// Leaderships that were not taken over in time are left to elections;
+    /// the node deregisters either way, since it is going away.
+    pub async fn leave<G, D>(&self, groups: &G, discovery: &D, config: &LeaveConfig) -> Result<LeaveOutcome>
+    where
+        G: LeadershipTransfer + LeaderSource,
+        D: ServiceDiscovery,
+    {
+        let local = self.local_node_id();
+        self.mark_leaving(local);
+        tracing::info!(node_id = %local, "leaving the cluster");
+
+        let led = led_groups(groups, self).await;
+        for group_id in &led {
+            if let Err(e) = groups.transfer_leadership(*group_id).await {
+                tracing::warn!(%group_id, error = %e, "leadership transfer failed");
+            }
+        }
+
+        // A group is handed off once another voter leads it
+        let deadline = Instant::now() + config.timeout;
+        let mut still_leading = led.clone();
+        while !still_leading.is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(config.poll_interval).await;
+            let leaderships = groups.leaderships().await;
+            still_leading.retain(|group_id| {
+                !leaderships
+                    .iter()
+                    .any(|g| g.group_id == *group_id && g.leader.is_some_and(|leader| leader != local))
+            });
+        }
+        if !still_leading.is_empty() {
+            tracing::warn!(groups = ?still_leading, "leaving without handing off every leadership");
+        }
+
+        discovery.deregister(local).await?;
+        tracing::info!(node_id = %local, "deregistered from discovery");
+
+        Ok(LeaveOutcome {
+            handed_off: led.into_iter().filter(|g| !still_leading.contains(g)).collect(),
+            still_leading,
+        })
+    }
+}
+
+/// Groups this node currently leads
+async fn led_groups<S: LeaderSource>(source: &S, membership: &ClusterMembership) -> Vec<RaftGroupId> {
+    let local = membership.local_node_id();
+    source
+        .leaderships()
+        .await
+        .into_iter()
+        .filter(|g| g.leader == Some(local))
+        .map(|g| g.group_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discovery::StaticDiscovery;
+    use crate::leader_balance::GroupLeadership;
+    use crate::membership::{ClusterNode, NodeStats, NodeStatus};
+    use std::sync::Mutex;
+    use vraftls_core::{NodeId, Timestamp};
+
+    /// Groups led by node 1; only group 1 has a voter willing to take over
+    struct Groups(Mutex<Vec<GroupLeadership>>);
+
+    impl LeadershipTransfer for Groups {
+        async fn transfer_leadership(&self, group_id: RaftGroupId) -> Result<bool> {
+            if group_id == RaftGroupId::new(1) {
+                for group in self.0.lock().unwrap().iter_mut().filter(|g| g.group_id == group_id) {
+                    group.leader = Some(NodeId::new(2));
+                }
+            }
+            Ok(true)
+        }
+    }
+
+    impl LeaderSource for Groups {
+        async fn leaderships(&self) -> Vec<GroupLeadership> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_leave() {
+        let local = NodeId::new(1);
+        let membership = ClusterMembership::new(local);
+        membership.upsert_node(ClusterNode {
+            id: local,
+            addr: "127.0.0.1:8001".parse().unwrap(),
+            status: NodeStatus::Healthy,
+            raft_groups: vec![RaftGroupId::new(1), RaftGroupId::new(2)],
+            last_heartbeat: Timestamp::now(),
+            region: None,
+            zone: None,
+            rack: None,
+            leading: Vec::new(),
+            stats: NodeStats::default(),
+            version: None,
+            quarantined: false,
+        });
+        let group = |id: u64| GroupLeadership {
+            group_id: RaftGroupId::new(id),
+            leader: Some(local),
+            voters: vec![local, NodeId::new(2)],
+            term: 1,
+        };
+        let groups = Groups(Mutex::new(vec![group(1), group(2)]));
+        let config = LeaveConfig {
+            timeout: Duration::from_millis(50),
+            poll_interval: Duration::from_millis(10),
+        };
+
+        let outcome = membership
+            .leave(&groups, &StaticDiscovery::new(Vec::new()), &config)
+            .await
+            .unwrap();
+
+        assert_eq!(membership.get_node(local).unwrap().status, NodeStatus::Leaving);
+        assert_eq!(outcome.handed_off, vec![RaftGroupId::new(1)]);
+        assert_eq!(outcome.still_leading, vec![RaftGroupId::new(2)]);
+    }
+}
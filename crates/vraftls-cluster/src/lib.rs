@@ -1,11 +1,47 @@
 //! VRaftLS Cluster - Cluster membership and coordination
 
+pub mod bootstrap;
+pub mod decommission;
 pub mod discovery;
+pub mod events;
 pub mod failure;
+pub mod hash_ring;
+pub mod health;
+pub mod heartbeat;
+pub mod leader_balance;
+pub mod leave;
 pub mod membership;
 pub mod metadata;
+pub mod metadata_client;
+pub mod metadata_state_machine;
+pub mod placement;
+pub mod quarantine;
+pub mod rebalance;
+pub mod repair;
+pub mod routing;
+pub mod split;
+pub mod topology;
+pub mod version;
 
+pub use bootstrap::*;
+pub use decommission::*;
 pub use discovery::*;
+pub use events::*;
 pub use failure::*;
+pub use hash_ring::*;
+pub use health::*;
+pub use heartbeat::*;
+pub use leader_balance::*;
+pub use leave::*;
 pub use membership::*;
 pub use metadata::*;
+pub use metadata_client::*;
+pub use metadata_state_machine::*;
+pub use placement::*;
+pub use quarantine::*;
+pub use rebalance::*;
+pub use repair::*;
+pub use routing::*;
+pub use split::*;
+pub use topology::*;
+pub use version::*;
@@ -1,9 +1,19 @@
 //! Cluster membership management
 
+use crate::events::{ClusterEvent, ClusterEvents};
+use crate::quarantine::{FlapConfig, FlapTracker};
+use crate::version::NodeVersion;
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
+use std::io;
 use std::net::SocketAddr;
-use vraftls_core::{NodeId, RaftGroupId, Timestamp};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use vraftls_core::{NodeId, RaftGroupId, Result, Timestamp, VRaftError};
 
 /// Node status in the cluster
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -18,6 +28,9 @@ pub enum NodeStatus {
     Joining,
     /// Node is leaving the cluster
     Leaving,
+    /// Node is being serviced; it keeps its replicas but takes no new
+    /// sessions or replicas, and missed heartbeats raise no alerts
+    Maintenance,
 }
 
 /// Information about a cluster node
@@ -28,6 +41,85 @@ pub struct ClusterNode {
     pub status: NodeStatus,
     pub raft_groups: Vec<RaftGroupId>,
     pub last_heartbeat: Timestamp,
+
+    /// Region the node runs in
+    #[serde(default)]
+    pub region: Option<String>,
+
+    /// Zone the node runs in, within its region
+    #[serde(default)]
+    pub zone: Option<String>,
+
+    /// Rack the node runs in, within its zone
+    #[serde(default)]
+    pub rack: Option<String>,
+
+    /// Groups the node last reported leading
+    #[serde(default)]
+    pub leading: Vec<LeaderClaim>,
+
+    /// Load the node last reported
+    #[serde(default)]
+    pub stats: NodeStats,
+
+    /// Version the node last reported; `None` until heard from, or for
+    /// builds that predate version reporting
+    #[serde(default)]
+    pub version: Option<NodeVersion>,
+
+    /// Kept out of placement for flapping between healthy and suspect
+    #[serde(default)]
+    pub quarantined: bool,
+}
+
+impl ClusterNode {
+    /// A node not heard from yet, hosting no groups
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Self {
+            id,
+            addr,
+            status: NodeStatus::Joining,
+            raft_groups: Vec::new(),
+            last_heartbeat: Timestamp::now(),
+            region: None,
+            zone: None,
+            rack: None,
+            leading: Vec::new(),
+            stats: NodeStats::default(),
+            version: None,
+            quarantined: false,
+        }
+    }
+
+    /// Whether new replicas may be placed on the node
+    pub fn is_placeable(&self) -> bool {
+        self.status == NodeStatus::Healthy && !self.quarantined
+    }
+}
+
+/// Load and capacity usage of a node, reported with heartbeats
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStats {
+    /// Files in the groups hosted on the node
+    pub file_count: u64,
+
+    /// Resident memory of the node process
+    pub memory_bytes: u64,
+
+    /// Language server processes running on the node
+    pub ls_processes: u32,
+
+    /// Bytes used by the node's data directory
+    pub disk_bytes: u64,
+}
+
+/// A node's claim to lead a group
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LeaderClaim {
+    pub group_id: RaftGroupId,
+
+    /// Term the node believes it leads in
+    pub term: u64,
 }
 
 /// Cluster membership registry
@@ -37,6 +129,15 @@ pub struct ClusterMembership {
 
     /// Local node ID
     local_node_id: NodeId,
+
+    /// Publishes membership changes
+    events: ClusterEvents,
+
+    /// Status flips, for quarantining flapping nodes
+    flaps: FlapTracker,
+
+    /// Suspicion level (phi) of each node, as last graded by the failure detector
+    suspicion: DashMap<NodeId, f64>,
 }
 
 impl ClusterMembership {
@@ -44,12 +145,98 @@ impl ClusterMembership {
         Self {
             nodes: DashMap::new(),
             local_node_id,
+            events: ClusterEvents::new(),
+            flaps: FlapTracker::default(),
+            suspicion: DashMap::new(),
         }
     }
 
+    /// Quarantine nodes that flap as defined by `config`
+    pub fn with_flap_config(mut self, config: FlapConfig) -> Self {
+        self.flaps = FlapTracker::new(config);
+        self
+    }
+
+    /// Publish membership changes to `events`
+    pub fn with_events(mut self, events: ClusterEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Event bus membership changes are published to
+    pub fn events(&self) -> &ClusterEvents {
+        &self.events
+    }
+
     /// Add or update a node
     pub fn upsert_node(&self, node: ClusterNode) {
-        self.nodes.insert(node.id, node);
+        let (id, status) = (node.id, node.status.clone());
+        match self.nodes.insert(id, node) {
+            None => self.events.emit(ClusterEvent::NodeJoined { node_id: id }),
+            Some(previous) if previous.status != status => self.status_changed(id, previous.status, status),
+            Some(_) => {}
+        }
+    }
+
+    /// Publish a status change and quarantine the node if it flaps
+    fn status_changed(&self, id: NodeId, from: NodeStatus, to: NodeStatus) {
+        let flip = matches!(
+            (&from, &to),
+            (NodeStatus::Healthy, NodeStatus::Suspect) | (NodeStatus::Suspect, NodeStatus::Healthy)
+        );
+        self.events.status_changed(id, from, to);
+        if !flip || !self.flaps.record_at(id, Instant::now()) {
+            return;
+        }
+
+        let newly = match self.nodes.get_mut(&id) {
+            Some(mut node) => !std::mem::replace(&mut node.quarantined, true),
+            None => false,
+        };
+        if newly {
+            tracing::warn!(node_id = %id, "node is flapping, quarantined from placement");
+            self.events.emit(ClusterEvent::NodeQuarantined { node_id: id });
+        }
+    }
+
+    /// Release quarantined nodes that stopped flapping
+    pub fn release_stable(&self) -> Vec<NodeId> {
+        let now = Instant::now();
+        let released: Vec<_> = self
+            .nodes
+            .iter_mut()
+            .filter_map(|mut node| {
+                if !node.quarantined || !self.flaps.is_stable_at(node.id, now) {
+                    return None;
+                }
+                node.quarantined = false;
+                Some(node.id)
+            })
+            .collect();
+        for node_id in &released {
+            tracing::info!(%node_id, "node stable again, released from quarantine");
+            self.events.emit(ClusterEvent::NodeReleased { node_id: *node_id });
+        }
+        released
+    }
+
+    /// Change a node's status, publishing the change
+    fn set_status(&self, id: NodeId, status: NodeStatus) {
+        let from = {
+            let Some(mut node) = self.nodes.get_mut(&id) else {
+                return;
+            };
+            if node.status == status {
+                return;
+            }
+            std::mem::replace(&mut node.status, status.clone())
+        };
+        self.status_changed(id, from, status);
+    }
+
+    /// Get the local node ID
+    pub fn local_node_id(&self) -> NodeId {
+        self.local_node_id
     }
 
     /// Get a node by ID
@@ -57,6 +244,11 @@ impl ClusterMembership {
         self.nodes.get(&id).map(|n| n.clone())
     }
 
+    /// Get all known nodes
+    pub fn all_nodes(&self) -> Vec<ClusterNode> {
+        self.nodes.iter().map(|n| n.clone()).collect()
+    }
+
     /// Get all healthy nodes
     pub fn healthy_nodes(&self) -> Vec<ClusterNode> {
         self.nodes
@@ -68,31 +260,183 @@ impl ClusterMembership {
 
     /// Mark a node as suspect
     pub fn mark_suspect(&self, id: NodeId) {
-        if let Some(mut node) = self.nodes.get_mut(&id) {
-            node.status = NodeStatus::Suspect;
-        }
+        self.set_status(id, NodeStatus::Suspect);
     }
 
     /// Mark a node as down
     pub fn mark_down(&self, id: NodeId) {
+        self.set_status(id, NodeStatus::Down);
+    }
+
+    /// Mark a node as leaving
+    pub fn mark_leaving(&self, id: NodeId) {
+        self.set_status(id, NodeStatus::Leaving);
+    }
+
+    /// Put a node into maintenance or take it out again
+    ///
+    /// A node leaving maintenance is healthy until heartbeats say otherwise.
+    pub fn set_maintenance(&self, id: NodeId, enabled: bool) -> Result<()> {
+        let status = self.get_node(id).ok_or(VRaftError::NodeUnreachable(id))?.status;
+        match (enabled, status) {
+            (true, NodeStatus::Leaving) => Err(VRaftError::InvalidConfig(format!("node {} is leaving", id))),
+            (true, _) => {
+                self.set_status(id, NodeStatus::Maintenance);
+                Ok(())
+            }
+            (false, NodeStatus::Maintenance) => {
+                self.set_status(id, NodeStatus::Healthy);
+                Ok(())
+            }
+            (false, _) => Ok(()),
+        }
+    }
+
+    /// Set the region, zone and rack a node reported
+    pub fn set_failure_domain(&self, id: NodeId, region: Option<String>, zone: Option<String>, rack: Option<String>) {
+        if let Some(mut node) = self.nodes.get_mut(&id) {
+            node.region = region;
+            node.zone = zone;
+            node.rack = rack;
+        }
+    }
+
+    /// Set the groups a node reported leading
+    pub fn set_leading(&self, id: NodeId, leading: Vec<LeaderClaim>) {
+        if let Some(mut node) = self.nodes.get_mut(&id) {
+            node.leading = leading;
+        }
+    }
+
+    /// Set the load a node reported
+    pub fn set_stats(&self, id: NodeId, stats: NodeStats) {
+        if let Some(mut node) = self.nodes.get_mut(&id) {
+            node.stats = stats;
+        }
+    }
+
+    /// Set the version a node reported
+    pub fn set_version(&self, id: NodeId, version: Option<NodeVersion>) {
         if let Some(mut node) = self.nodes.get_mut(&id) {
-            node.status = NodeStatus::Down;
+            node.version = version;
+        }
+    }
+
+    /// Set the suspicion level the failure detector grades a node with;
+    /// `None` if it does not grade the node
+    pub fn set_suspicion(&self, id: NodeId, suspicion: Option<f64>) {
+        match suspicion {
+            Some(phi) if self.nodes.contains_key(&id) => {
+                self.suspicion.insert(id, phi);
+            }
+            _ => {
+                self.suspicion.remove(&id);
+            }
         }
     }
 
+    /// Suspicion level (phi) of a node; `None` if the failure detector
+    /// does not grade it
+    pub fn suspicion(&self, id: NodeId) -> Option<f64> {
+        self.suspicion.get(&id).map(|phi| *phi)
+    }
+
+    /// Whether a node reported supporting an optional feature
+    pub fn node_supports(&self, id: NodeId, feature: &str) -> bool {
+        self.nodes
+            .get(&id)
+            .and_then(|n| n.version.as_ref().map(|v| v.supports(feature)))
+            .unwrap_or(false)
+    }
+
+    /// Whether every node that is not down supports an optional feature
+    ///
+    /// Features that change what all replicas must understand, such as
+    /// storage formats, are only turned on once this holds.
+    pub fn cluster_supports(&self, feature: &str) -> bool {
+        self.nodes
+            .iter()
+            .filter(|n| n.status != NodeStatus::Down)
+            .all(|n| n.version.as_ref().is_some_and(|v| v.supports(feature)))
+    }
+
+    /// Record that a group's replica moved from one node to another
+    pub fn move_replica(&self, group_id: RaftGroupId, from: NodeId, to: NodeId) {
+        if let Some(mut node) = self.nodes.get_mut(&from) {
+            node.raft_groups.retain(|g| *g != group_id);
+        }
+        if let Some(mut node) = self.nodes.get_mut(&to) {
+            if !node.raft_groups.contains(&group_id) {
+                node.raft_groups.push(group_id);
+            }
+        }
+        self.events.emit(ClusterEvent::GroupMoved { group_id, from, to });
+    }
+
     /// Update heartbeat for a node
+    ///
+    /// A reachable node that was joining, suspect or down becomes healthy.
     pub fn update_heartbeat(&self, id: NodeId) {
-        if let Some(mut node) = self.nodes.get_mut(&id) {
-            node.last_heartbeat = Timestamp::now();
-            if node.status == NodeStatus::Suspect {
-                node.status = NodeStatus::Healthy;
+        let recovered = match self.nodes.get_mut(&id) {
+            Some(mut node) => {
+                node.last_heartbeat = Timestamp::now();
+                matches!(node.status, NodeStatus::Suspect | NodeStatus::Down | NodeStatus::Joining)
             }
+            None => false,
+        };
+        if recovered {
+            self.set_status(id, NodeStatus::Healthy);
+        }
+    }
+
+    /// Reconcile with the nodes reported by service discovery
+    ///
+    /// New nodes are added as joining and moved nodes get their new address.
+    /// Known remote nodes that are no longer registered are marked down.
+    pub fn sync_discovered(&self, discovered: &[(NodeId, SocketAddr)]) {
+        for (id, addr) in discovered {
+            match self.nodes.get_mut(id) {
+                Some(mut node) => node.addr = *addr,
+                None => self.upsert_node(ClusterNode::new(*id, *addr)),
+            }
+        }
+
+        let gone: Vec<_> = self
+            .nodes
+            .iter()
+            .filter(|node| node.id != self.local_node_id && !discovered.iter().any(|(id, _)| *id == node.id))
+            .map(|node| node.id)
+            .collect();
+        for id in gone {
+            self.set_status(id, NodeStatus::Down);
+        }
+    }
+
+    /// Add the nodes known before a restart
+    ///
+    /// Nodes already known, including this one, are kept. Nodes that were
+    /// up have not been heard from since and rejoin as joining; leaving,
+    /// down and maintenance nodes keep their status.
+    pub fn restore(&self, nodes: Vec<ClusterNode>) {
+        for mut node in nodes {
+            if node.id == self.local_node_id || self.nodes.contains_key(&node.id) {
+                continue;
+            }
+            if matches!(node.status, NodeStatus::Healthy | NodeStatus::Suspect) {
+                node.status = NodeStatus::Joining;
+            }
+            node.leading.clear();
+            self.upsert_node(node);
         }
     }
 
     /// Remove a node
     pub fn remove_node(&self, id: NodeId) {
-        self.nodes.remove(&id);
+        self.flaps.remove(id);
+        self.suspicion.remove(&id);
+        if self.nodes.remove(&id).is_some() {
+            self.events.emit(ClusterEvent::NodeLeft { node_id: id });
+        }
     }
 
     /// Get node count
@@ -108,3 +452,175 @@ impl ClusterMembership {
             .count()
     }
 }
+
+/// File holding the known nodes across restarts
+pub struct MembershipStore {
+    path: PathBuf,
+}
+
+impl MembershipStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Load the nodes saved last; none if nothing was saved yet
+    pub fn load(&self) -> io::Result<Vec<ClusterNode>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replace the saved nodes
+    pub fn save(&self, nodes: &[ClusterNode]) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(nodes).map_err(io::Error::other)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Save the membership whenever nodes join, leave or change status, and
+/// every `interval` to catch address and load updates
+///
+/// Stops once the event bus is dropped.
+pub fn spawn_membership_persistence(
+    membership: Arc<ClusterMembership>,
+    store: MembershipStore,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut events = membership.events().subscribe();
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(ClusterEvent::NodeJoined { .. } | ClusterEvent::NodeLeft { .. } | ClusterEvent::NodeStatusChanged { .. })
+                    | Err(RecvError::Lagged(_)) => {}
+                    Ok(_) => continue,
+                    Err(RecvError::Closed) => break,
+                },
+                _ = ticker.tick() => {}
+            }
+
+            if let Err(e) = store.save(&membership.all_nodes()) {
+                tracing::warn!(error = %e, "failed to persist cluster membership");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::FEATURE_VFS_TRANSACTIONS;
+
+    fn node(id: u64, status: NodeStatus) -> ClusterNode {
+        ClusterNode {
+            status,
+            raft_groups: vec![RaftGroupId::new(1)],
+            ..ClusterNode::new(NodeId::new(id), format!("127.0.0.1:{}", 8000 + id).parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_restore() {
+        let membership = ClusterMembership::new(NodeId::new(1));
+        membership.upsert_node(node(1, NodeStatus::Healthy));
+
+        membership.restore(vec![
+            node(1, NodeStatus::Down),
+            node(2, NodeStatus::Healthy),
+            node(3, NodeStatus::Leaving),
+        ]);
+
+        let status = |id| membership.get_node(NodeId::new(id)).unwrap().status;
+        assert_eq!(status(1), NodeStatus::Healthy);
+        assert_eq!(status(2), NodeStatus::Joining);
+        assert_eq!(status(3), NodeStatus::Leaving);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let membership = ClusterMembership::new(NodeId::new(1));
+        membership.upsert_node(node(2, NodeStatus::Healthy));
+        membership.upsert_node(node(3, NodeStatus::Leaving));
+        let status = |id| membership.get_node(NodeId::new(id)).unwrap().status;
+
+        membership.set_maintenance(NodeId::new(2), true).unwrap();
+        assert_eq!(status(2), NodeStatus::Maintenance);
+        assert!(membership.healthy_nodes().iter().all(|n| n.id != NodeId::new(2)));
+
+        // Heartbeats do not end maintenance
+        membership.update_heartbeat(NodeId::new(2));
+        assert_eq!(status(2), NodeStatus::Maintenance);
+
+        membership.set_maintenance(NodeId::new(2), false).unwrap();
+        assert_eq!(status(2), NodeStatus::Healthy);
+
+        assert!(membership.set_maintenance(NodeId::new(3), true).is_err());
+        assert!(membership.set_maintenance(NodeId::new(4), true).is_err());
+    }
+
+    #[test]
+    fn test_suspicion() {
+        let membership = ClusterMembership::new(NodeId::new(1));
+        membership.upsert_node(node(2, NodeStatus::Healthy));
+
+        membership.set_suspicion(NodeId::new(2), Some(1.5));
+        assert_eq!(membership.suspicion(NodeId::new(2)), Some(1.5));
+        membership.set_suspicion(NodeId::new(2), None);
+        assert_eq!(membership.suspicion(NodeId::new(2)), None);
+
+        // Unknown and removed nodes are not graded
+        membership.set_suspicion(NodeId::new(3), Some(1.5));
+        assert_eq!(membership.suspicion(NodeId::new(3)), None);
+        membership.set_suspicion(NodeId::new(2), Some(2.0));
+        membership.remove_node(NodeId::new(2));
+        assert_eq!(membership.suspicion(NodeId::new(2)), None);
+    }
+
+    #[test]
+    fn test_cluster_supports() {
+        let membership = ClusterMembership::new(NodeId::new(1));
+        for id in 1..=3 {
+            membership.upsert_node(node(id, NodeStatus::Healthy));
+            membership.set_version(NodeId::new(id), Some(NodeVersion::current()));
+        }
+        assert!(membership.cluster_supports(FEATURE_VFS_TRANSACTIONS));
+
+        // A node on an older build holds the feature back until it is down
+        let mut old = NodeVersion::current();
+        old.features.remove(FEATURE_VFS_TRANSACTIONS);
+        membership.set_version(NodeId::new(3), Some(old));
+        assert!(membership.node_supports(NodeId::new(2), FEATURE_VFS_TRANSACTIONS));
+        assert!(!membership.cluster_supports(FEATURE_VFS_TRANSACTIONS));
+
+        membership.mark_down(NodeId::new(3));
+        assert!(membership.cluster_supports(FEATURE_VFS_TRANSACTIONS));
+    }
+
+    #[test]
+    fn test_quarantine_flapping_node() {
+        let membership = ClusterMembership::new(NodeId::new(1)).with_flap_config(FlapConfig {
+            threshold: 2,
+            window: Duration::from_secs(60),
+            cooldown: Duration::ZERO,
+        });
+        membership.upsert_node(node(2, NodeStatus::Healthy));
+        let node_2 = || membership.get_node(NodeId::new(2)).unwrap();
+
+        membership.mark_suspect(NodeId::new(2));
+        membership.update_heartbeat(NodeId::new(2));
+        assert!(node_2().is_placeable());
+
+        membership.mark_suspect(NodeId::new(2));
+        assert!(node_2().quarantined);
+        membership.update_heartbeat(NodeId::new(2));
+        assert!(!node_2().is_placeable());
+
+        assert_eq!(membership.release_stable(), vec![NodeId::new(2)]);
+        assert!(node_2().is_placeable());
+    }
+}
@@ -1,49 +1,262 @@
 //! Metadata Raft group management
 
-use std::collections::HashMap;
+use crate::events::{ClusterEvent, ClusterEvents};
+use crate::hash_ring::PlacementRing;
+use crate::health::DegradedGroup;
+use crate::membership::ClusterNode;
+use crate::placement::{PlacementPolicy, ZoneAwarePlacement};
+use crate::routing::{RoutingDelta, RoutingTable};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use vraftls_core::{NodeId, PartitionKey, RaftGroupId};
+use tokio::sync::{watch, RwLock};
+use vraftls_core::{NodeId, PartitionKey, RaftGroupId, Result, SharedConfig, SharedConfigChange, VRaftError};
+
+/// HTTP path serving the settings shared by the cluster
+pub const SHARED_CONFIG_PATH: &str = "/admin/config";
+
+/// Body of a `409 Conflict` answer to a shared config change sent to a node
+/// that does not lead the metadata group
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MetadataLeaderHint {
+    /// The metadata group's leader as the node knows it
+    pub leader: Option<NodeId>,
+
+    /// Address of that leader, if it is a known member
+    pub addr: Option<SocketAddr>,
+}
 
 /// Routing table entry
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RoutingEntry {
     pub group_id: RaftGroupId,
     pub leader: Option<NodeId>,
     pub replicas: Vec<NodeId>,
+
+    /// Routing table version at which this entry last changed
+    #[serde(default)]
+    pub epoch: u64,
+}
+
+/// Complete metadata contents, as stored in snapshots
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MetadataState {
+    /// Partition key to Raft group mapping
+    pub routing: RoutingTable,
+
+    /// Raft group to node mapping
+    pub group_nodes: BTreeMap<RaftGroupId, Vec<NodeId>>,
+
+    /// Next Raft group ID
+    pub next_group_id: u64,
+
+    /// Placement weight of groups whose weight is not the default of 1; groups
+    /// created by a split have weight 0
+    #[serde(default)]
+    pub group_weights: BTreeMap<RaftGroupId, u32>,
+
+    /// Settings shared by the whole cluster
+    #[serde(default)]
+    pub shared_config: SharedConfig,
 }
 
 /// Cluster metadata (stored in Raft group 0)
+///
+/// On nodes hosting the metadata group this is the state applied by
+/// `MetadataStateMachine`; changes go through `MetadataProposer`.
 pub struct ClusterMetadata {
     /// Partition key to Raft group mapping
-    routing_table: RwLock<HashMap<PartitionKey, RoutingEntry>>,
+    routing_table: RwLock<RoutingTable>,
 
     /// Raft group to node mapping
     group_nodes: RwLock<HashMap<RaftGroupId, Vec<NodeId>>>,
 
     /// Next Raft group ID
     next_group_id: RwLock<u64>,
+
+    /// Placement of keys without an explicit route
+    placement: RwLock<PlacementRing>,
+
+    /// Chooses the nodes of new groups
+    placement_policy: Arc<dyn PlacementPolicy>,
+
+    /// Publishes group changes
+    events: ClusterEvents,
+
+    /// Groups found degraded by this node; not replicated
+    degraded: RwLock<HashMap<RaftGroupId, DegradedGroup>>,
+
+    /// Settings shared by the whole cluster
+    shared_config: watch::Sender<SharedConfig>,
 }
 
 impl ClusterMetadata {
     pub fn new() -> Self {
         Self {
-            routing_table: RwLock::new(HashMap::new()),
+            routing_table: RwLock::new(RoutingTable::new()),
             group_nodes: RwLock::new(HashMap::new()),
             next_group_id: RwLock::new(1), // 0 is reserved for metadata group
+            placement: RwLock::new(PlacementRing::new()),
+            placement_policy: Arc::new(ZoneAwarePlacement::default()),
+            events: ClusterEvents::new(),
+            degraded: RwLock::new(HashMap::new()),
+            shared_config: watch::channel(SharedConfig::default()).0,
         }
     }
 
+    /// Publish group changes to `events`
+    pub fn with_events(mut self, events: ClusterEvents) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Set the policy choosing the nodes of new groups
+    pub fn with_placement_policy(mut self, policy: Arc<dyn PlacementPolicy>) -> Self {
+        self.placement_policy = policy;
+        self
+    }
+
+    /// Choose the nodes for a new group's replicas
+    pub fn place_group(&self, nodes: &[ClusterNode], replicas: usize) -> Result<Vec<NodeId>> {
+        self.placement_policy.choose_replicas(nodes, replicas)
+    }
+
+    /// Order a new group's replicas by leadership preference
+    pub fn leader_preference(&self, nodes: &[ClusterNode], replicas: &[NodeId]) -> Vec<NodeId> {
+        self.placement_policy.choose_leader_preference(nodes, replicas)
+    }
+
     /// Lookup the Raft group for a partition key
+    ///
+    /// Keys without an explicit route are placed on the hash ring; such
+    /// entries carry epoch 0.
     pub async fn lookup(&self, key: &PartitionKey) -> Option<RoutingEntry> {
-        let table = self.routing_table.read().await;
-        table.get(key).cloned()
+        if let Some(entry) = self.routing_table.read().await.get(key) {
+            return Some(entry.clone());
+        }
+
+        let group_id = self.placement.read().await.group_for(key)?;
+        Some(RoutingEntry {
+            group_id,
+            leader: None,
+            replicas: self.get_group_nodes(group_id).await,
+            epoch: 0,
+        })
     }
 
-    /// Update routing entry
-    pub async fn update_routing(&self, key: PartitionKey, entry: RoutingEntry) {
+    /// Update routing entry; returns the epoch it was given
+    pub async fn update_routing(&self, key: PartitionKey, entry: RoutingEntry) -> u64 {
         let mut table = self.routing_table.write().await;
-        table.insert(key, entry);
+        table.update(key, entry)
+    }
+
+    /// Remove a routing entry
+    pub async fn remove_routing(&self, key: &PartitionKey) {
+        self.routing_table.write().await.remove(key);
+    }
+
+    /// Current routing table version
+    pub async fn routing_version(&self) -> u64 {
+        self.routing_table.read().await.version()
+    }
+
+    /// Routing changes since `version`
+    pub async fn routing_delta(&self, version: u64) -> RoutingDelta {
+        self.routing_table.read().await.delta(version)
+    }
+
+    /// Reject a request routed with an outdated epoch
+    pub async fn check_routing_epoch(&self, key: &PartitionKey, epoch: u64) -> Result<()> {
+        self.routing_table.read().await.check_epoch(key, epoch)
+    }
+
+    /// Set the nodes hosting a Raft group
+    ///
+    /// A group with nodes takes part in placement; one without is taken off
+    /// the hash ring.
+    pub async fn set_group_nodes(&self, group_id: RaftGroupId, nodes: Vec<NodeId>) {
+        let mut placement = self.placement.write().await;
+        if nodes.is_empty() {
+            placement.remove_group(group_id);
+            self.group_nodes.write().await.remove(&group_id);
+        } else {
+            if placement.weight(group_id) == 0 {
+                placement.set_group(group_id, 1);
+            }
+            let created = self.group_nodes.write().await.insert(group_id, nodes.clone()).is_none();
+            if created {
+                self.events.emit(ClusterEvent::GroupCreated { group_id, nodes });
+            }
+        }
+    }
+
+    /// Set the share of keys a hosted Raft group is placed
+    pub async fn set_group_weight(&self, group_id: RaftGroupId, weight: u32) {
+        if self.group_nodes.read().await.contains_key(&group_id) {
+            self.placement
+                .write()
+                .await
+                .set_group(group_id, weight.max(1));
+        }
+    }
+
+    /// Settings shared by the whole cluster
+    pub fn shared_config(&self) -> SharedConfig {
+        self.shared_config.borrow().clone()
+    }
+
+    /// Receive the shared settings whenever a change is applied
+    pub fn subscribe_shared_config(&self) -> watch::Receiver<SharedConfig> {
+        self.shared_config.subscribe()
+    }
+
+    /// Change one shared setting
+    pub fn update_shared_config(&self, change: SharedConfigChange) {
+        self.shared_config.send_modify(|config| config.apply(change));
+    }
+
+    /// Move keys split off `source` to the new group `target`
+    ///
+    /// `target` is hosted by the same nodes as `source` and serves only the
+    /// keys routed to it explicitly; it is kept off the hash ring so that no
+    /// other key changes placement. Returns the epoch of the new routes.
+    pub async fn split_group(&self, source: RaftGroupId, target: RaftGroupId, keys: Vec<PartitionKey>) -> u64 {
+        let replicas = self.get_group_nodes(source).await;
+        self.group_nodes.write().await.insert(target, replicas.clone());
+        self.events.emit(ClusterEvent::GroupCreated {
+            group_id: target,
+            nodes: replicas.clone(),
+        });
+
+        let mut table = self.routing_table.write().await;
+        let mut epoch = table.version();
+        for key in keys {
+            epoch = table.update(
+                key,
+                RoutingEntry {
+                    group_id: target,
+                    leader: None,
+                    replicas: replicas.clone(),
+                    epoch: 0,
+                },
+            );
+        }
+        epoch
+    }
+
+    /// Raft groups with assigned nodes, excluding the metadata group
+    pub async fn groups(&self) -> Vec<RaftGroupId> {
+        let mut groups: Vec<_> = self
+            .group_nodes
+            .read()
+            .await
+            .keys()
+            .copied()
+            .filter(|group_id| *group_id != RaftGroupId::METADATA)
+            .collect();
+        groups.sort();
+        groups
     }
 
     /// Get nodes for a Raft group
@@ -52,6 +265,37 @@ impl ClusterMetadata {
         groups.get(&group_id).cloned().unwrap_or_default()
     }
 
+    /// Record a group as degraded
+    pub async fn mark_degraded(&self, group: DegradedGroup) {
+        self.degraded.write().await.insert(group.group_id, group);
+    }
+
+    /// Record a group as healthy again
+    pub async fn clear_degraded(&self, group_id: RaftGroupId) {
+        self.degraded.write().await.remove(&group_id);
+    }
+
+    /// Degraded state of a group, if any
+    pub async fn degraded_group(&self, group_id: RaftGroupId) -> Option<DegradedGroup> {
+        self.degraded.read().await.get(&group_id).cloned()
+    }
+
+    /// All degraded groups
+    pub async fn degraded_groups(&self) -> Vec<DegradedGroup> {
+        self.degraded.read().await.values().cloned().collect()
+    }
+
+    /// Fail fast if a group cannot safely accept writes
+    pub async fn check_writable(&self, group_id: RaftGroupId) -> Result<()> {
+        match self.degraded.read().await.get(&group_id) {
+            Some(degraded) => Err(VRaftError::GroupDegraded {
+                group: group_id,
+                reason: degraded.reason.to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
     /// Allocate a new Raft group ID
     pub async fn allocate_group_id(&self) -> RaftGroupId {
         let mut next_id = self.next_group_id.write().await;
@@ -59,6 +303,60 @@ impl ClusterMetadata {
         *next_id += 1;
         RaftGroupId::new(id)
     }
+
+    /// Copy of the whole metadata
+    pub async fn state(&self) -> MetadataState {
+        MetadataState {
+            routing: self.routing_table.read().await.clone(),
+            group_nodes: self
+                .group_nodes
+                .read()
+                .await
+                .iter()
+                .map(|(k, v)| (*k, v.clone()))
+                .collect(),
+            next_group_id: *self.next_group_id.read().await,
+            group_weights: {
+                let placement = self.placement.read().await;
+                self.group_nodes
+                    .read()
+                    .await
+                    .keys()
+                    .map(|group_id| (*group_id, placement.weight(*group_id)))
+                    .filter(|(_, weight)| *weight != 1)
+                    .collect()
+            },
+            shared_config: self.shared_config(),
+        }
+    }
+
+    /// Replace the whole metadata
+    pub async fn restore(&self, state: MetadataState) {
+        let mut placement = PlacementRing::new();
+        for (group_id, nodes) in &state.group_nodes {
+            if !nodes.is_empty() {
+                placement.set_group(
+                    *group_id,
+                    state
+                        .group_weights
+                        .get(group_id)
+                        .copied()
+                        .unwrap_or(1)
+                        .max(1),
+                );
+            }
+        }
+
+        *self.routing_table.write().await = state.routing;
+        *self.placement.write().await = placement;
+        *self.group_nodes.write().await = state.group_nodes.into_iter().collect();
+        *self.next_group_id.write().await = state.next_group_id.max(1);
+        self.shared_config.send_if_modified(|config| {
+            let changed = *config != state.shared_config;
+            *config = state.shared_config;
+            changed
+        });
+    }
 }
 
 impl Default for ClusterMetadata {
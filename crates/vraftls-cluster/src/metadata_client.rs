@@ -0,0 +1,436 @@
+//! Routing lookups for gateways
+//!
+//! Gateways resolve the group of a file on every LSP request. Asking the
+//! metadata group each time would make it the bottleneck of the cluster, so
+//! `MetadataClient` caches routes for a TTL and coalesces the lookups that
+//! arrive within a short window into one batch. A route that turns out to
+//! be wrong, because the request hit a node that no longer leads or hosts
+//! the group, is dropped so the next lookup fetches it again. Cached routes
+//! are also kept current between lookups by polling the routing changes
+//! since the table version last seen.
+
+use crate::metadata::{ClusterMetadata, RoutingEntry};
+use crate::routing::RoutingDelta;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use vraftls_core::{PartitionKey, RaftGroupId, Result, VRaftError};
+
+/// HTTP path answering batched routing lookups
+pub const ROUTING_LOOKUP_PATH: &str = "/cluster/routing/lookup";
+
+/// HTTP path serving the routing changes since a table version
+pub const ROUTING_DELTA_PATH: &str = "/cluster/routing/delta";
+
+/// Answers routing lookups in batches
+pub trait RoutingLookup: Send + Sync + 'static {
+    /// Route of each key, in the order of `keys`
+    fn lookup_routes(&self, keys: Vec<PartitionKey>) -> impl Future<Output = Result<Vec<Option<RoutingEntry>>>> + Send;
+
+    /// Routing changes since table version `since`
+    fn fetch_delta(&self, since: u64) -> impl Future<Output = Result<RoutingDelta>> + Send;
+}
+
+impl RoutingLookup for Arc<ClusterMetadata> {
+    async fn lookup_routes(&self, keys: Vec<PartitionKey>) -> Result<Vec<Option<RoutingEntry>>> {
+        let mut routes = Vec::with_capacity(keys.len());
+        for key in &keys {
+            routes.push(self.lookup(key).await);
+        }
+        Ok(routes)
+    }
+
+    async fn fetch_delta(&self, since: u64) -> Result<RoutingDelta> {
+        Ok(self.routing_delta(since).await)
+    }
+}
+
+/// Query of a routing delta request
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoutingDeltaQuery {
+    pub since: u64,
+}
+
+/// Looks routes up on cluster nodes over HTTP, trying each node in turn
+pub struct HttpRoutingLookup {
+    nodes: Vec<SocketAddr>,
+    client: reqwest::Client,
+}
+
+impl HttpRoutingLookup {
+    pub fn new(nodes: Vec<SocketAddr>, timeout: Duration) -> Self {
+        let client = reqwest::Client::builder().timeout(timeout).build().unwrap_or_default();
+        Self { nodes, client }
+    }
+}
+
+impl RoutingLookup for HttpRoutingLookup {
+    async fn lookup_routes(&self, keys: Vec<PartitionKey>) -> Result<Vec<Option<RoutingEntry>>> {
+        // Every node applies the metadata group, so any of them can answer
+        for addr in &self.nodes {
+            let response = self
+                .client
+                .post(format!("http://{}{}", addr, ROUTING_LOOKUP_PATH))
+                .json(&keys)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            match response {
+                Ok(response) => {
+                    return response
+                        .json()
+                        .await
+                        .map_err(|e| VRaftError::Serialization(e.to_string()))
+                }
+                Err(e) => tracing::debug!(%addr, error = %e, "routing lookup failed"),
+            }
+        }
+        Err(VRaftError::ConnectionFailed("no node answered the routing lookup".to_string()))
+    }
+
+    async fn fetch_delta(&self, since: u64) -> Result<RoutingDelta> {
+        for addr in &self.nodes {
+            let response = self
+                .client
+                .get(format!("http://{}{}", addr, ROUTING_DELTA_PATH))
+                .query(&RoutingDeltaQuery { since })
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            match response {
+                Ok(response) => {
+                    return response
+                        .json()
+                        .await
+                        .map_err(|e| VRaftError::Serialization(e.to_string()))
+                }
+                Err(e) => tracing::debug!(%addr, error = %e, "routing delta request failed"),
+            }
+        }
+        Err(VRaftError::ConnectionFailed("no node answered the routing delta request".to_string()))
+    }
+}
+
+/// Metadata client settings
+#[derive(Clone, Debug)]
+pub struct MetadataClientConfig {
+    /// How long a cached route is used
+    pub ttl: Duration,
+
+    /// How long a lookup waits for others to join its batch
+    pub batch_window: Duration,
+
+    /// Interval between polls for routing changes
+    pub refresh_interval: Duration,
+}
+
+impl Default for MetadataClientConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(30),
+            batch_window: Duration::from_millis(2),
+            refresh_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+type Waiter = oneshot::Sender<Result<Option<RoutingEntry>>>;
+
+struct ClientInner<S> {
+    source: S,
+    config: MetadataClientConfig,
+
+    /// Routes with the time they were fetched
+    cache: DashMap<PartitionKey, (RoutingEntry, Instant)>,
+
+    /// Lookups waiting for the next batch; `None` while no batch is scheduled
+    pending: Mutex<Option<HashMap<PartitionKey, Vec<Waiter>>>>,
+
+    /// Routing table version the cache was last refreshed to
+    version: AtomicU64,
+}
+
+/// Caching, batching routing client embedded in gateways; clones share the cache
+pub struct MetadataClient<S> {
+    inner: Arc<ClientInner<S>>,
+}
+
+impl<S> Clone for MetadataClient<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<S: RoutingLookup> MetadataClient<S> {
+    pub fn new(source: S, config: MetadataClientConfig) -> Self {
+        Self {
+            inner: Arc::new(ClientInner {
+                source,
+                config,
+                cache: DashMap::new(),
+                pending: Mutex::new(None),
+                version: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Route of a key, from the cache while it is fresh
+    pub async fn lookup(&self, key: &PartitionKey) -> Result<Option<RoutingEntry>> {
+        if let Some(entry) = self.cached(key) {
+            return Ok(Some(entry));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let schedule = {
+            let mut pending = self.inner.pending.lock().unwrap();
+            let schedule = pending.is_none();
+            pending.get_or_insert_with(HashMap::new).entry(key.clone()).or_default().push(tx);
+            schedule
+        };
+        // The first lookup of a batch sends it; a task, so that dropping
+        // this lookup does not strand the others
+        if schedule {
+            let inner = self.inner.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(inner.config.batch_window).await;
+                inner.flush().await;
+            });
+        }
+
+        rx.await
+            .map_err(|_| VRaftError::Internal("routing batch dropped".to_string()))?
+    }
+
+    /// Cached route of a key, if still fresh
+    fn cached(&self, key: &PartitionKey) -> Option<RoutingEntry> {
+        let entry = self.inner.cache.get(key)?;
+        let (route, fetched) = entry.value();
+        (fetched.elapsed() < self.inner.config.ttl).then(|| route.clone())
+    }
+
+    /// Drop a key's route after a request routed with it failed
+    ///
+    /// Returns whether the error means the route is outdated.
+    pub fn report_error(&self, key: &PartitionKey, error: &VRaftError) -> bool {
+        let outdated = matches!(
+            error,
+            VRaftError::NotLeader { .. } | VRaftError::StaleRouting { .. } | VRaftError::GroupNotFound(_)
+        );
+        if outdated {
+            self.inner.cache.remove(key);
+        }
+        outdated
+    }
+
+    /// Drop the routes to a group, e.g. after it moved or changed leader
+    pub fn invalidate_group(&self, group_id: RaftGroupId) {
+        self.inner.cache.retain(|_, (route, _)| route.group_id != group_id);
+    }
+
+    /// Drop every cached route
+    pub fn clear(&self) {
+        self.inner.cache.clear();
+    }
+
+    /// Number of cached routes, fresh or not
+    pub fn cached_routes(&self) -> usize {
+        self.inner.cache.len()
+    }
+
+    /// Bring the cached routes up to date with the routing changes since
+    /// the last refresh
+    ///
+    /// Changed routes are replaced and removed ones dropped; keys not cached
+    /// stay uncached. A full delta also drops every cached key it omits.
+    pub async fn refresh(&self) -> Result<()> {
+        let since = self.inner.version.load(Ordering::SeqCst);
+        let delta = self.inner.source.fetch_delta(since).await?;
+        let fetched = Instant::now();
+
+        if delta.full {
+            let listed: HashSet<_> = delta.updated.iter().map(|(key, _)| key).collect();
+            self.inner.cache.retain(|key, _| listed.contains(key));
+        }
+        for key in &delta.removed {
+            self.inner.cache.remove(key);
+        }
+        for (key, route) in delta.updated {
+            if let Some(mut cached) = self.inner.cache.get_mut(&key) {
+                *cached = (route, fetched);
+            }
+        }
+        self.inner.version.store(delta.version, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Refresh the cached routes every `refresh_interval` until the task
+    /// is aborted
+    pub fn spawn_refresh(&self) -> JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(client.inner.config.refresh_interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = client.refresh().await {
+                    tracing::debug!(error = %e, "routing refresh failed");
+                }
+            }
+        })
+    }
+}
+
+impl<S: RoutingLookup> ClientInner<S> {
+    /// Send the pending lookups as one batch and answer their callers
+    async fn flush(&self) {
+        let Some(pending) = self.pending.lock().unwrap().take() else {
+            return;
+        };
+        let (keys, waiters): (Vec<_>, Vec<_>) = pending.into_iter().unzip();
+
+        match self.source.lookup_routes(keys.clone()).await {
+            Ok(routes) => {
+                let fetched = Instant::now();
+                let mut routes = routes.into_iter();
+                for (key, waiters) in keys.into_iter().zip(waiters) {
+                    let route = routes.next().flatten();
+                    if let Some(route) = &route {
+                        self.cache.insert(key, (route.clone(), fetched));
+                    }
+                    for waiter in waiters {
+                        let _ = waiter.send(Ok(route.clone()));
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, keys = keys.len(), "routing lookup failed");
+                for waiter in waiters.into_iter().flatten() {
+                    let _ = waiter.send(Err(copy_error(&e)));
+                }
+            }
+        }
+    }
+}
+
+/// An error for each caller of a failed batch; errors are not `Clone`
+fn copy_error(error: &VRaftError) -> VRaftError {
+    match error {
+        VRaftError::NotLeader { leader } => VRaftError::NotLeader { leader: *leader },
+        VRaftError::NodeUnreachable(node) => VRaftError::NodeUnreachable(*node),
+        VRaftError::Timeout => VRaftError::Timeout,
+        VRaftError::ConnectionFailed(message) => VRaftError::ConnectionFailed(message.clone()),
+        other => VRaftError::Internal(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use vraftls_core::NodeId;
+
+    /// Routes every key to group 1 and counts the batches
+    struct CountingLookup(Arc<AtomicUsize>);
+
+    impl RoutingLookup for CountingLookup {
+        async fn lookup_routes(&self, keys: Vec<PartitionKey>) -> Result<Vec<Option<RoutingEntry>>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(keys
+                .iter()
+                .map(|_| {
+                    Some(RoutingEntry {
+                        group_id: RaftGroupId::new(1),
+                        leader: Some(NodeId::new(1)),
+                        replicas: vec![NodeId::new(1)],
+                        epoch: 1,
+                    })
+                })
+                .collect())
+        }
+
+        async fn fetch_delta(&self, since: u64) -> Result<RoutingDelta> {
+            Ok(RoutingDelta {
+                version: since,
+                full: false,
+                updated: Vec::new(),
+                removed: Vec::new(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_batches_and_caches() {
+        let batches = Arc::new(AtomicUsize::new(0));
+        let client = MetadataClient::new(CountingLookup(batches.clone()), MetadataClientConfig::default());
+        let (a, b) = (PartitionKey::from_path("/a.rs"), PartitionKey::from_path("/b.rs"));
+
+        let (ra, rb) = tokio::join!(client.lookup(&a), client.lookup(&b));
+        assert!(ra.unwrap().is_some() && rb.unwrap().is_some());
+        assert_eq!(batches.load(Ordering::SeqCst), 1);
+
+        client.lookup(&a).await.unwrap();
+        assert_eq!(batches.load(Ordering::SeqCst), 1);
+
+        assert!(client.report_error(&a, &VRaftError::NotLeader { leader: None }));
+        client.lookup(&a).await.unwrap();
+        assert_eq!(batches.load(Ordering::SeqCst), 2);
+
+        client.invalidate_group(RaftGroupId::new(1));
+        assert_eq!(client.cached_routes(), 0);
+    }
+
+    /// Routes every key to group 1, then moves `/a.rs` to group 2 and
+    /// removes `/b.rs` in version 2
+    struct MovingLookup;
+
+    impl RoutingLookup for MovingLookup {
+        async fn lookup_routes(&self, keys: Vec<PartitionKey>) -> Result<Vec<Option<RoutingEntry>>> {
+            Ok(keys.iter().map(|_| Some(route(1, 1))).collect())
+        }
+
+        async fn fetch_delta(&self, _since: u64) -> Result<RoutingDelta> {
+            Ok(RoutingDelta {
+                version: 2,
+                full: false,
+                updated: vec![
+                    (PartitionKey::from_path("/a.rs"), route(2, 2)),
+                    (PartitionKey::from_path("/c.rs"), route(2, 2)),
+                ],
+                removed: vec![PartitionKey::from_path("/b.rs")],
+            })
+        }
+    }
+
+    fn route(group: u64, epoch: u64) -> RoutingEntry {
+        RoutingEntry {
+            group_id: RaftGroupId::new(group),
+            leader: Some(NodeId::new(1)),
+            replicas: vec![NodeId::new(1)],
+            epoch,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_refresh_applies_delta() {
+        let client = MetadataClient::new(MovingLookup, MetadataClientConfig::default());
+        let (a, b) = (PartitionKey::from_path("/a.rs"), PartitionKey::from_path("/b.rs"));
+        client.lookup(&a).await.unwrap();
+        client.lookup(&b).await.unwrap();
+
+        client.refresh().await.unwrap();
+        assert_eq!(client.cached(&a), Some(route(2, 2)));
+        assert_eq!(client.cached(&b), None);
+
+        // Keys not looked up are not cached by a refresh
+        assert_eq!(client.cached_routes(), 1);
+    }
+}
@@ -0,0 +1,423 @@
+//! State machine of the metadata Raft group (group 0)
+//!
+//! The routing table, group→node assignments and group ID allocation are
+//! changed only by committed `MetadataCommand`s, so every node hosting the
+//! metadata group applies the same changes in the same order. Commands
+//! travel as `VfsRequestPayload::Metadata` entries, which lets the metadata
+//! group share log storage and networking with the data groups.
+
+use crate::metadata::{ClusterMetadata, MetadataState, RoutingEntry};
+use openraft::storage::{RaftSnapshotBuilder, RaftStateMachine, Snapshot};
+use openraft::{
+    Entry, EntryPayload, ErrorSubject, ErrorVerb, LogId, OptionalSend, SnapshotMeta, StorageError,
+    StoredMembership,
+};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use vraftls_core::{NodeId, PartitionKey, RaftGroupId, Result, SharedConfigChange, VRaftError};
+use vraftls_raft::proposal::map_write_error;
+use vraftls_raft::{
+    RaftNodeId, SnapshotStore, VRaftNode, VRaftRaft, VRaftTypeConfig, VfsRequest, VfsRequestPayload,
+    VfsStateMachineResponse,
+};
+use vraftls_vfs::{VfsCommandError, VfsResponse};
+
+/// Change to the cluster metadata
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MetadataCommand {
+    /// Route a partition key to a Raft group
+    UpdateRouting { key: PartitionKey, entry: RoutingEntry },
+
+    /// Drop a partition key's route
+    RemoveRouting { key: PartitionKey },
+
+    /// Set the nodes hosting a Raft group
+    AssignGroup { group_id: RaftGroupId, nodes: Vec<NodeId> },
+
+    /// Set the placement weight of a Raft group
+    SetGroupWeight { group_id: RaftGroupId, weight: u32 },
+
+    /// Reserve a new Raft group ID
+    AllocateGroupId,
+
+    /// Route keys split off `source` to the new group `target`, in one step
+    SplitGroup {
+        source: RaftGroupId,
+        target: RaftGroupId,
+        keys: Vec<PartitionKey>,
+    },
+
+    /// Change a setting shared by the whole cluster
+    UpdateSharedConfig { change: SharedConfigChange },
+}
+
+/// Result of a metadata command
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MetadataResponse {
+    Ok,
+    RoutingUpdated { epoch: u64 },
+    GroupAllocated(RaftGroupId),
+    Error(String),
+}
+
+/// Snapshot contents of the metadata group
+#[derive(Serialize, Deserialize)]
+struct MetadataSnapshot {
+    last_applied_log: Option<LogId<RaftNodeId>>,
+    membership: StoredMembership<RaftNodeId, VRaftNode>,
+    state: MetadataState,
+}
+
+/// Applies metadata commands to `ClusterMetadata`
+///
+/// Clones share the same state; one is handed to the Raft instance.
+#[derive(Clone)]
+pub struct MetadataStateMachine {
+    metadata: Arc<ClusterMetadata>,
+    last_applied_log: Arc<RwLock<Option<LogId<RaftNodeId>>>>,
+    membership: Arc<RwLock<StoredMembership<RaftNodeId, VRaftNode>>>,
+    snapshot_store: Option<Arc<SnapshotStore>>,
+}
+
+impl MetadataStateMachine {
+    pub fn new(metadata: Arc<ClusterMetadata>) -> Self {
+        Self {
+            metadata,
+            last_applied_log: Arc::default(),
+            membership: Arc::default(),
+            snapshot_store: None,
+        }
+    }
+
+    /// Persist snapshots to `store`, restoring the latest one now
+    ///
+    /// Log entries purged after that snapshot are therefore not needed to
+    /// rebuild the metadata after a restart.
+    pub async fn with_snapshot_store(mut self, store: Arc<SnapshotStore>) -> io::Result<Self> {
+        if let Some(snapshot) = store.latest()? {
+            self.restore(&snapshot.snapshot.into_inner()).await?;
+        }
+        self.snapshot_store = Some(store);
+        Ok(self)
+    }
+
+    /// The metadata this state machine maintains
+    pub fn metadata(&self) -> &Arc<ClusterMetadata> {
+        &self.metadata
+    }
+
+    async fn restore(&self, data: &[u8]) -> io::Result<()> {
+        let snapshot: MetadataSnapshot = serde_json::from_slice(data)?;
+        *self.last_applied_log.write().await = snapshot.last_applied_log;
+        *self.membership.write().await = snapshot.membership;
+        self.metadata.restore(snapshot.state).await;
+        Ok(())
+    }
+
+    async fn apply_command(&self, command: MetadataCommand) -> MetadataResponse {
+        match command {
+            MetadataCommand::UpdateRouting { key, entry } => {
+                return MetadataResponse::RoutingUpdated {
+                    epoch: self.metadata.update_routing(key, entry).await,
+                }
+            }
+            MetadataCommand::RemoveRouting { key } => self.metadata.remove_routing(&key).await,
+            MetadataCommand::AssignGroup { group_id, nodes } => self.metadata.set_group_nodes(group_id, nodes).await,
+            MetadataCommand::SetGroupWeight { group_id, weight } => {
+                self.metadata.set_group_weight(group_id, weight).await
+            }
+            MetadataCommand::AllocateGroupId => {
+                return MetadataResponse::GroupAllocated(self.metadata.allocate_group_id().await)
+            }
+            MetadataCommand::SplitGroup { source, target, keys } => {
+                return MetadataResponse::RoutingUpdated {
+                    epoch: self.metadata.split_group(source, target, keys).await,
+                }
+            }
+            MetadataCommand::UpdateSharedConfig { change } => self.metadata.update_shared_config(change),
+        }
+        MetadataResponse::Ok
+    }
+}
+
+/// Builds snapshots from a copy of the metadata
+pub struct MetadataSnapshotBuilder {
+    snapshot: MetadataSnapshot,
+    store: Option<Arc<SnapshotStore>>,
+}
+
+impl RaftSnapshotBuilder<VRaftTypeConfig> for MetadataSnapshotBuilder {
+    async fn build_snapshot(&mut self) -> std::result::Result<Snapshot<VRaftTypeConfig>, StorageError<RaftNodeId>> {
+        let data = serde_json::to_vec(&self.snapshot)
+            .map_err(|e| StorageError::from_io_error(ErrorSubject::StateMachine, ErrorVerb::Read, e.into()))?;
+        let meta = SnapshotMeta {
+            last_log_id: self.snapshot.last_applied_log,
+            last_membership: self.snapshot.membership.clone(),
+            snapshot_id: format!(
+                "metadata-{}-{}",
+                self.snapshot.last_applied_log.map(|l| l.index).unwrap_or(0),
+                vraftls_core::Timestamp::now().0
+            ),
+        };
+
+        if let Some(store) = &self.store {
+            store.save(&meta, &data).await.map_err(|e| {
+                StorageError::from_io_error(ErrorSubject::Snapshot(Some(meta.signature())), ErrorVerb::Write, e)
+            })?;
+        }
+
+        Ok(Snapshot {
+            meta,
+            snapshot: Box::new(Cursor::new(data)),
+        })
+    }
+}
+
+impl RaftStateMachine<VRaftTypeConfig> for MetadataStateMachine {
+    type SnapshotBuilder = MetadataSnapshotBuilder;
+
+    async fn applied_state(
+        &mut self,
+    ) -> std::result::Result<
+        (Option<LogId<RaftNodeId>>, StoredMembership<RaftNodeId, VRaftNode>),
+        StorageError<RaftNodeId>,
+    > {
+        Ok((*self.last_applied_log.read().await, self.membership.read().await.clone()))
+    }
+
+    async fn apply<I>(&mut self, entries: I) -> std::result::Result<Vec<VfsStateMachineResponse>, StorageError<RaftNodeId>>
+    where
+        I: IntoIterator<Item = Entry<VRaftTypeConfig>> + OptionalSend,
+        I::IntoIter: OptionalSend,
+    {
+        let mut responses = Vec::new();
+
+        for entry in entries {
+            *self.last_applied_log.write().await = Some(entry.log_id);
+
+            let response = match entry.payload {
+                EntryPayload::Normal(VfsRequest {
+                    payload: VfsRequestPayload::Metadata(value),
+                    ..
+                }) => {
+                    let response = match serde_json::from_value(value) {
+                        Ok(command) => self.apply_command(command).await,
+                        Err(e) => MetadataResponse::Error(format!("invalid metadata command: {}", e)),
+                    };
+                    VfsStateMachineResponse {
+                        response: VfsResponse::Ok(None),
+                        metadata: serde_json::to_value(response).ok(),
+                    }
+                }
+                EntryPayload::Normal(_) => VfsStateMachineResponse::new(VfsResponse::Error(
+                    VfsCommandError::StorageError("VFS command sent to the metadata group".to_string()),
+                )),
+                EntryPayload::Membership(membership) => {
+                    *self.membership.write().await = StoredMembership::new(Some(entry.log_id), membership);
+                    VfsStateMachineResponse::new(VfsResponse::Ok(None))
+                }
+                EntryPayload::Blank => VfsStateMachineResponse::new(VfsResponse::Ok(None)),
+            };
+            responses.push(response);
+        }
+
+        Ok(responses)
+    }
+
+    async fn get_snapshot_builder(&mut self) -> Self::SnapshotBuilder {
+        MetadataSnapshotBuilder {
+            snapshot: MetadataSnapshot {
+                last_applied_log: *self.last_applied_log.read().await,
+                membership: self.membership.read().await.clone(),
+                state: self.metadata.state().await,
+            },
+            store: self.snapshot_store.clone(),
+        }
+    }
+
+    async fn begin_receiving_snapshot(&mut self) -> std::result::Result<Box<Cursor<Vec<u8>>>, StorageError<RaftNodeId>> {
+        Ok(Box::new(Cursor::new(Vec::new())))
+    }
+
+    async fn install_snapshot(
+        &mut self,
+        meta: &SnapshotMeta<RaftNodeId, VRaftNode>,
+        snapshot: Box<Cursor<Vec<u8>>>,
+    ) -> std::result::Result<(), StorageError<RaftNodeId>> {
+        let data = snapshot.into_inner();
+        if let Some(store) = &self.snapshot_store {
+            store.save(meta, &data).await.map_err(|e| {
+                StorageError::from_io_error(ErrorSubject::Snapshot(Some(meta.signature())), ErrorVerb::Write, e)
+            })?;
+        }
+
+        self.restore(&data)
+            .await
+            .map_err(|e| StorageError::from_io_error(ErrorSubject::StateMachine, ErrorVerb::Read, e))
+    }
+
+    async fn get_current_snapshot(
+        &mut self,
+    ) -> std::result::Result<Option<Snapshot<VRaftTypeConfig>>, StorageError<RaftNodeId>> {
+        if let Some(store) = &self.snapshot_store {
+            return store
+                .latest()
+                .map_err(|e| StorageError::from_io_error(ErrorSubject::Snapshot(None), ErrorVerb::Read, e));
+        }
+
+        let mut builder = self.get_snapshot_builder().await;
+        builder.build_snapshot().await.map(Some)
+    }
+}
+
+/// Proposes metadata commands to the metadata group
+#[derive(Clone)]
+pub struct MetadataProposer {
+    raft: VRaftRaft,
+}
+
+impl MetadataProposer {
+    pub fn new(raft: VRaftRaft) -> Self {
+        Self { raft }
+    }
+
+    /// Propose a command and wait until it is applied
+    pub async fn propose(&self, command: MetadataCommand) -> Result<MetadataResponse> {
+        let value = serde_json::to_value(&command).map_err(|e| VRaftError::Serialization(e.to_string()))?;
+        let request = VfsRequest {
+            group_id: RaftGroupId::METADATA,
+            payload: VfsRequestPayload::Metadata(value),
+            session: None,
+            trace: vraftls_raft::TraceContext::current().map(|c| c.child()),
+        };
+
+        let result = self.raft.client_write(request).await.map_err(map_write_error)?;
+
+        let response = result
+            .data
+            .metadata
+            .ok_or_else(|| VRaftError::Internal("metadata command had no result".to_string()))?;
+        match serde_json::from_value(response).map_err(|e| VRaftError::Serialization(e.to_string()))? {
+            MetadataResponse::Error(message) => Err(VRaftError::Internal(message)),
+            response => Ok(response),
+        }
+    }
+
+    /// Route a partition key to a Raft group; returns the entry's new epoch
+    pub async fn update_routing(&self, key: PartitionKey, entry: RoutingEntry) -> Result<u64> {
+        match self.propose(MetadataCommand::UpdateRouting { key, entry }).await? {
+            MetadataResponse::RoutingUpdated { epoch } => Ok(epoch),
+            other => Err(VRaftError::Internal(format!("unexpected metadata response: {:?}", other))),
+        }
+    }
+
+    /// Drop a partition key's route
+    pub async fn remove_routing(&self, key: PartitionKey) -> Result<()> {
+        self.propose(MetadataCommand::RemoveRouting { key }).await.map(|_| ())
+    }
+
+    /// Set the nodes hosting a Raft group
+    pub async fn assign_group(&self, group_id: RaftGroupId, nodes: Vec<NodeId>) -> Result<()> {
+        self.propose(MetadataCommand::AssignGroup { group_id, nodes }).await.map(|_| ())
+    }
+
+    /// Set the placement weight of a Raft group
+    pub async fn set_group_weight(&self, group_id: RaftGroupId, weight: u32) -> Result<()> {
+        self.propose(MetadataCommand::SetGroupWeight { group_id, weight }).await.map(|_| ())
+    }
+
+    /// Route keys split off `source` to the new group `target`; returns the
+    /// epoch of the new routes
+    pub async fn split_group(&self, source: RaftGroupId, target: RaftGroupId, keys: Vec<PartitionKey>) -> Result<u64> {
+        match self.propose(MetadataCommand::SplitGroup { source, target, keys }).await? {
+            MetadataResponse::RoutingUpdated { epoch } => Ok(epoch),
+            other => Err(VRaftError::Internal(format!("unexpected metadata response: {:?}", other))),
+        }
+    }
+
+    /// Change a setting shared by the whole cluster
+    pub async fn update_shared_config(&self, change: SharedConfigChange) -> Result<()> {
+        self.propose(MetadataCommand::UpdateSharedConfig { change })
+            .await
+            .map(|_| ())
+    }
+
+    /// Whether this node leads the metadata group
+    pub fn is_leader(&self) -> bool {
+        let metrics = self.raft.metrics();
+        let metrics = metrics.borrow();
+        metrics.current_leader == Some(metrics.id)
+    }
+
+    /// Reserve a new Raft group ID, unique across the cluster
+    pub async fn allocate_group_id(&self) -> Result<RaftGroupId> {
+        match self.propose(MetadataCommand::AllocateGroupId).await? {
+            MetadataResponse::GroupAllocated(group_id) => Ok(group_id),
+            other => Err(VRaftError::Internal(format!("unexpected metadata response: {:?}", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openraft::{CommittedLeaderId, EntryPayload};
+
+    fn entry(index: u64, command: MetadataCommand) -> Entry<VRaftTypeConfig> {
+        Entry {
+            log_id: LogId::new(CommittedLeaderId::new(1, 1), index),
+            payload: EntryPayload::Normal(VfsRequest {
+                group_id: RaftGroupId::METADATA,
+                payload: VfsRequestPayload::Metadata(serde_json::to_value(command).unwrap()),
+                session: None,
+                trace: None,
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_and_snapshot() {
+        let mut sm = MetadataStateMachine::new(Arc::new(ClusterMetadata::new()));
+        let key = PartitionKey::from_path("/src/main.rs");
+        let routing = RoutingEntry {
+            group_id: RaftGroupId::new(1),
+            leader: None,
+            replicas: vec![NodeId::new(1), NodeId::new(2)],
+            epoch: 0,
+        };
+
+        let responses = sm
+            .apply(vec![
+                entry(1, MetadataCommand::AllocateGroupId),
+                entry(2, MetadataCommand::AllocateGroupId),
+                entry(3, MetadataCommand::UpdateRouting { key: key.clone(), entry: routing.clone() }),
+                entry(
+                    4,
+                    MetadataCommand::UpdateSharedConfig {
+                        change: SharedConfigChange::Feature {
+                            name: "scatter-gather".to_string(),
+                            enabled: Some(true),
+                        },
+                    },
+                ),
+            ])
+            .await
+            .unwrap();
+        let allocated: MetadataResponse = serde_json::from_value(responses[1].metadata.clone().unwrap()).unwrap();
+        assert!(matches!(allocated, MetadataResponse::GroupAllocated(id) if id == RaftGroupId::new(2)));
+
+        // A follower installing the snapshot ends up with the same metadata
+        let snapshot = sm.get_snapshot_builder().await.build_snapshot().await.unwrap();
+        let mut follower = MetadataStateMachine::new(Arc::new(ClusterMetadata::new()));
+        follower.install_snapshot(&snapshot.meta, snapshot.snapshot).await.unwrap();
+
+        let routed = follower.metadata().lookup(&key).await.unwrap();
+        assert_eq!(routed.group_id, routing.group_id);
+        assert_eq!(routed.epoch, 1);
+        assert_eq!(follower.metadata().allocate_group_id().await, RaftGroupId::new(3));
+        assert!(follower.metadata().shared_config().feature("scatter-gather"));
+        assert_eq!(follower.applied_state().await.unwrap().0.map(|l| l.index), Some(4));
+    }
+}
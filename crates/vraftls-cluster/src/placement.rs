@@ -0,0 +1,197 @@
+//! Replica placement policies
+//!
+//! A `PlacementPolicy` chooses the nodes of a new group and the order in
+//! which they should be preferred as its leader. The zone-aware policy places
+//! replicas on the least loaded healthy nodes (fewest groups, then fewest
+//! files), preferring nodes in a zone (or rack) not used by the group yet.
+//! Nodes without the relevant label count as a domain of their own.
+
+use crate::membership::ClusterNode;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use vraftls_core::{ClusterConfig, NodeId, PlacementConstraint, PlacementPolicyKind, Result, VRaftError};
+
+/// Decides where the replicas of new groups go
+pub trait PlacementPolicy: Send + Sync {
+    /// Choose nodes for the replicas of a new group
+    fn choose_replicas(&self, nodes: &[ClusterNode], replicas: usize) -> Result<Vec<NodeId>>;
+
+    /// Order a group's replicas by leadership preference, most preferred first
+    ///
+    /// By default nodes leading the fewest groups come first.
+    fn choose_leader_preference(&self, nodes: &[ClusterNode], replicas: &[NodeId]) -> Vec<NodeId> {
+        let leaderships = |id: &NodeId| nodes.iter().find(|n| n.id == *id).map_or(0, |n| n.leading.len());
+        let mut preference = replicas.to_vec();
+        preference.sort_by_key(|id| (leaderships(id), *id));
+        preference
+    }
+}
+
+/// The policy selected in the configuration
+pub fn placement_policy(config: &ClusterConfig) -> Arc<dyn PlacementPolicy> {
+    match config.placement_policy {
+        PlacementPolicyKind::RoundRobin => Arc::new(RoundRobinPlacement::new()),
+        PlacementPolicyKind::LeastLoaded => Arc::new(LeastLoadedPlacement),
+        PlacementPolicyKind::ZoneAware => Arc::new(ZoneAwarePlacement::new(config.placement)),
+    }
+}
+
+/// Places each new group on the next healthy nodes, in node ID order
+#[derive(Debug, Default)]
+pub struct RoundRobinPlacement {
+    next: AtomicUsize,
+}
+
+impl RoundRobinPlacement {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl PlacementPolicy for RoundRobinPlacement {
+    fn choose_replicas(&self, nodes: &[ClusterNode], replicas: usize) -> Result<Vec<NodeId>> {
+        let mut healthy: Vec<_> = healthy_nodes(nodes, replicas)?.iter().map(|n| n.id).collect();
+        healthy.sort();
+        let start = self.next.fetch_add(replicas, Ordering::Relaxed);
+        Ok((0..replicas).map(|i| healthy[(start + i) % healthy.len()]).collect())
+    }
+}
+
+/// Places replicas on the least loaded healthy nodes, ignoring failure domains
+#[derive(Debug, Default)]
+pub struct LeastLoadedPlacement;
+
+impl PlacementPolicy for LeastLoadedPlacement {
+    fn choose_replicas(&self, nodes: &[ClusterNode], replicas: usize) -> Result<Vec<NodeId>> {
+        place_replicas(nodes, replicas, PlacementConstraint::None)
+    }
+}
+
+/// Places replicas on the least loaded healthy nodes across failure domains
+#[derive(Debug, Default)]
+pub struct ZoneAwarePlacement {
+    constraint: PlacementConstraint,
+}
+
+impl ZoneAwarePlacement {
+    pub fn new(constraint: PlacementConstraint) -> Self {
+        Self { constraint }
+    }
+}
+
+impl PlacementPolicy for ZoneAwarePlacement {
+    fn choose_replicas(&self, nodes: &[ClusterNode], replicas: usize) -> Result<Vec<NodeId>> {
+        place_replicas(nodes, replicas, self.constraint)
+    }
+}
+
+/// Healthy nodes, failing if there are fewer than `replicas`
+fn healthy_nodes(nodes: &[ClusterNode], replicas: usize) -> Result<Vec<&ClusterNode>> {
+    let healthy: Vec<_> = nodes.iter().filter(|n| n.is_placeable()).collect();
+    if healthy.len() < replicas {
+        return Err(VRaftError::InvalidConfig(format!(
+            "{} replicas requested but only {} healthy nodes",
+            replicas,
+            healthy.len()
+        )));
+    }
+    Ok(healthy)
+}
+
+/// Failure domain of a node under a constraint; `None` if it has no label
+pub fn failure_domain(node: &ClusterNode, constraint: PlacementConstraint) -> Option<String> {
+    match constraint {
+        PlacementConstraint::None => None,
+        PlacementConstraint::Zone => node.zone.clone(),
+        PlacementConstraint::Rack => {
+            let rack = node.rack.as_ref()?;
+            Some(format!("{}/{}", node.zone.as_deref().unwrap_or_default(), rack))
+        }
+    }
+}
+
+/// Choose nodes for the replicas of a group
+///
+/// Fails if there are too few healthy nodes, or if the constraint would be
+/// violated because every chosen node is in the same domain.
+pub fn place_replicas(nodes: &[ClusterNode], replicas: usize, constraint: PlacementConstraint) -> Result<Vec<NodeId>> {
+    let mut candidates = healthy_nodes(nodes, replicas)?;
+    candidates.sort_by_key(|n| (n.raft_groups.len(), n.stats.file_count, n.id));
+
+    let mut chosen = Vec::with_capacity(replicas);
+    let mut used = HashSet::new();
+    while chosen.len() < replicas {
+        // Least loaded node in an unused domain, else the least loaded one
+        let index = candidates
+            .iter()
+            .position(|n| failure_domain(n, constraint).is_none_or(|d| !used.contains(&d)))
+            .unwrap_or(0);
+        let node = candidates.remove(index);
+        if let Some(domain) = failure_domain(node, constraint) {
+            used.insert(domain);
+        }
+        chosen.push(node);
+    }
+
+    if replicas > 1 && constraint != PlacementConstraint::None {
+        let domains: Vec<_> = chosen.iter().map(|n| failure_domain(n, constraint)).collect();
+        if domains[0].is_some() && domains.iter().all(|d| *d == domains[0]) {
+            return Err(VRaftError::InvalidConfig(format!(
+                "all {} replicas would be in failure domain {}",
+                replicas,
+                domains[0].as_deref().unwrap_or_default()
+            )));
+        }
+    }
+
+    Ok(chosen.into_iter().map(|n| n.id).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::membership::NodeStatus;
+    use vraftls_core::RaftGroupId;
+
+    fn node(id: u64, zone: &str, rack: &str, groups: usize) -> ClusterNode {
+        ClusterNode {
+            status: NodeStatus::Healthy,
+            raft_groups: (0..groups as u64).map(RaftGroupId::new).collect(),
+            zone: Some(zone.to_string()),
+            rack: Some(rack.to_string()),
+            ..ClusterNode::new(NodeId::new(id), format!("127.0.0.1:{}", 8000 + id).parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_place_replicas() {
+        let nodes = vec![
+            node(1, "a", "r1", 0),
+            node(2, "a", "r2", 0),
+            node(3, "b", "r1", 5),
+        ];
+
+        // Node 3 is loaded but the only one in zone b
+        let placed = place_replicas(&nodes, 2, PlacementConstraint::Zone).unwrap();
+        assert_eq!(placed, vec![NodeId::new(1), NodeId::new(3)]);
+
+        let placed = place_replicas(&nodes, 2, PlacementConstraint::Rack).unwrap();
+        assert_eq!(placed, vec![NodeId::new(1), NodeId::new(2)]);
+
+        assert!(place_replicas(&nodes[..2], 2, PlacementConstraint::Zone).is_err());
+        assert!(place_replicas(&nodes[..2], 2, PlacementConstraint::None).is_ok());
+        assert!(place_replicas(&nodes, 4, PlacementConstraint::None).is_err());
+    }
+
+    #[test]
+    fn test_round_robin_placement() {
+        let nodes = vec![node(3, "a", "r1", 0), node(1, "a", "r1", 0), node(2, "a", "r1", 0)];
+        let policy = RoundRobinPlacement::new();
+        let ids = |ids: &[u64]| ids.iter().copied().map(NodeId::new).collect::<Vec<_>>();
+
+        assert_eq!(policy.choose_replicas(&nodes, 2).unwrap(), ids(&[1, 2]));
+        assert_eq!(policy.choose_replicas(&nodes, 2).unwrap(), ids(&[3, 1]));
+        assert_eq!(policy.choose_leader_preference(&nodes, &ids(&[3, 1])), ids(&[1, 3]));
+    }
+}
@@ -0,0 +1,116 @@
+//! Quarantine of flapping nodes
+//!
+//! A node on a lossy link can alternate between Healthy and Suspect every
+//! few seconds. Each flip would make it a placement candidate again, so new
+//! replicas land on it and then stall. Nodes that flip too often within a
+//! window are quarantined: they keep their replicas and status, but are left
+//! out of placement until no flip has been seen for a cooldown period.
+
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use vraftls_core::{ClusterConfig, NodeId};
+
+/// When a node counts as flapping
+#[derive(Clone, Debug)]
+pub struct FlapConfig {
+    /// Flips between Healthy and Suspect tolerated within `window`
+    pub threshold: usize,
+
+    /// Window flips are counted in
+    pub window: Duration,
+
+    /// Time without flips before a quarantined node is released
+    pub cooldown: Duration,
+}
+
+impl From<&ClusterConfig> for FlapConfig {
+    fn from(config: &ClusterConfig) -> Self {
+        Self {
+            threshold: config.flap_threshold,
+            window: config.flap_window,
+            cooldown: config.flap_cooldown,
+        }
+    }
+}
+
+impl Default for FlapConfig {
+    fn default() -> Self {
+        Self::from(&ClusterConfig::default())
+    }
+}
+
+/// Recent status flips of each node
+pub struct FlapTracker {
+    config: FlapConfig,
+    flips: DashMap<NodeId, VecDeque<Instant>>,
+}
+
+impl FlapTracker {
+    pub fn new(config: FlapConfig) -> Self {
+        Self {
+            config,
+            flips: DashMap::new(),
+        }
+    }
+
+    /// Record a flip at `at`; returns whether the node now flaps
+    pub fn record_at(&self, node: NodeId, at: Instant) -> bool {
+        let mut flips = self.flips.entry(node).or_default();
+        flips.push_back(at);
+        while flips
+            .front()
+            .is_some_and(|first| at.saturating_duration_since(*first) > self.config.window)
+        {
+            flips.pop_front();
+        }
+        flips.len() > self.config.threshold
+    }
+
+    /// Whether a node has not flipped for the cooldown period at `now`
+    pub fn is_stable_at(&self, node: NodeId, now: Instant) -> bool {
+        self.flips
+            .get(&node)
+            .and_then(|flips| flips.back().copied())
+            .is_none_or(|last| now.saturating_duration_since(last) >= self.config.cooldown)
+    }
+
+    /// Forget a node's flips
+    pub fn remove(&self, node: NodeId) {
+        self.flips.remove(&node);
+    }
+}
+
+impl Default for FlapTracker {
+    fn default() -> Self {
+        Self::new(FlapConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flapping() {
+        let tracker = FlapTracker::new(FlapConfig {
+            threshold: 3,
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(120),
+        });
+        let node = NodeId::new(2);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Flips spread wider than the window never add up
+        for secs in [0, 40, 80, 120, 160] {
+            assert!(!tracker.record_at(node, at(secs)));
+        }
+
+        assert!(!tracker.record_at(node, at(170)));
+        assert!(tracker.record_at(node, at(175)));
+        assert!(!tracker.is_stable_at(node, at(200)));
+        assert!(tracker.is_stable_at(node, at(295)));
+    }
+}
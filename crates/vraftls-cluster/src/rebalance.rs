@@ -0,0 +1,233 @@
+//! Replica rebalancing
+//!
+//! Counts the group replicas each healthy node hosts and moves replicas from
+//! the most to the least loaded node until no two nodes differ by more than
+//! one. A move adds the replica on the target as a learner, waits for it to
+//! catch up and only then removes the source from the group.
+
+use crate::membership::{ClusterMembership, ClusterNode, NodeStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+use vraftls_core::{NodeId, RaftGroupId, Result, Timestamp, VRaftError};
+
+/// Move of one group replica between nodes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaMove {
+    pub group_id: RaftGroupId,
+    pub from: NodeId,
+    pub to: NodeId,
+}
+
+/// Carries out replica moves
+pub trait ReplicaMover: Send + Sync {
+    /// Move a replica, returning once the source has left the group
+    ///
+    /// Fails with `NotLeader` if this node cannot change the group.
+    fn move_replica(&self, replica_move: &ReplicaMove) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+/// Rebalancing settings
+#[derive(Clone, Debug)]
+pub struct RebalanceConfig {
+    /// Interval between automatic rounds
+    pub interval: Duration,
+
+    /// Moves started per round
+    pub max_moves_per_round: usize,
+}
+
+impl Default for RebalanceConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(300),
+            max_moves_per_round: 1,
+        }
+    }
+}
+
+/// State reported by the admin API
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RebalanceStatus {
+    pub paused: bool,
+
+    /// Replicas per healthy node
+    pub replicas: BTreeMap<NodeId, usize>,
+
+    /// Moves completed by the last round
+    pub last_moves: Vec<ReplicaMove>,
+
+    /// When the last round ran
+    pub last_run: Option<Timestamp>,
+}
+
+/// Evens out replicas across nodes
+pub struct Rebalancer<M> {
+    membership: Arc<ClusterMembership>,
+    mover: M,
+    config: RebalanceConfig,
+    paused: AtomicBool,
+
+    /// Serializes rounds
+    running: Mutex<()>,
+
+    last_moves: RwLock<Vec<ReplicaMove>>,
+    last_run: RwLock<Option<Timestamp>>,
+}
+
+impl<M: ReplicaMover> Rebalancer<M> {
+    pub fn new(membership: Arc<ClusterMembership>, mover: M, config: RebalanceConfig) -> Self {
+        Self {
+            membership,
+            mover,
+            config,
+            paused: AtomicBool::new(false),
+            running: Mutex::new(()),
+            last_moves: RwLock::new(Vec::new()),
+            last_run: RwLock::new(None),
+        }
+    }
+
+    /// Stop automatic rounds
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Restart automatic rounds
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub async fn status(&self) -> RebalanceStatus {
+        RebalanceStatus {
+            paused: self.is_paused(),
+            replicas: replica_counts(&self.membership.all_nodes()),
+            last_moves: self.last_moves.read().await.clone(),
+            last_run: *self.last_run.read().await,
+        }
+    }
+
+    /// Run one round now, even when paused; returns the completed moves
+    pub async fn run_once(&self) -> Result<Vec<ReplicaMove>> {
+        let _running = self.running.lock().await;
+
+        let planned = plan_moves(&self.membership.all_nodes(), self.config.max_moves_per_round);
+        let mut done = Vec::new();
+        for replica_move in planned {
+            match self.mover.move_replica(&replica_move).await {
+                Ok(()) => {
+                    self.membership.move_replica(replica_move.group_id, replica_move.from, replica_move.to);
+                    tracing::info!(
+                        group_id = %replica_move.group_id,
+                        from = %replica_move.from,
+                        to = %replica_move.to,
+                        "moved replica"
+                    );
+                    done.push(replica_move);
+                }
+                // Another node leads the group and moves it
+                Err(VRaftError::NotLeader { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        *self.last_moves.write().await = done.clone();
+        *self.last_run.write().await = Some(Timestamp::now());
+        Ok(done)
+    }
+}
+
+/// Replicas hosted by each healthy node
+pub fn replica_counts(nodes: &[ClusterNode]) -> BTreeMap<NodeId, usize> {
+    nodes
+        .iter()
+        .filter(|n| n.status == NodeStatus::Healthy)
+        .map(|n| (n.id, n.raft_groups.len()))
+        .collect()
+}
+
+/// Moves that bring healthy nodes within one replica of each other;
+/// quarantined nodes are left alone
+pub fn plan_moves(nodes: &[ClusterNode], max_moves: usize) -> Vec<ReplicaMove> {
+    let mut hosted: BTreeMap<NodeId, Vec<RaftGroupId>> = nodes
+        .iter()
+        .filter(|n| n.is_placeable())
+        .map(|n| (n.id, n.raft_groups.clone()))
+        .collect();
+
+    let mut moves = Vec::new();
+    while moves.len() < max_moves {
+        let Some((&from, most)) = hosted.iter().max_by_key(|(_, groups)| groups.len()) else {
+            break;
+        };
+        let Some((&to, least)) = hosted.iter().min_by_key(|(_, groups)| groups.len()) else {
+            break;
+        };
+        if most.len() <= least.len() + 1 {
+            break;
+        }
+
+        // A node can hold only one replica of a group
+        let Some(&group_id) = most.iter().find(|g| !least.contains(g)) else {
+            break;
+        };
+        hosted.get_mut(&from).unwrap().retain(|g| *g != group_id);
+        hosted.get_mut(&to).unwrap().push(group_id);
+        moves.push(ReplicaMove { group_id, from, to });
+    }
+    moves
+}
+
+/// Run rebalancing rounds until the task is aborted
+pub fn spawn_rebalancer<M: ReplicaMover + 'static>(rebalancer: Arc<Rebalancer<M>>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(rebalancer.config.interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if rebalancer.is_paused() {
+                continue;
+            }
+            if let Err(e) = rebalancer.run_once().await {
+                tracing::warn!(error = %e, "rebalancing round failed");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: u64, groups: &[u64]) -> ClusterNode {
+        ClusterNode {
+            status: NodeStatus::Healthy,
+            raft_groups: groups.iter().copied().map(RaftGroupId::new).collect(),
+            ..ClusterNode::new(NodeId::new(id), format!("127.0.0.1:{}", 8000 + id).parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_plan_moves() {
+        let nodes = vec![node(1, &[1, 2, 3, 4]), node(2, &[1, 2]), node(3, &[])];
+        let moves = plan_moves(&nodes, 10);
+        assert_eq!(moves.len(), 2);
+        assert!(moves.iter().all(|m| m.from == NodeId::new(1) && m.to == NodeId::new(3)));
+
+        // Node 2 already hosts group 1, so it never receives it
+        let nodes = vec![node(1, &[1, 2, 3]), node(2, &[1])];
+        let moves = plan_moves(&nodes, 10);
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves[0].group_id, RaftGroupId::new(2));
+
+        assert_eq!(plan_moves(&[node(1, &[1, 2, 3, 4]), node(2, &[])], 1).len(), 1);
+    }
+}
@@ -0,0 +1,340 @@
+//! Anti-entropy repair
+//!
+//! Raft keeps replicas identical only as long as they all apply the same log
+//! the same way; a state machine bug or a bad restore can make one drift
+//! silently. The leader of each group therefore compares digests with its
+//! followers from time to time. A digest spreads the file checksums over
+//! fixed buckets, so only buckets whose hashes differ are fetched file by
+//! file. Divergent files are rewritten from the leader's copy through Raft.
+//!
+//! Replicas are compared only at the same applied index; a follower that is
+//! still catching up is left for the next round. The leader's copy always
+//! wins, so a leader that itself diverged spreads its state.
+
+use crate::leader_balance::LeaderSource;
+use crate::membership::ClusterMembership;
+use crate::version::FEATURE_REPAIR_DIGEST;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{ClusterConfig, FileId, NodeId, RaftGroupId, Result, VRaftError};
+
+/// HTTP path serving a replica's digest
+pub const DIGEST_PATH: &str = "/repair/digest";
+
+/// Buckets the files of a digest are spread over
+pub const DIGEST_BUCKETS: usize = 64;
+
+/// Checksum of one file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct FileDigest {
+    pub file_id: FileId,
+    pub version: u64,
+    pub checksum: u64,
+}
+
+/// Digest of one replica of a group
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupDigest {
+    pub group_id: RaftGroupId,
+
+    /// Last log index applied to the replica
+    pub applied_index: Option<u64>,
+
+    /// ID the replica gives the next created file
+    pub next_file_id: u64,
+
+    /// Hash of the files in each bucket
+    pub buckets: Vec<u64>,
+
+    /// Files of the requested bucket by path; empty for a summary
+    pub files: BTreeMap<String, FileDigest>,
+}
+
+impl GroupDigest {
+    /// Summarize a replica's files, keeping those of `bucket` if given
+    pub fn build(
+        group_id: RaftGroupId,
+        applied_index: Option<u64>,
+        next_file_id: u64,
+        files: impl IntoIterator<Item = (String, FileDigest)>,
+        bucket: Option<usize>,
+    ) -> Self {
+        let files: BTreeMap<_, _> = files.into_iter().collect();
+        let mut hashers: Vec<_> = (0..DIGEST_BUCKETS).map(|_| DefaultHasher::new()).collect();
+        for (path, file) in &files {
+            let hasher = &mut hashers[bucket_of(path)];
+            path.hash(hasher);
+            file.hash(hasher);
+        }
+
+        Self {
+            group_id,
+            applied_index,
+            next_file_id,
+            buckets: hashers.into_iter().map(|h| h.finish()).collect(),
+            files: files
+                .into_iter()
+                .filter(|(path, _)| Some(bucket_of(path)) == bucket)
+                .collect(),
+        }
+    }
+
+    /// Buckets whose hashes differ from `other`
+    pub fn differing_buckets(&self, other: &GroupDigest) -> Vec<usize> {
+        (0..DIGEST_BUCKETS)
+            .filter(|i| self.buckets.get(*i) != other.buckets.get(*i))
+            .collect()
+    }
+}
+
+/// Bucket a path falls into
+pub fn bucket_of(path: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    (hasher.finish() % DIGEST_BUCKETS as u64) as usize
+}
+
+/// A file that differs between the leader and a replica
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DivergentFile {
+    pub path: String,
+
+    /// The leader's copy; `None` if only the replica has the file
+    pub leader: Option<FileDigest>,
+
+    /// The replica's copy; `None` if the replica lacks the file
+    pub replica: Option<FileDigest>,
+}
+
+/// Files that differ between two digests of the same bucket
+pub fn divergent_files(leader: &GroupDigest, replica: &GroupDigest) -> Vec<DivergentFile> {
+    let paths: BTreeSet<_> = leader.files.keys().chain(replica.files.keys()).collect();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let (ours, theirs) = (leader.files.get(path), replica.files.get(path));
+            (ours != theirs).then(|| DivergentFile {
+                path: path.clone(),
+                leader: ours.copied(),
+                replica: theirs.copied(),
+            })
+        })
+        .collect()
+}
+
+/// The replicas of groups hosted on this node
+pub trait LocalReplicas: Send + Sync {
+    /// Digest of this node's replica of a group
+    fn digest(
+        &self,
+        group_id: RaftGroupId,
+        bucket: Option<usize>,
+    ) -> impl std::future::Future<Output = Result<GroupDigest>> + Send;
+
+    /// Rewrite divergent files from this node's copy through Raft
+    fn repair(
+        &self,
+        group_id: RaftGroupId,
+        files: Vec<DivergentFile>,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+}
+
+/// Repair settings
+#[derive(Clone, Debug)]
+pub struct RepairConfig {
+    /// Interval between repair rounds
+    pub interval: Duration,
+
+    /// Timeout of a single digest request
+    pub timeout: Duration,
+}
+
+impl From<&ClusterConfig> for RepairConfig {
+    fn from(config: &ClusterConfig) -> Self {
+        Self {
+            interval: config.repair_interval,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Files repaired on one replica
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairReport {
+    pub group_id: RaftGroupId,
+    pub replica: NodeId,
+    pub files: Vec<DivergentFile>,
+}
+
+/// Compares the groups this node leads with their followers
+pub struct RepairService<S, R> {
+    membership: Arc<ClusterMembership>,
+    source: S,
+    replicas: R,
+    config: RepairConfig,
+    client: reqwest::Client,
+}
+
+impl<S: LeaderSource, R: LocalReplicas> RepairService<S, R> {
+    pub fn new(membership: Arc<ClusterMembership>, source: S, replicas: R, config: RepairConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .build()
+            .unwrap_or_default();
+        Self {
+            membership,
+            source,
+            replicas,
+            config,
+            client,
+        }
+    }
+
+    /// Compare every led group with its followers and repair divergence
+    pub async fn run_once(&self) -> Vec<RepairReport> {
+        let local = self.membership.local_node_id();
+        let mut reports = Vec::new();
+
+        for group in self.source.leaderships().await {
+            if group.leader != Some(local) {
+                continue;
+            }
+            for replica in group.voters.iter().copied().filter(|v| *v != local) {
+                // Older nodes serve no digests
+                if !self.membership.node_supports(replica, FEATURE_REPAIR_DIGEST) {
+                    continue;
+                }
+                match self.check_replica(group.group_id, replica).await {
+                    Ok(Some(report)) => reports.push(report),
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::debug!(group_id = %group.group_id, %replica, error = %e, "repair check skipped")
+                    }
+                }
+            }
+        }
+        reports
+    }
+
+    /// Compare one follower with this node; `None` if it is in sync
+    async fn check_replica(&self, group_id: RaftGroupId, replica: NodeId) -> Result<Option<RepairReport>> {
+        let addr = self
+            .membership
+            .get_node(replica)
+            .ok_or(VRaftError::NodeUnreachable(replica))?
+            .addr;
+
+        let ours = self.replicas.digest(group_id, None).await?;
+        let theirs = self.fetch(addr, replica, group_id, None).await?;
+        if ours.applied_index != theirs.applied_index {
+            return Ok(None);
+        }
+
+        let mut files = Vec::new();
+        for bucket in ours.differing_buckets(&theirs) {
+            let ours_bucket = self.replicas.digest(group_id, Some(bucket)).await?;
+            let theirs_bucket = self.fetch(addr, replica, group_id, Some(bucket)).await?;
+            // Writes applied in between make the comparison meaningless
+            if ours_bucket.applied_index != ours.applied_index || theirs_bucket.applied_index != ours.applied_index {
+                return Ok(None);
+            }
+            files.extend(divergent_files(&ours_bucket, &theirs_bucket));
+        }
+        // A replica's file ID counter can only be raised to the leader's
+        if theirs.next_file_id > ours.next_file_id {
+            tracing::warn!(%group_id, %replica, "replica is ahead in file IDs, cannot repair");
+        }
+        if files.is_empty() && theirs.next_file_id >= ours.next_file_id {
+            return Ok(None);
+        }
+
+        tracing::warn!(%group_id, %replica, files = files.len(), "replica diverged, repairing");
+        self.replicas.repair(group_id, files.clone()).await?;
+        Ok(Some(RepairReport {
+            group_id,
+            replica,
+            files,
+        }))
+    }
+
+    /// Fetch a follower's digest
+    async fn fetch(
+        &self,
+        addr: SocketAddr,
+        replica: NodeId,
+        group_id: RaftGroupId,
+        bucket: Option<usize>,
+    ) -> Result<GroupDigest> {
+        let mut query = vec![("group_id", group_id.0.to_string())];
+        if let Some(bucket) = bucket {
+            query.push(("bucket", bucket.to_string()));
+        }
+        let response = self
+            .client
+            .get(format!("http://{}{}", addr, DIGEST_PATH))
+            .query(&query)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|_| VRaftError::NodeUnreachable(replica))?;
+        response
+            .json()
+            .await
+            .map_err(|e| VRaftError::Serialization(e.to_string()))
+    }
+}
+
+/// Run repair rounds until the task is aborted
+pub fn spawn_repair_service<S, R>(service: Arc<RepairService<S, R>>) -> JoinHandle<()>
+where
+    S: LeaderSource + 'static,
+    R: LocalReplicas + 'static,
+{
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(service.config.interval);
+        // The first tick completes immediately; skip it while the node starts
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            service.run_once().await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(id: u64, checksum: u64) -> FileDigest {
+        FileDigest {
+            file_id: FileId::new(id),
+            version: 0,
+            checksum,
+        }
+    }
+
+    #[test]
+    fn test_divergent_files() {
+        let group_id = RaftGroupId::new(1);
+        let leader_files = [("/a.rs".to_string(), file(1, 10)), ("/b.rs".to_string(), file(2, 20))];
+        let replica_files = [("/a.rs".to_string(), file(1, 10)), ("/b.rs".to_string(), file(2, 21)), ("/c.rs".to_string(), file(3, 30))];
+
+        let leader = GroupDigest::build(group_id, Some(5), 3, leader_files.clone(), None);
+        let replica = GroupDigest::build(group_id, Some(5), 4, replica_files.clone(), None);
+        let buckets = leader.differing_buckets(&replica);
+        assert!(buckets.contains(&bucket_of("/b.rs")));
+        assert!(!buckets.contains(&bucket_of("/a.rs")) || bucket_of("/a.rs") == bucket_of("/b.rs"));
+
+        let leader = GroupDigest::build(group_id, Some(5), 3, leader_files, Some(bucket_of("/b.rs")));
+        let replica = GroupDigest::build(group_id, Some(5), 4, replica_files, Some(bucket_of("/b.rs")));
+        let divergent = divergent_files(&leader, &replica);
+        assert!(divergent.iter().any(|f| f.path == "/b.rs" && f.leader == Some(file(2, 20))));
+        assert!(divergent.iter().all(|f| f.path != "/a.rs"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Versioned routing table
+//!
+//! Every change to the table bumps its version, and the changed entry takes
+//! the new version as its epoch. Gateways remember the version they last
+//! saw and fetch only the delta since then; nodes compare the epoch a
+//! request was routed with against the current one and reject stale routes.
+
+use crate::metadata::RoutingEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use vraftls_core::{PartitionKey, Result, VRaftError};
+
+/// Removed keys remembered for deltas; older removals force a full resync
+const MAX_TOMBSTONES: usize = 10_000;
+
+/// Routing changes since a version
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RoutingDelta {
+    /// Table version the delta brings the caller to
+    pub version: u64,
+
+    /// Whether this is the whole table rather than a delta; the caller must
+    /// then drop every entry not listed
+    pub full: bool,
+
+    /// Entries added or changed
+    pub updated: Vec<(PartitionKey, RoutingEntry)>,
+
+    /// Keys whose route was removed
+    pub removed: Vec<PartitionKey>,
+}
+
+/// Routing table with per-entry epochs
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RoutingTable {
+    /// Current routes
+    #[serde(with = "as_pairs")]
+    entries: HashMap<PartitionKey, RoutingEntry>,
+
+    /// Version at which each recently removed key was removed
+    #[serde(with = "as_pairs")]
+    tombstones: HashMap<PartitionKey, u64>,
+
+    /// Version of the last change
+    version: u64,
+
+    /// Removals up to this version are forgotten
+    pruned_through: u64,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Version of the last change
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Number of routed keys
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Current route of a key
+    pub fn get(&self, key: &PartitionKey) -> Option<&RoutingEntry> {
+        self.entries.get(key)
+    }
+
+    /// All current routes
+    pub fn iter(&self) -> impl Iterator<Item = (&PartitionKey, &RoutingEntry)> {
+        self.entries.iter()
+    }
+
+    /// Set a key's route; returns the epoch it was given
+    pub fn update(&mut self, key: PartitionKey, mut entry: RoutingEntry) -> u64 {
+        self.version += 1;
+        entry.epoch = self.version;
+        self.tombstones.remove(&key);
+        self.entries.insert(key, entry);
+        self.version
+    }
+
+    /// Remove a key's route
+    pub fn remove(&mut self, key: &PartitionKey) -> Option<RoutingEntry> {
+        let removed = self.entries.remove(key)?;
+        self.version += 1;
+        self.tombstones.insert(key.clone(), self.version);
+
+        if self.tombstones.len() > MAX_TOMBSTONES {
+            // Forget the oldest half of the removals
+            let mut versions: Vec<_> = self.tombstones.values().copied().collect();
+            versions.sort_unstable();
+            let cutoff = versions[versions.len() / 2];
+            self.tombstones.retain(|_, version| *version > cutoff);
+            self.pruned_through = self.pruned_through.max(cutoff);
+        }
+        Some(removed)
+    }
+
+    /// Changes after `since`, or the whole table if they are no longer known
+    pub fn delta(&self, since: u64) -> RoutingDelta {
+        let full = since < self.pruned_through || since > self.version;
+        let mut delta = RoutingDelta {
+            version: self.version,
+            full,
+            updated: self
+                .entries
+                .iter()
+                .filter(|(_, entry)| full || entry.epoch > since)
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect(),
+            removed: Vec::new(),
+        };
+        if !full {
+            delta.removed = self
+                .tombstones
+                .iter()
+                .filter(|(_, version)| **version > since)
+                .map(|(key, _)| key.clone())
+                .collect();
+        }
+        delta
+    }
+
+    /// Reject a request routed with an older epoch than the key's current one
+    pub fn check_epoch(&self, key: &PartitionKey, epoch: u64) -> Result<()> {
+        let current = match self.entries.get(key) {
+            Some(entry) => entry.epoch,
+            None => self.tombstones.get(key).copied().unwrap_or(0),
+        };
+        if epoch < current {
+            return Err(VRaftError::StaleRouting { epoch, current });
+        }
+        Ok(())
+    }
+
+    /// Apply a delta received from the metadata group
+    pub fn apply_delta(&mut self, delta: RoutingDelta) {
+        if delta.full {
+            self.entries.clear();
+        }
+        for key in &delta.removed {
+            self.entries.remove(key);
+        }
+        self.entries.extend(delta.updated);
+        self.version = delta.version;
+    }
+}
+
+/// Serialize a map with non-string keys as a list of pairs
+mod as_pairs {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        Ok(Vec::<(K, V)>::deserialize(deserializer)?.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vraftls_core::{NodeId, RaftGroupId};
+
+    fn entry(group: u64) -> RoutingEntry {
+        RoutingEntry {
+            group_id: RaftGroupId::new(group),
+            leader: None,
+            replicas: vec![NodeId::new(1)],
+            epoch: 0,
+        }
+    }
+
+    #[test]
+    fn test_deltas_and_epochs() {
+        let (a, b) = (PartitionKey::from_path("/a.rs"), PartitionKey::from_path("/b.rs"));
+        let mut table = RoutingTable::new();
+        table.update(a.clone(), entry(1));
+        table.update(b.clone(), entry(1));
+        let seen = table.version();
+
+        table.update(a.clone(), entry(2));
+        table.remove(&b);
+
+        let delta = table.delta(seen);
+        assert!(!delta.full);
+        assert_eq!(delta.updated.len(), 1);
+        assert_eq!(delta.updated[0].1.group_id, RaftGroupId::new(2));
+        assert_eq!(delta.removed, vec![b.clone()]);
+
+        // A gateway applying the delta converges on the table
+        let mut gateway = RoutingTable::new();
+        gateway.apply_delta(table.delta(0));
+        assert_eq!(gateway.get(&a), table.get(&a));
+        assert!(gateway.get(&b).is_none());
+
+        assert!(table.check_epoch(&a, seen).is_err());
+        assert!(table.check_epoch(&a, table.version()).is_ok());
+        assert!(table.check_epoch(&b, seen).is_err());
+
+        let json = serde_json::to_string(&table).unwrap();
+        let restored: RoutingTable = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.version(), table.version());
+        assert_eq!(restored.get(&a), table.get(&a));
+    }
+}
@@ -0,0 +1,667 @@
+//! Raft group splitting
+//!
+//! A group holding more than `max_files_per_group` files is split in two:
+//! its files are ordered by partition key and the upper half moves to a
+//! newly allocated group on the same nodes. The moved files are copied
+//! first, then their routes switch to the new group in a single metadata
+//! entry, and only then are they deleted from the source group.
+//!
+//! The new group is started on every replica through `GROUP_START_PATH`
+//! before the leader initializes it. Copies and deletes are proposed as
+//! transactions, so splits wait until every node understands them.
+//!
+//! The files being moved are written to a [`SplitJournal`] before they are
+//! copied, so a split a restart interrupts is finished by the next check:
+//! copied over again if their routes did not switch yet, otherwise caught
+//! up and deleted from the source.
+
+use crate::membership::ClusterMembership;
+use crate::metadata::ClusterMetadata;
+use crate::metadata_state_machine::MetadataProposer;
+use crate::version::FEATURE_VFS_TRANSACTIONS;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{FileId, FileVersion, NodeId, RaftGroupId, Result, VRaftError, VfsConfig};
+use vraftls_vfs::{VfsCommand, VfsCommandError, VfsPath, VfsResponse};
+
+/// HTTP path asking a node to host a new group
+pub const GROUP_START_PATH: &str = "/cluster/groups/start";
+
+/// Interval between oversize checks
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Timeout of a group start request
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of a group start request
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GroupStartRequest {
+    pub group_id: RaftGroupId,
+}
+
+/// Asks other nodes to host a new group
+#[derive(Clone)]
+pub struct RemoteGroupStarter {
+    client: reqwest::Client,
+}
+
+impl Default for RemoteGroupStarter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RemoteGroupStarter {
+    pub fn new() -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(START_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self { client }
+    }
+
+    /// Start the group on the node at `addr`; nodes already hosting it
+    /// accept too
+    pub async fn start(&self, node_id: NodeId, addr: SocketAddr, group_id: RaftGroupId) -> Result<()> {
+        self.client
+            .post(format!("http://{}{}", addr, GROUP_START_PATH))
+            .json(&GroupStartRequest { group_id })
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map(|_| ())
+            .map_err(|_| VRaftError::NodeUnreachable(node_id))
+    }
+}
+
+/// A file as read from a group's VFS
+#[derive(Clone, Debug)]
+pub struct GroupFile {
+    pub id: FileId,
+    pub path: VfsPath,
+    pub content: String,
+}
+
+/// Access to the Raft groups hosted in the cluster
+pub trait GroupStore: Send + Sync {
+    /// Number of files in a group
+    fn file_count(&self, group_id: RaftGroupId) -> impl std::future::Future<Output = Result<u64>> + Send;
+
+    /// All files of a group with their content
+    fn list_files(&self, group_id: RaftGroupId) -> impl std::future::Future<Output = Result<Vec<GroupFile>>> + Send;
+
+    /// Start a new group on the given nodes
+    fn start_group(
+        &self,
+        group_id: RaftGroupId,
+        nodes: Vec<NodeId>,
+    ) -> impl std::future::Future<Output = Result<()>> + Send;
+
+    /// Propose a command to a group and wait until it is applied
+    fn propose(
+        &self,
+        group_id: RaftGroupId,
+        command: VfsCommand,
+    ) -> impl std::future::Future<Output = Result<VfsResponse>> + Send;
+}
+
+/// Split settings
+#[derive(Clone, Debug)]
+pub struct SplitConfig {
+    /// Files a group may hold before it is split
+    pub max_files_per_group: u64,
+
+    /// Interval between oversize checks
+    pub check_interval: Duration,
+}
+
+impl From<&VfsConfig> for SplitConfig {
+    fn from(config: &VfsConfig) -> Self {
+        Self {
+            max_files_per_group: config.max_files_per_group.max(2),
+            check_interval: DEFAULT_CHECK_INTERVAL,
+        }
+    }
+}
+
+/// Result of a split
+#[derive(Clone, Debug)]
+pub struct SplitOutcome {
+    pub source: RaftGroupId,
+    pub target: RaftGroupId,
+
+    /// Files moved to the target group
+    pub moved: usize,
+
+    /// Epoch of the moved files' routes
+    pub epoch: u64,
+}
+
+/// A file moving to the new group, by its ID in the source group
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MovedFile {
+    pub id: FileId,
+    pub path: VfsPath,
+}
+
+/// A split under way
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SplitProgress {
+    pub source: RaftGroupId,
+    pub target: RaftGroupId,
+    pub moved: Vec<MovedFile>,
+}
+
+/// File holding the progress of an unfinished split
+pub struct SplitJournal {
+    path: PathBuf,
+}
+
+impl SplitJournal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Load the unfinished split, if any
+    pub fn load(&self) -> io::Result<Option<SplitProgress>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).map(Some).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Persist progress
+    pub fn save(&self, progress: &SplitProgress) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(progress).map_err(io::Error::other)?;
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, &self.path)
+    }
+
+    /// Forget a finished split
+    pub fn clear(&self) -> io::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Detects oversized groups and splits them
+///
+/// Runs on every node but acts only on the metadata group leader.
+pub struct SplitCoordinator<S> {
+    metadata: Arc<ClusterMetadata>,
+    proposer: MetadataProposer,
+    store: Arc<S>,
+    config: SplitConfig,
+
+    /// Nodes whose features gate splitting; unchecked without one
+    membership: Option<Arc<ClusterMembership>>,
+
+    /// Progress of the split under way; not resumable without one
+    journal: Option<SplitJournal>,
+}
+
+impl<S: GroupStore> SplitCoordinator<S> {
+    pub fn new(
+        metadata: Arc<ClusterMetadata>,
+        proposer: MetadataProposer,
+        store: Arc<S>,
+        config: SplitConfig,
+    ) -> Self {
+        Self {
+            metadata,
+            proposer,
+            store,
+            config,
+            membership: None,
+            journal: None,
+        }
+    }
+
+    /// Only split once every node that is not down supports transactions
+    pub fn with_membership(mut self, membership: Arc<ClusterMembership>) -> Self {
+        self.membership = Some(membership);
+        self
+    }
+
+    /// Persist the progress of splits, so one a restart interrupted is
+    /// finished by the next check
+    pub fn with_journal(mut self, journal: SplitJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Groups holding more files than allowed, among those hosted here
+    pub async fn oversized_groups(&self) -> Result<Vec<RaftGroupId>> {
+        let mut oversized = Vec::new();
+        for group_id in self.metadata.groups().await {
+            let count = match self.store.file_count(group_id).await {
+                Ok(count) => count,
+                Err(VRaftError::GroupNotFound(_)) => continue,
+                Err(e) => {
+                    tracing::warn!(group = %group_id, error = %e, "could not count files; skipped");
+                    continue;
+                }
+            };
+            if count > self.config.max_files_per_group {
+                oversized.push(group_id);
+            }
+        }
+        Ok(oversized)
+    }
+
+    /// Finish an interrupted split, then split every oversized group once
+    ///
+    /// A group that fails to split is skipped until the next check; an
+    /// interrupted split that still fails holds off new ones.
+    pub async fn check(&self) -> Result<Vec<SplitOutcome>> {
+        if !self.proposer.is_leader() {
+            return Ok(Vec::new());
+        }
+        // Replicas on older builds could not apply the split's transactions
+        if let Some(membership) = &self.membership {
+            if !membership.cluster_supports(FEATURE_VFS_TRANSACTIONS) {
+                tracing::debug!("not every node supports transactions yet; splits postponed");
+                return Ok(Vec::new());
+            }
+        }
+
+        let mut outcomes = Vec::new();
+        outcomes.extend(self.resume().await?);
+        for group_id in self.oversized_groups().await? {
+            match self.split(group_id).await {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(e) => {
+                    tracing::warn!(group = %group_id, error = %e, "group split failed");
+                    // A split that got under way is finished before any other
+                    if self.load()?.is_some() {
+                        break;
+                    }
+                }
+            }
+        }
+        for outcome in &outcomes {
+            tracing::info!(
+                source = %outcome.source,
+                target = %outcome.target,
+                moved = outcome.moved,
+                "split raft group"
+            );
+        }
+        Ok(outcomes)
+    }
+
+    /// Move the upper half of a group's files to a new group
+    pub async fn split(&self, source: RaftGroupId) -> Result<SplitOutcome> {
+        let (_, moved) = split_by_key(self.store.list_files(source).await?);
+        if moved.is_empty() {
+            return Err(VRaftError::Internal(format!("group {} has too few files to split", source)));
+        }
+
+        let target = self.proposer.allocate_group_id().await?;
+        self.store.start_group(target, self.metadata.get_group_nodes(source).await).await?;
+        let progress = SplitProgress {
+            source,
+            target,
+            moved: moved
+                .iter()
+                .map(|file| MovedFile {
+                    id: file.id,
+                    path: file.path.clone(),
+                })
+                .collect(),
+        };
+        self.save(&progress)?;
+
+        let epoch = self.copy_and_route(&progress, &moved).await?;
+        self.finish(&progress, epoch).await
+    }
+
+    /// Finish the split a restart interrupted, if any
+    ///
+    /// Once the moved files are routed to the target, only the catch-up and
+    /// the deletes are left. Before that the copies may be partial: they
+    /// are dropped and made again from the source's files.
+    pub async fn resume(&self) -> Result<Option<SplitOutcome>> {
+        let Some(progress) = self.load()? else {
+            return Ok(None);
+        };
+        tracing::info!(source = %progress.source, target = %progress.target, "resuming interrupted split");
+
+        let route = match progress.moved.first() {
+            Some(file) => self.metadata.lookup(&file.path.partition_key()).await,
+            None => None,
+        };
+        let epoch = match route {
+            Some(route) if route.group_id == progress.target => route.epoch,
+            _ => {
+                let copies = self.store.list_files(progress.target).await?;
+                if !copies.is_empty() {
+                    let deletes = copies
+                        .iter()
+                        .map(|file| VfsCommand::DeleteFile { file_id: file.id })
+                        .collect();
+                    let dropped = self
+                        .store
+                        .propose(progress.target, VfsCommand::Transaction { commands: deletes })
+                        .await?;
+                    expect_transaction(dropped)?;
+                }
+                let moved: Vec<_> = self
+                    .store
+                    .list_files(progress.source)
+                    .await?
+                    .into_iter()
+                    .filter(|file| progress.moved.iter().any(|m| m.id == file.id))
+                    .collect();
+                self.copy_and_route(&progress, &moved).await?
+            }
+        };
+        self.finish(&progress, epoch).await.map(Some)
+    }
+
+    /// Copy the moved files, into their directories, then route them to
+    /// the target; returns the epoch of their routes
+    async fn copy_and_route(&self, progress: &SplitProgress, moved: &[GroupFile]) -> Result<u64> {
+        let directories: BTreeMap<_, _> = moved
+            .iter()
+            .filter_map(|file| file.path.parent())
+            .map(|parent| (parent.components().to_vec(), parent))
+            .collect();
+        let mut creates: Vec<_> = directories
+            .into_values()
+            .map(|path| VfsCommand::CreateDirectory { path, recursive: true })
+            .collect();
+        creates.extend(moved.iter().map(|file| VfsCommand::CreateFile {
+            path: file.path.clone(),
+            content: file.content.clone(),
+        }));
+        let created = self
+            .store
+            .propose(progress.target, VfsCommand::Transaction { commands: creates })
+            .await?;
+        expect_transaction(created)?;
+
+        let keys = moved.iter().map(|file| file.path.partition_key()).collect();
+        self.proposer.split_group(progress.source, progress.target, keys).await
+    }
+
+    /// Catch up and delete the moved files, then forget the split
+    async fn finish(&self, progress: &SplitProgress, epoch: u64) -> Result<SplitOutcome> {
+        finish_split(self.store.as_ref(), progress).await?;
+        if let Some(journal) = &self.journal {
+            journal.clear().map_err(VRaftError::Io)?;
+        }
+        Ok(SplitOutcome {
+            source: progress.source,
+            target: progress.target,
+            moved: progress.moved.len(),
+            epoch,
+        })
+    }
+
+    fn load(&self) -> Result<Option<SplitProgress>> {
+        match &self.journal {
+            Some(journal) => journal.load().map_err(VRaftError::Io),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, progress: &SplitProgress) -> Result<()> {
+        match &self.journal {
+            Some(journal) => journal.save(progress).map_err(VRaftError::Io),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Carry over writes that reached the source while copying, then delete
+/// the moved files from the source
+///
+/// Each update expects the copy's initial version, so writes the target
+/// took after the route switch are not overwritten. Safe to run again
+/// after a failure.
+async fn finish_split<S: GroupStore>(store: &S, progress: &SplitProgress) -> Result<()> {
+    let current: HashMap<_, _> = store
+        .list_files(progress.source)
+        .await?
+        .into_iter()
+        .map(|file| (file.id, file))
+        .collect();
+    let copies: HashMap<_, _> = store
+        .list_files(progress.target)
+        .await?
+        .into_iter()
+        .map(|file| (file.path.components().to_vec(), file))
+        .collect();
+
+    for file in &progress.moved {
+        let (Some(current), Some(copy)) = (current.get(&file.id), copies.get(file.path.components())) else {
+            continue;
+        };
+        if current.content == copy.content {
+            continue;
+        }
+        let update = VfsCommand::UpdateFile {
+            file_id: copy.id,
+            content: current.content.clone(),
+            expected_version: Some(FileVersion::initial().0),
+        };
+        match store.propose(progress.target, update).await? {
+            VfsResponse::Error(VfsCommandError::VersionMismatch { .. }) => {
+                tracing::debug!(target = %progress.target, file_id = %copy.id, "newer write kept over catch-up");
+            }
+            VfsResponse::Error(e) => return Err(VRaftError::Internal(e.to_string())),
+            _ => {}
+        }
+    }
+
+    let deletes: Vec<_> = progress
+        .moved
+        .iter()
+        .filter(|file| current.contains_key(&file.id))
+        .map(|file| VfsCommand::DeleteFile { file_id: file.id })
+        .collect();
+    if !deletes.is_empty() {
+        let deleted = store
+            .propose(progress.source, VfsCommand::Transaction { commands: deletes })
+            .await?;
+        expect_transaction(deleted)?;
+    }
+    Ok(())
+}
+
+/// Responses of a transaction, or why it was not applied
+fn expect_transaction(response: VfsResponse) -> Result<Vec<VfsResponse>> {
+    match response {
+        VfsResponse::Transaction(responses) => Ok(responses),
+        VfsResponse::Error(e) => Err(VRaftError::TransactionAborted(e.to_string())),
+        other => Err(VRaftError::TransactionAborted(format!("{:?}", other))),
+    }
+}
+
+/// Order files by partition key and cut them in half; returns (kept, moved)
+pub fn split_by_key(mut files: Vec<GroupFile>) -> (Vec<GroupFile>, Vec<GroupFile>) {
+    files.sort_by_cached_key(|file| file.path.partition_key().0);
+    let moved = files.split_off(files.len() / 2);
+    (files, moved)
+}
+
+/// Run the coordinator until the task is aborted
+pub fn spawn_split_coordinator<S: GroupStore + 'static>(coordinator: SplitCoordinator<S>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(coordinator.config.check_interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = coordinator.check().await {
+                tracing::warn!(error = %e, "group split failed");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use vraftls_vfs::Vfs;
+
+    /// Groups kept in memory, optionally failing deletes from one of them
+    struct MemoryStore {
+        groups: HashMap<RaftGroupId, Vfs>,
+        failing_deletes: Option<RaftGroupId>,
+        fail: AtomicBool,
+    }
+
+    impl GroupStore for MemoryStore {
+        async fn file_count(&self, group_id: RaftGroupId) -> Result<u64> {
+            Ok(self.list_files(group_id).await?.len() as u64)
+        }
+
+        async fn list_files(&self, group_id: RaftGroupId) -> Result<Vec<GroupFile>> {
+            let vfs = self.groups.get(&group_id).ok_or(VRaftError::GroupNotFound(group_id))?;
+            let mut files = Vec::new();
+            for id in vfs.all_file_ids() {
+                let file = vfs.get_file(id).ok_or(VRaftError::FileNotFound(id))?;
+                files.push(GroupFile {
+                    id,
+                    path: file.path,
+                    content: vfs.get_content(id)?,
+                });
+            }
+            Ok(files)
+        }
+
+        async fn start_group(&self, _group_id: RaftGroupId, _nodes: Vec<NodeId>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn propose(&self, group_id: RaftGroupId, command: VfsCommand) -> Result<VfsResponse> {
+            let deletes = matches!(&command, VfsCommand::Transaction { commands }
+                if commands.iter().any(|c| matches!(c, VfsCommand::DeleteFile { .. })));
+            if deletes && Some(group_id) == self.failing_deletes && self.fail.load(Ordering::SeqCst) {
+                return Ok(VfsResponse::Error(VfsCommandError::StorageError("disk full".to_string())));
+            }
+            let vfs = self.groups.get(&group_id).ok_or(VRaftError::GroupNotFound(group_id))?;
+            Ok(vfs.apply(command))
+        }
+    }
+
+    fn group_with(group_id: RaftGroupId, files: &[(&str, &str)]) -> Vfs {
+        let vfs = Vfs::new(group_id);
+        vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new("/src"),
+            recursive: true,
+        });
+        for (path, content) in files {
+            vfs.apply(VfsCommand::CreateFile {
+                path: VfsPath::new(path),
+                content: content.to_string(),
+            });
+        }
+        vfs
+    }
+
+    #[tokio::test]
+    async fn test_finish_split_delete_fails() {
+        let (source, target) = (RaftGroupId::new(1), RaftGroupId::new(2));
+        let store = MemoryStore {
+            groups: HashMap::from([
+                (source, group_with(source, &[("/src/a.rs", "a2"), ("/src/b.rs", "b"), ("/src/c.rs", "c")])),
+                (target, group_with(target, &[("/src/b.rs", "b"), ("/src/c.rs", "c")])),
+            ]),
+            failing_deletes: Some(source),
+            fail: AtomicBool::new(true),
+        };
+        // b.rs changed on the source after it was copied
+        let source_files = store.list_files(source).await.unwrap();
+        let b = source_files.iter().find(|f| f.path == VfsPath::new("/src/b.rs")).unwrap();
+        store.groups[&source].apply(VfsCommand::UpdateFile {
+            file_id: b.id,
+            content: "b2".to_string(),
+            expected_version: None,
+        });
+        let progress = SplitProgress {
+            source,
+            target,
+            moved: source_files
+                .iter()
+                .filter(|f| f.path != VfsPath::new("/src/a.rs"))
+                .map(|f| MovedFile {
+                    id: f.id,
+                    path: f.path.clone(),
+                })
+                .collect(),
+        };
+        let content = |group: RaftGroupId, path: &str| {
+            let vfs = &store.groups[&group];
+            vfs.get_file_by_path(&VfsPath::new(path)).map(|f| vfs.get_content(f.id).unwrap())
+        };
+
+        // The source keeps its files when the delete fails
+        assert!(matches!(
+            finish_split(&store, &progress).await,
+            Err(VRaftError::TransactionAborted(_))
+        ));
+        assert_eq!(store.file_count(source).await.unwrap(), 3);
+        assert_eq!(content(target, "/src/b.rs").as_deref(), Some("b2"));
+
+        // Finishing again once the source takes deletes completes the split
+        store.fail.store(false, Ordering::SeqCst);
+        finish_split(&store, &progress).await.unwrap();
+        assert_eq!(content(source, "/src/a.rs").as_deref(), Some("a2"));
+        assert_eq!(store.file_count(source).await.unwrap(), 1);
+        assert_eq!(content(target, "/src/b.rs").as_deref(), Some("b2"));
+        assert_eq!(content(target, "/src/c.rs").as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn test_split_journal() {
+        let path = std::env::temp_dir().join(format!("vraftls-split-{}.json", std::process::id()));
+        let journal = SplitJournal::new(&path);
+        assert!(journal.load().unwrap().is_none());
+
+        let progress = SplitProgress {
+            source: RaftGroupId::new(1),
+            target: RaftGroupId::new(2),
+            moved: vec![MovedFile {
+                id: FileId::new(3),
+                path: VfsPath::new("/src/a.rs"),
+            }],
+        };
+        journal.save(&progress).unwrap();
+        let loaded = journal.load().unwrap().unwrap();
+        assert_eq!((loaded.source, loaded.target), (progress.source, progress.target));
+        assert_eq!(loaded.moved[0].path, VfsPath::new("/src/a.rs"));
+
+        journal.clear().unwrap();
+        assert!(journal.load().unwrap().is_none());
+        journal.clear().unwrap();
+    }
+
+    #[test]
+    fn test_split_by_key() {
+        let files: Vec<_> = (0..5)
+            .map(|i| GroupFile {
+                id: FileId::new(i),
+                path: VfsPath::new(format!("/src/f{}.rs", i)),
+                content: String::new(),
+            })
+            .collect();
+        let (kept, moved) = split_by_key(files);
+        assert_eq!((kept.len(), moved.len()), (2, 3));
+
+        // Every moved key sorts after every kept key
+        let max_kept = kept.iter().map(|f| f.path.partition_key().0).max().unwrap();
+        assert!(moved.iter().all(|f| f.path.partition_key().0 > max_kept));
+    }
+}
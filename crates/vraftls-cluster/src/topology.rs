@@ -0,0 +1,232 @@
+//! Cluster topology snapshot
+//!
+//! Combines this node's view of the membership, the group placement from the
+//! metadata group and the leadership claims heard with heartbeats into one
+//! document for CLIs and dashboards. Leaders of groups this node does not
+//! host are as recent as the last heartbeat from their leader.
+
+use crate::leader_balance::GroupLeadership;
+use crate::membership::{ClusterMembership, ClusterNode, NodeStats, NodeStatus};
+use crate::metadata::ClusterMetadata;
+use crate::version::NodeVersion;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use vraftls_core::{NodeId, RaftGroupId, Timestamp};
+
+/// HTTP path serving the topology
+pub const TOPOLOGY_PATH: &str = "/cluster/topology";
+
+/// A node as seen from the answering node
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopologyNode {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub status: NodeStatus,
+    pub region: Option<String>,
+    pub zone: Option<String>,
+    pub rack: Option<String>,
+
+    /// Groups the node hosts a replica of
+    pub groups: Vec<RaftGroupId>,
+
+    /// Groups the node leads
+    pub leading: Vec<RaftGroupId>,
+
+    pub stats: NodeStats,
+    pub version: Option<NodeVersion>,
+    pub last_heartbeat: Timestamp,
+
+    /// Suspicion level (phi) the answering node grades the node with;
+    /// `None` for itself, nodes not heard from, and fixed-timeout detection
+    #[serde(default)]
+    pub suspicion: Option<f64>,
+}
+
+/// A Raft group and where its replicas are
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopologyGroup {
+    pub group_id: RaftGroupId,
+    pub leader: Option<NodeId>,
+
+    /// Term of the leader; `None` if no leader is known
+    pub term: Option<u64>,
+
+    /// Nodes the metadata group placed the group on
+    pub replicas: Vec<NodeId>,
+
+    /// Why the group rejects writes, if it does
+    pub degraded: Option<String>,
+}
+
+/// Nodes and groups of the cluster
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClusterTopology {
+    /// Node that answered
+    pub local_node: NodeId,
+
+    pub nodes: Vec<TopologyNode>,
+    pub groups: Vec<TopologyGroup>,
+}
+
+impl ClusterTopology {
+    /// Collect the topology as this node sees it
+    pub async fn collect(
+        membership: &ClusterMembership,
+        metadata: &ClusterMetadata,
+        local_groups: &[GroupLeadership],
+    ) -> Self {
+        let mut placement = BTreeMap::new();
+        for group_id in metadata.groups().await {
+            placement.insert(group_id, metadata.get_group_nodes(group_id).await);
+        }
+        let degraded = metadata
+            .degraded_groups()
+            .await
+            .into_iter()
+            .map(|group| (group.group_id, group.reason.to_string()))
+            .collect();
+
+        let mut topology = Self::build(
+            membership.local_node_id(),
+            membership.all_nodes(),
+            &placement,
+            local_groups,
+            &degraded,
+        );
+        for node in &mut topology.nodes {
+            node.suspicion = membership.suspicion(node.id);
+        }
+        topology
+    }
+
+    /// Assemble the topology from its parts
+    ///
+    /// A group's leader is the claim with the highest term, whether made by
+    /// this node's Raft instance or by a peer in its heartbeats.
+    pub fn build(
+        local: NodeId,
+        mut nodes: Vec<ClusterNode>,
+        placement: &BTreeMap<RaftGroupId, Vec<NodeId>>,
+        local_groups: &[GroupLeadership],
+        degraded: &BTreeMap<RaftGroupId, String>,
+    ) -> Self {
+        nodes.sort_by_key(|n| n.id);
+
+        let mut leaders: BTreeMap<RaftGroupId, (u64, NodeId)> = BTreeMap::new();
+        let mut claim = |group_id: RaftGroupId, term: u64, node: NodeId| {
+            let current = leaders.entry(group_id).or_insert((term, node));
+            if term > current.0 {
+                *current = (term, node);
+            }
+        };
+        for group in local_groups {
+            if let Some(leader) = group.leader {
+                claim(group.group_id, group.term, leader);
+            }
+        }
+        for node in nodes.iter().filter(|n| n.id != local && n.status != NodeStatus::Down) {
+            for lead in &node.leading {
+                claim(lead.group_id, lead.term, node.id);
+            }
+        }
+
+        let group_ids: BTreeSet<_> = placement
+            .keys()
+            .copied()
+            .chain(local_groups.iter().map(|g| g.group_id))
+            .chain(leaders.keys().copied())
+            .collect();
+        let groups = group_ids
+            .into_iter()
+            .map(|group_id| {
+                let hosted = local_groups.iter().find(|g| g.group_id == group_id);
+                let replicas = match placement.get(&group_id) {
+                    Some(replicas) => replicas.clone(),
+                    None => hosted.map(|g| g.voters.clone()).unwrap_or_default(),
+                };
+                let leader = leaders.get(&group_id);
+                TopologyGroup {
+                    group_id,
+                    leader: leader.map(|(_, node)| *node),
+                    term: leader.map(|(term, _)| *term),
+                    replicas,
+                    degraded: degraded.get(&group_id).cloned(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let nodes = nodes
+            .into_iter()
+            .map(|node| TopologyNode {
+                leading: groups
+                    .iter()
+                    .filter(|g| g.leader == Some(node.id))
+                    .map(|g| g.group_id)
+                    .collect(),
+                id: node.id,
+                addr: node.addr,
+                status: node.status,
+                region: node.region,
+                zone: node.zone,
+                rack: node.rack,
+                groups: node.raft_groups,
+                stats: node.stats,
+                version: node.version,
+                last_heartbeat: node.last_heartbeat,
+                suspicion: None,
+            })
+            .collect();
+
+        Self {
+            local_node: local,
+            nodes,
+            groups,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::membership::LeaderClaim;
+
+    fn node(id: u64, leading: &[(u64, u64)]) -> ClusterNode {
+        ClusterNode {
+            status: NodeStatus::Healthy,
+            raft_groups: vec![RaftGroupId::new(1)],
+            leading: leading
+                .iter()
+                .map(|(group, term)| LeaderClaim {
+                    group_id: RaftGroupId::new(*group),
+                    term: *term,
+                })
+                .collect(),
+            ..ClusterNode::new(NodeId::new(id), format!("127.0.0.1:{}", 8000 + id).parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let placement = BTreeMap::from([(RaftGroupId::new(1), vec![NodeId::new(1), NodeId::new(2)])]);
+        let local_groups = [GroupLeadership {
+            group_id: RaftGroupId::new(1),
+            leader: Some(NodeId::new(1)),
+            voters: vec![NodeId::new(1), NodeId::new(2)],
+            term: 3,
+        }];
+        // Node 2 claims group 1 in a later term, and leads group 2 which
+        // this node does not host
+        let nodes = vec![node(2, &[(1, 4), (2, 1)]), node(1, &[])];
+
+        let topology = ClusterTopology::build(NodeId::new(1), nodes, &placement, &local_groups, &BTreeMap::new());
+
+        assert_eq!(topology.nodes[0].id, NodeId::new(1));
+        assert_eq!(topology.groups.len(), 2);
+        assert_eq!(topology.groups[0].leader, Some(NodeId::new(2)));
+        assert_eq!(topology.groups[0].term, Some(4));
+        assert_eq!(topology.groups[0].replicas, vec![NodeId::new(1), NodeId::new(2)]);
+        assert_eq!(topology.nodes[1].leading, vec![RaftGroupId::new(1), RaftGroupId::new(2)]);
+        assert!(topology.nodes[0].leading.is_empty());
+    }
+}
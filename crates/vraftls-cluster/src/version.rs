@@ -0,0 +1,102 @@
+//! Protocol versions and optional features
+//!
+//! Nodes report their version with every heartbeat. A node whose protocol
+//! range does not overlap ours is kept out of the cluster: it stays joining
+//! instead of becoming healthy. Optional features are only used towards a
+//! node once it has reported supporting them, so a cluster in the middle of
+//! a rolling upgrade keeps working at the level of its oldest node.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// Node-to-node protocol version of this build
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build still talks to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Anti-entropy digests served at `DIGEST_PATH`, with FNV-1a checksums;
+/// digests of builds with the earlier checksum are not comparable
+pub const FEATURE_REPAIR_DIGEST: &str = "repair-digest-fnv1a";
+
+/// Reads from any replica at `/client/read`
+pub const FEATURE_FOLLOWER_READS: &str = "follower-reads";
+
+/// Cluster topology served at `TOPOLOGY_PATH`
+pub const FEATURE_TOPOLOGY: &str = "topology";
+
+/// `VfsCommand::Transaction` log entries, which group splits propose
+pub const FEATURE_VFS_TRANSACTIONS: &str = "vfs-transactions";
+
+/// Optional features of this build
+pub const SUPPORTED_FEATURES: &[&str] = &[
+    FEATURE_REPAIR_DIGEST,
+    FEATURE_FOLLOWER_READS,
+    FEATURE_TOPOLOGY,
+    FEATURE_VFS_TRANSACTIONS,
+];
+
+/// Version and features a node reports
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeVersion {
+    /// Protocol version the node speaks
+    pub protocol: u32,
+
+    /// Oldest protocol version the node still talks to
+    pub min_protocol: u32,
+
+    /// Optional features the node supports
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+
+    /// Release of the node binary, for operators
+    #[serde(default)]
+    pub build: String,
+}
+
+impl NodeVersion {
+    /// Version of this build
+    pub fn current() -> Self {
+        Self {
+            protocol: PROTOCOL_VERSION,
+            min_protocol: MIN_PROTOCOL_VERSION,
+            features: SUPPORTED_FEATURES.iter().map(|f| f.to_string()).collect(),
+            build: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Whether each node speaks a protocol version the other accepts
+    pub fn is_compatible(&self, other: &NodeVersion) -> bool {
+        self.protocol >= other.min_protocol && other.protocol >= self.min_protocol
+    }
+
+    /// Whether the node supports an optional feature
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(protocol: u32, min_protocol: u32) -> NodeVersion {
+        NodeVersion {
+            protocol,
+            min_protocol,
+            features: BTreeSet::new(),
+            build: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_is_compatible() {
+        // A rolling upgrade: the new build still accepts the old protocol
+        assert!(version(2, 1).is_compatible(&version(1, 1)));
+        assert!(version(1, 1).is_compatible(&version(2, 1)));
+
+        // The new build dropped the old protocol
+        assert!(!version(3, 2).is_compatible(&version(1, 1)));
+        assert!(!version(1, 1).is_compatible(&version(3, 2)));
+    }
+}
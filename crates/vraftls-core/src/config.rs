@@ -1,6 +1,8 @@
 //! Configuration types for VRaftLS
 
+use crate::types::LanguageId;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::time::Duration;
@@ -18,13 +20,27 @@ pub struct NodeConfig {
     pub data_dir: PathBuf,
 
     /// Raft configuration
+    #[serde(default)]
     pub raft: RaftConfig,
 
     /// VFS configuration
+    #[serde(default)]
     pub vfs: VfsConfig,
 
     /// Cache configuration
     pub cache: CacheConfig,
+
+    /// Archival of purged logs and snapshots to object storage
+    #[serde(default)]
+    pub archive: Option<ArchiveConfig>,
+
+    /// Cluster topology and placement
+    #[serde(default)]
+    pub cluster: ClusterConfig,
+
+    /// How nodes find each other
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
 }
 
 impl Default for NodeConfig {
@@ -36,12 +52,16 @@ impl Default for NodeConfig {
             raft: RaftConfig::default(),
             vfs: VfsConfig::default(),
             cache: CacheConfig::default(),
+            archive: None,
+            cluster: ClusterConfig::default(),
+            discovery: DiscoveryConfig::default(),
         }
     }
 }
 
 /// Raft consensus configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RaftConfig {
     /// Heartbeat interval
     #[serde(with = "duration_millis")]
@@ -55,9 +75,39 @@ pub struct RaftConfig {
     #[serde(with = "duration_millis")]
     pub election_timeout_max: Duration,
 
+    /// Election priority of this node (higher is preferred, 0 for none)
+    ///
+    /// Should match the priority advertised in the group membership.
+    pub election_priority: u8,
+
     /// Maximum entries per AppendEntries RPC
     pub max_append_entries: u64,
 
+    /// Maximum concurrent AppendEntries requests per follower
+    pub max_inflight_appends: u64,
+
+    /// Maximum replication bytes in flight per follower
+    pub max_inflight_bytes: u64,
+
+    /// Compression of Raft RPC request bodies
+    pub rpc_compression: RpcCompression,
+
+    /// RPC bodies smaller than this are sent uncompressed
+    pub rpc_compression_threshold: u64,
+
+    /// Largest Raft RPC body accepted from a peer, once decompressed
+    pub max_rpc_body_bytes: u64,
+
+    /// Maximum serialized size of a single log entry; larger commands are chunked
+    pub max_entry_bytes: u64,
+
+    /// Maximum entries a follower may lag behind the commit index to serve stale reads
+    pub max_read_lag: u64,
+
+    /// Longest a replica may go without hearing from the leader and still serve stale reads
+    #[serde(with = "duration_millis")]
+    pub max_read_staleness: Duration,
+
     /// Snapshot chunk size for transfer
     pub snapshot_chunk_size: u64,
 
@@ -66,6 +116,31 @@ pub struct RaftConfig {
 
     /// Maximum log bytes before triggering snapshot
     pub max_log_bytes: u64,
+
+    /// Interval between checks of the snapshot thresholds
+    #[serde(with = "duration_millis")]
+    pub snapshot_check_interval: Duration,
+
+    /// Files serialized per batch while building a snapshot
+    pub snapshot_batch_size: u64,
+
+    /// Pause between snapshot batches (zero only yields to other tasks)
+    #[serde(with = "duration_millis")]
+    pub snapshot_throttle: Duration,
+
+    /// Number of most recent snapshots kept on disk
+    pub snapshot_retain_count: u64,
+
+    /// Remove stored snapshots older than this (zero keeps them regardless of age)
+    #[serde(with = "duration_secs")]
+    pub snapshot_max_age: Duration,
+
+    /// Interval between snapshot garbage collection runs
+    #[serde(with = "duration_secs")]
+    pub snapshot_gc_interval: Duration,
+
+    /// Verify the whole Raft log when storage is opened
+    pub scrub_on_startup: bool,
 }
 
 impl Default for RaftConfig {
@@ -74,16 +149,42 @@ impl Default for RaftConfig {
             heartbeat_interval: Duration::from_millis(100),
             election_timeout_min: Duration::from_millis(300),
             election_timeout_max: Duration::from_millis(500),
+            election_priority: 0,
             max_append_entries: 100,
+            max_inflight_appends: 4,
+            max_inflight_bytes: 16 * 1024 * 1024, // 16MB
+            rpc_compression: RpcCompression::Zstd,
+            rpc_compression_threshold: 64 * 1024, // 64KB
+            max_rpc_body_bytes: 64 * 1024 * 1024, // 64MB
+            max_entry_bytes: 512 * 1024, // 512KB
+            max_read_lag: 100,
+            max_read_staleness: Duration::from_secs(2),
             snapshot_chunk_size: 1024 * 1024, // 1MB
             max_log_entries: 10000,
             max_log_bytes: 100 * 1024 * 1024, // 100MB
+            snapshot_check_interval: Duration::from_secs(1),
+            snapshot_batch_size: 256,
+            snapshot_throttle: Duration::ZERO,
+            snapshot_retain_count: 2,
+            snapshot_max_age: Duration::ZERO,
+            snapshot_gc_interval: Duration::from_secs(60),
+            scrub_on_startup: false,
         }
     }
 }
 
+/// Compression algorithm for Raft RPC bodies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RpcCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
 /// Virtual File System configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct VfsConfig {
     /// Maximum file size in bytes
     pub max_file_size: u64,
@@ -93,6 +194,32 @@ pub struct VfsConfig {
 
     /// Enable file content compression
     pub enable_compression: bool,
+
+    /// Files smaller than this are kept uncompressed
+    pub compression_threshold: u64,
+
+    /// Previous versions kept per file
+    pub history_versions: usize,
+
+    /// Spill large or cold file contents to disk
+    pub enable_spill: bool,
+
+    /// Files smaller than this are never spilled
+    pub spill_min_file_size: u64,
+
+    /// Memory file contents may take before large files are spilled
+    pub spill_memory_budget: u64,
+
+    /// Large files not accessed for this long are spilled
+    #[serde(with = "duration_secs")]
+    pub spill_cold_after: Duration,
+
+    /// Keep deleted files, compressed, in a trash they can be restored from
+    pub enable_trash: bool,
+
+    /// How long deleted files stay in the trash
+    #[serde(with = "duration_secs")]
+    pub trash_retention: Duration,
 }
 
 impl Default for VfsConfig {
@@ -101,6 +228,14 @@ impl Default for VfsConfig {
             max_file_size: 10 * 1024 * 1024,   // 10MB
             max_files_per_group: 200,
             enable_compression: true,
+            compression_threshold: 64 * 1024, // 64KB
+            history_versions: 10,
+            enable_spill: true,
+            spill_min_file_size: 256 * 1024,          // 256KB
+            spill_memory_budget: 512 * 1024 * 1024,   // 512MB
+            spill_cold_after: Duration::from_secs(600), // 10 minutes
+            enable_trash: false,
+            trash_retention: Duration::from_secs(24 * 60 * 60), // 1 day
         }
     }
 }
@@ -129,8 +264,327 @@ impl Default for CacheConfig {
     }
 }
 
+/// Failure domains replicas of a group are spread across
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlacementConstraint {
+    /// No constraint
+    None,
+    /// Not all replicas in the same rack
+    Rack,
+    /// Not all replicas in the same zone
+    #[default]
+    Zone,
+}
+
+/// Policy choosing the nodes of new Raft groups
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlacementPolicyKind {
+    /// Healthy nodes in turn
+    RoundRobin,
+    /// Nodes hosting the fewest groups and files
+    LeastLoaded,
+    /// Least loaded nodes spread across the failure domains of `placement`
+    #[default]
+    ZoneAware,
+}
+
+/// Strategy deciding when a silent node is suspect or down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureDetectionKind {
+    /// Down after `failure_timeout` without heartbeats
+    FixedTimeout,
+    /// Suspicion adapted to each node's heartbeat jitter
+    #[default]
+    PhiAccrual,
+    /// Phi-accrual, but down only once `failure_confirmations` nodes agree
+    QuorumConfirmed,
+}
+
+/// Cluster topology configuration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Region this node runs in; gateways prefer replicas in their own
+    /// region for reads
+    pub region: Option<String>,
+
+    /// Zone (e.g. availability zone) this node runs in, within its region
+    pub zone: Option<String>,
+
+    /// Rack this node runs in, within its zone
+    pub rack: Option<String>,
+
+    /// Failure domain constraint for replica placement
+    pub placement: PlacementConstraint,
+
+    /// Policy choosing the nodes of new groups
+    pub placement_policy: PlacementPolicyKind,
+
+    /// Leaderships a node may hold beyond the least loaded voter it shares a group with
+    pub leader_max_imbalance: usize,
+
+    /// Interval between leader balancing checks
+    #[serde(with = "duration_secs")]
+    pub leader_balance_interval: Duration,
+
+    /// Nodes to wait for before bootstrapping a new cluster; `None` skips bootstrap
+    pub expected_nodes: Option<usize>,
+
+    /// How long bootstrap waits for the expected nodes
+    #[serde(with = "duration_secs")]
+    pub bootstrap_timeout: Duration,
+
+    /// Replicas of each data group
+    pub group_replicas: usize,
+
+    /// Interval between anti-entropy repair rounds
+    #[serde(with = "duration_secs")]
+    pub repair_interval: Duration,
+
+    /// Interval between membership heartbeats to each peer
+    #[serde(with = "duration_millis")]
+    pub heartbeat_interval: Duration,
+
+    /// Strategy deciding when nodes are suspect or down
+    pub failure_detection: FailureDetectionKind,
+
+    /// Phi above which the phi-accrual strategies consider a node suspect
+    pub suspect_phi: f64,
+
+    /// Phi above which the phi-accrual strategies consider a node down
+    pub down_phi: f64,
+
+    /// Heartbeat inter-arrival samples the phi-accrual strategies keep per node
+    pub phi_window_size: usize,
+
+    /// Silence after which the fixed-timeout strategy considers a node down
+    #[serde(with = "duration_secs")]
+    pub failure_timeout: Duration,
+
+    /// Nodes, this one included, that must find a node down under the
+    /// quorum-confirmed strategy
+    pub failure_confirmations: usize,
+
+    /// How long a leaving node waits for its leaderships to be taken over
+    #[serde(with = "duration_secs")]
+    pub leave_timeout: Duration,
+
+    /// Flips between healthy and suspect a node may make within
+    /// `flap_window` before it is quarantined from placement
+    pub flap_threshold: usize,
+
+    /// Window status flips are counted in
+    #[serde(with = "duration_secs")]
+    pub flap_window: Duration,
+
+    /// Time a quarantined node must go without flips to be released
+    #[serde(with = "duration_secs")]
+    pub flap_cooldown: Duration,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            region: None,
+            zone: None,
+            rack: None,
+            placement: PlacementConstraint::default(),
+            placement_policy: PlacementPolicyKind::default(),
+            leader_max_imbalance: 1,
+            leader_balance_interval: Duration::from_secs(30),
+            expected_nodes: None,
+            bootstrap_timeout: Duration::from_secs(300),
+            group_replicas: 3,
+            repair_interval: Duration::from_secs(600),
+            heartbeat_interval: Duration::from_secs(1),
+            failure_detection: FailureDetectionKind::default(),
+            suspect_phi: 5.0,
+            down_phi: 8.0,
+            phi_window_size: 100,
+            failure_timeout: Duration::from_secs(10),
+            failure_confirmations: 2,
+            leave_timeout: Duration::from_secs(30),
+            flap_threshold: 5,
+            flap_window: Duration::from_secs(300),
+            flap_cooldown: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Service discovery backend and its settings
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum DiscoveryConfig {
+    /// Only the peers given on the command line
+    #[default]
+    Static,
+    /// Multicast DNS on the local network, for development clusters
+    Mdns {
+        /// DNS-SD service type (`_vraftls._tcp.local` if unset)
+        #[serde(default)]
+        service_type: Option<String>,
+    },
+    /// Instances of a Consul service, kept registered with a TTL check
+    Consul {
+        /// Consul agent URL (e.g. `http://127.0.0.1:8500`)
+        agent: String,
+        /// Service name shared by every node of the cluster
+        service: String,
+        /// ACL token
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Keys under a prefix in etcd, attached to a lease each node keeps alive
+    Etcd {
+        /// etcd client URL (e.g. `http://127.0.0.1:2379`)
+        endpoint: String,
+        /// Key prefix shared by every node of the cluster (`/vraftls/nodes/` if unset)
+        #[serde(default)]
+        prefix: Option<String>,
+        /// Auth token
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Ready endpoints of a Kubernetes Service, read with the pod's service account
+    Kubernetes {
+        /// Service whose endpoints are the cluster nodes
+        service: String,
+        /// Name of the Service port used for cluster traffic (first port if unset)
+        #[serde(default)]
+        port_name: Option<String>,
+    },
+}
+
+/// Settings shared by the whole cluster, stored in the metadata group
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SharedConfig {
+    /// Cache entry TTL in seconds; `None` keeps each node's `cache.ttl`
+    pub cache_ttl_secs: Option<u64>,
+
+    /// Language server command by language ID (e.g. `rust`), replacing the built-in one
+    pub ls_commands: BTreeMap<String, String>,
+
+    /// Feature flags by name
+    pub features: BTreeMap<String, bool>,
+}
+
+impl SharedConfig {
+    /// Cache entry TTL, if set cluster-wide
+    pub fn cache_ttl(&self) -> Option<Duration> {
+        self.cache_ttl_secs.map(Duration::from_secs)
+    }
+
+    /// Language server command override for a language
+    pub fn ls_command(&self, language: &str) -> Option<&str> {
+        self.ls_commands.get(language).map(String::as_str)
+    }
+
+    /// Whether a feature is enabled; unknown features are off
+    pub fn feature(&self, name: &str) -> bool {
+        self.features.get(name).copied().unwrap_or(false)
+    }
+
+    /// Apply a single change
+    pub fn apply(&mut self, change: SharedConfigChange) {
+        match change {
+            SharedConfigChange::CacheTtl { secs } => self.cache_ttl_secs = secs,
+            SharedConfigChange::LsCommand { language, command: Some(command) } => {
+                self.ls_commands.insert(language, command);
+            }
+            SharedConfigChange::LsCommand { language, command: None } => {
+                self.ls_commands.remove(&language);
+            }
+            SharedConfigChange::Feature { name, enabled: Some(enabled) } => {
+                self.features.insert(name, enabled);
+            }
+            SharedConfigChange::Feature { name, enabled: None } => {
+                self.features.remove(&name);
+            }
+        }
+    }
+}
+
+/// Change to one shared setting; `None` unsets it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedConfigChange {
+    CacheTtl { secs: Option<u64> },
+    LsCommand { language: String, command: Option<String> },
+    Feature { name: String, enabled: Option<bool> },
+}
+
+/// Object storage archive configuration (S3, the GCS XML API or another
+/// store taking bearer tokens)
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ArchiveConfig {
+    /// Object storage endpoint (e.g. `https://storage.googleapis.com`)
+    pub endpoint: String,
+
+    /// Bucket that receives archived objects
+    pub bucket: String,
+
+    /// Key prefix for this node's objects
+    pub prefix: String,
+
+    /// Bearer token sent with uploads (e.g. a GCS OAuth access token)
+    #[serde(default)]
+    pub auth_token: Option<String>,
+
+    /// Credentials for signing uploads with AWS Signature Version 4, for S3
+    /// and stores compatible with it
+    #[serde(default)]
+    pub s3: Option<S3Credentials>,
+
+    /// Maximum archive uploads waiting to be sent
+    pub queue_size: usize,
+
+    /// Upload attempts before an object is given up on
+    pub max_attempts: u32,
+
+    /// Delay between upload attempts
+    #[serde(with = "duration_millis")]
+    pub retry_backoff: Duration,
+}
+
+impl ArchiveConfig {
+    pub fn new(endpoint: impl Into<String>, bucket: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            bucket: bucket.into(),
+            prefix: "vraftls".to_string(),
+            auth_token: None,
+            s3: None,
+            queue_size: 64,
+            max_attempts: 5,
+            retry_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Credentials used to sign archive uploads to S3-compatible storage
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct S3Credentials {
+    /// Region the bucket lives in (e.g. `us-east-1`)
+    pub region: String,
+
+    /// Access key ID
+    pub access_key_id: String,
+
+    /// Secret access key
+    pub secret_access_key: String,
+
+    /// Session token of temporary credentials
+    #[serde(default)]
+    pub session_token: Option<String>,
+}
+
 /// Gateway configuration
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct GatewayConfig {
     /// Cluster nodes to connect to
     pub cluster_nodes: Vec<SocketAddr>,
@@ -141,6 +595,32 @@ pub struct GatewayConfig {
 
     /// Connection pool size per node
     pub pool_size: u32,
+
+    /// Language servers by language ID (e.g. `rust`, or the file extension
+    /// of other languages such as `zig`), replacing the built-in ones
+    pub language_servers: BTreeMap<String, LanguageServerConfig>,
+
+    /// Limits on the language servers running at once
+    pub server_limits: LanguageServerLimits,
+
+    /// Time language servers get to answer each kind of request
+    pub server_timeouts: RequestTimeouts,
+
+    /// Pause in typing after which an editor's changes are written to the
+    /// VFS and forwarded to the language servers, as one burst
+    #[serde(with = "duration_millis")]
+    pub change_debounce: Duration,
+
+    /// File the documents editors have open are saved to, for a restarted
+    /// gateway to reopen them with its language servers; none if unset
+    pub session_file: Option<PathBuf>,
+
+    /// Region the gateway runs in; reads go to replicas in the same region
+    /// instead of the leader when set
+    pub region: Option<String>,
+
+    /// Cluster node running alongside the gateway, preferred for reads
+    pub local_node: Option<u64>,
 }
 
 impl Default for GatewayConfig {
@@ -149,8 +629,134 @@ impl Default for GatewayConfig {
             cluster_nodes: vec!["127.0.0.1:8080".parse().unwrap()],
             request_timeout: Duration::from_secs(30),
             pool_size: 10,
+            language_servers: BTreeMap::new(),
+            server_limits: LanguageServerLimits::default(),
+            server_timeouts: RequestTimeouts::default(),
+            change_debounce: Duration::from_millis(50),
+            session_file: None,
+            region: None,
+            local_node: None,
+        }
+    }
+}
+
+/// Time a language server gets to answer a request, by LSP method
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestTimeouts {
+    /// Timeout of methods not listed in `methods`
+    #[serde(with = "duration_millis")]
+    pub default: Duration,
+
+    /// Timeouts in milliseconds by method (e.g. `textDocument/completion`)
+    pub methods: BTreeMap<String, u64>,
+}
+
+impl RequestTimeouts {
+    /// Timeout of a request
+    pub fn for_method(&self, method: &str) -> Duration {
+        self.methods
+            .get(method)
+            .map_or(self.default, |millis| Duration::from_millis(*millis))
+    }
+}
+
+impl Default for RequestTimeouts {
+    fn default() -> Self {
+        // Completion is typed through and useless late; a rename may
+        // have to check the whole workspace
+        let methods = [
+            ("textDocument/completion", 2_000),
+            ("textDocument/rename", 30_000),
+            ("workspace/symbol", 10_000),
+        ];
+        Self {
+            default: Duration::from_secs(30),
+            methods: methods.into_iter().map(|(method, millis)| (method.to_string(), millis)).collect(),
+        }
+    }
+}
+
+/// Limits on a gateway's language server processes
+///
+/// Servers without open documents are shut down when past a limit and
+/// spawned again when a document of their language is opened.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LanguageServerLimits {
+    /// Time a server without open documents is kept running unused
+    #[serde(with = "duration_secs")]
+    pub idle_timeout: Duration,
+
+    /// Most servers running at once
+    pub max_servers: usize,
+
+    /// Resident memory the servers may use together, in bytes
+    pub max_memory_bytes: Option<u64>,
+
+    /// Interval between checks of the idle and memory limits
+    #[serde(with = "duration_secs")]
+    pub check_interval: Duration,
+
+    /// Requests each server works on at once; the others wait, most
+    /// urgent first
+    pub max_concurrent_requests: usize,
+
+    /// Background requests, e.g. code lenses, waiting for a server before
+    /// more are shed
+    pub max_background_queue: usize,
+}
+
+impl Default for LanguageServerLimits {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(600),
+            max_servers: 8,
+            max_memory_bytes: None,
+            check_interval: Duration::from_secs(30),
+            max_concurrent_requests: 4,
+            max_background_queue: 16,
+        }
+    }
+}
+
+/// How to run the language server of a language
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct LanguageServerConfig {
+    /// Program to run
+    pub command: String,
+
+    /// Arguments passed to the program
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Environment variables set for the program
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// `initializationOptions` sent to the server instead of the editor's
+    #[serde(default)]
+    pub initialization_options: Option<serde_json::Value>,
+}
+
+impl LanguageServerConfig {
+    pub fn new(command: impl Into<String>) -> Self {
+        Self {
+            command: command.into(),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            initialization_options: None,
         }
     }
+
+    /// Built-in server of a language, talking LSP over stdio
+    pub fn builtin(language: &LanguageId) -> Option<Self> {
+        let command = language.language_server_command()?;
+        Some(Self {
+            args: vec!["--stdio".to_string()],
+            ..Self::new(command)
+        })
+    }
 }
 
 // Serde helpers for Duration
@@ -193,3 +799,60 @@ mod duration_secs {
         Ok(Duration::from_secs(secs))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_node_config() {
+        let config: NodeConfig = serde_json::from_str(
+            r#"{
+                "node_id": 2,
+                "cluster_addr": "127.0.0.1:8082",
+                "data_dir": "/var/lib/vraftls",
+                "raft": { "heartbeat_interval": 50 },
+                "cache": { "l1_max_entries": 100, "l2_max_bytes": 1048576, "ttl": 60 },
+                "cluster": { "suspect_phi": 3.5 }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.raft.heartbeat_interval, Duration::from_millis(50));
+        assert_eq!(config.cluster.suspect_phi, 3.5);
+        assert_eq!(config.cluster.down_phi, ClusterConfig::default().down_phi);
+        assert_eq!(config.raft.election_timeout_min, RaftConfig::default().election_timeout_min);
+        assert_eq!(config.vfs.max_file_size, VfsConfig::default().max_file_size);
+    }
+
+    #[test]
+    fn test_shared_config_apply() {
+        let mut config = SharedConfig::default();
+        assert_eq!(config.cache_ttl(), None);
+        assert!(!config.feature("scatter-gather"));
+
+        config.apply(SharedConfigChange::CacheTtl { secs: Some(30) });
+        config.apply(SharedConfigChange::LsCommand {
+            language: "rust".to_string(),
+            command: Some("rust-analyzer --log-file /tmp/ra.log".to_string()),
+        });
+        config.apply(SharedConfigChange::Feature {
+            name: "scatter-gather".to_string(),
+            enabled: Some(true),
+        });
+        assert_eq!(config.cache_ttl(), Some(Duration::from_secs(30)));
+        assert_eq!(config.ls_command("rust"), Some("rust-analyzer --log-file /tmp/ra.log"));
+        assert!(config.feature("scatter-gather"));
+
+        // `None` unsets each setting again
+        config.apply(SharedConfigChange::CacheTtl { secs: None });
+        config.apply(SharedConfigChange::LsCommand {
+            language: "rust".to_string(),
+            command: None,
+        });
+        config.apply(SharedConfigChange::Feature {
+            name: "scatter-gather".to_string(),
+            enabled: None,
+        });
+        assert_eq!(config, SharedConfig::default());
+    }
+}
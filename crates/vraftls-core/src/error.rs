@@ -19,6 +19,21 @@ pub enum VRaftError {
     #[error("raft log error: {0}")]
     RaftLog(String),
 
+    #[error("replica too stale to serve read: {lag} entries behind (max {max_lag})")]
+    StaleRead { lag: u64, max_lag: u64 },
+
+    #[error("replica has not heard from the leader within {max_ms}ms (last contact {since_ms:?}ms ago)")]
+    LeaderContactLost { since_ms: Option<u64>, max_ms: u64 },
+
+    #[error("membership reconfiguration already in progress")]
+    ReconfigurationInProgress,
+
+    #[error("stale routing: epoch {epoch} is older than current epoch {current}")]
+    StaleRouting { epoch: u64, current: u64 },
+
+    #[error("raft group {group} is degraded: {reason}")]
+    GroupDegraded { group: RaftGroupId, reason: String },
+
     // VFS errors
     #[error("file not found: {0:?}")]
     FileNotFound(FileId),
@@ -36,6 +51,9 @@ pub enum VRaftError {
     #[error("node unreachable: {0}")]
     NodeUnreachable(NodeId),
 
+    #[error("node {node} still hosts {replicas} replicas")]
+    NodeNotDrained { node: NodeId, replicas: usize },
+
     #[error("network timeout")]
     Timeout,
 
@@ -55,6 +73,10 @@ pub enum VRaftError {
     #[error("invalid LSP request: {0}")]
     InvalidLspRequest(String),
 
+    // Search errors
+    #[error("invalid search pattern: {0}")]
+    InvalidSearchPattern(String),
+
     // Storage errors
     #[error("storage error: {0}")]
     Storage(String),
@@ -69,6 +91,10 @@ pub enum VRaftError {
     #[error("transaction timeout")]
     TransactionTimeout,
 
+    // Configuration errors
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+
     // Internal errors
     #[error("internal error: {0}")]
     Internal(String),
@@ -82,6 +108,7 @@ impl VRaftError {
         matches!(
             self,
             Self::NotLeader { .. }
+                | Self::StaleRouting { .. }
                 | Self::NodeUnreachable(_)
                 | Self::Timeout
                 | Self::TransactionTimeout
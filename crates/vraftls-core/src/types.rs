@@ -113,6 +113,28 @@ impl fmt::Debug for ClientId {
     }
 }
 
+/// Transaction spanning Raft groups, as its coordinator numbers it
+#[derive(Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct TransactionId(pub u64);
+
+impl TransactionId {
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Debug for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TransactionId({})", self.0)
+    }
+}
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// Language identifier for language servers
 #[derive(Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub enum LanguageId {
@@ -136,6 +158,18 @@ impl LanguageId {
         }
     }
 
+    /// Identifier of the language in `TextDocumentItem`s
+    pub fn lsp_name(&self) -> &str {
+        match self {
+            Self::Rust => "rust",
+            Self::TypeScript => "typescript",
+            Self::JavaScript => "javascript",
+            Self::Go => "go",
+            Self::Python => "python",
+            Self::Other(name) => name,
+        }
+    }
+
     pub fn language_server_command(&self) -> Option<&'static str> {
         match self {
             Self::Rust => Some("rust-analyzer"),
@@ -145,6 +179,17 @@ impl LanguageId {
             Self::Other(_) => None,
         }
     }
+
+    /// Top-level sections of the editor settings the language server reads
+    pub fn settings_sections(&self) -> &'static [&'static str] {
+        match self {
+            Self::Rust => &["rust-analyzer"],
+            Self::TypeScript | Self::JavaScript => &["typescript", "javascript"],
+            Self::Go => &["gopls"],
+            Self::Python => &["python", "pyright"],
+            Self::Other(_) => &[],
+        }
+    }
 }
 
 /// Timestamp in milliseconds since Unix epoch
@@ -1,17 +1,279 @@
 //! VRaftLS Gateway - LSP gateway binary
 
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
 use clap::Parser;
-use tower_lsp::{LspService, Server};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use vraftls_lsp::LspGateway;
+use tokio::sync::broadcast;
+use vraftls_cluster::{
+    ClusterEvent, ClusterEvents, ClusterTopology, HttpRoutingLookup, MetadataClient, MetadataClientConfig, NodeStatus,
+    SHARED_CONFIG_PATH, TOPOLOGY_PATH,
+};
+use vraftls_core::{GatewayConfig, NodeId, PartitionKey, Result, SharedConfig, VRaftError};
+use vraftls_lsp::{GatewaySessions, LspRouter, PartitionRoute, RouteLookup};
+
+/// How often the cluster's nodes and group leaders are refreshed
+const TOPOLOGY_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Parser)]
 #[command(name = "vraftls-gateway")]
 #[command(about = "VRaftLS LSP gateway")]
 struct Args {
-    /// Use stdio for LSP communication (default)
-    #[arg(long, default_value = "true")]
+    /// Serve an editor over stdio; the default unless --listen is given
+    #[arg(long)]
     stdio: bool,
+
+    /// Also accept editors over TCP on this address
+    #[arg(long)]
+    listen: Option<SocketAddr>,
+
+    /// Gateway configuration file (JSON); language servers are read from it
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Serve per-method request metrics over HTTP on this address
+    #[arg(long)]
+    metrics: Option<SocketAddr>,
+
+    /// Cluster nodes to route documents to by their partition
+    /// (comma-separated); overrides the configuration's cluster nodes
+    #[arg(long, value_delimiter = ',')]
+    cluster: Vec<SocketAddr>,
+
+    /// Region this gateway runs in, for reads from replicas in it;
+    /// overrides the configuration's region
+    #[arg(long)]
+    region: Option<String>,
+
+    /// ID of the cluster node running alongside this gateway, preferred for
+    /// reads; overrides the configuration's local node
+    #[arg(long)]
+    node_id: Option<u64>,
+}
+
+/// Routes of partitions, as the cluster's metadata group holds them
+struct ClusterRoutes(MetadataClient<HttpRoutingLookup>);
+
+#[tower_lsp::async_trait]
+impl RouteLookup for ClusterRoutes {
+    async fn route(&self, key: &PartitionKey) -> Result<Option<PartitionRoute>> {
+        let route = self.0.lookup(key).await?;
+        Ok(route.map(|entry| PartitionRoute {
+            group_id: entry.group_id,
+            leader: entry.leader,
+            replicas: entry.replicas,
+        }))
+    }
+}
+
+/// Keep the router's view of the cluster's nodes and leaders current, and
+/// the settings shared by the cluster applied
+///
+/// Changes between successive topologies are published on `events`.
+async fn follow_topology(nodes: Vec<SocketAddr>, sessions: Arc<GatewaySessions>, events: ClusterEvents) {
+    let router = sessions.router().clone();
+    let client = reqwest::Client::builder()
+        .timeout(TOPOLOGY_INTERVAL)
+        .build()
+        .unwrap_or_default();
+    let mut interval = tokio::time::interval(TOPOLOGY_INTERVAL);
+    let mut previous: Option<ClusterTopology> = None;
+    loop {
+        interval.tick().await;
+        let mut topology = None;
+        for addr in &nodes {
+            match fetch_topology(&client, *addr).await {
+                Ok(fetched) => {
+                    topology = Some(fetched);
+                    match fetch_shared_config(&client, *addr).await {
+                        Ok(config) => sessions.apply_shared_config(&config),
+                        Err(e) => tracing::debug!("Failed to fetch shared config from {}: {}", addr, e),
+                    }
+                    break;
+                }
+                Err(e) => tracing::debug!("Failed to fetch topology from {}: {}", addr, e),
+            }
+        }
+        let Some(topology) = topology else {
+            tracing::warn!("No cluster node answered for its topology");
+            continue;
+        };
+
+        for node in &topology.nodes {
+            router.update_node_addr(node.id, node.addr).await;
+            router.update_node_region(node.id, node.region.clone()).await;
+        }
+        for group in &topology.groups {
+            if let Some(leader) = group.leader {
+                router.update_leader(group.group_id, leader).await;
+            }
+            router.update_replicas(group.group_id, group.replicas.clone()).await;
+            match &group.degraded {
+                Some(reason) => router.mark_degraded(group.group_id, reason.clone()).await,
+                None => router.clear_degraded(group.group_id).await,
+            }
+        }
+        if let Some(previous) = &previous {
+            publish_changes(previous, &topology, &events);
+        }
+        previous = Some(topology);
+    }
+}
+
+/// Publish the events that turned one topology into the next
+fn publish_changes(before: &ClusterTopology, after: &ClusterTopology, events: &ClusterEvents) {
+    for node in &before.nodes {
+        match after.nodes.iter().find(|n| n.id == node.id) {
+            Some(now) if now.status != node.status => {
+                events.status_changed(node.id, node.status.clone(), now.status.clone())
+            },
+            Some(_) => {}
+            None => events.emit(ClusterEvent::NodeLeft { node_id: node.id }),
+        }
+    }
+
+    for group in &after.groups {
+        let Some(was) = before.groups.iter().find(|g| g.group_id == group.group_id) else {
+            continue;
+        };
+        if was.leader != group.leader {
+            events.emit(ClusterEvent::LeaderChanged {
+                group_id: group.group_id,
+                leader: group.leader,
+            });
+        }
+        let removed: Vec<_> = was.replicas.iter().filter(|n| !group.replicas.contains(n)).copied().collect();
+        let added: Vec<_> = group.replicas.iter().filter(|n| !was.replicas.contains(n)).copied().collect();
+        match (removed.as_slice(), added.as_slice()) {
+            ([], []) => {}
+            (&[from], &[to]) => events.emit(ClusterEvent::GroupMoved {
+                group_id: group.group_id,
+                from,
+                to,
+            }),
+            _ => events.emit(ClusterEvent::ReplicasChanged {
+                group_id: group.group_id,
+                removed,
+                added,
+            }),
+        }
+    }
+}
+
+/// Drop the routes cluster events make stale, so no request keeps going
+/// to a demoted or dead node until it times out
+async fn invalidate_routes(
+    mut events: broadcast::Receiver<ClusterEvent>,
+    router: Arc<LspRouter>,
+    routes: MetadataClient<HttpRoutingLookup>,
+) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                tracing::warn!("Missed {} cluster events, dropping all routes", missed);
+                router.clear().await;
+                routes.clear();
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        match event {
+            ClusterEvent::LeaderChanged { group_id, leader } => {
+                routes.invalidate_group(group_id);
+                router.leader_changed(group_id, leader).await;
+            }
+            ClusterEvent::GroupMoved { group_id, from, to } => {
+                routes.invalidate_group(group_id);
+                router.group_moved(group_id, from, to).await;
+            }
+            ClusterEvent::ReplicasChanged { group_id, removed, added } => {
+                routes.invalidate_group(group_id);
+                router.replicas_changed(group_id, &removed, &added).await;
+            }
+            ClusterEvent::NodeLeft { node_id }
+            | ClusterEvent::NodeStatusChanged {
+                node_id,
+                to: NodeStatus::Down,
+                ..
+            } => {
+                // Cached routes may still name the node as leader or replica
+                routes.clear();
+                router.node_down(node_id).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn fetch_topology(client: &reqwest::Client, addr: SocketAddr) -> Result<ClusterTopology> {
+    client
+        .get(format!("http://{}{}", addr, TOPOLOGY_PATH))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| VRaftError::Serialization(e.to_string()))
+}
+
+async fn fetch_shared_config(client: &reqwest::Client, addr: SocketAddr) -> Result<SharedConfig> {
+    client
+        .get(format!("http://{}{}", addr, SHARED_CONFIG_PATH))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| VRaftError::Serialization(e.to_string()))
+}
+
+/// Serve the gateway's metrics at `GET /metrics`
+async fn serve_metrics(listener: TcpListener, sessions: Arc<GatewaySessions>) {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .with_state(sessions);
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!("Metrics server stopped: {}", e);
+    }
+}
+
+async fn metrics(State(sessions): State<Arc<GatewaySessions>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        sessions.metrics().render(),
+    )
+}
+
+/// Serve every editor that connects over TCP
+async fn serve_tcp(listener: TcpListener, sessions: Arc<GatewaySessions>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        tracing::info!("Editor connected from {}", peer);
+
+        let sessions = sessions.clone();
+        tokio::spawn(async move {
+            let (read, write) = stream.into_split();
+            sessions.serve(read, write).await;
+            tracing::info!("Editor at {} disconnected", peer);
+        });
+    }
 }
 
 #[tokio::main]
@@ -22,16 +284,66 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let _args = Args::parse();
+    let args = Args::parse();
 
     tracing::info!("Starting VRaftLS gateway");
 
-    let stdin = tokio::io::stdin();
-    let stdout = tokio::io::stdout();
+    let config: GatewayConfig = match &args.config {
+        Some(path) => serde_json::from_slice(&std::fs::read(path)?)?,
+        None => GatewayConfig::default(),
+    };
+
+    // Editors over stdio and TCP share one VFS and set of language servers
+    let sessions = GatewaySessions::with_config(&config);
+
+    // Without cluster nodes every document is served locally
+    let cluster = if args.cluster.is_empty() {
+        config.cluster_nodes.clone()
+    } else {
+        args.cluster.clone()
+    };
+    if !cluster.is_empty() {
+        tracing::info!("Routing documents across cluster nodes {:?}", cluster);
+        let lookup = HttpRoutingLookup::new(cluster.clone(), config.request_timeout);
+        let routes = MetadataClient::new(lookup, MetadataClientConfig::default());
+        routes.spawn_refresh();
+        sessions.router().set_routes(Arc::new(ClusterRoutes(routes.clone()))).await;
+
+        // Reads go to nearby replicas rather than always to the leader
+        let region = args.region.clone().or(config.region.clone());
+        if let Some(region) = &region {
+            tracing::info!("Reading from replicas in region {}", region);
+        }
+        sessions.router().set_local_region(region).await;
+        if let Some(node_id) = args.node_id.or(config.local_node) {
+            sessions.router().set_local_node(NodeId::new(node_id)).await;
+        }
+
+        let events = ClusterEvents::new();
+        tokio::spawn(invalidate_routes(events.subscribe(), sessions.router().clone(), routes));
+        tokio::spawn(follow_topology(cluster, sessions.clone(), events));
+    }
+
+    if let Some(addr) = args.metrics {
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Serving metrics on {}", listener.local_addr()?);
+        tokio::spawn(serve_metrics(listener, sessions.clone()));
+    }
+
+    let Some(addr) = args.listen else {
+        sessions.serve(tokio::io::stdin(), tokio::io::stdout()).await;
+        return Ok(());
+    };
 
-    let (service, socket) = LspService::new(|client| LspGateway::new(client));
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Listening for editors on {}", listener.local_addr()?);
+    let tcp = tokio::spawn(serve_tcp(listener, sessions.clone()));
 
-    Server::new(stdin, stdout, socket).serve(service).await;
+    if args.stdio {
+        sessions.serve(tokio::io::stdin(), tokio::io::stdout()).await;
+    } else {
+        tcp.await?;
+    }
 
     Ok(())
 }
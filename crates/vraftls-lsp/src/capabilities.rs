@@ -0,0 +1,115 @@
+//! Capabilities the gateway announces for its language servers
+//!
+//! Which requests the gateway can answer depends on the language servers it
+//! has spawned, and they are spawned only once documents are opened. Editors
+//! that register capabilities dynamically are therefore announced only what
+//! the gateway answers itself at `initialize`; each provider a server turns
+//! out to have is registered with them later, for the server's language, as
+//! if the server had registered it. Editors that cannot register
+//! capabilities keep the full static set.
+//!
+//! Capabilities are read as the editor and the servers sent them, since
+//! lsp-types does not know the newest ones, e.g. inline completion.
+
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use tower_lsp::lsp_types::{Registration, ServerCapabilities};
+use vraftls_core::LanguageId;
+
+use crate::semantic_tokens::gateway_legend;
+
+/// Methods registered per language, with the editor's capability telling it
+/// registers them dynamically and the server's capability providing them
+const PROVIDERS: &[(&str, &str, &str)] = &[
+    ("textDocument/completion", "completion", "completionProvider"),
+    ("textDocument/hover", "hover", "hoverProvider"),
+    ("textDocument/signatureHelp", "signatureHelp", "signatureHelpProvider"),
+    ("textDocument/definition", "definition", "definitionProvider"),
+    ("textDocument/declaration", "declaration", "declarationProvider"),
+    ("textDocument/typeDefinition", "typeDefinition", "typeDefinitionProvider"),
+    ("textDocument/implementation", "implementation", "implementationProvider"),
+    ("textDocument/references", "references", "referencesProvider"),
+    ("textDocument/documentHighlight", "documentHighlight", "documentHighlightProvider"),
+    ("textDocument/documentSymbol", "documentSymbol", "documentSymbolProvider"),
+    ("textDocument/codeAction", "codeAction", "codeActionProvider"),
+    ("textDocument/codeLens", "codeLens", "codeLensProvider"),
+    ("textDocument/documentLink", "documentLink", "documentLinkProvider"),
+    ("textDocument/documentColor", "colorProvider", "colorProvider"),
+    ("textDocument/formatting", "formatting", "documentFormattingProvider"),
+    ("textDocument/rangeFormatting", "rangeFormatting", "documentRangeFormattingProvider"),
+    ("textDocument/onTypeFormatting", "onTypeFormatting", "documentOnTypeFormattingProvider"),
+    ("textDocument/rename", "rename", "renameProvider"),
+    ("textDocument/foldingRange", "foldingRange", "foldingRangeProvider"),
+    ("textDocument/selectionRange", "selectionRange", "selectionRangeProvider"),
+    ("textDocument/linkedEditingRange", "linkedEditingRange", "linkedEditingRangeProvider"),
+    ("textDocument/prepareCallHierarchy", "callHierarchy", "callHierarchyProvider"),
+    ("textDocument/semanticTokens", "semanticTokens", "semanticTokensProvider"),
+    ("textDocument/inlayHint", "inlayHint", "inlayHintProvider"),
+    ("textDocument/inlineValue", "inlineValue", "inlineValueProvider"),
+    ("textDocument/inlineCompletion", "inlineCompletion", "inlineCompletionProvider"),
+];
+
+
+/// Provider methods an editor registers dynamically, by the capabilities in
+/// its `initialize` request
+pub fn dynamic_methods(client: &Value) -> HashSet<String> {
+    let text_document = &client["textDocument"];
+    PROVIDERS
+        .iter()
+        .filter(|(_, client_key, _)| text_document[client_key]["dynamicRegistration"] == Value::Bool(true))
+        .map(|(method, _, _)| method.to_string())
+        .collect()
+}
+
+/// Leave the providers an editor registers dynamically out of the
+/// capabilities announced at `initialize`
+pub fn without_dynamic(capabilities: ServerCapabilities, dynamic: &HashSet<String>) -> ServerCapabilities {
+    let mut value = match serde_json::to_value(&capabilities) {
+        Ok(Value::Object(value)) => value,
+        _ => return capabilities,
+    };
+    for (method, _, provider) in PROVIDERS {
+        if dynamic.contains(*method) {
+            value.remove(*provider);
+        }
+    }
+    serde_json::from_value(Value::Object(value)).unwrap_or(capabilities)
+}
+
+/// Providers to announce at `initialize` that `ServerCapabilities` has no
+/// field for, unless the editor registers them dynamically
+pub fn untyped_providers(dynamic: &HashSet<String>) -> Vec<(&'static str, Value)> {
+    let mut providers = Vec::new();
+    if !dynamic.contains("textDocument/inlineCompletion") {
+        providers.push(("inlineCompletionProvider", Value::Bool(true)));
+    }
+    providers
+}
+
+/// Registrations of the providers a language's server has
+///
+/// Each is limited to the server's language and carries the server's
+/// options, except semantic tokens, which editors get in the gateway's legend.
+pub fn provider_registrations(language: &LanguageId, capabilities: &Value) -> Vec<Registration> {
+    let selector = json!([{ "language": language.lsp_name() }]);
+    PROVIDERS
+        .iter()
+        .filter_map(|(method, _, provider)| {
+            let mut options = match &capabilities[provider] {
+                Value::Object(options) => options.clone(),
+                Value::Bool(true) => Default::default(),
+                _ => return None,
+            };
+            options.remove("id");
+            options.insert("documentSelector".to_string(), selector.clone());
+            if *method == "textDocument/semanticTokens" {
+                options.insert("legend".to_string(), json!(gateway_legend()));
+            }
+            Some(Registration {
+                id: method.to_string(),
+                method: method.to_string(),
+                register_options: Some(Value::Object(options)),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,242 @@
+//! Position encoding negotiated with each editor
+//!
+//! Within the gateway positions count UTF-16 code units: the VFS, the
+//! language server proxies and `LspGateway` all use it. Each editor is given
+//! the encoding it prefers among those it lists in `general.positionEncodings`
+//! and its messages are converted where it connects, against the text the
+//! editor has: what it sends to UTF-16, and answers and messages to it back
+//! to its encoding.
+//!
+//! Document changes are left to `LspGateway`, which converts them as it
+//! applies them, since the positions of each change refer to the text the
+//! ones before it left.
+
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::Service;
+use tower_lsp::jsonrpc::{Id, Request, Response};
+use tower_lsp::lsp_types::{PositionEncodingKind, TextDocumentContentChangeEvent, Url};
+use tower_lsp::{ClientSocket, Loopback};
+use vraftls_core::ClientId;
+
+use crate::gateway::LspGateway;
+use crate::position_encoding::{convert_character, request_uri, DocumentSource, PositionConverter, PositionEncoding};
+use crate::session::{apply_content_changes, GatewaySessions, OpenDocuments};
+
+/// Messages from the editor whose positions `LspGateway` converts itself
+const CONVERTED_BY_GATEWAY: &[&str] = &["initialize", "textDocument/didChange", "notebookDocument/didChange"];
+
+/// An editor's connection to the gateway
+#[derive(Clone)]
+pub struct EditorConnection {
+    client_id: ClientId,
+    sessions: Arc<GatewaySessions>,
+    open_documents: OpenDocuments,
+}
+
+impl EditorConnection {
+    pub fn new(gateway: &LspGateway) -> Self {
+        Self {
+            client_id: gateway.client_id,
+            sessions: gateway.sessions.clone(),
+            open_documents: gateway.open_documents.clone(),
+        }
+    }
+
+    /// Convert the positions of the messages `inner` is sent and answers
+    pub fn service<S>(&self, inner: S) -> EditorEncoding<S> {
+        EditorEncoding {
+            inner,
+            connection: self.clone(),
+        }
+    }
+
+    /// Convert the positions of the messages sent to the editor through `socket`
+    pub fn socket(&self, socket: ClientSocket) -> EditorSocket {
+        EditorSocket {
+            socket,
+            connection: self.clone(),
+        }
+    }
+
+    /// Convert a message's positions from the editor's encoding, or to it
+    fn convert(&self, message: &mut Value, uri: Option<&Url>, to_editor: bool) {
+        let encoding = self.sessions.position_encoding(self.client_id);
+        let (from, to) = if to_editor {
+            (PositionEncoding::Utf16, encoding)
+        } else {
+            (encoding, PositionEncoding::Utf16)
+        };
+        PositionConverter::new(from, to, self).convert(message, uri);
+    }
+}
+
+impl DocumentSource for EditorConnection {
+    fn text(&self, uri: &Url) -> Option<String> {
+        match self.open_documents.get(uri) {
+            Some(doc) => Some(doc.text.clone()),
+            None => DocumentSource::text(self.sessions.vfs.as_ref(), uri),
+        }
+    }
+}
+
+/// Service converting positions between an editor's encoding and UTF-16
+pub struct EditorEncoding<S> {
+    inner: S,
+    connection: EditorConnection,
+}
+
+impl<S> Service<Request> for EditorEncoding<S>
+where
+    S: Service<Request, Response = Option<Response>>,
+    S::Future: Send + 'static,
+{
+    type Response = Option<Response>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let connection = self.connection.clone();
+        let (method, id, mut params) = request.into_parts();
+        if method == "initialize" {
+            let offered = params
+                .as_ref()
+                .and_then(|params| params.pointer("/capabilities/general/positionEncodings"))
+                .and_then(|offered| serde_json::from_value::<Vec<PositionEncodingKind>>(offered.clone()).ok());
+            let encoding = PositionEncoding::negotiate(offered.as_deref());
+            connection.sessions.set_position_encoding(connection.client_id, encoding);
+        }
+
+        let convert = !CONVERTED_BY_GATEWAY.contains(&method.as_ref());
+        let uri = params.as_ref().and_then(request_uri);
+        if let (true, Some(params)) = (convert, params.as_mut()) {
+            connection.convert(params, None, false);
+        }
+
+        let response = self.inner.call(request_of(method, id, params));
+
+        Box::pin(async move {
+            let response = response.await?;
+            Ok(response.map(|response| {
+                if !convert {
+                    return response;
+                }
+                let (id, result) = response.into_parts();
+                let result = result.map(|mut result| {
+                    connection.convert(&mut result, uri.as_ref(), true);
+                    result
+                });
+                Response::from_parts(id, result)
+            }))
+        })
+    }
+}
+
+/// Socket to an editor converting the positions of the messages sent to it
+pub struct EditorSocket {
+    socket: ClientSocket,
+    connection: EditorConnection,
+}
+
+impl Loopback for EditorSocket {
+    type RequestStream = Pin<Box<dyn Stream<Item = Request> + Send>>;
+    type ResponseSink = <ClientSocket as Loopback>::ResponseSink;
+
+    fn split(self) -> (Self::RequestStream, Self::ResponseSink) {
+        let (requests, responses) = self.socket.split();
+        let connection = self.connection;
+        let requests = requests.map(move |request| {
+            let (method, id, mut params) = request.into_parts();
+            if let Some(params) = params.as_mut() {
+                connection.convert(params, None, true);
+            }
+            request_of(method, id, params)
+        });
+        (Box::pin(requests), responses)
+    }
+}
+
+/// A request put back together from its parts
+fn request_of(method: Cow<'static, str>, id: Option<Id>, params: Option<Value>) -> Request {
+    let mut request = Request::build(method);
+    if let Some(id) = id {
+        request = request.id(id);
+    }
+    if let Some(params) = params {
+        request = request.params(params);
+    }
+    request.finish()
+}
+
+/// An editor's changes to a document with their ranges converted to UTF-16
+///
+/// Each change is converted against the text the changes before it left,
+/// starting from `text`.
+pub(crate) fn changes_to_utf16(
+    text: &str,
+    changes: Vec<TextDocumentContentChangeEvent>,
+    encoding: PositionEncoding,
+) -> Vec<TextDocumentContentChangeEvent> {
+    if encoding == PositionEncoding::Utf16 {
+        return changes;
+    }
+    let mut text = text.to_string();
+    changes
+        .into_iter()
+        .map(|mut change| {
+            if let Some(range) = change.range.as_mut() {
+                for position in [&mut range.start, &mut range.end] {
+                    let line = text.split('\n').nth(position.line as usize).unwrap_or_default();
+                    position.character = convert_character(line, position.character, encoding, PositionEncoding::Utf16);
+                }
+                change.range_length = None;
+            }
+            text = apply_content_changes(&text, std::slice::from_ref(&change));
+            change
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower_lsp::lsp_types::{Position, Range};
+
+    fn change(range: ((u32, u32), (u32, u32)), text: &str) -> TextDocumentContentChangeEvent {
+        let ((l0, c0), (l1, c1)) = range;
+        TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(l0, c0), Position::new(l1, c1))),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_changes_to_utf16() {
+        // `é` is two bytes but one UTF-16 unit
+        let text = "let é = 1;\n";
+        let changes = vec![
+            // Insert `é` before `=`, in UTF-8 after the first `é`
+            change(((0, 7), (0, 7)), "é"),
+            // Then replace `1`, which the first change moved by two bytes
+            change(((0, 11), (0, 12)), "2"),
+        ];
+
+        let converted = changes_to_utf16(text, changes.clone(), PositionEncoding::Utf8);
+        assert_eq!(converted[0].range, Some(Range::new(Position::new(0, 6), Position::new(0, 6))));
+        assert_eq!(converted[1].range, Some(Range::new(Position::new(0, 9), Position::new(0, 10))));
+        assert_eq!(apply_content_changes(text, &converted), "let é é= 2;\n");
+
+        // UTF-16 changes are left as they are
+        assert_eq!(changes_to_utf16(text, changes.clone(), PositionEncoding::Utf16), changes);
+    }
+}
@@ -0,0 +1,50 @@
+//! Language servers' own extensions to LSP
+//!
+//! rust-analyzer adds requests under `rust-analyzer/` (`expandMacro`,
+//! `viewHir`, `runnables`, ...) and `experimental/`, which its editor
+//! plugins enable from the server's experimental capabilities. The editor's
+//! experimental client capabilities reach the server unchanged in
+//! `initialize`, and the extension requests are relayed by the passthrough:
+//! those about a document to its server, the others by their namespace.
+//!
+//! Servers start only once a document needs them, after the editor has
+//! read the gateway's capabilities, so the gateway announces the extensions
+//! on their behalf.
+
+use serde_json::Value;
+use tower_lsp::lsp_types::notification::Notification;
+use vraftls_core::LanguageId;
+
+/// Language whose server handles an extension method without a document
+pub fn extension_language(method: &str) -> Option<LanguageId> {
+    if method.starts_with("rust-analyzer/") {
+        return Some(LanguageId::Rust);
+    }
+    None
+}
+
+/// Experimental capabilities the gateway announces to editors
+pub fn experimental_capabilities() -> Value {
+    serde_json::json!({
+        "externalDocs": true,
+        "hoverRange": true,
+        "joinLines": true,
+        "matchingBrace": true,
+        "moveItem": true,
+        "onEnter": true,
+        "openCargoToml": true,
+        "parentModule": true,
+        "childModules": true,
+        "runnables": { "kinds": ["cargo"] },
+        "ssr": true,
+        "workspaceSymbolScopeKindFiltering": true,
+    })
+}
+
+/// rust-analyzer's health, shown by its editor plugins
+pub enum ServerStatus {}
+
+impl Notification for ServerStatus {
+    type Params = Value;
+    const METHOD: &'static str = "experimental/serverStatus";
+}
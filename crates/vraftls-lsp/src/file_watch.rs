@@ -0,0 +1,291 @@
+//! File watchers registered by language servers
+//!
+//! Servers ask their client to watch files, e.g. `Cargo.toml` or
+//! `tsconfig.json`, with a dynamic `workspace/didChangeWatchedFiles`
+//! registration. The gateway is that client: it keeps the watchers here and
+//! matches them against VFS change events, since the files the cluster
+//! serves live in the VFS rather than on the gateway's disk.
+
+use dashmap::DashMap;
+use tower_lsp::lsp_types::*;
+use vraftls_core::LanguageId;
+use vraftls_vfs::{FileChangeType as VfsChangeType, VfsPath};
+
+/// One watcher of a registration
+struct Watcher {
+    /// Patterns with their braces expanded
+    patterns: Vec<String>,
+
+    /// Events the server wants
+    kind: WatchKind,
+}
+
+impl Watcher {
+    fn new(watcher: FileSystemWatcher) -> Option<Self> {
+        let pattern = match watcher.glob_pattern {
+            GlobPattern::String(pattern) => pattern,
+            GlobPattern::Relative(relative) => {
+                let base = match relative.base_uri {
+                    OneOf::Left(folder) => folder.uri,
+                    OneOf::Right(uri) => uri,
+                };
+                let base = base.to_file_path().ok()?;
+                format!("{}/{}", base.to_string_lossy().trim_end_matches('/'), relative.pattern)
+            }
+        };
+        Some(Self {
+            patterns: expand_braces(&pattern),
+            kind: watcher.kind.unwrap_or(WatchKind::Create | WatchKind::Change | WatchKind::Delete),
+        })
+    }
+
+    fn matches(&self, path: &str, kind: WatchKind) -> bool {
+        self.kind.contains(kind) && self.patterns.iter().any(|p| glob_matches(p, path))
+    }
+}
+
+/// Watchers of every server, by language and registration ID
+#[derive(Default)]
+pub struct FileWatchers {
+    registrations: DashMap<(LanguageId, String), Vec<Watcher>>,
+}
+
+impl FileWatchers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the watchers of a registration, replacing one with the same ID
+    pub fn register(&self, language: LanguageId, id: String, options: DidChangeWatchedFilesRegistrationOptions) {
+        let watchers = options.watchers.into_iter().filter_map(Watcher::new).collect();
+        self.registrations.insert((language, id), watchers);
+    }
+
+    /// Remove the watchers of a registration
+    pub fn unregister(&self, language: &LanguageId, id: &str) {
+        self.registrations.remove(&(language.clone(), id.to_string()));
+    }
+
+    /// Languages whose servers watch a change to a path
+    pub fn watching(&self, path: &VfsPath, change: FileChangeType) -> Vec<LanguageId> {
+        let path = path.to_path_buf();
+        let path = path.to_string_lossy();
+        let kind = match change {
+            FileChangeType::CREATED => WatchKind::Create,
+            FileChangeType::DELETED => WatchKind::Delete,
+            _ => WatchKind::Change,
+        };
+
+        let mut languages: Vec<LanguageId> = Vec::new();
+        for entry in self.registrations.iter() {
+            let language = &entry.key().0;
+            if !languages.contains(language) && entry.value().iter().any(|w| w.matches(&path, kind)) {
+                languages.push(language.clone());
+            }
+        }
+        languages
+    }
+}
+
+/// The watched-file changes a VFS change event amounts to
+///
+/// A rename is a deletion of the old path and a creation of the new one;
+/// the old path is only known if the caller saw the file before.
+pub fn watched_changes(
+    change: VfsChangeType,
+    path: &VfsPath,
+    old_path: Option<&VfsPath>,
+) -> Vec<(VfsPath, FileChangeType)> {
+    match change {
+        VfsChangeType::Created => vec![(path.clone(), FileChangeType::CREATED)],
+        VfsChangeType::Modified => vec![(path.clone(), FileChangeType::CHANGED)],
+        VfsChangeType::Deleted => vec![(path.clone(), FileChangeType::DELETED)],
+        VfsChangeType::Renamed => old_path
+            .map(|old| (old.clone(), FileChangeType::DELETED))
+            .into_iter()
+            .chain([(path.clone(), FileChangeType::CREATED)])
+            .collect(),
+    }
+}
+
+/// Expand `{a,b}` alternatives into separate patterns
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let Some(open) = pattern.find('{') else {
+        return vec![pattern.to_string()];
+    };
+
+    // Find the matching brace and the commas at its level
+    let mut depth = 0;
+    let mut splits = vec![open];
+    let mut close = None;
+    for (i, c) in pattern.char_indices().skip_while(|(i, _)| *i < open) {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(i);
+                    break;
+                }
+            }
+            ',' if depth == 1 => splits.push(i),
+            _ => {}
+        }
+    }
+    let Some(close) = close else {
+        return vec![pattern.to_string()];
+    };
+    splits.push(close);
+
+    let (prefix, suffix) = (&pattern[..open], &pattern[close + 1..]);
+    splits
+        .windows(2)
+        .flat_map(|w| expand_braces(&format!("{}{}{}", prefix, &pattern[w[0] + 1..w[1]], suffix)))
+        .collect()
+}
+
+/// Whether a path matches an LSP glob pattern without braces
+///
+/// `*` and `?` stay within a path segment, `**` spans any number of
+/// segments and `[...]` matches a character class.
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    match_from(&pattern, &path)
+}
+
+fn match_from(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            match_from(rest, path)
+                || path
+                    .iter()
+                    .enumerate()
+                    .any(|(i, c)| *c == '/' && match_from(rest, &path[i + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| match_from(rest, &path[i..])),
+        ['*', rest @ ..] => {
+            let segment = path.iter().position(|c| *c == '/').unwrap_or(path.len());
+            (0..=segment).any(|i| match_from(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => path.first().is_some_and(|c| *c != '/') && match_from(rest, &path[1..]),
+        ['[', rest @ ..] => match rest.iter().position(|c| *c == ']') {
+            Some(end) => {
+                path.first()
+                    .is_some_and(|c| *c != '/' && class_matches(&rest[..end], *c))
+                    && match_from(&rest[end + 1..], &path[1..])
+            }
+            None => path.first() == Some(&'[') && match_from(rest, &path[1..]),
+        },
+        [c, rest @ ..] => path.first() == Some(c) && match_from(rest, &path[1..]),
+    }
+}
+
+/// Whether a character is in a `[...]` class, given without the brackets
+fn class_matches(class: &[char], c: char) -> bool {
+    let (negated, class) = match class {
+        ['!', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watcher(glob_pattern: GlobPattern, kind: Option<WatchKind>) -> FileSystemWatcher {
+        FileSystemWatcher { glob_pattern, kind }
+    }
+
+    #[test]
+    fn test_glob_double_star() {
+        assert!(glob_matches("**/Cargo.toml", "/work/Cargo.toml"));
+        assert!(glob_matches("**/Cargo.toml", "/work/crates/a/Cargo.toml"));
+        assert!(glob_matches("/work/**/*.rs", "/work/main.rs"));
+        assert!(glob_matches("/work/**/*.rs", "/work/src/bin/main.rs"));
+        assert!(glob_matches("/work/**", "/work/src/lib.rs"));
+
+        // `*` and `?` stay within a segment
+        assert!(!glob_matches("/work/*.rs", "/work/src/lib.rs"));
+        assert!(!glob_matches("/work/?/lib.rs", "/work/ab/lib.rs"));
+        assert!(glob_matches("/work/[a-c]?/lib.rs", "/work/ab/lib.rs"));
+        assert!(!glob_matches("/work/[!a-c]?/lib.rs", "/work/ab/lib.rs"));
+    }
+
+    #[test]
+    fn test_glob_non_matching_paths() {
+        assert!(!glob_matches("**/Cargo.toml", "/work/Cargo.lock"));
+        assert!(!glob_matches("**/Cargo.toml", "/work/NotCargo.toml"));
+        assert!(!glob_matches("/work/**/*.rs", "/other/src/main.rs"));
+        assert!(!glob_matches("/work/**/*.rs", "/work/src/main.rs.bak"));
+    }
+
+    #[test]
+    fn test_expand_braces() {
+        assert_eq!(expand_braces("**/*.rs"), ["**/*.rs"]);
+        assert_eq!(expand_braces("**/*.{ts,js}"), ["**/*.ts", "**/*.js"]);
+        assert_eq!(
+            expand_braces("{src,tests/{unit,e2e}}/*.ts"),
+            ["src/*.ts", "tests/unit/*.ts", "tests/e2e/*.ts"]
+        );
+        // Unbalanced braces are matched literally
+        assert_eq!(expand_braces("a{b"), ["a{b"]);
+    }
+
+    #[test]
+    fn test_watching() {
+        let watchers = FileWatchers::new();
+        let base = Url::from_file_path("/work/app").unwrap();
+        watchers.register(
+            LanguageId::Rust,
+            "cargo".to_string(),
+            DidChangeWatchedFilesRegistrationOptions {
+                watchers: vec![watcher(GlobPattern::String("**/Cargo.{toml,lock}".to_string()), None)],
+            },
+        );
+        watchers.register(
+            LanguageId::TypeScript,
+            "tsconfig".to_string(),
+            DidChangeWatchedFilesRegistrationOptions {
+                watchers: vec![watcher(
+                    GlobPattern::Relative(RelativePattern {
+                        base_uri: OneOf::Right(base),
+                        pattern: "**/tsconfig.json".to_string(),
+                    }),
+                    Some(WatchKind::Create | WatchKind::Delete),
+                )],
+            },
+        );
+
+        let path = VfsPath::new;
+        assert_eq!(
+            watchers.watching(&path("/work/Cargo.lock"), FileChangeType::CHANGED),
+            [LanguageId::Rust]
+        );
+        assert_eq!(
+            watchers.watching(&path("/work/app/web/tsconfig.json"), FileChangeType::CREATED),
+            [LanguageId::TypeScript]
+        );
+
+        // Outside the relative pattern's base, or a kind not watched
+        assert!(watchers.watching(&path("/work/tsconfig.json"), FileChangeType::CREATED).is_empty());
+        assert!(watchers.watching(&path("/work/app/tsconfig.json"), FileChangeType::CHANGED).is_empty());
+        assert!(watchers.watching(&path("/work/src/main.rs"), FileChangeType::CHANGED).is_empty());
+
+        watchers.unregister(&LanguageId::Rust, "cargo");
+        assert!(watchers.watching(&path("/work/Cargo.lock"), FileChangeType::CHANGED).is_empty());
+    }
+}
@@ -1,62 +1,283 @@
 //! LSP Gateway - Main entry point for LSP protocol handling
 
-use dashmap::DashMap;
-use serde_json::Value;
-use std::sync::atomic::{AtomicU64, Ordering};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tower_lsp::jsonrpc::Result as JsonRpcResult;
 use tower_lsp::lsp_types::*;
+use tower_lsp::lsp_types::request::{
+    GotoDeclarationParams, GotoDeclarationResponse, GotoImplementationParams, GotoImplementationResponse,
+    GotoTypeDefinitionParams, GotoTypeDefinitionResponse,
+};
 use tower_lsp::{Client, LanguageServer};
-use vraftls_core::{ClientId, LanguageId};
-use vraftls_vfs::{Vfs, VfsHandle, VfsPath};
-
-use crate::proxy::{LanguageServerPool, LanguageServerProxy};
-use crate::router::LspRouter;
-
-/// LSP Gateway server
+use vraftls_cache::{CacheEntry, CacheKey, CacheType, PositionBucket};
+use vraftls_core::{ClientId, LanguageId, RaftGroupId, VRaftError};
+use vraftls_vfs::{VfsPath, VfsResponse};
+
+use crate::capabilities::without_dynamic;
+use crate::extensions::experimental_capabilities;
+use crate::notebook::cell_path;
+use crate::editor_encoding::changes_to_utf16;
+use crate::proxy::LanguageServerProxy;
+use crate::remote::{RemoteRequest, ScatterTarget};
+use crate::router::{RequestKind, ResponseAggregator, RouteDecision};
+use crate::semantic_tokens::gateway_legend;
+use crate::session::{apply_content_changes, client_uri, DocumentState, GatewaySessions, OpenDocuments};
+use crate::workspace_edit::{delete_files_command, merge_workspace_edits, rename_files_command};
+use crate::workspace_symbol::SymbolQuery;
+
+/// Most workspace symbols returned for one query
+const MAX_WORKSPACE_SYMBOLS: usize = 256;
+
+/// Completion batches remembered for resolve requests
+const COMPLETION_BATCHES: u64 = 64;
+
+/// LSP `ServerCancelled` error code, for requests the editor may retry
+const SERVER_CANCELLED: i64 = -32802;
+
+/// LSP gateway serving one editor
+///
+/// Editors connected to the same gateway share its `GatewaySessions`.
 pub struct LspGateway {
     /// LSP client for sending notifications
-    client: Client,
-
-    /// Virtual file system
-    vfs: VfsHandle,
+    pub(crate) client: Client,
 
-    /// Language server proxy pool
-    ls_pool: Arc<LanguageServerPool>,
+    /// ID of the editor in the shared sessions
+    pub(crate) client_id: ClientId,
 
-    /// Request router
-    router: Arc<LspRouter>,
-
-    /// Client ID counter
-    next_client_id: AtomicU64,
+    /// State shared with the other editors
+    pub(crate) sessions: Arc<GatewaySessions>,
 
     /// Workspace folders
     workspace_folders: RwLock<Vec<WorkspaceFolder>>,
 
     /// Open documents
-    open_documents: DashMap<Url, DocumentState>,
+    pub(crate) open_documents: OpenDocuments,
+}
+
+/// Wrap an item's data with the document it came from
+///
+/// Resolve requests carry only the item, so the tag is what routes them
+/// back to the document's language server.
+fn tag_document(uri: &Url, data: Option<serde_json::Value>) -> Option<serde_json::Value> {
+    Some(serde_json::json!({ "uri": uri, "data": data }))
 }
 
-/// State of an open document
-struct DocumentState {
-    version: i32,
-    language_id: LanguageId,
-    vfs_path: VfsPath,
+/// Split data wrapped by `tag_document` into the document and the original data
+fn untag_document(data: Option<serde_json::Value>) -> (Option<Url>, Option<serde_json::Value>) {
+    let tag = data.unwrap_or_default();
+    let uri = tag
+        .get("uri")
+        .and_then(|uri| uri.as_str())
+        .and_then(|uri| Url::parse(uri).ok());
+    let data = tag.get("data").cloned().filter(|data| !data.is_null());
+    (uri, data)
+}
+
+/// A document as the editor has it, for another node to open
+fn document_item(uri: &Url, doc: &DocumentState) -> TextDocumentItem {
+    TextDocumentItem {
+        uri: uri.clone(),
+        language_id: doc.language_id.lsp_name().to_string(),
+        version: doc.synced_version.map_or(0, |v| v.0 as i32),
+        text: doc.text.clone(),
+    }
+}
+
+/// Range of the identifier at a position, or right before it
+///
+/// What editors are told to rename when the language server cannot say.
+fn word_range(text: &str, position: Position) -> Option<Range> {
+    let line = text.lines().nth(position.line as usize)?;
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+
+    // Characters with the UTF-16 offset each starts at
+    let mut chars = Vec::new();
+    let mut end_of_line = 0;
+    for c in line.chars() {
+        chars.push((end_of_line, c));
+        end_of_line += c.len_utf16() as u32;
+    }
+
+    let cursor = chars.iter().take_while(|(start, _)| *start < position.character).count();
+    let at = if chars.get(cursor).is_some_and(|(_, c)| is_word(*c)) {
+        cursor
+    } else if cursor > 0 && is_word(chars[cursor - 1].1) {
+        cursor - 1
+    } else {
+        return None;
+    };
+
+    let mut start = at;
+    while start > 0 && is_word(chars[start - 1].1) {
+        start -= 1;
+    }
+    let mut end = at + 1;
+    while end < chars.len() && is_word(chars[end].1) {
+        end += 1;
+    }
+    if chars[start].1.is_ascii_digit() {
+        return None;
+    }
+    Some(Range {
+        start: Position::new(position.line, chars[start].0),
+        end: Position::new(position.line, chars.get(end).map_or(end_of_line, |(offset, _)| *offset)),
+    })
+}
+
+/// JSON-RPC error for a request the cluster failed to serve
+fn internal_error(e: VRaftError) -> tower_lsp::jsonrpc::Error {
+    tower_lsp::jsonrpc::Error {
+        code: tower_lsp::jsonrpc::ErrorCode::InternalError,
+        message: e.to_string().into(),
+        data: None,
+    }
+}
+
+/// JSON-RPC error for a write to a group with no leader, which the editor
+/// may send again once one is elected
+fn no_leader_error(group: RaftGroupId) -> tower_lsp::jsonrpc::Error {
+    tower_lsp::jsonrpc::Error {
+        code: tower_lsp::jsonrpc::ErrorCode::ServerError(SERVER_CANCELLED),
+        message: format!("group {} has no leader, try again", group).into(),
+        data: None,
+    }
+}
+
+/// `$/progress` carrying workspace diagnostics, which `ProgressParams`
+/// only has work done progress for
+enum DiagnosticProgress {}
+
+impl notification::Notification for DiagnosticProgress {
+    type Params = DiagnosticProgressParams;
+    const METHOD: &'static str = "$/progress";
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiagnosticProgressParams {
+    token: ProgressToken,
+    value: WorkspaceDiagnosticReportPartialResult,
+}
+
+/// Write the changes an editor made to a document since the last flush
+/// to the VFS, and forward them to the language server
+///
+/// With a generation, only if no change came after it; the flush of the
+/// later change takes this one along. A document of one of the cluster's
+/// groups is written through the group's leader first; changes the leader
+/// did not take are kept for the next flush.
+pub(crate) async fn flush_changes(
+    client: &Client,
+    sessions: &GatewaySessions,
+    open_documents: &OpenDocuments,
+    uri: &Url,
+    generation: Option<u64>,
+) {
+    let Some(mut doc) = open_documents.get_mut(uri) else {
+        return;
+    };
+    if doc.pending_changes.is_empty() || generation.is_some_and(|g| g != doc.change_generation) {
+        return;
+    }
+    let content_changes = std::mem::take(&mut doc.pending_changes);
+    let (text, vfs_path) = (doc.text.clone(), doc.vfs_path.clone());
+    drop(doc);
+
+    if let Err(e) = sessions.commit_document(uri, &vfs_path, &text).await {
+        tracing::warn!("Leader did not take change to {}: {}", uri, e);
+        if let Some(mut doc) = open_documents.get_mut(uri) {
+            let later = std::mem::replace(&mut doc.pending_changes, content_changes);
+            doc.pending_changes.extend(later);
+        }
+        client
+            .show_message(MessageType::WARNING, format!("Change to {} not saved: {}", uri, e))
+            .await;
+        return;
+    }
+    let Some(mut doc) = open_documents.get_mut(uri) else {
+        return;
+    };
+
+    // Write the editor's changes to the VFS on top of the version it saw
+    let based_on = doc.synced_version;
+    let synced = sessions.sync_document(&doc, &content_changes);
+    doc.synced_version = synced.as_ref().ok().copied();
+    drop(doc);
+    sessions.changed.mark();
+
+    let version = match synced {
+        Ok(version) => version,
+        Err(e) => {
+            tracing::warn!("Rejected change to {}: {}", uri, e);
+            client
+                .show_message(MessageType::WARNING, format!("{}; reload the file to keep editing", e))
+                .await;
+            return;
+        }
+    };
+
+    // Forward to language server; the changes as they are only if they
+    // lead from the server's version to the new one
+    let server_version = sessions.server_version(&vfs_path);
+    if server_version.is_none_or(|v| v == version) {
+        return;
+    }
+    let content_changes = if server_version == based_on && based_on.map(|v| v.next()) == Some(version) {
+        content_changes
+    } else {
+        let Some(file) = sessions.vfs.get_file_by_path(&vfs_path) else {
+            return;
+        };
+        vec![TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: sessions.vfs.get_content(file.id).unwrap_or_default(),
+        }]
+    };
+    if let Some(ls) = sessions.language_server(&vfs_path).await {
+        ls.did_change(DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: uri.clone(),
+                version: version.0 as i32,
+            },
+            content_changes,
+        })
+        .await;
+        sessions.set_server_version(&vfs_path, Some(version));
+    }
+}
+
+/// File operation filter matching every file and folder on disk
+fn all_files() -> FileOperationRegistrationOptions {
+    FileOperationRegistrationOptions {
+        filters: vec![FileOperationFilter {
+            scheme: Some("file".to_string()),
+            pattern: FileOperationPattern {
+                glob: "**/*".to_string(),
+                matches: None,
+                options: None,
+            },
+        }],
+    }
 }
 
 impl LspGateway {
-    /// Create a new LSP gateway
+    /// Create a gateway for a single editor
     pub fn new(client: Client) -> Self {
-        let vfs = Arc::new(Vfs::new(vraftls_core::RaftGroupId::new(1)));
+        Self::with_sessions(client, GatewaySessions::new())
+    }
+
+    /// Create a gateway for one of the editors sharing `sessions`
+    pub fn with_sessions(client: Client, sessions: Arc<GatewaySessions>) -> Self {
+        let (client_id, open_documents) = sessions.connect(client.clone());
         Self {
             client,
-            vfs,
-            ls_pool: Arc::new(LanguageServerPool::new()),
-            router: Arc::new(LspRouter::new()),
-            next_client_id: AtomicU64::new(1),
+            client_id,
+            sessions,
             workspace_folders: RwLock::new(Vec::new()),
-            open_documents: DashMap::new(),
+            open_documents,
         }
     }
 
@@ -65,10 +286,260 @@ impl LspGateway {
         uri.to_file_path().ok().map(VfsPath::from)
     }
 
+    /// Target of a document link as the editor should open it
+    ///
+    /// Links to files the VFS holds point at the URI the editor knows the
+    /// file by, with the path normalized as the VFS stores it.
+    fn link_target(&self, target: Url) -> Url {
+        let Ok(path) = target.to_file_path() else {
+            return target;
+        };
+        if self.sessions.vfs.get_file_by_path(&VfsPath::from(path)).is_none() {
+            return target;
+        }
+        client_uri(&self.open_documents, &target).unwrap_or(target)
+    }
+
+    /// Cache key of a document at its current VFS version
+    fn cache_key(&self, doc: &DocumentState, cache_type: CacheType) -> Option<CacheKey> {
+        let file = self.sessions.vfs.get_file_by_path(&doc.vfs_path)?;
+        Some(CacheKey {
+            file_id: file.id,
+            file_version: file.version,
+            cache_type,
+            bucket: None,
+        })
+    }
+
+    /// Cache key of data at a position of a document, at its current VFS
+    /// version
+    fn position_cache_key(&self, doc: &DocumentState, cache_type: CacheType, position: Position) -> Option<CacheKey> {
+        let key = self.cache_key(doc, cache_type)?;
+        Some(CacheKey {
+            bucket: Some(PositionBucket::of(position.line, position.character)),
+            ..key
+        })
+    }
+
+    /// Cached response, if one was stored for the key
+    async fn cached<T: DeserializeOwned>(&self, key: Option<&CacheKey>) -> Option<T> {
+        let entry = self.sessions.cache.get(key?).await?;
+        serde_json::from_slice(entry.data()).ok()
+    }
+
+    /// Store a response for the document version in the key
+    async fn store<T: Serialize>(&self, key: Option<CacheKey>, value: &T) {
+        let Some(key) = key else {
+            return;
+        };
+        if let Ok(data) = serde_json::to_vec(value) {
+            let entry = CacheEntry::new(&key.cache_type, data);
+            self.sessions.cache.insert(key, entry).await;
+        }
+    }
+
     /// Get the language server for a file
     async fn get_language_server(&self, path: &VfsPath) -> Option<Arc<LanguageServerProxy>> {
-        let lang_id = path.language_id()?;
-        self.ls_pool.get_or_spawn(lang_id).await.ok()
+        self.sessions.language_server(path).await
+    }
+
+    /// Write the changes an editor made to a document since the last flush
+    /// to the VFS, and forward them to the language server
+    async fn flush_changes(&self, uri: &Url, generation: Option<u64>) {
+        flush_changes(&self.client, &self.sessions, &self.open_documents, uri, generation).await
+    }
+
+    /// Answer a document request on the node the router places it on
+    ///
+    /// Writes go to the leader of the document's group, following leader
+    /// changes, and fail as retryable when no leader is elected in time;
+    /// they never go to a replica. Reads are answered by a replica that applied the log up to
+    /// the writes made to the document, or else by the leader. Returns
+    /// `None` when the document is served by this node.
+    async fn forward<P: Serialize, R: DeserializeOwned>(
+        &self,
+        uri: &Url,
+        doc: &DocumentState,
+        method: &str,
+        params: &P,
+    ) -> Option<JsonRpcResult<Option<R>>> {
+        let router = &self.sessions.router;
+        let local = router.local_node().await;
+        let group = self.sessions.vfs.get_file_by_path(&doc.vfs_path).map(|f| f.owning_group);
+
+        if let (RequestKind::Write, Some(group)) = (RequestKind::of(method), group) {
+            if router.serves_group(group).await {
+                let document = document_item(uri, doc);
+                let response = router
+                    .send_to_leader(group, method, |node| {
+                        let document = document.clone();
+                        async move {
+                            if Some(node) == local {
+                                return Ok(None);
+                            }
+                            let addr = router.node_addr(node).await.ok_or(VRaftError::NodeUnreachable(node))?;
+                            let remote = &self.sessions.remote_lsp;
+                            remote.request(addr, method, params, Some(document), vec![group], None).await.map(Some)
+                        }
+                    })
+                    .await;
+                return match response {
+                    Ok(None) => None,
+                    Ok(Some(response)) => Some(Ok(response)),
+                    Err(VRaftError::NotLeader { .. }) => {
+                        tracing::warn!(?group, "{} failed, no leader", method);
+                        Some(Err(no_leader_error(group)))
+                    }
+                    Err(e) => {
+                        tracing::warn!(?group, error = %e, "{} failed", method);
+                        Some(Err(internal_error(e)))
+                    }
+                };
+            }
+        }
+
+        let node = match router.route_for_file(&doc.vfs_path).await {
+            RouteDecision::Single(node) if Some(node) != local => node,
+            _ => return None,
+        };
+        let Some(addr) = router.node_addr(node).await else {
+            tracing::debug!("No address for node {}, serving {} locally", node, method);
+            return None;
+        };
+
+        let groups: Vec<_> = group.into_iter().collect();
+        let min_applied_index = group.and_then(|group| self.sessions.applied_index(&doc.vfs_path, group));
+        let remote = &self.sessions.remote_lsp;
+        let mut response = remote
+            .request(addr, method, params, Some(document_item(uri, doc)), groups.clone(), min_applied_index)
+            .await;
+
+        // The replica is behind the writes; the leader has applied them
+        if let (Err(VRaftError::StaleRead { lag, .. }), Some(group)) = (&response, group) {
+            tracing::debug!(%node, ?group, lag, "Replica behind writes, reading {} from the leader", method);
+            let leader = router.get_leader(group).await.filter(|leader| Some(*leader) != local)?;
+            let addr = router.node_addr(leader).await?;
+            response = remote
+                .request(addr, method, params, Some(document_item(uri, doc)), groups, None)
+                .await;
+        }
+        Some(response.map_err(|e| {
+            tracing::warn!(%node, error = %e, "{} failed", method);
+            internal_error(e)
+        }))
+    }
+
+    /// A server's workspace diagnostic report, for the URI the editor uses
+    fn client_report(&self, report: WorkspaceDocumentDiagnosticReport) -> WorkspaceDocumentDiagnosticReport {
+        match report {
+            WorkspaceDocumentDiagnosticReport::Full(mut report) => {
+                report.uri = client_uri(&self.open_documents, &report.uri).unwrap_or(report.uri);
+                WorkspaceDocumentDiagnosticReport::Full(report)
+            }
+            WorkspaceDocumentDiagnosticReport::Unchanged(mut report) => {
+                report.uri = client_uri(&self.open_documents, &report.uri).unwrap_or(report.uri);
+                WorkspaceDocumentDiagnosticReport::Unchanged(report)
+            }
+        }
+    }
+
+    /// Reports of the diagnostics pushed for files of languages whose
+    /// servers are not in `pulled`
+    fn pushed_diagnostics(
+        &self,
+        previous_result_ids: &[PreviousResultId],
+        pulled: &[LanguageId],
+    ) -> Vec<WorkspaceDocumentDiagnosticReport> {
+        let mut reports = Vec::new();
+        for path in self.sessions.diagnosed_files() {
+            if path.language_id().is_some_and(|lang| pulled.contains(&lang)) {
+                continue;
+            }
+            let Some(uri) = Url::from_file_path(path.to_path_buf())
+                .ok()
+                .and_then(|uri| client_uri(&self.open_documents, &uri))
+            else {
+                continue;
+            };
+            let previous = previous_result_ids
+                .iter()
+                .find(|previous| previous.uri == uri)
+                .map(|previous| previous.value.as_str());
+            reports.push(match self.sessions.diagnostic_report(&path, previous) {
+                DocumentDiagnosticReport::Full(report) => {
+                    WorkspaceDocumentDiagnosticReport::Full(WorkspaceFullDocumentDiagnosticReport {
+                        uri,
+                        version: None,
+                        full_document_diagnostic_report: report.full_document_diagnostic_report,
+                    })
+                }
+                DocumentDiagnosticReport::Unchanged(report) => {
+                    WorkspaceDocumentDiagnosticReport::Unchanged(WorkspaceUnchangedDocumentDiagnosticReport {
+                        uri,
+                        version: None,
+                        unchanged_document_diagnostic_report: report.unchanged_document_diagnostic_report,
+                    })
+                }
+            });
+        }
+        reports
+    }
+
+    /// Send workspace diagnostic reports to the editor as partial results
+    /// of `token`, or keep them for the final answer without one
+    async fn report_diagnostics(
+        &self,
+        token: Option<&ProgressToken>,
+        reports: Vec<WorkspaceDocumentDiagnosticReport>,
+        items: &mut Vec<WorkspaceDocumentDiagnosticReport>,
+    ) {
+        match token {
+            Some(_) if reports.is_empty() => {}
+            Some(token) => {
+                self.client
+                    .send_notification::<DiagnosticProgress>(DiagnosticProgressParams {
+                        token: token.clone(),
+                        value: WorkspaceDiagnosticReportPartialResult { items: reports },
+                    })
+                    .await
+            }
+            None => items.extend(reports),
+        }
+    }
+
+    /// Other nodes to scatter a workspace-wide request to
+    ///
+    /// Groups no node can serve and nodes without a known address are
+    /// reported to the aggregator, so the results are known to be partial.
+    async fn scatter_targets<T>(&self, method: &str, aggregator: &mut ResponseAggregator<T>) -> Vec<ScatterTarget> {
+        let groups = match self.sessions.router.route_workspace().await {
+            RouteDecision::ScatterGather(groups) => groups,
+            _ => Vec::new(),
+        };
+        let local = self.sessions.router.local_node().await;
+
+        let nodes = self.sessions.router.scatter_targets(&groups, method).await;
+        for group in groups.iter().filter(|group| !nodes.values().flatten().any(|g| g == *group)) {
+            aggregator.add_error(format!("no node serves group {}", group));
+        }
+
+        let mut targets = Vec::new();
+        for (node, groups) in nodes {
+            if Some(node) == local {
+                continue;
+            }
+            match self.sessions.router.node_addr(node).await {
+                Some(addr) => targets.push(ScatterTarget { node, addr, groups }),
+                None => aggregator.add_error(format!("no address for node {}", node)),
+            }
+        }
+        targets
+    }
+}
+
+impl Drop for LspGateway {
+    fn drop(&mut self) {
+        self.sessions.disconnect(self.client_id);
     }
 }
 
@@ -77,71 +548,134 @@ impl LanguageServer for LspGateway {
     async fn initialize(&self, params: InitializeParams) -> JsonRpcResult<InitializeResult> {
         tracing::info!("LSP initialize: {:?}", params.root_uri);
 
+        // Language servers are spawned later, with the editor's workspace;
+        // the providers they have are registered with the editor then, if
+        // it can take them
+        self.sessions.ls_pool.set_client_params(&params).await;
+        self.sessions.changed.mark();
+        let dynamic = self.sessions.dynamic_methods(self.client_id);
+
         // Store workspace folders
         if let Some(folders) = params.workspace_folders {
             let mut ws = self.workspace_folders.write().await;
             *ws = folders;
         }
 
-        Ok(InitializeResult {
-            capabilities: ServerCapabilities {
-                // Text document sync
-                text_document_sync: Some(TextDocumentSyncCapability::Options(
-                    TextDocumentSyncOptions {
-                        open_close: Some(true),
-                        change: Some(TextDocumentSyncKind::INCREMENTAL),
-                        save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
-                            include_text: Some(true),
-                        })),
-                        ..Default::default()
-                    },
-                )),
-
-                // Completion
-                completion_provider: Some(CompletionOptions {
-                    trigger_characters: Some(vec![".".to_string(), ":".to_string()]),
-                    resolve_provider: Some(true),
+        let capabilities = ServerCapabilities {
+            // Positions in the encoding the editor prefers, converted from
+            // UTF-16 where it connects
+            position_encoding: Some(self.sessions.position_encoding(self.client_id).kind()),
+
+            // Text document sync
+            text_document_sync: Some(TextDocumentSyncCapability::Options(
+                TextDocumentSyncOptions {
+                    open_close: Some(true),
+                    change: Some(TextDocumentSyncKind::INCREMENTAL),
+                    save: Some(TextDocumentSyncSaveOptions::SaveOptions(SaveOptions {
+                        include_text: Some(true),
+                    })),
                     ..Default::default()
-                }),
+                },
+            )),
 
-                // Hover
-                hover_provider: Some(HoverProviderCapability::Simple(true)),
+            // Completion
+            completion_provider: Some(CompletionOptions {
+                trigger_characters: Some(vec![".".to_string(), ":".to_string()]),
+                resolve_provider: Some(true),
+                ..Default::default()
+            }),
 
-                // Go to definition
-                definition_provider: Some(OneOf::Left(true)),
+            // Hover
+            hover_provider: Some(HoverProviderCapability::Simple(true)),
 
-                // References
-                references_provider: Some(OneOf::Left(true)),
+            // Go to definition
+            definition_provider: Some(OneOf::Left(true)),
 
-                // Document symbols
-                document_symbol_provider: Some(OneOf::Left(true)),
+            // Go to declaration, type definition and implementation
+            declaration_provider: Some(DeclarationCapability::Simple(true)),
+            type_definition_provider: Some(TypeDefinitionProviderCapability::Simple(true)),
+            implementation_provider: Some(ImplementationProviderCapability::Simple(true)),
 
-                // Workspace symbols
-                workspace_symbol_provider: Some(OneOf::Left(true)),
+            // References
+            references_provider: Some(OneOf::Left(true)),
 
-                // Code actions
-                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+            // Document symbols
+            document_symbol_provider: Some(OneOf::Left(true)),
 
-                // Formatting
-                document_formatting_provider: Some(OneOf::Left(true)),
+            // Workspace symbols
+            workspace_symbol_provider: Some(OneOf::Left(true)),
 
-                // Rename
-                rename_provider: Some(OneOf::Right(RenameOptions {
-                    prepare_provider: Some(true),
-                    work_done_progress_options: Default::default(),
-                })),
+            // Code actions
+            code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
 
-                // Diagnostics
-                diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
-                    DiagnosticOptions {
-                        inter_file_dependencies: true,
-                        workspace_diagnostics: true,
-                        ..Default::default()
-                    },
-                )),
+            // Formatting
+            document_formatting_provider: Some(OneOf::Left(true)),
 
-                ..Default::default()
-            },
+            // Rename
+            rename_provider: Some(OneOf::Right(RenameOptions {
+                prepare_provider: Some(true),
+                work_done_progress_options: Default::default(),
+            })),
+
+            // Document links
+            document_link_provider: Some(DocumentLinkOptions {
+                resolve_provider: Some(true),
+                work_done_progress_options: Default::default(),
+            }),
+
+            // Folding and selection ranges
+            folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+            selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+
+            // Inline values, shown while debugging
+            inline_value_provider: Some(OneOf::Left(true)),
+
+            // Inlay hints
+            inlay_hint_provider: Some(OneOf::Right(InlayHintServerCapabilities::Options(
+                InlayHintOptions {
+                    resolve_provider: Some(true),
+                    ..Default::default()
+                },
+            ))),
+
+            // Semantic tokens, translated to one legend for all servers
+            semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+                SemanticTokensOptions {
+                    legend: gateway_legend(),
+                    range: Some(true),
+                    full: Some(SemanticTokensFullOptions::Delta { delta: Some(true) }),
+                    ..Default::default()
+                },
+            )),
+
+            // File renames and deletions, so servers can update imports
+            workspace: Some(WorkspaceServerCapabilities {
+                workspace_folders: None,
+                file_operations: Some(WorkspaceFileOperationsServerCapabilities {
+                    will_rename: Some(all_files()),
+                    did_rename: Some(all_files()),
+                    did_delete: Some(all_files()),
+                    ..Default::default()
+                }),
+            }),
+
+            // Diagnostics
+            diagnostic_provider: Some(DiagnosticServerCapabilities::Options(
+                DiagnosticOptions {
+                    inter_file_dependencies: true,
+                    workspace_diagnostics: true,
+                    ..Default::default()
+                },
+            )),
+
+            // Extensions of the language servers, e.g. rust-analyzer's
+            experimental: Some(experimental_capabilities()),
+
+            ..Default::default()
+        };
+
+        Ok(InitializeResult {
+            capabilities: without_dynamic(capabilities, &dynamic),
             server_info: Some(ServerInfo {
                 name: "vraftls".to_string(),
                 version: Some(env!("CARGO_PKG_VERSION").to_string()),
@@ -151,6 +685,7 @@ impl LanguageServer for LspGateway {
 
     async fn initialized(&self, _: InitializedParams) {
         tracing::info!("LSP initialized");
+        self.sessions.register_providers(self.client_id).await;
         self.client
             .log_message(MessageType::INFO, "VRaftLS initialized")
             .await;
@@ -161,43 +696,166 @@ impl LanguageServer for LspGateway {
         Ok(())
     }
 
+    async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> JsonRpcResult<Option<Vec<SymbolInformation>>> {
+        let query = params.query;
+        let mut aggregator = ResponseAggregator::new();
+
+        // Groups on other nodes are searched by their language servers,
+        // all nodes at once
+        let mut searches = tokio::task::JoinSet::new();
+        for target in self.scatter_targets("workspace/symbol", &mut aggregator).await {
+            let remote = self.sessions.remote_symbols.clone();
+            let query = SymbolQuery {
+                query: query.clone(),
+                groups: target.groups,
+            };
+            searches.spawn(async move { (target.node, remote.search(target.addr, &query).await) });
+        }
+
+        self.sessions.ls_pool.collect_workspace_symbols(&query, &mut aggregator).await;
+
+        while let Some(joined) = searches.join_next().await {
+            match joined {
+                Ok((_, Ok(symbols))) => symbols.into_iter().for_each(|s| aggregator.add_response(s)),
+                Ok((node, Err(e))) => aggregator.add_error(format!("node {}: {}", node, e)),
+                Err(e) => aggregator.add_error(e.to_string()),
+            }
+        }
+
+        if aggregator.has_errors() {
+            tracing::warn!("workspace/symbol incomplete: {:?}", aggregator.errors());
+        }
+        Ok(aggregator.into_ranked_symbols(&query, MAX_WORKSPACE_SYMBOLS))
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> JsonRpcResult<Option<serde_json::Value>> {
+        tracing::debug!("execute_command: {}", params.command);
+
+        // Edits the command makes go to this editor, and its servers see
+        // what the editor shows
+        self.sessions.set_active(self.client_id);
+        let uris: Vec<Url> = self.open_documents.iter().map(|doc| doc.key().clone()).collect();
+        for uri in uris {
+            self.flush_changes(&uri, None).await;
+        }
+
+        match self.sessions.ls_pool.server_for_command(&params.command) {
+            Some(ls) => ls.execute_command(params).await,
+            None => Err(tower_lsp::jsonrpc::Error::invalid_params(format!(
+                "unknown command: {}",
+                params.command
+            ))),
+        }
+    }
+
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        tracing::debug!("did_change_configuration");
+
+        self.sessions.ls_pool.change_configuration(params.settings).await;
+    }
+
+    async fn will_rename_files(&self, params: RenameFilesParams) -> JsonRpcResult<Option<WorkspaceEdit>> {
+        tracing::debug!("will_rename_files: {} files", params.files.len());
+
+        // The editor applies the edits before renaming, so they target the
+        // old paths
+        Ok(merge_workspace_edits(self.sessions.ls_pool.will_rename_files(&params).await))
+    }
+
+    async fn did_rename_files(&self, params: RenameFilesParams) {
+        tracing::debug!("did_rename_files: {} files", params.files.len());
+
+        match rename_files_command(&self.sessions.vfs, &params.files) {
+            Ok(command) => {
+                if let VfsResponse::Error(e) = self.sessions.vfs.apply(command) {
+                    tracing::warn!("Failed to rename files in the VFS: {}", e);
+                }
+            }
+            Err(e) => tracing::warn!("Invalid file rename: {}", e),
+        }
+        self.sessions.ls_pool.did_rename_files(&params).await;
+    }
+
+    async fn did_delete_files(&self, params: DeleteFilesParams) {
+        tracing::debug!("did_delete_files: {} files", params.files.len());
+
+        match delete_files_command(&self.sessions.vfs, &params.files) {
+            Ok(command) => {
+                if let VfsResponse::Error(e) = self.sessions.vfs.apply(command) {
+                    tracing::warn!("Failed to delete files from the VFS: {}", e);
+                }
+            }
+            Err(e) => tracing::warn!("Invalid file deletion: {}", e),
+        }
+        self.sessions.ls_pool.did_delete_files(&params).await;
+    }
+
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
         let uri = params.text_document.uri.clone();
-        let version = params.text_document.version;
         let text = params.text_document.text.clone();
         let language_id_str = params.text_document.language_id.clone();
 
         tracing::debug!("did_open: {}", uri);
 
-        if let Some(vfs_path) = self.uri_to_vfs_path(&uri) {
-            let language_id = match language_id_str.as_str() {
-                "rust" => LanguageId::Rust,
-                "typescript" | "typescriptreact" => LanguageId::TypeScript,
-                "javascript" | "javascriptreact" => LanguageId::JavaScript,
-                "go" => LanguageId::Go,
-                "python" => LanguageId::Python,
-                other => LanguageId::Other(other.to_string()),
-            };
-
-            // Store in VFS
-            self.vfs.apply(vraftls_vfs::VfsCommand::CreateFile {
-                path: vfs_path.clone(),
-                content: text.clone(),
-            });
+        let language_id = match language_id_str.as_str() {
+            "rust" => LanguageId::Rust,
+            "typescript" | "typescriptreact" => LanguageId::TypeScript,
+            "javascript" | "javascriptreact" => LanguageId::JavaScript,
+            "go" => LanguageId::Go,
+            "python" => LanguageId::Python,
+            other => LanguageId::Other(other.to_string()),
+        };
+
+        // Notebook cells are kept as virtual files next to their notebook
+        let vfs_path = self.uri_to_vfs_path(&uri).or_else(|| cell_path(&uri, &language_id));
+        if let Some(vfs_path) = vfs_path {
+
+            // Store in VFS, unless another editor has other content open
+            let synced_version = self.sessions.open_document(&vfs_path, &text);
+            if synced_version.is_none() {
+                self.client
+                    .show_message(
+                        MessageType::WARNING,
+                        format!("{} is open in another editor with different content", vfs_path),
+                    )
+                    .await;
+            }
 
             // Track open document
             self.open_documents.insert(
                 uri.clone(),
                 DocumentState {
-                    version,
-                    language_id: language_id.clone(),
+                    language_id,
                     vfs_path: vfs_path.clone(),
+                    text,
+                    synced_version,
+                    pending_changes: Vec::new(),
+                    change_generation: 0,
                 },
             );
+            self.sessions.changed.mark();
 
-            // Forward to language server
+            // Language servers have each file open once, at its VFS version
+            if self.sessions.server_version(&vfs_path).is_some() {
+                return;
+            }
+            let Some(file) = self.sessions.vfs.get_file_by_path(&vfs_path) else {
+                return;
+            };
             if let Some(ls) = self.get_language_server(&vfs_path).await {
-                ls.did_open(params).await;
+                ls.did_open(DidOpenTextDocumentParams {
+                    text_document: TextDocumentItem {
+                        uri,
+                        language_id: language_id_str,
+                        version: file.version.0 as i32,
+                        text: self.sessions.vfs.get_content(file.id).unwrap_or_default(),
+                    },
+                })
+                .await;
+                self.sessions.set_server_version(&vfs_path, Some(file.version));
             }
         }
     }
@@ -207,14 +865,36 @@ impl LanguageServer for LspGateway {
 
         tracing::debug!("did_change: {}", uri);
 
-        if let Some(mut doc) = self.open_documents.get_mut(&uri) {
-            doc.version = params.text_document.version;
-
-            // Forward to language server
-            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
-                ls.did_change(params).await;
-            }
+        let Some(mut doc) = self.open_documents.get_mut(&uri) else {
+            return;
+        };
+        self.sessions.set_active(self.client_id);
+
+        // The editor's copy follows every change; the VFS and the servers
+        // get a burst of changes at once, when typing pauses
+        let encoding = self.sessions.position_encoding(self.client_id);
+        let changes = changes_to_utf16(&doc.text, params.content_changes, encoding);
+        doc.text = apply_content_changes(&doc.text, &changes);
+        doc.pending_changes.extend(changes);
+        doc.change_generation += 1;
+        let generation = doc.change_generation;
+        drop(doc);
+
+        let debounce = self.sessions.change_debounce;
+        if debounce.is_zero() {
+            self.flush_changes(&uri, Some(generation)).await;
+            return;
         }
+
+        // Each change arms its own flush; one that wakes after a newer change
+        // sees a stale generation and leaves the flush to that change
+        let client = self.client.clone();
+        let sessions = self.sessions.clone();
+        let open_documents = self.open_documents.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(debounce).await;
+            flush_changes(&client, &sessions, &open_documents, &uri, Some(generation)).await;
+        });
     }
 
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
@@ -222,7 +902,16 @@ impl LanguageServer for LspGateway {
 
         tracing::debug!("did_close: {}", uri);
 
+        self.flush_changes(&uri, None).await;
         if let Some((_, doc)) = self.open_documents.remove(&uri) {
+            self.sessions.changed.mark();
+
+            // Servers keep files other editors still have open
+            if self.sessions.is_open(&doc.vfs_path) {
+                return;
+            }
+            self.sessions.set_server_version(&doc.vfs_path, None);
+            self.sessions.forget_applied_index(&doc.vfs_path);
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
                 ls.did_close(params).await;
             }
@@ -234,6 +923,7 @@ impl LanguageServer for LspGateway {
 
         tracing::debug!("did_save: {}", uri);
 
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
                 ls.did_save(params).await;
@@ -247,21 +937,103 @@ impl LanguageServer for LspGateway {
     ) -> JsonRpcResult<Option<CompletionResponse>> {
         let uri = params.text_document_position.text_document.uri.clone();
 
+        let position = params.text_document_position.position;
+
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
-                return ls.completion(params).await;
+                // Items edit the text around the position they were asked
+                // at, so only completions of the same position are reused
+                let key = self.position_cache_key(&doc, CacheType::Completions, position);
+                let cached = if ls.is_busy() {
+                    self.cached::<(Position, CompletionResponse)>(key.as_ref()).await
+                } else {
+                    None
+                };
+                let mut response = match cached {
+                    Some((at, response)) if at == position => response,
+                    _ => {
+                        let Some(response) = ls.completion(params).await? else {
+                            return Ok(None);
+                        };
+                        // Incomplete lists are asked again as the user types
+                        if !matches!(response, CompletionResponse::List(CompletionList { is_incomplete: true, .. })) {
+                            self.store(key, &(position, &response)).await;
+                        }
+                        response
+                    }
+                };
+
+                // Resolve requests carry only the item; tag each with its
+                // batch so they reach the server that produced it
+                let batch = self.sessions.next_completion_batch.fetch_add(1, Ordering::SeqCst);
+                self.sessions.completion_batches.insert(batch, ls.language().clone());
+                self.sessions.completion_batches
+                    .retain(|id, _| id + COMPLETION_BATCHES > batch);
+
+                let items = match response {
+                    CompletionResponse::Array(ref mut items) => items,
+                    CompletionResponse::List(ref mut list) => &mut list.items,
+                };
+                for item in items {
+                    item.data = Some(serde_json::json!({ "batch": batch, "data": item.data.take() }));
+                }
+                return Ok(Some(response));
             }
         }
 
         Ok(None)
     }
 
+    async fn completion_resolve(&self, mut item: CompletionItem) -> JsonRpcResult<CompletionItem> {
+        let tag = item.data.take().unwrap_or_default();
+        item.data = tag.get("data").cloned().filter(|data| !data.is_null());
+
+        let language = tag
+            .get("batch")
+            .and_then(|batch| batch.as_u64())
+            .and_then(|batch| self.sessions.completion_batches.get(&batch))
+            .map(|lang| lang.clone());
+        let Some(language) = language else {
+            // The batch is too old to remember; the item stays as it is
+            return Ok(item);
+        };
+
+        match self.sessions.ls_pool.get_or_spawn(language).await {
+            Ok(ls) => ls.completion_resolve(item).await,
+            Err(_) => Ok(item),
+        }
+    }
+
     async fn hover(&self, params: HoverParams) -> JsonRpcResult<Option<Hover>> {
         let uri = params.text_document_position_params.text_document.uri.clone();
 
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/hover", &params).await {
+                return response;
+            }
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
-                return ls.hover(params).await;
+                // A busy server is not waited on for a hover it gave before,
+                // at this position or over the range it covers
+                let position = params.text_document_position_params.position;
+                let key = self.position_cache_key(&doc, CacheType::Hover, position);
+                if ls.is_busy() {
+                    if let Some((at, hover)) = self.cached::<(Position, Hover)>(key.as_ref()).await {
+                        let covers = match hover.range {
+                            Some(range) => range.start <= position && position <= range.end,
+                            None => at == position,
+                        };
+                        if covers {
+                            return Ok(Some(hover));
+                        }
+                    }
+                }
+                let hover = ls.hover(params).await?;
+                if let Some(ref hover) = hover {
+                    self.store(key, &(position, hover)).await;
+                }
+                return Ok(hover);
             }
         }
 
@@ -274,7 +1046,11 @@ impl LanguageServer for LspGateway {
     ) -> JsonRpcResult<Option<GotoDefinitionResponse>> {
         let uri = params.text_document_position_params.text_document.uri.clone();
 
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/definition", &params).await {
+                return response;
+            }
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
                 return ls.goto_definition(params).await;
             }
@@ -283,30 +1059,125 @@ impl LanguageServer for LspGateway {
         Ok(None)
     }
 
-    async fn references(
+    async fn goto_declaration(
         &self,
-        params: ReferenceParams,
-    ) -> JsonRpcResult<Option<Vec<Location>>> {
-        let uri = params.text_document_position.text_document.uri.clone();
+        params: GotoDeclarationParams,
+    ) -> JsonRpcResult<Option<GotoDeclarationResponse>> {
+        let uri = params.text_document_position_params.text_document.uri.clone();
 
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/declaration", &params).await {
+                return response;
+            }
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
-                return ls.references(params).await;
+                return ls.goto_declaration(params).await;
             }
         }
 
         Ok(None)
     }
 
+    async fn goto_type_definition(
+        &self,
+        params: GotoTypeDefinitionParams,
+    ) -> JsonRpcResult<Option<GotoTypeDefinitionResponse>> {
+        let uri = params.text_document_position_params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/typeDefinition", &params).await {
+                return response;
+            }
+            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                return ls.goto_type_definition(params).await;
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn goto_implementation(
+        &self,
+        params: GotoImplementationParams,
+    ) -> JsonRpcResult<Option<GotoImplementationResponse>> {
+        let uri = params.text_document_position_params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/implementation", &params).await {
+                return response;
+            }
+            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                return ls.goto_implementation(params).await;
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn references(
+        &self,
+        params: ReferenceParams,
+    ) -> JsonRpcResult<Option<Vec<Location>>> {
+        let uri = params.text_document_position.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        let Some(doc) = self.open_documents.get(&uri) else {
+            return Ok(None);
+        };
+        let method = "textDocument/references";
+
+        // References can be in any group's files, so the nodes of every
+        // group are asked along with the local servers
+        let mut aggregator = ResponseAggregator::new();
+        let targets = self.scatter_targets(method, &mut aggregator).await;
+        let document = Some(document_item(&uri, &doc));
+        let params_json = serde_json::to_value(&params).unwrap_or_default();
+        let remote = self.sessions.remote_lsp.clone();
+        let scattered = remote.scatter(targets, method, &params_json, document, &mut aggregator);
+        let local = async {
+            match self.get_language_server(&doc.vfs_path).await {
+                Some(ls) => ls.references(params).await,
+                None => Ok(None),
+            }
+        };
+        let ((), local) = tokio::join!(scattered, local);
+
+        match local {
+            Ok(locations) => aggregator.add_response(serde_json::to_value(locations).unwrap_or_default()),
+            Err(e) => aggregator.add_error(e.to_string()),
+        }
+        if aggregator.has_errors() {
+            tracing::warn!("{} incomplete: {:?}", method, aggregator.errors());
+        }
+        Ok(serde_json::from_value(aggregator.into_merged(method)).unwrap_or_default())
+    }
+
     async fn document_symbol(
         &self,
         params: DocumentSymbolParams,
     ) -> JsonRpcResult<Option<DocumentSymbolResponse>> {
         let uri = params.text_document.uri.clone();
 
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/documentSymbol", &params).await {
+                return response;
+            }
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
-                return ls.document_symbol(params).await;
+                // Symbols of this version are reused while the server is busy
+                let key = self.cache_key(&doc, CacheType::Symbols);
+                if ls.is_busy() {
+                    if let Some(symbols) = self.cached(key.as_ref()).await {
+                        return Ok(Some(symbols));
+                    }
+                }
+                let symbols = ls.document_symbol(params).await?;
+                if let Some(ref symbols) = symbols {
+                    self.store(key, symbols).await;
+                }
+                return Ok(symbols);
             }
         }
 
@@ -319,7 +1190,11 @@ impl LanguageServer for LspGateway {
     ) -> JsonRpcResult<Option<Vec<TextEdit>>> {
         let uri = params.text_document.uri.clone();
 
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/formatting", &params).await {
+                return response;
+            }
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
                 return ls.formatting(params).await;
             }
@@ -331,7 +1206,11 @@ impl LanguageServer for LspGateway {
     async fn rename(&self, params: RenameParams) -> JsonRpcResult<Option<WorkspaceEdit>> {
         let uri = params.text_document_position.text_document.uri.clone();
 
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/rename", &params).await {
+                return response;
+            }
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
                 return ls.rename(params).await;
             }
@@ -340,12 +1219,43 @@ impl LanguageServer for LspGateway {
         Ok(None)
     }
 
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> JsonRpcResult<Option<PrepareRenameResponse>> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        let Some(doc) = self.open_documents.get(&uri) else {
+            return Ok(None);
+        };
+        let mut response = Ok(None);
+        if let Some(forwarded) = self.forward(&uri, &doc, "textDocument/prepareRename", &params).await {
+            response = forwarded;
+        } else if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+            response = ls.prepare_rename(params.clone()).await;
+        }
+
+        // Servers that cannot prepare renames leave it to the word at the cursor
+        if !matches!(response, Ok(None)) {
+            return response;
+        }
+        let text = self
+            .sessions
+            .vfs
+            .get_file_by_path(&doc.vfs_path)
+            .and_then(|file| self.sessions.vfs.get_content(file.id).ok())
+            .unwrap_or_else(|| doc.text.clone());
+        Ok(word_range(&text, params.position).map(PrepareRenameResponse::Range))
+    }
+
     async fn code_action(
         &self,
         params: CodeActionParams,
     ) -> JsonRpcResult<Option<CodeActionResponse>> {
         let uri = params.text_document.uri.clone();
 
+        self.flush_changes(&uri, None).await;
         if let Some(doc) = self.open_documents.get(&uri) {
             if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
                 return ls.code_action(params).await;
@@ -354,4 +1264,338 @@ impl LanguageServer for LspGateway {
 
         Ok(None)
     }
+
+    async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> JsonRpcResult<Option<SemanticTokensResult>> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                return ls.semantic_tokens_full(params).await;
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> JsonRpcResult<Option<SemanticTokensFullDeltaResult>> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                return ls.semantic_tokens_full_delta(params).await;
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn semantic_tokens_range(
+        &self,
+        params: SemanticTokensRangeParams,
+    ) -> JsonRpcResult<Option<SemanticTokensRangeResult>> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                return ls.semantic_tokens_range(params).await;
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> JsonRpcResult<Option<Vec<InlayHint>>> {
+        let uri = params.text_document.uri.clone();
+        let range = params.range;
+
+        self.flush_changes(&uri, None).await;
+        let Some(doc) = self.open_documents.get(&uri) else {
+            return Ok(None);
+        };
+        let key = self.cache_key(&doc, CacheType::InlayHints);
+
+        // Hints fetched for a range covering this one are valid until the
+        // document changes, so scrolling back does not ask the server again
+        let mut cached: Vec<(Range, Vec<InlayHint>)> = self.cached(key.as_ref()).await.unwrap_or_default();
+        if let Some((_, hints)) = cached
+            .iter()
+            .find(|(r, _)| r.start <= range.start && range.end <= r.end)
+        {
+            return Ok(Some(
+                hints
+                    .iter()
+                    .filter(|h| range.start <= h.position && h.position <= range.end)
+                    .cloned()
+                    .collect(),
+            ));
+        }
+
+        let Some(ls) = self.get_language_server(&doc.vfs_path).await else {
+            return Ok(None);
+        };
+        let Some(mut hints) = ls.inlay_hint(params).await? else {
+            return Ok(None);
+        };
+
+        // Resolve requests carry only the hint; tag it with its document
+        for hint in &mut hints {
+            hint.data = tag_document(&uri, hint.data.take());
+        }
+
+        cached.push((range, hints.clone()));
+        self.store(key, &cached).await;
+
+        Ok(Some(hints))
+    }
+
+    async fn inlay_hint_resolve(&self, mut hint: InlayHint) -> JsonRpcResult<InlayHint> {
+        let (uri, data) = untag_document(hint.data.take());
+        hint.data = data;
+
+        if let Some(uri) = uri {
+            self.flush_changes(&uri, None).await;
+            if let Some(doc) = self.open_documents.get(&uri) {
+                if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                    let mut resolved = ls.inlay_hint_resolve(hint).await?;
+                    resolved.data = tag_document(&uri, resolved.data.take());
+                    return Ok(resolved);
+                }
+            }
+        }
+
+        Ok(hint)
+    }
+
+    async fn document_link(&self, params: DocumentLinkParams) -> JsonRpcResult<Option<Vec<DocumentLink>>> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                let Some(mut links) = ls.document_link(params).await? else {
+                    return Ok(None);
+                };
+                for link in &mut links {
+                    link.target = link.target.take().map(|target| self.link_target(target));
+                    link.data = tag_document(&uri, link.data.take());
+                }
+                return Ok(Some(links));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn document_link_resolve(&self, mut link: DocumentLink) -> JsonRpcResult<DocumentLink> {
+        let (uri, data) = untag_document(link.data.take());
+        link.data = data;
+
+        if let Some(uri) = uri {
+            self.flush_changes(&uri, None).await;
+            if let Some(doc) = self.open_documents.get(&uri) {
+                if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                    let mut resolved = ls.document_link_resolve(link).await?;
+                    resolved.target = resolved.target.take().map(|target| self.link_target(target));
+                    resolved.data = tag_document(&uri, resolved.data.take());
+                    return Ok(resolved);
+                }
+            }
+        }
+
+        Ok(link)
+    }
+
+    async fn inline_value(&self, params: InlineValueParams) -> JsonRpcResult<Option<Vec<InlineValue>>> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            if let Some(response) = self.forward(&uri, &doc, "textDocument/inlineValue", &params).await {
+                return response;
+            }
+            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                return ls.inline_value(params).await;
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn folding_range(
+        &self,
+        params: FoldingRangeParams,
+    ) -> JsonRpcResult<Option<Vec<FoldingRange>>> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            // Requested on every scroll, but only edits change them
+            let key = self.cache_key(&doc, CacheType::FoldingRanges);
+            if let Some(ranges) = self.cached(key.as_ref()).await {
+                return Ok(Some(ranges));
+            }
+            if let Some(ls) = self.get_language_server(&doc.vfs_path).await {
+                let ranges = ls.folding_range(params).await?;
+                if let Some(ref ranges) = ranges {
+                    self.store(key, ranges).await;
+                }
+                return Ok(ranges);
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn selection_range(
+        &self,
+        mut params: SelectionRangeParams,
+    ) -> JsonRpcResult<Option<Vec<SelectionRange>>> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        if let Some(doc) = self.open_documents.get(&uri) {
+            // Ranges are cached per position; only the missing ones are
+            // asked from the server
+            let key = self.cache_key(&doc, CacheType::SelectionRanges);
+            let mut cached: Vec<(Position, SelectionRange)> = self.cached(key.as_ref()).await.unwrap_or_default();
+            let lookup = |cached: &[(Position, SelectionRange)], position: &Position| {
+                cached.iter().find(|(p, _)| p == position).map(|(_, r)| r.clone())
+            };
+
+            let positions = std::mem::take(&mut params.positions);
+            let missing: Vec<Position> = positions
+                .iter()
+                .filter(|p| lookup(&cached, p).is_none())
+                .copied()
+                .collect();
+            if !missing.is_empty() {
+                let Some(ls) = self.get_language_server(&doc.vfs_path).await else {
+                    return Ok(None);
+                };
+                params.positions = missing.clone();
+                let Some(ranges) = ls.selection_range(params).await? else {
+                    return Ok(None);
+                };
+                cached.extend(missing.into_iter().zip(ranges));
+                self.store(key, &cached).await;
+            }
+
+            return Ok(positions.iter().map(|p| lookup(&cached, p)).collect());
+        }
+
+        Ok(None)
+    }
+
+    async fn diagnostic(&self, params: DocumentDiagnosticParams) -> JsonRpcResult<DocumentDiagnosticReportResult> {
+        let uri = params.text_document.uri.clone();
+
+        self.flush_changes(&uri, None).await;
+        let Some(path) = self.uri_to_vfs_path(&uri) else {
+            return Ok(DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(
+                RelatedFullDocumentDiagnosticReport::default(),
+            )));
+        };
+        if let Some(doc) = self.open_documents.get(&uri) {
+            let response = match self.forward(&uri, &doc, "textDocument/diagnostic", &params).await {
+                Some(response) => response?,
+                None => match self.get_language_server(&doc.vfs_path).await {
+                    Some(ls) => ls.diagnostic(params.clone()).await?,
+                    None => None,
+                },
+            };
+            if let Some(report) = response {
+                return Ok(report);
+            }
+        }
+
+        // The server only pushes diagnostics; answer with the last ones
+        let report = self
+            .sessions
+            .diagnostic_report(&path, params.previous_result_id.as_deref());
+        Ok(DocumentDiagnosticReportResult::Report(report))
+    }
+
+    async fn workspace_diagnostic(
+        &self,
+        params: WorkspaceDiagnosticParams,
+    ) -> JsonRpcResult<WorkspaceDiagnosticReportResult> {
+        let uris: Vec<Url> = self.open_documents.iter().map(|doc| doc.key().clone()).collect();
+        for uri in uris {
+            self.flush_changes(&uri, None).await;
+        }
+
+        // Servers and nodes answer whole; the editor gets each answer as a
+        // partial result as it arrives, if it asked for them
+        let token = params.partial_result_params.partial_result_token.clone();
+        let request = WorkspaceDiagnosticParams {
+            partial_result_params: PartialResultParams::default(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            ..params.clone()
+        };
+
+        // Groups on other nodes, and local servers, all at once
+        let mut aggregator: ResponseAggregator<WorkspaceDocumentDiagnosticReport> = ResponseAggregator::new();
+        let mut sources = tokio::task::JoinSet::new();
+        for target in self.scatter_targets("workspace/diagnostic", &mut aggregator).await {
+            let remote = self.sessions.remote_lsp.clone();
+            let request = RemoteRequest {
+                method: "workspace/diagnostic".to_string(),
+                params: serde_json::to_value(&request).unwrap_or_default(),
+                document: None,
+                groups: target.groups,
+                min_applied_index: None,
+            };
+            sources.spawn(async move {
+                let report = remote.send(target.addr, &request).await.and_then(|report| {
+                    serde_json::from_value::<WorkspaceDiagnosticReport>(report)
+                        .map_err(|e| VRaftError::Serialization(e.to_string()))
+                });
+                (format!("node {}", target.node), report.map(|r| r.items).map_err(|e| e.to_string()))
+            });
+        }
+        let servers = self.sessions.ls_pool.workspace_diagnostic_servers();
+        let pulled: Vec<LanguageId> = servers.iter().map(|server| server.language().clone()).collect();
+        for server in servers {
+            let request = request.clone();
+            sources.spawn(async move {
+                let items = match server.workspace_diagnostic(request).await {
+                    Ok(Some(WorkspaceDiagnosticReportResult::Report(report))) => Ok(report.items),
+                    Ok(Some(WorkspaceDiagnosticReportResult::Partial(partial))) => Ok(partial.items),
+                    Ok(None) => Ok(Vec::new()),
+                    Err(e) => Err(e.to_string()),
+                };
+                (format!("{:?}", server.language()), items)
+            });
+        }
+
+        // Files of servers that only push get the last diagnostics pushed
+        let mut items = Vec::new();
+        let pushed = self.pushed_diagnostics(&params.previous_result_ids, &pulled);
+        self.report_diagnostics(token.as_ref(), pushed, &mut items).await;
+
+        while let Some(joined) = sources.join_next().await {
+            match joined {
+                Ok((_, Ok(reports))) => {
+                    let reports = reports.into_iter().map(|r| self.client_report(r)).collect();
+                    self.report_diagnostics(token.as_ref(), reports, &mut items).await;
+                }
+                Ok((source, Err(e))) => aggregator.add_error(format!("{}: {}", source, e)),
+                Err(e) => aggregator.add_error(e.to_string()),
+            }
+        }
+
+        if aggregator.has_errors() {
+            tracing::warn!("workspace/diagnostic incomplete: {:?}", aggregator.errors());
+        }
+        Ok(WorkspaceDiagnosticReportResult::Report(WorkspaceDiagnosticReport { items }))
+    }
 }
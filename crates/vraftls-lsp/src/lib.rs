@@ -1,9 +1,41 @@
 //! VRaftLS LSP - Language Server Protocol gateway and routing
 
+pub mod capabilities;
+pub mod editor_encoding;
+pub mod extensions;
+pub mod file_watch;
 pub mod gateway;
+pub mod metrics;
+pub mod notebook;
+pub mod passthrough;
+pub mod position_encoding;
 pub mod proxy;
+pub mod remote;
+pub mod resume;
 pub mod router;
+pub mod scheduler;
+pub mod semantic_tokens;
+pub mod session;
+pub mod transaction;
+pub mod workspace_edit;
+pub mod workspace_symbol;
 
+pub use capabilities::*;
+pub use editor_encoding::*;
+pub use extensions::*;
+pub use file_watch::*;
 pub use gateway::*;
+pub use metrics::*;
+pub use notebook::*;
+pub use passthrough::*;
+pub use position_encoding::*;
 pub use proxy::*;
+pub use remote::*;
+pub use resume::*;
 pub use router::*;
+pub use scheduler::*;
+pub use semantic_tokens::*;
+pub use session::*;
+pub use transaction::*;
+pub use workspace_edit::*;
+pub use workspace_symbol::*;
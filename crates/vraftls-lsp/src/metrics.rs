@@ -0,0 +1,179 @@
+//! Per-method LSP request metrics
+//!
+//! Editors' requests to the gateway and the gateway's requests to each
+//! language server are counted per method, with their failures and a
+//! latency histogram. The gateway's sessions and its language server pool
+//! share one `LspMetrics` registry, rendered in the Prometheus text format
+//! so slow paths, e.g. completion on one backend, show up per method.
+
+use dashmap::DashMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::Service;
+use tower_lsp::jsonrpc::{Request, Response};
+use vraftls_core::LanguageId;
+
+/// Upper bounds of the latency histogram buckets, in milliseconds
+pub const LATENCY_BUCKETS_MS: [u64; 12] = [1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+/// Requests of one method, to the gateway or to one language server
+#[derive(Clone, Debug, Default)]
+pub struct MethodMetrics {
+    pub requests: u64,
+    pub errors: u64,
+
+    /// Time spent answering all requests
+    pub latency_sum: Duration,
+
+    /// Requests answered within each of `LATENCY_BUCKETS_MS`, not cumulative
+    pub buckets: [u64; LATENCY_BUCKETS_MS.len()],
+}
+
+impl MethodMetrics {
+    fn record(&mut self, elapsed: Duration, failed: bool) {
+        self.requests += 1;
+        if failed {
+            self.errors += 1;
+        }
+        self.latency_sum += elapsed;
+        let millis = elapsed.as_millis() as u64;
+        if let Some(bucket) = LATENCY_BUCKETS_MS.iter().position(|bound| millis <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+    }
+}
+
+/// Registry of request metrics by method and, for language servers, by
+/// the server's language
+#[derive(Default)]
+pub struct LspMetrics {
+    methods: DashMap<(String, Option<LanguageId>), MethodMetrics>,
+}
+
+impl LspMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a request the gateway answered, or a language server did
+    /// if `language` is given
+    pub fn record(&self, method: &str, language: Option<&LanguageId>, elapsed: Duration, failed: bool) {
+        self.methods
+            .entry((method.to_string(), language.cloned()))
+            .or_default()
+            .record(elapsed, failed);
+    }
+
+    /// Metrics of a method, as served by the gateway or a language server
+    pub fn get(&self, method: &str, language: Option<&LanguageId>) -> Option<MethodMetrics> {
+        self.methods
+            .get(&(method.to_string(), language.cloned()))
+            .map(|m| m.clone())
+    }
+
+    /// Every method's metrics, ordered by method then language
+    pub fn snapshot(&self) -> Vec<(String, Option<LanguageId>, MethodMetrics)> {
+        let mut snapshot: Vec<_> = self
+            .methods
+            .iter()
+            .map(|e| (e.key().0.clone(), e.key().1.clone(), e.value().clone()))
+            .collect();
+        snapshot.sort_by(|a, b| {
+            let language = |l: &Option<LanguageId>| l.as_ref().map(|l| l.lsp_name().to_string());
+            (&a.0, language(&a.1)).cmp(&(&b.0, language(&b.1)))
+        });
+        snapshot
+    }
+
+    /// All metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+        for (name, help) in [
+            ("vraftls_lsp_requests_total", "LSP requests answered"),
+            ("vraftls_lsp_request_errors_total", "LSP requests answered with an error"),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for (method, language, metrics) in &snapshot {
+                let value = if name == "vraftls_lsp_requests_total" {
+                    metrics.requests
+                } else {
+                    metrics.errors
+                };
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels(method, language.as_ref()), value);
+            }
+        }
+
+        let name = "vraftls_lsp_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {} Time taken to answer LSP requests", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (method, language, metrics) in &snapshot {
+            let labels = labels(method, language.as_ref());
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(metrics.buckets) {
+                cumulative += count;
+                let le = *bound as f64 / 1000.0;
+                let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, cumulative);
+            }
+            let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, metrics.requests);
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, metrics.latency_sum.as_secs_f64());
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, metrics.requests);
+        }
+        out
+    }
+}
+
+/// Labels of a method's series; requests to the gateway itself have the
+/// backend `gateway`
+fn labels(method: &str, language: Option<&LanguageId>) -> String {
+    let backend = language.map_or("gateway", |l| l.lsp_name());
+    format!("method=\"{}\",backend=\"{}\"", method, backend)
+}
+
+/// Service recording the requests and notifications editors send the gateway
+pub struct MeteredService<S> {
+    inner: S,
+    metrics: Arc<LspMetrics>,
+}
+
+impl<S> MeteredService<S> {
+    pub fn new(inner: S, metrics: Arc<LspMetrics>) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<S> Service<Request> for MeteredService<S>
+where
+    S: Service<Request, Response = Option<Response>>,
+    S::Future: Send + 'static,
+{
+    type Response = Option<Response>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let method = request.method().to_string();
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            let failed = match &response {
+                Ok(Some(response)) => response.is_error(),
+                Ok(None) => false,
+                Err(_) => true,
+            };
+            metrics.record(&method, None, start.elapsed(), failed);
+            response
+        })
+    }
+}
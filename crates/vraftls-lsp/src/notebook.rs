@@ -0,0 +1,253 @@
+//! Notebook document synchronization
+//!
+//! Editors send Jupyter notebooks as `notebookDocument/*` notifications,
+//! with each cell a text document of its own. The gateway serves every cell
+//! as a text document: the notebook notifications are translated into
+//! `textDocument/*` ones for the cells, and each cell is kept in the VFS as
+//! a virtual file next to the notebook, e.g. `nb.ipynb.cells/<cell>.py`, so
+//! it is stored, routed and given to a language server like any file.
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use tower_lsp::lsp_types::{
+    TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem, Url, VersionedTextDocumentIdentifier,
+};
+use vraftls_core::LanguageId;
+use vraftls_vfs::VfsPath;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidOpenNotebookDocumentParams {
+    cell_text_documents: Vec<TextDocumentItem>,
+}
+
+#[derive(Deserialize)]
+struct DidChangeNotebookDocumentParams {
+    change: NotebookDocumentChangeEvent,
+}
+
+#[derive(Deserialize)]
+struct NotebookDocumentChangeEvent {
+    cells: Option<NotebookDocumentCellChange>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NotebookDocumentCellChange {
+    structure: Option<NotebookDocumentCellChangeStructure>,
+    text_content: Option<Vec<NotebookDocumentChangeTextContent>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NotebookDocumentCellChangeStructure {
+    did_open: Option<Vec<TextDocumentItem>>,
+    did_close: Option<Vec<TextDocumentIdentifier>>,
+}
+
+#[derive(Deserialize)]
+struct NotebookDocumentChangeTextContent {
+    document: VersionedTextDocumentIdentifier,
+    changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DidCloseNotebookDocumentParams {
+    cell_text_documents: Vec<TextDocumentIdentifier>,
+}
+
+/// Notebook synchronization the gateway announces: every Jupyter notebook,
+/// whatever the language of its cells
+///
+/// Added to the `initialize` answer as it is, since `ServerCapabilities`
+/// has no field for it.
+pub fn notebook_sync_options() -> Value {
+    json!({
+        "notebookSelector": [{ "notebook": { "pattern": "**/*.ipynb" } }],
+        "save": false,
+    })
+}
+
+/// Text document notifications for the cells of a notebook notification
+///
+/// `None` if the method is not a notebook notification, or its params are
+/// invalid; notebook saves have no cell notifications.
+pub fn cell_notifications(method: &str, params: Value) -> Option<Vec<(&'static str, Value)>> {
+    let open = |cell: TextDocumentItem| ("textDocument/didOpen", json!({ "textDocument": cell }));
+    let close = |cell: TextDocumentIdentifier| ("textDocument/didClose", json!({ "textDocument": cell }));
+
+    match method {
+        "notebookDocument/didOpen" => {
+            let params: DidOpenNotebookDocumentParams = serde_json::from_value(params).ok()?;
+            Some(params.cell_text_documents.into_iter().map(open).collect())
+        }
+        "notebookDocument/didChange" => {
+            let params: DidChangeNotebookDocumentParams = serde_json::from_value(params).ok()?;
+            let Some(cells) = params.change.cells else {
+                return Some(Vec::new());
+            };
+
+            // Cells are closed and opened before the text of the cells
+            // that stay changes
+            let mut notifications = Vec::new();
+            if let Some(structure) = cells.structure {
+                notifications.extend(structure.did_close.into_iter().flatten().map(close));
+                notifications.extend(structure.did_open.into_iter().flatten().map(open));
+            }
+            for content in cells.text_content.into_iter().flatten() {
+                notifications.push((
+                    "textDocument/didChange",
+                    json!({ "textDocument": content.document, "contentChanges": content.changes }),
+                ));
+            }
+            Some(notifications)
+        }
+        "notebookDocument/didSave" => Some(Vec::new()),
+        "notebookDocument/didClose" => {
+            let params: DidCloseNotebookDocumentParams = serde_json::from_value(params).ok()?;
+            Some(params.cell_text_documents.into_iter().map(close).collect())
+        }
+        _ => None,
+    }
+}
+
+/// Virtual VFS file of a notebook cell
+///
+/// Cell URIs carry the notebook's path with the cell in their fragment,
+/// e.g. `vscode-notebook-cell:/work/nb.ipynb#W0sZmlsZQ%3D%3D`. The file's
+/// extension is the cell language's, so the cell goes to its language server.
+pub fn cell_path(cell: &Url, language: &LanguageId) -> Option<VfsPath> {
+    let fragment: String = cell
+        .fragment()?
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let notebook = Url::parse(&format!("file://{}", cell.path())).ok()?.to_file_path().ok()?;
+
+    let mut cells = notebook.into_os_string();
+    cells.push(".cells");
+    let file = PathBuf::from(cells).join(format!("{}.{}", fragment, extension(language)));
+    Some(VfsPath::from(file))
+}
+
+/// Extension of a language's files
+fn extension(language: &LanguageId) -> &str {
+    match language {
+        LanguageId::Rust => "rs",
+        LanguageId::TypeScript => "ts",
+        LanguageId::JavaScript => "js",
+        LanguageId::Go => "go",
+        LanguageId::Python => "py",
+        LanguageId::Other(name) => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::apply_content_changes;
+
+    const NOTEBOOK: &str = "file:///work/nb.ipynb";
+
+    fn cell(id: &str) -> String {
+        format!("vscode-notebook-cell:/work/nb.ipynb#{}", id)
+    }
+
+    fn methods(notifications: &[(&'static str, Value)]) -> Vec<&'static str> {
+        notifications.iter().map(|(method, _)| *method).collect()
+    }
+
+    #[test]
+    fn test_open_and_close() {
+        let opened = cell_notifications(
+            "notebookDocument/didOpen",
+            json!({
+                "notebookDocument": { "uri": NOTEBOOK, "notebookType": "jupyter-notebook", "version": 1, "cells": [] },
+                "cellTextDocuments": [
+                    { "uri": cell("a"), "languageId": "python", "version": 1, "text": "import os" },
+                    { "uri": cell("b"), "languageId": "python", "version": 1, "text": "print(1)" }
+                ]
+            }),
+        )
+        .unwrap();
+        assert_eq!(methods(&opened), ["textDocument/didOpen", "textDocument/didOpen"]);
+        assert_eq!(opened[1].1["textDocument"]["text"], "print(1)");
+
+        let closed = cell_notifications(
+            "notebookDocument/didClose",
+            json!({ "notebookDocument": { "uri": NOTEBOOK }, "cellTextDocuments": [{ "uri": cell("a") }] }),
+        )
+        .unwrap();
+        assert_eq!(closed, [("textDocument/didClose", json!({ "textDocument": { "uri": cell("a") } }))]);
+
+        assert_eq!(cell_notifications("notebookDocument/didSave", json!({})), Some(Vec::new()));
+        assert_eq!(cell_notifications("notebookDocument/didOpen", json!({})), None);
+        assert_eq!(cell_notifications("textDocument/didOpen", json!({})), None);
+    }
+
+    #[test]
+    fn test_change_structure_and_text() {
+        let change = |id: &str, version: i32, text: &str| {
+            json!({
+                "document": { "uri": cell(id), "version": version },
+                "changes": [{
+                    "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } },
+                    "text": text
+                }]
+            })
+        };
+        let notifications = cell_notifications(
+            "notebookDocument/didChange",
+            json!({
+                "notebookDocument": { "uri": NOTEBOOK, "version": 2 },
+                "change": {
+                    "cells": {
+                        "structure": {
+                            "array": { "start": 1, "deleteCount": 1, "cells": [{ "kind": 2, "document": cell("c") }] },
+                            "didOpen": [{ "uri": cell("c"), "languageId": "python", "version": 1, "text": "x = 1" }],
+                            "didClose": [{ "uri": cell("b") }]
+                        },
+                        "textContent": [change("a", 2, "# a\n"), change("c", 2, "# c\n")]
+                    }
+                }
+            }),
+        )
+        .unwrap();
+
+        // The removed cell is closed and the inserted one opened before
+        // the text of each cell changes
+        assert_eq!(
+            methods(&notifications),
+            ["textDocument/didClose", "textDocument/didOpen", "textDocument/didChange", "textDocument/didChange"]
+        );
+        assert_eq!(notifications[0].1["textDocument"]["uri"], cell("b"));
+        assert_eq!(notifications[1].1["textDocument"]["uri"], cell("c"));
+
+        // Each cell's edits apply to that cell's text alone
+        for ((_, params), (id, text, edited)) in notifications[2..]
+            .iter()
+            .zip([("a", "import os", "# a\nimport os"), ("c", "x = 1", "# c\nx = 1")])
+        {
+            assert_eq!(params["textDocument"], json!({ "uri": cell(id), "version": 2 }));
+            let changes: Vec<TextDocumentContentChangeEvent> =
+                serde_json::from_value(params["contentChanges"].clone()).unwrap();
+            assert_eq!(apply_content_changes(text, &changes), edited);
+        }
+
+        // Metadata changes have no cell notifications
+        let metadata = json!({ "notebookDocument": { "uri": NOTEBOOK, "version": 3 }, "change": { "metadata": {} } });
+        assert_eq!(cell_notifications("notebookDocument/didChange", metadata), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_cell_path() {
+        let url = Url::parse(&cell("W0sZmlsZQ%3D%3D")).unwrap();
+        assert_eq!(
+            cell_path(&url, &LanguageId::Python),
+            Some(VfsPath::new("/work/nb.ipynb.cells/W0sZmlsZQ_3D_3D.py"))
+        );
+        assert_eq!(cell_path(&Url::parse(NOTEBOOK).unwrap(), &LanguageId::Python), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Passthrough of LSP methods the gateway does not handle itself
+//!
+//! tower-lsp answers "method not found" for requests it has no handler
+//! for, and for those `LspGateway` leaves to the trait's default, e.g.
+//! `textDocument/moniker` or a server's own extensions. Such requests
+//! about a document are relayed as they are to the document's language
+//! server, if it advertises the method; extension requests without one go
+//! to the server of their namespace. Notifications tower-lsp does not route
+//! are relayed the same way, except notebook notifications, which are
+//! handed to `LspGateway` as text document notifications for their cells.
+
+use serde_json::Value;
+use std::collections::HashSet;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::Service;
+use tower_lsp::jsonrpc::{ErrorCode, Request, Response, Result as JsonRpcResult};
+use tower_lsp::{Client, ExitedError, LspService};
+use vraftls_core::ClientId;
+use vraftls_vfs::VfsPath;
+
+use crate::capabilities::{dynamic_methods, untyped_providers};
+use crate::extensions::extension_language;
+use crate::gateway::{flush_changes, LspGateway};
+use crate::notebook::{cell_notifications, notebook_sync_options};
+use crate::position_encoding::request_uri;
+use crate::proxy::LanguageServerProxy;
+use crate::session::{GatewaySessions, OpenDocuments};
+
+/// Notifications tower-lsp hands to `LspGateway`; `$/` ones it handles or
+/// may ignore
+const ROUTED_NOTIFICATIONS: &[&str] = &[
+    "initialized",
+    "exit",
+    "workspace/didChangeWorkspaceFolders",
+    "workspace/didChangeConfiguration",
+    "workspace/didChangeWatchedFiles",
+    "workspace/didCreateFiles",
+    "workspace/didRenameFiles",
+    "workspace/didDeleteFiles",
+    "textDocument/didOpen",
+    "textDocument/didChange",
+    "textDocument/willSave",
+    "textDocument/didSave",
+    "textDocument/didClose",
+];
+
+/// Service relaying what an editor's `LspGateway` does not handle
+pub struct Passthrough {
+    inner: LspService<LspGateway>,
+    client_id: ClientId,
+    relay: Relay,
+}
+
+impl Passthrough {
+    pub fn new(inner: LspService<LspGateway>) -> Self {
+        let gateway = inner.inner();
+        let relay = Relay {
+            client: gateway.client.clone(),
+            sessions: gateway.sessions.clone(),
+            open_documents: gateway.open_documents.clone(),
+        };
+        Self {
+            client_id: gateway.client_id,
+            inner,
+            relay,
+        }
+    }
+}
+
+impl Service<Request> for Passthrough {
+    type Response = Option<Response>;
+    type Error = ExitedError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if request.id().is_none() {
+            let params = request.params().cloned().unwrap_or(Value::Null);
+            if let Some(cells) = cell_notifications(request.method(), params) {
+                // Handled one after the other, so cells are opened before
+                // they change
+                let calls: Vec<_> = cells
+                    .into_iter()
+                    .map(|(method, params)| self.inner.call(Request::build(method).params(params).finish()))
+                    .collect();
+                return Box::pin(async move {
+                    for call in calls {
+                        call.await?;
+                    }
+                    Ok(None)
+                });
+            }
+        }
+
+        let method = request.method().to_string();
+        let id = request.id().cloned();
+        let params = request.params().cloned().unwrap_or(Value::Null);
+        let relay = self.relay.clone();
+
+        // Read before `LspGateway` answers, from the capabilities as the
+        // editor sent them
+        let mut dynamic = HashSet::new();
+        if method == "initialize" {
+            dynamic = dynamic_methods(&params["capabilities"]);
+            relay.sessions.set_dynamic_methods(self.client_id, dynamic.clone());
+        }
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let response = response.await?;
+            match (id, response) {
+                (Some(id), Some(response))
+                    if response.error().is_some_and(|e| e.code == ErrorCode::MethodNotFound) =>
+                {
+                    Ok(Some(match relay.request(&method, params).await {
+                        Some(Ok(result)) => Response::from_ok(id, result),
+                        Some(Err(e)) => Response::from_error(id, e),
+                        None => response,
+                    }))
+                }
+                (Some(_), Some(response)) if method == "initialize" => {
+                    Ok(Some(with_untyped_capabilities(response, &dynamic)))
+                }
+                (None, response) => {
+                    if !method.starts_with("$/") && !ROUTED_NOTIFICATIONS.contains(&method.as_str()) {
+                        relay.notify(&method, params).await;
+                    }
+                    Ok(response)
+                }
+                (_, response) => Ok(response),
+            }
+        })
+    }
+}
+
+/// An `initialize` answer announcing notebook synchronization and the
+/// providers `ServerCapabilities` has no field for
+fn with_untyped_capabilities(response: Response, dynamic: &HashSet<String>) -> Response {
+    let (id, result) = response.into_parts();
+    Response::from_parts(
+        id,
+        result.map(|mut result| {
+            if let Some(capabilities) = result.get_mut("capabilities").and_then(Value::as_object_mut) {
+                capabilities.insert("notebookDocumentSync".to_string(), notebook_sync_options());
+                for (provider, options) in untyped_providers(dynamic) {
+                    capabilities.insert(provider.to_string(), options);
+                }
+            }
+            result
+        }),
+    )
+}
+
+/// What relaying needs of the editor's gateway
+#[derive(Clone)]
+struct Relay {
+    client: Client,
+    sessions: Arc<GatewaySessions>,
+    open_documents: OpenDocuments,
+}
+
+impl Relay {
+    /// Language server of the document a message is about, once it has the
+    /// editor's latest changes, or of the extension the method belongs to
+    async fn owner(&self, method: &str, params: &Value) -> Option<Arc<LanguageServerProxy>> {
+        let Some(uri) = request_uri(params) else {
+            let language = extension_language(method)?;
+            return self.sessions.ls_pool.get_or_spawn(language).await.ok();
+        };
+        flush_changes(&self.client, &self.sessions, &self.open_documents, &uri, None).await;
+        let path = match self.open_documents.get(&uri) {
+            Some(doc) => doc.vfs_path.clone(),
+            None => VfsPath::from(uri.to_file_path().ok()?),
+        };
+        self.sessions.language_server(&path).await
+    }
+
+    /// Relay a request; `None` if no server advertises the method
+    async fn request(&self, method: &str, params: Value) -> Option<JsonRpcResult<Value>> {
+        let server = self.owner(method, &params).await?;
+        if !server.supports(method) {
+            tracing::debug!("No server handles {}", method);
+            return None;
+        }
+        Some(server.forward(method, params).await.map(Option::unwrap_or_default))
+    }
+
+    /// Relay a notification to the document's server, if any
+    async fn notify(&self, method: &str, params: Value) {
+        match self.owner(method, &params).await {
+            Some(server) => server.forward_notification(method, params).await,
+            None => tracing::debug!("Ignoring notification {}", method),
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Position encodings and conversion between them
+//!
+//! An LSP position counts characters within a line in the code units of the
+//! negotiated encoding. The gateway counts in UTF-16 and converts positions
+//! of editors and language servers that picked another encoding, using the
+//! text they have of the documents.
+//!
+//! Positions are found by shape: any object with just a `line` and a
+//! `character`. The document they refer to is the nearest enclosing `uri`
+//! or `textDocument.uri`, or the key of a workspace edit's `changes`.
+//! Semantic tokens are decoded from their deltas against the document's
+//! lines, converted and encoded again. Edits of a semantic tokens delta
+//! cannot be decoded without the tokens they apply to and pass through as
+//! they are.
+
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use tower_lsp::lsp_types::{PositionEncodingKind, Url};
+use vraftls_vfs::{Vfs, VfsPath};
+
+/// Code units positions count characters in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    Utf8,
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Encoding of a kind a client or server named, if known
+    pub fn from_kind(kind: &PositionEncodingKind) -> Option<Self> {
+        match kind.as_str() {
+            "utf-8" => Some(Self::Utf8),
+            "utf-16" => Some(Self::Utf16),
+            "utf-32" => Some(Self::Utf32),
+            _ => None,
+        }
+    }
+
+    /// Encoding to use with a peer offering `offered`, most preferred
+    /// first: the first one known, or else UTF-16, which every peer supports
+    pub fn negotiate(offered: Option<&[PositionEncodingKind]>) -> Self {
+        offered.into_iter().flatten().find_map(Self::from_kind).unwrap_or_default()
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+            Self::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    /// Code units of a character
+    fn len(self, c: char) -> usize {
+        match self {
+            Self::Utf8 => c.len_utf8(),
+            Self::Utf16 => c.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
+}
+
+/// Where positions are converted, using the documents' text
+pub trait DocumentSource: Send + Sync {
+    /// Text of a document, if known
+    fn text(&self, uri: &Url) -> Option<String>;
+}
+
+impl DocumentSource for Vfs {
+    fn text(&self, uri: &Url) -> Option<String> {
+        let path = VfsPath::from(uri.to_file_path().ok()?);
+        let file = self.get_file_by_path(&path)?;
+        self.get_content(file.id).ok()
+    }
+}
+
+/// Character offset of a position in a line, counted in another encoding
+///
+/// Offsets past the end of the line are clamped to it, and offsets inside a
+/// character moved to where the character starts.
+pub fn convert_character(line: &str, character: u32, from: PositionEncoding, to: PositionEncoding) -> u32 {
+    let (mut from_units, mut to_units) = (0, 0);
+    for c in line.chars() {
+        from_units += from.len(c);
+        if from_units > character as usize {
+            break;
+        }
+        to_units += to.len(c);
+    }
+    to_units as u32
+}
+
+/// Converts the positions in LSP messages between two encodings
+pub struct PositionConverter<'a> {
+    from: PositionEncoding,
+    to: PositionEncoding,
+    documents: &'a dyn DocumentSource,
+
+    /// Lines of the documents read so far
+    lines: HashMap<Url, Option<Vec<String>>>,
+}
+
+impl<'a> PositionConverter<'a> {
+    pub fn new(from: PositionEncoding, to: PositionEncoding, documents: &'a dyn DocumentSource) -> Self {
+        Self {
+            from,
+            to,
+            documents,
+            lines: HashMap::new(),
+        }
+    }
+
+    /// Convert every position in a message
+    ///
+    /// Positions outside any document with a URI refer to `uri`.
+    pub fn convert(&mut self, value: &mut Value, uri: Option<&Url>) {
+        if self.from != self.to {
+            self.walk(value, uri.cloned());
+        }
+    }
+
+    fn walk(&mut self, value: &mut Value, uri: Option<Url>) {
+        match value {
+            Value::Array(items) => {
+                for item in items {
+                    self.walk(item, uri.clone());
+                }
+            }
+            Value::Object(object) => {
+                if is_position(object) {
+                    if let Some(uri) = &uri {
+                        self.convert_position(object, uri);
+                    }
+                    return;
+                }
+                let uri = object_uri(object).or(uri);
+                if let (Some(uri), true) = (&uri, is_semantic_tokens(object)) {
+                    if let Some(Value::Array(data)) = object.get_mut("data") {
+                        self.convert_semantic_tokens(data, uri);
+                    }
+                }
+                for (key, child) in object.iter_mut() {
+                    if key == "changes" {
+                        // Workspace edit changes, by document
+                        if let Value::Object(changes) = child {
+                            for (target, edits) in changes.iter_mut() {
+                                self.walk(edits, Url::parse(target).ok().or(uri.clone()));
+                            }
+                            continue;
+                        }
+                    }
+                    self.walk(child, uri.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn convert_position(&mut self, position: &mut Map<String, Value>, uri: &Url) {
+        let (Some(line), Some(character)) = (
+            position.get("line").and_then(Value::as_u64),
+            position.get("character").and_then(Value::as_u64),
+        ) else {
+            return;
+        };
+
+        let (from, to) = (self.from, self.to);
+        let Some(text) = self.lines(uri).and_then(|lines| lines.get(line as usize)) else {
+            return;
+        };
+        let character = convert_character(text, character as u32, from, to);
+        position.insert("character".to_string(), Value::from(character));
+    }
+
+    /// Convert semantic tokens, five numbers each: line and start relative
+    /// to the previous token, length, type and modifiers
+    fn convert_semantic_tokens(&mut self, data: &mut [Value], uri: &Url) {
+        let (from, to) = (self.from, self.to);
+        let Some(lines) = self.lines(uri) else {
+            return;
+        };
+
+        let (mut line, mut start) = (0, 0);
+        let mut previous_start = 0;
+        for token in data.chunks_exact_mut(5) {
+            let number = |value: &Value| value.as_u64().unwrap_or(0) as u32;
+            let delta_line = number(&token[0]);
+            if delta_line > 0 {
+                line += delta_line;
+                start = 0;
+                previous_start = 0;
+            }
+            start += number(&token[1]);
+            let end = start + number(&token[2]);
+
+            let text = lines.get(line as usize).map(String::as_str).unwrap_or_default();
+            let converted_start = convert_character(text, start, from, to);
+            let converted_end = convert_character(text, end, from, to);
+            token[1] = Value::from(converted_start - previous_start);
+            token[2] = Value::from(converted_end.saturating_sub(converted_start));
+            previous_start = converted_start;
+        }
+    }
+
+    /// Lines of a document, read once
+    fn lines(&mut self, uri: &Url) -> Option<&Vec<String>> {
+        let documents = self.documents;
+        self.lines
+            .entry(uri.clone())
+            .or_insert_with(|| {
+                documents
+                    .text(uri)
+                    .map(|text| text.split('\n').map(str::to_string).collect())
+            })
+            .as_ref()
+    }
+}
+
+/// Document a request is about, which positions in its result refer to
+pub fn request_uri(params: &Value) -> Option<Url> {
+    params.as_object().and_then(object_uri)
+}
+
+/// Whether an object is an LSP position
+fn is_position(object: &Map<String, Value>) -> bool {
+    object.len() == 2 && object.contains_key("line") && object.contains_key("character")
+}
+
+/// Whether an object is a semantic tokens result: an optional `resultId`
+/// and `data`, five numbers a token
+fn is_semantic_tokens(object: &Map<String, Value>) -> bool {
+    let Some(Value::Array(data)) = object.get("data") else {
+        return false;
+    };
+    object.keys().all(|key| key == "data" || key == "resultId")
+        && data.len() % 5 == 0
+        && data.iter().all(Value::is_u64)
+}
+
+/// Document an object refers to, directly or as its `textDocument`
+fn object_uri(object: &Map<String, Value>) -> Option<Url> {
+    object
+        .get("uri")
+        .or_else(|| object.get("textDocument").and_then(|doc| doc.get("uri")))
+        .and_then(Value::as_str)
+        .and_then(|uri| Url::parse(uri).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use PositionEncoding::{Utf16, Utf32, Utf8};
+
+    /// Documents held in memory
+    struct Documents(HashMap<Url, String>);
+
+    impl DocumentSource for Documents {
+        fn text(&self, uri: &Url) -> Option<String> {
+            self.0.get(uri).cloned()
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        use PositionEncodingKind as Kind;
+        assert_eq!(PositionEncoding::negotiate(None), Utf16);
+        assert_eq!(PositionEncoding::negotiate(Some(&[])), Utf16);
+        assert_eq!(PositionEncoding::negotiate(Some(&[Kind::UTF8, Kind::UTF16])), Utf8);
+        assert_eq!(PositionEncoding::negotiate(Some(&[Kind::new("utf-7"), Kind::UTF32])), Utf32);
+    }
+
+    #[test]
+    fn test_convert_character_multi_byte() {
+        // `é` and `ö` take two bytes but one UTF-16 unit
+        let line = "héllo wörld";
+        assert_eq!(convert_character(line, 2, Utf16, Utf8), 3);
+        assert_eq!(convert_character(line, 3, Utf8, Utf16), 2);
+        assert_eq!(convert_character(line, 8, Utf16, Utf8), 10);
+        assert_eq!(convert_character(line, 8, Utf16, Utf32), 8);
+    }
+
+    #[test]
+    fn test_convert_character_surrogate_pair() {
+        // `😀` is four bytes, a UTF-16 surrogate pair and one UTF-32 unit
+        let line = "a😀b";
+        assert_eq!(convert_character(line, 3, Utf16, Utf8), 5);
+        assert_eq!(convert_character(line, 3, Utf16, Utf32), 2);
+        assert_eq!(convert_character(line, 5, Utf8, Utf16), 3);
+        assert_eq!(convert_character(line, 2, Utf32, Utf16), 3);
+
+        // An offset inside a character moves to its start
+        assert_eq!(convert_character(line, 2, Utf16, Utf8), 1);
+        assert_eq!(convert_character(line, 3, Utf8, Utf16), 1);
+        assert_eq!(convert_character("é", 1, Utf8, Utf32), 0);
+    }
+
+    #[test]
+    fn test_convert_character_clamps_past_end_of_line() {
+        assert_eq!(convert_character("abc", 10, Utf16, Utf8), 3);
+        assert_eq!(convert_character("a😀", 20, Utf16, Utf8), 5);
+        assert_eq!(convert_character("a😀", 20, Utf8, Utf16), 3);
+        assert_eq!(convert_character("", 4, Utf32, Utf16), 0);
+    }
+
+    #[test]
+    fn test_workspace_edit_changes_use_their_document() {
+        let a = Url::parse("file:///src/a.rs").unwrap();
+        let b = Url::parse("file:///src/b.rs").unwrap();
+        let documents = Documents(HashMap::from([
+            (a.clone(), "let é = 1;\nfn main() {}".to_string()),
+            (b.clone(), "// 😀😀\nlet x = 2;".to_string()),
+        ]));
+        let edit = |line, start, end| {
+            json!({
+                "range": {
+                    "start": {"line": line, "character": start},
+                    "end": {"line": line, "character": end}
+                },
+                "newText": "x"
+            })
+        };
+        let mut message = json!({
+            "edit": {
+                "changes": {
+                    a.as_str(): [edit(0, 4, 6), edit(1, 3, 7)],
+                    b.as_str(): [edit(0, 3, 11)],
+                    "file:///src/unknown.rs": [edit(0, 4, 6)]
+                }
+            }
+        });
+
+        // Each key's edits are converted against that document's text
+        PositionConverter::new(Utf8, Utf16, &documents).convert(&mut message, None);
+        let changes = &message["edit"]["changes"];
+        assert_eq!(changes[a.as_str()], json!([edit(0, 4, 5), edit(1, 3, 7)]));
+        assert_eq!(changes[b.as_str()], json!([edit(0, 3, 7)]));
+        assert_eq!(changes["file:///src/unknown.rs"], json!([edit(0, 4, 6)]));
+    }
+
+    #[test]
+    fn test_semantic_tokens() {
+        let uri = Url::parse("file:///src/a.rs").unwrap();
+        let documents = Documents(HashMap::from([(
+            uri.clone(),
+            "let é = \"😀\";\nfn ö() {}".to_string(),
+        )]));
+        // `é` at 4, the string at 9 to 15 and `let` on the first line;
+        // `fn` and `ö` on the second, all in UTF-8
+        let mut result = json!({
+            "resultId": "1",
+            "data": [0, 0, 3, 1, 0, 0, 4, 2, 2, 0, 0, 5, 6, 3, 0, 1, 0, 2, 1, 0, 0, 3, 2, 4, 0]
+        });
+
+        PositionConverter::new(Utf8, Utf16, &documents).convert(&mut result, Some(&uri));
+        assert_eq!(
+            result,
+            json!({
+                "resultId": "1",
+                "data": [0, 0, 3, 1, 0, 0, 4, 1, 2, 0, 0, 4, 4, 3, 0, 1, 0, 2, 1, 0, 0, 3, 1, 4, 0]
+            })
+        );
+
+        // Converting back gives the tokens the server sent
+        PositionConverter::new(Utf16, Utf8, &documents).convert(&mut result, Some(&uri));
+        assert_eq!(
+            result["data"],
+            json!([0, 0, 3, 1, 0, 0, 4, 2, 2, 0, 0, 5, 6, 3, 0, 1, 0, 2, 1, 0, 0, 3, 2, 4, 0])
+        );
+
+        // Delta edits cannot be decoded and are left alone
+        let delta = json!({"resultId": "2", "edits": [{"start": 5, "deleteCount": 5, "data": [0, 4, 2, 2, 0]}]});
+        let mut converted = delta.clone();
+        PositionConverter::new(Utf8, Utf16, &documents).convert(&mut converted, Some(&uri));
+        assert_eq!(converted, delta);
+    }
+}
@@ -3,30 +3,209 @@
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::process::Stdio;
 use std::sync::atomic::{AtomicI64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as StdMutex, OnceLock, RwLock as StdRwLock, Weak};
+use std::time::Instant;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::process::{Child, ChildStdin, ChildStdout, Command};
-use tokio::sync::{oneshot, Mutex, RwLock};
+use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
 use tower_lsp::jsonrpc::Result as JsonRpcResult;
 use tower_lsp::lsp_types::*;
-use vraftls_core::{LanguageId, Result, VRaftError};
+use tower_lsp::lsp_types::request::{
+    GotoDeclarationParams, GotoDeclarationResponse, GotoImplementationParams, GotoImplementationResponse,
+    GotoTypeDefinitionParams, GotoTypeDefinitionResponse,
+};
+use vraftls_core::{LanguageId, LanguageServerConfig, LanguageServerLimits, RequestTimeouts, Result, VRaftError};
+
+use crate::capabilities::provider_registrations;
+use crate::metrics::LspMetrics;
+use crate::position_encoding::{request_uri, DocumentSource, PositionConverter, PositionEncoding};
+use crate::router::ResponseAggregator;
+use crate::scheduler::{RequestPriority, RequestScheduler};
+use crate::session::apply_content_changes;
+use crate::semantic_tokens::{apply_edits, gateway_legend, semantic_tokens_options, LegendMap};
+use crate::workspace_symbol::flatten_symbols;
 
 /// Pool of language server processes
 pub struct LanguageServerPool {
     /// Running language servers
     servers: DashMap<LanguageId, Arc<LanguageServerProxy>>,
+
+    /// Held while a language's server is spawned, so it is spawned once
+    spawning: DashMap<LanguageId, Arc<Mutex<()>>>,
+
+    /// Servers being spawned, counted against `max_servers`
+    reserved: StdMutex<usize>,
+
+    /// Where servers send their notifications
+    notifications: Option<NotificationSender>,
+
+    /// Initialize parameters of the editor, passed on to spawned servers
+    client_params: RwLock<InitializeParams>,
+
+    /// Settings from the editor, read by the servers
+    settings: SharedSettings,
+
+    /// Configured servers by language ID, replacing the built-in ones
+    configs: BTreeMap<String, LanguageServerConfig>,
+
+    /// Commands set cluster-wide by language ID, replacing the configured ones
+    commands: StdRwLock<BTreeMap<String, String>>,
+
+    /// When idle servers are shut down
+    limits: LanguageServerLimits,
+
+    /// Time servers get to answer each kind of request
+    timeouts: RequestTimeouts,
+
+    /// Text of the documents, to convert positions of servers using
+    /// another encoding than the gateway
+    documents: Option<Arc<dyn DocumentSource>>,
+
+    /// Where servers' requests are recorded
+    metrics: Option<Arc<LspMetrics>>,
 }
 
 impl LanguageServerPool {
     pub fn new() -> Self {
         Self {
             servers: DashMap::new(),
+            spawning: DashMap::new(),
+            reserved: StdMutex::new(0),
+            notifications: None,
+            client_params: RwLock::new(InitializeParams::default()),
+            settings: Arc::new(StdRwLock::new(Value::Null)),
+            configs: BTreeMap::new(),
+            commands: StdRwLock::new(BTreeMap::new()),
+            limits: LanguageServerLimits::default(),
+            timeouts: RequestTimeouts::default(),
+            documents: None,
+            metrics: None,
+        }
+    }
+
+    /// Record the requests of every server in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<LspMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Convert positions of servers not using UTF-16 with the text of `documents`
+    pub fn with_documents(mut self, documents: Arc<dyn DocumentSource>) -> Self {
+        self.documents = Some(documents);
+        self
+    }
+
+    /// Convert the positions in a message from a language's server to the
+    /// gateway's encoding
+    pub fn convert_from_server(&self, lang: &LanguageId, message: &mut Value) {
+        if let Some(server) = self.servers.get(lang) {
+            server.convert_from_server(message);
+        }
+    }
+
+    /// Give servers these times to answer requests
+    pub fn with_request_timeouts(mut self, timeouts: RequestTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Shut down idle servers past `limits`
+    pub fn with_limits(mut self, limits: LanguageServerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Run the configured servers instead of the built-in ones
+    pub fn with_language_servers(mut self, configs: BTreeMap<String, LanguageServerConfig>) -> Self {
+        self.configs = configs;
+        self
+    }
+
+    /// Run servers spawned from now on with these commands, by language ID
+    ///
+    /// A command's first word is the program and the rest its arguments.
+    /// Servers already running keep their command until they are stopped.
+    pub fn set_commands(&self, commands: BTreeMap<String, String>) {
+        *self.commands.write().unwrap() = commands;
+    }
+
+    /// How to run the server of a language, if it has one
+    pub fn server_config(&self, lang: &LanguageId) -> Option<LanguageServerConfig> {
+        let config = self
+            .configs
+            .get(lang.lsp_name())
+            .cloned()
+            .or_else(|| LanguageServerConfig::builtin(lang));
+        let commands = self.commands.read().unwrap();
+        let Some(command) = commands.get(lang.lsp_name()) else {
+            return config;
+        };
+        let mut words = command.split_whitespace();
+        let Some(program) = words.next() else {
+            return config;
+        };
+        let mut config = config.unwrap_or_else(|| LanguageServerConfig::new(program));
+        config.command = program.to_string();
+        config.args = words.map(str::to_string).collect();
+        Some(config)
+    }
+
+    /// Store new settings from the editor and pass them on to the servers
+    ///
+    /// Each server is sent the sections of its language; servers that pull
+    /// their configuration instead get the stored settings when they ask.
+    pub async fn change_configuration(&self, settings: Value) {
+        *self.settings.write().unwrap() = settings.clone();
+
+        for server in self.running() {
+            server
+                .did_change_configuration(DidChangeConfigurationParams {
+                    settings: language_settings(&settings, server.language()),
+                })
+                .await;
         }
     }
 
+    /// Remember the editor's initialize parameters for servers spawned later
+    ///
+    /// The gateway watches files for the servers itself, in the VFS, so
+    /// they may register watchers whatever the editor supports. Servers are
+    /// offered the editor's position encoding, which saves converting
+    /// positions the editor sends when they take it, and the gateway's.
+    pub async fn set_client_params(&self, params: &InitializeParams) {
+        let mut params = params.clone();
+        let general = params.capabilities.general.get_or_insert_with(Default::default);
+        let editor = PositionEncoding::negotiate(general.position_encodings.as_deref());
+        let mut offered = vec![editor.kind()];
+        if editor != PositionEncoding::Utf16 {
+            offered.push(PositionEncoding::Utf16.kind());
+        }
+        general.position_encodings = Some(offered);
+        params
+            .capabilities
+            .workspace
+            .get_or_insert_with(Default::default)
+            .did_change_watched_files = Some(DidChangeWatchedFilesClientCapabilities {
+            dynamic_registration: Some(true),
+            relative_pattern_support: Some(true),
+        });
+        *self.client_params.write().await = params;
+    }
+
+    /// Parameters servers are initialized with
+    pub async fn client_params(&self) -> InitializeParams {
+        self.client_params.read().await.clone()
+    }
+
+    /// Forward notifications from the servers to `sender`
+    pub fn with_notifications(mut self, sender: NotificationSender) -> Self {
+        self.notifications = Some(sender);
+        self
+    }
+
     /// Get or spawn a language server for the given language
     pub async fn get_or_spawn(&self, lang: LanguageId) -> Result<Arc<LanguageServerProxy>> {
         // Check if already running
@@ -34,13 +213,238 @@ impl LanguageServerPool {
             return Ok(server.clone());
         }
 
+        // One spawn per language at a time; those waiting for it take its server
+        let spawning = self.spawning.entry(lang.clone()).or_default().clone();
+        let _spawning = spawning.lock().await;
+        if let Some(server) = self.servers.get(&lang) {
+            return Ok(server.clone());
+        }
+
+        let config = self
+            .server_config(&lang)
+            .ok_or_else(|| VRaftError::UnsupportedLanguage(format!("{:?}", lang)))?;
+        let _slot = self.reserve().await?;
+
         // Spawn new server
-        let server = LanguageServerProxy::spawn(lang.clone()).await?;
+        let mut server =
+            LanguageServerProxy::spawn(lang.clone(), &config, self.notifications.clone(), self.settings.clone())
+                .await?
+                .with_timeouts(self.timeouts.clone())
+                .with_scheduler(RequestScheduler::new(
+                    self.limits.max_concurrent_requests,
+                    self.limits.max_background_queue,
+                ));
+        if let Some(documents) = &self.documents {
+            server = server.with_documents(documents.clone());
+        }
+        if let Some(metrics) = &self.metrics {
+            server = server.with_metrics(metrics.clone());
+        }
+        let mut params = self.client_params.read().await.clone();
+        if let Some(options) = config.initialization_options {
+            params.initialization_options = Some(options);
+        }
+        if let Err(e) = server.initialize(params).await {
+            server.shutdown().await;
+            return Err(e);
+        }
         let server = Arc::new(server);
-        self.servers.insert(lang, server.clone());
+        self.servers.insert(lang.clone(), server.clone());
+
+        // Let the editor know the server's commands and providers, as if the
+        // server had registered them itself
+        let mut registrations = server
+            .raw_capabilities()
+            .map(|caps| provider_registrations(&lang, caps))
+            .unwrap_or_default();
+        let commands = server.commands();
+        if !commands.is_empty() {
+            registrations.push(Registration {
+                id: "workspace/executeCommand".to_string(),
+                method: "workspace/executeCommand".to_string(),
+                register_options: Some(serde_json::json!({ "commands": commands })),
+            });
+        }
+        if let (Some(notifications), false) = (&self.notifications, registrations.is_empty()) {
+            let _ = notifications.send(ServerNotification {
+                language: lang.clone(),
+                method: "client/registerCapability".to_string(),
+                params: serde_json::json!({ "registrations": registrations }),
+                reply: None,
+            });
+        }
         Ok(server)
     }
 
+    /// Take a slot under `max_servers` for a new server, making room by
+    /// shutting down the least recently used idle server
+    async fn reserve(&self) -> Result<Reservation<'_>> {
+        loop {
+            let idle = {
+                let mut reserved = self.reserved.lock().unwrap();
+                let running = self.running();
+                if running.len() + *reserved < self.limits.max_servers {
+                    *reserved += 1;
+                    return Ok(Reservation(&self.reserved));
+                }
+                let idle = running
+                    .into_iter()
+                    .filter(|server| server.open_documents() == 0)
+                    .min_by_key(|server| server.last_used());
+                match idle {
+                    Some(server) => server,
+                    None => {
+                        return Err(VRaftError::LanguageServer(format!(
+                            "{} language servers running or starting, none idle",
+                            self.servers.len() + *reserved
+                        )))
+                    }
+                }
+            };
+            self.stop(&idle, "server limit reached").await;
+        }
+    }
+
+    /// Running server that provides a command
+    pub fn server_for_command(&self, command: &str) -> Option<Arc<LanguageServerProxy>> {
+        self.servers
+            .iter()
+            .find(|e| e.value().commands().iter().any(|c| c == command))
+            .map(|e| e.value().clone())
+    }
+
+    /// Ask every running server for workspace symbols matching `query`
+    pub async fn collect_workspace_symbols(
+        &self,
+        query: &str,
+        aggregator: &mut ResponseAggregator<SymbolInformation>,
+    ) {
+        for server in self.running() {
+            let params = WorkspaceSymbolParams {
+                query: query.to_string(),
+                ..Default::default()
+            };
+            match server.symbol(params).await {
+                Ok(response) => {
+                    for symbol in response.map(flatten_symbols).unwrap_or_default() {
+                        aggregator.add_response(symbol);
+                    }
+                }
+                Err(e) => aggregator.add_error(format!("{:?}: {}", server.language(), e)),
+            }
+        }
+    }
+
+    /// Running servers that report the diagnostics of the whole workspace
+    ///
+    /// Diagnostics of the other servers' files are only known from what
+    /// they pushed.
+    pub fn workspace_diagnostic_servers(&self) -> Vec<Arc<LanguageServerProxy>> {
+        self.running()
+            .into_iter()
+            .filter(|server| server.supports("workspace/diagnostic"))
+            .collect()
+    }
+
+    /// Ask every running server for the edits a file rename needs
+    ///
+    /// Servers that fail are skipped, so the rename still updates the
+    /// imports the others know about.
+    pub async fn will_rename_files(&self, params: &RenameFilesParams) -> Vec<WorkspaceEdit> {
+        let mut edits = Vec::new();
+        for server in self.running() {
+            match server.will_rename_files(params.clone()).await {
+                Ok(Some(edit)) => edits.push(edit),
+                Ok(None) => {}
+                Err(e) => tracing::warn!("willRenameFiles failed on {:?}: {}", server.language(), e),
+            }
+        }
+        edits
+    }
+
+    /// Tell every running server about renamed files
+    pub async fn did_rename_files(&self, params: &RenameFilesParams) {
+        for server in self.running() {
+            server.did_rename_files(params.clone()).await;
+        }
+    }
+
+    /// Tell every running server about deleted files
+    pub async fn did_delete_files(&self, params: &DeleteFilesParams) {
+        for server in self.running() {
+            server.did_delete_files(params.clone()).await;
+        }
+    }
+
+    /// Tell a running server about changes to files it watches
+    pub async fn did_change_watched_files(&self, language: &LanguageId, params: DidChangeWatchedFilesParams) {
+        let server = self.servers.get(language).map(|e| e.value().clone());
+        if let Some(server) = server {
+            server.did_change_watched_files(params).await;
+        }
+    }
+
+    /// Running servers, cloned out so no map lock is held across awaits
+    pub(crate) fn running(&self) -> Vec<Arc<LanguageServerProxy>> {
+        self.servers.iter().map(|e| e.value().clone()).collect()
+    }
+
+    /// Shut down idle servers every `check_interval` until the pool is dropped
+    pub async fn run_eviction(pool: Weak<Self>) {
+        let Some(interval) = pool.upgrade().map(|pool| pool.limits.check_interval) else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let Some(pool) = pool.upgrade() else {
+                return;
+            };
+            pool.evict_idle().await;
+        }
+    }
+
+    /// Shut down servers unused past the idle timeout, then idle servers
+    /// while all of them together use more memory than allowed
+    ///
+    /// Servers with open documents are kept, whatever memory they use.
+    pub async fn evict_idle(&self) {
+        for server in self.running() {
+            if server.open_documents() == 0 && server.last_used().elapsed() >= self.limits.idle_timeout {
+                self.stop(&server, "idle").await;
+            }
+        }
+
+        let Some(max_memory) = self.limits.max_memory_bytes else {
+            return;
+        };
+        let mut servers: Vec<_> = self
+            .running()
+            .into_iter()
+            .filter_map(|server| server.memory_usage().map(|memory| (server, memory)))
+            .collect();
+        let mut total: u64 = servers.iter().map(|(_, memory)| memory).sum();
+        servers.sort_by_key(|(server, _)| server.last_used());
+        for (server, memory) in servers {
+            if total <= max_memory {
+                break;
+            }
+            if server.open_documents() == 0 {
+                self.stop(&server, "memory limit reached").await;
+                total -= memory;
+            }
+        }
+    }
+
+    /// Shut a server down; the next request for its language spawns a new one
+    async fn stop(&self, server: &Arc<LanguageServerProxy>, reason: &str) {
+        tracing::info!("Shutting down {:?} language server: {}", server.language(), reason);
+        self.servers
+            .remove_if(server.language(), |_, running| Arc::ptr_eq(running, server));
+        server.shutdown().await;
+    }
+
     /// Shutdown all language servers
     pub async fn shutdown_all(&self) {
         for entry in self.servers.iter() {
@@ -50,15 +454,164 @@ impl LanguageServerPool {
     }
 }
 
+/// A slot under the pool's `max_servers`, given back when dropped
+struct Reservation<'a>(&'a StdMutex<usize>);
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() -= 1;
+    }
+}
+
 impl Default for LanguageServerPool {
     fn default() -> Self {
         Self::new()
     }
 }
 
+/// File operations a server wants to hear about
+fn file_operations(caps: &ServerCapabilities) -> Option<&WorkspaceFileOperationsServerCapabilities> {
+    caps.workspace.as_ref()?.file_operations.as_ref()
+}
+
+/// Whether a `bool`-or-options capability is enabled
+fn enabled<T>(provider: Option<&OneOf<bool, T>>) -> bool {
+    provider.is_some_and(|p| !matches!(p, OneOf::Left(false)))
+}
+
 /// Pending request map type
 type PendingRequests = Arc<DashMap<i64, oneshot::Sender<Value>>>;
 
+/// Editor settings shared with the servers' reader tasks
+pub type SharedSettings = Arc<StdRwLock<Value>>;
+
+/// Stdin of a server, shared with tasks that write after the caller is gone
+type SharedStdin = Arc<Mutex<Option<ChildStdin>>>;
+
+/// Write a message to a server
+async fn write_message(stdin: &SharedStdin, message: &Value) {
+    let content = message.to_string();
+    let message = format!("Content-Length: {}\r\n\r\n{}", content.len(), content);
+    if let Some(ref mut stdin) = *stdin.lock().await {
+        let _ = stdin.write_all(message.as_bytes()).await;
+    }
+}
+
+/// Response to a request from a server
+fn reply_message(id: &Value, result: JsonRpcResult<Value>) -> Value {
+    match result {
+        Ok(result) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": result,
+        }),
+        Err(error) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": error,
+        }),
+    }
+}
+
+/// Result the gateway answers a request from a server with
+///
+/// The gateway acts as the client of every server. Registrations are
+/// accepted here and passed on to the editors once the notification is
+/// forwarded; configuration is answered from the stored editor settings.
+fn server_request_result(
+    method: &str,
+    params: &Value,
+    settings: &Value,
+) -> std::result::Result<Value, tower_lsp::jsonrpc::Error> {
+    match method {
+        "client/registerCapability" | "client/unregisterCapability" => Ok(Value::Null),
+        "workspace/configuration" => {
+            let params: ConfigurationParams = serde_json::from_value(params.clone())
+                .map_err(|e| tower_lsp::jsonrpc::Error::invalid_params(e.to_string()))?;
+            Ok(params
+                .items
+                .iter()
+                .map(|item| match &item.section {
+                    Some(section) => settings_section(settings, section),
+                    None => settings.clone(),
+                })
+                .collect())
+        }
+        _ => Err(tower_lsp::jsonrpc::Error::method_not_found()),
+    }
+}
+
+/// Value of a dotted settings section, e.g. `rust-analyzer.cargo`
+fn settings_section(settings: &Value, section: &str) -> Value {
+    // Editors send either nested objects or flat dotted keys
+    if let Some(value) = settings.get(section) {
+        return value.clone();
+    }
+    section
+        .split('.')
+        .try_fold(settings, |value, key| value.get(key))
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+/// The sections of the editor settings a language's server reads
+fn language_settings(settings: &Value, language: &LanguageId) -> Value {
+    let sections: serde_json::Map<_, _> = language
+        .settings_sections()
+        .iter()
+        .filter_map(|section| Some((section.to_string(), settings.get(*section)?.clone())))
+        .collect();
+    Value::Object(sections)
+}
+
+/// A request sent to a server; cancels it there if dropped before the response
+struct InFlight<'a> {
+    proxy: &'a LanguageServerProxy,
+    id: i64,
+    done: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.proxy.cancel(self.id);
+        }
+    }
+}
+
+/// A notification, or a request, sent by a language server
+#[derive(Debug)]
+pub struct ServerNotification {
+    /// Language of the server that sent it
+    pub language: LanguageId,
+    pub method: String,
+    pub params: Value,
+
+    /// Where to answer a request only the gateway can answer; `None` for
+    /// notifications and for requests the proxy answered itself
+    pub reply: Option<ServerReply>,
+}
+
+/// Answer to a request from a language server
+pub type ServerReply = oneshot::Sender<JsonRpcResult<Value>>;
+
+/// Requests from servers that the gateway answers, with the editor's help
+const GATEWAY_REQUESTS: &[&str] = &[
+    "workspace/applyEdit",
+    "window/showMessageRequest",
+    "window/showDocument",
+    "window/workDoneProgress/create",
+];
+
+/// LSP `RequestFailed` error code, answered for requests that time out
+const REQUEST_FAILED: i64 = -32803;
+
+/// LSP `ServerCancelled` error code, answered for requests shed under load
+const SERVER_CANCELLED: i64 = -32802;
+
+/// Sender for notifications from language servers
+pub type NotificationSender = mpsc::UnboundedSender<ServerNotification>;
+
 /// Proxy to a language server process
 pub struct LanguageServerProxy {
     /// Language ID
@@ -68,7 +621,7 @@ pub struct LanguageServerProxy {
     process: Mutex<Option<Child>>,
 
     /// Stdin writer
-    stdin: Mutex<Option<ChildStdin>>,
+    stdin: SharedStdin,
 
     /// Pending requests waiting for response
     pending: PendingRequests,
@@ -76,21 +629,62 @@ pub struct LanguageServerProxy {
     /// Next request ID
     next_id: AtomicI64,
 
-    /// Is the server initialized
-    initialized: RwLock<bool>,
+    /// Capabilities the server announced; set once initialized
+    capabilities: OnceLock<ServerCapabilities>,
+
+    /// The same, as the server sent them, with those lsp-types does not know
+    raw_capabilities: OnceLock<Value>,
+
+    /// Translation of the server's semantic token legend to the gateway's
+    legend_map: OnceLock<LegendMap>,
+
+    /// Last semantic tokens of each document, as the server encoded them
+    semantic_tokens: DashMap<Url, SemanticTokens>,
+
+    /// Process ID, to read the server's memory use
+    pid: Option<u32>,
+
+    /// Documents open on the server, with the text the server was sent
+    open_documents: DashMap<Url, String>,
+
+    /// When the server was last sent a request or notification
+    last_used: StdMutex<Instant>,
+
+    /// Time the server gets to answer each kind of request
+    timeouts: RequestTimeouts,
+
+    /// Text of the documents not open on the server, to convert positions
+    /// if the server does not use UTF-16
+    documents: Option<Arc<dyn DocumentSource>>,
+
+    /// Where requests to the server are recorded
+    metrics: Option<Arc<LspMetrics>>,
+
+    /// Admits requests to the server, most urgent first
+    scheduler: Arc<RequestScheduler>,
+
+    /// Document last opened or changed, whose interactive requests go first
+    focused: StdMutex<Option<Url>>,
 }
 
 impl LanguageServerProxy {
     /// Spawn a new language server process
-    pub async fn spawn(lang: LanguageId) -> Result<Self> {
-        let cmd = lang
-            .language_server_command()
-            .ok_or_else(|| VRaftError::UnsupportedLanguage(format!("{:?}", lang)))?;
+    ///
+    /// Notifications from the server are sent to `notifications`, or dropped
+    /// if there is none.
+    pub async fn spawn(
+        lang: LanguageId,
+        config: &LanguageServerConfig,
+        notifications: Option<NotificationSender>,
+        settings: SharedSettings,
+    ) -> Result<Self> {
+        let cmd = &config.command;
 
         tracing::info!("Spawning language server: {} for {:?}", cmd, lang);
 
         let mut child = Command::new(cmd)
-            .arg("--stdio")
+            .args(&config.args)
+            .envs(&config.env)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
@@ -99,29 +693,359 @@ impl LanguageServerProxy {
 
         let stdin = child.stdin.take();
         let stdout = child.stdout.take();
+        let pid = child.id();
 
         let proxy = Self {
             language: lang,
             process: Mutex::new(Some(child)),
-            stdin: Mutex::new(stdin),
+            stdin: Arc::new(Mutex::new(stdin)),
             pending: Arc::new(DashMap::new()),
             next_id: AtomicI64::new(1),
-            initialized: RwLock::new(false),
+            capabilities: OnceLock::new(),
+            raw_capabilities: OnceLock::new(),
+            legend_map: OnceLock::new(),
+            semantic_tokens: DashMap::new(),
+            pid,
+            timeouts: RequestTimeouts::default(),
+            documents: None,
+            metrics: None,
+            scheduler: Arc::new(RequestScheduler::new(usize::MAX, usize::MAX)),
+            focused: StdMutex::new(None),
+            open_documents: DashMap::new(),
+            last_used: StdMutex::new(Instant::now()),
         };
 
         // Start response reader task
         if let Some(stdout) = stdout {
             let pending = proxy.pending.clone();
+            let stdin = proxy.stdin.clone();
+            let language = proxy.language.clone();
             tokio::spawn(async move {
-                Self::read_responses(stdout, pending).await;
+                Self::read_responses(stdout, stdin, pending, language, notifications, settings).await;
             });
         }
 
         Ok(proxy)
     }
 
+    /// Give the server these times to answer requests
+    pub fn with_timeouts(mut self, timeouts: RequestTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Convert positions with the text of `documents` if the server does
+    /// not use UTF-16
+    pub fn with_documents(mut self, documents: Arc<dyn DocumentSource>) -> Self {
+        self.documents = Some(documents);
+        self
+    }
+
+    /// Record the requests to the server in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<LspMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Admit requests to the server through `scheduler`
+    pub fn with_scheduler(mut self, scheduler: RequestScheduler) -> Self {
+        self.scheduler = Arc::new(scheduler);
+        self
+    }
+
+    /// Requests the server works on, and requests waiting for it
+    pub fn load(&self) -> (usize, usize) {
+        self.scheduler.load()
+    }
+
+    /// Whether the server is working on as many requests as it may
+    pub fn is_busy(&self) -> bool {
+        self.scheduler.busy()
+    }
+
+    /// Priority of a request; interactive only in the focused document
+    fn priority(&self, method: &str, params: &Value) -> RequestPriority {
+        let priority = RequestPriority::of(method);
+        if priority != RequestPriority::Interactive {
+            return priority;
+        }
+        let focused = self.focused.lock().unwrap();
+        match (focused.as_ref(), request_uri(params)) {
+            (Some(focused), Some(uri)) if *focused != uri => RequestPriority::Normal,
+            _ => priority,
+        }
+    }
+
+    fn focus(&self, uri: &Url) {
+        *self.focused.lock().unwrap() = Some(uri.clone());
+    }
+
+    /// Encoding of the positions the server sends and expects
+    pub fn position_encoding(&self) -> PositionEncoding {
+        self.capabilities
+            .get()
+            .and_then(|caps| caps.position_encoding.as_ref())
+            .and_then(PositionEncoding::from_kind)
+            .unwrap_or_default()
+    }
+
+    /// Converter of a message's positions to the server's encoding, or back
+    ///
+    /// Open documents are converted against the text the server has, which
+    /// is behind the VFS while newer changes are on their way, and other
+    /// documents against the VFS. `None` if the server uses the gateway's
+    /// encoding.
+    fn position_converter(&self, to_server: bool) -> Option<PositionConverter<'_>> {
+        let encoding = self.position_encoding();
+        if encoding == PositionEncoding::Utf16 {
+            return None;
+        }
+        let (from, to) = if to_server {
+            (PositionEncoding::Utf16, encoding)
+        } else {
+            (encoding, PositionEncoding::Utf16)
+        };
+        Some(PositionConverter::new(from, to, self))
+    }
+
+    /// Convert the positions in a message from the server to the gateway's
+    /// encoding
+    pub fn convert_from_server(&self, message: &mut Value) {
+        if let Some(mut converter) = self.position_converter(false) {
+            converter.convert(message, None);
+        }
+    }
+
+    /// Get the language this server handles
+    pub fn language(&self) -> &LanguageId {
+        &self.language
+    }
+
+    /// Number of documents open on the server
+    pub fn open_documents(&self) -> usize {
+        self.open_documents.len()
+    }
+
+    /// Whether a document is open on the server
+    pub fn is_open(&self, uri: &Url) -> bool {
+        self.open_documents.contains_key(uri)
+    }
+
+    /// When the server was last sent a request or notification
+    pub fn last_used(&self) -> Instant {
+        *self.last_used.lock().unwrap()
+    }
+
+    fn touch(&self) {
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+
+    /// Resident memory of the server process, in bytes
+    ///
+    /// Read from `/proc`, so `None` where there is none.
+    pub fn memory_usage(&self) -> Option<u64> {
+        let status = std::fs::read_to_string(format!("/proc/{}/status", self.pid?)).ok()?;
+        let kib: u64 = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmRSS:"))?
+            .trim()
+            .trim_end_matches("kB")
+            .trim()
+            .parse()
+            .ok()?;
+        Some(kib * 1024)
+    }
+
+    /// Run the initialize handshake on behalf of the editor
+    ///
+    /// The server gets the editor's workspace and capabilities, so it
+    /// answers in the form the editor expects.
+    pub async fn initialize(&self, client: InitializeParams) -> Result<()> {
+        let params = InitializeParams {
+            process_id: Some(std::process::id()),
+            root_uri: client.root_uri,
+            initialization_options: client.initialization_options,
+            capabilities: client.capabilities,
+            workspace_folders: client.workspace_folders,
+            client_info: Some(ClientInfo {
+                name: "vraftls".to_string(),
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            }),
+            ..Default::default()
+        };
+
+        let mut result: Value = self.request("initialize", params).await.map_err(|e| {
+            VRaftError::LanguageServer(format!("initialize failed for {:?}: {}", self.language, e))
+        })?;
+        let raw_capabilities = result["capabilities"].take();
+        let result = InitializeResult {
+            capabilities: serde_json::from_value(raw_capabilities.clone())
+                .map_err(|e| VRaftError::LanguageServer(format!("invalid capabilities of {:?}: {}", self.language, e)))?,
+            server_info: None,
+        };
+        let _ = self.raw_capabilities.set(raw_capabilities);
+        if let Some(options) = semantic_tokens_options(&result.capabilities) {
+            let _ = self.legend_map.set(LegendMap::new(&options.legend, &gateway_legend()));
+        }
+        let _ = self.capabilities.set(result.capabilities);
+        self.notify("initialized", InitializedParams {}).await;
+
+        tracing::info!("Language server for {:?} initialized", self.language);
+        Ok(())
+    }
+
+    /// Capabilities the server announced, once initialized
+    pub fn capabilities(&self) -> Option<&ServerCapabilities> {
+        self.capabilities.get()
+    }
+
+    /// Capabilities as the server sent them, once initialized
+    pub fn raw_capabilities(&self) -> Option<&Value> {
+        self.raw_capabilities.get()
+    }
+
+    /// Commands the server executes
+    pub fn commands(&self) -> Vec<String> {
+        self.capabilities
+            .get()
+            .and_then(|caps| caps.execute_command_provider.as_ref())
+            .map(|options| options.commands.clone())
+            .unwrap_or_default()
+    }
+
+    /// Whether the server handles a request method
+    pub fn supports(&self, method: &str) -> bool {
+        let Some(caps) = self.capabilities.get() else {
+            return false;
+        };
+        match method {
+            "textDocument/completion" => caps.completion_provider.is_some(),
+            "completionItem/resolve" => caps
+                .completion_provider
+                .as_ref()
+                .is_some_and(|p| p.resolve_provider == Some(true)),
+            "textDocument/hover" => caps
+                .hover_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, HoverProviderCapability::Simple(false))),
+            "textDocument/definition" => enabled(caps.definition_provider.as_ref()),
+            "textDocument/declaration" => caps
+                .declaration_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, DeclarationCapability::Simple(false))),
+            "textDocument/typeDefinition" => caps
+                .type_definition_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, TypeDefinitionProviderCapability::Simple(false))),
+            "textDocument/implementation" => caps
+                .implementation_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, ImplementationProviderCapability::Simple(false))),
+            "textDocument/references" => enabled(caps.references_provider.as_ref()),
+            "workspace/symbol" => enabled(caps.workspace_symbol_provider.as_ref()),
+            "textDocument/documentSymbol" => enabled(caps.document_symbol_provider.as_ref()),
+            "textDocument/formatting" => enabled(caps.document_formatting_provider.as_ref()),
+            "textDocument/rename" => enabled(caps.rename_provider.as_ref()),
+            "textDocument/prepareRename" => matches!(
+                caps.rename_provider,
+                Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    ..
+                }))
+            ),
+            "textDocument/signatureHelp" => caps.signature_help_provider.is_some(),
+            "textDocument/documentHighlight" => enabled(caps.document_highlight_provider.as_ref()),
+            "textDocument/rangeFormatting" => enabled(caps.document_range_formatting_provider.as_ref()),
+            "textDocument/onTypeFormatting" => caps.document_on_type_formatting_provider.is_some(),
+            "textDocument/codeLens" => caps.code_lens_provider.is_some(),
+            "textDocument/documentColor" | "textDocument/colorPresentation" => caps
+                .color_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, ColorProviderCapability::Simple(false))),
+            "textDocument/linkedEditingRange" => caps
+                .linked_editing_range_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, LinkedEditingRangeServerCapabilities::Simple(false))),
+            "textDocument/prepareCallHierarchy" => caps
+                .call_hierarchy_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, CallHierarchyServerCapability::Simple(false))),
+            "textDocument/codeAction" => caps
+                .code_action_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, CodeActionProviderCapability::Simple(false))),
+            "textDocument/foldingRange" => caps
+                .folding_range_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, FoldingRangeProviderCapability::Simple(false))),
+            "textDocument/selectionRange" => caps
+                .selection_range_provider
+                .as_ref()
+                .is_some_and(|p| !matches!(p, SelectionRangeProviderCapability::Simple(false))),
+            "textDocument/documentLink" => caps.document_link_provider.is_some(),
+            "documentLink/resolve" => caps
+                .document_link_provider
+                .as_ref()
+                .is_some_and(|p| p.resolve_provider == Some(true)),
+            "textDocument/inlayHint" => enabled(caps.inlay_hint_provider.as_ref()),
+            "textDocument/inlineValue" => enabled(caps.inline_value_provider.as_ref()),
+            "textDocument/inlineCompletion" => self
+                .raw_capabilities
+                .get()
+                .is_some_and(|caps| matches!(caps["inlineCompletionProvider"], Value::Bool(true) | Value::Object(_))),
+            "inlayHint/resolve" => match caps.inlay_hint_provider.as_ref() {
+                Some(OneOf::Right(InlayHintServerCapabilities::Options(o))) => o.resolve_provider == Some(true),
+                Some(OneOf::Right(InlayHintServerCapabilities::RegistrationOptions(o))) => {
+                    o.inlay_hint_options.resolve_provider == Some(true)
+                }
+                _ => false,
+            },
+            "textDocument/semanticTokens/full" => semantic_tokens_options(caps)
+                .is_some_and(|o| !matches!(o.full, None | Some(SemanticTokensFullOptions::Bool(false)))),
+            "textDocument/semanticTokens/full/delta" => semantic_tokens_options(caps)
+                .is_some_and(|o| matches!(o.full, Some(SemanticTokensFullOptions::Delta { delta: Some(true) }))),
+            "textDocument/semanticTokens/range" => {
+                semantic_tokens_options(caps).is_some_and(|o| o.range == Some(true))
+            }
+            "textDocument/diagnostic" => caps.diagnostic_provider.is_some(),
+            "workspace/diagnostic" => match caps.diagnostic_provider.as_ref() {
+                Some(DiagnosticServerCapabilities::Options(o)) => o.workspace_diagnostics,
+                Some(DiagnosticServerCapabilities::RegistrationOptions(o)) => o.diagnostic_options.workspace_diagnostics,
+                None => false,
+            },
+            "workspace/willRenameFiles" => file_operations(caps).is_some_and(|o| o.will_rename.is_some()),
+            "workspace/didRenameFiles" => file_operations(caps).is_some_and(|o| o.did_rename.is_some()),
+            "workspace/didDeleteFiles" => file_operations(caps).is_some_and(|o| o.did_delete.is_some()),
+            _ => true,
+        }
+    }
+
+    /// Remember a document's tokens and translate them for the editor
+    fn store_semantic_tokens(&self, uri: Url, tokens: SemanticTokens) -> SemanticTokens {
+        let translated = SemanticTokens {
+            result_id: tokens.result_id.clone(),
+            data: self.translate_semantic_tokens(&tokens.data),
+        };
+        self.semantic_tokens.insert(uri, tokens);
+        translated
+    }
+
+    fn translate_semantic_tokens(&self, tokens: &[SemanticToken]) -> Vec<SemanticToken> {
+        match self.legend_map.get() {
+            Some(map) => map.translate(tokens),
+            None => tokens.to_vec(),
+        }
+    }
+
     /// Read responses from the language server
-    async fn read_responses(stdout: ChildStdout, pending: PendingRequests) {
+    async fn read_responses(
+        stdout: ChildStdout,
+        stdin: SharedStdin,
+        pending: PendingRequests,
+        language: LanguageId,
+        notifications: Option<NotificationSender>,
+        settings: SharedSettings,
+    ) {
         let mut reader = BufReader::new(stdout);
         let mut headers = String::new();
 
@@ -159,14 +1083,64 @@ impl LanguageServerProxy {
                 }
 
                 if let Ok(json) = serde_json::from_slice::<Value>(&content) {
-                    // Check if it's a response (has id)
-                    if let Some(id) = json.get("id").and_then(|v| v.as_i64()) {
-                        if let Some((_, sender)) = pending.remove(&id) {
-                            let _ = sender.send(json);
+                    let method = json.get("method").and_then(|v| v.as_str());
+                    let params = json.get("params").cloned().unwrap_or(Value::Null);
+                    match (json.get("id"), method) {
+                        // It's a request from the server (has id and method)
+                        (Some(id), Some(method)) => {
+                            tracing::debug!("Received server request: {}", method);
+                            if GATEWAY_REQUESTS.contains(&method) {
+                                // Answered by the gateway, without holding up
+                                // the responses to our own requests
+                                let (tx, rx) = oneshot::channel();
+                                if let Some(ref notifications) = notifications {
+                                    let _ = notifications.send(ServerNotification {
+                                        language: language.clone(),
+                                        method: method.to_string(),
+                                        params,
+                                        reply: Some(tx),
+                                    });
+                                }
+                                let (id, stdin) = (id.clone(), stdin.clone());
+                                tokio::spawn(async move {
+                                    let result = rx
+                                        .await
+                                        .unwrap_or_else(|_| Err(tower_lsp::jsonrpc::Error::method_not_found()));
+                                    write_message(&stdin, &reply_message(&id, result)).await;
+                                });
+                            } else {
+                                let settings = settings.read().unwrap().clone();
+                                let result = server_request_result(method, &params, &settings);
+                                write_message(&stdin, &reply_message(id, result)).await;
+                                if let Some(ref notifications) = notifications {
+                                    let _ = notifications.send(ServerNotification {
+                                        language: language.clone(),
+                                        method: method.to_string(),
+                                        params,
+                                        reply: None,
+                                    });
+                                }
+                            }
+                        }
+                        // It's a response (has id)
+                        (Some(id), None) => {
+                            if let Some((_, sender)) = id.as_i64().and_then(|id| pending.remove(&id)) {
+                                let _ = sender.send(json);
+                            }
                         }
-                    } else {
                         // It's a notification
-                        tracing::debug!("Received notification: {:?}", json.get("method"));
+                        (None, Some(method)) => {
+                            tracing::debug!("Received notification: {}", method);
+                            if let Some(ref notifications) = notifications {
+                                let _ = notifications.send(ServerNotification {
+                                    language: language.clone(),
+                                    method: method.to_string(),
+                                    params,
+                                    reply: None,
+                                });
+                            }
+                        }
+                        (None, None) => {}
                     }
                 }
             }
@@ -175,11 +1149,44 @@ impl LanguageServerProxy {
 
     /// Send a request and wait for response
     async fn request<P, R>(&self, method: &str, params: P) -> JsonRpcResult<R>
+    where
+        P: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        let start = Instant::now();
+        let params = serde_json::to_value(params).map_err(|_| tower_lsp::jsonrpc::Error::internal_error())?;
+        let priority = self.priority(method, &params);
+        let result = match self.scheduler.acquire(priority).await {
+            Some(_permit) => self.send_request(method, params).await,
+            None => {
+                tracing::debug!("{:?} server busy, shedding {}", self.language, method);
+                Err(tower_lsp::jsonrpc::Error {
+                    code: tower_lsp::jsonrpc::ErrorCode::ServerError(SERVER_CANCELLED),
+                    message: format!("{} shed: server busy", method).into(),
+                    data: None,
+                })
+            }
+        };
+        if let Some(metrics) = &self.metrics {
+            metrics.record(method, Some(&self.language), start.elapsed(), result.is_err());
+        }
+        result
+    }
+
+    /// Send a request and wait for the server's answer
+    async fn send_request<P, R>(&self, method: &str, params: P) -> JsonRpcResult<R>
     where
         P: Serialize,
         R: for<'de> Deserialize<'de>,
     {
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.touch();
+
+        let mut params = serde_json::to_value(params).map_err(|_| tower_lsp::jsonrpc::Error::internal_error())?;
+        let uri = request_uri(&params);
+        if let Some(mut converter) = self.position_converter(true) {
+            converter.convert(&mut params, None);
+        }
 
         let request = serde_json::json!({
             "jsonrpc": "2.0",
@@ -189,7 +1196,7 @@ impl LanguageServerProxy {
         });
 
         let content = serde_json::to_string(&request)
-            .map_err(|e| tower_lsp::jsonrpc::Error::internal_error())?;
+            .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())?;
 
         let message = format!("Content-Length: {}\r\n\r\n{}", content.len(), content);
 
@@ -208,27 +1215,115 @@ impl LanguageServerProxy {
             }
         }
 
-        // Wait for response
-        match tokio::time::timeout(std::time::Duration::from_secs(30), rx).await {
-            Ok(Ok(response)) => {
-                if let Some(result) = response.get("result") {
-                    serde_json::from_value(result.clone())
-                        .map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
-                } else if let Some(error) = response.get("error") {
-                    Err(tower_lsp::jsonrpc::Error::internal_error())
-                } else {
-                    Err(tower_lsp::jsonrpc::Error::internal_error())
-                }
+        // tower-lsp drops this future when the editor cancels its request;
+        // the guard then cancels the request on the server as well
+        let mut in_flight = InFlight {
+            proxy: self,
+            id,
+            done: false,
+        };
+
+        // Wait for response; a request that times out is cancelled on the
+        // server as the guard drops
+        let timeout = self.timeouts.for_method(method);
+        let response = match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => return Err(tower_lsp::jsonrpc::Error::internal_error()),
+            Err(_) => {
+                tracing::warn!("{:?} server did not answer {} within {:?}", self.language, method, timeout);
+                return Err(tower_lsp::jsonrpc::Error {
+                    code: tower_lsp::jsonrpc::ErrorCode::ServerError(REQUEST_FAILED),
+                    message: format!("{} timed out after {:?}", method, timeout).into(),
+                    data: Some(serde_json::json!({ "timeout_ms": timeout.as_millis() as u64 })),
+                });
             }
-            _ => {
-                self.pending.remove(&id);
-                Err(tower_lsp::jsonrpc::Error::internal_error())
+        };
+        in_flight.done = true;
+
+        if let Some(result) = response.get("result") {
+            let mut result = result.clone();
+            if let Some(mut converter) = self.position_converter(false) {
+                converter.convert(&mut result, uri.as_ref());
             }
+            serde_json::from_value(result).map_err(|_| tower_lsp::jsonrpc::Error::internal_error())
+        } else if let Some(error) = response.get("error") {
+            Err(serde_json::from_value(error.clone())
+                .unwrap_or_else(|_| tower_lsp::jsonrpc::Error::internal_error()))
+        } else {
+            Err(tower_lsp::jsonrpc::Error::internal_error())
         }
     }
 
+    /// Cancel a request still waiting for the server
+    ///
+    /// The waiter is answered with a RequestCancelled error and the server
+    /// is sent `$/cancelRequest` so it can stop working on it.
+    pub fn cancel(&self, id: i64) {
+        let Some((_, sender)) = self.pending.remove(&id) else {
+            return;
+        };
+        let _ = sender.send(serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": tower_lsp::jsonrpc::Error::request_cancelled(),
+        }));
+
+        // Ids in `$/cancelRequest` are LSP integers; a request numbered past
+        // them is only given up here
+        let Ok(number) = i32::try_from(id) else {
+            tracing::warn!("Request {} on {:?} server cannot be cancelled by id", id, self.language);
+            return;
+        };
+        let notification = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "$/cancelRequest",
+            "params": CancelParams {
+                id: NumberOrString::Number(number),
+            },
+        });
+        let stdin = self.stdin.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { write_message(&stdin, &notification).await });
+        }
+        tracing::debug!("Cancelled request {} on {:?} server", id, self.language);
+    }
+
+    /// Send a request the server announced support for
+    ///
+    /// Requests the server does not handle answer `None` without a round trip.
+    async fn request_supported<P, R>(&self, method: &str, params: P) -> JsonRpcResult<Option<R>>
+    where
+        P: Serialize,
+        R: for<'de> Deserialize<'de>,
+    {
+        if !self.supports(method) {
+            tracing::debug!("{:?} server does not support {}", self.language, method);
+            return Ok(None);
+        }
+        self.request(method, params).await
+    }
+
+    /// Send a request received from another node, as raw JSON
+    ///
+    /// Answers `None` when the server does not support the method.
+    pub async fn forward(&self, method: &str, params: Value) -> JsonRpcResult<Option<Value>> {
+        self.request_supported(method, params).await
+    }
+
+    /// Send a notification the gateway does not handle, as raw JSON
+    pub async fn forward_notification(&self, method: &str, params: Value) {
+        self.notify(method, params).await;
+    }
+
     /// Send a notification (no response expected)
     async fn notify<P: Serialize>(&self, method: &str, params: P) {
+        self.touch();
+        let Ok(mut params) = serde_json::to_value(params) else {
+            return;
+        };
+        if let Some(mut converter) = self.position_converter(true) {
+            converter.convert(&mut params, None);
+        }
         let notification = serde_json::json!({
             "jsonrpc": "2.0",
             "method": method,
@@ -263,14 +1358,37 @@ impl LanguageServerProxy {
     // LSP method implementations
 
     pub async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        self.focus(&params.text_document.uri);
+        self.open_documents
+            .insert(params.text_document.uri.clone(), params.text_document.text.clone());
         self.notify("textDocument/didOpen", params).await;
     }
 
-    pub async fn did_change(&self, params: DidChangeTextDocumentParams) {
+    pub async fn did_change(&self, mut params: DidChangeTextDocumentParams) {
+        self.focus(&params.text_document.uri);
+        let Some(mut text) = self.open_documents.get_mut(&params.text_document.uri) else {
+            self.notify("textDocument/didChange", params).await;
+            return;
+        };
+        *text = apply_content_changes(&text, &params.content_changes);
+
+        // The ranges of the changes are in UTF-16, and converting them would
+        // take the text between each change, so a server needing conversion
+        // gets the whole new text instead
+        if self.position_encoding() != PositionEncoding::Utf16 {
+            params.content_changes = vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: text.clone(),
+            }];
+        }
+        drop(text);
         self.notify("textDocument/didChange", params).await;
     }
 
     pub async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        self.semantic_tokens.remove(&params.text_document.uri);
+        self.open_documents.remove(&params.text_document.uri);
         self.notify("textDocument/didClose", params).await;
     }
 
@@ -278,53 +1396,349 @@ impl LanguageServerProxy {
         self.notify("textDocument/didSave", params).await;
     }
 
+    pub async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        self.notify("workspace/didChangeConfiguration", params).await;
+    }
+
     pub async fn completion(
         &self,
         params: CompletionParams,
     ) -> JsonRpcResult<Option<CompletionResponse>> {
-        self.request("textDocument/completion", params).await
+        self.request_supported("textDocument/completion", params).await
+    }
+
+    pub async fn completion_resolve(&self, item: CompletionItem) -> JsonRpcResult<CompletionItem> {
+        if !self.supports("completionItem/resolve") {
+            return Ok(item);
+        }
+        self.request("completionItem/resolve", item).await
     }
 
     pub async fn hover(&self, params: HoverParams) -> JsonRpcResult<Option<Hover>> {
-        self.request("textDocument/hover", params).await
+        self.request_supported("textDocument/hover", params).await
     }
 
     pub async fn goto_definition(
         &self,
         params: GotoDefinitionParams,
     ) -> JsonRpcResult<Option<GotoDefinitionResponse>> {
-        self.request("textDocument/definition", params).await
+        self.request_supported("textDocument/definition", params).await
+    }
+
+    pub async fn goto_declaration(
+        &self,
+        params: GotoDeclarationParams,
+    ) -> JsonRpcResult<Option<GotoDeclarationResponse>> {
+        self.request_supported("textDocument/declaration", params).await
+    }
+
+    pub async fn goto_type_definition(
+        &self,
+        params: GotoTypeDefinitionParams,
+    ) -> JsonRpcResult<Option<GotoTypeDefinitionResponse>> {
+        self.request_supported("textDocument/typeDefinition", params).await
+    }
+
+    pub async fn goto_implementation(
+        &self,
+        params: GotoImplementationParams,
+    ) -> JsonRpcResult<Option<GotoImplementationResponse>> {
+        self.request_supported("textDocument/implementation", params).await
     }
 
     pub async fn references(
         &self,
         params: ReferenceParams,
     ) -> JsonRpcResult<Option<Vec<Location>>> {
-        self.request("textDocument/references", params).await
+        self.request_supported("textDocument/references", params).await
     }
 
     pub async fn document_symbol(
         &self,
         params: DocumentSymbolParams,
     ) -> JsonRpcResult<Option<DocumentSymbolResponse>> {
-        self.request("textDocument/documentSymbol", params).await
+        self.request_supported("textDocument/documentSymbol", params).await
     }
 
     pub async fn formatting(
         &self,
         params: DocumentFormattingParams,
     ) -> JsonRpcResult<Option<Vec<TextEdit>>> {
-        self.request("textDocument/formatting", params).await
+        self.request_supported("textDocument/formatting", params).await
     }
 
     pub async fn rename(&self, params: RenameParams) -> JsonRpcResult<Option<WorkspaceEdit>> {
-        self.request("textDocument/rename", params).await
+        self.request_supported("textDocument/rename", params).await
+    }
+
+    pub async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> JsonRpcResult<Option<PrepareRenameResponse>> {
+        self.request_supported("textDocument/prepareRename", params).await
     }
 
     pub async fn code_action(
         &self,
         params: CodeActionParams,
     ) -> JsonRpcResult<Option<CodeActionResponse>> {
-        self.request("textDocument/codeAction", params).await
+        self.request_supported("textDocument/codeAction", params).await
+    }
+
+    pub async fn semantic_tokens_full(
+        &self,
+        params: SemanticTokensParams,
+    ) -> JsonRpcResult<Option<SemanticTokensResult>> {
+        let uri = params.text_document.uri.clone();
+        let tokens: Option<SemanticTokens> = self
+            .request_supported("textDocument/semanticTokens/full", params)
+            .await?;
+        Ok(tokens.map(|tokens| SemanticTokensResult::Tokens(self.store_semantic_tokens(uri, tokens))))
+    }
+
+    /// Semantic tokens relative to an earlier result
+    ///
+    /// Deltas are only passed through when the server's legend matches the
+    /// gateway's; otherwise they are applied here and the whole translated
+    /// document is returned. Servers without delta support get a full request.
+    pub async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> JsonRpcResult<Option<SemanticTokensFullDeltaResult>> {
+        let uri = params.text_document.uri.clone();
+        let previous = self
+            .semantic_tokens
+            .get(&uri)
+            .filter(|t| t.result_id.as_deref() == Some(params.previous_result_id.as_str()))
+            .map(|t| t.data.clone());
+
+        let Some(previous) = previous.filter(|_| self.supports("textDocument/semanticTokens/full/delta")) else {
+            let full = SemanticTokensParams {
+                work_done_progress_params: params.work_done_progress_params,
+                partial_result_params: params.partial_result_params,
+                text_document: params.text_document,
+            };
+            return Ok(self.semantic_tokens_full(full).await?.map(|result| match result {
+                SemanticTokensResult::Tokens(tokens) => SemanticTokensFullDeltaResult::Tokens(tokens),
+                SemanticTokensResult::Partial(partial) => SemanticTokensFullDeltaResult::Tokens(SemanticTokens {
+                    result_id: None,
+                    data: partial.data,
+                }),
+            }));
+        };
+
+        let result: Option<SemanticTokensFullDeltaResult> =
+            self.request("textDocument/semanticTokens/full/delta", params).await?;
+        let (result_id, edits) = match result {
+            None => return Ok(None),
+            Some(SemanticTokensFullDeltaResult::Tokens(tokens)) => {
+                return Ok(Some(SemanticTokensFullDeltaResult::Tokens(
+                    self.store_semantic_tokens(uri, tokens),
+                )));
+            }
+            Some(SemanticTokensFullDeltaResult::TokensDelta(delta)) => (delta.result_id, delta.edits),
+            Some(SemanticTokensFullDeltaResult::PartialTokensDelta { edits }) => (None, edits),
+        };
+
+        let tokens = SemanticTokens {
+            result_id: result_id.clone(),
+            data: apply_edits(&previous, &edits),
+        };
+        if self.legend_map.get().is_none_or(|map| map.is_identity()) {
+            self.semantic_tokens.insert(uri, tokens);
+            return Ok(Some(SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                result_id,
+                edits,
+            })));
+        }
+        Ok(Some(SemanticTokensFullDeltaResult::Tokens(
+            self.store_semantic_tokens(uri, tokens),
+        )))
+    }
+
+    pub async fn semantic_tokens_range(
+        &self,
+        params: SemanticTokensRangeParams,
+    ) -> JsonRpcResult<Option<SemanticTokensRangeResult>> {
+        let tokens: Option<SemanticTokens> = self
+            .request_supported("textDocument/semanticTokens/range", params)
+            .await?;
+        Ok(tokens.map(|tokens| {
+            SemanticTokensRangeResult::Tokens(SemanticTokens {
+                result_id: tokens.result_id,
+                data: self.translate_semantic_tokens(&tokens.data),
+            })
+        }))
+    }
+
+    pub async fn inlay_hint(&self, params: InlayHintParams) -> JsonRpcResult<Option<Vec<InlayHint>>> {
+        self.request_supported("textDocument/inlayHint", params).await
+    }
+
+    pub async fn inlay_hint_resolve(&self, hint: InlayHint) -> JsonRpcResult<InlayHint> {
+        if !self.supports("inlayHint/resolve") {
+            return Ok(hint);
+        }
+        self.request("inlayHint/resolve", hint).await
+    }
+
+    pub async fn inline_value(&self, params: InlineValueParams) -> JsonRpcResult<Option<Vec<InlineValue>>> {
+        self.request_supported("textDocument/inlineValue", params).await
+    }
+
+    pub async fn folding_range(
+        &self,
+        params: FoldingRangeParams,
+    ) -> JsonRpcResult<Option<Vec<FoldingRange>>> {
+        self.request_supported("textDocument/foldingRange", params).await
+    }
+
+    pub async fn selection_range(
+        &self,
+        params: SelectionRangeParams,
+    ) -> JsonRpcResult<Option<Vec<SelectionRange>>> {
+        self.request_supported("textDocument/selectionRange", params).await
+    }
+
+    pub async fn diagnostic(
+        &self,
+        params: DocumentDiagnosticParams,
+    ) -> JsonRpcResult<Option<DocumentDiagnosticReportResult>> {
+        self.request_supported("textDocument/diagnostic", params).await
+    }
+
+    pub async fn workspace_diagnostic(
+        &self,
+        params: WorkspaceDiagnosticParams,
+    ) -> JsonRpcResult<Option<WorkspaceDiagnosticReportResult>> {
+        self.request_supported("workspace/diagnostic", params).await
+    }
+
+    pub async fn symbol(
+        &self,
+        params: WorkspaceSymbolParams,
+    ) -> JsonRpcResult<Option<WorkspaceSymbolResponse>> {
+        self.request_supported("workspace/symbol", params).await
+    }
+
+    pub async fn document_link(
+        &self,
+        params: DocumentLinkParams,
+    ) -> JsonRpcResult<Option<Vec<DocumentLink>>> {
+        self.request_supported("textDocument/documentLink", params).await
+    }
+
+    pub async fn document_link_resolve(&self, link: DocumentLink) -> JsonRpcResult<DocumentLink> {
+        if !self.supports("documentLink/resolve") {
+            return Ok(link);
+        }
+        self.request("documentLink/resolve", link).await
+    }
+
+    pub async fn execute_command(&self, params: ExecuteCommandParams) -> JsonRpcResult<Option<Value>> {
+        self.request("workspace/executeCommand", params).await
+    }
+
+    pub async fn will_rename_files(&self, params: RenameFilesParams) -> JsonRpcResult<Option<WorkspaceEdit>> {
+        self.request_supported("workspace/willRenameFiles", params).await
+    }
+
+    pub async fn did_rename_files(&self, params: RenameFilesParams) {
+        if self.supports("workspace/didRenameFiles") {
+            self.notify("workspace/didRenameFiles", params).await;
+        }
+    }
+
+    pub async fn did_delete_files(&self, params: DeleteFilesParams) {
+        if self.supports("workspace/didDeleteFiles") {
+            self.notify("workspace/didDeleteFiles", params).await;
+        }
+    }
+
+    pub async fn did_change_watched_files(&self, params: DidChangeWatchedFilesParams) {
+        self.notify("workspace/didChangeWatchedFiles", params).await;
+    }
+}
+
+impl DocumentSource for LanguageServerProxy {
+    fn text(&self, uri: &Url) -> Option<String> {
+        match self.open_documents.get(uri) {
+            Some(text) => Some(text.clone()),
+            None => self.documents.as_ref()?.text(uri),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server that answers `initialize` after a while and ignores the rest
+    fn slow_server() -> LanguageServerConfig {
+        let script = r#"head -c 1 >/dev/null; sleep 0.2
+body='{"jsonrpc":"2.0","id":1,"result":{"capabilities":{}}}'
+printf 'Content-Length: %d\r\n\r\n%s' "${#body}" "$body"
+exec cat >/dev/null"#;
+        LanguageServerConfig {
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            env: BTreeMap::new(),
+            initialization_options: None,
+        }
+    }
+
+    fn limited_pool(max_servers: usize) -> LanguageServerPool {
+        let configs = ["rust", "python"].map(|lang| (lang.to_string(), slow_server()));
+        LanguageServerPool::new()
+            .with_language_servers(configs.into_iter().collect())
+            .with_request_timeouts(RequestTimeouts {
+                methods: BTreeMap::from([("shutdown".to_string(), 50)]),
+                ..Default::default()
+            })
+            .with_limits(LanguageServerLimits {
+                max_servers,
+                ..Default::default()
+            })
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_spawns() {
+        // Callers asking for a language at once share one server
+        let pool = limited_pool(8);
+        let (a, b, c) = tokio::join!(
+            pool.get_or_spawn(LanguageId::Rust),
+            pool.get_or_spawn(LanguageId::Rust),
+            pool.get_or_spawn(LanguageId::Rust),
+        );
+        let (a, b, c) = (a.unwrap(), b.unwrap(), c.unwrap());
+        assert!(Arc::ptr_eq(&a, &b) && Arc::ptr_eq(&a, &c));
+        assert_eq!(pool.running().len(), 1);
+        pool.shutdown_all().await;
+
+        // A server still starting holds its slot under the limit
+        let pool = limited_pool(1);
+        let (rust, python) = tokio::join!(
+            pool.get_or_spawn(LanguageId::Rust),
+            pool.get_or_spawn(LanguageId::Python),
+        );
+        assert!(rust.is_ok() != python.is_ok());
+        assert_eq!(pool.running().len(), 1);
+        pool.shutdown_all().await;
+    }
+
+    #[tokio::test]
+    async fn test_cancel_past_lsp_integers() {
+        let pool = limited_pool(1);
+        let server = pool.get_or_spawn(LanguageId::Rust).await.unwrap();
+
+        // The waiter is answered even though the server cannot be told
+        let id = i64::from(i32::MAX) + 1;
+        let (sender, receiver) = oneshot::channel();
+        server.pending.insert(id, sender);
+        server.cancel(id);
+        let response = receiver.await.unwrap();
+        assert_eq!(response["id"], id);
+        assert_eq!(response["error"]["code"], -32800);
+        pool.shutdown_all().await;
     }
 }
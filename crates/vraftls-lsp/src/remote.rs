@@ -0,0 +1,526 @@
+//! LSP requests served by other nodes
+//!
+//! A document's language server runs next to the replicas of its Raft
+//! group. When the router places a document on another node, the gateway
+//! sends the request to that node's `/lsp` endpoint, with the document as the
+//! editor has it so the node's server answers for the same text. Requests
+//! spanning groups are scattered to one node per group and the answers merged.
+//!
+//! Leaders acknowledge edits of their group's files with the log index they
+//! were committed at. The gateway keeps that index per document and sends
+//! it along with later reads of the document, which a replica only answers
+//! once it has applied the log that far, so an editor reads its own writes
+//! wherever the read is routed.
+//!
+//! Concurrent requests on a document share the copy open on the node's
+//! server through [`RemoteDocuments`], so one finishing does not close the
+//! document under another, and one bringing other text does not change it
+//! under another.
+//!
+//! Edits to a group's files go to the leader's `/lsp/write` endpoint as
+//! [`RemoteWrite`]s: an editor's text of a document, or the steps of a
+//! transaction spanning groups.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tower_lsp::lsp_types::{
+    DidCloseTextDocumentParams, DidOpenTextDocumentParams, TextDocumentIdentifier, TextDocumentItem, Url, WorkspaceEdit,
+};
+use vraftls_core::{LanguageId, NodeId, RaftGroupId, Result, TransactionId, VRaftError};
+
+use crate::proxy::LanguageServerProxy;
+use crate::router::ResponseAggregator;
+
+/// HTTP path nodes answer LSP requests on
+pub const LSP_PATH: &str = "/lsp";
+
+/// HTTP path group leaders take edits of their files on
+pub const LSP_WRITE_PATH: &str = "/lsp/write";
+
+/// An LSP request for a node's language servers
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemoteRequest {
+    pub method: String,
+    pub params: Value,
+
+    /// Document the request is about, opened on the node while it runs
+    #[serde(default)]
+    pub document: Option<TextDocumentItem>,
+
+    /// Groups whose files the node should answer for
+    #[serde(default)]
+    pub groups: Vec<RaftGroupId>,
+
+    /// Log index the node's replicas of `groups` must have applied before
+    /// it answers, so the read sees writes acknowledged up to there
+    #[serde(default)]
+    pub min_applied_index: Option<u64>,
+}
+
+/// An edit of a group's files, for the group's leader
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RemoteWrite {
+    pub group: RaftGroupId,
+    pub step: WriteStep,
+}
+
+/// What a leader does with an edit of its group's files
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WriteStep {
+    /// Apply an edit of the group's files on its own, as one transaction
+    Apply { edit: WorkspaceEdit },
+
+    /// Check the group's part of a transaction and hold its files
+    Prepare {
+        transaction: TransactionId,
+        edit: WorkspaceEdit,
+    },
+
+    /// Apply a prepared part, under its hold
+    Commit { transaction: TransactionId },
+
+    /// Undo a committed part, or drop a prepared one, and release its hold
+    Abort { transaction: TransactionId },
+
+    /// Release the hold of a committed part
+    Release { transaction: TransactionId },
+}
+
+/// Answer of a leader to a [`RemoteWrite`]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WriteAck {
+    /// Log index the edit was committed at; `None` for steps writing nothing
+    pub applied_index: Option<u64>,
+}
+
+/// Body of a `409 Conflict` answer to a write on a group the node does not lead
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeaderHint {
+    pub group: RaftGroupId,
+
+    /// The group's leader as the node knows it
+    pub leader: Option<NodeId>,
+}
+
+/// Body of a `412 Precondition Failed` answer to a read the node's replica
+/// had not applied the log far enough for in time
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppliedIndexHint {
+    pub group: RaftGroupId,
+
+    /// Log index the replica had applied
+    pub applied_index: Option<u64>,
+}
+
+/// A node to scatter a request to
+#[derive(Clone, Debug)]
+pub struct ScatterTarget {
+    pub node: NodeId,
+    pub addr: SocketAddr,
+
+    /// Groups the node answers for
+    pub groups: Vec<RaftGroupId>,
+}
+
+/// Sends LSP requests to other nodes
+#[derive(Clone)]
+pub struct RemoteLsp {
+    client: reqwest::Client,
+}
+
+impl RemoteLsp {
+    pub fn new(timeout: Duration) -> Self {
+        let client = reqwest::Client::builder().timeout(timeout).build().unwrap_or_default();
+        Self { client }
+    }
+
+    /// Send a request to the node at `addr`
+    ///
+    /// Fails with `NotLeader` if the request writes to a group the node
+    /// does not lead, and with `StaleRead` if the node's replica did not
+    /// apply the log up to the request's `min_applied_index` in time.
+    pub async fn send(&self, addr: SocketAddr, request: &RemoteRequest) -> Result<Value> {
+        let response = self
+            .client
+            .post(format!("http://{}{}", addr, LSP_PATH))
+            .json(request)
+            .send()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?;
+
+        if response.status() == reqwest::StatusCode::CONFLICT {
+            let hint: LeaderHint = response
+                .json()
+                .await
+                .map_err(|e| VRaftError::Serialization(e.to_string()))?;
+            return Err(VRaftError::NotLeader { leader: hint.leader });
+        }
+        if response.status() == reqwest::StatusCode::PRECONDITION_FAILED {
+            let hint: AppliedIndexHint = response
+                .json()
+                .await
+                .map_err(|e| VRaftError::Serialization(e.to_string()))?;
+            let required = request.min_applied_index.unwrap_or_default();
+            return Err(VRaftError::StaleRead {
+                lag: required.saturating_sub(hint.applied_index.unwrap_or_default()),
+                max_lag: 0,
+            });
+        }
+
+        response
+            .error_for_status()
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| VRaftError::Serialization(e.to_string()))
+    }
+
+    /// Send an edit of a group's files to the node at `addr`
+    ///
+    /// Fails with `NotLeader` if the node does not lead the group, and with
+    /// `TransactionAborted` if the edit does not apply to the group's files.
+    pub async fn write(&self, addr: SocketAddr, write: &RemoteWrite) -> Result<WriteAck> {
+        let response = self
+            .client
+            .post(format!("http://{}{}", addr, LSP_WRITE_PATH))
+            .json(write)
+            .send()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?;
+
+        match response.status() {
+            reqwest::StatusCode::CONFLICT => {
+                let hint: LeaderHint = response
+                    .json()
+                    .await
+                    .map_err(|e| VRaftError::Serialization(e.to_string()))?;
+                Err(VRaftError::NotLeader { leader: hint.leader })
+            }
+            reqwest::StatusCode::UNPROCESSABLE_ENTITY => {
+                let reason = response.text().await.unwrap_or_default();
+                Err(VRaftError::TransactionAborted(reason))
+            }
+            _ => response
+                .error_for_status()
+                .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?
+                .json()
+                .await
+                .map_err(|e| VRaftError::Serialization(e.to_string())),
+        }
+    }
+
+    /// Send a request on a document of `groups` to a single node and decode
+    /// its answer
+    pub async fn request<P: Serialize, R: DeserializeOwned>(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        params: &P,
+        document: Option<TextDocumentItem>,
+        groups: Vec<RaftGroupId>,
+        min_applied_index: Option<u64>,
+    ) -> Result<Option<R>> {
+        let request = RemoteRequest {
+            method: method.to_string(),
+            params: serde_json::to_value(params).map_err(|e| VRaftError::Serialization(e.to_string()))?,
+            document,
+            groups,
+            min_applied_index,
+        };
+        let response = self.send(addr, &request).await?;
+        serde_json::from_value(response).map_err(|e| VRaftError::Serialization(e.to_string()))
+    }
+
+    /// Send a request to every target at once and collect the answers
+    ///
+    /// Nodes that fail are reported as errors of the aggregator; the others'
+    /// answers are still returned.
+    pub async fn scatter(
+        &self,
+        targets: Vec<ScatterTarget>,
+        method: &str,
+        params: &Value,
+        document: Option<TextDocumentItem>,
+        aggregator: &mut ResponseAggregator<Value>,
+    ) {
+        let mut requests = tokio::task::JoinSet::new();
+        for target in targets {
+            let remote = self.clone();
+            let request = RemoteRequest {
+                method: method.to_string(),
+                params: params.clone(),
+                document: document.clone(),
+                groups: target.groups,
+                min_applied_index: None,
+            };
+            requests.spawn(async move { (target.node, remote.send(target.addr, &request).await) });
+        }
+
+        while let Some(joined) = requests.join_next().await {
+            match joined {
+                Ok((_, Ok(Value::Null))) => {}
+                Ok((_, Ok(response))) => aggregator.add_response(response),
+                Ok((node, Err(e))) => aggregator.add_error(format!("node {}: {}", node, e)),
+                Err(e) => aggregator.add_error(e.to_string()),
+            }
+        }
+    }
+}
+
+impl Default for RemoteLsp {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(30))
+    }
+}
+
+/// A language server remote requests open documents on
+#[tower_lsp::async_trait]
+pub trait DocumentServer: Send + Sync {
+    fn language(&self) -> &LanguageId;
+
+    /// Whether the document is open on the server
+    fn is_open(&self, uri: &Url) -> bool;
+
+    async fn open(&self, document: TextDocumentItem);
+
+    async fn close(&self, uri: Url);
+}
+
+#[tower_lsp::async_trait]
+impl DocumentServer for LanguageServerProxy {
+    fn language(&self) -> &LanguageId {
+        LanguageServerProxy::language(self)
+    }
+
+    fn is_open(&self, uri: &Url) -> bool {
+        LanguageServerProxy::is_open(self, uri)
+    }
+
+    async fn open(&self, document: TextDocumentItem) {
+        self.did_open(DidOpenTextDocumentParams { text_document: document }).await;
+    }
+
+    async fn close(&self, uri: Url) {
+        self.did_close(DidCloseTextDocumentParams {
+            text_document: TextDocumentIdentifier::new(uri),
+        })
+        .await;
+    }
+}
+
+/// A document open on a server for remote requests
+struct SharedDocument {
+    /// Requests using the document
+    users: usize,
+
+    text: String,
+
+    /// Whether the requests opened it, rather than finding it open
+    owned: bool,
+}
+
+/// Documents open on a node's language servers for the requests gateways
+/// send it
+///
+/// The first request on a document opens it and the last one done closes
+/// it. A request bringing other text waits until the requests on the open
+/// text are done, then opens its own. Documents the server already had
+/// open are left as they are.
+#[derive(Default)]
+pub struct RemoteDocuments {
+    open: Mutex<HashMap<(LanguageId, Url), SharedDocument>>,
+
+    /// Signalled whenever a document is closed
+    closed: Notify,
+}
+
+impl RemoteDocuments {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Have `document` open on `server` with the request's text, until the
+    /// returned lease is dropped
+    pub async fn acquire<S: DocumentServer + ?Sized + 'static>(
+        self: &Arc<Self>,
+        server: &Arc<S>,
+        document: &TextDocumentItem,
+    ) -> DocumentLease<S> {
+        loop {
+            // Registered before looking, so a close in between is not missed
+            let closed = self.closed.notified();
+            tokio::pin!(closed);
+            closed.as_mut().enable();
+
+            let mut open = self.open.lock().await;
+            match open.entry((server.language().clone(), document.uri.clone())) {
+                Entry::Occupied(mut entry) => {
+                    let shared = entry.get_mut();
+                    if shared.owned && shared.text != document.text {
+                        drop(open);
+                        closed.await;
+                        continue;
+                    }
+                    shared.users += 1;
+                }
+                Entry::Vacant(entry) => {
+                    let owned = !server.is_open(&document.uri);
+                    if owned {
+                        server.open(document.clone()).await;
+                    }
+                    entry.insert(SharedDocument {
+                        users: 1,
+                        text: document.text.clone(),
+                        owned,
+                    });
+                }
+            }
+            return DocumentLease {
+                documents: self.clone(),
+                server: server.clone(),
+                uri: document.uri.clone(),
+            };
+        }
+    }
+
+    /// A request is done with a document; the last one closes it
+    async fn release<S: DocumentServer + ?Sized>(&self, server: &S, uri: &Url) {
+        let mut open = self.open.lock().await;
+        let Entry::Occupied(mut entry) = open.entry((server.language().clone(), uri.clone())) else {
+            return;
+        };
+        entry.get_mut().users -= 1;
+        if entry.get().users > 0 {
+            return;
+        }
+        if entry.remove().owned {
+            server.close(uri.clone()).await;
+        }
+        self.closed.notify_waiters();
+    }
+}
+
+/// A request's use of a document open through [`RemoteDocuments`]
+///
+/// Dropping it releases the document, closing it if no other request uses
+/// it anymore.
+pub struct DocumentLease<S: DocumentServer + ?Sized + 'static> {
+    documents: Arc<RemoteDocuments>,
+    server: Arc<S>,
+    uri: Url,
+}
+
+impl<S: DocumentServer + ?Sized + 'static> Drop for DocumentLease<S> {
+    fn drop(&mut self) {
+        let (documents, server, uri) = (self.documents.clone(), self.server.clone(), self.uri.clone());
+        tokio::spawn(async move { documents.release(server.as_ref(), &uri).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex as StdMutex;
+
+    /// Records what was sent to the server
+    struct Recorder {
+        language: LanguageId,
+        preopened: Vec<Url>,
+        sent: StdMutex<Vec<String>>,
+    }
+
+    impl Recorder {
+        fn new(preopened: Vec<Url>) -> Arc<Self> {
+            Arc::new(Self {
+                language: LanguageId::Rust,
+                preopened,
+                sent: StdMutex::new(Vec::new()),
+            })
+        }
+
+        fn sent(&self) -> Vec<String> {
+            std::mem::take(&mut self.sent.lock().unwrap())
+        }
+    }
+
+    #[tower_lsp::async_trait]
+    impl DocumentServer for Recorder {
+        fn language(&self) -> &LanguageId {
+            &self.language
+        }
+
+        fn is_open(&self, uri: &Url) -> bool {
+            self.preopened.contains(uri)
+        }
+
+        async fn open(&self, document: TextDocumentItem) {
+            self.sent.lock().unwrap().push(format!("open {} {}", document.version, document.text));
+        }
+
+        async fn close(&self, _uri: Url) {
+            self.sent.lock().unwrap().push("close".to_string());
+        }
+    }
+
+    fn document(version: i32, text: &str) -> TextDocumentItem {
+        let uri = Url::parse("file:///src/lib.rs").unwrap();
+        TextDocumentItem::new(uri, "rust".to_string(), version, text.to_string())
+    }
+
+    /// Let the releases of dropped leases run
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remote_documents_shared() {
+        let documents = Arc::new(RemoteDocuments::new());
+        let server = Recorder::new(Vec::new());
+
+        let first = documents.acquire(&server, &document(3, "a")).await;
+        let second = documents.acquire(&server, &document(3, "a")).await;
+        assert_eq!(server.sent(), ["open 3 a"]);
+
+        // Other text waits for the requests on the open text
+        let other = tokio::spawn({
+            let (documents, server) = (documents.clone(), server.clone());
+            async move { documents.acquire(&server, &document(1, "b")).await }
+        });
+        settle().await;
+        assert!(!other.is_finished());
+        assert!(server.sent().is_empty());
+
+        drop(first);
+        settle().await;
+        assert!(!other.is_finished());
+        drop(second);
+        let other = other.await.unwrap();
+        assert_eq!(server.sent(), ["close", "open 1 b"]);
+
+        drop(other);
+        settle().await;
+        assert_eq!(server.sent(), ["close"]);
+    }
+
+    #[tokio::test]
+    async fn test_remote_documents_preopened() {
+        let documents = Arc::new(RemoteDocuments::new());
+        let uri = document(0, "").uri;
+        let server = Recorder::new(vec![uri.clone()]);
+
+        let first = documents.acquire(&server, &document(1, "a")).await;
+        let second = documents.acquire(&server, &document(2, "b")).await;
+        drop((first, second));
+        settle().await;
+        assert!(server.sent().is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+//! Resuming editor sessions after a gateway restart
+//!
+//! The documents editors have open, and the workspace and capabilities the
+//! last editor initialized with, are saved to the gateway's session file
+//! shortly after they change. A restarted gateway reads them back: the
+//! documents go into the VFS and are reopened with their language servers
+//! before any editor reconnects, so a reconnecting editor finds its servers
+//! ready instead of starting cold. Documents no editor reopens within
+//! `RESUME_GRACE` are closed again.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tower_lsp::lsp_types::{DidCloseTextDocumentParams, DidOpenTextDocumentParams, InitializeParams, TextDocumentIdentifier, TextDocumentItem, Url};
+use vraftls_core::{FileVersion, Result, VRaftError};
+use vraftls_vfs::VfsPath;
+
+use crate::session::GatewaySessions;
+
+/// How often changed sessions are saved
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time editors have to reopen restored documents
+pub const RESUME_GRACE: Duration = Duration::from_secs(60);
+
+/// What a restarted gateway resumes from
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SavedSessions {
+    /// What the last editor initialized with: its workspace folders and
+    /// capabilities, which language servers are started with
+    pub initialize: InitializeParams,
+
+    pub documents: Vec<SavedDocument>,
+}
+
+/// A document open in an editor
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SavedDocument {
+    /// URI the editor opened the document with
+    pub uri: Url,
+    pub language_id: String,
+    pub path: VfsPath,
+
+    /// VFS version the document was at; the restarted VFS numbers its
+    /// versions afresh
+    pub version: u64,
+    pub text: String,
+}
+
+impl SavedSessions {
+    /// Read saved sessions; `None` if none were saved
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(VRaftError::Storage(e.to_string())),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| VRaftError::Serialization(e.to_string()))
+    }
+
+    /// Write the sessions, replacing the saved ones at once
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec(self).map_err(|e| VRaftError::Serialization(e.to_string()))?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        std::fs::write(&tmp, data).map_err(|e| VRaftError::Storage(e.to_string()))?;
+        std::fs::rename(&tmp, path).map_err(|e| VRaftError::Storage(e.to_string()))
+    }
+}
+
+/// Marks sessions changed since they were last saved
+#[derive(Default)]
+pub(crate) struct SessionsChanged(AtomicBool);
+
+impl SessionsChanged {
+    pub fn mark(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> bool {
+        self.0.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Save the sessions to `path` whenever they changed, until the gateway is
+/// dropped
+pub(crate) async fn save_sessions(sessions: Weak<GatewaySessions>, path: PathBuf) {
+    let mut ticker = tokio::time::interval(SAVE_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let Some(sessions) = sessions.upgrade() else {
+            return;
+        };
+        if !sessions.changed.take() {
+            continue;
+        }
+        let saved = sessions.saved_sessions().await;
+        if let Err(e) = saved.save(&path) {
+            tracing::warn!("Failed to save sessions to {}: {}", path.display(), e);
+            sessions.changed.mark();
+        }
+    }
+}
+
+/// VFS version and text a saved document is reopened at
+///
+/// A document the VFS already has was written since the restart, e.g. by an
+/// editor that reconnected first, and is reopened as the VFS has it rather
+/// than rolled back to the saved text.
+fn restore_document(sessions: &GatewaySessions, document: &SavedDocument) -> Option<(FileVersion, String)> {
+    match sessions.vfs.get_file_by_path(&document.path) {
+        Some(file) => Some((file.version, sessions.vfs.get_content(file.id).ok()?)),
+        None => Some((sessions.open_document(&document.path, &document.text)?, document.text.clone())),
+    }
+}
+
+/// Reopen the saved documents with their language servers, and close those
+/// no editor reopened after `RESUME_GRACE`
+pub(crate) async fn resume(sessions: Arc<GatewaySessions>, saved: SavedSessions) {
+    sessions.ls_pool.set_client_params(&saved.initialize).await;
+
+    let mut restored = Vec::new();
+    for document in saved.documents {
+        let Some((version, text)) = restore_document(&sessions, &document) else {
+            continue;
+        };
+        if sessions.server_version(&document.path).is_some() {
+            continue;
+        }
+        let Some(ls) = sessions.language_server(&document.path).await else {
+            continue;
+        };
+        ls.did_open(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem {
+                uri: document.uri.clone(),
+                language_id: document.language_id,
+                version: version.0 as i32,
+                text,
+            },
+        })
+        .await;
+        sessions.set_server_version(&document.path, Some(version));
+        restored.push((document.uri, document.path));
+    }
+    tracing::info!("Resumed {} documents", restored.len());
+
+    tokio::time::sleep(RESUME_GRACE).await;
+    for (uri, path) in restored {
+        if sessions.is_open(&path) {
+            continue;
+        }
+        sessions.set_server_version(&path, None);
+        if let Some(ls) = sessions.language_server(&path).await {
+            ls.did_close(DidCloseTextDocumentParams {
+                text_document: TextDocumentIdentifier { uri },
+            })
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vraftls_vfs::{VfsCommand, VfsResponse};
+
+    fn saved(path: &str, text: &str) -> SavedDocument {
+        SavedDocument {
+            uri: Url::from_file_path(path).unwrap(),
+            language_id: "rust".to_string(),
+            path: VfsPath::new(path),
+            version: 7,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("vraftls-sessions-{}.json", std::process::id()));
+        assert!(SavedSessions::load(&path).unwrap().is_none());
+
+        let sessions = SavedSessions {
+            documents: vec![saved("/work/src/main.rs", "fn main() {}\n")],
+            ..Default::default()
+        };
+        sessions.save(&path).unwrap();
+        let loaded = SavedSessions::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.documents.len(), 1);
+        assert_eq!(loaded.documents[0].text, "fn main() {}\n");
+        assert_eq!(loaded.documents[0].version, 7);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_document() {
+        let sessions = GatewaySessions::new();
+
+        // Not in the VFS yet: the saved text is restored
+        let document = saved("/work/src/main.rs", "fn main() {}\n");
+        let (version, text) = restore_document(&sessions, &document).unwrap();
+        assert_eq!(text, document.text);
+        let file = sessions.vfs.get_file_by_path(&document.path).unwrap();
+        assert_eq!(file.version, version);
+        assert_eq!(sessions.vfs.get_content(file.id).unwrap(), document.text);
+
+        // Written since the restart: the newer text is kept
+        let document = saved("/work/src/lib.rs", "pub fn old() {}\n");
+        sessions.vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new("/work/src"),
+            recursive: true,
+        });
+        let VfsResponse::Created(id) = sessions.vfs.apply(VfsCommand::CreateFile {
+            path: document.path.clone(),
+            content: "pub fn new() {}\n".to_string(),
+        }) else {
+            panic!("file not created");
+        };
+        let newer = sessions.vfs.get_file(id).unwrap().version;
+        assert_eq!(restore_document(&sessions, &document), Some((newer, "pub fn new() {}\n".to_string())));
+        assert_eq!(sessions.vfs.get_content(id).unwrap(), "pub fn new() {}\n");
+    }
+}
@@ -1,12 +1,20 @@
 //! LSP Request Router
 
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
 use tokio::sync::RwLock;
-use vraftls_core::{NodeId, PartitionKey, RaftGroupId};
+use std::sync::Arc;
+use vraftls_core::{NodeId, PartitionKey, RaftGroupId, Result, VRaftError};
 use vraftls_vfs::VfsPath;
 
+/// Times a request is sent before a `NotLeader` answer reaches the editor
+const MAX_LEADER_ATTEMPTS: usize = 3;
+
+/// Wait before resending to a node that knew no leader, while a group elects one
+const LEADER_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
 /// Decision on how to route an LSP request
 #[derive(Clone, Debug)]
 pub enum RouteDecision {
@@ -19,65 +27,481 @@ pub enum RouteDecision {
     /// Fan out to multiple nodes and aggregate results
     ScatterGather(Vec<RaftGroupId>),
 
-    /// Two-phase commit across multiple groups
+    /// Commit through the leaders of these groups, in all or none
     TwoPhaseCommit(Vec<RaftGroupId>),
 }
 
+/// Where the cluster places a partition
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionRoute {
+    pub group_id: RaftGroupId,
+    pub leader: Option<NodeId>,
+    pub replicas: Vec<NodeId>,
+}
+
+/// The cluster's routing of partitions to Raft groups, as the metadata
+/// group holds it
+#[tower_lsp::async_trait]
+pub trait RouteLookup: Send + Sync {
+    /// Route of a partition key, if it has one
+    async fn route(&self, key: &PartitionKey) -> Result<Option<PartitionRoute>>;
+}
+
+/// Whether an LSP request changes state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestKind {
+    /// Served by any replica of the file's group
+    Read,
+
+    /// Must reach the group leader
+    Write,
+}
+
+impl RequestKind {
+    /// Kind of an LSP method; only methods that change files or server
+    /// state are writes, so new and extension requests go to any replica
+    pub fn of(method: &str) -> Self {
+        match method {
+            "textDocument/rename"
+            | "textDocument/willSaveWaitUntil"
+            | "workspace/applyEdit"
+            | "workspace/executeCommand"
+            | "workspace/willCreateFiles"
+            | "workspace/willRenameFiles"
+            | "workspace/willDeleteFiles" => Self::Write,
+            _ => Self::Read,
+        }
+    }
+}
+
 /// Router for LSP requests
 pub struct LspRouter {
     /// File to node mapping cache
     file_cache: RwLock<HashMap<VfsPath, NodeId>>,
 
+    /// Group of each file routed by its partition, so the group's files
+    /// can be dropped from `file_cache` when it changes
+    file_groups: RwLock<HashMap<VfsPath, RaftGroupId>>,
+
     /// Raft group to leader node mapping
     group_leaders: RwLock<HashMap<RaftGroupId, NodeId>>,
 
     /// Local node ID
     local_node_id: RwLock<Option<NodeId>>,
+
+    /// Groups reported degraded by the cluster, with the reason
+    degraded_groups: RwLock<HashMap<RaftGroupId, String>>,
+
+    /// Nodes in maintenance, not given new sessions
+    maintenance_nodes: RwLock<HashSet<NodeId>>,
+
+    /// Region this gateway runs in
+    local_region: RwLock<Option<String>>,
+
+    /// Region of each known node
+    node_regions: RwLock<HashMap<NodeId, String>>,
+
+    /// Nodes holding a replica of each Raft group
+    group_replicas: RwLock<HashMap<RaftGroupId, Vec<NodeId>>>,
+
+    /// Address of each known node
+    node_addrs: RwLock<HashMap<NodeId, SocketAddr>>,
+
+    /// Routes of the files not in `file_cache`; without it every file is
+    /// served locally
+    routes: RwLock<Option<Arc<dyn RouteLookup>>>,
 }
 
 impl LspRouter {
     pub fn new() -> Self {
         Self {
             file_cache: RwLock::new(HashMap::new()),
+            file_groups: RwLock::new(HashMap::new()),
             group_leaders: RwLock::new(HashMap::new()),
             local_node_id: RwLock::new(None),
+            degraded_groups: RwLock::new(HashMap::new()),
+            maintenance_nodes: RwLock::new(HashSet::new()),
+            local_region: RwLock::new(None),
+            node_regions: RwLock::new(HashMap::new()),
+            group_replicas: RwLock::new(HashMap::new()),
+            node_addrs: RwLock::new(HashMap::new()),
+            routes: RwLock::new(None),
         }
     }
 
+    /// Look the routes of files up in `routes`
+    pub async fn set_routes(&self, routes: Arc<dyn RouteLookup>) {
+        *self.routes.write().await = Some(routes);
+    }
+
     /// Set the local node ID
     pub async fn set_local_node(&self, node_id: NodeId) {
         let mut local = self.local_node_id.write().await;
         *local = Some(node_id);
     }
 
+    /// Set the region this gateway runs in
+    pub async fn set_local_region(&self, region: Option<String>) {
+        *self.local_region.write().await = region;
+    }
+
+    /// Record the region a node runs in
+    pub async fn update_node_region(&self, node_id: NodeId, region: Option<String>) {
+        let mut regions = self.node_regions.write().await;
+        match region {
+            Some(region) => regions.insert(node_id, region),
+            None => regions.remove(&node_id),
+        };
+    }
+
+    /// Get the local node ID
+    pub async fn local_node(&self) -> Option<NodeId> {
+        *self.local_node_id.read().await
+    }
+
+    /// Record the address a node serves on
+    pub async fn update_node_addr(&self, node_id: NodeId, addr: SocketAddr) {
+        self.node_addrs.write().await.insert(node_id, addr);
+    }
+
+    /// Address of a node, if known
+    pub async fn node_addr(&self, node_id: NodeId) -> Option<SocketAddr> {
+        self.node_addrs.read().await.get(&node_id).copied()
+    }
+
+    /// Update the nodes holding a replica of a Raft group
+    pub async fn update_replicas(&self, group_id: RaftGroupId, replicas: Vec<NodeId>) {
+        self.group_replicas.write().await.insert(group_id, replicas);
+    }
+
     /// Update the leader for a Raft group
     pub async fn update_leader(&self, group_id: RaftGroupId, leader: NodeId) {
         let mut leaders = self.group_leaders.write().await;
         leaders.insert(group_id, leader);
     }
 
+    /// Follow a group's change of leader
+    ///
+    /// The group's files are routed again, so none keeps going to a node
+    /// that no longer leads it.
+    pub async fn leader_changed(&self, group_id: RaftGroupId, leader: Option<NodeId>) {
+        match leader {
+            Some(leader) => self.update_leader(group_id, leader).await,
+            None => {
+                self.group_leaders.write().await.remove(&group_id);
+            }
+        }
+        self.forget_group_files(group_id).await;
+    }
+
+    /// Follow a group's replica moving from one node to another
+    pub async fn group_moved(&self, group_id: RaftGroupId, from: NodeId, to: NodeId) {
+        self.replicas_changed(group_id, &[from], &[to]).await;
+    }
+
+    /// Follow a group losing the `removed` replicas and gaining the `added` ones
+    ///
+    /// The group's files are routed again, and a removed leader is no longer
+    /// known as the group's leader.
+    pub async fn replicas_changed(&self, group_id: RaftGroupId, removed: &[NodeId], added: &[NodeId]) {
+        if let Some(replicas) = self.group_replicas.write().await.get_mut(&group_id) {
+            replicas.retain(|n| !removed.contains(n) && !added.contains(n));
+            replicas.extend_from_slice(added);
+        }
+        let mut leaders = self.group_leaders.write().await;
+        if leaders.get(&group_id).is_some_and(|leader| removed.contains(leader)) {
+            leaders.remove(&group_id);
+        }
+        drop(leaders);
+        self.forget_group_files(group_id).await;
+    }
+
+    /// Stop routing to a node that is down or left the cluster
+    ///
+    /// Files served by the node are routed again, and groups it led have no
+    /// known leader until the next election is seen.
+    pub async fn node_down(&self, node_id: NodeId) {
+        self.group_leaders.write().await.retain(|_, leader| *leader != node_id);
+        for replicas in self.group_replicas.write().await.values_mut() {
+            replicas.retain(|n| *n != node_id);
+        }
+
+        let mut cache = self.file_cache.write().await;
+        let mut groups = self.file_groups.write().await;
+        cache.retain(|path, node| {
+            let keep = *node != node_id;
+            if !keep {
+                groups.remove(path);
+            }
+            keep
+        });
+    }
+
+    /// Drop the cached owners of a group's files
+    async fn forget_group_files(&self, group_id: RaftGroupId) {
+        let mut cache = self.file_cache.write().await;
+        let mut groups = self.file_groups.write().await;
+        groups.retain(|path, group| {
+            let keep = *group != group_id;
+            if !keep {
+                cache.remove(path);
+            }
+            keep
+        });
+    }
+
+    /// Mark a Raft group as degraded (quorum lost or split brain)
+    pub async fn mark_degraded(&self, group_id: RaftGroupId, reason: String) {
+        self.degraded_groups.write().await.insert(group_id, reason);
+    }
+
+    /// Mark a degraded Raft group as recovered
+    pub async fn clear_degraded(&self, group_id: RaftGroupId) {
+        self.degraded_groups.write().await.remove(&group_id);
+    }
+
+    /// Mark a node as in or out of maintenance
+    pub async fn set_maintenance(&self, node_id: NodeId, enabled: bool) {
+        let mut nodes = self.maintenance_nodes.write().await;
+        if enabled {
+            nodes.insert(node_id);
+        } else {
+            nodes.remove(&node_id);
+        }
+    }
+
+    /// Fail fast instead of sending a write to a degraded group
+    pub async fn check_writable(&self, group_id: RaftGroupId) -> Result<()> {
+        match self.degraded_groups.read().await.get(&group_id) {
+            Some(reason) => Err(VRaftError::GroupDegraded {
+                group: group_id,
+                reason: reason.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
     /// Route a file-based request
+    ///
+    /// Files not cached yet are looked up by their partition key; the
+    /// group's leader and replicas are recorded on the way, and the file is
+    /// served by the replica `read_replica` picks, or the leader.
     pub async fn route_for_file(&self, path: &VfsPath) -> RouteDecision {
         // Check cache first
         let cache = self.file_cache.read().await;
         if let Some(node_id) = cache.get(path) {
-            return RouteDecision::Single(*node_id);
+            // Sessions on a node in maintenance go elsewhere
+            if !self.maintenance_nodes.read().await.contains(node_id) {
+                return RouteDecision::Single(*node_id);
+            }
         }
         drop(cache);
 
-        // For now, return local only (single node mode)
-        RouteDecision::LocalOnly
+        let Some(routes) = self.routes.read().await.clone() else {
+            return RouteDecision::LocalOnly;
+        };
+        let route = match routes.route(&path.partition_key()).await {
+            Ok(Some(route)) => route,
+            Ok(None) => return RouteDecision::LocalOnly,
+            Err(e) => {
+                tracing::debug!("No route for {}: {}", path, e);
+                return RouteDecision::LocalOnly;
+            }
+        };
+
+        if let Some(leader) = route.leader {
+            self.update_leader(route.group_id, leader).await;
+        }
+        if !route.replicas.is_empty() {
+            self.update_replicas(route.group_id, route.replicas).await;
+        }
+        let node = match self.read_replica(route.group_id).await {
+            Some(node) => node,
+            None => match self.get_leader(route.group_id).await {
+                Some(leader) if !self.maintenance_nodes.read().await.contains(&leader) => leader,
+                _ => return RouteDecision::LocalOnly,
+            },
+        };
+        self.cache_file_owner(path.clone(), node).await;
+        self.file_groups.write().await.insert(path.clone(), route.group_id);
+        RouteDecision::Single(node)
+    }
+
+    /// Raft group holding a file
+    ///
+    /// Files not known yet are looked up by their partition key, recording
+    /// the group's leader and replicas on the way. `None` for files served
+    /// locally: without routes, or without a route for the file.
+    pub async fn group_for_file(&self, path: &VfsPath) -> Result<Option<RaftGroupId>> {
+        if let Some(group) = self.file_groups.read().await.get(path) {
+            return Ok(Some(*group));
+        }
+        let Some(routes) = self.routes.read().await.clone() else {
+            return Ok(None);
+        };
+        let Some(route) = routes.route(&path.partition_key()).await? else {
+            return Ok(None);
+        };
+
+        if let Some(leader) = route.leader {
+            self.update_leader(route.group_id, leader).await;
+        }
+        if !route.replicas.is_empty() {
+            self.update_replicas(route.group_id, route.replicas).await;
+        }
+        self.file_groups.write().await.insert(path.clone(), route.group_id);
+        Ok(Some(route.group_id))
+    }
+
+    /// Route an edit of files by the groups holding them
+    ///
+    /// Edits of files in groups are committed through the groups' leaders,
+    /// in every group or none; edits of files served locally stay local.
+    /// An edit mixing both cannot be made atomic and is refused.
+    pub async fn route_edit(&self, paths: &[VfsPath]) -> Result<RouteDecision> {
+        let mut groups = Vec::new();
+        let mut local = false;
+        for path in paths {
+            match self.group_for_file(path).await? {
+                Some(group) if !groups.contains(&group) => groups.push(group),
+                Some(_) => {}
+                None => local = true,
+            }
+        }
+        groups.sort();
+
+        match (groups.is_empty(), local) {
+            (true, _) => Ok(RouteDecision::LocalOnly),
+            (false, false) => Ok(RouteDecision::TwoPhaseCommit(groups)),
+            (false, true) => Err(VRaftError::InvalidLspRequest(
+                "edit spans files of the cluster and files served locally".to_string(),
+            )),
+        }
     }
 
     /// Route a workspace-wide request (scatter-gather)
+    ///
+    /// Every group the cluster serves is included, whether or not its
+    /// leader is known, since replicas can answer reads.
     pub async fn route_workspace(&self) -> RouteDecision {
-        let leaders = self.group_leaders.read().await;
-        if leaders.is_empty() {
+        let mut groups: BTreeSet<RaftGroupId> = self.group_leaders.read().await.keys().copied().collect();
+        let replicas = self.group_replicas.read().await;
+        groups.extend(replicas.iter().filter(|(_, nodes)| !nodes.is_empty()).map(|(group, _)| *group));
+        if groups.is_empty() {
             return RouteDecision::LocalOnly;
         }
+        RouteDecision::ScatterGather(groups.into_iter().collect())
+    }
 
-        let groups: Vec<RaftGroupId> = leaders.keys().copied().collect();
-        RouteDecision::ScatterGather(groups)
+    /// Nodes to scatter a request over, with the groups each one serves
+    ///
+    /// Each group goes to the node `route_request` picks, so a node hosting
+    /// several groups is asked once. Groups without a known node are left out.
+    pub async fn scatter_targets(
+        &self,
+        groups: &[RaftGroupId],
+        method: &str,
+    ) -> BTreeMap<NodeId, Vec<RaftGroupId>> {
+        let mut targets: BTreeMap<NodeId, Vec<RaftGroupId>> = BTreeMap::new();
+        for group_id in groups {
+            match self.route_request(*group_id, method).await {
+                Some(node) => targets.entry(node).or_default().push(*group_id),
+                None => tracing::debug!("No node to send {} for group {:?} to", method, group_id),
+            }
+        }
+        targets
+    }
+
+    /// Node to send an LSP request on a group's files to
+    ///
+    /// Writes go to the leader wherever it runs. Reads stay in this
+    /// gateway's region when a replica there can serve them, and only cross
+    /// regions to the leader otherwise, or to any replica while the group
+    /// has no known leader.
+    pub async fn route_request(&self, group_id: RaftGroupId, method: &str) -> Option<NodeId> {
+        let leader = self.get_leader(group_id).await;
+        match RequestKind::of(method) {
+            RequestKind::Write => leader,
+            RequestKind::Read => match self.read_replica(group_id).await.or(leader) {
+                Some(node) => Some(node),
+                None => self.any_replica(group_id).await,
+            },
+        }
+    }
+
+    /// Send a request on a group's files to the node `route_request` picks
+    ///
+    /// A node that turns out not to lead the group answers `NotLeader`; the
+    /// group's leader is then updated from its hint and the request sent
+    /// again, a bounded number of times. Without a hint, or without any
+    /// known leader, the group is still electing, so the request is resent
+    /// after a short wait. Writes to a degraded group fail without being
+    /// sent.
+    pub async fn send_to_leader<T, F, Fut>(&self, group_id: RaftGroupId, method: &str, mut send: F) -> Result<T>
+    where
+        F: FnMut(NodeId) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        if RequestKind::of(method) == RequestKind::Write {
+            self.check_writable(group_id).await?;
+        }
+
+        let mut last_error = VRaftError::NotLeader { leader: None };
+        for _ in 0..MAX_LEADER_ATTEMPTS {
+            let Some(node) = self.route_request(group_id, method).await else {
+                tokio::time::sleep(LEADER_RETRY_BACKOFF).await;
+                continue;
+            };
+
+            let leader = match send(node).await {
+                Err(VRaftError::NotLeader { leader }) => leader,
+                other => return other,
+            };
+            tracing::debug!("Node {} does not lead group {:?}, leader is {:?}", node, group_id, leader);
+            match leader {
+                Some(leader) if leader != node => self.update_leader(group_id, leader).await,
+                _ => tokio::time::sleep(LEADER_RETRY_BACKOFF).await,
+            }
+            last_error = VRaftError::NotLeader { leader };
+        }
+        Err(last_error)
+    }
+
+    /// Replica to read a group from without leaving this gateway's region
+    ///
+    /// Prefers this node, then the leader if it is in the region, then any
+    /// other replica in the region. Nodes in maintenance are skipped.
+    pub async fn read_replica(&self, group_id: RaftGroupId) -> Option<NodeId> {
+        let replicas = self.group_replicas.read().await.get(&group_id).cloned()?;
+        let maintenance = self.maintenance_nodes.read().await;
+        let available: Vec<_> = replicas.into_iter().filter(|n| !maintenance.contains(n)).collect();
+
+        if let Some(local) = *self.local_node_id.read().await {
+            if available.contains(&local) {
+                return Some(local);
+            }
+        }
+
+        let region = self.local_region.read().await.clone()?;
+        let regions = self.node_regions.read().await;
+        let leader = self.get_leader(group_id).await;
+        available
+            .into_iter()
+            .filter(|n| regions.get(n) == Some(&region))
+            .min_by_key(|n| Some(*n) != leader)
+    }
+
+    /// Any replica of a group not in maintenance, in any region
+    async fn any_replica(&self, group_id: RaftGroupId) -> Option<NodeId> {
+        let replicas = self.group_replicas.read().await;
+        let maintenance = self.maintenance_nodes.read().await;
+        replicas.get(&group_id)?.iter().copied().find(|n| !maintenance.contains(n))
+    }
+
+    /// Whether the cluster serves a group: its leader or replicas are known
+    pub async fn serves_group(&self, group_id: RaftGroupId) -> bool {
+        self.group_leaders.read().await.contains_key(&group_id)
+            || self.group_replicas.read().await.get(&group_id).is_some_and(|r| !r.is_empty())
     }
 
     /// Get the leader node for a Raft group
@@ -96,6 +520,7 @@ impl LspRouter {
     pub async fn invalidate_file(&self, path: &VfsPath) {
         let mut cache = self.file_cache.write().await;
         cache.remove(path);
+        self.file_groups.write().await.remove(path);
     }
 
     /// Clear all cached state
@@ -103,6 +528,7 @@ impl LspRouter {
         let mut cache = self.file_cache.write().await;
         cache.clear();
         drop(cache);
+        self.file_groups.write().await.clear();
 
         let mut leaders = self.group_leaders.write().await;
         leaders.clear();
@@ -145,6 +571,10 @@ impl<T> ResponseAggregator<T> {
         &self.responses
     }
 
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
     pub fn has_errors(&self) -> bool {
         !self.errors.is_empty()
     }
@@ -181,3 +611,266 @@ impl ResponseAggregator<tower_lsp::lsp_types::Location> {
         }
     }
 }
+
+/// Aggregator for workspace symbols
+impl ResponseAggregator<tower_lsp::lsp_types::SymbolInformation> {
+    /// Merge symbols from all nodes, the best matches for `query` first
+    ///
+    /// Replicas of a group may report the same symbol, so duplicates are
+    /// dropped before ranking.
+    pub fn into_ranked_symbols(
+        self,
+        query: &str,
+        limit: usize,
+    ) -> Option<Vec<tower_lsp::lsp_types::SymbolInformation>> {
+        let mut seen = HashSet::new();
+        let mut symbols: Vec<_> = self
+            .responses
+            .into_iter()
+            .filter(|s| {
+                let start = s.location.range.start;
+                seen.insert((s.name.clone(), s.location.uri.to_string(), start.line, start.character))
+            })
+            .collect();
+        if symbols.is_empty() {
+            return None;
+        }
+
+        symbols.sort_by_cached_key(|s| (symbol_rank(&s.name, query), s.name.len(), s.name.clone()));
+        symbols.truncate(limit);
+        Some(symbols)
+    }
+}
+
+/// Aggregator for raw responses of remote nodes
+impl ResponseAggregator<serde_json::Value> {
+    /// Merge the answers to an LSP request as the method's result type allows
+    ///
+    /// Location and item lists are concatenated without duplicates,
+    /// completions and workspace edits are combined, and any other request
+    /// takes the first answer.
+    pub fn into_merged(self, method: &str) -> serde_json::Value {
+        use serde_json::Value;
+
+        let mut responses = self.responses.into_iter().filter(|r| !r.is_null());
+        match method {
+            "textDocument/definition"
+            | "textDocument/declaration"
+            | "textDocument/typeDefinition"
+            | "textDocument/implementation"
+            | "textDocument/references"
+            | "textDocument/codeAction"
+            | "textDocument/inlayHint"
+            | "textDocument/documentLink"
+            | "textDocument/foldingRange"
+            | "workspace/symbol" => {
+                let mut items: Vec<Value> = Vec::new();
+                for item in responses.flat_map(|r| match r {
+                    Value::Array(items) => items,
+                    item => vec![item],
+                }) {
+                    if !items.contains(&item) {
+                        items.push(item);
+                    }
+                }
+                if items.is_empty() {
+                    Value::Null
+                } else {
+                    Value::Array(items)
+                }
+            }
+            "textDocument/completion" => {
+                let mut incomplete = false;
+                let mut items = Vec::new();
+                for response in responses {
+                    match response {
+                        Value::Array(list) => items.extend(list),
+                        list => {
+                            incomplete |= list["isIncomplete"].as_bool().unwrap_or(false);
+                            if let Some(Value::Array(list)) = list.get("items") {
+                                items.extend(list.iter().cloned());
+                            }
+                        }
+                    }
+                }
+                if items.is_empty() {
+                    Value::Null
+                } else {
+                    serde_json::json!({ "isIncomplete": incomplete, "items": items })
+                }
+            }
+            "workspace/diagnostic" => {
+                let items: Vec<Value> = responses
+                    .flat_map(|r| match r.get("items") {
+                        Some(Value::Array(items)) => items.clone(),
+                        _ => Vec::new(),
+                    })
+                    .collect();
+                serde_json::json!({ "items": items })
+            }
+            "textDocument/rename" | "workspace/willRenameFiles" => {
+                let edits = responses.filter_map(|r| serde_json::from_value(r).ok()).collect();
+                crate::workspace_edit::merge_workspace_edits(edits)
+                    .and_then(|edit| serde_json::to_value(edit).ok())
+                    .unwrap_or(Value::Null)
+            }
+            _ => responses.next().unwrap_or(Value::Null),
+        }
+    }
+}
+
+/// How well a symbol name matches a query; lower is better
+fn symbol_rank(name: &str, query: &str) -> u8 {
+    let (lower_name, lower_query) = (name.to_lowercase(), query.to_lowercase());
+    if name == query {
+        0
+    } else if lower_name == lower_query {
+        1
+    } else if name.starts_with(query) {
+        2
+    } else if lower_name.starts_with(&lower_query) {
+        3
+    } else if lower_name.contains(&lower_query) {
+        4
+    } else {
+        5
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Routes of a fixed set of files
+    struct FileRoutes(HashMap<PartitionKey, RaftGroupId>);
+
+    #[tower_lsp::async_trait]
+    impl RouteLookup for FileRoutes {
+        async fn route(&self, key: &PartitionKey) -> Result<Option<PartitionRoute>> {
+            Ok(self.0.get(key).map(|group_id| PartitionRoute {
+                group_id: *group_id,
+                leader: Some(NodeId::new(group_id.0)),
+                replicas: Vec::new(),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_request_kind() {
+        for method in ["textDocument/rename", "workspace/executeCommand", "workspace/willRenameFiles"] {
+            assert_eq!(RequestKind::of(method), RequestKind::Write, "{method}");
+        }
+        for method in [
+            "textDocument/hover",
+            "textDocument/semanticTokens/full/delta",
+            "textDocument/inlayHint",
+            "textDocument/prepareRename",
+            "rust-analyzer/expandMacro",
+            "rust-analyzer/viewHir",
+        ] {
+            assert_eq!(RequestKind::of(method), RequestKind::Read, "{method}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_edit() {
+        let router = LspRouter::new();
+        let paths = |paths: &[&str]| paths.iter().map(VfsPath::new).collect::<Vec<_>>();
+        assert!(matches!(
+            router.route_edit(&paths(&["/a.rs"])).await,
+            Ok(RouteDecision::LocalOnly)
+        ));
+
+        let routes = [("/a.rs", 1), ("/b.rs", 2), ("/c.rs", 1)]
+            .into_iter()
+            .map(|(path, group)| (VfsPath::new(path).partition_key(), RaftGroupId::new(group)))
+            .collect();
+        router.set_routes(Arc::new(FileRoutes(routes))).await;
+
+        match router.route_edit(&paths(&["/b.rs", "/a.rs", "/c.rs"])).await {
+            Ok(RouteDecision::TwoPhaseCommit(groups)) => {
+                assert_eq!(groups, [RaftGroupId::new(1), RaftGroupId::new(2)])
+            }
+            other => panic!("expected TwoPhaseCommit, got {:?}", other),
+        }
+        assert_eq!(router.get_leader(RaftGroupId::new(2)).await, Some(NodeId::new(2)));
+
+        // Files without a route are served locally, which no transaction spans
+        assert!(router.route_edit(&paths(&["/a.rs", "/d.rs"])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_to_leader_waits_for_election() {
+        let router = Arc::new(LspRouter::new());
+        let group = RaftGroupId::new(1);
+        assert!(!router.serves_group(group).await);
+        router.update_replicas(group, vec![NodeId::new(1), NodeId::new(2)]).await;
+        assert!(router.serves_group(group).await);
+
+        // No leader is elected in time: the write fails as `NotLeader`
+        // without going to a replica
+        let sent = router.send_to_leader(group, "textDocument/rename", |node| async move { Ok(node) }).await;
+        assert!(matches!(sent, Err(VRaftError::NotLeader { leader: None })));
+
+        // A leader elected while waiting gets the write
+        let elect = {
+            let router = router.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(LEADER_RETRY_BACKOFF / 2).await;
+                router.leader_changed(group, Some(NodeId::new(2))).await;
+            })
+        };
+        let sent = router.send_to_leader(group, "textDocument/rename", |node| async move { Ok(node) }).await;
+        assert_eq!(sent.unwrap(), NodeId::new(2));
+        elect.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replicas_changed() {
+        let router = LspRouter::new();
+        let group = RaftGroupId::new(1);
+        let path = VfsPath::new("/a.rs");
+        router.update_replicas(group, vec![NodeId::new(1), NodeId::new(2), NodeId::new(3)]).await;
+        router.update_leader(group, NodeId::new(1)).await;
+        router.cache_file_owner(path.clone(), NodeId::new(1)).await;
+        router.file_groups.write().await.insert(path.clone(), group);
+
+        // The group only shrinks; its removed leader and cached routes go
+        router.replicas_changed(group, &[NodeId::new(1)], &[]).await;
+        assert_eq!(router.group_replicas.read().await[&group], [NodeId::new(2), NodeId::new(3)]);
+        assert_eq!(router.get_leader(group).await, None);
+        assert!(!router.file_cache.read().await.contains_key(&path));
+
+        // Uneven changes are followed as a whole
+        router.update_leader(group, NodeId::new(2)).await;
+        router.replicas_changed(group, &[NodeId::new(3)], &[NodeId::new(4), NodeId::new(5)]).await;
+        assert_eq!(
+            router.group_replicas.read().await[&group],
+            [NodeId::new(2), NodeId::new(4), NodeId::new(5)]
+        );
+        assert_eq!(router.get_leader(group).await, Some(NodeId::new(2)));
+    }
+
+    #[tokio::test]
+    async fn test_route_workspace_without_leader() {
+        let router = LspRouter::new();
+        assert!(matches!(router.route_workspace().await, RouteDecision::LocalOnly));
+
+        let (led, electing) = (RaftGroupId::new(1), RaftGroupId::new(2));
+        router.update_leader(led, NodeId::new(1)).await;
+        router.update_replicas(led, vec![NodeId::new(1)]).await;
+        router.update_replicas(electing, vec![NodeId::new(2), NodeId::new(3)]).await;
+        router.set_maintenance(NodeId::new(2), true).await;
+
+        // The group still electing a leader is read from a live replica
+        match router.route_workspace().await {
+            RouteDecision::ScatterGather(groups) => assert_eq!(groups, [led, electing]),
+            other => panic!("expected ScatterGather, got {:?}", other),
+        }
+        let targets = router.scatter_targets(&[led, electing], "workspace/symbol").await;
+        assert_eq!(
+            targets,
+            BTreeMap::from([(NodeId::new(1), vec![led]), (NodeId::new(3), vec![electing])])
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Prioritized admission of requests to a language server
+//!
+//! A server works on a few requests at a time; the rest wait in a priority
+//! queue in front of it. Interactive requests, e.g. completion or hover in
+//! the document being edited, go before normal ones, and those before
+//! background work like code lenses or diagnostics, so typing stays fast
+//! while a server is busy. When too much background work is already
+//! waiting, new background requests are shed instead of queued; editors
+//! ask for them again.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+/// How urgently a request should be answered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RequestPriority {
+    /// Refreshes editors make on their own; may be shed
+    Background,
+    Normal,
+    /// Requests a user waits on while typing
+    Interactive,
+}
+
+impl RequestPriority {
+    /// Priority of a method in the document the user is editing
+    pub fn of(method: &str) -> Self {
+        match method {
+            "initialize"
+            | "shutdown"
+            | "textDocument/completion"
+            | "textDocument/inlineCompletion"
+            | "completionItem/resolve"
+            | "textDocument/hover"
+            | "textDocument/signatureHelp"
+            | "textDocument/definition"
+            | "textDocument/declaration"
+            | "textDocument/typeDefinition"
+            | "textDocument/implementation"
+            | "textDocument/documentHighlight"
+            | "textDocument/onTypeFormatting" => Self::Interactive,
+            "textDocument/codeLens"
+            | "codeLens/resolve"
+            | "textDocument/diagnostic"
+            | "workspace/diagnostic"
+            | "textDocument/inlayHint"
+            | "textDocument/foldingRange"
+            | "textDocument/documentLink"
+            | "textDocument/documentColor"
+            | "textDocument/semanticTokens/full"
+            | "textDocument/semanticTokens/full/delta"
+            | "textDocument/semanticTokens/range" => Self::Background,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// A request waiting for the server
+struct Waiter {
+    priority: RequestPriority,
+    seq: u64,
+    admit: oneshot::Sender<()>,
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Highest priority first, then first come
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct State {
+    in_flight: usize,
+    waiting: BinaryHeap<Waiter>,
+    next_seq: u64,
+}
+
+/// Admits requests to a server in priority order
+pub struct RequestScheduler {
+    state: Mutex<State>,
+
+    /// Requests the server works on at once
+    max_in_flight: usize,
+
+    /// Background requests waiting before more are shed
+    max_background_queue: usize,
+}
+
+/// Admission of a request; the next one is admitted when it drops
+pub struct SchedulerPermit {
+    scheduler: Arc<RequestScheduler>,
+}
+
+impl Drop for SchedulerPermit {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+/// A request waiting for admission, which gives its slot on if it is
+/// admitted but no longer waited for
+struct Waiting {
+    admitted: oneshot::Receiver<()>,
+    scheduler: Arc<RequestScheduler>,
+    done: bool,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if !self.done && self.admitted.try_recv().is_ok() {
+            self.scheduler.release();
+        }
+    }
+}
+
+impl RequestScheduler {
+    pub fn new(max_in_flight: usize, max_background_queue: usize) -> Self {
+        Self {
+            state: Mutex::new(State {
+                in_flight: 0,
+                waiting: BinaryHeap::new(),
+                next_seq: 0,
+            }),
+            max_in_flight: max_in_flight.max(1),
+            max_background_queue,
+        }
+    }
+
+    /// Wait until a request may be sent to the server
+    ///
+    /// Returns `None` if the request is shed.
+    pub async fn acquire(self: &Arc<Self>, priority: RequestPriority) -> Option<SchedulerPermit> {
+        let admitted = {
+            let mut state = self.state.lock().unwrap();
+            if state.in_flight < self.max_in_flight && state.waiting.is_empty() {
+                state.in_flight += 1;
+                return Some(self.permit());
+            }
+            if priority == RequestPriority::Background {
+                let queued = state
+                    .waiting
+                    .iter()
+                    .filter(|w| w.priority == RequestPriority::Background && !w.admit.is_closed())
+                    .count();
+                if queued >= self.max_background_queue {
+                    return None;
+                }
+            }
+
+            let (admit, admitted) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.waiting.push(Waiter { priority, seq, admit });
+            admitted
+        };
+
+        let mut waiting = Waiting {
+            admitted,
+            scheduler: self.clone(),
+            done: false,
+        };
+        let admitted = (&mut waiting.admitted).await.is_ok();
+        waiting.done = true;
+        admitted.then(|| self.permit())
+    }
+
+    /// Whether a new request would have to wait
+    pub fn busy(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.in_flight >= self.max_in_flight || !state.waiting.is_empty()
+    }
+
+    /// Requests being worked on and waiting
+    pub fn load(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.in_flight, state.waiting.len())
+    }
+
+    fn permit(self: &Arc<Self>) -> SchedulerPermit {
+        SchedulerPermit {
+            scheduler: self.clone(),
+        }
+    }
+
+    /// Hand a finished request's slot to the most urgent waiter, if any
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiting.pop() {
+            // Waiters that gave up are skipped
+            if waiter.admit.send(()).is_ok() {
+                return;
+            }
+        }
+        state.in_flight = state.in_flight.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RequestPriority::{Background, Interactive, Normal};
+
+    /// Let spawned requests run up to where they wait
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Queue a request that records its label once admitted
+    fn request(
+        scheduler: &Arc<RequestScheduler>,
+        priority: RequestPriority,
+        label: &'static str,
+        admitted: &Arc<Mutex<Vec<&'static str>>>,
+    ) -> tokio::task::JoinHandle<()> {
+        let (scheduler, admitted) = (scheduler.clone(), admitted.clone());
+        tokio::spawn(async move {
+            let _permit = scheduler.acquire(priority).await;
+            admitted.lock().unwrap().push(label);
+        })
+    }
+
+    #[tokio::test]
+    async fn test_admits_by_priority_then_arrival() {
+        let scheduler = Arc::new(RequestScheduler::new(1, 8));
+        let admitted = Arc::new(Mutex::new(Vec::new()));
+        let permit = scheduler.acquire(Normal).await.unwrap();
+        assert!(scheduler.busy());
+
+        let mut requests = Vec::new();
+        for (priority, label) in [
+            (Background, "lens"),
+            (Normal, "references"),
+            (Interactive, "completion"),
+            (Background, "hints"),
+            (Interactive, "hover"),
+        ] {
+            requests.push(request(&scheduler, priority, label, &admitted));
+            settle().await;
+        }
+        assert_eq!(scheduler.load(), (1, 5));
+
+        drop(permit);
+        for request in requests {
+            request.await.unwrap();
+        }
+        assert_eq!(
+            *admitted.lock().unwrap(),
+            ["completion", "hover", "references", "lens", "hints"]
+        );
+        assert_eq!(scheduler.load(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_requests_are_dropped() {
+        let scheduler = Arc::new(RequestScheduler::new(1, 1));
+        let admitted = Arc::new(Mutex::new(Vec::new()));
+        let permit = scheduler.acquire(Interactive).await.unwrap();
+
+        let cancelled = request(&scheduler, Background, "lens", &admitted);
+        settle().await;
+        // The background queue is full
+        assert!(scheduler.acquire(Background).await.is_none());
+
+        // A cancelled request neither takes a slot in the background queue
+        // nor is admitted
+        cancelled.abort();
+        settle().await;
+        let hints = request(&scheduler, Background, "hints", &admitted);
+        settle().await;
+        let hover = request(&scheduler, Interactive, "hover", &admitted);
+        settle().await;
+
+        drop(permit);
+        hover.await.unwrap();
+        hints.await.unwrap();
+        assert_eq!(*admitted.lock().unwrap(), ["hover", "hints"]);
+        assert_eq!(scheduler.load(), (0, 0));
+    }
+}
@@ -0,0 +1,276 @@
+//! Semantic token legends
+//!
+//! The editor learns one token legend in the gateway's initialize response,
+//! before any language server has been spawned, while each server encodes
+//! its tokens against its own legend. The gateway therefore advertises the
+//! standard LSP legend and translates the token type and modifier indices of
+//! every server into it. Types a server adds beyond the standard ones have
+//! no index the editor knows and are dropped.
+
+use tower_lsp::lsp_types::*;
+
+/// Legend the gateway advertises to the editor
+pub fn gateway_legend() -> SemanticTokensLegend {
+    SemanticTokensLegend {
+        token_types: vec![
+            SemanticTokenType::NAMESPACE,
+            SemanticTokenType::TYPE,
+            SemanticTokenType::CLASS,
+            SemanticTokenType::ENUM,
+            SemanticTokenType::INTERFACE,
+            SemanticTokenType::STRUCT,
+            SemanticTokenType::TYPE_PARAMETER,
+            SemanticTokenType::PARAMETER,
+            SemanticTokenType::VARIABLE,
+            SemanticTokenType::PROPERTY,
+            SemanticTokenType::ENUM_MEMBER,
+            SemanticTokenType::EVENT,
+            SemanticTokenType::FUNCTION,
+            SemanticTokenType::METHOD,
+            SemanticTokenType::MACRO,
+            SemanticTokenType::KEYWORD,
+            SemanticTokenType::MODIFIER,
+            SemanticTokenType::COMMENT,
+            SemanticTokenType::STRING,
+            SemanticTokenType::NUMBER,
+            SemanticTokenType::REGEXP,
+            SemanticTokenType::OPERATOR,
+            SemanticTokenType::DECORATOR,
+        ],
+        token_modifiers: vec![
+            SemanticTokenModifier::DECLARATION,
+            SemanticTokenModifier::DEFINITION,
+            SemanticTokenModifier::READONLY,
+            SemanticTokenModifier::STATIC,
+            SemanticTokenModifier::DEPRECATED,
+            SemanticTokenModifier::ABSTRACT,
+            SemanticTokenModifier::ASYNC,
+            SemanticTokenModifier::MODIFICATION,
+            SemanticTokenModifier::DOCUMENTATION,
+            SemanticTokenModifier::DEFAULT_LIBRARY,
+        ],
+    }
+}
+
+/// Semantic token options of a server's capabilities
+pub fn semantic_tokens_options(caps: &ServerCapabilities) -> Option<&SemanticTokensOptions> {
+    match caps.semantic_tokens_provider.as_ref()? {
+        SemanticTokensServerCapabilities::SemanticTokensOptions(options) => Some(options),
+        SemanticTokensServerCapabilities::SemanticTokensRegistrationOptions(options) => {
+            Some(&options.semantic_tokens_options)
+        }
+    }
+}
+
+/// Translation of a server's legend into the gateway legend
+#[derive(Debug, Clone)]
+pub struct LegendMap {
+    /// Gateway index of each server token type
+    types: Vec<Option<u32>>,
+
+    /// Gateway bit of each server modifier bit
+    modifiers: Vec<Option<u32>>,
+}
+
+impl LegendMap {
+    pub fn new(server: &SemanticTokensLegend, gateway: &SemanticTokensLegend) -> Self {
+        Self {
+            types: server
+                .token_types
+                .iter()
+                .map(|t| {
+                    gateway
+                        .token_types
+                        .iter()
+                        .position(|x| x == t)
+                        .map(|i| i as u32)
+                })
+                .collect(),
+            modifiers: server
+                .token_modifiers
+                .iter()
+                .map(|m| {
+                    gateway
+                        .token_modifiers
+                        .iter()
+                        .position(|x| x == m)
+                        .map(|i| i as u32)
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether server tokens are valid in the gateway legend as they are
+    pub fn is_identity(&self) -> bool {
+        let same = |map: &[Option<u32>]| map.iter().enumerate().all(|(i, m)| *m == Some(i as u32));
+        same(&self.types) && same(&self.modifiers)
+    }
+
+    /// Translate tokens into the gateway legend
+    ///
+    /// Positions are relative to the previous token, so the position of a
+    /// dropped token is carried over to the one after it.
+    pub fn translate(&self, tokens: &[SemanticToken]) -> Vec<SemanticToken> {
+        let mut out = Vec::with_capacity(tokens.len());
+        let (mut carry_line, mut carry_start) = (0, 0);
+
+        for token in tokens {
+            let delta_line = token.delta_line + carry_line;
+            let delta_start = if token.delta_line == 0 {
+                token.delta_start + carry_start
+            } else {
+                token.delta_start
+            };
+
+            match self.types.get(token.token_type as usize).copied().flatten() {
+                Some(token_type) => {
+                    out.push(SemanticToken {
+                        delta_line,
+                        delta_start,
+                        length: token.length,
+                        token_type,
+                        token_modifiers_bitset: self.translate_modifiers(token.token_modifiers_bitset),
+                    });
+                    carry_line = 0;
+                    carry_start = 0;
+                }
+                None => {
+                    carry_line = delta_line;
+                    carry_start = delta_start;
+                }
+            }
+        }
+        out
+    }
+
+    fn translate_modifiers(&self, bitset: u32) -> u32 {
+        self.modifiers
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| bitset & (1 << bit) != 0)
+            .filter_map(|(_, mapped)| *mapped)
+            .fold(0, |acc, bit| acc | (1 << bit))
+    }
+}
+
+/// Apply delta edits to the tokens they were computed against
+///
+/// Edit offsets count the integers of the encoded array, five per token.
+pub fn apply_edits(tokens: &[SemanticToken], edits: &[SemanticTokensEdit]) -> Vec<SemanticToken> {
+    let mut data: Vec<u32> = tokens.iter().flat_map(encode).collect();
+
+    // Offsets refer to the original array, so apply the last edit first
+    let mut edits: Vec<_> = edits.iter().collect();
+    edits.sort_by_key(|e| std::cmp::Reverse(e.start));
+    for edit in edits {
+        let start = (edit.start as usize).min(data.len());
+        let end = (start + edit.delete_count as usize).min(data.len());
+        let inserted = edit.data.iter().flatten().flat_map(encode);
+        data.splice(start..end, inserted);
+    }
+
+    data.chunks_exact(5)
+        .map(|c| SemanticToken {
+            delta_line: c[0],
+            delta_start: c[1],
+            length: c[2],
+            token_type: c[3],
+            token_modifiers_bitset: c[4],
+        })
+        .collect()
+}
+
+fn encode(token: &SemanticToken) -> [u32; 5] {
+    [
+        token.delta_line,
+        token.delta_start,
+        token.length,
+        token.token_type,
+        token.token_modifiers_bitset,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(delta_line: u32, delta_start: u32, length: u32, token_type: u32, modifiers: u32) -> SemanticToken {
+        SemanticToken {
+            delta_line,
+            delta_start,
+            length,
+            token_type,
+            token_modifiers_bitset: modifiers,
+        }
+    }
+
+    #[test]
+    fn test_translate_into_gateway_legend() {
+        let gateway = gateway_legend();
+        assert!(LegendMap::new(&gateway, &gateway).is_identity());
+
+        // Types and modifiers the gateway legend has, in another order, and
+        // ones it lacks
+        let server = SemanticTokensLegend {
+            token_types: vec![
+                SemanticTokenType::VARIABLE,
+                SemanticTokenType::new("lifetime"),
+                SemanticTokenType::FUNCTION,
+                SemanticTokenType::KEYWORD,
+            ],
+            token_modifiers: vec![
+                SemanticTokenModifier::new("unsafe"),
+                SemanticTokenModifier::READONLY,
+                SemanticTokenModifier::DECLARATION,
+            ],
+        };
+        let map = LegendMap::new(&server, &gateway);
+        assert!(!map.is_identity());
+
+        let tokens = [
+            token(0, 0, 3, 3, 0),
+            token(0, 4, 2, 0, 0b110),
+            token(1, 2, 3, 1, 0),
+            token(0, 5, 4, 2, 0b001),
+            token(1, 1, 1, 1, 0),
+            token(1, 3, 2, 0, 0b011),
+        ];
+        // Lifetimes are dropped, and their positions carried to the next
+        // token on the same or a later line
+        assert_eq!(
+            map.translate(&tokens),
+            [
+                token(0, 0, 3, 15, 0),
+                token(0, 4, 2, 8, 0b101),
+                token(1, 7, 4, 12, 0),
+                token(2, 3, 2, 8, 0b100),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_edits() {
+        let tokens = [token(0, 0, 3, 15, 0), token(0, 4, 2, 8, 0), token(1, 0, 2, 15, 0)];
+        let edits = [
+            SemanticTokensEdit {
+                start: 15,
+                delete_count: 0,
+                data: Some(vec![token(0, 3, 4, 12, 0)]),
+            },
+            SemanticTokensEdit {
+                start: 5,
+                delete_count: 5,
+                data: Some(vec![token(0, 4, 3, 8, 1)]),
+            },
+        ];
+        assert_eq!(
+            apply_edits(&tokens, &edits),
+            [
+                token(0, 0, 3, 15, 0),
+                token(0, 4, 3, 8, 1),
+                token(1, 0, 2, 15, 0),
+                token(0, 3, 4, 12, 0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,1144 @@
+//! Editor sessions sharing one gateway
+//!
+//! Several editors can connect to one gateway process, each served by its
+//! own `LspGateway`. They share the VFS, the language servers and the
+//! response caches; which documents an editor has open, and the text it
+//! sees, is tracked per session.
+//!
+//! The VFS holds the one true copy of a document. An editor's changes are
+//! written to it only on top of the version that editor last saw, so two
+//! editors typing into the same file cannot silently overwrite each other;
+//! the one that lost the race is told its copy is out of date. Language
+//! servers see each document once, at its VFS version, however many editors
+//! have it open.
+
+use dashmap::{DashMap, DashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::mpsc;
+use tower_lsp::lsp_types::*;
+use tower_lsp::{Client, LspService, Server};
+use vraftls_cache::CacheHierarchy;
+use vraftls_core::{ClientId, FileId, FileVersion, GatewayConfig, LanguageId, RaftGroupId, Result, SharedConfig, VRaftError};
+use vraftls_vfs::{TextPosition, TextRope, Vfs, VfsCommand, VfsHandle, VfsPath, VfsResponse, VfsTextEdit};
+
+use crate::capabilities::provider_registrations;
+use crate::editor_encoding::EditorConnection;
+use crate::extensions::ServerStatus;
+use crate::file_watch::{watched_changes, FileWatchers};
+use crate::gateway::LspGateway;
+use crate::metrics::{LspMetrics, MeteredService};
+use crate::passthrough::Passthrough;
+use crate::position_encoding::PositionEncoding;
+use crate::proxy::{LanguageServerPool, LanguageServerProxy, ServerNotification};
+use crate::remote::RemoteLsp;
+use crate::resume::{resume, save_sessions, SavedDocument, SavedSessions, SessionsChanged};
+use crate::router::{LspRouter, RouteDecision};
+use crate::transaction::{CommittedTransaction, LocalParticipant, RemoteParticipant, TransactionCoordinator};
+use crate::workspace_edit::{apply_text_edits, edit_paths, split_workspace_edit};
+use crate::workspace_symbol::RemoteSymbolSearch;
+
+/// Responses kept in the per-version response cache
+const RESPONSE_CACHE_ENTRIES: u64 = 10_000;
+
+/// State of a document an editor has open
+pub(crate) struct DocumentState {
+    pub language_id: LanguageId,
+    pub vfs_path: VfsPath,
+
+    /// The document as the editor has it
+    pub text: String,
+
+    /// VFS version the editor's text was last written at or matched;
+    /// `None` while the editor's copy differs from a newer VFS version
+    pub synced_version: Option<FileVersion>,
+
+    /// Changes in the text not yet written to the VFS
+    pub pending_changes: Vec<TextDocumentContentChangeEvent>,
+
+    /// Number of changes the editor made, to tell the last of a burst
+    pub change_generation: u64,
+}
+
+/// Diagnostics a language server last pushed for a file
+pub(crate) struct PublishedDiagnostics {
+    /// Result ID editors pulling the diagnostics get, to tell them apart
+    /// from the next ones
+    pub result_id: String,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A workspace edit committed to its files, which stay held until released
+pub(crate) struct CommittedEdit {
+    /// The edit in the groups holding its files, if it touched any
+    remote: Option<CommittedTransaction<RemoteParticipant>>,
+
+    /// The edit in the gateway's VFS, unless it did not apply there
+    local: Option<CommittedTransaction<LocalParticipant>>,
+}
+
+impl CommittedEdit {
+    /// Let go of the edit's files
+    pub(crate) async fn release(self) {
+        if let Some(remote) = self.remote {
+            remote.release().await;
+        }
+        if let Some(local) = self.local {
+            local.release().await;
+        }
+    }
+
+    /// Undo the edit, in the groups and in the gateway's VFS, and let go of
+    /// its files
+    pub(crate) async fn abort(self) {
+        if let Some(local) = self.local {
+            local.abort().await;
+        }
+        if let Some(remote) = self.remote {
+            if !remote.abort().await {
+                tracing::error!("Edit {} rejected by the editor was not undone in every group", remote.id());
+            }
+        }
+    }
+}
+
+/// Documents an editor has open, by the URI it uses
+pub(crate) type OpenDocuments = Arc<DashMap<Url, DocumentState>>;
+
+/// A connected editor
+#[derive(Clone)]
+struct Session {
+    client: Client,
+    open_documents: OpenDocuments,
+
+    /// Provider methods the editor registers dynamically
+    dynamic_methods: Arc<HashSet<String>>,
+
+    /// IDs of the registrations the editor was given, so restarted servers
+    /// do not register again
+    registered: Arc<DashSet<String>>,
+
+    /// Encoding of the positions the editor sends and expects
+    position_encoding: PositionEncoding,
+}
+
+impl Session {
+    /// Pass a server's registrations on to the editor
+    ///
+    /// Commands are always passed on, providers only if the editor
+    /// registers them dynamically.
+    async fn register(&self, language: &LanguageId, registrations: &[Registration]) {
+        // Registration IDs are per server; keep them apart
+        let registrations: Vec<_> = registrations
+            .iter()
+            .filter(|r| r.method == "workspace/executeCommand" || self.dynamic_methods.contains(&r.method))
+            .map(|r| Registration {
+                id: format!("{:?}/{}", language, r.id),
+                ..r.clone()
+            })
+            .filter(|r| self.registered.insert(r.id.clone()))
+            .collect();
+        if registrations.is_empty() {
+            return;
+        }
+        if let Err(e) = self.client.register_capability(registrations).await {
+            tracing::debug!("Editor refused registration from {:?}: {}", language, e);
+        }
+    }
+}
+
+/// The editors connected to a gateway and the state they share
+pub struct GatewaySessions {
+    /// Virtual file system
+    pub(crate) vfs: VfsHandle,
+
+    /// Language server proxy pool
+    pub(crate) ls_pool: Arc<LanguageServerPool>,
+
+    /// Request router
+    pub(crate) router: Arc<LspRouter>,
+
+    /// Sends workspace symbol queries to other nodes
+    pub(crate) remote_symbols: RemoteSymbolSearch,
+
+    /// Sends document requests to the nodes serving them
+    pub(crate) remote_lsp: RemoteLsp,
+
+    /// Edits committed through the leaders of the groups they touch
+    remote_edits: Arc<RemoteParticipant>,
+
+    /// Edits of this VFS, whether of files served here or copies of edits
+    /// committed through the leaders
+    local_edits: Arc<LocalParticipant>,
+
+    /// Pause in typing after which changes are written and forwarded
+    pub(crate) change_debounce: Duration,
+
+    /// Responses cached per document version
+    pub(crate) cache: CacheHierarchy,
+
+    /// Language server that produced each recent completion batch
+    pub(crate) completion_batches: DashMap<u64, LanguageId>,
+
+    /// Next completion batch ID
+    pub(crate) next_completion_batch: AtomicU64,
+
+    /// Connected editors
+    sessions: DashMap<ClientId, Session>,
+
+    /// Client ID counter
+    next_client_id: AtomicU64,
+
+    /// Editor that last changed a document or ran a command, which gets
+    /// the edits language servers ask for; 0 if none
+    active_client: AtomicU64,
+
+    /// Documents open on the language servers, with the VFS version they have
+    server_documents: DashMap<VfsPath, FileVersion>,
+
+    /// Log index writes to each document were last acknowledged at, by the
+    /// group they were written to; reads of the document wait for it
+    applied_indexes: DashMap<VfsPath, (RaftGroupId, u64)>,
+
+    /// Diagnostics pushed by the language servers, for editors that pull
+    /// them from servers which only push
+    diagnostics: DashMap<VfsPath, PublishedDiagnostics>,
+
+    /// Diagnostics result ID counter
+    next_diagnostics_result: AtomicU64,
+
+    /// Requests editors made and the gateway made of language servers
+    metrics: Arc<LspMetrics>,
+
+    /// Set when the open documents changed since they were saved
+    pub(crate) changed: SessionsChanged,
+}
+
+impl GatewaySessions {
+    /// Create the shared state, without any editor connected
+    pub fn new() -> Arc<Self> {
+        Self::with_config(&GatewayConfig::default())
+    }
+
+    /// Create the shared state for a configured gateway
+    pub fn with_config(config: &GatewayConfig) -> Arc<Self> {
+        let vfs = Arc::new(Vfs::new(RaftGroupId::new(1)));
+
+        // Forward notifications from the language servers to the editors
+        let (tx, rx) = mpsc::unbounded_channel();
+        let metrics = Arc::new(LspMetrics::new());
+        let ls_pool = LanguageServerPool::new()
+            .with_notifications(tx)
+            .with_metrics(metrics.clone())
+            .with_documents(vfs.clone())
+            .with_language_servers(config.language_servers.clone())
+            .with_limits(config.server_limits.clone())
+            .with_request_timeouts(config.server_timeouts.clone());
+        let ls_pool = Arc::new(ls_pool);
+        tokio::spawn(LanguageServerPool::run_eviction(Arc::downgrade(&ls_pool)));
+        let watchers = Arc::new(FileWatchers::new());
+        tokio::spawn(Self::watch_files(vfs.clone(), watchers.clone(), ls_pool.clone()));
+
+        let router = Arc::new(LspRouter::new());
+        let remote_lsp = RemoteLsp::default();
+        let sessions = Arc::new(Self {
+            local_edits: Arc::new(LocalParticipant::new(vfs.clone())),
+            vfs,
+            ls_pool,
+            remote_edits: Arc::new(RemoteParticipant::new(router.clone(), remote_lsp.clone())),
+            router,
+            remote_symbols: RemoteSymbolSearch::default(),
+            remote_lsp,
+            change_debounce: config.change_debounce,
+            cache: CacheHierarchy::new(RESPONSE_CACHE_ENTRIES),
+            completion_batches: DashMap::new(),
+            next_completion_batch: AtomicU64::new(1),
+            sessions: DashMap::new(),
+            next_client_id: AtomicU64::new(1),
+            active_client: AtomicU64::new(0),
+            server_documents: DashMap::new(),
+            applied_indexes: DashMap::new(),
+            diagnostics: DashMap::new(),
+            next_diagnostics_result: AtomicU64::new(1),
+            metrics,
+            changed: SessionsChanged::default(),
+        });
+        tokio::spawn(Self::forward_notifications(Arc::downgrade(&sessions), watchers, rx));
+
+        // Pick up where the previous gateway left off
+        if let Some(path) = &config.session_file {
+            match SavedSessions::load(path) {
+                Ok(Some(saved)) => {
+                    tokio::spawn(resume(sessions.clone(), saved));
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("Failed to read sessions from {}: {}", path.display(), e),
+            }
+            tokio::spawn(save_sessions(Arc::downgrade(&sessions), path.clone()));
+        }
+        sessions
+    }
+
+    /// Serve one editor connection until it closes
+    pub async fn serve<I, O>(self: &Arc<Self>, input: I, output: O)
+    where
+        I: AsyncRead + Unpin,
+        O: AsyncWrite,
+    {
+        let sessions = self.clone();
+        let (service, socket) = LspService::new(move |client| LspGateway::with_sessions(client, sessions));
+        let editor = EditorConnection::new(service.inner());
+        let service = MeteredService::new(editor.service(Passthrough::new(service)), self.metrics.clone());
+        Server::new(input, output, editor.socket(socket)).serve(service).await;
+    }
+
+    /// Metrics of the requests served by the gateway and its language servers
+    pub fn metrics(&self) -> &Arc<LspMetrics> {
+        &self.metrics
+    }
+
+    /// Router placing documents on cluster nodes
+    pub fn router(&self) -> &Arc<LspRouter> {
+        &self.router
+    }
+
+    /// Apply the settings shared by the cluster: the response cache TTL
+    /// and the language server commands
+    pub fn apply_shared_config(&self, config: &SharedConfig) {
+        self.cache.set_ttl(config.cache_ttl());
+        self.ls_pool.set_commands(config.ls_commands.clone());
+    }
+
+    /// Register a newly connected editor
+    pub(crate) fn connect(&self, client: Client) -> (ClientId, OpenDocuments) {
+        let client_id = ClientId::new(self.next_client_id.fetch_add(1, Ordering::SeqCst));
+        let open_documents = OpenDocuments::default();
+        self.sessions.insert(
+            client_id,
+            Session {
+                client,
+                open_documents: open_documents.clone(),
+                dynamic_methods: Default::default(),
+                registered: Default::default(),
+                position_encoding: PositionEncoding::Utf16,
+            },
+        );
+        tracing::info!("Editor connected: {:?}", client_id);
+        (client_id, open_documents)
+    }
+
+    /// Record the providers an editor registers dynamically
+    pub(crate) fn set_dynamic_methods(&self, client_id: ClientId, methods: HashSet<String>) {
+        if let Some(mut session) = self.sessions.get_mut(&client_id) {
+            session.dynamic_methods = Arc::new(methods);
+        }
+    }
+
+    /// Provider methods an editor registers dynamically
+    pub(crate) fn dynamic_methods(&self, client_id: ClientId) -> Arc<HashSet<String>> {
+        self.sessions
+            .get(&client_id)
+            .map(|s| s.dynamic_methods.clone())
+            .unwrap_or_default()
+    }
+
+    /// Record the position encoding an editor was given
+    pub(crate) fn set_position_encoding(&self, client_id: ClientId, encoding: PositionEncoding) {
+        if let Some(mut session) = self.sessions.get_mut(&client_id) {
+            session.position_encoding = encoding;
+        }
+    }
+
+    /// Position encoding of an editor; UTF-16 until it is initialized
+    pub(crate) fn position_encoding(&self, client_id: ClientId) -> PositionEncoding {
+        self.sessions
+            .get(&client_id)
+            .map_or(PositionEncoding::Utf16, |s| s.position_encoding)
+    }
+
+    /// Register the providers of the servers already running with an editor
+    /// that just initialized
+    pub(crate) async fn register_providers(&self, client_id: ClientId) {
+        let Some(session) = self.sessions.get(&client_id).map(|s| s.value().clone()) else {
+            return;
+        };
+        for server in self.ls_pool.running() {
+            if let Some(caps) = server.raw_capabilities() {
+                session.register(server.language(), &provider_registrations(server.language(), caps)).await;
+            }
+        }
+    }
+
+    /// Forget a disconnected editor and close what only it had open
+    pub(crate) fn disconnect(&self, client_id: ClientId) {
+        let Some((_, session)) = self.sessions.remove(&client_id) else {
+            return;
+        };
+        self.changed.mark();
+        let _ = self
+            .active_client
+            .compare_exchange(client_id.0, 0, Ordering::SeqCst, Ordering::SeqCst);
+        tracing::info!("Editor disconnected: {:?}", client_id);
+
+        let orphaned: Vec<(Url, VfsPath)> = session
+            .open_documents
+            .iter()
+            .filter(|doc| !self.is_open(&doc.vfs_path))
+            .map(|doc| (doc.key().clone(), doc.vfs_path.clone()))
+            .collect();
+        for (_, path) in &orphaned {
+            self.server_documents.remove(path);
+            self.applied_indexes.remove(path);
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let ls_pool = self.ls_pool.clone();
+        runtime.spawn(async move {
+            for (uri, path) in orphaned {
+                let Some(language) = path.language_id() else {
+                    continue;
+                };
+                if let Ok(ls) = ls_pool.get_or_spawn(language).await {
+                    ls.did_close(DidCloseTextDocumentParams {
+                        text_document: TextDocumentIdentifier { uri },
+                    })
+                    .await;
+                }
+            }
+        });
+    }
+
+    /// Make an editor the one that gets edits from language servers
+    pub(crate) fn set_active(&self, client_id: ClientId) {
+        self.active_client.store(client_id.0, Ordering::SeqCst);
+    }
+
+    /// Whether any editor has a file open
+    pub(crate) fn is_open(&self, path: &VfsPath) -> bool {
+        self.sessions
+            .iter()
+            .any(|s| s.open_documents.iter().any(|doc| &doc.vfs_path == path))
+    }
+
+    /// VFS version of a document the language servers have open
+    pub(crate) fn server_version(&self, path: &VfsPath) -> Option<FileVersion> {
+        self.server_documents.get(path).map(|v| *v)
+    }
+
+    /// Record the VFS version the language servers have of a document
+    pub(crate) fn set_server_version(&self, path: &VfsPath, version: Option<FileVersion>) {
+        match version {
+            Some(version) => self.server_documents.insert(path.clone(), version),
+            None => self.server_documents.remove(path).map(|(_, v)| v),
+        };
+    }
+
+    /// The documents editors have open, once each, as the VFS has them
+    pub(crate) async fn saved_sessions(&self) -> SavedSessions {
+        let mut documents = HashMap::new();
+        for session in self.sessions.iter() {
+            for doc in session.open_documents.iter() {
+                if documents.contains_key(&doc.vfs_path) {
+                    continue;
+                }
+                let Some(file) = self.vfs.get_file_by_path(&doc.vfs_path) else {
+                    continue;
+                };
+                let Ok(text) = self.vfs.get_content(file.id) else {
+                    continue;
+                };
+                let document = SavedDocument {
+                    uri: doc.key().clone(),
+                    language_id: doc.language_id.lsp_name().to_string(),
+                    path: doc.vfs_path.clone(),
+                    version: file.version.0,
+                    text,
+                };
+                documents.insert(doc.vfs_path.clone(), document);
+            }
+        }
+        SavedSessions {
+            initialize: self.ls_pool.client_params().await,
+            documents: documents.into_values().collect(),
+        }
+    }
+
+    /// Log index reads of a document in `group` must see, after the writes
+    /// made to it
+    pub(crate) fn applied_index(&self, path: &VfsPath, group: RaftGroupId) -> Option<u64> {
+        self.applied_indexes
+            .get(path)
+            .filter(|entry| entry.0 == group)
+            .map(|entry| entry.1)
+    }
+
+    /// Remember the log index a write to a document was acknowledged at
+    pub(crate) fn record_applied_index(&self, path: &VfsPath, group: RaftGroupId, index: u64) {
+        let mut entry = self.applied_indexes.entry(path.clone()).or_insert((group, index));
+        if entry.0 != group || entry.1 < index {
+            *entry = (group, index);
+        }
+    }
+
+    /// Forget the writes to a document no editor has open anymore
+    pub(crate) fn forget_applied_index(&self, path: &VfsPath) {
+        self.applied_indexes.remove(path);
+    }
+
+    /// Language server for a file, spawned if not running yet
+    pub(crate) async fn language_server(&self, path: &VfsPath) -> Option<Arc<LanguageServerProxy>> {
+        let lang_id = path.language_id()?;
+        self.ls_pool.get_or_spawn(lang_id).await.ok()
+    }
+
+    /// Remember the diagnostics a server pushed for a file
+    fn store_diagnostics(&self, path: VfsPath, diagnostics: Vec<Diagnostic>) {
+        let id = self.next_diagnostics_result.fetch_add(1, Ordering::SeqCst);
+        let published = PublishedDiagnostics {
+            result_id: format!("vraftls/{}", id),
+            diagnostics,
+        };
+        self.diagnostics.insert(path, published);
+    }
+
+    /// Report of the diagnostics last pushed for a file
+    ///
+    /// Unchanged if the editor already has them, by `previous_result_id`.
+    pub(crate) fn diagnostic_report(&self, path: &VfsPath, previous_result_id: Option<&str>) -> DocumentDiagnosticReport {
+        let Some(published) = self.diagnostics.get(path) else {
+            return DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport::default());
+        };
+        if previous_result_id == Some(published.result_id.as_str()) {
+            return DocumentDiagnosticReport::Unchanged(RelatedUnchangedDocumentDiagnosticReport {
+                related_documents: None,
+                unchanged_document_diagnostic_report: UnchangedDocumentDiagnosticReport {
+                    result_id: published.result_id.clone(),
+                },
+            });
+        }
+        DocumentDiagnosticReport::Full(RelatedFullDocumentDiagnosticReport {
+            related_documents: None,
+            full_document_diagnostic_report: FullDocumentDiagnosticReport {
+                result_id: Some(published.result_id.clone()),
+                items: published.diagnostics.clone(),
+            },
+        })
+    }
+
+    /// Files with pushed diagnostics
+    pub(crate) fn diagnosed_files(&self) -> Vec<VfsPath> {
+        self.diagnostics.iter().map(|e| e.key().clone()).collect()
+    }
+
+    /// Open a document in the VFS with an editor's text
+    ///
+    /// A file another editor has open keeps its content; the new editor's
+    /// copy counts as out of date until it matches again.
+    pub(crate) fn open_document(&self, path: &VfsPath, text: &str) -> Option<FileVersion> {
+        let Some(file) = self.vfs.get_file_by_path(path) else {
+            // Editors open files in directories the VFS may not have yet
+            if let Some(parent) = path.parent() {
+                self.vfs.apply(VfsCommand::CreateDirectory {
+                    path: parent,
+                    recursive: true,
+                });
+            }
+            return match self.vfs.apply(VfsCommand::CreateFile {
+                path: path.clone(),
+                content: text.to_string(),
+            }) {
+                VfsResponse::Created(file_id) => self.vfs.get_file(file_id).map(|f| f.version),
+                _ => None,
+            };
+        };
+
+        if self.vfs.get_content(file.id).is_ok_and(|content| content == text) {
+            return Some(file.version);
+        }
+        if self.is_open(path) {
+            return None;
+        }
+        self.write(file.id, text, file.version).ok()
+    }
+
+    /// Write an editor's text of a document to the VFS
+    ///
+    /// `changes` are the editor's changes since its copy was synced. Unless
+    /// one of them replaces the whole text, only the changes are written, as
+    /// text edits; otherwise the whole text is. The write only goes through
+    /// on top of the VFS version the editor's copy was synced at; returns
+    /// the version the text is now at.
+    pub(crate) fn sync_document(
+        &self,
+        doc: &DocumentState,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> Result<FileVersion> {
+        let file = self
+            .vfs
+            .get_file_by_path(&doc.vfs_path)
+            .ok_or_else(|| VRaftError::PathNotInWorkspace(doc.vfs_path.to_string()))?;
+
+        // Another editor, or an edit from a server, got there first
+        let content = self.vfs.get_content(file.id)?;
+        if content == doc.text {
+            return Ok(file.version);
+        }
+        match doc.synced_version {
+            Some(synced) if synced == file.version => match text_edits(&content, changes, &doc.text) {
+                Some(edits) => self.commit(
+                    VfsCommand::ApplyTextEdits {
+                        file_id: file.id,
+                        edits,
+                        expected_version: Some(synced.0),
+                    },
+                    file.id,
+                    synced,
+                ),
+                None => self.write(file.id, &doc.text, synced),
+            },
+            _ => Err(VRaftError::TransactionAborted(format!(
+                "{} was changed by another editor",
+                doc.vfs_path
+            ))),
+        }
+    }
+
+    fn write(&self, file_id: FileId, text: &str, expected: FileVersion) -> Result<FileVersion> {
+        let command = VfsCommand::UpdateFile {
+            file_id,
+            content: text.to_string(),
+            expected_version: Some(expected.0),
+        };
+        self.commit(command, file_id, expected)
+    }
+
+    /// Commit an editor's text of a document through the leader of its group
+    ///
+    /// The log index the leader committed it at is kept for later reads of
+    /// the document. Documents served here are left to `sync_document`.
+    pub(crate) async fn commit_document(&self, uri: &Url, path: &VfsPath, text: &str) -> Result<()> {
+        let Some(group) = self.router.group_for_file(path).await? else {
+            return Ok(());
+        };
+        let index = self.remote_edits.apply(group, document_edit(uri, text)).await?;
+        self.record_applied_index(path, group, index);
+        Ok(())
+    }
+
+    /// Apply a write to a file expected at a version
+    ///
+    /// Files a workspace edit holds are refused until it is done.
+    fn commit(&self, command: VfsCommand, file_id: FileId, expected: FileVersion) -> Result<FileVersion> {
+        if let Err(e) = self.local_edits.holds().check(&command, None) {
+            return Err(VRaftError::TransactionAborted(e.to_string()));
+        }
+        match self.vfs.apply(command) {
+            VfsResponse::Error(e) => Err(VRaftError::TransactionAborted(e.to_string())),
+            _ => Ok(self.vfs.get_file(file_id).map_or(expected.next(), |f| f.version)),
+        }
+    }
+
+    /// Connected editors, cloned out so no map lock is held across awaits
+    fn connected(&self) -> Vec<Session> {
+        self.sessions.iter().map(|s| s.value().clone()).collect()
+    }
+
+    /// The editor server edits go to: the active one, or any
+    fn edit_target(&self) -> Option<Session> {
+        let active = ClientId::new(self.active_client.load(Ordering::SeqCst));
+        self.sessions
+            .get(&active)
+            .map(|s| s.value().clone())
+            .or_else(|| self.connected().into_iter().next())
+    }
+
+    /// Commit a workspace edit to the files it touches
+    ///
+    /// Files of the cluster's groups are edited through the groups'
+    /// leaders, in every group or none, and the log index each group
+    /// committed at is kept for reads of its files; the gateway's VFS then
+    /// takes the same edit, so its copies match. Files served here are
+    /// edited in the gateway's VFS alone. The files stay held until the
+    /// returned edit is released.
+    pub(crate) async fn commit_edit(&self, edit: &WorkspaceEdit) -> Result<CommittedEdit> {
+        let paths = edit_paths(edit)?;
+        let local = || BTreeMap::from([(self.vfs.group_id(), edit.clone())]);
+        let local_coordinator = TransactionCoordinator::new(self.local_edits.clone());
+        let groups = match self.router.route_edit(&paths).await? {
+            RouteDecision::TwoPhaseCommit(groups) => groups,
+            _ => {
+                let committed = local_coordinator.execute(local()).await?;
+                return Ok(CommittedEdit {
+                    remote: None,
+                    local: Some(committed),
+                });
+            }
+        };
+        tracing::debug!(?groups, "Committing workspace edit through the group leaders");
+
+        let mut file_groups = HashMap::new();
+        for path in paths {
+            if let Some(group) = self.router.group_for_file(&path).await? {
+                file_groups.insert(path, group);
+            }
+        }
+        let parts = split_workspace_edit(edit, |path| file_groups.get(path).copied())?;
+        let remote = TransactionCoordinator::new(self.remote_edits.clone()).execute(parts).await?;
+        for (path, group) in &file_groups {
+            if let Some(index) = remote.applied_indexes().get(group) {
+                self.record_applied_index(path, *group, *index);
+            }
+        }
+
+        // The gateway's copies follow the groups, which hold the files of record
+        let local = match local_coordinator.execute(local()).await {
+            Ok(local) => Some(local),
+            Err(e) => {
+                tracing::warn!("Committed edit does not apply to the gateway's VFS: {}", e);
+                None
+            }
+        };
+        Ok(CommittedEdit {
+            remote: Some(remote),
+            local,
+        })
+    }
+
+    /// Apply an edit a language server asked for
+    ///
+    /// The edit is committed first, through the leaders of the groups it
+    /// touches, so it never lands in half of a refactoring; then the editor
+    /// gets it. Its files stay held until the editor answered, and the edit
+    /// is undone if the editor did not apply it.
+    async fn apply_edit(&self, params: ApplyWorkspaceEditParams) -> ApplyWorkspaceEditResponse {
+        let failed = |reason: String| ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some(reason),
+            failed_change: None,
+        };
+        let Some(session) = self.edit_target() else {
+            return failed("no editor connected".to_string());
+        };
+
+        let committed = match self.commit_edit(&params.edit).await {
+            Ok(committed) => committed,
+            Err(e) => return failed(e.to_string()),
+        };
+        let response = match session.client.apply_edit(params.edit).await {
+            Ok(response) => response,
+            Err(e) => failed(e.to_string()),
+        };
+        if response.applied {
+            committed.release().await;
+        } else {
+            committed.abort().await;
+        }
+        response
+    }
+
+    /// Relay language server notifications the editors need
+    async fn forward_notifications(
+        sessions: Weak<Self>,
+        watchers: Arc<FileWatchers>,
+        mut rx: mpsc::UnboundedReceiver<ServerNotification>,
+    ) {
+        while let Some(mut notification) = rx.recv().await {
+            let Some(sessions) = sessions.upgrade() else {
+                return;
+            };
+
+            // Positions reach editors in UTF-16 whatever the server uses
+            sessions.ls_pool.convert_from_server(&notification.language, &mut notification.params);
+            match notification.method.as_str() {
+                "workspace/applyEdit" => {
+                    let Some(reply) = notification.reply else {
+                        continue;
+                    };
+                    let params: ApplyWorkspaceEditParams = match serde_json::from_value(notification.params) {
+                        Ok(params) => params,
+                        Err(e) => {
+                            let _ = reply.send(Err(tower_lsp::jsonrpc::Error::invalid_params(e.to_string())));
+                            continue;
+                        }
+                    };
+                    // The editor may take a while to answer; keep relaying
+                    // other notifications meanwhile
+                    tokio::spawn(async move {
+                        let response = sessions.apply_edit(params).await;
+                        let _ = reply.send(
+                            serde_json::to_value(response)
+                                .map_err(|_| tower_lsp::jsonrpc::Error::internal_error()),
+                        );
+                    });
+                }
+                "client/registerCapability" => {
+                    let Ok(params) = serde_json::from_value::<RegistrationParams>(notification.params) else {
+                        continue;
+                    };
+                    // File watchers are served from the VFS, not the editor
+                    for registration in &params.registrations {
+                        if registration.method != "workspace/didChangeWatchedFiles" {
+                            continue;
+                        }
+                        match registration
+                            .register_options
+                            .clone()
+                            .map(serde_json::from_value::<DidChangeWatchedFilesRegistrationOptions>)
+                        {
+                            Some(Ok(options)) => {
+                                watchers.register(notification.language.clone(), registration.id.clone(), options)
+                            }
+                            _ => tracing::debug!("Invalid file watchers from {:?}", notification.language),
+                        }
+                    }
+
+                    for session in sessions.connected() {
+                        session.register(&notification.language, &params.registrations).await;
+                    }
+                }
+                "client/unregisterCapability" => {
+                    let Ok(params) = serde_json::from_value::<UnregistrationParams>(notification.params) else {
+                        continue;
+                    };
+                    for unregistration in params.unregisterations {
+                        if unregistration.method == "workspace/didChangeWatchedFiles" {
+                            watchers.unregister(&notification.language, &unregistration.id);
+                        }
+                    }
+                }
+                "textDocument/publishDiagnostics" => {
+                    let params: PublishDiagnosticsParams =
+                        match serde_json::from_value(notification.params) {
+                            Ok(params) => params,
+                            Err(e) => {
+                                tracing::warn!("Invalid diagnostics from {:?}: {}", notification.language, e);
+                                continue;
+                            }
+                        };
+                    if let Ok(path) = params.uri.to_file_path() {
+                        sessions.store_diagnostics(VfsPath::from(path), params.diagnostics.clone());
+                    }
+
+                    // Servers number documents by VFS version, which no
+                    // editor knows, so the version is left out
+                    for session in sessions.connected() {
+                        let Some(uri) = client_uri(&session.open_documents, &params.uri) else {
+                            tracing::debug!("Dropping diagnostics for {}", params.uri);
+                            continue;
+                        };
+                        session
+                            .client
+                            .publish_diagnostics(uri, params.diagnostics.clone(), None)
+                            .await;
+                    }
+                }
+                "window/workDoneProgress/create" => {
+                    let Some(reply) = notification.reply else {
+                        continue;
+                    };
+                    let params: WorkDoneProgressCreateParams = match serde_json::from_value(notification.params) {
+                        Ok(params) => params,
+                        Err(e) => {
+                            let _ = reply.send(Err(tower_lsp::jsonrpc::Error::invalid_params(e.to_string())));
+                            continue;
+                        }
+                    };
+                    // The server may report progress once every editor
+                    // knows the token
+                    let token = client_token(&notification.language, params.token);
+                    let language = notification.language;
+                    tokio::spawn(async move {
+                        for session in sessions.connected() {
+                            if let Err(e) = session
+                                .client
+                                .send_request::<request::WorkDoneProgressCreate>(WorkDoneProgressCreateParams {
+                                    token: token.clone(),
+                                })
+                                .await
+                            {
+                                tracing::debug!("Editor refused progress from {:?}: {}", language, e);
+                            }
+                        }
+                        let _ = reply.send(Ok(serde_json::Value::Null));
+                    });
+                }
+                "window/showMessageRequest" => {
+                    let Some(reply) = notification.reply else {
+                        continue;
+                    };
+                    let params: ShowMessageRequestParams = match serde_json::from_value(notification.params) {
+                        Ok(params) => params,
+                        Err(e) => {
+                            let _ = reply.send(Err(tower_lsp::jsonrpc::Error::invalid_params(e.to_string())));
+                            continue;
+                        }
+                    };
+                    // Prompts go to the editor the user works in; without
+                    // one, no action is chosen
+                    tokio::spawn(async move {
+                        let chosen = match sessions.edit_target() {
+                            Some(session) => session.client.send_request::<request::ShowMessageRequest>(params).await,
+                            None => Ok(None),
+                        };
+                        let _ = reply.send(chosen.map(|chosen| serde_json::to_value(chosen).unwrap_or_default()));
+                    });
+                }
+                "window/showDocument" => {
+                    let Some(reply) = notification.reply else {
+                        continue;
+                    };
+                    let mut params: ShowDocumentParams = match serde_json::from_value(notification.params) {
+                        Ok(params) => params,
+                        Err(e) => {
+                            let _ = reply.send(Err(tower_lsp::jsonrpc::Error::invalid_params(e.to_string())));
+                            continue;
+                        }
+                    };
+                    tokio::spawn(async move {
+                        let result = match sessions.edit_target() {
+                            Some(session) => {
+                                // Open documents are shown under the editor's URI
+                                if let Some(uri) = client_uri(&session.open_documents, &params.uri) {
+                                    params.uri = uri;
+                                }
+                                session.client.send_request::<request::ShowDocument>(params).await
+                            }
+                            None => Ok(ShowDocumentResult { success: false }),
+                        };
+                        let _ = reply.send(result.map(|result| serde_json::to_value(result).unwrap_or_default()));
+                    });
+                }
+                "experimental/serverStatus" => {
+                    for session in sessions.connected() {
+                        session
+                            .client
+                            .send_notification::<ServerStatus>(notification.params.clone())
+                            .await;
+                    }
+                }
+                "$/progress" => {
+                    let Ok(params) = serde_json::from_value::<ProgressParams>(notification.params) else {
+                        continue;
+                    };
+                    let token = client_token(&notification.language, params.token);
+                    for session in sessions.connected() {
+                        session
+                            .client
+                            .send_notification::<notification::Progress>(ProgressParams {
+                                token: token.clone(),
+                                value: params.value.clone(),
+                            })
+                            .await;
+                    }
+                }
+                method => {
+                    tracing::debug!("Ignoring notification {} from {:?}", method, notification.language);
+                }
+            }
+        }
+    }
+
+    /// Tell language servers about VFS changes to the files they watch
+    ///
+    /// Events that arrive together, e.g. from one transaction, reach each
+    /// server as one notification.
+    async fn watch_files(vfs: VfsHandle, watchers: Arc<FileWatchers>, ls_pool: Arc<LanguageServerPool>) {
+        let mut events = vfs.subscribe();
+
+        // Renames only carry the new path; remember the old ones
+        let mut paths: HashMap<FileId, VfsPath> = vfs
+            .all_file_ids()
+            .into_iter()
+            .filter_map(|id| Some((id, vfs.get_file(id)?.path)))
+            .collect();
+        drop(vfs);
+
+        loop {
+            let mut batch = match events.recv().await {
+                Ok(event) => vec![event],
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("File watchers missed {} VFS changes", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            loop {
+                match events.try_recv() {
+                    Ok(event) => batch.push(event),
+                    Err(TryRecvError::Lagged(skipped)) => {
+                        tracing::warn!("File watchers missed {} VFS changes", skipped);
+                    }
+                    Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                }
+            }
+
+            let mut changes: HashMap<LanguageId, Vec<FileEvent>> = HashMap::new();
+            for event in batch {
+                let old_path = match event.change_type {
+                    vraftls_vfs::FileChangeType::Deleted => paths.remove(&event.file_id),
+                    _ => paths.insert(event.file_id, event.path.clone()),
+                };
+                for (path, change) in watched_changes(event.change_type, &event.path, old_path.as_ref()) {
+                    let Ok(uri) = Url::from_file_path(path.to_path_buf()) else {
+                        continue;
+                    };
+                    for language in watchers.watching(&path, change) {
+                        changes.entry(language).or_default().push(FileEvent::new(uri.clone(), change));
+                    }
+                }
+            }
+
+            for (language, changes) in changes {
+                ls_pool
+                    .did_change_watched_files(&language, DidChangeWatchedFilesParams { changes })
+                    .await;
+            }
+        }
+    }
+}
+
+/// Convert a URI from a language server back to the one an editor uses
+///
+/// Open documents keep the URI the editor opened them with; other files
+/// get a file URI for their path. URIs the editor opened documents with,
+/// e.g. those of notebook cells, are kept.
+pub(crate) fn client_uri(open_documents: &DashMap<Url, DocumentState>, uri: &Url) -> Option<Url> {
+    if open_documents.contains_key(uri) {
+        return Some(uri.clone());
+    }
+    let path = VfsPath::from(uri.to_file_path().ok()?);
+    open_documents
+        .iter()
+        .find(|doc| doc.vfs_path == path)
+        .map(|doc| doc.key().clone())
+        .or_else(|| Url::from_file_path(path.to_path_buf()).ok())
+}
+
+/// Progress token the editor sees for a server's token
+///
+/// Servers pick their tokens independently, so they are prefixed with
+/// the server's language to keep them apart.
+fn client_token(language: &LanguageId, token: NumberOrString) -> NumberOrString {
+    let token = match token {
+        NumberOrString::Number(n) => n.to_string(),
+        NumberOrString::String(s) => s,
+    };
+    NumberOrString::String(format!("vraftls/{:?}/{}", language, token))
+}
+
+/// An editor's text after a change notification
+pub(crate) fn apply_content_changes(text: &str, changes: &[TextDocumentContentChangeEvent]) -> String {
+    changes.iter().fold(text.to_string(), |text, change| match change.range {
+        Some(range) => apply_text_edits(&text, &[TextEdit::new(range, change.text.clone())]),
+        None => change.text.clone(),
+    })
+}
+
+/// Edit setting the whole text of a document, creating it if need be
+fn document_edit(uri: &Url, text: &str) -> WorkspaceEdit {
+    let create = CreateFile {
+        uri: uri.clone(),
+        options: Some(CreateFileOptions {
+            overwrite: None,
+            ignore_if_exists: Some(true),
+        }),
+        annotation_id: None,
+    };
+    let replace = TextDocumentEdit {
+        text_document: OptionalVersionedTextDocumentIdentifier {
+            uri: uri.clone(),
+            version: None,
+        },
+        edits: vec![OneOf::Left(TextEdit {
+            range: Range::new(Position::new(0, 0), Position::new(u32::MAX, 0)),
+            new_text: text.to_string(),
+        })],
+    };
+    WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Operations(vec![
+            DocumentChangeOperation::Op(ResourceOp::Create(create)),
+            DocumentChangeOperation::Edit(replace),
+        ])),
+        ..Default::default()
+    }
+}
+
+/// An editor's changes as text edits of the VFS content they were made to
+///
+/// `None` if a change replaces the whole text, or the edits would not lead
+/// to the editor's text, e.g. after a change the editor sent out of order.
+fn text_edits(content: &str, changes: &[TextDocumentContentChangeEvent], text: &str) -> Option<Vec<VfsTextEdit>> {
+    let position = |position: Position| TextPosition {
+        line: position.line,
+        character: position.character,
+    };
+    let edits = changes
+        .iter()
+        .map(|change| {
+            let range = change.range?;
+            Some(VfsTextEdit {
+                start: position(range.start),
+                end: position(range.end),
+                new_text: change.text.clone(),
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut edited = TextRope::from(content);
+    for edit in &edits {
+        edited.replace(edit.start, edit.end, &edit.new_text);
+    }
+    (edited == *text).then_some(edits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workspace_edit::workspace_edit_command;
+
+    fn change(range: Option<((u32, u32), (u32, u32))>, text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: range.map(|((l0, c0), (l1, c1))| Range::new(Position::new(l0, c0), Position::new(l1, c1))),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_changes_as_text_edits() {
+        let content = "fn main() {\n}\n";
+        let changes = [change(Some(((0, 11), (0, 11))), "\n    run();"), change(Some(((1, 10), (1, 10))), " // 😀")];
+        let text = apply_content_changes(content, &changes);
+        let edits = text_edits(content, &changes, &text).unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[1].start, TextPosition { line: 1, character: 10 });
+
+        // A whole-text change, or changes leading elsewhere, go as the text
+        let changes = [change(None, "fn main() {}\n")];
+        assert!(text_edits(content, &changes, "fn main() {}\n").is_none());
+        let changes = [change(Some(((0, 0), (0, 0))), "//")];
+        assert!(text_edits(content, &changes, "fn main() {}\n").is_none());
+    }
+
+    #[test]
+    fn test_document_edit() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        let uri = Url::from_file_path("/src/a.rs").unwrap();
+        let path = VfsPath::new("/src/a.rs");
+        let content = |vfs: &Vfs| vfs.get_content(vfs.get_file_by_path(&path).unwrap().id).unwrap();
+
+        // The leader may not have the document yet
+        let command = workspace_edit_command(&vfs, &document_edit(&uri, "fn a() {}\n")).unwrap();
+        assert!(!matches!(vfs.apply(command), VfsResponse::Error(_)));
+        assert_eq!(content(&vfs), "fn a() {}\n");
+
+        let command = workspace_edit_command(&vfs, &document_edit(&uri, "fn b() {}\n")).unwrap();
+        assert!(!matches!(vfs.apply(command), VfsResponse::Error(_)));
+        assert_eq!(content(&vfs), "fn b() {}\n");
+    }
+}
@@ -0,0 +1,843 @@
+//! Two-phase commit of edits spanning several Raft groups
+//!
+//! Each group applies a `VfsCommand::Transaction` atomically, but a rename
+//! that touches files of two groups needs both transactions or neither. The
+//! coordinator first prepares each group's part of the edit on the group's
+//! leader: the leader builds the part's commands against its own files, pins
+//! every update to the version it read, and holds the files, refusing other
+//! writes to them. Only when all groups prepared does it commit the parts,
+//! each under its hold. If a commit fails, every group is aborted: parts
+//! already committed are undone while their files are still held, the
+//! others are dropped. The holds are released once the caller is done with
+//! the edit. A part the coordinator never commits, as when it is lost after
+//! preparing, is dropped with its hold once the hold expires.
+//!
+//! The gateway reaches the groups' leaders with [`RemoteParticipant`]; a
+//! gateway serving every file itself takes part with [`LocalParticipant`].
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tower_lsp::lsp_types::WorkspaceEdit;
+use vraftls_core::{FileId, RaftGroupId, Result, Timestamp, TransactionId, VRaftError};
+use vraftls_vfs::{Vfs, VfsCommand, VfsFile, VfsHandle, VfsResponse, WriteHolds, HOLD_TIMEOUT};
+
+use crate::remote::{RemoteLsp, RemoteWrite, WriteStep};
+use crate::router::LspRouter;
+use crate::workspace_edit::workspace_edit_command;
+
+/// Method transaction steps are routed as; a write, so they reach leaders
+const TRANSACTION_METHOD: &str = "workspace/applyEdit";
+
+/// A Raft group taking part in a transaction
+pub trait TransactionParticipant: Send + Sync + 'static {
+    /// Check a group's part of an edit against the group's files, and hold
+    /// the files it touches
+    fn prepare(
+        &self,
+        group: RaftGroupId,
+        transaction: TransactionId,
+        edit: WorkspaceEdit,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Apply a prepared part as one transaction of the group
+    ///
+    /// Returns the log index it was committed at, for groups with a log.
+    fn commit(&self, group: RaftGroupId, transaction: TransactionId) -> impl Future<Output = Result<Option<u64>>> + Send;
+
+    /// Undo a committed part, or drop a prepared one, and release its hold
+    fn abort(&self, group: RaftGroupId, transaction: TransactionId) -> impl Future<Output = Result<()>> + Send;
+
+    /// Release the hold of a committed part
+    fn release(&self, group: RaftGroupId, transaction: TransactionId) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// A group's part of a transaction, once prepared
+struct Prepared {
+    commands: Vec<VfsCommand>,
+    images: Vec<VfsFile>,
+
+    /// Responses of the commands, once committed
+    responses: Option<Vec<VfsResponse>>,
+}
+
+/// Transactions prepared against a group's files, as its leader keeps them
+///
+/// Committed parts are kept until they are aborted or released. Holds
+/// expire after `HOLD_TIMEOUT`, after which parts can no longer be
+/// committed; parts left uncommitted are then dropped.
+pub struct PreparedTransactions {
+    holds: Arc<WriteHolds>,
+    prepared: Mutex<HashMap<TransactionId, Prepared>>,
+    hold_timeout: Duration,
+}
+
+impl PreparedTransactions {
+    pub fn new(holds: Arc<WriteHolds>) -> Self {
+        Self {
+            holds,
+            prepared: Mutex::new(HashMap::new()),
+            hold_timeout: HOLD_TIMEOUT,
+        }
+    }
+
+    /// Hold the files of prepared parts for `timeout` instead of `HOLD_TIMEOUT`
+    pub fn with_hold_timeout(mut self, timeout: Duration) -> Self {
+        self.hold_timeout = timeout;
+        self
+    }
+
+    /// Holds of the prepared parts, for the group's other writes to check
+    pub fn holds(&self) -> &Arc<WriteHolds> {
+        &self.holds
+    }
+
+    /// Prepare a group's part of an edit against its files
+    ///
+    /// The commands are built from the files as they are, updates pinned to
+    /// the versions read, and the files they touch are held. Fails if the
+    /// edit does not apply, or another transaction holds its files.
+    pub fn prepare(&self, vfs: &Vfs, transaction: TransactionId, edit: &WorkspaceEdit) -> Result<()> {
+        self.expire();
+        let commands = match workspace_edit_command(vfs, edit)? {
+            VfsCommand::Transaction { commands } => commands,
+            command => vec![command],
+        };
+        let images = images(vfs, &commands)?;
+        let commands = pin_versions(commands, &images);
+        self.holds
+            .hold(
+                transaction,
+                vfs,
+                &VfsCommand::Transaction {
+                    commands: commands.clone(),
+                },
+                self.hold_timeout,
+            )
+            .map_err(|e| VRaftError::TransactionAborted(e.to_string()))?;
+
+        // A write may have landed between reading the files and holding them
+        let changed = images
+            .iter()
+            .find(|image| vfs.get_file(image.id).is_none_or(|file| file.version != image.version));
+        if let Some(image) = changed {
+            self.holds.release(transaction);
+            return Err(VRaftError::TransactionAborted(format!(
+                "{} changed while preparing",
+                image.path
+            )));
+        }
+
+        self.lock().insert(
+            transaction,
+            Prepared {
+                commands,
+                images,
+                responses: None,
+            },
+        );
+        Ok(())
+    }
+
+    /// Commands of a prepared part, to commit under its hold
+    ///
+    /// Fails once the hold expired, or for a part not prepared here, as
+    /// after a leader change.
+    pub fn commands(&self, transaction: TransactionId) -> Result<Vec<VfsCommand>> {
+        let prepared = self.lock();
+        match prepared.get(&transaction) {
+            Some(part) if part.responses.is_some() => Err(VRaftError::TransactionAborted(format!(
+                "transaction {} is already committed",
+                transaction
+            ))),
+            Some(part) if self.holds.is_held(transaction) => Ok(part.commands.clone()),
+            _ => Err(VRaftError::TransactionAborted(format!(
+                "transaction {} is not prepared",
+                transaction
+            ))),
+        }
+    }
+
+    /// Record the responses a part was committed with
+    pub fn committed(&self, transaction: TransactionId, responses: Vec<VfsResponse>) {
+        if let Some(part) = self.lock().get_mut(&transaction) {
+            part.responses = Some(responses);
+        }
+    }
+
+    /// Commands undoing a committed part; `None` if it was not committed
+    ///
+    /// The part is forgotten, but its files stay held until released, so
+    /// the undo applies under the hold.
+    pub fn abort(&self, transaction: TransactionId) -> Option<Vec<VfsCommand>> {
+        let part = self.lock().remove(&transaction)?;
+        let responses = part.responses?;
+        Some(undo_commands(&part.commands, &responses, &part.images))
+    }
+
+    /// Forget a part and let go of its files
+    pub fn release(&self, transaction: TransactionId) {
+        self.lock().remove(&transaction);
+        self.holds.release(transaction);
+    }
+
+    /// Drop the uncommitted parts whose hold expired, their coordinator
+    /// having given up on them or been lost
+    ///
+    /// Committed parts wait for their coordinator to release or undo them.
+    fn expire(&self) {
+        let mut prepared = self.lock();
+        prepared.retain(|transaction, part| {
+            let expired = part.responses.is_none() && !self.holds.is_held(*transaction);
+            if expired {
+                tracing::warn!(%transaction, "dropping prepared transaction its coordinator never committed");
+                self.holds.release(*transaction);
+            }
+            !expired
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<TransactionId, Prepared>> {
+        self.prepared.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Files commands update, delete or rename, as they are now, with their
+/// content
+fn images(vfs: &Vfs, commands: &[VfsCommand]) -> Result<Vec<VfsFile>> {
+    let mut images: Vec<VfsFile> = Vec::new();
+    for command in commands {
+        let (file_id, expected_version) = match command {
+            VfsCommand::CreateFile { path, .. } => {
+                if vfs.get_file_by_path(path).is_some() {
+                    return Err(VRaftError::FileExists(path.to_string()));
+                }
+                continue;
+            }
+            VfsCommand::UpdateFile {
+                file_id,
+                expected_version,
+                ..
+            }
+            | VfsCommand::ApplyTextEdits {
+                file_id,
+                expected_version,
+                ..
+            } => (*file_id, *expected_version),
+            VfsCommand::DeleteFile { file_id } | VfsCommand::RenameFile { file_id, .. } => (*file_id, None),
+            // Directories have no content to roll back; files in deleted
+            // ones are deleted by commands of their own
+            VfsCommand::CreateDirectory { .. } | VfsCommand::DeleteDirectory { .. } => continue,
+            other => {
+                return Err(VRaftError::TransactionAborted(format!(
+                    "unsupported command in transaction: {:?}",
+                    other
+                )))
+            }
+        };
+        if images.iter().any(|f| f.id == file_id) {
+            continue;
+        }
+
+        let file = vfs.get_file(file_id).ok_or(VRaftError::FileNotFound(file_id))?;
+        if let Some(expected) = expected_version.filter(|v| *v != file.version.0) {
+            return Err(VRaftError::TransactionAborted(format!(
+                "{} changed: expected version {}, found {}",
+                file.path, expected, file.version.0
+            )));
+        }
+        // Rolling back needs the content
+        vfs.get_content(file_id)?;
+        images.push(file);
+    }
+    Ok(images)
+}
+
+/// A VFS taking part in transactions itself, as the VFS of a gateway
+/// serving every file does
+pub struct LocalParticipant {
+    vfs: VfsHandle,
+    transactions: PreparedTransactions,
+}
+
+impl LocalParticipant {
+    pub fn new(vfs: VfsHandle) -> Self {
+        Self {
+            vfs,
+            transactions: PreparedTransactions::new(Arc::new(WriteHolds::new())),
+        }
+    }
+
+    /// Hold the files of prepared transactions for `timeout`
+    pub fn with_hold_timeout(mut self, timeout: Duration) -> Self {
+        self.transactions = self.transactions.with_hold_timeout(timeout);
+        self
+    }
+
+    /// Holds of the prepared transactions, for other writes to check
+    pub fn holds(&self) -> &Arc<WriteHolds> {
+        self.transactions.holds()
+    }
+
+    fn apply(&self, commands: Vec<VfsCommand>) -> Result<Vec<VfsResponse>> {
+        match self.vfs.apply(VfsCommand::Transaction { commands }) {
+            VfsResponse::Transaction(responses) => Ok(responses),
+            VfsResponse::Error(e) => Err(VRaftError::TransactionAborted(e.to_string())),
+            other => Err(VRaftError::Internal(format!(
+                "unexpected transaction response: {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+impl TransactionParticipant for LocalParticipant {
+    async fn prepare(&self, _group: RaftGroupId, transaction: TransactionId, edit: WorkspaceEdit) -> Result<()> {
+        self.transactions.prepare(&self.vfs, transaction, &edit)
+    }
+
+    async fn commit(&self, _group: RaftGroupId, transaction: TransactionId) -> Result<Option<u64>> {
+        let responses = self.apply(self.transactions.commands(transaction)?)?;
+        self.transactions.committed(transaction, responses);
+        Ok(None)
+    }
+
+    async fn abort(&self, _group: RaftGroupId, transaction: TransactionId) -> Result<()> {
+        let undone = match self.transactions.abort(transaction) {
+            Some(undo) => self.apply(undo).map(|_| ()),
+            None => Ok(()),
+        };
+        self.transactions.release(transaction);
+        undone
+    }
+
+    async fn release(&self, _group: RaftGroupId, transaction: TransactionId) -> Result<()> {
+        self.transactions.release(transaction);
+        Ok(())
+    }
+}
+
+/// Groups taking part through their leaders, as the gateway reaches them
+pub struct RemoteParticipant {
+    router: Arc<LspRouter>,
+    remote: RemoteLsp,
+}
+
+impl RemoteParticipant {
+    pub fn new(router: Arc<LspRouter>, remote: RemoteLsp) -> Self {
+        Self { router, remote }
+    }
+
+    /// Send a step of a transaction to the group's leader, following
+    /// leader changes
+    async fn send(&self, group: RaftGroupId, step: WriteStep) -> Result<Option<u64>> {
+        let write = RemoteWrite { group, step };
+        let ack = self
+            .router
+            .send_to_leader(group, TRANSACTION_METHOD, |node| {
+                let write = write.clone();
+                async move {
+                    let addr = self.router.node_addr(node).await.ok_or(VRaftError::NodeUnreachable(node))?;
+                    self.remote.write(addr, &write).await
+                }
+            })
+            .await?;
+        Ok(ack.applied_index)
+    }
+
+    /// Apply an edit of one group's files on its own, on the group's leader
+    ///
+    /// Returns the log index the leader committed it at.
+    pub async fn apply(&self, group: RaftGroupId, edit: WorkspaceEdit) -> Result<u64> {
+        self.send(group, WriteStep::Apply { edit })
+            .await?
+            .ok_or_else(|| VRaftError::Internal(format!("group {} applied an edit without a log index", group)))
+    }
+}
+
+impl TransactionParticipant for RemoteParticipant {
+    async fn prepare(&self, group: RaftGroupId, transaction: TransactionId, edit: WorkspaceEdit) -> Result<()> {
+        self.send(group, WriteStep::Prepare { transaction, edit }).await.map(|_| ())
+    }
+
+    async fn commit(&self, group: RaftGroupId, transaction: TransactionId) -> Result<Option<u64>> {
+        self.send(group, WriteStep::Commit { transaction }).await
+    }
+
+    async fn abort(&self, group: RaftGroupId, transaction: TransactionId) -> Result<()> {
+        self.send(group, WriteStep::Abort { transaction }).await.map(|_| ())
+    }
+
+    async fn release(&self, group: RaftGroupId, transaction: TransactionId) -> Result<()> {
+        self.send(group, WriteStep::Release { transaction }).await.map(|_| ())
+    }
+}
+
+/// Runs transactions across Raft groups
+pub struct TransactionCoordinator<P> {
+    participant: Arc<P>,
+}
+
+impl<P> Clone for TransactionCoordinator<P> {
+    fn clone(&self) -> Self {
+        Self {
+            participant: self.participant.clone(),
+        }
+    }
+}
+
+impl<P: TransactionParticipant> TransactionCoordinator<P> {
+    pub fn new(participant: Arc<P>) -> Self {
+        Self { participant }
+    }
+
+    /// Commit each group's part of an edit, in all groups or in none
+    ///
+    /// A single group is prepared too, so its part can be undone like any
+    /// other. The files stay held once committed; the caller releases them
+    /// with [`CommittedTransaction::release`], or undoes the edit with
+    /// [`CommittedTransaction::abort`].
+    pub async fn execute(&self, parts: BTreeMap<RaftGroupId, WorkspaceEdit>) -> Result<CommittedTransaction<P>> {
+        let mut committed = CommittedTransaction {
+            participant: self.participant.clone(),
+            transaction: next_transaction(),
+            groups: parts.keys().copied().collect(),
+            applied_indexes: BTreeMap::new(),
+        };
+
+        if let Err(e) = self.prepare(committed.transaction, parts).await {
+            committed.abort().await;
+            return Err(e);
+        }
+        for group in committed.groups.clone() {
+            match self.participant.commit(group, committed.transaction).await {
+                Ok(Some(index)) => {
+                    committed.applied_indexes.insert(group, index);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!(%group, error = %e, "transaction commit failed, rolling back");
+                    committed.abort().await;
+                    return Err(VRaftError::TransactionAborted(format!("group {}: {}", group, e)));
+                }
+            }
+        }
+        Ok(committed)
+    }
+
+    /// Prepare every group at once; fails if any group does
+    async fn prepare(&self, transaction: TransactionId, parts: BTreeMap<RaftGroupId, WorkspaceEdit>) -> Result<()> {
+        let mut preparing = tokio::task::JoinSet::new();
+        for (group, edit) in parts {
+            let participant = self.participant.clone();
+            preparing.spawn(async move { (group, participant.prepare(group, transaction, edit).await) });
+        }
+
+        // Every group answers before any is aborted, so none stays held
+        let mut failure = None;
+        while let Some(joined) = preparing.join_next().await {
+            let error = match joined {
+                Ok((_, Ok(()))) => continue,
+                Ok((group, Err(e))) => VRaftError::TransactionAborted(format!("group {}: {}", group, e)),
+                Err(e) => VRaftError::Internal(e.to_string()),
+            };
+            failure.get_or_insert(error);
+        }
+        failure.map_or(Ok(()), Err)
+    }
+}
+
+/// A transaction committed in every group, with its files still held
+pub struct CommittedTransaction<P: TransactionParticipant> {
+    participant: Arc<P>,
+    transaction: TransactionId,
+    groups: Vec<RaftGroupId>,
+    applied_indexes: BTreeMap<RaftGroupId, u64>,
+}
+
+impl<P: TransactionParticipant> CommittedTransaction<P> {
+    pub fn id(&self) -> TransactionId {
+        self.transaction
+    }
+
+    /// Log index each group committed its part at
+    pub fn applied_indexes(&self) -> &BTreeMap<RaftGroupId, u64> {
+        &self.applied_indexes
+    }
+
+    /// Let go of the files in every group
+    ///
+    /// A group that cannot be reached lets go once the hold expires.
+    pub async fn release(self) {
+        for group in &self.groups {
+            if let Err(e) = self.participant.release(*group, self.transaction).await {
+                tracing::warn!(%group, error = %e, "failed to release transaction");
+            }
+        }
+    }
+
+    /// Undo the transaction in every group, last first, and let go of the
+    /// files; returns whether every group was undone
+    pub async fn abort(&self) -> bool {
+        let mut undone = true;
+        for group in self.groups.iter().rev() {
+            if let Err(e) = self.participant.abort(*group, self.transaction).await {
+                tracing::error!(%group, error = %e, "transaction rollback failed");
+                undone = false;
+            }
+        }
+        undone
+    }
+}
+
+/// ID for a new transaction, unique across gateways
+fn next_transaction() -> TransactionId {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    TransactionId::new((Timestamp::now().0 << 20) ^ seq ^ (u64::from(std::process::id()) << 44))
+}
+
+/// Require updates without a version to find the version prepared against
+fn pin_versions(commands: Vec<VfsCommand>, images: &[VfsFile]) -> Vec<VfsCommand> {
+    let mut pinned = HashSet::new();
+    commands
+        .into_iter()
+        .map(|command| match command {
+            // Only the first update of a file; later ones see its new version
+            VfsCommand::UpdateFile {
+                file_id,
+                content,
+                expected_version: None,
+            } if pinned.insert(file_id) => VfsCommand::UpdateFile {
+                file_id,
+                content,
+                expected_version: images.iter().find(|f| f.id == file_id).map(|f| f.version.0),
+            },
+            command => command,
+        })
+        .collect()
+}
+
+/// Commands restoring the files a committed transaction changed
+///
+/// Every touched file goes back to its prepared image; deleted files are
+/// created again, under a new ID, as are deleted directories. Directories
+/// the transaction created are left in place.
+fn undo_commands(commands: &[VfsCommand], responses: &[VfsResponse], images: &[VfsFile]) -> Vec<VfsCommand> {
+    let image = |file_id: FileId| images.iter().find(|f| f.id == file_id);
+    let content = |file: &VfsFile| file.content_string().unwrap_or_default();
+    let deleted: HashSet<FileId> = commands
+        .iter()
+        .filter_map(|c| match c {
+            VfsCommand::DeleteFile { file_id } => Some(*file_id),
+            _ => None,
+        })
+        .collect();
+
+    let mut restored = HashSet::new();
+    let mut undo = Vec::new();
+    for (command, response) in commands.iter().zip(responses).rev() {
+        match (command, response) {
+            (VfsCommand::CreateFile { .. }, VfsResponse::Created(file_id)) => {
+                undo.push(VfsCommand::DeleteFile { file_id: *file_id })
+            }
+            (VfsCommand::DeleteDirectory { path, .. }, _) => undo.push(VfsCommand::CreateDirectory {
+                path: path.clone(),
+                recursive: true,
+            }),
+            (VfsCommand::DeleteFile { file_id }, _) => {
+                if let Some(file) = image(*file_id) {
+                    undo.push(VfsCommand::CreateFile {
+                        path: file.path.clone(),
+                        content: content(file),
+                    });
+                }
+            }
+            // Restored once, from the image, after undoing later commands
+            (
+                VfsCommand::UpdateFile { file_id, .. }
+                | VfsCommand::ApplyTextEdits { file_id, .. }
+                | VfsCommand::RenameFile { file_id, .. },
+                _,
+            )
+                if !deleted.contains(file_id) && restored.insert(*file_id) =>
+            {
+                if let Some(file) = image(*file_id) {
+                    restored_file(file, &content(file), commands, &mut undo);
+                }
+            }
+            _ => {}
+        }
+    }
+    undo
+}
+
+/// Commands putting a file back to its image's path and content
+fn restored_file(file: &VfsFile, content: &str, commands: &[VfsCommand], undo: &mut Vec<VfsCommand>) {
+    let renamed = commands
+        .iter()
+        .any(|c| matches!(c, VfsCommand::RenameFile { file_id, .. } if *file_id == file.id));
+    let updated = commands.iter().any(|c| {
+        matches!(c, VfsCommand::UpdateFile { file_id, .. } | VfsCommand::ApplyTextEdits { file_id, .. } if *file_id == file.id)
+    });
+    if renamed {
+        undo.push(VfsCommand::RenameFile {
+            file_id: file.id,
+            new_path: file.path.clone(),
+        });
+    }
+    if updated {
+        undo.push(VfsCommand::UpdateFile {
+            file_id: file.id,
+            content: content.to_string(),
+            expected_version: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower_lsp::lsp_types::{
+        DeleteFile, DocumentChangeOperation, DocumentChanges, OneOf, OptionalVersionedTextDocumentIdentifier, Position,
+        Range, RenameFile, ResourceOp, TextDocumentEdit, TextEdit, Url,
+    };
+    use vraftls_core::FileVersion;
+    use vraftls_vfs::VfsPath;
+
+    /// Groups sharing one VFS, failing every commit to one of them
+    struct FailingGroup {
+        groups: BTreeMap<RaftGroupId, LocalParticipant>,
+        failing: RaftGroupId,
+    }
+
+    impl FailingGroup {
+        fn new(vfs: &VfsHandle, groups: &[u64], failing: u64) -> Self {
+            Self {
+                groups: groups
+                    .iter()
+                    .map(|group| (RaftGroupId::new(*group), LocalParticipant::new(vfs.clone())))
+                    .collect(),
+                failing: RaftGroupId::new(failing),
+            }
+        }
+    }
+
+    impl TransactionParticipant for FailingGroup {
+        async fn prepare(&self, group: RaftGroupId, transaction: TransactionId, edit: WorkspaceEdit) -> Result<()> {
+            self.groups[&group].prepare(group, transaction, edit).await
+        }
+
+        async fn commit(&self, group: RaftGroupId, transaction: TransactionId) -> Result<Option<u64>> {
+            if group == self.failing {
+                return Err(VRaftError::RaftConsensus("no quorum".to_string()));
+            }
+            self.groups[&group].commit(group, transaction).await
+        }
+
+        async fn abort(&self, group: RaftGroupId, transaction: TransactionId) -> Result<()> {
+            self.groups[&group].abort(group, transaction).await
+        }
+
+        async fn release(&self, group: RaftGroupId, transaction: TransactionId) -> Result<()> {
+            self.groups[&group].release(group, transaction).await
+        }
+    }
+
+    fn create(vfs: &Vfs, path: &str) -> FileId {
+        match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new(path),
+            content: path.to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        }
+    }
+
+    fn content(vfs: &Vfs, path: &str) -> Option<String> {
+        let file = vfs.get_file_by_path(&VfsPath::new(path))?;
+        vfs.get_content(file.id).ok()
+    }
+
+    fn uri(path: &str) -> Url {
+        Url::from_file_path(path).unwrap()
+    }
+
+    /// Replace the whole text of a file
+    fn write(path: &str, text: &str) -> DocumentChangeOperation {
+        DocumentChangeOperation::Edit(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: uri(path),
+                version: None,
+            },
+            edits: vec![OneOf::Left(TextEdit {
+                range: Range::new(Position::new(0, 0), Position::new(u32::MAX, 0)),
+                new_text: text.to_string(),
+            })],
+        })
+    }
+
+    fn edit(operations: Vec<DocumentChangeOperation>) -> WorkspaceEdit {
+        WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(operations)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pin_versions() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        let file_id = create(&vfs, "/a.rs");
+        let other = create(&vfs, "/b.rs");
+        let images = vec![vfs.get_file(file_id).unwrap()];
+        let update = |file_id, expected_version| VfsCommand::UpdateFile {
+            file_id,
+            content: String::new(),
+            expected_version,
+        };
+
+        let pinned = pin_versions(vec![update(file_id, None), update(file_id, None), update(other, Some(7))], &images);
+        let expected: Vec<_> = pinned
+            .iter()
+            .map(|command| match command {
+                VfsCommand::UpdateFile { expected_version, .. } => *expected_version,
+                _ => panic!("expected UpdateFile"),
+            })
+            .collect();
+        // Only the first update of a prepared file; explicit versions stay
+        assert_eq!(expected, [Some(FileVersion::initial().0), None, Some(7)]);
+    }
+
+    #[tokio::test]
+    async fn test_roll_back_committed_groups() {
+        let vfs: VfsHandle = Arc::new(Vfs::new(RaftGroupId::new(1)));
+        let renamed = create(&vfs, "/a.rs");
+        create(&vfs, "/b.rs");
+        create(&vfs, "/c.rs");
+
+        let parts = BTreeMap::from([
+            (
+                RaftGroupId::new(1),
+                edit(vec![
+                    DocumentChangeOperation::Op(ResourceOp::Rename(RenameFile {
+                        old_uri: uri("/a.rs"),
+                        new_uri: uri("/a2.rs"),
+                        options: None,
+                        annotation_id: None,
+                    })),
+                    write("/a2.rs", "renamed"),
+                    DocumentChangeOperation::Op(ResourceOp::Delete(DeleteFile {
+                        uri: uri("/b.rs"),
+                        options: None,
+                    })),
+                    DocumentChangeOperation::Op(ResourceOp::Create(tower_lsp::lsp_types::CreateFile {
+                        uri: uri("/d.rs"),
+                        options: None,
+                        annotation_id: None,
+                    })),
+                ]),
+            ),
+            (RaftGroupId::new(2), edit(vec![write("/c.rs", "changed")])),
+        ]);
+        let coordinator = TransactionCoordinator::new(Arc::new(FailingGroup::new(&vfs, &[1, 2], 2)));
+        assert!(coordinator.execute(parts).await.is_err());
+
+        // Group 1 committed, then went back: renamed and updated in place,
+        // the deleted file created again, the created one deleted
+        assert_eq!(vfs.get_file_by_path(&VfsPath::new("/a.rs")).unwrap().id, renamed);
+        assert_eq!(content(&vfs, "/a.rs").as_deref(), Some("/a.rs"));
+        assert!(vfs.get_file_by_path(&VfsPath::new("/a2.rs")).is_none());
+        assert_eq!(content(&vfs, "/b.rs").as_deref(), Some("/b.rs"));
+        assert!(vfs.get_file_by_path(&VfsPath::new("/d.rs")).is_none());
+        assert_eq!(content(&vfs, "/c.rs").as_deref(), Some("/c.rs"));
+    }
+
+    #[tokio::test]
+    async fn test_abort_on_held_file() {
+        let vfs: VfsHandle = Arc::new(Vfs::new(RaftGroupId::new(1)));
+        create(&vfs, "/a.rs");
+        let held = create(&vfs, "/b.rs");
+        let participant = Arc::new(FailingGroup::new(&vfs, &[1, 2], 0));
+
+        // Another transaction holds the second group's file, so neither
+        // group commits
+        let other = participant.groups[&RaftGroupId::new(2)].holds().clone();
+        let update = VfsCommand::UpdateFile {
+            file_id: held,
+            content: String::new(),
+            expected_version: None,
+        };
+        other.hold(TransactionId::new(1), &vfs, &update, HOLD_TIMEOUT).unwrap();
+
+        let parts = BTreeMap::from([
+            (RaftGroupId::new(1), edit(vec![write("/a.rs", "changed")])),
+            (RaftGroupId::new(2), edit(vec![write("/b.rs", "changed")])),
+        ]);
+        let error = TransactionCoordinator::new(participant.clone()).execute(parts).await.err().unwrap();
+        assert!(matches!(error, VRaftError::TransactionAborted(_)));
+        assert_eq!(content(&vfs, "/a.rs").as_deref(), Some("/a.rs"));
+        assert_eq!(content(&vfs, "/b.rs").as_deref(), Some("/b.rs"));
+
+        // The first group's hold went with the abort
+        assert!(participant.groups[&RaftGroupId::new(1)]
+            .holds()
+            .check(&update, None)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_committed_files_held_until_released() {
+        let vfs: VfsHandle = Arc::new(Vfs::new(RaftGroupId::new(1)));
+        let file_id = create(&vfs, "/a.rs");
+        let participant = Arc::new(LocalParticipant::new(vfs.clone()));
+        let coordinator = TransactionCoordinator::new(participant.clone());
+        let update = VfsCommand::UpdateFile {
+            file_id,
+            content: String::new(),
+            expected_version: None,
+        };
+
+        let parts = |text| BTreeMap::from([(RaftGroupId::new(1), edit(vec![write("/a.rs", text)]))]);
+        let committed = coordinator.execute(parts("changed")).await.unwrap();
+        assert_eq!(content(&vfs, "/a.rs").as_deref(), Some("changed"));
+        assert!(participant.holds().check(&update, None).is_err());
+        committed.release().await;
+        assert!(participant.holds().check(&update, None).is_ok());
+
+        // Undone after committing, as when the editor refuses the edit
+        let committed = coordinator.execute(parts("again")).await.unwrap();
+        assert_eq!(content(&vfs, "/a.rs").as_deref(), Some("again"));
+        assert!(committed.abort().await);
+        assert_eq!(content(&vfs, "/a.rs").as_deref(), Some("changed"));
+        assert!(participant.holds().check(&update, None).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_coordinator_lost_after_prepare() {
+        let vfs: VfsHandle = Arc::new(Vfs::new(RaftGroupId::new(1)));
+        create(&vfs, "/a.rs");
+        let participant = Arc::new(LocalParticipant::new(vfs.clone()).with_hold_timeout(Duration::from_millis(50)));
+        let parts = |text| BTreeMap::from([(RaftGroupId::new(1), edit(vec![write("/a.rs", text)]))]);
+
+        // Prepared, then the coordinator is gone before committing
+        let lost = next_transaction();
+        TransactionCoordinator::new(participant.clone())
+            .prepare(lost, parts("lost"))
+            .await
+            .unwrap();
+        let coordinator = TransactionCoordinator::new(participant.clone());
+        assert!(coordinator.execute(parts("next")).await.is_err());
+
+        // Once its hold expired the part is dropped and other edits go ahead
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let committed = coordinator.execute(parts("next")).await.unwrap();
+        assert!(!participant.transactions.lock().contains_key(&lost));
+        committed.release().await;
+
+        // A commit arriving late is refused
+        assert!(participant.commit(RaftGroupId::new(1), lost).await.is_err());
+        assert_eq!(content(&vfs, "/a.rs").as_deref(), Some("next"));
+    }
+}
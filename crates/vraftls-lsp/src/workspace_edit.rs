@@ -0,0 +1,667 @@
+//! Workspace edits applied to the VFS
+//!
+//! Language servers change files through `workspace/applyEdit`. Each Raft
+//! group turns its part of such an edit into one VFS transaction; the
+//! gateway splits an edit spanning groups into those parts, which
+//! `transaction` commits in every group or in none.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tower_lsp::lsp_types::*;
+use vraftls_core::{FileId, RaftGroupId, Result, VRaftError};
+use vraftls_vfs::{Vfs, VfsCommand, VfsPath};
+
+/// A file an edit refers to
+#[derive(Clone, Copy)]
+enum Target {
+    /// A file already in the VFS
+    Existing(FileId),
+
+    /// A file created by the edit, at this command index
+    Created(usize),
+}
+
+/// Commands applying a workspace edit, as one transaction
+struct EditPlan<'a> {
+    vfs: &'a Vfs,
+    commands: Vec<VfsCommand>,
+
+    /// Files the edit created, renamed or edited so far
+    targets: HashMap<VfsPath, Target>,
+
+    /// Paths the edit deleted or renamed away
+    removed: HashSet<VfsPath>,
+
+    /// Content of existing files after the edits so far
+    contents: HashMap<FileId, String>,
+}
+
+impl<'a> EditPlan<'a> {
+    fn new(vfs: &'a Vfs) -> Self {
+        Self {
+            vfs,
+            commands: Vec::new(),
+            targets: HashMap::new(),
+            removed: HashSet::new(),
+            contents: HashMap::new(),
+        }
+    }
+
+    fn target(&self, path: &VfsPath) -> Option<Target> {
+        if let Some(target) = self.targets.get(path) {
+            return Some(*target);
+        }
+        if self.removed.contains(path) {
+            return None;
+        }
+        self.vfs.get_file_by_path(path).map(|f| Target::Existing(f.id))
+    }
+
+    fn content(&self, target: Target) -> Result<String> {
+        match target {
+            Target::Existing(file_id) => match self.contents.get(&file_id) {
+                Some(content) => Ok(content.clone()),
+                None => self.vfs.get_content(file_id),
+            },
+            Target::Created(index) => match &self.commands[index] {
+                VfsCommand::CreateFile { content, .. } => Ok(content.clone()),
+                _ => Err(VRaftError::Internal("edit target is not a created file".to_string())),
+            },
+        }
+    }
+
+    fn set_content(&mut self, target: Target, new_content: String) {
+        match target {
+            Target::Existing(file_id) => {
+                self.contents.insert(file_id, new_content.clone());
+                self.commands.push(VfsCommand::UpdateFile {
+                    file_id,
+                    content: new_content,
+                    expected_version: None,
+                });
+            }
+            Target::Created(index) => {
+                if let VfsCommand::CreateFile { content, .. } = &mut self.commands[index] {
+                    *content = new_content;
+                }
+            }
+        }
+    }
+
+    fn edit(&mut self, uri: &Url, edits: &[TextEdit]) -> Result<()> {
+        let path = uri_path(uri)?;
+        let target = self
+            .target(&path)
+            .ok_or_else(|| VRaftError::PathNotInWorkspace(path.to_string()))?;
+        let content = apply_text_edits(&self.content(target)?, edits);
+        self.set_content(target, content);
+        Ok(())
+    }
+
+    fn create(&mut self, op: &CreateFile) -> Result<()> {
+        let path = uri_path(&op.uri)?;
+        let (overwrite, ignore_if_exists) = op
+            .options
+            .as_ref()
+            .map_or((None, None), |o| (o.overwrite, o.ignore_if_exists));
+        match self.target(&path) {
+            Some(target) if overwrite == Some(true) => self.set_content(target, String::new()),
+            Some(_) if ignore_if_exists == Some(true) => {}
+            Some(_) => return Err(VRaftError::FileExists(path.to_string())),
+            None => {
+                self.removed.remove(&path);
+                self.targets.insert(path.clone(), Target::Created(self.commands.len()));
+                self.commands.push(VfsCommand::CreateFile {
+                    path,
+                    content: String::new(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, op: &RenameFile) -> Result<()> {
+        let (old_path, new_path) = (uri_path(&op.old_uri)?, uri_path(&op.new_uri)?);
+        let (overwrite, ignore_if_exists) = op
+            .options
+            .as_ref()
+            .map_or((None, None), |o| (o.overwrite, o.ignore_if_exists));
+        if self.target(&new_path).is_some() && overwrite != Some(true) {
+            if ignore_if_exists == Some(true) {
+                return Ok(());
+            }
+            return Err(VRaftError::FileExists(new_path.to_string()));
+        }
+        let target = self
+            .target(&old_path)
+            .ok_or_else(|| VRaftError::PathNotInWorkspace(old_path.to_string()))?;
+
+        match target {
+            Target::Existing(file_id) => self.commands.push(VfsCommand::RenameFile {
+                file_id,
+                new_path: new_path.clone(),
+            }),
+            Target::Created(index) => {
+                if let VfsCommand::CreateFile { path, .. } = &mut self.commands[index] {
+                    *path = new_path.clone();
+                }
+            }
+        }
+        self.targets.remove(&old_path);
+        self.removed.insert(old_path);
+        self.removed.remove(&new_path);
+        self.targets.insert(new_path, target);
+        Ok(())
+    }
+
+    fn delete(&mut self, op: &DeleteFile) -> Result<()> {
+        let path = uri_path(&op.uri)?;
+        let ignore_missing = op
+            .options
+            .as_ref()
+            .and_then(|o| o.ignore_if_not_exists)
+            .unwrap_or(false);
+        match self.target(&path) {
+            Some(Target::Existing(file_id)) => self.commands.push(VfsCommand::DeleteFile { file_id }),
+            Some(Target::Created(_)) => {
+                return Err(VRaftError::InvalidLspRequest(format!(
+                    "edit deletes the file it creates: {}",
+                    path
+                )))
+            }
+            None if ignore_missing => return Ok(()),
+            None => return Err(VRaftError::PathNotInWorkspace(path.to_string())),
+        }
+        self.targets.remove(&path);
+        self.removed.insert(path);
+        Ok(())
+    }
+}
+
+/// Build the VFS transaction applying a workspace edit
+///
+/// Changes are read against the current VFS content; operations in
+/// `document_changes` apply in order, so edits may target files the same
+/// edit created or renamed.
+pub fn workspace_edit_command(vfs: &Vfs, edit: &WorkspaceEdit) -> Result<VfsCommand> {
+    let mut plan = EditPlan::new(vfs);
+
+    match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => {
+            for edit in edits {
+                plan.edit(&edit.text_document.uri, &text_edits(&edit.edits))?;
+            }
+        }
+        Some(DocumentChanges::Operations(operations)) => {
+            for operation in operations {
+                match operation {
+                    DocumentChangeOperation::Edit(edit) => {
+                        plan.edit(&edit.text_document.uri, &text_edits(&edit.edits))?
+                    }
+                    DocumentChangeOperation::Op(ResourceOp::Create(op)) => plan.create(op)?,
+                    DocumentChangeOperation::Op(ResourceOp::Rename(op)) => plan.rename(op)?,
+                    DocumentChangeOperation::Op(ResourceOp::Delete(op)) => plan.delete(op)?,
+                }
+            }
+        }
+        // `changes` is only used by servers that do not send `document_changes`
+        None => {
+            for (uri, edits) in edit.changes.iter().flatten() {
+                plan.edit(uri, edits)?;
+            }
+        }
+    }
+
+    let mut commands = parent_directories(vfs, &plan.commands);
+    commands.extend(plan.commands);
+    Ok(VfsCommand::Transaction { commands })
+}
+
+/// Commands creating the directories files are created or renamed into,
+/// where the VFS does not have them yet
+fn parent_directories(vfs: &Vfs, commands: &[VfsCommand]) -> Vec<VfsCommand> {
+    let mut parents = BTreeMap::new();
+    for command in commands {
+        let path = match command {
+            VfsCommand::CreateFile { path, .. } => path,
+            VfsCommand::RenameFile { new_path, .. } => new_path,
+            _ => continue,
+        };
+        if let Some(parent) = path.parent().filter(|parent| !vfs.is_directory(parent)) {
+            parents.insert(parent.components().to_vec(), parent);
+        }
+    }
+    parents
+        .into_values()
+        .map(|path| VfsCommand::CreateDirectory { path, recursive: true })
+        .collect()
+}
+
+/// Paths of the files a workspace edit changes, creates, renames or deletes
+pub fn edit_paths(edit: &WorkspaceEdit) -> Result<Vec<VfsPath>> {
+    let mut uris: Vec<&Url> = edit.changes.iter().flat_map(|changes| changes.keys()).collect();
+    match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => uris.extend(edits.iter().map(|e| &e.text_document.uri)),
+        Some(DocumentChanges::Operations(operations)) => {
+            for operation in operations {
+                match operation {
+                    DocumentChangeOperation::Edit(edit) => uris.push(&edit.text_document.uri),
+                    DocumentChangeOperation::Op(ResourceOp::Create(op)) => uris.push(&op.uri),
+                    DocumentChangeOperation::Op(ResourceOp::Rename(op)) => uris.extend([&op.old_uri, &op.new_uri]),
+                    DocumentChangeOperation::Op(ResourceOp::Delete(op)) => uris.push(&op.uri),
+                }
+            }
+        }
+        None => {}
+    }
+
+    let mut paths = Vec::new();
+    for uri in uris {
+        let path = uri_path(uri)?;
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Split a workspace edit into the parts of each Raft group
+///
+/// Each part keeps the order of its operations and the edit's change
+/// annotations. A file renamed into another group would have to move
+/// between groups, which an edit cannot do; such an edit is refused.
+pub fn split_workspace_edit(
+    edit: &WorkspaceEdit,
+    group_of: impl Fn(&VfsPath) -> Option<RaftGroupId>,
+) -> Result<BTreeMap<RaftGroupId, WorkspaceEdit>> {
+    fn part<'a>(
+        parts: &'a mut BTreeMap<RaftGroupId, WorkspaceEdit>,
+        group: RaftGroupId,
+        edit: &WorkspaceEdit,
+    ) -> &'a mut WorkspaceEdit {
+        parts.entry(group).or_insert_with(|| WorkspaceEdit {
+            change_annotations: edit.change_annotations.clone(),
+            ..Default::default()
+        })
+    }
+
+    let group = |uri: &Url| {
+        let path = uri_path(uri)?;
+        group_of(&path).ok_or_else(|| VRaftError::PathNotInWorkspace(path.to_string()))
+    };
+    let mut parts: BTreeMap<RaftGroupId, WorkspaceEdit> = BTreeMap::new();
+    for (uri, text_edits) in edit.changes.iter().flatten() {
+        part(&mut parts, group(uri)?, edit)
+            .changes
+            .get_or_insert_with(HashMap::new)
+            .insert(uri.clone(), text_edits.clone());
+    }
+    match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => {
+            for text_edit in edits {
+                let target = part(&mut parts, group(&text_edit.text_document.uri)?, edit);
+                match target.document_changes.get_or_insert_with(|| DocumentChanges::Edits(Vec::new())) {
+                    DocumentChanges::Edits(edits) => edits.push(text_edit.clone()),
+                    DocumentChanges::Operations(_) => unreachable!("edits only"),
+                }
+            }
+        }
+        Some(DocumentChanges::Operations(operations)) => {
+            for operation in operations {
+                let operation_group = match operation {
+                    DocumentChangeOperation::Edit(edit) => group(&edit.text_document.uri)?,
+                    DocumentChangeOperation::Op(ResourceOp::Create(op)) => group(&op.uri)?,
+                    DocumentChangeOperation::Op(ResourceOp::Delete(op)) => group(&op.uri)?,
+                    DocumentChangeOperation::Op(ResourceOp::Rename(op)) => {
+                        let (from, to) = (group(&op.old_uri)?, group(&op.new_uri)?);
+                        if from != to {
+                            return Err(VRaftError::InvalidLspRequest(format!(
+                                "cannot rename {} into another group",
+                                op.old_uri
+                            )));
+                        }
+                        from
+                    }
+                };
+                let target = part(&mut parts, operation_group, edit);
+                match target.document_changes.get_or_insert_with(|| DocumentChanges::Operations(Vec::new())) {
+                    DocumentChanges::Operations(operations) => operations.push(operation.clone()),
+                    DocumentChanges::Edits(_) => unreachable!("operations only"),
+                }
+            }
+        }
+        None => {}
+    }
+    Ok(parts)
+}
+
+/// Build the VFS transaction for files the editor renamed
+///
+/// A renamed folder moves every file below it, into directories created as
+/// needed; files the VFS does not hold are left out.
+pub fn rename_files_command(vfs: &Vfs, files: &[FileRename]) -> Result<VfsCommand> {
+    let mut commands = Vec::new();
+    for rename in files {
+        let old_root = uri_string_path(&rename.old_uri)?;
+        let new_root = uri_string_path(&rename.new_uri)?.to_path_buf();
+        for file in vfs.list_directory(&old_root) {
+            let below = &file.path.components()[old_root.components().len()..];
+            let new_path = below.iter().fold(new_root.clone(), |path, c| path.join(c));
+            commands.push(VfsCommand::RenameFile {
+                file_id: file.id,
+                new_path: VfsPath::from(new_path),
+            });
+        }
+    }
+    let mut directories = parent_directories(vfs, &commands);
+    directories.extend(commands);
+    Ok(VfsCommand::Transaction { commands: directories })
+}
+
+/// Build the VFS transaction for files the editor deleted
+///
+/// A deleted folder removes every file below it, and the directory itself.
+pub fn delete_files_command(vfs: &Vfs, files: &[FileDelete]) -> Result<VfsCommand> {
+    let mut commands = Vec::new();
+    for delete in files {
+        let root = uri_string_path(&delete.uri)?;
+        for file in vfs.list_directory(&root) {
+            commands.push(VfsCommand::DeleteFile { file_id: file.id });
+        }
+        if vfs.is_directory(&root) {
+            commands.push(VfsCommand::DeleteDirectory {
+                path: root,
+                recursive: true,
+            });
+        }
+    }
+    Ok(VfsCommand::Transaction { commands })
+}
+
+/// Merge the edits several language servers answered with into one
+///
+/// Edits only using `changes` stay in that form for editors without
+/// `documentChanges` support; otherwise everything becomes operations, since
+/// editors ignore `changes` once `documentChanges` is set.
+pub fn merge_workspace_edits(edits: Vec<WorkspaceEdit>) -> Option<WorkspaceEdit> {
+    if edits.len() <= 1 {
+        return edits.into_iter().next();
+    }
+
+    let mut merged = WorkspaceEdit::default();
+    if edits.iter().all(|edit| edit.document_changes.is_none()) {
+        let changes = merged.changes.get_or_insert_with(HashMap::new);
+        for (uri, text_edits) in edits.into_iter().flat_map(|edit| edit.changes.into_iter().flatten()) {
+            changes.entry(uri).or_default().extend(text_edits);
+        }
+        return Some(merged);
+    }
+
+    let mut operations = Vec::new();
+    for edit in edits {
+        match edit.document_changes {
+            Some(DocumentChanges::Edits(edits)) => {
+                operations.extend(edits.into_iter().map(DocumentChangeOperation::Edit))
+            }
+            Some(DocumentChanges::Operations(ops)) => operations.extend(ops),
+            None => {
+                for (uri, text_edits) in edit.changes.into_iter().flatten() {
+                    operations.push(DocumentChangeOperation::Edit(TextDocumentEdit {
+                        text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+                        edits: text_edits.into_iter().map(OneOf::Left).collect(),
+                    }));
+                }
+            }
+        }
+        if let Some(annotations) = edit.change_annotations {
+            merged.change_annotations.get_or_insert_with(HashMap::new).extend(annotations);
+        }
+    }
+    merged.document_changes = Some(DocumentChanges::Operations(operations));
+    Some(merged)
+}
+
+fn text_edits(edits: &[OneOf<TextEdit, AnnotatedTextEdit>]) -> Vec<TextEdit> {
+    edits
+        .iter()
+        .map(|edit| match edit {
+            OneOf::Left(edit) => edit.clone(),
+            OneOf::Right(annotated) => annotated.text_edit.clone(),
+        })
+        .collect()
+}
+
+fn uri_path(uri: &Url) -> Result<VfsPath> {
+    uri.to_file_path()
+        .map(VfsPath::from)
+        .map_err(|_| VRaftError::InvalidPath(uri.to_string()))
+}
+
+/// Path of a URI given as a string, as file operation events carry them
+fn uri_string_path(uri: &str) -> Result<VfsPath> {
+    let url = Url::parse(uri).map_err(|_| VRaftError::InvalidPath(uri.to_string()))?;
+    uri_path(&url)
+}
+
+/// Apply text edits to a document
+///
+/// Edits refer to the original content; edits at the same position are
+/// inserted in the order given.
+pub fn apply_text_edits(content: &str, edits: &[TextEdit]) -> String {
+    let mut ranges: Vec<_> = edits
+        .iter()
+        .map(|edit| {
+            let start = offset_at(content, edit.range.start);
+            let end = offset_at(content, edit.range.end).max(start);
+            (start, end, edit.new_text.as_str())
+        })
+        .collect();
+    ranges.sort_by_key(|(start, _, _)| *start);
+
+    let mut result = content.to_string();
+    for (start, end, text) in ranges.into_iter().rev() {
+        result.replace_range(start..end, text);
+    }
+    result
+}
+
+/// Byte offset of an LSP position, whose character counts UTF-16 units
+///
+/// Positions past the end of a line or of the document are clamped.
+fn offset_at(content: &str, position: Position) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match content[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return content.len(),
+        }
+    }
+
+    let line = &content[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vraftls_vfs::VfsResponse;
+
+    fn uri(path: &str) -> Url {
+        Url::from_file_path(path).unwrap()
+    }
+
+    fn text_edit(start: (u32, u32), end: (u32, u32), new_text: &str) -> TextEdit {
+        TextEdit {
+            range: Range::new(Position::new(start.0, start.1), Position::new(end.0, end.1)),
+            new_text: new_text.to_string(),
+        }
+    }
+
+    fn edit_op(path: &str, edits: Vec<TextEdit>) -> DocumentChangeOperation {
+        DocumentChangeOperation::Edit(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri: uri(path),
+                version: None,
+            },
+            edits: edits.into_iter().map(OneOf::Left).collect(),
+        })
+    }
+
+    fn create_op(path: &str) -> DocumentChangeOperation {
+        DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+            uri: uri(path),
+            options: None,
+            annotation_id: None,
+        }))
+    }
+
+    fn rename_op(from: &str, to: &str) -> DocumentChangeOperation {
+        DocumentChangeOperation::Op(ResourceOp::Rename(RenameFile {
+            old_uri: uri(from),
+            new_uri: uri(to),
+            options: None,
+            annotation_id: None,
+        }))
+    }
+
+    fn delete_op(path: &str) -> DocumentChangeOperation {
+        DocumentChangeOperation::Op(ResourceOp::Delete(DeleteFile {
+            uri: uri(path),
+            options: None,
+        }))
+    }
+
+    fn operations(operations: Vec<DocumentChangeOperation>) -> WorkspaceEdit {
+        WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(operations)),
+            ..Default::default()
+        }
+    }
+
+    fn vfs_with(files: &[(&str, &str)]) -> Vfs {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new("/src"),
+            recursive: true,
+        });
+        for (path, content) in files {
+            vfs.apply(VfsCommand::CreateFile {
+                path: VfsPath::new(path),
+                content: content.to_string(),
+            });
+        }
+        vfs
+    }
+
+    fn content(vfs: &Vfs, path: &str) -> Option<String> {
+        let file = vfs.get_file_by_path(&VfsPath::new(path))?;
+        vfs.get_content(file.id).ok()
+    }
+
+    #[test]
+    fn test_apply_text_edits() {
+        let content = "let a = 1;\nlet 😀 = 2;\n";
+        let edits = [
+            text_edit((1, 9), (1, 10), "3"),
+            text_edit((0, 0), (0, 0), "// a\n"),
+            text_edit((0, 0), (0, 0), "// b\n"),
+            text_edit((0, 4), (0, 5), "x"),
+        ];
+        // Positions count UTF-16 units; inserts at one position keep their order
+        assert_eq!(apply_text_edits(content, &edits), "// a\n// b\nlet x = 1;\nlet 😀 = 3;\n");
+
+        // Positions past the end of a line or of the document are clamped
+        let edits = [text_edit((0, 99), (0, 99), "!"), text_edit((9, 0), (9, 0), "end")];
+        assert_eq!(apply_text_edits("ab\ncd", &edits), "ab!\ncdend");
+    }
+
+    #[test]
+    fn test_workspace_edit_command() {
+        let vfs = vfs_with(&[("/src/a.rs", "fn a() {}\n"), ("/src/b.rs", "fn b() {}\n")]);
+        let edit = operations(vec![
+            create_op("/src/new/c.rs"),
+            edit_op("/src/new/c.rs", vec![text_edit((0, 0), (0, 0), "fn c() {}\n")]),
+            rename_op("/src/a.rs", "/src/a2.rs"),
+            edit_op("/src/a2.rs", vec![text_edit((0, 3), (0, 4), "a2")]),
+            delete_op("/src/b.rs"),
+        ]);
+
+        let command = workspace_edit_command(&vfs, &edit).unwrap();
+        assert!(matches!(vfs.apply(command), VfsResponse::Transaction(_)));
+        assert_eq!(content(&vfs, "/src/new/c.rs").as_deref(), Some("fn c() {}\n"));
+        assert_eq!(content(&vfs, "/src/a2.rs").as_deref(), Some("fn a2() {}\n"));
+        assert_eq!(content(&vfs, "/src/a.rs"), None);
+        assert_eq!(content(&vfs, "/src/b.rs"), None);
+    }
+
+    #[test]
+    fn test_workspace_edit_command_refused() {
+        let vfs = vfs_with(&[("/src/a.rs", "")]);
+
+        let edit = operations(vec![edit_op("/src/missing.rs", vec![text_edit((0, 0), (0, 0), "x")])]);
+        assert!(matches!(
+            workspace_edit_command(&vfs, &edit),
+            Err(VRaftError::PathNotInWorkspace(_))
+        ));
+        let edit = operations(vec![create_op("/src/a.rs")]);
+        assert!(matches!(workspace_edit_command(&vfs, &edit), Err(VRaftError::FileExists(_))));
+        let edit = operations(vec![create_op("/src/c.rs"), delete_op("/src/c.rs")]);
+        assert!(matches!(
+            workspace_edit_command(&vfs, &edit),
+            Err(VRaftError::InvalidLspRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_edit_paths() {
+        let mut edit = operations(vec![
+            rename_op("/src/a.rs", "/src/b.rs"),
+            edit_op("/src/b.rs", vec![]),
+            delete_op("/lib/c.rs"),
+        ]);
+        edit.changes = Some(HashMap::from([(uri("/src/a.rs"), vec![])]));
+
+        let paths = edit_paths(&edit).unwrap();
+        let expected = ["/src/a.rs", "/src/b.rs", "/lib/c.rs"].map(VfsPath::new);
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn test_split_workspace_edit() {
+        let group_of = |path: &VfsPath| match path.components().first().map(String::as_str) {
+            Some("src") => Some(RaftGroupId::new(1)),
+            Some("lib") => Some(RaftGroupId::new(2)),
+            _ => None,
+        };
+        let edit = operations(vec![
+            edit_op("/src/a.rs", vec![]),
+            delete_op("/lib/b.rs"),
+            rename_op("/src/c.rs", "/src/d.rs"),
+        ]);
+
+        let parts = split_workspace_edit(&edit, group_of).unwrap();
+        let paths: Vec<_> = parts.values().map(|part| edit_paths(part).unwrap()).collect();
+        assert_eq!(parts.keys().copied().collect::<Vec<_>>(), [RaftGroupId::new(1), RaftGroupId::new(2)]);
+        assert_eq!(paths[0], ["/src/a.rs", "/src/c.rs", "/src/d.rs"].map(VfsPath::new));
+        assert_eq!(paths[1], [VfsPath::new("/lib/b.rs")]);
+
+        // Files cannot move between groups, nor be edited outside of them
+        let edit = operations(vec![rename_op("/src/a.rs", "/lib/a.rs")]);
+        assert!(matches!(
+            split_workspace_edit(&edit, group_of),
+            Err(VRaftError::InvalidLspRequest(_))
+        ));
+        let edit = operations(vec![delete_op("/tmp/e.rs")]);
+        assert!(matches!(
+            split_workspace_edit(&edit, group_of),
+            Err(VRaftError::PathNotInWorkspace(_))
+        ));
+    }
+}
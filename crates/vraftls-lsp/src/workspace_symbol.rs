@@ -0,0 +1,82 @@
+//! Workspace symbol search across the cluster
+//!
+//! Each Raft group's files are only known to the language servers running
+//! next to its replicas, so a `workspace/symbol` query is scattered to one
+//! node per group and the answers are merged and ranked by the gateway.
+
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tower_lsp::lsp_types::*;
+use vraftls_core::{RaftGroupId, Result, VRaftError};
+
+/// HTTP path nodes answer workspace symbol queries on
+pub const WORKSPACE_SYMBOL_PATH: &str = "/lsp/workspace/symbol";
+
+/// A workspace symbol query for some of a node's groups
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SymbolQuery {
+    pub query: String,
+
+    /// Groups whose files the node should search
+    pub groups: Vec<RaftGroupId>,
+}
+
+/// Symbols of a response in the flat form the editor gets
+///
+/// Symbols located only by URI point at the start of their document.
+pub fn flatten_symbols(response: WorkspaceSymbolResponse) -> Vec<SymbolInformation> {
+    match response {
+        WorkspaceSymbolResponse::Flat(symbols) => symbols,
+        WorkspaceSymbolResponse::Nested(symbols) => symbols
+            .into_iter()
+            .map(|symbol| {
+                #[allow(deprecated)]
+                SymbolInformation {
+                    name: symbol.name,
+                    kind: symbol.kind,
+                    tags: symbol.tags,
+                    deprecated: None,
+                    location: match symbol.location {
+                        OneOf::Left(location) => location,
+                        OneOf::Right(location) => Location::new(location.uri, Range::default()),
+                    },
+                    container_name: symbol.container_name,
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Sends symbol queries to other nodes
+#[derive(Clone)]
+pub struct RemoteSymbolSearch {
+    client: reqwest::Client,
+}
+
+impl RemoteSymbolSearch {
+    pub fn new(timeout: Duration) -> Self {
+        let client = reqwest::Client::builder().timeout(timeout).build().unwrap_or_default();
+        Self { client }
+    }
+
+    /// Search the given groups on the node at `addr`
+    pub async fn search(&self, addr: SocketAddr, query: &SymbolQuery) -> Result<Vec<SymbolInformation>> {
+        self.client
+            .post(format!("http://{}{}", addr, WORKSPACE_SYMBOL_PATH))
+            .json(query)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| VRaftError::Serialization(e.to_string()))
+    }
+}
+
+impl Default for RemoteSymbolSearch {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5))
+    }
+}
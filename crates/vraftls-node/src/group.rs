@@ -0,0 +1,310 @@
+//! Raft groups hosted by the node
+
+use crate::server::{GroupHandle, GroupMover};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use vraftls_cluster::{spawn_leader_watch, ClusterEvents, ClusterMetadata, MetadataStateMachine};
+use vraftls_core::{NodeConfig, RaftConfig, RaftGroupId};
+use vraftls_raft::tuning::timing_changed;
+use vraftls_raft::{
+    create_raft, openraft_config, spawn_leadership_handoff, spawn_snapshot_gc, spawn_snapshot_trigger, spawn_trash_expiry, Archiver, CommandChunker, CompressionConfig, FlowControlConfig,
+    HttpObjectStore, HttpRaftNetworkFactory, LeaderForwarder, LeadershipHandoff, RaftInspector, RaftTuner, ReconfigJournal,
+    Reconfigurator, RocksDbLogStorage, SnapshotBuildConfig, SnapshotRetention, SnapshotStore,
+    SnapshotTrigger, SnapshotTriggerConfig, StaleReader, VRaftRaft, VfsProposer, VfsStateMachine,
+};
+use vraftls_lsp::PreparedTransactions;
+use vraftls_vfs::{spawn_spillover, SpillManager, Vfs, WriteHolds};
+
+/// Interval between VFS spill passes
+const SPILL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Opens the data groups of this node, each in its own directory
+pub struct GroupFactory {
+    pub node_id: u64,
+    pub data_dir: PathBuf,
+    pub config: NodeConfig,
+    pub events: ClusterEvents,
+}
+
+impl GroupFactory {
+    fn group_dir(&self, group_id: RaftGroupId) -> PathBuf {
+        self.data_dir.join(format!("group-{}", group_id))
+    }
+
+    /// Data groups with a directory here, from before a restart
+    pub fn existing_groups(&self) -> std::io::Result<Vec<RaftGroupId>> {
+        let mut groups = Vec::new();
+        if !self.data_dir.exists() {
+            return Ok(groups);
+        }
+        for entry in std::fs::read_dir(&self.data_dir)? {
+            let name = entry?.file_name();
+            let Some(id) = name.to_str().and_then(|name| name.strip_prefix("group-")) else {
+                continue;
+            };
+            match id.parse().map(RaftGroupId::new) {
+                Ok(group_id) if group_id != RaftGroupId::METADATA => groups.push(group_id),
+                _ => {}
+            }
+        }
+        groups.sort();
+        Ok(groups)
+    }
+
+    /// Open a group's storage and state machine, restoring its latest
+    /// snapshot
+    pub async fn open(&self, group_id: RaftGroupId) -> anyhow::Result<GroupParts> {
+        let raft_config = &self.config.raft;
+        let group_dir = self.group_dir(group_id);
+
+        // Archive of purged logs and snapshots, one prefix per group
+        let archiver = self.config.archive.as_ref().map(|archive| {
+            let mut archive = archive.clone();
+            archive.prefix = format!("{}/group-{}", archive.prefix.trim_end_matches('/'), group_id);
+            Archiver::spawn(HttpObjectStore::new(&archive), &archive).0
+        });
+
+        // Raft storage and state machine
+        let mut log_storage = RocksDbLogStorage::new(&group_dir)?;
+        let mut snapshot_store = SnapshotStore::open(group_dir.join("snapshots"))?;
+        if let Some(archiver) = archiver {
+            log_storage = log_storage.with_archiver(archiver.clone());
+            snapshot_store = snapshot_store.with_archiver(archiver);
+        }
+        let log_storage = Arc::new(log_storage);
+        let snapshot_store = Arc::new(snapshot_store);
+        if raft_config.scrub_on_startup {
+            log_storage.scrub().await?;
+        }
+
+        // File contents, spilled to disk when large or cold
+        let vfs_config = &self.config.vfs;
+        let mut vfs = Vfs::new(group_id).with_config(vfs_config);
+        if vfs_config.enable_spill {
+            vfs = vfs.with_spill(SpillManager::new(group_dir.join("spill"), vfs_config)?);
+        }
+        let vfs = Arc::new(vfs);
+        if vfs_config.enable_spill {
+            spawn_spillover(vfs.clone(), SPILL_INTERVAL);
+        }
+        let state_machine = Arc::new(
+            VfsStateMachine::with_vfs(group_id, vfs)
+                .with_snapshot_config(SnapshotBuildConfig::from(raft_config))
+                .with_snapshot_store(snapshot_store.clone())
+                .await?,
+        );
+        spawn_snapshot_gc(
+            snapshot_store.clone(),
+            SnapshotRetention::from(raft_config),
+            raft_config.snapshot_gc_interval,
+        );
+
+        Ok(GroupParts {
+            node_id: self.node_id,
+            group_id,
+            log_storage,
+            snapshot_store,
+            state_machine,
+            reconfig_journal: group_dir.join("reconfig.json"),
+            events: self.events.clone(),
+            trash_retention: vfs_config.enable_trash.then_some(vfs_config.trash_retention),
+            transactions: Arc::new(PreparedTransactions::new(Arc::new(WriteHolds::new()))),
+        })
+    }
+}
+
+/// Everything needed to (re)start a group's Raft instance
+#[derive(Clone)]
+pub struct GroupParts {
+    pub node_id: u64,
+    pub group_id: RaftGroupId,
+    pub log_storage: Arc<RocksDbLogStorage>,
+    pub snapshot_store: Arc<SnapshotStore>,
+    pub state_machine: Arc<VfsStateMachine>,
+    pub reconfig_journal: PathBuf,
+    pub events: ClusterEvents,
+
+    /// How long deleted files stay in the trash; `None` without a trash
+    pub trash_retention: Option<Duration>,
+
+    /// Transactions prepared while this node leads the group, kept across
+    /// restarts of its Raft instance
+    pub transactions: Arc<PreparedTransactions>,
+}
+
+impl GroupParts {
+    /// Start a Raft instance for the group and build its handle
+    pub async fn start(
+        &self,
+        config: &RaftConfig,
+        tuner: Arc<RaftTuner>,
+    ) -> anyhow::Result<(VRaftRaft, GroupHandle)> {
+        let raft = create_raft(
+            self.node_id,
+            openraft_config(format!("group-{}", self.group_id), config)?,
+            HttpRaftNetworkFactory::with_flow_control(FlowControlConfig::from(config))
+                .with_compression(CompressionConfig::from(config))
+                .with_group(self.group_id),
+            self.log_storage.clone(),
+            self.state_machine.clone(),
+        )
+        .await?;
+
+        // Stops by itself once this instance shuts down
+        spawn_snapshot_trigger(SnapshotTrigger::new(
+            raft.clone(),
+            self.log_storage.clone(),
+            SnapshotTriggerConfig::from(config),
+        ));
+        spawn_leadership_handoff(LeadershipHandoff::new(raft.clone(), config));
+        spawn_leader_watch(self.group_id, raft.clone(), self.events.clone());
+
+        let proposer = VfsProposer::new(raft.clone(), self.group_id)
+            .with_chunker(CommandChunker::from(config))
+            .with_holds(self.transactions.holds().clone());
+        if let Some(retention) = self.trash_retention {
+            spawn_trash_expiry(proposer.clone(), self.state_machine.vfs().clone(), retention);
+        }
+        let handle = GroupHandle {
+            raft: raft.clone(),
+            inspector: RaftInspector::new(self.group_id, raft.clone())
+                .with_log_storage(self.log_storage.clone())
+                .with_snapshot_store(self.snapshot_store.clone()),
+            forwarder: LeaderForwarder::new(proposer),
+            tuner,
+            log_storage: self.log_storage.clone(),
+            reconfig: Arc::new(Reconfigurator::new(
+                raft.clone(),
+                ReconfigJournal::new(&self.reconfig_journal),
+            )),
+            vfs: Some(self.state_machine.vfs().clone()),
+            transactions: Some(self.transactions.clone()),
+            stale_reader: Some(
+                StaleReader::new(self.state_machine.clone(), self.log_storage.clone(), config)
+                    .with_raft(raft.clone()),
+            ),
+        };
+        spawn_reconfig_resume(self.group_id, raft.clone(), handle.reconfig.clone());
+
+        Ok((raft, handle))
+    }
+}
+
+/// Start this node's replica of the metadata group (group 0)
+///
+/// Committed metadata commands are applied to `metadata`. The group stays
+/// uninitialized until the cluster is bootstrapped.
+pub async fn start_metadata_group(
+    node_id: u64,
+    group_dir: &Path,
+    config: &RaftConfig,
+    metadata: Arc<ClusterMetadata>,
+    events: ClusterEvents,
+) -> anyhow::Result<GroupHandle> {
+    let group_id = RaftGroupId::METADATA;
+    let log_storage = Arc::new(RocksDbLogStorage::new(group_dir)?);
+    let snapshot_store = Arc::new(SnapshotStore::open(group_dir.join("snapshots"))?);
+    let state_machine = MetadataStateMachine::new(metadata)
+        .with_snapshot_store(snapshot_store)
+        .await?;
+
+    let raft = create_raft(
+        node_id,
+        openraft_config(format!("group-{}", group_id), config)?,
+        HttpRaftNetworkFactory::with_flow_control(FlowControlConfig::from(config))
+            .with_compression(CompressionConfig::from(config))
+            .with_group(group_id),
+        log_storage.clone(),
+        state_machine,
+    )
+    .await?;
+    spawn_snapshot_trigger(SnapshotTrigger::new(
+        raft.clone(),
+        log_storage.clone(),
+        SnapshotTriggerConfig::from(config),
+    ));
+    spawn_leadership_handoff(LeadershipHandoff::new(raft.clone(), config));
+    spawn_leader_watch(group_id, raft.clone(), events);
+
+    let handle = GroupHandle {
+        raft: raft.clone(),
+        inspector: RaftInspector::new(group_id, raft.clone()).with_log_storage(log_storage.clone()),
+        forwarder: LeaderForwarder::new(VfsProposer::new(raft.clone(), group_id)),
+        tuner: Arc::new(RaftTuner::new(config.clone())),
+        log_storage,
+        reconfig: Arc::new(Reconfigurator::new(
+            raft.clone(),
+            ReconfigJournal::new(group_dir.join("reconfig.json")),
+        )),
+        vfs: None,
+        transactions: None,
+        stale_reader: None,
+    };
+    spawn_reconfig_resume(group_id, raft, handle.reconfig.clone());
+
+    Ok(handle)
+}
+
+/// Finish an interrupted reconfiguration once this node leads the group
+fn spawn_reconfig_resume(group_id: RaftGroupId, raft: VRaftRaft, reconfig: Arc<Reconfigurator>) {
+    tokio::spawn(async move {
+        let leader = raft
+            .wait(None)
+            .metrics(|m| m.current_leader.is_some(), "leader elected")
+            .await;
+        let Ok(metrics) = leader else {
+            return;
+        };
+        if metrics.current_leader != Some(metrics.id) {
+            return;
+        }
+
+        if let Err(e) = reconfig.resume().await {
+            tracing::warn!(%group_id, error = %e, "failed to resume reconfiguration");
+        }
+    });
+}
+
+/// Rebuild the group's Raft instance whenever its timing is retuned
+///
+/// The new instance reuses the same log storage and state machine, so the
+/// node keeps its log, vote and applied state across the rebuild.
+pub fn spawn_config_watch(
+    mover: GroupMover,
+    parts: GroupParts,
+    tuner: Arc<RaftTuner>,
+    mut running: RaftConfig,
+    mut raft: VRaftRaft,
+) {
+    tokio::spawn(async move {
+        let mut config_rx = tuner.subscribe();
+
+        while config_rx.changed().await.is_ok() {
+            let config = config_rx.borrow_and_update().clone();
+            if !timing_changed(&running, &config) {
+                continue;
+            }
+
+            if let Err(e) = raft.shutdown().await {
+                tracing::warn!(group_id = %parts.group_id, error = %e, "raft shutdown failed");
+            }
+
+            // Fall back to the previous settings if the new ones fail
+            for candidate in [config, running.clone()] {
+                match parts.start(&candidate, tuner.clone()).await {
+                    Ok((new_raft, handle)) => {
+                        mover.register_group(handle).await;
+                        raft = new_raft;
+                        running = candidate;
+                        tracing::info!(group_id = %parts.group_id, "raft restarted with new timing");
+                        break;
+                    }
+                    Err(e) => {
+                        tracing::error!(group_id = %parts.group_id, error = %e, "failed to restart raft");
+                    }
+                }
+            }
+        }
+    });
+}
@@ -1,7 +1,40 @@
 //! VRaftLS Node - Data node binary
 
+mod group;
+mod server;
+mod stats;
+
 use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use vraftls_cluster::{
+    placement_policy, spawn_drainer, spawn_health_monitor, spawn_leader_balancer, spawn_rebalancer, spawn_repair_service,
+    BootstrapConfig, Bootstrapper,
+    ClusterEvents, ClusterMembership, ClusterMetadata, ClusterNode, failure_detector, GroupHealthMonitor,
+    HeartbeatConfig, HeartbeatService, LeaderBalanceConfig, LeaderBalancer, MetadataProposer, NodeStats, NodeStatus, NodeVersion,
+    spawn_membership_persistence, spawn_split_coordinator, MembershipStore, RepairConfig, RepairService, SplitConfig, SplitCoordinator, SplitJournal, NodeDiscovery, ServiceDiscovery, LeaveConfig, FlapConfig,
+};
+use vraftls_core::{NodeConfig, NodeId, RaftGroupId, Timestamp};
+use vraftls_lsp::LanguageServerPool;
+use vraftls_raft::RaftTuner;
+
+/// Interval between drain attempts for leaving nodes
+const DRAIN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval between quorum and split-brain checks
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Interval between samples of this node's load
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval between saves of the known nodes, besides membership changes
+const MEMBERSHIP_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Interval between polls of the discovery backend
+const DISCOVERY_SYNC_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Parser)]
 #[command(name = "vraftls-node")]
@@ -17,7 +50,48 @@ struct Args {
 
     /// Data directory
     #[arg(long, default_value = "./data")]
-    data_dir: String,
+    data_dir: PathBuf,
+
+    /// Raft group hosted by this node
+    #[arg(long, default_value = "1")]
+    group_id: u64,
+
+    /// Node configuration file (JSON); Raft and archive settings are read from it
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Peer node as `<id>=<addr>` (repeatable)
+    #[arg(long = "peer", value_parser = parse_peer)]
+    peers: Vec<(NodeId, SocketAddr)>,
+
+    /// Bootstrap a new cluster once this many nodes are up (overrides the config file)
+    #[arg(long)]
+    expected_nodes: Option<usize>,
+}
+
+fn parse_peer(value: &str) -> Result<(NodeId, SocketAddr), String> {
+    let (id, addr) = value.split_once('=').ok_or("expected <id>=<addr>")?;
+    let id = id.parse().map_err(|e| format!("invalid node id: {}", e))?;
+    let addr = addr.parse().map_err(|e| format!("invalid address: {}", e))?;
+    Ok((NodeId::new(id), addr))
+}
+
+/// Apply the settings shared by the cluster whenever they change
+///
+/// Language servers spawned afterwards run the commands set cluster-wide;
+/// feature flags are read from the metadata when used.
+fn spawn_shared_config_watch(metadata: &ClusterMetadata, language_servers: Arc<LanguageServerPool>) {
+    let mut shared_config = metadata.subscribe_shared_config();
+    tokio::spawn(async move {
+        loop {
+            let config = shared_config.borrow_and_update().clone();
+            language_servers.set_commands(config.ls_commands.clone());
+            tracing::info!(?config, "shared cluster config applied");
+            if shared_config.changed().await.is_err() {
+                return;
+            }
+        }
+    });
 }
 
 #[tokio::main]
@@ -35,10 +109,155 @@ async fn main() -> anyhow::Result<()> {
         "Starting VRaftLS node"
     );
 
-    // TODO: Initialize node components
-    // - Raft storage
-    // - State machine
-    // - HTTP server
+    let mut node_config: NodeConfig = match &args.config {
+        Some(path) => serde_json::from_slice(&std::fs::read(path)?)?,
+        None => NodeConfig::default(),
+    };
+    if args.expected_nodes.is_some() {
+        node_config.cluster.expected_nodes = args.expected_nodes;
+    }
+    let raft_config = node_config.raft.clone();
+    let group_id = RaftGroupId::new(args.group_id);
+
+    // Membership, metadata and leadership changes
+    let events = ClusterEvents::new();
+
+    // Raft storage and state machine of each data group
+    let factory = Arc::new(group::GroupFactory {
+        node_id: args.node_id,
+        data_dir: args.data_dir.clone(),
+        config: node_config.clone(),
+        events: events.clone(),
+    });
+    let parts = factory.open(group_id).await?;
+    let tuner = Arc::new(RaftTuner::new(raft_config.clone()));
+    let (raft, handle) = parts.start(&raft_config, tuner.clone()).await?;
+
+    // Cluster membership, kept current by heartbeats
+    let membership = Arc::new(
+        ClusterMembership::new(NodeId::new(args.node_id))
+            .with_events(events.clone())
+            .with_flap_config(FlapConfig::from(&node_config.cluster)),
+    );
+    membership.upsert_node(ClusterNode {
+        id: NodeId::new(args.node_id),
+        addr: args.listen.parse()?,
+        status: NodeStatus::Healthy,
+        raft_groups: vec![group_id],
+        last_heartbeat: Timestamp::now(),
+        region: node_config.cluster.region.clone(),
+        zone: node_config.cluster.zone.clone(),
+        rack: node_config.cluster.rack.clone(),
+        leading: Vec::new(),
+        stats: NodeStats::default(),
+        version: Some(NodeVersion::current()),
+        quarantined: false,
+    });
+    // Reconnect to the nodes known before a restart right away
+    let membership_store = MembershipStore::new(args.data_dir.join("membership.json"));
+    membership.restore(membership_store.load()?);
+    // Peers from the configured discovery backend; this node advertises itself there
+    let discovery = Arc::new(NodeDiscovery::from_config(&node_config.discovery, args.peers.clone())?);
+    discovery.register(NodeId::new(args.node_id), args.listen.parse()?).await?;
+    discovery.clone().spawn_sync(membership.clone(), DISCOVERY_SYNC_INTERVAL);
+    spawn_membership_persistence(membership.clone(), membership_store, MEMBERSHIP_SAVE_INTERVAL);
+    stats::spawn_stats_sampler(
+        membership.clone(),
+        parts.state_machine.vfs().clone(),
+        args.data_dir.clone(),
+        STATS_INTERVAL,
+    );
+    Arc::new(HeartbeatService::new(
+        membership.clone(),
+        failure_detector(&node_config.cluster),
+        HeartbeatConfig::from(&node_config.cluster),
+    ))
+    .spawn();
+
+    // Metadata group, replicated to every node
+    let metadata = Arc::new(
+        ClusterMetadata::new()
+            .with_placement_policy(placement_policy(&node_config.cluster))
+            .with_events(events.clone()),
+    );
+    let metadata_group = group::start_metadata_group(
+        args.node_id,
+        &args.data_dir.join(format!("group-{}", RaftGroupId::METADATA)),
+        &raft_config,
+        metadata.clone(),
+        events,
+    )
+    .await?;
+    let proposer = MetadataProposer::new(metadata_group.raft.clone());
+    // HTTP server
+    let state = server::AppState::new(membership.clone(), metadata.clone(), proposer.clone(), factory.clone())
+        .with_leave(discovery.clone(), LeaveConfig::from(&node_config.cluster))
+        .with_rpc_body_limit(&raft_config);
+    spawn_shared_config_watch(&metadata, state.language_servers());
+    state.register_group(handle).await;
+    state.register_group(metadata_group).await;
+    // Groups split off onto this node before a restart
+    for existing in factory.existing_groups()? {
+        if existing != group_id {
+            state.group_mover().host_group(existing).await?;
+        }
+    }
+    spawn_split_coordinator(
+        SplitCoordinator::new(
+            metadata.clone(),
+            proposer.clone(),
+            Arc::new(state.group_mover()),
+            SplitConfig::from(&node_config.vfs),
+        )
+        .with_membership(membership.clone())
+        .with_journal(SplitJournal::new(args.data_dir.join("split.json"))),
+    );
+    spawn_rebalancer(state.rebalancer());
+    spawn_drainer(state.decommissioner(), DRAIN_INTERVAL);
+    spawn_leader_balancer(Arc::new(LeaderBalancer::new(
+        NodeId::new(args.node_id),
+        state.group_mover(),
+        LeaderBalanceConfig::from(&node_config.cluster),
+    )));
+    spawn_health_monitor(
+        Arc::new(GroupHealthMonitor::new(membership.clone(), metadata.clone(), state.group_mover())),
+        HEALTH_CHECK_INTERVAL,
+    );
+    spawn_repair_service(Arc::new(RepairService::new(
+        membership.clone(),
+        state.group_mover(),
+        state.group_mover(),
+        RepairConfig::from(&node_config.cluster),
+    )));
+    if node_config.cluster.expected_nodes.is_some() {
+        let bootstrapper = Bootstrapper::new(
+            BootstrapConfig::from(&node_config.cluster),
+            discovery.clone(),
+            state.group_mover(),
+            membership,
+            metadata,
+            proposer,
+        );
+        tokio::spawn(async move {
+            match bootstrapper.run().await {
+                Ok(outcome) => tracing::info!(?outcome, "cluster bootstrap finished"),
+                Err(e) => tracing::error!(error = %e, "cluster bootstrap failed"),
+            }
+        });
+    }
+    group::spawn_config_watch(state.group_mover(), parts, tuner, raft_config, raft);
+
+    let listener = tokio::net::TcpListener::bind(&args.listen).await?;
+    axum::serve(listener, server::router(state))
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+
+    // Leave the registry right away rather than once the registration expires
+    if let Err(e) = discovery.deregister(NodeId::new(args.node_id)).await {
+        tracing::warn!(error = %e, "failed to deregister from service discovery");
+    }
 
     Ok(())
 }
@@ -0,0 +1,1404 @@
+//! HTTP server for the data node
+
+use crate::group::{spawn_config_watch, GroupFactory};
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+use tower_http::trace::TraceLayer;
+use tower_lsp::lsp_types::SymbolInformation;
+use tracing::Instrument;
+use vraftls_cluster::{
+    ClusterMembership, ClusterMetadata, ClusterTopology, Decommissioner, DegradedGroup,
+    DivergentFile, DrainStatus, FileDigest, GroupDigest, GroupFile, GroupInitializer,
+    GroupLeadership, GroupStartRequest, GroupStore, HeartbeatResponse, LeaderClaim, LeaderSource,
+    LeadershipTransfer, LeaveConfig, LeaveOutcome, LocalReplicas, MetadataLeaderHint,
+    MetadataProposer, NodeDiscovery, NodeStatus, NodeVersion, RebalanceConfig, RebalanceStatus,
+    Rebalancer, RemoteGroupStarter, ReplicaMove, ReplicaMover, RoutingDelta, RoutingDeltaQuery,
+    RoutingEntry, RoutingLookup, StaticDiscovery, DIGEST_PATH, GROUP_START_PATH, HEARTBEAT_PATH,
+    ROUTING_DELTA_PATH, ROUTING_LOOKUP_PATH, SHARED_CONFIG_PATH, TOPOLOGY_PATH,
+};
+use vraftls_core::{
+    ClusterConfig, LanguageId, NodeId, PartitionKey, RaftConfig, RaftGroupId,
+    Result as VRaftResult, SharedConfig, SharedConfigChange, VRaftError,
+};
+use vraftls_lsp::{
+    workspace_edit_command, AppliedIndexHint, LanguageServerPool, LeaderHint, LspMetrics,
+    PreparedTransactions, RemoteDocuments, RemoteRequest, RemoteWrite, RequestKind,
+    ResponseAggregator, SymbolQuery, WriteAck, WriteStep, LSP_PATH, LSP_WRITE_PATH,
+    WORKSPACE_SYMBOL_PATH,
+};
+use vraftls_raft::compression::{decode_body, DecodeError, ACCEPT_ENCODING};
+use vraftls_raft::network::{rpc_reply, RAFT_GROUP_HEADER};
+use vraftls_raft::trace_context::TRACEPARENT;
+use vraftls_raft::{
+    ClientSession, ClientWriteRequest, ClientWriteResponse, LeaderForwarder, LeadershipHandoff,
+    RaftInspector, RaftStatus, RaftTuner, ReconfigPlan, ReconfigProgress, Reconfigurator,
+    RocksDbLogStorage, StaleRead, StaleReader, TimingUpdate, TraceContext, VRaftNode, VRaftRaft,
+};
+use vraftls_vfs::{
+    FileRepair, VfsCommand, VfsHandle, VfsPath, VfsQuery, VfsQueryResponse, VfsResponse,
+};
+
+/// A Raft group hosted on this node
+#[derive(Clone)]
+pub struct GroupHandle {
+    /// The group's Raft instance
+    pub raft: VRaftRaft,
+
+    /// Status introspection
+    pub inspector: RaftInspector,
+
+    /// Client writes, forwarded to the leader when needed
+    pub forwarder: LeaderForwarder,
+
+    /// Runtime-tunable Raft configuration
+    pub tuner: Arc<RaftTuner>,
+
+    /// Raft log storage
+    pub log_storage: Arc<RocksDbLogStorage>,
+
+    /// Membership changes
+    pub reconfig: Arc<Reconfigurator>,
+
+    /// Files of a data group; `None` for the metadata group
+    pub vfs: Option<VfsHandle>,
+
+    /// Transactions spanning groups prepared on a data group while this
+    /// node leads it; `None` for the metadata group
+    pub transactions: Option<Arc<PreparedTransactions>>,
+
+    /// Bounded-staleness reads of a data group; `None` for the metadata group
+    pub stale_reader: Option<StaleReader>,
+}
+
+/// How long a new group may take to elect its first leader
+const GROUP_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Raft groups hosted on this node
+type GroupMap = Arc<RwLock<BTreeMap<RaftGroupId, GroupHandle>>>;
+
+/// Shared state of the HTTP server
+#[derive(Clone)]
+pub struct AppState {
+    /// Raft groups hosted on this node
+    groups: GroupMap,
+
+    /// Known cluster nodes
+    membership: Arc<ClusterMembership>,
+
+    /// Cluster metadata, including groups found degraded
+    metadata: Arc<ClusterMetadata>,
+
+    /// Moves replicas and leaderships of the groups hosted here
+    mover: GroupMover,
+
+    /// Moves replicas of the groups this node leads
+    rebalancer: Arc<Rebalancer<GroupMover>>,
+
+    /// Drains nodes before removal
+    decommissioner: Arc<Decommissioner<GroupMover>>,
+
+    /// Changes to the metadata group
+    proposer: MetadataProposer,
+
+    /// Discovery this node deregisters from when it leaves
+    discovery: Arc<NodeDiscovery>,
+
+    /// Graceful leave settings
+    leave_config: LeaveConfig,
+
+    /// Language servers over the files of the groups hosted here
+    language_servers: Arc<LanguageServerPool>,
+
+    /// Requests gateways made of the language servers
+    lsp_metrics: Arc<LspMetrics>,
+
+    /// Documents open on the language servers for gateways' requests
+    remote_documents: Arc<RemoteDocuments>,
+
+    /// Largest Raft RPC body accepted, once decompressed
+    rpc_body_limit: usize,
+}
+
+impl AppState {
+    pub fn new(
+        membership: Arc<ClusterMembership>,
+        metadata: Arc<ClusterMetadata>,
+        proposer: MetadataProposer,
+        factory: Arc<GroupFactory>,
+    ) -> Self {
+        let groups = GroupMap::default();
+        let mover = GroupMover {
+            groups: groups.clone(),
+            membership: membership.clone(),
+            session: Arc::new(ClientSession::new()),
+            factory,
+            starter: RemoteGroupStarter::new(),
+            hosting: Arc::new(Mutex::new(())),
+        };
+        // Servers answering gateways' requests are shut down once idle
+        let lsp_metrics = Arc::new(LspMetrics::new());
+        let language_servers = Arc::new(LanguageServerPool::new().with_metrics(lsp_metrics.clone()));
+        tokio::spawn(LanguageServerPool::run_eviction(Arc::downgrade(&language_servers)));
+
+        Self {
+            rebalancer: Arc::new(Rebalancer::new(membership.clone(), mover.clone(), RebalanceConfig::default())),
+            decommissioner: Arc::new(Decommissioner::new(membership.clone(), mover.clone())),
+            mover,
+            groups,
+            membership,
+            metadata,
+            proposer,
+            discovery: Arc::new(NodeDiscovery::Static(StaticDiscovery::new(Vec::new()))),
+            leave_config: LeaveConfig::from(&ClusterConfig::default()),
+            language_servers,
+            lsp_metrics,
+            remote_documents: Arc::new(RemoteDocuments::new()),
+            rpc_body_limit: RaftConfig::default().max_rpc_body_bytes as usize,
+        }
+    }
+
+    /// Deregister from `discovery` after a graceful leave
+    pub fn with_leave(mut self, discovery: Arc<NodeDiscovery>, config: LeaveConfig) -> Self {
+        self.discovery = discovery;
+        self.leave_config = config;
+        self
+    }
+
+    /// Refuse Raft RPC bodies larger than `config` allows once decompressed
+    pub fn with_rpc_body_limit(mut self, config: &RaftConfig) -> Self {
+        self.rpc_body_limit = config.max_rpc_body_bytes as usize;
+        self
+    }
+
+    /// Replica rebalancer of this node
+    pub fn rebalancer(&self) -> Arc<Rebalancer<GroupMover>> {
+        self.rebalancer.clone()
+    }
+
+    /// Moves replicas and leaderships of the groups hosted on this node
+    pub fn group_mover(&self) -> GroupMover {
+        self.mover.clone()
+    }
+
+    /// Language servers over the files of the groups hosted here
+    pub fn language_servers(&self) -> Arc<LanguageServerPool> {
+        self.language_servers.clone()
+    }
+
+    /// Node decommissioner of this node
+    pub fn decommissioner(&self) -> Arc<Decommissioner<GroupMover>> {
+        self.decommissioner.clone()
+    }
+
+    /// Register a Raft group hosted on this node
+    pub async fn register_group(&self, group: GroupHandle) {
+        self.mover.register_group(group).await;
+    }
+
+    /// Look up a hosted Raft group
+    async fn group(&self, group_id: RaftGroupId) -> Option<GroupHandle> {
+        self.groups.read().await.get(&group_id).cloned()
+    }
+
+    /// Group addressed by a Raft RPC; the only group if the RPC names none
+    async fn rpc_group(&self, headers: &HeaderMap) -> Option<GroupHandle> {
+        let groups = self.groups.read().await;
+        match headers.get(RAFT_GROUP_HEADER) {
+            Some(value) => {
+                let group_id = value.to_str().ok()?.parse().ok()?;
+                groups.get(&RaftGroupId::new(group_id)).cloned()
+            }
+            None if groups.len() == 1 => groups.values().next().cloned(),
+            None => None,
+        }
+    }
+}
+
+/// Moves replicas by reconfiguring the groups hosted on this node
+#[derive(Clone)]
+pub struct GroupMover {
+    groups: GroupMap,
+    membership: Arc<ClusterMembership>,
+
+    /// Session of the repairs and proposals, so forwarding them can be
+    /// retried; concurrent ones may apply out of order within the session's
+    /// window
+    session: Arc<ClientSession>,
+
+    /// Opens the groups this node starts hosting
+    factory: Arc<GroupFactory>,
+
+    /// Starts new groups on the other replicas
+    starter: RemoteGroupStarter,
+
+    /// Held while a group is opened, so it is opened once
+    hosting: Arc<Mutex<()>>,
+}
+
+impl ReplicaMover for GroupMover {
+    async fn move_replica(&self, replica_move: &ReplicaMove) -> VRaftResult<()> {
+        // Groups not hosted here are moved by whichever node leads them
+        let group = self
+            .groups
+            .read()
+            .await
+            .get(&replica_move.group_id)
+            .cloned()
+            .ok_or(VRaftError::NotLeader { leader: None })?;
+        let target = self
+            .membership
+            .get_node(replica_move.to)
+            .ok_or(VRaftError::NodeUnreachable(replica_move.to))?;
+
+        let mut voters: BTreeSet<_> = group.raft.metrics().borrow().membership_config.voter_ids().collect();
+        voters.remove(&replica_move.from.0);
+        voters.insert(replica_move.to.0);
+
+        group
+            .reconfig
+            .start(ReconfigPlan {
+                voters,
+                new_nodes: BTreeMap::from([(replica_move.to.0, VRaftNode::new(target.addr.to_string()))]),
+            })
+            .await
+    }
+}
+
+impl LeadershipTransfer for GroupMover {
+    async fn transfer_leadership(&self, group_id: RaftGroupId) -> VRaftResult<bool> {
+        let Some(group) = self.groups.read().await.get(&group_id).cloned() else {
+            return Ok(false);
+        };
+        let leads = {
+            let metrics = group.raft.metrics();
+            let metrics = metrics.borrow();
+            metrics.current_leader == Some(metrics.id)
+        };
+        if leads {
+            LeadershipHandoff::new(group.raft.clone(), &group.tuner.current())
+                .step_aside()
+                .await;
+        }
+        Ok(leads)
+    }
+}
+
+impl GroupInitializer for GroupMover {
+    async fn initialize_group(&self, group_id: RaftGroupId, voters: BTreeMap<NodeId, VRaftNode>) -> VRaftResult<bool> {
+        let group = self
+            .groups
+            .read()
+            .await
+            .get(&group_id)
+            .cloned()
+            .ok_or(VRaftError::GroupNotFound(group_id))?;
+        let initialized = group
+            .raft
+            .is_initialized()
+            .await
+            .map_err(|e| VRaftError::RaftConsensus(e.to_string()))?;
+        if initialized {
+            return Ok(false);
+        }
+
+        let members: BTreeMap<_, _> = voters.into_iter().map(|(id, node)| (id.0, node)).collect();
+        group
+            .raft
+            .initialize(members)
+            .await
+            .map_err(|e| VRaftError::RaftConsensus(e.to_string()))?;
+        Ok(true)
+    }
+}
+
+impl GroupMover {
+    /// Register a Raft group hosted on this node
+    pub async fn register_group(&self, group: GroupHandle) {
+        self.groups
+            .write()
+            .await
+            .insert(group.inspector.group_id(), group);
+    }
+
+    /// Open and start a data group here, unless it is already hosted
+    pub async fn host_group(&self, group_id: RaftGroupId) -> VRaftResult<()> {
+        let _hosting = self.hosting.lock().await;
+        if self.groups.read().await.contains_key(&group_id) {
+            return Ok(());
+        }
+
+        let config = self.factory.config.raft.clone();
+        let tuner = Arc::new(RaftTuner::new(config.clone()));
+        let started = async {
+            let parts = self.factory.open(group_id).await?;
+            let (raft, handle) = parts.start(&config, tuner.clone()).await?;
+            anyhow::Ok((parts, raft, handle))
+        };
+        let (parts, raft, handle) = started
+            .await
+            .map_err(|e| VRaftError::Internal(format!("failed to start group {}: {}", group_id, e)))?;
+        self.register_group(handle).await;
+        spawn_config_watch(self.clone(), parts, tuner, config, raft);
+        tracing::info!(%group_id, "hosting raft group");
+        Ok(())
+    }
+
+    /// A hosted data group with its files
+    async fn data_group(&self, group_id: RaftGroupId) -> VRaftResult<(GroupHandle, VfsHandle)> {
+        let group = self
+            .groups
+            .read()
+            .await
+            .get(&group_id)
+            .cloned()
+            .ok_or(VRaftError::GroupNotFound(group_id))?;
+        let vfs = group.vfs.clone().ok_or(VRaftError::GroupNotFound(group_id))?;
+        Ok((group, vfs))
+    }
+}
+
+impl LocalReplicas for GroupMover {
+    async fn digest(&self, group_id: RaftGroupId, bucket: Option<usize>) -> VRaftResult<GroupDigest> {
+        let (group, vfs) = self.data_group(group_id).await?;
+        let applied = || group.raft.metrics().borrow().last_applied.map(|log_id| log_id.index);
+
+        let applied_index = applied();
+        let files: Vec<_> = vfs
+            .all_file_ids()
+            .into_iter()
+            .filter_map(|file_id| vfs.get_file(file_id))
+            .map(|file| {
+                let digest = FileDigest {
+                    file_id: file.id,
+                    version: file.version.0,
+                    checksum: file.checksum.0,
+                };
+                (file.path.to_string(), digest)
+            })
+            .collect();
+        if applied() != applied_index {
+            return Err(VRaftError::Internal("entries applied while building the digest".to_string()));
+        }
+
+        Ok(GroupDigest::build(group_id, applied_index, vfs.next_file_id(), files, bucket))
+    }
+
+    async fn repair(&self, group_id: RaftGroupId, files: Vec<DivergentFile>) -> VRaftResult<()> {
+        let (group, vfs) = self.data_group(group_id).await?;
+
+        // Current copies, in case files changed since the digest
+        let mut repairs = Vec::with_capacity(files.len());
+        for file in files {
+            let path = VfsPath::new(&file.path);
+            let repair = match (file.leader, file.replica) {
+                (Some(ours), _) => {
+                    let Some(current) = vfs.get_file(ours.file_id) else {
+                        continue;
+                    };
+                    FileRepair {
+                        file_id: current.id,
+                        path: current.path,
+                        content: Some(vfs.get_content(current.id)?),
+                        version: current.version.0,
+                    }
+                }
+                (None, Some(theirs)) => FileRepair {
+                    file_id: theirs.file_id,
+                    path,
+                    content: None,
+                    version: theirs.version,
+                },
+                (None, None) => continue,
+            };
+            repairs.push(repair);
+        }
+
+        let response = group
+            .forwarder
+            .write(
+                ClientWriteRequest::new(
+                    group_id,
+                    VfsCommand::Repair {
+                        files: repairs,
+                        next_file_id: vfs.next_file_id(),
+                    },
+                )
+                .with_session(self.session.next()),
+            )
+            .await?;
+        match response {
+            VfsResponse::Error(e) => Err(VRaftError::Internal(e.to_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl GroupStore for GroupMover {
+    async fn file_count(&self, group_id: RaftGroupId) -> VRaftResult<u64> {
+        let (_, vfs) = self.data_group(group_id).await?;
+        Ok(vfs.all_file_ids().len() as u64)
+    }
+
+    async fn list_files(&self, group_id: RaftGroupId) -> VRaftResult<Vec<GroupFile>> {
+        let (_, vfs) = self.data_group(group_id).await?;
+        vfs.all_file_ids()
+            .into_iter()
+            .filter_map(|file_id| vfs.get_file(file_id))
+            .map(|file| {
+                Ok(GroupFile {
+                    id: file.id,
+                    content: vfs.get_content(file.id)?,
+                    path: file.path,
+                })
+            })
+            .collect()
+    }
+
+    async fn start_group(&self, group_id: RaftGroupId, nodes: Vec<NodeId>) -> VRaftResult<()> {
+        let local = self.membership.local_node_id();
+        if !nodes.contains(&local) {
+            return Err(VRaftError::Internal(format!("group {} would not be hosted here", group_id)));
+        }
+
+        let mut voters = BTreeMap::new();
+        for node_id in nodes {
+            let node = self
+                .membership
+                .get_node(node_id)
+                .ok_or(VRaftError::NodeUnreachable(node_id))?;
+            if node_id == local {
+                self.host_group(group_id).await?;
+            } else {
+                self.starter.start(node_id, node.addr, group_id).await?;
+            }
+            voters.insert(node_id, VRaftNode::new(node.addr.to_string()));
+        }
+        self.initialize_group(group_id, voters).await?;
+
+        let (group, _) = self.data_group(group_id).await?;
+        group
+            .raft
+            .wait(Some(GROUP_START_TIMEOUT))
+            .metrics(|m| m.current_leader.is_some(), "new group leader")
+            .await
+            .map_err(|e| VRaftError::RaftConsensus(e.to_string()))?;
+        Ok(())
+    }
+
+    async fn propose(&self, group_id: RaftGroupId, command: VfsCommand) -> VRaftResult<VfsResponse> {
+        let (group, _) = self.data_group(group_id).await?;
+        group
+            .forwarder
+            .write(ClientWriteRequest::new(group_id, command).with_session(self.session.next()))
+            .await
+    }
+}
+
+impl LeaderSource for GroupMover {
+    async fn leaderships(&self) -> Vec<GroupLeadership> {
+        self.groups
+            .read()
+            .await
+            .iter()
+            .map(|(group_id, group)| {
+                let metrics = group.raft.metrics();
+                let metrics = metrics.borrow();
+                GroupLeadership {
+                    group_id: *group_id,
+                    leader: metrics.current_leader.map(NodeId::new),
+                    voters: metrics.membership_config.voter_ids().map(NodeId::new).collect(),
+                    term: metrics.current_term,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Build the HTTP router
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/raft/status", get(all_status))
+        .route("/raft/status/:group_id", get(group_status))
+        .route("/raft/logs/:group_id", get(dump_logs))
+        .route("/raft/append_entries", post(raft_append_entries))
+        .route("/raft/vote", post(raft_vote))
+        .route("/raft/install_snapshot", post(raft_install_snapshot))
+        .route("/client/read", get(client_read))
+        .route("/client/query", post(client_query))
+        .route("/client/write", post(client_write))
+        .route(HEARTBEAT_PATH, get(heartbeat))
+        .route("/cluster/degraded", get(degraded_groups))
+        .route(TOPOLOGY_PATH, get(topology))
+        .route(ROUTING_LOOKUP_PATH, post(lookup_routes))
+        .route(ROUTING_DELTA_PATH, get(routing_delta))
+        .route(WORKSPACE_SYMBOL_PATH, post(workspace_symbols))
+        .route(LSP_PATH, post(lsp_request))
+        .route(LSP_WRITE_PATH, post(lsp_write))
+        .route("/metrics/lsp", get(lsp_metrics))
+        .route(DIGEST_PATH, get(replica_digest))
+        .route(GROUP_START_PATH, post(start_group))
+        .route(
+            "/admin/raft/:group_id/timing",
+            get(get_timing).put(update_timing),
+        )
+        .route(
+            "/admin/raft/:group_id/membership",
+            get(get_reconfig).post(start_reconfig),
+        )
+        .route(
+            "/admin/nodes/:node_id/decommission",
+            get(drain_status).post(decommission_node),
+        )
+        .route("/admin/nodes/:node_id", delete(remove_node))
+        .route("/admin/maintenance", put(set_maintenance))
+        .route("/admin/leave", post(leave_cluster))
+        .route(SHARED_CONFIG_PATH, get(shared_config).put(update_shared_config))
+        .route("/admin/features/:name", get(feature))
+        .route("/admin/rebalance", get(rebalance_status))
+        .route("/admin/rebalance/run", post(run_rebalance))
+        .route("/admin/rebalance/pause", post(pause_rebalance))
+        .route("/admin/rebalance/resume", post(resume_rebalance))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state)
+}
+
+/// Status of every Raft group on this node
+async fn all_status(State(state): State<AppState>) -> Json<Vec<RaftStatus>> {
+    let groups = state.groups.read().await;
+    let mut statuses = Vec::with_capacity(groups.len());
+    for group in groups.values() {
+        statuses.push(group.inspector.status().await);
+    }
+    Json(statuses)
+}
+
+/// Status of a single Raft group
+async fn group_status(
+    State(state): State<AppState>,
+    Path(group_id): Path<u64>,
+) -> Result<Json<RaftStatus>, StatusCode> {
+    let group = state
+        .group(RaftGroupId::new(group_id))
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(group.inspector.status().await))
+}
+
+/// Index range of a log dump
+#[derive(Deserialize)]
+struct LogRange {
+    #[serde(default)]
+    from: u64,
+    to: Option<u64>,
+}
+
+/// Export a group's log entries as JSON lines
+async fn dump_logs(
+    State(state): State<AppState>,
+    Path(group_id): Path<u64>,
+    Query(range): Query<LogRange>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let group = state
+        .group(RaftGroupId::new(group_id))
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "raft group not found".to_string()))?;
+
+    let body = group
+        .log_storage
+        .dump_logs(range.from, range.to.unwrap_or(u64::MAX))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body))
+}
+
+/// Trace context sent by the caller, if any
+fn trace_context(headers: &HeaderMap) -> Option<TraceContext> {
+    headers
+        .get(TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(TraceContext::parse)
+}
+
+/// Handle a Raft RPC from a peer
+async fn raft_rpc<Req, Resp, E, F, Fut>(
+    state: AppState,
+    headers: HeaderMap,
+    body: Bytes,
+    rpc: &'static str,
+    call: F,
+) -> Response
+where
+    Req: DeserializeOwned,
+    Resp: Serialize,
+    E: Serialize,
+    F: FnOnce(VRaftRaft, Req) -> Fut,
+    Fut: Future<Output = Result<Resp, E>>,
+{
+    let Some(group) = state.rpc_group(&headers).await else {
+        return (StatusCode::NOT_FOUND, "raft group not found").into_response();
+    };
+
+    let content_encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok());
+    let body = match decode_body(content_encoding, &body, state.rpc_body_limit) {
+        Ok(body) => body,
+        Err(e @ DecodeError::TooLarge { .. }) => {
+            return (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()).into_response();
+        }
+        Err(e) => {
+            let headers = [(header::ACCEPT_ENCODING, ACCEPT_ENCODING)];
+            return (StatusCode::UNSUPPORTED_MEDIA_TYPE, headers, e.to_string()).into_response();
+        }
+    };
+    let request: Req = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let trace = trace_context(&headers);
+    let span = tracing::debug_span!(
+        "raft_rpc",
+        rpc,
+        group_id = %group.inspector.group_id(),
+        trace_id = tracing::field::Empty
+    );
+    if let Some(trace) = trace {
+        span.record("trace_id", trace.trace_id_hex());
+    }
+
+    rpc_reply(call(group.raft.clone(), request).instrument(span).await)
+}
+
+/// Replicate log entries from the leader
+///
+/// Every request the current leader sends, heartbeats included, counts as
+/// contact with it for stale reads.
+async fn raft_append_entries(State(state): State<AppState>, headers: HeaderMap, body: Bytes) -> Response {
+    let contact = state
+        .rpc_group(&headers)
+        .await
+        .and_then(|group| group.stale_reader)
+        .map(|reader| reader.leader_contact().clone());
+    raft_rpc(state, headers, body, "append_entries", |raft, request| async move {
+        let response = raft.append_entries(request).await;
+        if let (Ok(response), Some(contact)) = (&response, &contact) {
+            contact.observe(response);
+        }
+        response
+    })
+    .await
+}
+
+/// Vote in an election
+async fn raft_vote(State(state): State<AppState>, headers: HeaderMap, body: Bytes) -> Response {
+    raft_rpc(state, headers, body, "vote", |raft, request| async move {
+        raft.vote(request).await
+    })
+    .await
+}
+
+/// Receive a snapshot chunk from the leader
+async fn raft_install_snapshot(State(state): State<AppState>, headers: HeaderMap, body: Bytes) -> Response {
+    raft_rpc(state, headers, body, "install_snapshot", |raft, request| async move {
+        raft.install_snapshot(request).await
+    })
+    .await
+}
+
+/// Answer a peer's heartbeat
+async fn heartbeat(State(state): State<AppState>) -> Json<HeartbeatResponse> {
+    let local = state.membership.local_node_id();
+    let node = state.membership.get_node(local);
+    let leading = state
+        .group_mover()
+        .leaderships()
+        .await
+        .into_iter()
+        .filter(|group| group.leader == Some(local))
+        .map(|group| LeaderClaim {
+            group_id: group.group_id,
+            term: group.term,
+        })
+        .collect();
+    Json(HeartbeatResponse {
+        node_id: local,
+        region: node.as_ref().and_then(|n| n.region.clone()),
+        zone: node.as_ref().and_then(|n| n.zone.clone()),
+        rack: node.as_ref().and_then(|n| n.rack.clone()),
+        leading,
+        maintenance: node.as_ref().is_some_and(|n| n.status == NodeStatus::Maintenance),
+        leaving: node.as_ref().is_some_and(|n| n.status == NodeStatus::Leaving),
+        version: Some(NodeVersion::current()),
+        unreachable: state
+            .membership
+            .all_nodes()
+            .into_iter()
+            .filter(|n| matches!(n.status, NodeStatus::Suspect | NodeStatus::Down))
+            .map(|n| n.id)
+            .collect(),
+        stats: node.map(|n| n.stats).unwrap_or_default(),
+    })
+}
+
+/// Replica and bucket of a digest request
+#[derive(Deserialize)]
+struct DigestQuery {
+    group_id: u64,
+    bucket: Option<usize>,
+}
+
+/// Digest of this node's replica of a group, for anti-entropy repair
+async fn replica_digest(
+    State(state): State<AppState>,
+    Query(query): Query<DigestQuery>,
+) -> Result<Json<GroupDigest>, (StatusCode, String)> {
+    state
+        .group_mover()
+        .digest(RaftGroupId::new(query.group_id), query.bucket)
+        .await
+        .map(Json)
+        .map_err(|e| match e {
+            VRaftError::GroupNotFound(_) => (StatusCode::NOT_FOUND, e.to_string()),
+            _ => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+        })
+}
+
+/// Host a group another node is splitting off
+async fn start_group(
+    State(state): State<AppState>,
+    Json(request): Json<GroupStartRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state
+        .mover
+        .host_group(request.group_id)
+        .await
+        .map(|_| StatusCode::NO_CONTENT)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// VFS query served by this node's replica
+#[derive(Deserialize)]
+struct StaleQueryRequest {
+    group_id: RaftGroupId,
+    query: VfsQuery,
+}
+
+/// Answer a VFS query from this node's replica, whether it leads the group
+/// or not
+///
+/// Refused with 503 while the replica lags the commit index by more than
+/// `max_read_lag` entries, or has not heard from the leader within
+/// `max_read_staleness`; the lag is returned with the result otherwise.
+async fn client_query(
+    State(state): State<AppState>,
+    Json(request): Json<StaleQueryRequest>,
+) -> Result<Json<StaleRead>, (StatusCode, String)> {
+    let reader = stale_reader(&state, request.group_id).await?;
+    reader
+        .read(request.query)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))
+}
+
+/// Stale reader of a hosted data group
+async fn stale_reader(state: &AppState, group_id: RaftGroupId) -> Result<StaleReader, (StatusCode, String)> {
+    state
+        .group(group_id)
+        .await
+        .and_then(|group| group.stale_reader)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, VRaftError::GroupNotFound(group_id).to_string()))
+}
+
+/// File of a follower read
+#[derive(Deserialize)]
+struct ReadQuery {
+    group_id: u64,
+    path: String,
+}
+
+/// A file as this node's replica has it
+#[derive(Serialize)]
+struct ReadResponse {
+    path: String,
+    version: u64,
+    content: Option<String>,
+
+    /// Last log index applied to the replica, for callers checking staleness
+    applied_index: Option<u64>,
+
+    /// Committed entries not yet applied to the replica
+    lag: u64,
+}
+
+/// Read a file from this node's replica, whether it leads the group or not
+///
+/// Lets gateways read from a replica in their own region. The result may
+/// lag the leader by the entries not yet applied here; reads are refused
+/// with 503 once that exceeds `max_read_lag`, or once the leader has not
+/// been heard from within `max_read_staleness`.
+async fn client_read(
+    State(state): State<AppState>,
+    Query(query): Query<ReadQuery>,
+) -> Result<Json<ReadResponse>, (StatusCode, String)> {
+    let group_id = RaftGroupId::new(query.group_id);
+    let read = stale_reader(&state, group_id)
+        .await?
+        .read(VfsQuery::GetFileByPath(VfsPath::new(&query.path)))
+        .await
+        .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?;
+    let VfsQueryResponse::File(Some(file)) = read.response else {
+        return Err((StatusCode::NOT_FOUND, format!("file not found: {}", query.path)));
+    };
+    Ok(Json(ReadResponse {
+        path: file.path.to_string(),
+        version: file.version.0,
+        content: file.content_string(),
+        applied_index: read.applied_index,
+        lag: read.lag,
+    }))
+}
+
+/// Nodes, groups, leaders and replica placement as this node sees them
+async fn topology(State(state): State<AppState>) -> Json<ClusterTopology> {
+    let local_groups = state.group_mover().leaderships().await;
+    Json(ClusterTopology::collect(&state.membership, &state.metadata, &local_groups).await)
+}
+
+/// Workspace symbols in some of this node's groups, for gateway scatter-gather
+///
+/// Language servers are started for the languages of the groups' files.
+async fn workspace_symbols(
+    State(state): State<AppState>,
+    Json(query): Json<SymbolQuery>,
+) -> Json<Vec<SymbolInformation>> {
+    for language in group_languages(&state, &query.groups).await {
+        if let Err(e) = state.language_servers.get_or_spawn(language.clone()).await {
+            tracing::debug!(?language, error = %e, "no language server for workspace symbols");
+        }
+    }
+
+    let mut aggregator = ResponseAggregator::new();
+    state
+        .language_servers
+        .collect_workspace_symbols(&query.query, &mut aggregator)
+        .await;
+    let (symbols, errors) = aggregator.into_results();
+    if !errors.is_empty() {
+        tracing::warn!(?errors, "workspace symbol search incomplete");
+    }
+    Json(symbols)
+}
+
+/// Languages of the files in some of this node's groups
+async fn group_languages(state: &AppState, groups: &[RaftGroupId]) -> HashSet<LanguageId> {
+    let mut languages = HashSet::new();
+    for group_id in groups {
+        let Ok((_, vfs)) = state.group_mover().data_group(*group_id).await else {
+            continue;
+        };
+        for file_id in vfs.all_file_ids() {
+            if let Some(language) = vfs.get_file(file_id).and_then(|f| f.path.language_id()) {
+                languages.insert(language);
+            }
+        }
+    }
+    languages
+}
+
+/// Time a replica gets to apply the log up to the index a read requires
+const READ_INDEX_WAIT: Duration = Duration::from_secs(2);
+
+/// Serve an LSP request a gateway routed to this node
+///
+/// A document request goes to the server of the document's language, with
+/// the document open for the duration of the request; a workspace request
+/// goes to the servers of the languages in the given groups. Reads carrying
+/// a log index are answered once the groups' replicas applied the log that
+/// far.
+async fn lsp_request(
+    State(state): State<AppState>,
+    Json(request): Json<RemoteRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    // Writes are only served by the leader; the gateway retries there
+    if RequestKind::of(&request.method) == RequestKind::Write {
+        for group_id in &request.groups {
+            let Some(group) = state.group(*group_id).await else {
+                continue;
+            };
+            let metrics = group.raft.metrics().borrow().clone();
+            if metrics.current_leader != Some(metrics.id) {
+                let hint = LeaderHint {
+                    group: *group_id,
+                    leader: metrics.current_leader.map(NodeId::new),
+                };
+                return Err((StatusCode::CONFLICT, serde_json::to_string(&hint).unwrap_or_default()));
+            }
+        }
+    }
+
+    // Reads wait for the writes the gateway made before them
+    if let Some(min_applied_index) = request.min_applied_index {
+        for group_id in &request.groups {
+            let Some(group) = state.group(*group_id).await else {
+                continue;
+            };
+            let applied = group
+                .raft
+                .wait(Some(READ_INDEX_WAIT))
+                .applied_index_at_least(Some(min_applied_index), "read-your-writes")
+                .await;
+            if applied.is_err() {
+                let hint = AppliedIndexHint {
+                    group: *group_id,
+                    applied_index: group.raft.metrics().borrow().last_applied.map(|l| l.index),
+                };
+                return Err((StatusCode::PRECONDITION_FAILED, serde_json::to_string(&hint).unwrap_or_default()));
+            }
+        }
+    }
+
+    let languages = match &request.document {
+        Some(document) => document
+            .uri
+            .to_file_path()
+            .ok()
+            .and_then(|path| VfsPath::from(path).language_id())
+            .into_iter()
+            .collect(),
+        None => group_languages(&state, &request.groups).await,
+    };
+
+    let mut aggregator = ResponseAggregator::new();
+    for language in languages {
+        let server = match state.language_servers.get_or_spawn(language.clone()).await {
+            Ok(server) => server,
+            Err(e) => {
+                aggregator.add_error(format!("{:?}: {}", language, e));
+                continue;
+            }
+        };
+
+        let _lease = match &request.document {
+            Some(document) => Some(state.remote_documents.acquire(&server, document).await),
+            None => None,
+        };
+        match server.forward(&request.method, request.params.clone()).await {
+            Ok(Some(response)) => aggregator.add_response(response),
+            Ok(None) => {}
+            Err(e) => aggregator.add_error(format!("{:?}: {}", language, e)),
+        }
+    }
+
+    if aggregator.responses().is_empty() && aggregator.has_errors() {
+        return Err((StatusCode::BAD_GATEWAY, aggregator.errors().join("; ")));
+    }
+    Ok(Json(aggregator.into_merged(&request.method)))
+}
+
+/// Apply a gateway's edit of a group's files, or take a step of its
+/// transaction on them
+///
+/// Only the group's leader takes them; other nodes answer 409 with the
+/// leader they know. Edits that do not apply to the group's files are
+/// refused with 422 and the reason. Writes answer with the log index they
+/// were committed at.
+async fn lsp_write(
+    State(state): State<AppState>,
+    Json(write): Json<RemoteWrite>,
+) -> Result<Json<WriteAck>, (StatusCode, String)> {
+    let not_found = || (StatusCode::NOT_FOUND, VRaftError::GroupNotFound(write.group).to_string());
+    let group = state.group(write.group).await.ok_or_else(not_found)?;
+    let (Some(vfs), Some(transactions)) = (group.vfs.clone(), group.transactions.clone()) else {
+        return Err(not_found());
+    };
+    let leader_hint = |leader: Option<NodeId>| {
+        let hint = LeaderHint {
+            group: write.group,
+            leader,
+        };
+        (StatusCode::CONFLICT, serde_json::to_string(&hint).unwrap_or_default())
+    };
+    let metrics = group.raft.metrics().borrow().clone();
+    if metrics.current_leader != Some(metrics.id) {
+        return Err(leader_hint(metrics.current_leader.map(NodeId::new)));
+    }
+
+    let proposer = group.forwarder.proposer();
+    let written = match write.step {
+        WriteStep::Apply { edit } => match state.metadata.check_writable(write.group).await {
+            Ok(()) => match workspace_edit_command(&vfs, &edit) {
+                Ok(command) => proposer
+                    .propose_acknowledged(command)
+                    .await
+                    .and_then(|(response, index)| transaction_responses(response).map(|_| Some(index))),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        },
+        WriteStep::Prepare { transaction, edit } => {
+            match state.metadata.check_writable(write.group).await {
+                Ok(()) => transactions.prepare(&vfs, transaction, &edit).map(|_| None),
+                Err(e) => Err(e),
+            }
+        }
+        WriteStep::Commit { transaction } => match transactions.commands(transaction) {
+            Ok(commands) => {
+                let committed = proposer
+                    .propose_held(VfsCommand::Transaction { commands }, transaction)
+                    .await;
+                committed.and_then(|(response, index)| {
+                    transactions.committed(transaction, transaction_responses(response)?);
+                    Ok(Some(index))
+                })
+            }
+            Err(e) => Err(e),
+        },
+        WriteStep::Abort { transaction } => {
+            let undone = match transactions.abort(transaction) {
+                Some(undo) => proposer
+                    .propose_held(VfsCommand::Transaction { commands: undo }, transaction)
+                    .await
+                    .and_then(|(response, index)| transaction_responses(response).map(|_| Some(index))),
+                None => Ok(None),
+            };
+            transactions.release(transaction);
+            undone
+        }
+        WriteStep::Release { transaction } => {
+            transactions.release(transaction);
+            Ok(None)
+        }
+    };
+
+    match written {
+        Ok(applied_index) => Ok(Json(WriteAck { applied_index })),
+        Err(VRaftError::NotLeader { leader }) => Err(leader_hint(leader)),
+        Err(
+            e @ (VRaftError::TransactionAborted(_)
+            | VRaftError::FileExists(_)
+            | VRaftError::FileNotFound(_)
+            | VRaftError::PathNotInWorkspace(_)
+            | VRaftError::InvalidPath(_)
+            | VRaftError::InvalidLspRequest(_)),
+        ) => Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string())),
+        Err(e) => Err((StatusCode::SERVICE_UNAVAILABLE, e.to_string())),
+    }
+}
+
+/// Responses of a group's transaction, or why it was not applied
+fn transaction_responses(response: VfsResponse) -> VRaftResult<Vec<VfsResponse>> {
+    match response {
+        VfsResponse::Transaction(responses) => Ok(responses),
+        VfsResponse::Error(e) => Err(VRaftError::TransactionAborted(e.to_string())),
+        other => Err(VRaftError::Internal(format!("unexpected transaction response: {:?}", other))),
+    }
+}
+
+/// Routes of a batch of partition keys, for gateway metadata clients
+async fn lookup_routes(
+    State(state): State<AppState>,
+    Json(keys): Json<Vec<PartitionKey>>,
+) -> Result<Json<Vec<Option<RoutingEntry>>>, (StatusCode, String)> {
+    state
+        .metadata
+        .lookup_routes(keys)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Routing changes since the caller's table version
+async fn routing_delta(State(state): State<AppState>, Query(query): Query<RoutingDeltaQuery>) -> Json<RoutingDelta> {
+    Json(state.metadata.routing_delta(query.since).await)
+}
+
+/// Per-method metrics of the language servers, in the Prometheus text format
+async fn lsp_metrics(State(state): State<AppState>) -> String {
+    state.lsp_metrics.render()
+}
+
+/// Groups that currently reject writes
+async fn degraded_groups(State(state): State<AppState>) -> Json<Vec<DegradedGroup>> {
+    Json(state.metadata.degraded_groups().await)
+}
+
+/// Apply a client write, forwarding it to the leader if needed
+async fn client_write(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<ClientWriteRequest>,
+) -> Json<ClientWriteResponse> {
+    // Join the caller's trace, or start one for this write
+    let trace = trace_context(&headers).unwrap_or_else(TraceContext::new_root);
+    let span = tracing::info_span!(
+        "client_write",
+        group_id = %request.group_id,
+        trace_id = %trace.trace_id_hex()
+    );
+
+    // Writes to a group without quorum or with competing leaders fail fast
+    if let Err(e) = state.metadata.check_writable(request.group_id).await {
+        return Json(ClientWriteResponse::from(Err(e)));
+    }
+
+    // Writes routed before their key moved to another group are refused
+    if let Some(route) = &request.route {
+        if let Err(e) = state.metadata.check_routing_epoch(&route.key, route.epoch).await {
+            return Json(ClientWriteResponse::from(Err(e)));
+        }
+    }
+
+    let result = match state.group(request.group_id).await {
+        Some(group) => {
+            trace
+                .scope(group.forwarder.write(request))
+                .instrument(span)
+                .await
+        }
+        None => Err(VRaftError::GroupNotFound(request.group_id)),
+    };
+    Json(result.into())
+}
+
+/// Current Raft configuration of a group
+async fn get_timing(
+    State(state): State<AppState>,
+    Path(group_id): Path<u64>,
+) -> Result<Json<RaftConfig>, StatusCode> {
+    let group = state
+        .group(RaftGroupId::new(group_id))
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(group.tuner.current()))
+}
+
+/// Retune a group's Raft timing
+///
+/// The metadata group's instance is not rebuilt on retuning, so its timing
+/// only changes with the node's configuration.
+async fn update_timing(
+    State(state): State<AppState>,
+    Path(group_id): Path<u64>,
+    Json(update): Json<TimingUpdate>,
+) -> Result<Json<RaftConfig>, (StatusCode, String)> {
+    if RaftGroupId::new(group_id) == RaftGroupId::METADATA {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "the metadata group's timing cannot be changed at runtime".to_string(),
+        ));
+    }
+    let group = state
+        .group(RaftGroupId::new(group_id))
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "raft group not found".to_string()))?;
+
+    group
+        .tuner
+        .update(&update)
+        .map(Json)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+/// Progress of a group's unfinished reconfiguration
+async fn get_reconfig(
+    State(state): State<AppState>,
+    Path(group_id): Path<u64>,
+) -> Result<Json<Option<ReconfigProgress>>, (StatusCode, String)> {
+    let group = state
+        .group(RaftGroupId::new(group_id))
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "raft group not found".to_string()))?;
+
+    group
+        .reconfig
+        .progress()
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Change a group's voters; runs until the new membership is committed
+async fn start_reconfig(
+    State(state): State<AppState>,
+    Path(group_id): Path<u64>,
+    Json(plan): Json<ReconfigPlan>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let group = state
+        .group(RaftGroupId::new(group_id))
+        .await
+        .ok_or((StatusCode::NOT_FOUND, "raft group not found".to_string()))?;
+
+    group.reconfig.start(plan).await.map_err(|e| {
+        let status = match e {
+            VRaftError::NotLeader { .. } | VRaftError::ReconfigurationInProgress => StatusCode::CONFLICT,
+            VRaftError::InvalidConfig(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, e.to_string())
+    })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Settings shared by the whole cluster
+async fn shared_config(State(state): State<AppState>) -> Json<SharedConfig> {
+    Json(state.metadata.shared_config())
+}
+
+/// Change one shared setting; the change reaches every node through the
+/// metadata group
+///
+/// Only the metadata group's leader takes changes; other nodes answer 409
+/// with the leader they know and its address.
+async fn update_shared_config(
+    State(state): State<AppState>,
+    Json(change): Json<SharedConfigChange>,
+) -> Result<Json<SharedConfig>, (StatusCode, String)> {
+    match state.proposer.update_shared_config(change).await {
+        Ok(()) => Ok(Json(state.metadata.shared_config())),
+        Err(VRaftError::NotLeader { leader }) => {
+            let hint = MetadataLeaderHint {
+                leader,
+                addr: leader.and_then(|id| state.membership.get_node(id)).map(|node| node.addr),
+            };
+            Err((StatusCode::CONFLICT, serde_json::to_string(&hint).unwrap_or_default()))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+/// Whether a feature is enabled cluster-wide
+#[derive(Serialize)]
+struct FeatureState {
+    name: String,
+    enabled: bool,
+}
+
+/// State of a feature flag; unknown features are off
+async fn feature(State(state): State<AppState>, Path(name): Path<String>) -> Json<FeatureState> {
+    let enabled = state.metadata.shared_config().feature(&name);
+    Json(FeatureState { name, enabled })
+}
+
+/// Rebalancer state and replica counts
+async fn rebalance_status(State(state): State<AppState>) -> Json<RebalanceStatus> {
+    Json(state.rebalancer.status().await)
+}
+
+/// Run a rebalancing round now; returns the moves it made
+async fn run_rebalance(State(state): State<AppState>) -> Result<Json<Vec<ReplicaMove>>, (StatusCode, String)> {
+    state
+        .rebalancer
+        .run_once()
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Stop automatic rebalancing
+async fn pause_rebalance(State(state): State<AppState>) -> StatusCode {
+    state.rebalancer.pause();
+    StatusCode::NO_CONTENT
+}
+
+/// Restart automatic rebalancing
+async fn resume_rebalance(State(state): State<AppState>) -> StatusCode {
+    state.rebalancer.resume();
+    StatusCode::NO_CONTENT
+}
+
+/// Body of a maintenance request
+#[derive(Deserialize)]
+struct MaintenanceRequest {
+    enabled: bool,
+}
+
+/// Put this node into maintenance or take it out; peers learn of it with
+/// the next heartbeat
+async fn set_maintenance(
+    State(state): State<AppState>,
+    Json(request): Json<MaintenanceRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let local = state.membership.local_node_id();
+    state
+        .membership
+        .set_maintenance(local, request.enabled)
+        .map_err(decommission_error)?;
+    tracing::info!(enabled = request.enabled, "maintenance mode changed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Leave the cluster gracefully, handing off this node's leaderships
+///
+/// The node keeps serving its replicas until they are drained or it is
+/// stopped.
+async fn leave_cluster(State(state): State<AppState>) -> Result<Json<LeaveOutcome>, (StatusCode, String)> {
+    state
+        .membership
+        .leave(&state.group_mover(), state.discovery.as_ref(), &state.leave_config)
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+/// Map a decommissioning error to an HTTP status
+fn decommission_error(e: VRaftError) -> (StatusCode, String) {
+    let status = match e {
+        VRaftError::NodeUnreachable(_) => StatusCode::NOT_FOUND,
+        VRaftError::NodeNotDrained { .. } | VRaftError::InvalidConfig(_) => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, e.to_string())
+}
+
+/// Drain progress of a node
+async fn drain_status(
+    State(state): State<AppState>,
+    Path(node_id): Path<u64>,
+) -> Result<Json<DrainStatus>, (StatusCode, String)> {
+    state
+        .decommissioner
+        .status(NodeId::new(node_id))
+        .map(Json)
+        .map_err(decommission_error)
+}
+
+/// Mark a node as leaving and move what can be moved from here right away
+async fn decommission_node(
+    State(state): State<AppState>,
+    Path(node_id): Path<u64>,
+) -> Result<Json<DrainStatus>, (StatusCode, String)> {
+    let node_id = NodeId::new(node_id);
+    state.decommissioner.start(node_id).map_err(decommission_error)?;
+    state
+        .decommissioner
+        .drain(node_id)
+        .await
+        .map(Json)
+        .map_err(decommission_error)
+}
+
+/// Remove a drained node from the cluster
+async fn remove_node(
+    State(state): State<AppState>,
+    Path(node_id): Path<u64>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    state
+        .decommissioner
+        .remove(NodeId::new(node_id))
+        .map_err(decommission_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
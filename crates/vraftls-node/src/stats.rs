@@ -0,0 +1,63 @@
+//! Load statistics of this node, reported with heartbeats
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_cluster::{ClusterMembership, NodeStats};
+use vraftls_vfs::VfsHandle;
+
+/// Record this node's load in its membership entry until the task is aborted
+pub fn spawn_stats_sampler(
+    membership: Arc<ClusterMembership>,
+    vfs: VfsHandle,
+    data_dir: PathBuf,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            // Walking the data directory blocks
+            let (vfs, data_dir) = (vfs.clone(), data_dir.clone());
+            match tokio::task::spawn_blocking(move || sample(&vfs, &data_dir)).await {
+                Ok(stats) => membership.set_stats(membership.local_node_id(), stats),
+                Err(e) => tracing::warn!(error = %e, "failed to sample node stats"),
+            }
+        }
+    })
+}
+
+fn sample(vfs: &VfsHandle, data_dir: &Path) -> NodeStats {
+    NodeStats {
+        file_count: vfs.file_count() as u64,
+        memory_bytes: resident_memory().unwrap_or(0),
+        // Language servers run in the LSP gateway, not on data nodes
+        ls_processes: 0,
+        disk_bytes: dir_size(data_dir),
+    }
+}
+
+/// Resident set size of this process; `None` where /proc is unavailable
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Total size of the files below `path`
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => dir_size(&entry.path()),
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
@@ -0,0 +1,290 @@
+//! Chunking of oversized VFS commands
+//!
+//! A command whose serialized form exceeds `RaftConfig::max_entry_bytes` is
+//! split into several `Chunk` entries, so a single AppendEntries RPC never
+//! carries more than `max_append_entries * max_entry_bytes` of payload. The
+//! state machine buffers chunks and applies the command once the last one
+//! has been committed.
+
+use crate::types::{VfsRequest, VfsRequestPayload};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use vraftls_core::{RaftConfig, Timestamp};
+use vraftls_vfs::VfsCommand;
+
+/// Number of log entries after which an incomplete transfer is discarded
+pub const CHUNK_EXPIRY_ENTRIES: u64 = 10_000;
+
+/// One slice of a serialized command
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CommandChunk {
+    /// Identifies the chunked command
+    pub transfer_id: u64,
+
+    /// Position of this chunk
+    pub index: u32,
+
+    /// Total number of chunks
+    pub total: u32,
+
+    /// Slice of the JSON-serialized command
+    pub data: String,
+}
+
+/// Splits oversized requests into chunk entries
+#[derive(Clone, Debug)]
+pub struct CommandChunker {
+    /// Maximum serialized size of one entry
+    max_entry_bytes: usize,
+}
+
+impl CommandChunker {
+    pub fn new(max_entry_bytes: usize) -> Self {
+        Self {
+            max_entry_bytes: max_entry_bytes.max(64),
+        }
+    }
+
+    /// Split a request into entries that each fit in `max_entry_bytes`
+    ///
+    /// Every chunk carries the request's session, so a retried command is
+    /// deduplicated as a whole.
+    pub fn split(&self, request: VfsRequest) -> Result<Vec<VfsRequest>, serde_json::Error> {
+        let serialized = match &request.payload {
+            VfsRequestPayload::Command(command) => serde_json::to_string(command)?,
+            _ => return Ok(vec![request]),
+        };
+        if serialized.len() <= self.max_entry_bytes {
+            return Ok(vec![request]);
+        }
+
+        // Chunk data is embedded as a JSON string, where escaping can at
+        // most double its size
+        let pieces = split_at_char_boundaries(&serialized, self.max_entry_bytes / 2);
+        let total = pieces.len() as u32;
+        let transfer_id = next_transfer_id();
+
+        Ok(pieces
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| VfsRequest {
+                group_id: request.group_id,
+                payload: VfsRequestPayload::Chunk(CommandChunk {
+                    transfer_id,
+                    index: index as u32,
+                    total,
+                    data: data.to_string(),
+                }),
+                session: request.session,
+                trace: request.trace,
+            })
+            .collect())
+    }
+}
+
+impl Default for CommandChunker {
+    fn default() -> Self {
+        Self::from(&RaftConfig::default())
+    }
+}
+
+impl From<&RaftConfig> for CommandChunker {
+    fn from(config: &RaftConfig) -> Self {
+        Self::new(config.max_entry_bytes as usize)
+    }
+}
+
+/// Generate a transfer ID that is unique across proposers in practice
+fn next_transfer_id() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+    (Timestamp::now().0 << 20) ^ seq ^ (u64::from(std::process::id()) << 44)
+}
+
+/// Split a string into pieces of at most `max_bytes`, never inside a char
+fn split_at_char_boundaries(s: &str, max_bytes: usize) -> Vec<&str> {
+    let max_bytes = max_bytes.max(4);
+    let mut pieces = Vec::new();
+    let mut rest = s;
+
+    while !rest.is_empty() {
+        let mut end = max_bytes.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+
+    pieces
+}
+
+/// A chunked command still waiting for some of its chunks
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingTransfer {
+    /// Total number of chunks
+    pub total: u32,
+
+    /// Chunks received so far
+    pub chunks: BTreeMap<u32, String>,
+
+    /// Log index of the first chunk received
+    pub started_at: u64,
+}
+
+/// Result of feeding a chunk to the assembler
+#[derive(Debug)]
+pub enum ChunkOutcome {
+    /// More chunks are needed
+    Pending,
+
+    /// All chunks arrived; the reassembled command
+    Complete(VfsCommand),
+
+    /// The chunks did not form a valid command
+    Invalid(String),
+}
+
+/// Reassembles chunked commands inside the state machine
+///
+/// All decisions depend only on the applied log, so every replica buffers
+/// and expires the same transfers.
+#[derive(Debug, Default)]
+pub struct ChunkAssembler {
+    pending: BTreeMap<u64, PendingTransfer>,
+}
+
+impl ChunkAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restore buffered transfers (from a snapshot)
+    pub fn from_pending(pending: BTreeMap<u64, PendingTransfer>) -> Self {
+        Self { pending }
+    }
+
+    /// Buffered transfers (for snapshots)
+    pub fn pending(&self) -> &BTreeMap<u64, PendingTransfer> {
+        &self.pending
+    }
+
+    /// Feed a chunk committed at `log_index`
+    pub fn accept(&mut self, chunk: CommandChunk, log_index: u64) -> ChunkOutcome {
+        self.expire(log_index);
+
+        if chunk.total == 0 || chunk.index >= chunk.total {
+            return ChunkOutcome::Invalid(format!(
+                "chunk {} of {} out of range",
+                chunk.index, chunk.total
+            ));
+        }
+
+        let transfer = self
+            .pending
+            .entry(chunk.transfer_id)
+            .or_insert_with(|| PendingTransfer {
+                total: chunk.total,
+                chunks: BTreeMap::new(),
+                started_at: log_index,
+            });
+        transfer.chunks.insert(chunk.index, chunk.data);
+
+        if transfer.chunks.len() < transfer.total as usize {
+            return ChunkOutcome::Pending;
+        }
+
+        let transfer = self
+            .pending
+            .remove(&chunk.transfer_id)
+            .expect("transfer was just updated");
+        let serialized: String = transfer.chunks.into_values().collect();
+
+        match serde_json::from_str(&serialized) {
+            Ok(command) => ChunkOutcome::Complete(command),
+            Err(e) => ChunkOutcome::Invalid(format!("failed to decode chunked command: {}", e)),
+        }
+    }
+
+    /// Drop transfers that have been incomplete for too long
+    fn expire(&mut self, log_index: u64) {
+        self.pending.retain(|id, transfer| {
+            let alive = transfer.started_at + CHUNK_EXPIRY_ENTRIES > log_index;
+            if !alive {
+                tracing::warn!(transfer_id = id, "discarding incomplete chunked command");
+            }
+            alive
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vraftls_core::RaftGroupId;
+    use vraftls_vfs::VfsPath;
+
+    fn large_create(len: usize) -> VfsRequest {
+        VfsRequest::new(
+            RaftGroupId::new(1),
+            VfsCommand::CreateFile {
+                path: VfsPath::new("/big.rs"),
+                content: "é\"x\n".repeat(len),
+            },
+        )
+    }
+
+    #[test]
+    fn test_small_request_is_not_chunked() {
+        let chunker = CommandChunker::new(1024);
+        let entries = chunker.split(large_create(4)).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(matches!(entries[0].payload, VfsRequestPayload::Command(_)));
+    }
+
+    #[test]
+    fn test_chunks_fit_and_reassemble() {
+        let chunker = CommandChunker::new(256);
+        let entries = chunker.split(large_create(500)).unwrap();
+        assert!(entries.len() > 1);
+
+        let mut assembler = ChunkAssembler::new();
+        let mut outcome = ChunkOutcome::Pending;
+        for (index, entry) in entries.into_iter().enumerate() {
+            assert!(serde_json::to_vec(&entry).unwrap().len() <= 256 + 128);
+            match entry.payload {
+                VfsRequestPayload::Chunk(chunk) => outcome = assembler.accept(chunk, index as u64),
+                _ => panic!("expected chunk"),
+            }
+        }
+
+        match outcome {
+            ChunkOutcome::Complete(VfsCommand::CreateFile { content, .. }) => {
+                assert_eq!(content, "é\"x\n".repeat(500));
+            }
+            other => panic!("expected complete command, got {:?}", other),
+        }
+        assert!(assembler.pending().is_empty());
+    }
+
+    #[test]
+    fn test_incomplete_transfer_expires() {
+        let mut assembler = ChunkAssembler::new();
+        let chunk = CommandChunk {
+            transfer_id: 7,
+            index: 0,
+            total: 2,
+            data: "{".to_string(),
+        };
+        assert!(matches!(assembler.accept(chunk.clone(), 1), ChunkOutcome::Pending));
+
+        let other = CommandChunk {
+            transfer_id: 8,
+            ..chunk
+        };
+        assembler.accept(other, 1 + CHUNK_EXPIRY_ENTRIES);
+        assert!(!assembler.pending().contains_key(&7));
+    }
+}
@@ -0,0 +1,186 @@
+//! Compression of Raft RPC bodies
+//!
+//! Large AppendEntries batches carry full file contents, so request bodies
+//! above a size threshold are compressed with gzip or zstd and sent with a
+//! `Content-Encoding` header. Receivers advertise the encodings they can
+//! decode in an `Accept-Encoding` response header (RFC 7694); senders only
+//! compress once the peer has advertised the chosen encoding, and fall back
+//! to identity when a peer answers `415 Unsupported Media Type`. Decoded
+//! bodies are capped, so a small compressed body cannot expand without bound.
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{self, Read, Write};
+use thiserror::Error;
+use vraftls_core::{RaftConfig, RpcCompression};
+
+/// Encodings a receiver can decode, for its `Accept-Encoding` header
+pub const ACCEPT_ENCODING: &str = "zstd, gzip";
+
+/// Content encoding of an RPC body
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// HTTP token of the encoding
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    /// Parse an HTTP encoding token
+    pub fn parse(token: &str) -> Option<Self> {
+        match token.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compress a body
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Self::Zstd => zstd::encode_all(data, 0),
+        }
+    }
+
+    /// Decompress a body, reading at most `limit` bytes plus one
+    pub fn decompress(&self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        let limit = limit as u64 + 1;
+        match self {
+            Self::Gzip => GzDecoder::new(data).take(limit).read_to_end(&mut out)?,
+            Self::Zstd => zstd::stream::read::Decoder::new(data)?.take(limit).read_to_end(&mut out)?,
+        };
+        Ok(out)
+    }
+}
+
+/// Why a received body could not be decoded
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("unsupported content encoding: {0}")]
+    Unsupported(String),
+
+    #[error("body cannot be decoded: {0}")]
+    Corrupt(#[from] io::Error),
+
+    #[error("body exceeds {limit} bytes once decoded")]
+    TooLarge { limit: usize },
+}
+
+/// Encodings listed in an `Accept-Encoding` header, skipping `q=0` entries
+pub fn parse_accept_encoding(header: &str) -> Vec<Encoding> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let encoding = Encoding::parse(parts.next()?)?;
+            let refused = parts.any(|p| matches!(p.trim().strip_prefix("q="), Some(q) if q.parse::<f32>() == Ok(0.0)));
+            (!refused).then_some(encoding)
+        })
+        .collect()
+}
+
+/// Decode a received body according to its `Content-Encoding` header,
+/// refusing bodies larger than `limit` bytes once decoded
+pub fn decode_body(content_encoding: Option<&str>, body: &[u8], limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let decoded = match content_encoding.map(str::trim) {
+        None | Some("") | Some("identity") => body.to_vec(),
+        Some(token) => Encoding::parse(token)
+            .ok_or_else(|| DecodeError::Unsupported(token.to_string()))?
+            .decompress(body, limit)?,
+    };
+    if decoded.len() > limit {
+        return Err(DecodeError::TooLarge { limit });
+    }
+    Ok(decoded)
+}
+
+/// Settings for compressing outgoing RPC bodies
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    /// Preferred encoding (`None` disables compression)
+    pub encoding: Option<Encoding>,
+
+    /// Bodies smaller than this are sent as-is
+    pub min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self::from(&RaftConfig::default())
+    }
+}
+
+impl From<&RaftConfig> for CompressionConfig {
+    fn from(config: &RaftConfig) -> Self {
+        Self {
+            encoding: match config.rpc_compression {
+                RpcCompression::None => None,
+                RpcCompression::Gzip => Some(Encoding::Gzip),
+                RpcCompression::Zstd => Some(Encoding::Zstd),
+            },
+            min_size: config.rpc_compression_threshold as usize,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Encoding to use for a body of `len` bytes sent to a peer accepting `accepted`
+    pub fn choose(&self, len: usize, accepted: &[Encoding]) -> Option<Encoding> {
+        self.encoding
+            .filter(|encoding| len >= self.min_size && accepted.contains(encoding))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_negotiation() {
+        let body = b"{\"entries\":[]}".repeat(100);
+        for encoding in [Encoding::Gzip, Encoding::Zstd] {
+            let compressed = encoding.compress(&body).unwrap();
+            assert!(compressed.len() < body.len());
+            assert_eq!(decode_body(Some(encoding.as_str()), &compressed, body.len()).unwrap(), body);
+        }
+        assert!(matches!(decode_body(Some("br"), &body, body.len()), Err(DecodeError::Unsupported(_))));
+
+        let accepted = parse_accept_encoding("gzip;q=0, zstd");
+        assert_eq!(accepted, vec![Encoding::Zstd]);
+
+        let config = CompressionConfig {
+            encoding: Some(Encoding::Zstd),
+            min_size: 1024,
+        };
+        assert_eq!(config.choose(body.len(), &accepted), Some(Encoding::Zstd));
+        assert_eq!(config.choose(100, &accepted), None);
+        assert_eq!(config.choose(body.len(), &[Encoding::Gzip]), None);
+    }
+
+    #[test]
+    fn test_decoded_size_limit() {
+        // A megabyte of zeros compresses to a few hundred bytes
+        let body = vec![0u8; 1024 * 1024];
+        for encoding in [Encoding::Gzip, Encoding::Zstd] {
+            let compressed = encoding.compress(&body).unwrap();
+            assert!(compressed.len() < 64 * 1024);
+            let decoded = decode_body(Some(encoding.as_str()), &compressed, 64 * 1024);
+            assert!(matches!(decoded, Err(DecodeError::TooLarge { limit: 65536 })));
+        }
+        assert!(matches!(decode_body(None, &body, 1024), Err(DecodeError::TooLarge { .. })));
+        assert_eq!(decode_body(None, &body, body.len()).unwrap().len(), body.len());
+    }
+}
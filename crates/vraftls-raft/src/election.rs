@@ -0,0 +1,155 @@
+//! Election priority hints
+//!
+//! OpenRaft 0.9 has no notion of leader priority, so priorities are built on
+//! the facilities it does have:
+//! - a node's election timeouts shrink with its `election_priority`, so
+//!   preferred nodes usually time out and win first;
+//! - a leader that sees a caught-up voter with a higher priority in the
+//!   membership pauses its heartbeats and elections, letting that node take
+//!   over once the leader's lease expires.
+//!
+//! Both are hints: any voter can still become leader when the preferred
+//! nodes are unavailable.
+
+use crate::types::{RaftNodeId, VRaftNode};
+use crate::VRaftRaft;
+use openraft::RaftMetrics;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::RaftConfig;
+
+/// Election timeouts scaled down for a node of the given priority
+///
+/// Priority 0 keeps the configured timeouts, so invalid settings still fail
+/// validation.
+pub fn prioritized_timeouts(heartbeat: u64, min: u64, max: u64, priority: u8) -> (u64, u64) {
+    if priority == 0 {
+        return (min, max);
+    }
+
+    let scale = |ms: u64| ms * 10 / (10 + priority as u64);
+    // OpenRaft requires heartbeat < min < max
+    let min = scale(min).max(heartbeat + 1);
+    let max = scale(max).max(min + 1);
+    (min, max)
+}
+
+/// Caught-up voter that should lead instead of a leader with priority `own`
+pub fn pick_successor(own: u8, candidates: impl IntoIterator<Item = (RaftNodeId, u8, bool)>) -> Option<RaftNodeId> {
+    candidates
+        .into_iter()
+        .filter(|(_, priority, caught_up)| *priority > own && *caught_up)
+        .max_by_key(|(id, priority, _)| (*priority, std::cmp::Reverse(*id)))
+        .map(|(id, _, _)| id)
+}
+
+/// Hands leadership to higher-priority voters
+pub struct LeadershipHandoff {
+    raft: VRaftRaft,
+
+    /// How long the leader pauses heartbeats and elections when stepping aside
+    pause: Duration,
+
+    /// Minimum time between two handoffs
+    cooldown: Duration,
+
+    /// Interval between checks
+    check_interval: Duration,
+}
+
+impl LeadershipHandoff {
+    pub fn new(raft: VRaftRaft, config: &RaftConfig) -> Self {
+        Self {
+            raft,
+            // Long enough for every follower's leader lease to expire
+            pause: config.election_timeout_max * 2,
+            cooldown: config.election_timeout_max * 20,
+            check_interval: config.election_timeout_max,
+        }
+    }
+
+    /// Preferred successor of this node, if it is the leader
+    fn successor(metrics: &RaftMetrics<RaftNodeId, VRaftNode>) -> Option<RaftNodeId> {
+        if metrics.current_leader != Some(metrics.id) {
+            return None;
+        }
+
+        let membership = metrics.membership_config.membership();
+        let own = membership.get_node(&metrics.id).map_or(0, |n| n.priority);
+        let last_index = metrics.last_log_index.unwrap_or(0);
+        let replication = metrics.replication.as_ref()?;
+
+        pick_successor(
+            own,
+            membership.voter_ids().filter(|id| *id != metrics.id).map(|id| {
+                let priority = membership.get_node(&id).map_or(0, |n| n.priority);
+                let caught_up = replication
+                    .get(&id)
+                    .and_then(|matched| matched.as_ref())
+                    .is_some_and(|matched| matched.index >= last_index);
+                (id, priority, caught_up)
+            }),
+        )
+    }
+
+    /// Step aside once if a preferred voter is ready
+    ///
+    /// Returns the node leadership was offered to.
+    pub async fn check(&self) -> Option<RaftNodeId> {
+        let successor = Self::successor(&self.raft.metrics().borrow())?;
+
+        tracing::info!(successor, "stepping aside for higher-priority node");
+        self.step_aside().await;
+
+        Some(successor)
+    }
+
+    /// Pause heartbeats and elections long enough for another voter to win
+    /// an election
+    pub async fn step_aside(&self) {
+        let runtime = self.raft.runtime_config();
+        runtime.heartbeat(false);
+        runtime.elect(false);
+        tokio::time::sleep(self.pause).await;
+        runtime.elect(true);
+        runtime.heartbeat(true);
+    }
+}
+
+/// Periodically hand leadership to higher-priority voters
+pub fn spawn_leadership_handoff(handoff: LeadershipHandoff) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut metrics_rx = handoff.raft.metrics();
+        loop {
+            tokio::time::sleep(handoff.check_interval).await;
+            if handoff.check().await.is_some() {
+                tokio::time::sleep(handoff.cooldown).await;
+            }
+
+            // Stop once the Raft instance has shut down
+            if metrics_rx.has_changed().is_err() {
+                break;
+            }
+            metrics_rx.mark_unchanged();
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_priority_hints() {
+        assert_eq!(prioritized_timeouts(100, 300, 500, 0), (300, 500));
+        assert_eq!(prioritized_timeouts(100, 500, 300, 0), (500, 300));
+        let (min, max) = prioritized_timeouts(100, 300, 500, 5);
+        assert_eq!((min, max), (200, 333));
+        // Timeouts never drop to the heartbeat interval
+        assert_eq!(prioritized_timeouts(100, 300, 500, 255), (101, 102));
+
+        let candidates = [(2, 5, true), (3, 9, false), (4, 5, true), (5, 1, true)];
+        assert_eq!(pick_successor(1, candidates), Some(2));
+        assert_eq!(pick_successor(5, candidates), None);
+    }
+}
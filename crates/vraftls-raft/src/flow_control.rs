@@ -0,0 +1,167 @@
+//! Flow control for outgoing replication traffic
+//!
+//! Limits how many AppendEntries requests and how many bytes may be in
+//! flight to each peer, so a slow follower cannot make the leader buffer
+//! without bound. Heartbeats (empty AppendEntries) and votes bypass the
+//! limits, and snapshot chunks use their own single slot, so neither is
+//! stuck behind bulk replication.
+
+use crate::types::RaftNodeId;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use vraftls_core::RaftConfig;
+
+/// Per-peer flow control limits
+#[derive(Clone, Debug)]
+pub struct FlowControlConfig {
+    /// Maximum concurrent AppendEntries requests per peer
+    pub max_inflight_appends: usize,
+
+    /// Maximum request bytes in flight per peer
+    pub max_inflight_bytes: usize,
+}
+
+impl Default for FlowControlConfig {
+    fn default() -> Self {
+        Self::from(&RaftConfig::default())
+    }
+}
+
+impl From<&RaftConfig> for FlowControlConfig {
+    fn from(config: &RaftConfig) -> Self {
+        Self {
+            max_inflight_appends: config.max_inflight_appends.max(1) as usize,
+            max_inflight_bytes: config.max_inflight_bytes.clamp(1, u32::MAX as u64) as usize,
+        }
+    }
+}
+
+/// Capacity held by an in-flight request; released on drop
+pub struct FlowPermit {
+    _slot: OwnedSemaphorePermit,
+    _bytes: OwnedSemaphorePermit,
+}
+
+/// Flow control state for one peer
+#[derive(Clone)]
+pub struct PeerFlowControl {
+    /// Slots for concurrent AppendEntries
+    appends: Arc<Semaphore>,
+
+    /// Slot for snapshot chunks
+    snapshots: Arc<Semaphore>,
+
+    /// Byte budget shared by appends and snapshot chunks
+    bytes: Arc<Semaphore>,
+
+    /// Size of the byte budget
+    max_bytes: usize,
+}
+
+impl PeerFlowControl {
+    pub fn new(config: &FlowControlConfig) -> Self {
+        Self {
+            appends: Arc::new(Semaphore::new(config.max_inflight_appends)),
+            snapshots: Arc::new(Semaphore::new(1)),
+            bytes: Arc::new(Semaphore::new(config.max_inflight_bytes)),
+            max_bytes: config.max_inflight_bytes,
+        }
+    }
+
+    /// Wait for capacity to send an AppendEntries request of `size` bytes
+    pub async fn acquire_append(&self, size: usize) -> FlowPermit {
+        let slot = self.appends.clone().acquire_owned().await;
+        self.with_bytes(slot.expect("semaphore is never closed"), size)
+            .await
+    }
+
+    /// Wait for capacity to send a snapshot chunk of `size` bytes
+    pub async fn acquire_snapshot(&self, size: usize) -> FlowPermit {
+        let slot = self.snapshots.clone().acquire_owned().await;
+        self.with_bytes(slot.expect("semaphore is never closed"), size)
+            .await
+    }
+
+    async fn with_bytes(&self, slot: OwnedSemaphorePermit, size: usize) -> FlowPermit {
+        // A request larger than the budget waits for the whole budget
+        let size = size.clamp(1, self.max_bytes) as u32;
+        let bytes = self
+            .bytes
+            .clone()
+            .acquire_many_owned(size)
+            .await
+            .expect("semaphore is never closed");
+
+        FlowPermit {
+            _slot: slot,
+            _bytes: bytes,
+        }
+    }
+
+    /// Bytes currently in flight
+    pub fn inflight_bytes(&self) -> usize {
+        self.max_bytes - self.bytes.available_permits()
+    }
+}
+
+/// Flow control for all peers of a node
+pub struct FlowControl {
+    config: FlowControlConfig,
+    peers: Mutex<HashMap<RaftNodeId, PeerFlowControl>>,
+}
+
+impl FlowControl {
+    pub fn new(config: FlowControlConfig) -> Self {
+        Self {
+            config,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Flow control state for a peer, shared by all clients to that peer
+    pub fn peer(&self, target: RaftNodeId) -> PeerFlowControl {
+        self.peers
+            .lock()
+            .unwrap()
+            .entry(target)
+            .or_insert_with(|| PeerFlowControl::new(&self.config))
+            .clone()
+    }
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        Self::new(FlowControlConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_appends_are_limited_per_peer() {
+        let flow = FlowControl::new(FlowControlConfig {
+            max_inflight_appends: 2,
+            max_inflight_bytes: 100,
+        });
+        let peer = flow.peer(2);
+
+        let first = peer.acquire_append(30).await;
+        let _second = flow.peer(2).acquire_append(30).await;
+        assert_eq!(peer.inflight_bytes(), 60);
+
+        // Out of slots: the third request waits until one completes
+        let third = tokio::time::timeout(Duration::from_millis(20), peer.acquire_append(10));
+        assert!(third.await.is_err());
+
+        drop(first);
+        let _third = peer.acquire_append(10).await;
+        assert_eq!(peer.inflight_bytes(), 40);
+
+        // Other peers are unaffected
+        let _other = flow.peer(3).acquire_append(100).await;
+    }
+}
@@ -0,0 +1,267 @@
+//! Forwarding of client writes to the Raft leader
+//!
+//! Followers accept client writes and proxy them to the current leader's
+//! client API, so callers do not need to track leadership themselves.
+
+use crate::proposal::VfsProposer;
+use crate::session::RequestSession;
+use crate::trace_context::{TraceContext, TRACEPARENT};
+use crate::types::RaftNodeId;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use vraftls_core::{NodeId, PartitionKey, RaftGroupId, Result, VRaftError};
+use vraftls_vfs::{VfsCommand, VfsResponse};
+
+/// Maximum number of node-to-node hops for a forwarded write
+const MAX_HOPS: u32 = 3;
+
+/// Client write request accepted by the node's client API
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientWriteRequest {
+    /// Target Raft group
+    pub group_id: RaftGroupId,
+
+    /// Command to apply
+    pub command: VfsCommand,
+
+    /// Client session for deduplication of retries
+    #[serde(default)]
+    pub session: Option<RequestSession>,
+
+    /// Number of times this request has been forwarded
+    #[serde(default)]
+    pub hops: u32,
+
+    /// Route the caller sent the write by; rejected once the key moved on
+    #[serde(default)]
+    pub route: Option<RouteEpoch>,
+}
+
+/// Partition key a write was routed by, with the epoch of its route
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RouteEpoch {
+    pub key: PartitionKey,
+    pub epoch: u64,
+}
+
+impl ClientWriteRequest {
+    pub fn new(group_id: RaftGroupId, command: VfsCommand) -> Self {
+        Self {
+            group_id,
+            command,
+            session: None,
+            hops: 0,
+            route: None,
+        }
+    }
+
+    /// Tag the request with the route it was sent by
+    pub fn with_route(mut self, key: PartitionKey, epoch: u64) -> Self {
+        self.route = Some(RouteEpoch { key, epoch });
+        self
+    }
+
+    /// Tag the request with a client session
+    pub fn with_session(mut self, session: RequestSession) -> Self {
+        self.session = Some(session);
+        self
+    }
+}
+
+/// Client write response returned by the node's client API
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ClientWriteResponse {
+    /// The command was applied
+    Applied(VfsResponse),
+
+    /// The node could not reach the leader
+    NotLeader { leader: Option<RaftNodeId> },
+
+    /// The write failed
+    Error(String),
+}
+
+impl From<Result<VfsResponse>> for ClientWriteResponse {
+    fn from(result: Result<VfsResponse>) -> Self {
+        match result {
+            Ok(response) => Self::Applied(response),
+            Err(VRaftError::NotLeader { leader }) => Self::NotLeader {
+                leader: leader.map(|id| id.0),
+            },
+            Err(e) => Self::Error(e.to_string()),
+        }
+    }
+}
+
+/// Settings for leader forwarding
+#[derive(Clone, Debug)]
+pub struct ForwardConfig {
+    /// Attempts before giving up
+    pub max_attempts: u32,
+
+    /// Delay between attempts (e.g. while an election is running)
+    pub retry_backoff: Duration,
+
+    /// Timeout of a single forwarded request
+    pub request_timeout: Duration,
+}
+
+impl Default for ForwardConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            retry_backoff: Duration::from_millis(100),
+            request_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Applies client writes locally or forwards them to the leader
+#[derive(Clone)]
+pub struct LeaderForwarder {
+    /// Local proposer
+    proposer: VfsProposer,
+
+    /// HTTP client for forwarding
+    client: Client,
+
+    /// Forwarding settings
+    config: ForwardConfig,
+}
+
+impl LeaderForwarder {
+    pub fn new(proposer: VfsProposer) -> Self {
+        Self::with_config(proposer, ForwardConfig::default())
+    }
+
+    pub fn with_config(proposer: VfsProposer, config: ForwardConfig) -> Self {
+        let client = Client::builder()
+            .timeout(config.request_timeout)
+            .build()
+            .expect("Failed to create HTTP client");
+
+        Self {
+            proposer,
+            client,
+            config,
+        }
+    }
+
+    /// Get the Raft group ID
+    pub fn group_id(&self) -> RaftGroupId {
+        self.proposer.group_id()
+    }
+
+    /// Proposer of the local Raft instance, for writes only its leader takes
+    pub fn proposer(&self) -> &VfsProposer {
+        &self.proposer
+    }
+
+    /// Apply a write on the leader, forwarding and retrying as needed
+    ///
+    /// The leader may have applied a write whose forwarding failed, so such
+    /// a write is only retried when the request carries a session; without
+    /// one, the error is returned.
+    pub async fn write(&self, request: ClientWriteRequest) -> Result<VfsResponse> {
+        let mut last_error = VRaftError::NotLeader { leader: None };
+
+        for attempt in 0..self.config.max_attempts.max(1) {
+            if attempt > 0 {
+                tokio::time::sleep(self.config.retry_backoff).await;
+            }
+
+            let result = match request.session {
+                Some(session) => {
+                    self.proposer
+                        .propose_with_session(request.command.clone(), session)
+                        .await
+                }
+                None => self.proposer.propose(request.command.clone()).await,
+            };
+
+            let leader = match result {
+                Err(VRaftError::NotLeader { leader }) => leader.map(|id| id.0),
+                other => return other,
+            };
+
+            if request.hops >= MAX_HOPS {
+                return Err(VRaftError::NotLeader {
+                    leader: leader.map(NodeId::new),
+                });
+            }
+
+            // No known leader yet: wait for the election to settle
+            let Some(addr) = self.leader_addr(leader) else {
+                last_error = VRaftError::NotLeader { leader: None };
+                continue;
+            };
+
+            match self.forward(&addr, &request).await {
+                Ok(ClientWriteResponse::Applied(response)) => return Ok(response),
+                Ok(ClientWriteResponse::NotLeader { leader }) => {
+                    last_error = VRaftError::NotLeader {
+                        leader: leader.map(NodeId::new),
+                    };
+                }
+                Ok(ClientWriteResponse::Error(message)) => {
+                    return Err(VRaftError::RaftConsensus(message))
+                }
+                Err(e) => {
+                    tracing::debug!(group_id = %self.group_id(), %addr, error = %e, "forwarding failed");
+                    if request.session.is_none() {
+                        return Err(e);
+                    }
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Resolve the leader's address from the membership
+    fn leader_addr(&self, leader: Option<RaftNodeId>) -> Option<String> {
+        let metrics = self.proposer.raft().metrics().borrow().clone();
+        let leader = leader.or(metrics.current_leader)?;
+
+        let addr = metrics
+            .membership_config
+            .nodes()
+            .find(|(id, _)| **id == leader)
+            .map(|(_, node)| node.addr.clone());
+        addr
+    }
+
+    /// Send the request to another node's client API
+    async fn forward(&self, addr: &str, request: &ClientWriteRequest) -> Result<ClientWriteResponse> {
+        let url = format!("http://{}/client/write", addr);
+        let forwarded = ClientWriteRequest {
+            hops: request.hops + 1,
+            ..request.clone()
+        };
+
+        let mut http_request = self.client.post(&url).json(&forwarded);
+        if let Some(trace) = TraceContext::current() {
+            http_request = http_request.header(TRACEPARENT, trace.child().to_string());
+        }
+
+        let response = http_request
+            .send()
+            .await
+            .map_err(|e| VRaftError::ConnectionFailed(e.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(VRaftError::ConnectionFailed(format!(
+                "HTTP {} from {}",
+                response.status(),
+                addr
+            )));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|e| VRaftError::Serialization(e.to_string()))
+    }
+}
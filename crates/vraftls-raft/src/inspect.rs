@@ -0,0 +1,207 @@
+//! Raft group introspection
+//!
+//! Exposes a serializable view of a group's Raft state for debugging stuck
+//! groups (e.g. via the node's `/raft/status` endpoint).
+
+use crate::snapshot_store::{SnapshotGcStats, SnapshotStore};
+use crate::storage::RocksDbLogStorage;
+use crate::types::RaftNodeId;
+use crate::VRaftRaft;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use vraftls_core::RaftGroupId;
+
+/// Snapshot of a Raft group's state on this node
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RaftStatus {
+    /// Raft group ID
+    pub group_id: RaftGroupId,
+
+    /// This node's ID
+    pub node_id: RaftNodeId,
+
+    /// Server state (Leader, Follower, ...)
+    pub state: String,
+
+    /// Current term
+    pub current_term: u64,
+
+    /// Last accepted vote
+    pub vote: VoteStatus,
+
+    /// Current leader, if known
+    pub current_leader: Option<RaftNodeId>,
+
+    /// Last log index appended to the local log
+    pub last_log_index: Option<u64>,
+
+    /// Last committed log index persisted locally
+    pub committed_index: Option<u64>,
+
+    /// Last log index applied to the state machine
+    pub last_applied_index: Option<u64>,
+
+    /// Last log index included in the current snapshot
+    pub snapshot_index: Option<u64>,
+
+    /// Last purged log index
+    pub purged_index: Option<u64>,
+
+    /// Milliseconds since a quorum acknowledged this leader
+    pub millis_since_quorum_ack: Option<u64>,
+
+    /// Effective membership
+    pub membership: MembershipStatus,
+
+    /// Per-follower replication progress (only on the leader)
+    pub replication: Vec<ReplicationProgress>,
+
+    /// Garbage collection of persisted snapshots, including bytes reclaimed
+    pub snapshot_gc: Option<SnapshotGcStats>,
+}
+
+/// Last accepted vote
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VoteStatus {
+    pub term: u64,
+    pub voted_for: Option<RaftNodeId>,
+    pub committed: bool,
+}
+
+/// Effective membership configuration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MembershipStatus {
+    /// Log index where this membership was proposed
+    pub log_index: Option<u64>,
+
+    /// Voting members
+    pub voters: Vec<RaftNodeId>,
+
+    /// Non-voting members
+    pub learners: Vec<RaftNodeId>,
+
+    /// Addresses of all members
+    pub nodes: BTreeMap<RaftNodeId, String>,
+}
+
+/// Replication progress of one follower
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplicationProgress {
+    /// Follower node ID
+    pub node_id: RaftNodeId,
+
+    /// Highest log index known to be replicated to the follower
+    pub matched_index: Option<u64>,
+
+    /// Entries the follower is behind the leader's log
+    pub lag: u64,
+}
+
+/// Read-only inspector for a Raft group
+#[derive(Clone)]
+pub struct RaftInspector {
+    /// Raft group ID
+    group_id: RaftGroupId,
+
+    /// The Raft instance
+    raft: VRaftRaft,
+
+    /// Log storage, for the persisted commit index
+    log_storage: Option<Arc<RocksDbLogStorage>>,
+
+    /// Snapshot store, for its garbage collection statistics
+    snapshot_store: Option<Arc<SnapshotStore>>,
+}
+
+impl RaftInspector {
+    pub fn new(group_id: RaftGroupId, raft: VRaftRaft) -> Self {
+        Self {
+            group_id,
+            raft,
+            log_storage: None,
+            snapshot_store: None,
+        }
+    }
+
+    /// Report the committed index from the given log storage
+    pub fn with_log_storage(mut self, log_storage: Arc<RocksDbLogStorage>) -> Self {
+        self.log_storage = Some(log_storage);
+        self
+    }
+
+    /// Report garbage collection statistics of the given snapshot store
+    pub fn with_snapshot_store(mut self, snapshot_store: Arc<SnapshotStore>) -> Self {
+        self.snapshot_store = Some(snapshot_store);
+        self
+    }
+
+    /// Get the Raft group ID
+    pub fn group_id(&self) -> RaftGroupId {
+        self.group_id
+    }
+
+    /// Collect the current status of the group
+    pub async fn status(&self) -> RaftStatus {
+        let metrics = self.raft.metrics().borrow().clone();
+
+        let committed_index = match &self.log_storage {
+            Some(storage) => storage.committed().await.map(|id| id.index),
+            None => None,
+        };
+
+        let membership = &metrics.membership_config;
+        let voters: Vec<_> = membership.voter_ids().collect();
+        let learners = membership
+            .membership()
+            .learner_ids()
+            .collect();
+        let nodes = membership
+            .nodes()
+            .map(|(id, node)| (*id, node.addr.clone()))
+            .collect();
+
+        let last_log_index = metrics.last_log_index.unwrap_or(0);
+        let replication = metrics
+            .replication
+            .iter()
+            .flatten()
+            .filter(|(id, _)| **id != metrics.id)
+            .map(|(id, matched)| {
+                let matched_index = matched.map(|l| l.index);
+                ReplicationProgress {
+                    node_id: *id,
+                    matched_index,
+                    lag: last_log_index.saturating_sub(matched_index.unwrap_or(0)),
+                }
+            })
+            .collect();
+
+        RaftStatus {
+            group_id: self.group_id,
+            node_id: metrics.id,
+            state: format!("{:?}", metrics.state),
+            current_term: metrics.current_term,
+            vote: VoteStatus {
+                term: metrics.vote.leader_id().get_term(),
+                voted_for: metrics.vote.leader_id().voted_for(),
+                committed: metrics.vote.committed,
+            },
+            current_leader: metrics.current_leader,
+            last_log_index: metrics.last_log_index,
+            committed_index,
+            last_applied_index: metrics.last_applied.map(|l| l.index),
+            snapshot_index: metrics.snapshot.map(|l| l.index),
+            purged_index: metrics.purged.map(|l| l.index),
+            millis_since_quorum_ack: metrics.millis_since_quorum_ack,
+            membership: MembershipStatus {
+                log_index: membership.log_id().map(|l| l.index),
+                voters,
+                learners,
+                nodes,
+            },
+            replication,
+            snapshot_gc: self.snapshot_store.as_ref().map(|store| store.gc_stats()),
+        }
+    }
+}
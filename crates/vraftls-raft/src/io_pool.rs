@@ -0,0 +1,92 @@
+//! Dedicated thread pool for blocking storage I/O
+//!
+//! RocksDB calls can block for a long time (e.g. during a compaction stall).
+//! Running them on Tokio worker threads would freeze Raft timers, so storage
+//! jobs are sent through a bounded queue to a small set of OS threads.
+
+use std::io;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Bounded pool of threads for blocking I/O
+pub struct IoPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl IoPool {
+    /// Start `threads` workers fed by a queue of `queue_size` jobs
+    pub fn new(name: &str, threads: usize, queue_size: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job>(queue_size.max(1));
+        let rx = Arc::new(Mutex::new(rx));
+
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || loop {
+                    // Only one idle worker waits on the queue at a time
+                    let job = rx.lock().unwrap().blocking_recv();
+                    match job {
+                        Some(job) => {
+                            if catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                tracing::error!("storage I/O job panicked");
+                            }
+                        }
+                        None => break,
+                    }
+                })
+                .expect("Failed to spawn I/O thread");
+        }
+
+        Self { jobs }
+    }
+
+    /// Run a blocking job on the pool and wait for its result
+    ///
+    /// Waits asynchronously while the queue is full.
+    pub async fn run<F, R>(&self, f: F) -> io::Result<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = reply_tx.send(f());
+        });
+
+        self.jobs
+            .send(job)
+            .await
+            .map_err(|_| io::Error::other("storage I/O pool stopped"))?;
+        reply_rx
+            .await
+            .map_err(|_| io::Error::other("storage I/O job failed"))
+    }
+}
+
+impl Default for IoPool {
+    fn default() -> Self {
+        Self::new("vraftls-io", 2, 1024)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_jobs_run_off_runtime() {
+        let pool = IoPool::new("test-io", 2, 4);
+        let name = pool
+            .run(|| std::thread::current().name().map(str::to_string))
+            .await
+            .unwrap();
+        assert!(name.unwrap().starts_with("test-io-"));
+
+        assert!(pool.run(|| panic!("boom")).await.is_err());
+        assert_eq!(pool.run(|| 42).await.unwrap(), 42);
+    }
+}
@@ -4,17 +4,80 @@
 //!
 //! - `types`: Type definitions for OpenRaft integration
 //! - `storage`: RocksDB-backed log storage
+//! - `io_pool`: Dedicated threads for blocking storage I/O
+//! - `archive`: Shipping of purged logs and snapshots to object storage
+//! - `log_dump`: JSON-lines export of log entries for debugging
 //! - `state_machine`: VFS state machine that applies committed entries
+//! - `snapshot`: Incremental, throttled snapshot building
+//! - `snapshot_trigger`: Automatic snapshots from log size thresholds
+//! - `snapshot_store`: On-disk snapshots and their retention
+//! - `chunking`: Splitting of oversized commands into multiple entries
+//! - `proposal`: Client-side proposal of VFS commands
+//! - `forward`: Forwarding of client writes to the leader
+//! - `inspect`: Serializable introspection of Raft group state
+//! - `reconfig`: Resumable multi-step membership changes
+//! - `session`: Client sessions for write deduplication
+//! - `stale_read`: Bounded-staleness reads served by followers
+//! - `trace_context`: W3C trace-context propagation across RPCs
+//! - `trash_expiry`: Leader-driven purging of expired trashed files
+//! - `tuning`: Runtime tuning of Raft timing
 //! - `network`: HTTP-based inter-node communication
+//! - `local_network`: In-process network with fault injection for tests
+//! - `compression`: Content-encoding of Raft RPC bodies
+//! - `election`: Election priority hints
+//! - `flow_control`: Per-peer limits on in-flight replication traffic
 
+// OpenRaft's StorageError is large; it is returned as-is throughout the crate.
+#![allow(clippy::result_large_err)]
+
+pub mod archive;
+pub mod chunking;
+pub mod compression;
+pub mod election;
+pub mod flow_control;
+pub mod forward;
+pub mod inspect;
+pub mod io_pool;
+pub mod local_network;
+pub mod log_dump;
 pub mod network;
+pub mod proposal;
+pub mod reconfig;
+pub mod session;
+pub mod snapshot;
+pub mod snapshot_store;
+pub mod snapshot_trigger;
+pub mod stale_read;
 pub mod state_machine;
 pub mod storage;
+pub mod trace_context;
+pub mod trash_expiry;
+pub mod tuning;
 pub mod types;
 
+pub use archive::{ArchiveObject, Archiver, HttpObjectStore, LocalObjectStore, ObjectStore};
+pub use chunking::{ChunkAssembler, CommandChunk, CommandChunker};
+pub use compression::CompressionConfig;
+pub use election::{spawn_leadership_handoff, LeadershipHandoff};
+pub use flow_control::FlowControlConfig;
+pub use forward::{ClientWriteRequest, ClientWriteResponse, ForwardConfig, LeaderForwarder, RouteEpoch};
+pub use inspect::{RaftInspector, RaftStatus};
+pub use io_pool::IoPool;
+pub use local_network::{LocalRaftNetwork, LocalRaftNetworkFactory, LocalRouter};
+pub use log_dump::LogDumpRecord;
 pub use network::{HttpRaftNetwork, HttpRaftNetworkFactory};
+pub use proposal::VfsProposer;
+pub use reconfig::{ReconfigJournal, ReconfigPlan, ReconfigProgress, ReconfigStep, Reconfigurator};
+pub use session::{ClientSession, RequestSession};
+pub use snapshot::{SnapshotBuildConfig, VfsSnapshotBuilder};
+pub use snapshot_store::{spawn_snapshot_gc, SnapshotGcStats, SnapshotRetention, SnapshotStore};
+pub use snapshot_trigger::{spawn_snapshot_trigger, SnapshotTrigger, SnapshotTriggerConfig};
+pub use stale_read::{LeaderContact, StaleRead, StaleReader};
 pub use state_machine::{VfsSnapshot, VfsSnapshotState, VfsStateMachine};
-pub use storage::RocksDbLogStorage;
+pub use storage::{LogRecoveryError, RocksDbLogStorage};
+pub use trace_context::TraceContext;
+pub use trash_expiry::spawn_trash_expiry;
+pub use tuning::{RaftTuner, TimingUpdate};
 pub use types::*;
 
 use openraft::Raft;
@@ -23,13 +86,45 @@ use std::sync::Arc;
 /// The Raft instance type for VRaftLS
 pub type VRaftRaft = Raft<VRaftTypeConfig>;
 
+/// Build an OpenRaft configuration from the VRaftLS settings
+pub fn openraft_config(
+    cluster_name: impl Into<String>,
+    config: &vraftls_core::RaftConfig,
+) -> Result<openraft::Config, openraft::ConfigError> {
+    let heartbeat_interval = config.heartbeat_interval.as_millis() as u64;
+    let (election_timeout_min, election_timeout_max) = election::prioritized_timeouts(
+        heartbeat_interval,
+        config.election_timeout_min.as_millis() as u64,
+        config.election_timeout_max.as_millis() as u64,
+        config.election_priority,
+    );
+
+    openraft::Config {
+        cluster_name: cluster_name.into(),
+        heartbeat_interval,
+        election_timeout_min,
+        election_timeout_max,
+        max_payload_entries: config.max_append_entries,
+        snapshot_max_chunk_size: config.snapshot_chunk_size,
+        // Snapshots are triggered by `SnapshotTrigger`, which also watches log size
+        snapshot_policy: openraft::SnapshotPolicy::Never,
+        ..Default::default()
+    }
+    .validate()
+}
+
 /// Create a new Raft instance
-pub async fn create_raft(
+///
+/// Data groups use `VfsStateMachine`; the metadata group brings its own.
+pub async fn create_raft<SM>(
     node_id: RaftNodeId,
     config: openraft::Config,
     network: HttpRaftNetworkFactory,
     log_storage: Arc<RocksDbLogStorage>,
-    state_machine: Arc<VfsStateMachine>,
-) -> Result<VRaftRaft, openraft::error::Fatal<RaftNodeId>> {
+    state_machine: SM,
+) -> Result<VRaftRaft, openraft::error::Fatal<RaftNodeId>>
+where
+    SM: openraft::storage::RaftStateMachine<VRaftTypeConfig>,
+{
     Raft::new(node_id, Arc::new(config), network, log_storage, state_machine).await
 }
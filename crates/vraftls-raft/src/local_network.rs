@@ -0,0 +1,301 @@
+//! In-process Raft network for tests
+//!
+//! Routes RPCs between Raft instances living in the same process, with
+//! injectable latency, message drops and network partitions. Drops use a
+//! seeded generator, so a test run is reproducible.
+
+use crate::types::{RaftNodeId, VRaftNode, VRaftTypeConfig};
+use crate::VRaftRaft;
+use openraft::error::{InstallSnapshotError, RPCError, RaftError, RemoteError, Unreachable};
+use openraft::network::{RPCOption, RaftNetwork, RaftNetworkFactory};
+use openraft::raft::{
+    AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
+    VoteRequest, VoteResponse,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Shared routing table and fault settings
+#[derive(Default)]
+struct RouterState {
+    /// Registered Raft instances
+    nodes: BTreeMap<RaftNodeId, VRaftRaft>,
+
+    /// Delay added to every RPC
+    latency: Duration,
+
+    /// Probability of dropping an RPC
+    drop_rate: f64,
+
+    /// Blocked (from, to) links
+    blocked: BTreeSet<(RaftNodeId, RaftNodeId)>,
+
+    /// State of the drop generator
+    seed: u64,
+}
+
+impl RouterState {
+    /// Next value of a xorshift generator in `[0, 1)`
+    fn next_random(&mut self) -> f64 {
+        let mut x = self.seed.max(1);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.seed = x;
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Routes RPCs between in-process Raft instances
+#[derive(Clone, Default)]
+pub struct LocalRouter {
+    state: Arc<Mutex<RouterState>>,
+}
+
+impl LocalRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed the generator deciding which RPCs are dropped
+    pub fn with_seed(self, seed: u64) -> Self {
+        self.state.lock().unwrap().seed = seed;
+        self
+    }
+
+    /// Network factory for the node `source`
+    pub fn network(&self, source: RaftNodeId) -> LocalRaftNetworkFactory {
+        LocalRaftNetworkFactory {
+            router: self.clone(),
+            source,
+        }
+    }
+
+    /// Make a Raft instance reachable
+    pub fn register(&self, id: RaftNodeId, raft: VRaftRaft) {
+        self.state.lock().unwrap().nodes.insert(id, raft);
+    }
+
+    /// Remove a Raft instance, e.g. to simulate a crash
+    pub fn unregister(&self, id: RaftNodeId) -> Option<VRaftRaft> {
+        self.state.lock().unwrap().nodes.remove(&id)
+    }
+
+    /// Delay every RPC by `latency`
+    pub fn set_latency(&self, latency: Duration) {
+        self.state.lock().unwrap().latency = latency;
+    }
+
+    /// Drop RPCs with probability `rate`
+    pub fn set_drop_rate(&self, rate: f64) {
+        self.state.lock().unwrap().drop_rate = rate.clamp(0.0, 1.0);
+    }
+
+    /// Cut all links between the two sides, in both directions
+    pub fn partition(&self, left: &[RaftNodeId], right: &[RaftNodeId]) {
+        let mut state = self.state.lock().unwrap();
+        for a in left {
+            for b in right {
+                state.blocked.insert((*a, *b));
+                state.blocked.insert((*b, *a));
+            }
+        }
+    }
+
+    /// Cut a node off from every other registered node
+    pub fn isolate(&self, id: RaftNodeId) {
+        let others: Vec<_> = {
+            let state = self.state.lock().unwrap();
+            state.nodes.keys().copied().filter(|n| *n != id).collect()
+        };
+        self.partition(&[id], &others);
+    }
+
+    /// Remove all partitions
+    pub fn heal(&self) {
+        self.state.lock().unwrap().blocked.clear();
+    }
+
+    /// Decide the fate of one RPC: the target and the delay, or why it fails
+    fn route(&self, from: RaftNodeId, to: RaftNodeId) -> Result<(VRaftRaft, Duration), String> {
+        let mut state = self.state.lock().unwrap();
+        if state.blocked.contains(&(from, to)) {
+            return Err(format!("link {} -> {} is partitioned", from, to));
+        }
+        if state.drop_rate > 0.0 && state.next_random() < state.drop_rate {
+            return Err(format!("rpc {} -> {} dropped", from, to));
+        }
+
+        let raft = state
+            .nodes
+            .get(&to)
+            .cloned()
+            .ok_or_else(|| format!("node {} is not running", to))?;
+        Ok((raft, state.latency))
+    }
+}
+
+/// Network factory handing out in-process clients
+pub struct LocalRaftNetworkFactory {
+    router: LocalRouter,
+    source: RaftNodeId,
+}
+
+impl RaftNetworkFactory<VRaftTypeConfig> for LocalRaftNetworkFactory {
+    type Network = LocalRaftNetwork;
+
+    async fn new_client(&mut self, target: RaftNodeId, _node: &VRaftNode) -> Self::Network {
+        LocalRaftNetwork {
+            router: self.router.clone(),
+            source: self.source,
+            target,
+        }
+    }
+}
+
+/// In-process Raft network client for one target
+pub struct LocalRaftNetwork {
+    router: LocalRouter,
+    source: RaftNodeId,
+    target: RaftNodeId,
+}
+
+impl LocalRaftNetwork {
+    /// Resolve the target, applying faults and latency
+    async fn connect<E: std::error::Error>(&self) -> Result<VRaftRaft, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId, E>>> {
+        let (raft, latency) = self
+            .router
+            .route(self.source, self.target)
+            .map_err(|e| RPCError::Unreachable(Unreachable::new(&std::io::Error::other(e))))?;
+
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+        Ok(raft)
+    }
+}
+
+impl RaftNetwork<VRaftTypeConfig> for LocalRaftNetwork {
+    async fn append_entries(
+        &mut self,
+        request: AppendEntriesRequest<VRaftTypeConfig>,
+        _option: RPCOption,
+    ) -> Result<AppendEntriesResponse<RaftNodeId>, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId>>> {
+        let raft = self.connect().await?;
+        raft.append_entries(request)
+            .await
+            .map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
+    }
+
+    async fn install_snapshot(
+        &mut self,
+        request: InstallSnapshotRequest<VRaftTypeConfig>,
+        _option: RPCOption,
+    ) -> Result<InstallSnapshotResponse<RaftNodeId>, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId, InstallSnapshotError>>> {
+        let raft = self.connect().await?;
+        raft.install_snapshot(request)
+            .await
+            .map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
+    }
+
+    async fn vote(
+        &mut self,
+        request: VoteRequest<RaftNodeId>,
+        _option: RPCOption,
+    ) -> Result<VoteResponse<RaftNodeId>, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId>>> {
+        let raft = self.connect().await?;
+        raft.vote(request)
+            .await
+            .map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proposal::VfsProposer;
+    use crate::state_machine::VfsStateMachine;
+    use crate::storage::RocksDbLogStorage;
+    use openraft::Raft;
+    use tempfile::TempDir;
+    use vraftls_core::RaftGroupId;
+    use vraftls_vfs::{VfsCommand, VfsPath};
+
+    async fn start_cluster(router: &LocalRouter, dir: &TempDir, ids: &[RaftNodeId]) -> Vec<VRaftRaft> {
+        let config = Arc::new(
+            openraft::Config {
+                heartbeat_interval: 50,
+                election_timeout_min: 200,
+                election_timeout_max: 400,
+                ..Default::default()
+            }
+            .validate()
+            .unwrap(),
+        );
+
+        let mut rafts = Vec::new();
+        for id in ids {
+            let log_storage = Arc::new(RocksDbLogStorage::new(dir.path().join(id.to_string())).unwrap());
+            let state_machine = Arc::new(VfsStateMachine::new(RaftGroupId::new(1)));
+            let raft = Raft::new(*id, config.clone(), router.network(*id), log_storage, state_machine)
+                .await
+                .unwrap();
+            router.register(*id, raft.clone());
+            rafts.push(raft);
+        }
+
+        let members: BTreeMap<_, _> = ids
+            .iter()
+            .map(|id| (*id, VRaftNode::new(format!("local-{}", id))))
+            .collect();
+        rafts[0].initialize(members).await.unwrap();
+        rafts
+    }
+
+    async fn wait_leader(raft: &VRaftRaft, not: Option<RaftNodeId>) -> RaftNodeId {
+        let metrics = raft
+            .wait(Some(Duration::from_secs(10)))
+            .metrics(|m| m.current_leader.is_some_and(|l| Some(l) != not), "leader elected")
+            .await
+            .unwrap();
+        metrics.current_leader.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replication_and_failover() {
+        let dir = TempDir::new().unwrap();
+        let router = LocalRouter::new().with_seed(7);
+        router.set_latency(Duration::from_millis(1));
+        let rafts = start_cluster(&router, &dir, &[1, 2, 3]).await;
+
+        let leader = wait_leader(&rafts[0], None).await;
+        let proposer = VfsProposer::new(rafts[leader as usize - 1].clone(), RaftGroupId::new(1));
+        proposer
+            .propose(VfsCommand::CreateFile {
+                path: VfsPath::new("/a.rs"),
+                content: String::new(),
+            })
+            .await
+            .unwrap();
+
+        // Every node applies the write
+        let index = rafts[leader as usize - 1].metrics().borrow().last_applied.unwrap().index;
+        for raft in &rafts {
+            raft.wait(Some(Duration::from_secs(10)))
+                .applied_index_at_least(Some(index), "write applied")
+                .await
+                .unwrap();
+        }
+
+        // Cutting off the leader makes the others elect a new one
+        router.isolate(leader);
+        let survivor = rafts.iter().find(|r| r.metrics().borrow().id != leader).unwrap();
+        let new_leader = wait_leader(survivor, Some(leader)).await;
+        assert_ne!(new_leader, leader);
+
+        for raft in rafts {
+            raft.shutdown().await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Human-readable export of Raft log entries
+//!
+//! Entries are written as JSON lines with decoded VFS requests, which makes
+//! it easy to diff the logs of two replicas when replication diverges.
+
+use crate::session::RequestSession;
+use crate::types::{RaftNodeId, VRaftTypeConfig, VfsRequestPayload};
+use openraft::{Entry, EntryPayload};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use vraftls_core::RaftGroupId;
+
+/// One exported log entry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogDumpRecord {
+    /// Log index
+    pub index: u64,
+
+    /// Term of the leader that created the entry
+    pub term: u64,
+
+    /// Node ID of the leader that created the entry
+    pub leader: RaftNodeId,
+
+    /// Decoded payload
+    #[serde(flatten)]
+    pub payload: LogDumpPayload,
+}
+
+/// Decoded payload of an exported entry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LogDumpPayload {
+    /// Blank entry appended by a new leader
+    Blank,
+
+    /// Client request
+    Normal {
+        group_id: RaftGroupId,
+        session: Option<RequestSession>,
+        request: VfsRequestPayload,
+    },
+
+    /// Membership change
+    Membership {
+        voters: Vec<RaftNodeId>,
+        learners: Vec<RaftNodeId>,
+        nodes: BTreeMap<RaftNodeId, String>,
+    },
+}
+
+impl From<&Entry<VRaftTypeConfig>> for LogDumpRecord {
+    fn from(entry: &Entry<VRaftTypeConfig>) -> Self {
+        let payload = match &entry.payload {
+            EntryPayload::Blank => LogDumpPayload::Blank,
+            EntryPayload::Normal(request) => LogDumpPayload::Normal {
+                group_id: request.group_id,
+                session: request.session,
+                request: request.payload.clone(),
+            },
+            EntryPayload::Membership(membership) => LogDumpPayload::Membership {
+                voters: membership.voter_ids().collect(),
+                learners: membership.learner_ids().collect(),
+                nodes: membership
+                    .nodes()
+                    .map(|(id, node)| (*id, node.addr.clone()))
+                    .collect(),
+            },
+        };
+
+        Self {
+            index: entry.log_id.index,
+            term: entry.log_id.leader_id.term,
+            leader: entry.log_id.leader_id.node_id,
+            payload,
+        }
+    }
+}
@@ -2,33 +2,94 @@
 //!
 //! Handles node-to-node communication for Raft consensus.
 
-use crate::types::{RaftNodeId, VRaftNode, VRaftTypeConfig, VfsRequest};
+use crate::compression::{parse_accept_encoding, CompressionConfig, Encoding, ACCEPT_ENCODING};
+use crate::flow_control::{FlowControl, FlowControlConfig, PeerFlowControl};
+use crate::trace_context::{TraceContext, TRACEPARENT};
+use crate::types::{RaftNodeId, VRaftNode, VRaftTypeConfig};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use openraft::error::{InstallSnapshotError, RPCError, RaftError, RemoteError};
 use openraft::network::{RPCOption, RaftNetwork, RaftNetworkFactory};
 use openraft::raft::{
     AppendEntriesRequest, AppendEntriesResponse, InstallSnapshotRequest, InstallSnapshotResponse,
     VoteRequest, VoteResponse,
 };
-use openraft::OptionalSend;
-use reqwest::Client;
+use reqwest::{header, Client, StatusCode};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use std::time::Duration;
+use vraftls_core::RaftGroupId;
+
+/// HTTP header naming the Raft group an RPC is addressed to
+pub const RAFT_GROUP_HEADER: &str = "x-raft-group";
+
+/// HTTP status of a Raft RPC the target's Raft refused
+///
+/// The body is the JSON `RaftError`, which the caller hands to openraft as a
+/// `RemoteError` rather than a network failure, so e.g. a snapshot mismatch
+/// restarts the transfer instead of resending the same chunk.
+pub const RAFT_ERROR_STATUS: StatusCode = StatusCode::UNPROCESSABLE_ENTITY;
+
+/// Reply to a Raft RPC with its JSON response, or with the error the
+/// target's Raft refused it with under `RAFT_ERROR_STATUS`
+pub fn rpc_reply<Resp, E>(result: Result<Resp, E>) -> Response
+where
+    Resp: Serialize,
+    E: Serialize,
+{
+    let accept = [(header::ACCEPT_ENCODING, ACCEPT_ENCODING)];
+    match result {
+        Ok(response) => (accept, Json(response)).into_response(),
+        Err(e) => (RAFT_ERROR_STATUS, accept, Json(e)).into_response(),
+    }
+}
 
 /// HTTP network factory
 pub struct HttpRaftNetworkFactory {
     /// HTTP client
     client: Client,
+
+    /// Per-peer replication limits
+    flow: Arc<FlowControl>,
+
+    /// Compression of request bodies
+    compression: CompressionConfig,
+
+    /// Raft group the RPCs are addressed to
+    group_id: Option<RaftGroupId>,
 }
 
 impl HttpRaftNetworkFactory {
     pub fn new() -> Self {
+        Self::with_flow_control(FlowControlConfig::default())
+    }
+
+    /// Create a factory with the given replication limits
+    pub fn with_flow_control(config: FlowControlConfig) -> Self {
         let client = Client::builder()
             .timeout(Duration::from_secs(10))
             .build()
             .expect("Failed to create HTTP client");
 
-        Self { client }
+        Self {
+            client,
+            flow: Arc::new(FlowControl::new(config)),
+            compression: CompressionConfig::default(),
+            group_id: None,
+        }
+    }
+
+    /// Address RPCs to a Raft group on nodes hosting several groups
+    pub fn with_group(mut self, group_id: RaftGroupId) -> Self {
+        self.group_id = Some(group_id);
+        self
+    }
+
+    /// Set how request bodies are compressed
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
     }
 }
 
@@ -46,6 +107,10 @@ impl RaftNetworkFactory<VRaftTypeConfig> for HttpRaftNetworkFactory {
             client: self.client.clone(),
             target,
             target_addr: node.addr.clone(),
+            flow: self.flow.peer(target),
+            compression: self.compression.clone(),
+            accepted: Vec::new(),
+            group_id: self.group_id,
         }
     }
 }
@@ -60,6 +125,34 @@ pub struct HttpRaftNetwork {
 
     /// Target node address
     target_addr: String,
+
+    /// Replication limits for the target
+    flow: PeerFlowControl,
+
+    /// Compression of request bodies
+    compression: CompressionConfig,
+
+    /// Encodings the target advertised it can decode
+    accepted: Vec<Encoding>,
+
+    /// Raft group the RPCs are addressed to
+    group_id: Option<RaftGroupId>,
+}
+
+/// Serialized request body, compressed when worthwhile
+struct RpcBody {
+    raw: Vec<u8>,
+    compressed: Option<(Encoding, Vec<u8>)>,
+    trace: Option<TraceContext>,
+}
+
+impl RpcBody {
+    /// Bytes sent over the wire
+    fn wire_len(&self) -> usize {
+        self.compressed
+            .as_ref()
+            .map_or(self.raw.len(), |(_, data)| data.len())
+    }
 }
 
 impl HttpRaftNetwork {
@@ -68,35 +161,103 @@ impl HttpRaftNetwork {
         format!("http://{}/raft/{}", self.target_addr, endpoint)
     }
 
-    /// Send a POST request
-    async fn post<Req, Resp>(&self, endpoint: &str, request: &Req) -> Result<Resp, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId>>>
+    /// Serialize a request body, compressing it if the target accepts it
+    fn encode<Req, E>(&self, request: &Req, trace: Option<TraceContext>) -> Result<RpcBody, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId, E>>>
+    where
+        Req: Serialize,
+        E: std::error::Error,
+    {
+        let raw = serde_json::to_vec(request).map_err(|e| RPCError::Network(openraft::error::NetworkError::new(&e)))?;
+
+        let compressed = self
+            .compression
+            .choose(raw.len(), &self.accepted)
+            .and_then(|encoding| match encoding.compress(&raw) {
+                Ok(data) if data.len() < raw.len() => Some((encoding, data)),
+                Ok(_) => None,
+                Err(e) => {
+                    tracing::debug!(target_node = self.target, error = %e, "rpc compression failed");
+                    None
+                }
+            });
+
+        Ok(RpcBody {
+            raw,
+            compressed,
+            trace: trace.map(|t| t.child()),
+        })
+    }
+
+    /// Send a POST request with a JSON body
+    async fn send(
+        &self,
+        url: &str,
+        data: Vec<u8>,
+        encoding: Option<Encoding>,
+        trace: Option<TraceContext>,
+    ) -> reqwest::Result<reqwest::Response> {
+        let mut request = self
+            .client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(group_id) = self.group_id {
+            request = request.header(RAFT_GROUP_HEADER, group_id.0.to_string());
+        }
+        if let Some(trace) = trace {
+            request = request.header(TRACEPARENT, trace.to_string());
+        }
+        if let Some(encoding) = encoding {
+            request = request.header(header::CONTENT_ENCODING, encoding.as_str());
+        }
+
+        request.body(data).send().await
+    }
+
+    /// Send an RPC and decode its JSON response
+    async fn post<Resp, E>(&mut self, endpoint: &str, body: RpcBody) -> Result<Resp, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId, E>>>
     where
-        Req: Serialize + Send + Sync,
         Resp: for<'de> Deserialize<'de>,
+        E: std::error::Error + DeserializeOwned,
     {
         let url = self.url(endpoint);
+        let network_error = |e: reqwest::Error| RPCError::Network(openraft::error::NetworkError::new(&e));
+        tracing::trace!(target_node = self.target, %url, bytes = body.wire_len(), "sending raft rpc");
 
-        let response = self
-            .client
-            .post(&url)
-            .json(request)
-            .send()
-            .await
-            .map_err(|e| RPCError::Network(openraft::error::NetworkError::new(&e)))?;
+        let response = match body.compressed {
+            Some((encoding, data)) => {
+                let response = self.send(&url, data, Some(encoding), body.trace).await.map_err(network_error)?;
+                if response.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE {
+                    // The target cannot decode the body after all: resend as identity
+                    self.accepted.clear();
+                    self.send(&url, body.raw, None, body.trace).await.map_err(network_error)?
+                } else {
+                    response
+                }
+            }
+            None => self.send(&url, body.raw, None, body.trace).await.map_err(network_error)?,
+        };
 
+        if let Some(accept) = response
+            .headers()
+            .get(header::ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+        {
+            self.accepted = parse_accept_encoding(accept);
+        }
+
+        if response.status() == RAFT_ERROR_STATUS {
+            let error: RaftError<RaftNodeId, E> = response.json().await.map_err(network_error)?;
+            return Err(RPCError::RemoteError(RemoteError::new(self.target, error)));
+        }
         if !response.status().is_success() {
             let status = response.status();
             let text = response.text().await.unwrap_or_default();
-            return Err(RPCError::Network(openraft::error::NetworkError::new(&std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(RPCError::Network(openraft::error::NetworkError::new(&std::io::Error::other(
                 format!("HTTP {}: {}", status, text),
             ))));
         }
 
-        response
-            .json()
-            .await
-            .map_err(|e| RPCError::Network(openraft::error::NetworkError::new(&e)))
+        response.json().await.map_err(network_error)
     }
 }
 
@@ -106,7 +267,22 @@ impl RaftNetwork<VRaftTypeConfig> for HttpRaftNetwork {
         request: AppendEntriesRequest<VRaftTypeConfig>,
         _option: RPCOption,
     ) -> Result<AppendEntriesResponse<RaftNodeId>, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId>>> {
-        self.post("append_entries", &request).await
+        let heartbeat = request.entries.is_empty();
+
+        // Continue the trace of the newest traced entry in the batch
+        let trace = request.entries.iter().rev().find_map(|entry| match &entry.payload {
+            openraft::EntryPayload::Normal(request) => request.trace,
+            _ => None,
+        });
+        let body = self.encode(&request, trace)?;
+
+        // Heartbeats bypass flow control so they are never queued behind data
+        let _permit = if heartbeat {
+            None
+        } else {
+            Some(self.flow.acquire_append(body.wire_len()).await)
+        };
+        self.post("append_entries", body).await
     }
 
     async fn install_snapshot(
@@ -114,7 +290,9 @@ impl RaftNetwork<VRaftTypeConfig> for HttpRaftNetwork {
         request: InstallSnapshotRequest<VRaftTypeConfig>,
         _option: RPCOption,
     ) -> Result<InstallSnapshotResponse<RaftNodeId>, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId, InstallSnapshotError>>> {
-        self.post("install_snapshot", &request).await
+        let body = self.encode(&request, TraceContext::current())?;
+        let _permit = self.flow.acquire_snapshot(body.wire_len()).await;
+        self.post("install_snapshot", body).await
     }
 
     async fn vote(
@@ -122,7 +300,8 @@ impl RaftNetwork<VRaftTypeConfig> for HttpRaftNetwork {
         request: VoteRequest<RaftNodeId>,
         _option: RPCOption,
     ) -> Result<VoteResponse<RaftNodeId>, RPCError<RaftNodeId, VRaftNode, RaftError<RaftNodeId>>> {
-        self.post("vote", &request).await
+        let body = self.encode(&request, TraceContext::current())?;
+        self.post("vote", body).await
     }
 }
 
@@ -173,9 +352,71 @@ impl From<AppendEntriesRequestWrapper> for AppendEntriesRequest<VRaftTypeConfig>
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_create_network_factory() {
-        let factory = HttpRaftNetworkFactory::new();
-        assert!(true);
+    #[tokio::test]
+    async fn test_create_network_factory() {
+        let mut factory = HttpRaftNetworkFactory::new();
+        let node: VRaftNode = serde_json::from_str(r#"{"addr":"127.0.0.1:9001"}"#).unwrap();
+
+        let network = factory.new_client(2, &node).await;
+        assert_eq!(network.target, 2);
+        assert_eq!(network.url("vote"), "http://127.0.0.1:9001/raft/vote");
+    }
+
+    #[tokio::test]
+    async fn test_refused_rpc_is_remote_error() {
+        use crate::local_network::LocalRouter;
+        use crate::state_machine::VfsStateMachine;
+        use crate::storage::RocksDbLogStorage;
+        use axum::routing::post;
+        use openraft::{CommittedLeaderId, LogId, Raft, SnapshotMeta, StoredMembership, Vote};
+        use tempfile::TempDir;
+
+        // A follower that is not receiving any snapshot
+        let dir = TempDir::new().unwrap();
+        let raft = Raft::new(
+            2,
+            Arc::new(openraft::Config::default().validate().unwrap()),
+            LocalRouter::new().network(2),
+            Arc::new(RocksDbLogStorage::new(dir.path()).unwrap()),
+            Arc::new(VfsStateMachine::new(RaftGroupId::new(1))),
+        )
+        .await
+        .unwrap();
+        let app = axum::Router::new().route(
+            "/raft/install_snapshot",
+            post(move |Json(request): Json<InstallSnapshotRequest<VRaftTypeConfig>>| async move {
+                rpc_reply(raft.install_snapshot(request).await)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        // A chunk from the middle of a snapshot the follower never saw start
+        let mut factory = HttpRaftNetworkFactory::new();
+        let mut network = factory.new_client(2, &VRaftNode::new(addr.to_string())).await;
+        let request = InstallSnapshotRequest {
+            vote: Vote::new_committed(1, 1),
+            meta: SnapshotMeta {
+                last_log_id: Some(LogId::new(CommittedLeaderId::new(1, 1), 10)),
+                last_membership: StoredMembership::default(),
+                snapshot_id: "1-10".to_string(),
+            },
+            offset: 1024,
+            data: vec![0; 16],
+            done: false,
+        };
+
+        // The leader sees the mismatch, which makes it restart from offset 0
+        match network.install_snapshot(request, RPCOption::new(Duration::from_secs(5))).await {
+            Err(RPCError::RemoteError(remote)) => {
+                assert_eq!(remote.target, 2);
+                assert!(matches!(
+                    remote.source,
+                    RaftError::APIError(InstallSnapshotError::SnapshotMismatch(_))
+                ));
+            }
+            other => panic!("expected a remote snapshot mismatch, got {:?}", other),
+        }
     }
 }
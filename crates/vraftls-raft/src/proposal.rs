@@ -0,0 +1,166 @@
+//! Client-side proposal of VFS commands
+//!
+//! Wraps `Raft::client_write` so oversized commands are transparently split
+//! into chunk entries before they are replicated. Commands touching files a
+//! prepared transaction holds are refused before they are proposed.
+
+use crate::chunking::CommandChunker;
+use crate::session::RequestSession;
+use crate::trace_context::TraceContext;
+use crate::types::{RaftNodeId, VRaftNode, VfsRequest, VfsRequestPayload};
+use crate::VRaftRaft;
+use openraft::error::{ClientWriteError, RaftError};
+use std::sync::Arc;
+use vraftls_core::{NodeId, RaftGroupId, Result, TransactionId, VRaftError};
+use vraftls_vfs::{VfsCommand, VfsResponse, WriteHolds};
+
+/// Proposes VFS commands to a Raft group
+#[derive(Clone)]
+pub struct VfsProposer {
+    /// The Raft instance
+    raft: VRaftRaft,
+
+    /// Raft group the commands belong to
+    group_id: RaftGroupId,
+
+    /// Splits oversized commands
+    chunker: CommandChunker,
+
+    /// Files held by transactions prepared on this node
+    holds: Option<Arc<WriteHolds>>,
+}
+
+impl VfsProposer {
+    pub fn new(raft: VRaftRaft, group_id: RaftGroupId) -> Self {
+        Self {
+            raft,
+            group_id,
+            chunker: CommandChunker::default(),
+            holds: None,
+        }
+    }
+
+    /// Set the chunker used for oversized commands
+    pub fn with_chunker(mut self, chunker: CommandChunker) -> Self {
+        self.chunker = chunker;
+        self
+    }
+
+    /// Refuse commands touching files the transactions in `holds` hold
+    pub fn with_holds(mut self, holds: Arc<WriteHolds>) -> Self {
+        self.holds = Some(holds);
+        self
+    }
+
+    /// Get the Raft group ID
+    pub fn group_id(&self) -> RaftGroupId {
+        self.group_id
+    }
+
+    /// Get the underlying Raft instance
+    pub fn raft(&self) -> &VRaftRaft {
+        &self.raft
+    }
+
+    /// Propose a command and wait until it is applied
+    ///
+    /// Chunks are written one after another; the response of the final
+    /// chunk is the response of the reassembled command.
+    pub async fn propose(&self, command: VfsCommand) -> Result<VfsResponse> {
+        self.propose_request(VfsRequest::new(self.group_id, command), None)
+            .await
+            .map(|(response, _)| response)
+    }
+
+    /// Propose a command, with the log index it was committed at
+    pub async fn propose_acknowledged(&self, command: VfsCommand) -> Result<(VfsResponse, u64)> {
+        self.propose_request(VfsRequest::new(self.group_id, command), None)
+            .await
+    }
+
+    /// Propose a command of a prepared transaction, past the transaction's
+    /// own hold
+    ///
+    /// Returns the log index the command was committed at.
+    pub async fn propose_held(
+        &self,
+        command: VfsCommand,
+        transaction: TransactionId,
+    ) -> Result<(VfsResponse, u64)> {
+        self.propose_request(VfsRequest::new(self.group_id, command), Some(transaction))
+            .await
+    }
+
+    /// Propose a command tagged with a client session
+    ///
+    /// Retries must reuse the same session tag so the command is applied
+    /// at most once.
+    pub async fn propose_with_session(
+        &self,
+        command: VfsCommand,
+        session: RequestSession,
+    ) -> Result<VfsResponse> {
+        self.propose_request(VfsRequest::new(self.group_id, command).with_session(session), None)
+            .await
+            .map(|(response, _)| response)
+    }
+
+    /// Propose several commands as one log entry, applied atomically
+    ///
+    /// Either every command is applied or, if one fails, none is.
+    pub async fn propose_batch(&self, commands: Vec<VfsCommand>) -> Result<Vec<VfsResponse>> {
+        match self.propose(VfsCommand::Transaction { commands }).await? {
+            VfsResponse::Transaction(responses) => Ok(responses),
+            VfsResponse::Error(e) => Err(VRaftError::TransactionAborted(e.to_string())),
+            other => Err(VRaftError::Internal(format!(
+                "unexpected transaction response: {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Propose a request, chunking it if needed
+    ///
+    /// Returns the response and the log index of the final chunk. Refused
+    /// with a `Held` error if a transaction other than `owner` holds what
+    /// the command touches.
+    async fn propose_request(
+        &self,
+        mut request: VfsRequest,
+        owner: Option<TransactionId>,
+    ) -> Result<(VfsResponse, u64)> {
+        if let (Some(holds), VfsRequestPayload::Command(command)) = (&self.holds, &request.payload) {
+            if let Err(e) = holds.check(command, owner) {
+                return Ok((VfsResponse::Error(e), 0));
+            }
+        }
+
+        // Record the caller's trace so replication can be traced too
+        if request.trace.is_none() {
+            request.trace = TraceContext::current().map(|c| c.child());
+        }
+
+        let entries = self
+            .chunker
+            .split(request)
+            .map_err(|e| VRaftError::Serialization(e.to_string()))?;
+
+        let mut response = (VfsResponse::Ok(None), 0);
+        for entry in entries {
+            let result = self.raft.client_write(entry).await.map_err(map_write_error)?;
+            response = (result.data.response, result.log_id.index);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Convert an OpenRaft write error into a VRaftLS error
+pub fn map_write_error(e: RaftError<RaftNodeId, ClientWriteError<RaftNodeId, VRaftNode>>) -> VRaftError {
+    match e {
+        RaftError::APIError(ClientWriteError::ForwardToLeader(forward)) => VRaftError::NotLeader {
+            leader: forward.leader_id.map(NodeId::new),
+        },
+        other => VRaftError::RaftConsensus(other.to_string()),
+    }
+}
@@ -0,0 +1,326 @@
+//! Resumable membership reconfiguration
+//!
+//! Changing a group's voters takes several steps: add the new nodes as
+//! learners, wait until they have caught up, commit the joint configuration
+//! and finally drop the nodes that are no longer wanted. Progress is
+//! journaled after every step, so a leader restarted mid-change resumes where
+//! it stopped instead of leaving the group half-configured.
+
+use crate::proposal::map_write_error;
+use crate::snapshot_store::write_atomic;
+use crate::types::{RaftNodeId, VRaftNode};
+use crate::VRaftRaft;
+use openraft::ChangeMembers;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::Instant;
+use vraftls_core::{NodeId, Result, Timestamp, VRaftError};
+
+/// Desired membership of a group
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReconfigPlan {
+    /// Voters of the final configuration
+    pub voters: BTreeSet<RaftNodeId>,
+
+    /// Nodes that are not members yet, with their addresses
+    #[serde(default)]
+    pub new_nodes: BTreeMap<RaftNodeId, VRaftNode>,
+}
+
+/// Step of a reconfiguration
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReconfigStep {
+    /// Add the new nodes as learners
+    AddLearners,
+
+    /// Wait until the future voters have caught up with the log
+    CatchUp,
+
+    /// Commit the joint configuration (old and new voters)
+    JointConfig,
+
+    /// Remove nodes that are no longer part of the group
+    FinalConfig,
+
+    /// The reconfiguration has finished
+    Done,
+}
+
+/// Journaled state of a reconfiguration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReconfigProgress {
+    /// Target membership
+    pub plan: ReconfigPlan,
+
+    /// Voters being removed, fixed when the change started
+    pub removed: BTreeSet<RaftNodeId>,
+
+    /// Next step to run
+    pub step: ReconfigStep,
+
+    /// When the change started
+    pub started_at: Timestamp,
+
+    /// When the last step completed
+    pub updated_at: Timestamp,
+}
+
+/// File holding the progress of an unfinished reconfiguration
+pub struct ReconfigJournal {
+    path: PathBuf,
+}
+
+impl ReconfigJournal {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Load the unfinished reconfiguration, if any
+    pub fn load(&self) -> io::Result<Option<ReconfigProgress>> {
+        match std::fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).map(Some).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Persist progress
+    pub fn save(&self, progress: &ReconfigProgress) -> io::Result<()> {
+        let data = serde_json::to_vec_pretty(progress).map_err(io::Error::other)?;
+        write_atomic(&self.path, &data)
+    }
+
+    /// Forget a finished reconfiguration
+    pub fn clear(&self) -> io::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Drives membership changes of a group from its leader
+pub struct Reconfigurator {
+    raft: VRaftRaft,
+    journal: ReconfigJournal,
+
+    /// Entries a new voter may lag behind the leader's log
+    max_lag: u64,
+
+    /// How long to wait for new voters to catch up
+    catch_up_timeout: Duration,
+}
+
+impl Reconfigurator {
+    pub fn new(raft: VRaftRaft, journal: ReconfigJournal) -> Self {
+        Self {
+            raft,
+            journal,
+            max_lag: 100,
+            catch_up_timeout: Duration::from_secs(300),
+        }
+    }
+
+    /// Set how close new voters must be to the leader before promotion
+    pub fn with_catch_up(mut self, max_lag: u64, timeout: Duration) -> Self {
+        self.max_lag = max_lag;
+        self.catch_up_timeout = timeout;
+        self
+    }
+
+    /// Progress of the unfinished reconfiguration, if any
+    pub fn progress(&self) -> Result<Option<ReconfigProgress>> {
+        self.journal.load().map_err(VRaftError::Io)
+    }
+
+    /// Start a reconfiguration and run it to completion
+    pub async fn start(&self, plan: ReconfigPlan) -> Result<()> {
+        if self.progress()?.is_some() {
+            return Err(VRaftError::ReconfigurationInProgress);
+        }
+        if plan.voters.is_empty() {
+            return Err(VRaftError::InvalidConfig("a group needs at least one voter".to_string()));
+        }
+        self.ensure_leader()?;
+
+        let removed = self
+            .raft
+            .metrics()
+            .borrow()
+            .membership_config
+            .voter_ids()
+            .filter(|id| !plan.voters.contains(id))
+            .collect();
+        let now = Timestamp::now();
+        let progress = ReconfigProgress {
+            plan,
+            removed,
+            step: ReconfigStep::AddLearners,
+            started_at: now,
+            updated_at: now,
+        };
+        self.journal.save(&progress)?;
+
+        self.run(progress).await
+    }
+
+    /// Continue a reconfiguration interrupted by a restart
+    ///
+    /// Returns whether there was one to resume.
+    pub async fn resume(&self) -> Result<bool> {
+        let Some(progress) = self.progress()? else {
+            return Ok(false);
+        };
+
+        tracing::info!(step = ?progress.step, "resuming membership reconfiguration");
+        self.run(progress).await?;
+        Ok(true)
+    }
+
+    /// Run the remaining steps, journaling after each one
+    async fn run(&self, mut progress: ReconfigProgress) -> Result<()> {
+        loop {
+            let next = match progress.step {
+                ReconfigStep::AddLearners => {
+                    let members = self.member_ids();
+                    for (id, node) in &progress.plan.new_nodes {
+                        if !members.contains(id) {
+                            self.raft
+                                .add_learner(*id, node.clone(), false)
+                                .await
+                                .map_err(map_write_error)?;
+                        }
+                    }
+                    ReconfigStep::CatchUp
+                }
+                ReconfigStep::CatchUp => {
+                    self.wait_caught_up(&progress.plan.voters).await?;
+                    ReconfigStep::JointConfig
+                }
+                ReconfigStep::JointConfig => {
+                    // OpenRaft commits the joint config, then the new voter set
+                    let voters: BTreeSet<_> = self.raft.metrics().borrow().membership_config.voter_ids().collect();
+                    if voters != progress.plan.voters {
+                        self.raft
+                            .change_membership(ChangeMembers::ReplaceAllVoters(progress.plan.voters.clone()), true)
+                            .await
+                            .map_err(map_write_error)?;
+                    }
+                    ReconfigStep::FinalConfig
+                }
+                ReconfigStep::FinalConfig => {
+                    let members = self.member_ids();
+                    let remaining: BTreeSet<_> = progress.removed.intersection(&members).copied().collect();
+                    if !remaining.is_empty() {
+                        self.raft
+                            .change_membership(ChangeMembers::RemoveNodes(remaining), false)
+                            .await
+                            .map_err(map_write_error)?;
+                    }
+                    ReconfigStep::Done
+                }
+                ReconfigStep::Done => {
+                    self.journal.clear()?;
+                    tracing::info!(voters = ?progress.plan.voters, "membership reconfiguration finished");
+                    return Ok(());
+                }
+            };
+
+            tracing::debug!(step = ?next, "reconfiguration step completed");
+            progress.step = next;
+            progress.updated_at = Timestamp::now();
+            self.journal.save(&progress)?;
+        }
+    }
+
+    /// IDs of all voters and learners
+    fn member_ids(&self) -> BTreeSet<RaftNodeId> {
+        self.raft
+            .metrics()
+            .borrow()
+            .membership_config
+            .nodes()
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    fn ensure_leader(&self) -> Result<()> {
+        let metrics = self.raft.metrics().borrow().clone();
+        if metrics.current_leader == Some(metrics.id) {
+            Ok(())
+        } else {
+            Err(VRaftError::NotLeader {
+                leader: metrics.current_leader.map(NodeId::new),
+            })
+        }
+    }
+
+    /// Wait until every future voter is within `max_lag` of the leader's log
+    async fn wait_caught_up(&self, voters: &BTreeSet<RaftNodeId>) -> Result<()> {
+        let deadline = Instant::now() + self.catch_up_timeout;
+        let mut metrics_rx = self.raft.metrics();
+
+        loop {
+            self.ensure_leader()?;
+
+            let caught_up = {
+                let metrics = metrics_rx.borrow_and_update();
+                let target = metrics.last_log_index.unwrap_or(0).saturating_sub(self.max_lag);
+                let replication = metrics.replication.as_ref();
+                voters.iter().filter(|id| **id != metrics.id).all(|id| {
+                    replication
+                        .and_then(|r| r.get(id))
+                        .and_then(|matched| matched.as_ref())
+                        .is_some_and(|matched| matched.index >= target)
+                })
+            };
+            if caught_up {
+                return Ok(());
+            }
+
+            match tokio::time::timeout_at(deadline, metrics_rx.changed()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => return Err(VRaftError::RaftConsensus("raft stopped".to_string())),
+                Err(_) => return Err(VRaftError::Timeout),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_journal_round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let journal = ReconfigJournal::new(temp_dir.path().join("reconfig.json"));
+        assert!(journal.load().unwrap().is_none());
+
+        let progress = ReconfigProgress {
+            plan: ReconfigPlan {
+                voters: [1, 2, 4].into(),
+                new_nodes: [(4, VRaftNode::new("127.0.0.1:8084"))].into(),
+            },
+            removed: [3].into(),
+            step: ReconfigStep::JointConfig,
+            started_at: Timestamp::now(),
+            updated_at: Timestamp::now(),
+        };
+        journal.save(&progress).unwrap();
+
+        let loaded = journal.load().unwrap().unwrap();
+        assert_eq!(loaded.step, ReconfigStep::JointConfig);
+        assert_eq!(loaded.removed, progress.removed);
+        assert_eq!(loaded.plan.new_nodes[&4].addr, "127.0.0.1:8084");
+
+        journal.clear().unwrap();
+        journal.clear().unwrap();
+        assert!(journal.load().unwrap().is_none());
+    }
+}
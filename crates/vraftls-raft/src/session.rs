@@ -0,0 +1,250 @@
+//! Client sessions for write deduplication
+//!
+//! Each client tags its writes with a session ID and a serial number that
+//! increases with every new command. A retried command keeps its serial, so
+//! the state machine can recognise it and return the cached response
+//! instead of applying it twice.
+//!
+//! Writers sharing a session may get their commands applied in another
+//! order than their serials were handed out, so the responses of a window
+//! of serials below the highest applied one are kept; only serials older
+//! than the window are refused as stale.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use vraftls_core::Timestamp;
+use vraftls_vfs::{VfsCommandError, VfsResponse};
+
+/// Number of log entries after which an idle session is forgotten
+pub const SESSION_EXPIRY_ENTRIES: u64 = 100_000;
+
+/// How often (in log entries) idle sessions are swept
+const SESSION_SWEEP_INTERVAL: u64 = 1024;
+
+/// Serials below a session's highest applied one whose responses are kept
+pub const SESSION_WINDOW: u64 = 256;
+
+/// Session tag carried by a request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestSession {
+    /// Client session ID
+    pub session_id: u64,
+
+    /// Serial number of the command within the session
+    pub serial: u64,
+}
+
+/// Client-side session that hands out serial numbers
+#[derive(Debug)]
+pub struct ClientSession {
+    /// Session ID
+    session_id: u64,
+
+    /// Last serial handed out
+    serial: AtomicU64,
+}
+
+impl ClientSession {
+    /// Start a new session with a fresh ID
+    pub fn new() -> Self {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let seq = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self::with_id((Timestamp::now().0 << 20) ^ seq ^ (u64::from(std::process::id()) << 44))
+    }
+
+    /// Resume a session with a known ID
+    pub fn with_id(session_id: u64) -> Self {
+        Self {
+            session_id,
+            serial: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the session ID
+    pub fn session_id(&self) -> u64 {
+        self.session_id
+    }
+
+    /// Tag for the next command; reuse the tag when retrying that command
+    pub fn next(&self) -> RequestSession {
+        RequestSession {
+            session_id: self.session_id,
+            serial: self.serial.fetch_add(1, Ordering::Relaxed) + 1,
+        }
+    }
+}
+
+impl Default for ClientSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Applied commands of a session
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionEntry {
+    /// Highest serial applied
+    pub serial: u64,
+
+    /// Response of the command with the highest serial
+    pub response: VfsResponse,
+
+    /// Responses of the commands applied with serials in the window below
+    /// `serial`
+    #[serde(default)]
+    pub earlier: BTreeMap<u64, VfsResponse>,
+
+    /// Log index of the last applied command
+    pub last_index: u64,
+}
+
+/// Per-session state kept by the state machine
+///
+/// Driven only by the applied log, so it is identical on every replica.
+#[derive(Debug, Default)]
+pub struct SessionTable {
+    sessions: BTreeMap<u64, SessionEntry>,
+}
+
+impl SessionTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restore sessions (from a snapshot)
+    pub fn from_entries(sessions: BTreeMap<u64, SessionEntry>) -> Self {
+        Self { sessions }
+    }
+
+    /// Tracked sessions (for snapshots)
+    pub fn entries(&self) -> &BTreeMap<u64, SessionEntry> {
+        &self.sessions
+    }
+
+    /// Response to return instead of applying, if the command was already seen
+    ///
+    /// A serial not applied yet is new, unless it is older than the window
+    /// of serials the session keeps responses of.
+    pub fn check(&self, session: &RequestSession) -> Option<VfsResponse> {
+        let entry = self.sessions.get(&session.session_id)?;
+
+        if session.serial == entry.serial {
+            Some(entry.response.clone())
+        } else if let Some(response) = entry.earlier.get(&session.serial) {
+            Some(response.clone())
+        } else if session.serial + SESSION_WINDOW <= entry.serial {
+            Some(VfsResponse::Error(VfsCommandError::StorageError(format!(
+                "stale request serial {} (session {} is at {})",
+                session.serial, session.session_id, entry.serial
+            ))))
+        } else {
+            None
+        }
+    }
+
+    /// Record the response of a newly applied command
+    pub fn record(&mut self, session: &RequestSession, response: &VfsResponse, log_index: u64) {
+        let entry = match self.sessions.get_mut(&session.session_id) {
+            Some(entry) => entry,
+            None => {
+                self.sessions.insert(
+                    session.session_id,
+                    SessionEntry {
+                        serial: session.serial,
+                        response: response.clone(),
+                        earlier: BTreeMap::new(),
+                        last_index: log_index,
+                    },
+                );
+                return;
+            }
+        };
+
+        if session.serial < entry.serial {
+            entry.earlier.insert(session.serial, response.clone());
+        } else if session.serial > entry.serial {
+            let previous = std::mem::replace(&mut entry.response, response.clone());
+            entry.earlier.insert(entry.serial, previous);
+            entry.serial = session.serial;
+        }
+        entry.last_index = log_index;
+
+        let highest = entry.serial;
+        entry.earlier.retain(|serial, _| serial + SESSION_WINDOW > highest);
+    }
+
+    /// Forget idle sessions; cheap to call on every apply
+    pub fn expire(&mut self, log_index: u64) {
+        if !log_index.is_multiple_of(SESSION_SWEEP_INTERVAL) {
+            return;
+        }
+        self.sessions
+            .retain(|_, entry| entry.last_index + SESSION_EXPIRY_ENTRIES > log_index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vraftls_core::FileId;
+
+    #[test]
+    fn test_duplicate_returns_cached_response() {
+        let client = ClientSession::with_id(42);
+        let first = client.next();
+        let mut table = SessionTable::new();
+
+        assert!(table.check(&first).is_none());
+        table.record(&first, &VfsResponse::Created(FileId::new(7)), 1);
+
+        match table.check(&first) {
+            Some(VfsResponse::Created(id)) => assert_eq!(id, FileId::new(7)),
+            other => panic!("expected cached response, got {:?}", other),
+        }
+
+        let second = client.next();
+        assert!(table.check(&second).is_none());
+        table.record(&second, &VfsResponse::Ok(None), 2);
+        assert!(matches!(table.check(&first), Some(VfsResponse::Created(_))));
+        assert!(matches!(table.check(&second), Some(VfsResponse::Ok(None))));
+    }
+
+    #[test]
+    fn test_serials_applied_out_of_order() {
+        let client = ClientSession::with_id(7);
+        let (first, second) = (client.next(), client.next());
+        let mut table = SessionTable::new();
+
+        // Concurrent writers got their commands applied the other way round
+        table.record(&second, &VfsResponse::Created(FileId::new(2)), 1);
+        assert!(table.check(&first).is_none());
+        table.record(&first, &VfsResponse::Created(FileId::new(1)), 2);
+
+        assert!(matches!(table.check(&first), Some(VfsResponse::Created(id)) if id == FileId::new(1)));
+        assert!(matches!(table.check(&second), Some(VfsResponse::Created(id)) if id == FileId::new(2)));
+
+        // Serials that fell out of the window are stale, applied or not
+        let mut latest = second;
+        for _ in 0..SESSION_WINDOW {
+            latest = client.next();
+            table.record(&latest, &VfsResponse::Ok(None), 3);
+        }
+        assert!(matches!(table.check(&first), Some(VfsResponse::Error(_))));
+        assert!(matches!(table.check(&latest), Some(VfsResponse::Ok(None))));
+        assert_eq!(table.entries()[&7].earlier.len() as u64, SESSION_WINDOW - 1);
+    }
+
+    #[test]
+    fn test_idle_sessions_expire() {
+        let session = ClientSession::with_id(1).next();
+        let mut table = SessionTable::new();
+        table.record(&session, &VfsResponse::Ok(None), 1);
+
+        table.expire(SESSION_SWEEP_INTERVAL);
+        assert!(table.check(&session).is_some());
+
+        table.expire(SESSION_SWEEP_INTERVAL * 200);
+        assert!(table.check(&session).is_none());
+    }
+}
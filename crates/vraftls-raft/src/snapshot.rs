@@ -0,0 +1,287 @@
+//! Incremental snapshot building
+//!
+//! Instead of cloning every file at once, the builder walks a copy-on-write
+//! view of the VFS in batches and streams each file into the snapshot
+//! buffer, pausing between batches so applies keep making progress.
+
+use crate::chunking::PendingTransfer;
+use crate::session::SessionEntry;
+use crate::snapshot_store::SnapshotStore;
+use crate::state_machine::VfsSnapshot;
+use crate::types::{RaftNodeId, VRaftNode, VRaftTypeConfig};
+use openraft::storage::{RaftSnapshotBuilder, Snapshot};
+use openraft::{ErrorSubject, ErrorVerb, LogId, SnapshotMeta, StorageError, StoredMembership};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::Duration;
+use vraftls_core::RaftConfig;
+use vraftls_vfs::{SnapshotView, VfsFile, VfsHandle};
+
+/// Settings for incremental snapshot building
+#[derive(Clone, Debug)]
+pub struct SnapshotBuildConfig {
+    /// Files serialized per batch
+    pub batch_size: usize,
+
+    /// Pause between batches (zero only yields)
+    pub throttle: Duration,
+}
+
+impl Default for SnapshotBuildConfig {
+    fn default() -> Self {
+        Self::from(&RaftConfig::default())
+    }
+}
+
+impl From<&RaftConfig> for SnapshotBuildConfig {
+    fn from(config: &RaftConfig) -> Self {
+        Self {
+            batch_size: config.snapshot_batch_size.max(1) as usize,
+            throttle: config.snapshot_throttle,
+        }
+    }
+}
+
+/// Snapshot builder over a point-in-time view of the VFS
+pub struct VfsSnapshotBuilder {
+    /// The live VFS
+    vfs: VfsHandle,
+
+    /// View captured when the builder was created
+    view: Arc<SnapshotView>,
+
+    /// Last applied log id at capture time
+    last_applied_log: Option<LogId<RaftNodeId>>,
+
+    /// Membership at capture time
+    membership: StoredMembership<RaftNodeId, VRaftNode>,
+
+    /// Chunked commands buffered at capture time
+    pending_chunks: BTreeMap<u64, PendingTransfer>,
+
+    /// Client sessions at capture time
+    sessions: BTreeMap<u64, SessionEntry>,
+
+    /// Batching and throttling settings
+    config: SnapshotBuildConfig,
+
+    /// Position of the next batch in the view
+    cursor: usize,
+
+    /// Where built snapshots are persisted, if anywhere
+    store: Option<Arc<SnapshotStore>>,
+}
+
+impl VfsSnapshotBuilder {
+    /// Create a builder; the caller must ensure no apply is in progress
+    pub fn new(
+        vfs: VfsHandle,
+        last_applied_log: Option<LogId<RaftNodeId>>,
+        membership: StoredMembership<RaftNodeId, VRaftNode>,
+        pending_chunks: BTreeMap<u64, PendingTransfer>,
+        sessions: BTreeMap<u64, SessionEntry>,
+        config: SnapshotBuildConfig,
+    ) -> Self {
+        let view = vfs.snapshot_view();
+        Self {
+            vfs,
+            view,
+            last_applied_log,
+            membership,
+            pending_chunks,
+            sessions,
+            config,
+            cursor: 0,
+            store: None,
+        }
+    }
+
+    /// Persist built snapshots to `store`
+    pub fn with_store(mut self, store: Option<Arc<SnapshotStore>>) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// Number of files captured for this snapshot
+    pub fn file_count(&self) -> usize {
+        self.view.len()
+    }
+
+    /// Read the next batch of files, or `None` when all files were read
+    pub fn next_batch(&mut self) -> Option<Vec<VfsFile>> {
+        let ids = self.view.file_ids();
+        if self.cursor >= ids.len() {
+            return None;
+        }
+
+        let end = (self.cursor + self.config.batch_size).min(ids.len());
+        let batch = ids[self.cursor..end]
+            .iter()
+            .filter_map(|&id| self.view.read(&self.vfs, id))
+            .collect();
+        self.cursor = end;
+
+        Some(batch)
+    }
+
+    /// Stream the whole snapshot into a buffer in the `VfsSnapshot` format
+    pub async fn write_snapshot(&mut self) -> Result<Vec<u8>, serde_json::Error> {
+        let mut buf = Vec::new();
+
+        buf.extend_from_slice(b"{\"last_applied_log\":");
+        serde_json::to_writer(&mut buf, &self.last_applied_log)?;
+        buf.extend_from_slice(b",\"membership\":");
+        serde_json::to_writer(&mut buf, &self.membership)?;
+        buf.extend_from_slice(b",\"vfs_state\":{\"files\":[");
+
+        let mut first = true;
+        while let Some(batch) = self.next_batch() {
+            for file in &batch {
+                if !first {
+                    buf.push(b',');
+                }
+                first = false;
+                serde_json::to_writer(&mut buf, file)?;
+            }
+
+            if self.config.throttle.is_zero() {
+                tokio::task::yield_now().await;
+            } else {
+                tokio::time::sleep(self.config.throttle).await;
+            }
+        }
+
+        buf.extend_from_slice(b"],\"directories\":");
+        serde_json::to_writer(&mut buf, self.view.directories())?;
+        buf.extend_from_slice(b",\"trash\":");
+        serde_json::to_writer(&mut buf, self.view.trash())?;
+        buf.extend_from_slice(b",\"next_file_id\":");
+        serde_json::to_writer(&mut buf, &self.view.next_file_id())?;
+        buf.extend_from_slice(b",\"history\":");
+        serde_json::to_writer(&mut buf, self.view.history())?;
+        buf.extend_from_slice(b"},\"pending_chunks\":");
+        serde_json::to_writer(&mut buf, &self.pending_chunks)?;
+        buf.extend_from_slice(b",\"sessions\":");
+        serde_json::to_writer(&mut buf, &self.sessions)?;
+        buf.push(b'}');
+        Ok(buf)
+    }
+}
+
+impl RaftSnapshotBuilder<VRaftTypeConfig> for VfsSnapshotBuilder {
+    async fn build_snapshot(&mut self) -> Result<Snapshot<VRaftTypeConfig>, StorageError<RaftNodeId>> {
+        let data = self.write_snapshot().await.map_err(|e| {
+            StorageError::from_io_error(ErrorSubject::StateMachine, ErrorVerb::Write, e.into())
+        })?;
+
+        let snapshot_id = format!(
+            "{}-{}",
+            self.last_applied_log.map(|l| l.index).unwrap_or(0),
+            chrono::Utc::now().timestamp()
+        );
+
+        tracing::debug!(
+            snapshot_id = %snapshot_id,
+            files = self.file_count(),
+            bytes = data.len(),
+            "built snapshot"
+        );
+
+        let meta = SnapshotMeta {
+            last_log_id: self.last_applied_log,
+            last_membership: self.membership.clone(),
+            snapshot_id,
+        };
+
+        if let Some(store) = &self.store {
+            store.save(&meta, &data).await.map_err(|e| {
+                StorageError::from_io_error(ErrorSubject::Snapshot(Some(meta.signature())), ErrorVerb::Write, e)
+            })?;
+        }
+
+        Ok(Snapshot {
+            meta,
+            snapshot: Box::new(Cursor::new(data)),
+        })
+    }
+}
+
+/// Decode a snapshot produced by `VfsSnapshotBuilder`
+pub fn decode_snapshot(data: &[u8]) -> Result<VfsSnapshot, serde_json::Error> {
+    serde_json::from_slice(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vraftls_core::RaftGroupId;
+    use vraftls_vfs::{Vfs, VfsCommand, VfsPath, VfsResponse};
+
+    fn create(vfs: &Vfs, path: &str, content: &str) -> vraftls_core::FileId {
+        match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new(path),
+            content: content.to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            other => panic!("expected Created, got {:?}", other),
+        }
+    }
+
+    fn builder(vfs: &VfsHandle, batch_size: usize) -> VfsSnapshotBuilder {
+        VfsSnapshotBuilder::new(
+            vfs.clone(),
+            None,
+            StoredMembership::default(),
+            BTreeMap::new(),
+            BTreeMap::new(),
+            SnapshotBuildConfig {
+                batch_size,
+                throttle: Duration::ZERO,
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_streams_in_batches() {
+        let vfs: VfsHandle = Arc::new(Vfs::new(RaftGroupId::new(1)));
+        for i in 0..5 {
+            create(&vfs, &format!("/file{}.rs", i), "fn main() {}");
+        }
+
+        let mut batches = builder(&vfs, 2);
+        let sizes: Vec<_> = std::iter::from_fn(|| batches.next_batch().map(|b| b.len())).collect();
+        assert_eq!(sizes, vec![2, 2, 1]);
+
+        let data = builder(&vfs, 2).write_snapshot().await.unwrap();
+        let snapshot = decode_snapshot(&data).unwrap();
+        assert_eq!(snapshot.vfs_state.files.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_ignores_later_changes() {
+        let vfs: VfsHandle = Arc::new(Vfs::new(RaftGroupId::new(1)));
+        let kept = create(&vfs, "/kept.rs", "original");
+        let removed = create(&vfs, "/removed.rs", "doomed");
+
+        let mut builder = builder(&vfs, 1);
+
+        vfs.apply(VfsCommand::UpdateFile {
+            file_id: kept,
+            content: "changed".to_string(),
+            expected_version: None,
+        });
+        vfs.apply(VfsCommand::DeleteFile { file_id: removed });
+        create(&vfs, "/new.rs", "too late");
+
+        let snapshot = decode_snapshot(&builder.write_snapshot().await.unwrap()).unwrap();
+        let mut contents: Vec<_> = snapshot
+            .vfs_state
+            .files
+            .iter()
+            .map(|f| f.content_string().unwrap())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, vec!["doomed", "original"]);
+    }
+}
@@ -0,0 +1,315 @@
+//! On-disk snapshot storage and retention
+//!
+//! Each snapshot is stored as `<snapshot_id>.snap` (data) next to
+//! `<snapshot_id>.meta` (JSON metadata). A background task removes old
+//! snapshots according to the configured retention policy.
+
+use crate::archive::{ArchiveObject, Archiver};
+use crate::types::{RaftNodeId, VRaftNode, VRaftTypeConfig};
+use openraft::storage::Snapshot;
+use openraft::SnapshotMeta;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::{RaftConfig, Timestamp};
+
+const DATA_EXT: &str = "snap";
+const META_EXT: &str = "meta";
+
+/// Retention policy for stored snapshots
+#[derive(Clone, Debug)]
+pub struct SnapshotRetention {
+    /// Number of most recent snapshots to keep (at least one is always kept)
+    pub keep_last: usize,
+
+    /// Remove snapshots older than this (zero disables age-based removal)
+    pub max_age: Duration,
+}
+
+impl From<&RaftConfig> for SnapshotRetention {
+    fn from(config: &RaftConfig) -> Self {
+        Self {
+            keep_last: config.snapshot_retain_count.max(1) as usize,
+            max_age: config.snapshot_max_age,
+        }
+    }
+}
+
+/// Metadata file stored next to each snapshot
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StoredSnapshotMeta {
+    /// OpenRaft snapshot metadata
+    pub meta: SnapshotMeta<RaftNodeId, VRaftNode>,
+
+    /// When the snapshot was stored
+    pub created_at: Timestamp,
+}
+
+/// A snapshot found on disk
+#[derive(Clone, Debug)]
+pub struct StoredSnapshot {
+    /// Stored metadata
+    pub meta: StoredSnapshotMeta,
+
+    /// Size of data and metadata files in bytes
+    pub size: u64,
+}
+
+/// Garbage collection statistics
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SnapshotGcStats {
+    /// Completed GC runs
+    pub runs: u64,
+
+    /// Snapshots removed
+    pub removed: u64,
+
+    /// Bytes reclaimed
+    pub reclaimed_bytes: u64,
+}
+
+/// Directory of persisted snapshots
+pub struct SnapshotStore {
+    /// Snapshot directory
+    dir: PathBuf,
+
+    gc_runs: AtomicU64,
+    gc_removed: AtomicU64,
+    gc_reclaimed_bytes: AtomicU64,
+
+    /// Archive for saved snapshots
+    archiver: Option<Archiver>,
+}
+
+impl SnapshotStore {
+    /// Open (creating if needed) a snapshot directory
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        std::fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+            gc_runs: AtomicU64::new(0),
+            gc_removed: AtomicU64::new(0),
+            gc_reclaimed_bytes: AtomicU64::new(0),
+            archiver: None,
+        })
+    }
+
+    /// Also ship every saved snapshot to an archive
+    pub fn with_archiver(mut self, archiver: Archiver) -> Self {
+        self.archiver = Some(archiver);
+        self
+    }
+
+    fn path(&self, snapshot_id: &str, ext: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", snapshot_id, ext))
+    }
+
+    /// Persist a snapshot
+    ///
+    /// Data is written before metadata, and each file is renamed into place,
+    /// so a snapshot is only listed once it is complete. With an archiver,
+    /// this waits for room in its upload queue.
+    pub async fn save(&self, meta: &SnapshotMeta<RaftNodeId, VRaftNode>, data: &[u8]) -> io::Result<()> {
+        let stored = StoredSnapshotMeta {
+            meta: meta.clone(),
+            created_at: Timestamp::now(),
+        };
+        let meta_bytes = serde_json::to_vec(&stored).map_err(io::Error::other)?;
+
+        write_atomic(&self.path(&meta.snapshot_id, DATA_EXT), data)?;
+        write_atomic(&self.path(&meta.snapshot_id, META_EXT), &meta_bytes)?;
+
+        if let Some(archiver) = &self.archiver {
+            archiver
+                .submit(ArchiveObject::snapshot_file(&meta.snapshot_id, DATA_EXT, data.to_vec()))
+                .await;
+            archiver
+                .submit(ArchiveObject::snapshot_file(&meta.snapshot_id, META_EXT, meta_bytes))
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// List stored snapshots, newest first
+    pub fn list(&self) -> io::Result<Vec<StoredSnapshot>> {
+        let mut snapshots = Vec::new();
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(META_EXT) {
+                continue;
+            }
+
+            let meta: StoredSnapshotMeta = match std::fs::read(&path)
+                .map_err(io::Error::other)
+                .and_then(|bytes| serde_json::from_slice(&bytes).map_err(io::Error::other))
+            {
+                Ok(meta) => meta,
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "skipping unreadable snapshot metadata");
+                    continue;
+                }
+            };
+
+            let data_path = self.path(&meta.meta.snapshot_id, DATA_EXT);
+            let Ok(data) = std::fs::metadata(&data_path) else {
+                continue;
+            };
+            let size = data.len() + std::fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+
+            snapshots.push(StoredSnapshot { meta, size });
+        }
+
+        snapshots.sort_by(|a, b| {
+            let key = |s: &StoredSnapshot| (s.meta.meta.last_log_id.map(|l| l.index), s.meta.created_at.0);
+            key(b).cmp(&key(a))
+        });
+
+        Ok(snapshots)
+    }
+
+    /// Load the most recent snapshot
+    pub fn latest(&self) -> io::Result<Option<Snapshot<VRaftTypeConfig>>> {
+        let Some(latest) = self.list()?.into_iter().next() else {
+            return Ok(None);
+        };
+
+        let data = std::fs::read(self.path(&latest.meta.meta.snapshot_id, DATA_EXT))?;
+        Ok(Some(Snapshot {
+            meta: latest.meta.meta,
+            snapshot: Box::new(Cursor::new(data)),
+        }))
+    }
+
+    /// Remove snapshots not covered by the retention policy
+    pub fn gc(&self, retention: &SnapshotRetention) -> io::Result<SnapshotGcStats> {
+        let now = Timestamp::now().0;
+        let max_age_ms = retention.max_age.as_millis() as u64;
+        let mut report = SnapshotGcStats {
+            runs: 1,
+            ..Default::default()
+        };
+
+        for (rank, snapshot) in self.list()?.into_iter().enumerate() {
+            // The newest snapshot is always kept
+            let too_many = rank >= retention.keep_last.max(1);
+            let too_old = rank > 0
+                && max_age_ms > 0
+                && now.saturating_sub(snapshot.meta.created_at.0) > max_age_ms;
+            if !(too_many || too_old) {
+                continue;
+            }
+
+            let id = &snapshot.meta.meta.snapshot_id;
+            std::fs::remove_file(self.path(id, META_EXT))?;
+            let _ = std::fs::remove_file(self.path(id, DATA_EXT));
+
+            tracing::debug!(snapshot_id = %id, bytes = snapshot.size, "removed old snapshot");
+            report.removed += 1;
+            report.reclaimed_bytes += snapshot.size;
+        }
+
+        self.gc_runs.fetch_add(1, Ordering::Relaxed);
+        self.gc_removed.fetch_add(report.removed, Ordering::Relaxed);
+        self.gc_reclaimed_bytes
+            .fetch_add(report.reclaimed_bytes, Ordering::Relaxed);
+
+        Ok(report)
+    }
+
+    /// Cumulative GC statistics
+    pub fn gc_stats(&self) -> SnapshotGcStats {
+        SnapshotGcStats {
+            runs: self.gc_runs.load(Ordering::Relaxed),
+            removed: self.gc_removed.load(Ordering::Relaxed),
+            reclaimed_bytes: self.gc_reclaimed_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Write a file via a temporary file and rename
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Periodically enforce the retention policy
+pub fn spawn_snapshot_gc(
+    store: Arc<SnapshotStore>,
+    retention: SnapshotRetention,
+    interval: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            let store = store.clone();
+            let retention = retention.clone();
+            match tokio::task::spawn_blocking(move || store.gc(&retention)).await {
+                Ok(Ok(report)) if report.removed > 0 => {
+                    tracing::info!(
+                        removed = report.removed,
+                        reclaimed_bytes = report.reclaimed_bytes,
+                        "snapshot gc"
+                    );
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::warn!(error = %e, "snapshot gc failed"),
+                Err(e) => tracing::warn!(error = %e, "snapshot gc task failed"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openraft::{CommittedLeaderId, LogId, StoredMembership};
+    use tempfile::TempDir;
+
+    fn meta(index: u64) -> SnapshotMeta<RaftNodeId, VRaftNode> {
+        SnapshotMeta {
+            last_log_id: Some(LogId::new(CommittedLeaderId::new(1, 1), index)),
+            last_membership: StoredMembership::default(),
+            snapshot_id: format!("{}-0", index),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gc_keeps_latest_snapshots() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = SnapshotStore::open(temp_dir.path()).unwrap();
+        for index in [10, 30, 20] {
+            store.save(&meta(index), b"data").await.unwrap();
+        }
+
+        let report = store
+            .gc(&SnapshotRetention {
+                keep_last: 2,
+                max_age: Duration::ZERO,
+            })
+            .unwrap();
+        assert_eq!(report.removed, 1);
+        assert!(report.reclaimed_bytes > 4);
+
+        let remaining: Vec<_> = store
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.meta.meta.snapshot_id)
+            .collect();
+        assert_eq!(remaining, vec!["30-0", "20-0"]);
+        assert_eq!(store.gc_stats().removed, 1);
+
+        let latest = store.latest().unwrap().unwrap();
+        assert_eq!(latest.meta.snapshot_id, "30-0");
+        assert_eq!(latest.snapshot.into_inner(), b"data");
+    }
+}
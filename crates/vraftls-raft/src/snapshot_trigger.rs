@@ -0,0 +1,233 @@
+//! Automatic snapshot triggering
+//!
+//! Watches applied-index progress and the on-disk log size, asks OpenRaft to
+//! build a snapshot once `max_log_entries` or `max_log_bytes` is crossed, and
+//! purges the log up to each new snapshot. OpenRaft's own snapshot policy is
+//! disabled in `openraft_config`, so this is the only trigger.
+
+use crate::storage::RocksDbLogStorage;
+use crate::types::RaftNodeId;
+use crate::VRaftRaft;
+use openraft::error::Fatal;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_core::RaftConfig;
+
+/// Snapshot thresholds
+#[derive(Clone, Debug)]
+pub struct SnapshotTriggerConfig {
+    /// Applied entries since the last snapshot that trigger a new one
+    pub max_log_entries: u64,
+
+    /// Log size in bytes that triggers a snapshot
+    pub max_log_bytes: u64,
+
+    /// Interval between checks
+    pub check_interval: Duration,
+}
+
+impl From<&RaftConfig> for SnapshotTriggerConfig {
+    fn from(config: &RaftConfig) -> Self {
+        Self {
+            max_log_entries: config.max_log_entries.max(1),
+            max_log_bytes: config.max_log_bytes.max(1),
+            check_interval: config.snapshot_check_interval,
+        }
+    }
+}
+
+impl SnapshotTriggerConfig {
+    /// Whether the log has grown enough to need a snapshot
+    pub fn crossed(&self, entries_since_snapshot: u64, log_bytes: u64) -> bool {
+        // Bytes alone never trigger without new entries to compact
+        entries_since_snapshot >= self.max_log_entries
+            || (entries_since_snapshot > 0 && log_bytes >= self.max_log_bytes)
+    }
+}
+
+/// Triggers snapshots and log purges for one Raft instance
+pub struct SnapshotTrigger {
+    raft: VRaftRaft,
+    log_storage: Arc<RocksDbLogStorage>,
+    config: SnapshotTriggerConfig,
+
+    /// Applied index at the last snapshot request
+    requested_at: u64,
+
+    /// Snapshot index the log was last purged to
+    purged_to: u64,
+}
+
+impl SnapshotTrigger {
+    pub fn new(raft: VRaftRaft, log_storage: Arc<RocksDbLogStorage>, config: SnapshotTriggerConfig) -> Self {
+        Self {
+            raft,
+            log_storage,
+            config,
+            requested_at: 0,
+            purged_to: 0,
+        }
+    }
+
+    /// Check the thresholds once
+    pub async fn check(&mut self) -> Result<(), Fatal<RaftNodeId>> {
+        let (applied, snapshot) = {
+            let metrics = self.raft.metrics();
+            let metrics = metrics.borrow();
+            (
+                metrics.last_applied.map_or(0, |l| l.index),
+                metrics.snapshot.map_or(0, |l| l.index),
+            )
+        };
+
+        // A snapshot finished since the last check: drop the log it covers
+        if snapshot > self.purged_to {
+            tracing::debug!(upto = snapshot, "purging log covered by snapshot");
+            self.raft.trigger().purge_log(snapshot).await?;
+            self.purged_to = snapshot;
+        }
+
+        let entries = applied.saturating_sub(snapshot);
+        let log_bytes = self.log_storage.log_bytes();
+        if applied > self.requested_at && self.config.crossed(entries, log_bytes) {
+            tracing::info!(applied, entries, log_bytes, "triggering snapshot");
+            // OpenRaft ignores the request while a snapshot is already being built
+            self.raft.trigger().snapshot().await?;
+            self.requested_at = applied;
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the snapshot thresholds periodically until Raft shuts down
+pub fn spawn_snapshot_trigger(mut trigger: SnapshotTrigger) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(trigger.config.check_interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = trigger.check().await {
+                tracing::debug!(error = %e, "snapshot trigger stopped");
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_network::LocalRouter;
+    use crate::proposal::VfsProposer;
+    use crate::snapshot_store::SnapshotStore;
+    use crate::state_machine::VfsStateMachine;
+    use crate::types::VRaftNode;
+    use openraft::Raft;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use tempfile::TempDir;
+    use vraftls_core::RaftGroupId;
+    use vraftls_vfs::{VfsCommand, VfsPath};
+
+    async fn start_node(router: &LocalRouter, dir: &Path) -> (VRaftRaft, Arc<RocksDbLogStorage>, Arc<VfsStateMachine>) {
+        let config = Arc::new(
+            openraft::Config {
+                heartbeat_interval: 50,
+                election_timeout_min: 200,
+                election_timeout_max: 400,
+                ..Default::default()
+            }
+            .validate()
+            .unwrap(),
+        );
+        let log_storage = Arc::new(RocksDbLogStorage::new(dir).unwrap());
+        let store = Arc::new(SnapshotStore::open(dir.join("snapshots")).unwrap());
+        let state_machine = Arc::new(
+            VfsStateMachine::new(RaftGroupId::new(1))
+                .with_snapshot_store(store)
+                .await
+                .unwrap(),
+        );
+        let raft = Raft::new(1, config, router.network(1), log_storage.clone(), state_machine.clone())
+            .await
+            .unwrap();
+        router.register(1, raft.clone());
+        (raft, log_storage, state_machine)
+    }
+
+    async fn create(raft: &VRaftRaft, path: &str) {
+        VfsProposer::new(raft.clone(), RaftGroupId::new(1))
+            .propose(VfsCommand::CreateFile {
+                path: VfsPath::new(path),
+                content: path.to_string(),
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restart_after_purge() {
+        let dir = TempDir::new().unwrap();
+        let router = LocalRouter::new();
+        let (raft, log_storage, _) = start_node(&router, dir.path()).await;
+        raft.initialize(BTreeMap::from([(1, VRaftNode::new("local-1"))])).await.unwrap();
+        raft.wait(Some(Duration::from_secs(10)))
+            .metrics(|m| m.current_leader == Some(1), "leader elected")
+            .await
+            .unwrap();
+
+        for path in ["/a.rs", "/b.rs", "/c.rs"] {
+            create(&raft, path).await;
+        }
+        let applied = raft.metrics().borrow().last_applied.unwrap();
+        raft.trigger().snapshot().await.unwrap();
+        let snapshot = raft
+            .wait(Some(Duration::from_secs(10)))
+            .metrics(|m| m.snapshot.is_some_and(|s| s >= applied), "snapshot built")
+            .await
+            .unwrap()
+            .snapshot
+            .unwrap();
+
+        // The check after the snapshot drops the log it covers
+        let config = SnapshotTriggerConfig {
+            max_log_entries: u64::MAX,
+            max_log_bytes: u64::MAX,
+            check_interval: Duration::from_secs(1),
+        };
+        SnapshotTrigger::new(raft.clone(), log_storage, config).check().await.unwrap();
+        raft.wait(Some(Duration::from_secs(10)))
+            .metrics(|m| m.purged.is_some_and(|p| p >= snapshot), "log purged")
+            .await
+            .unwrap();
+        create(&raft, "/d.rs").await;
+
+        raft.shutdown().await.unwrap();
+        router.unregister(1);
+        drop(raft);
+
+        // The restarted node starts from the snapshot and replays the rest
+        let (raft, _, state_machine) = start_node(&router, dir.path()).await;
+        raft.wait(Some(Duration::from_secs(10)))
+            .applied_index_at_least(Some(snapshot.index + 1), "log replayed")
+            .await
+            .unwrap();
+        assert_eq!(state_machine.vfs().file_count(), 4);
+        raft.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let config = SnapshotTriggerConfig {
+            max_log_entries: 100,
+            max_log_bytes: 1000,
+            check_interval: Duration::from_secs(1),
+        };
+
+        assert!(!config.crossed(99, 999));
+        assert!(config.crossed(100, 0));
+        assert!(config.crossed(1, 1000));
+        assert!(!config.crossed(0, 5000));
+    }
+}
@@ -0,0 +1,233 @@
+//! Bounded-staleness reads from any replica
+//!
+//! A follower serves queries from its own state machine as long as it has
+//! applied the log to within `max_lag` entries of the commit index it last
+//! heard from the leader, and heard from the leader within `max_staleness`.
+//! A replica cut off from the leader knows neither how far the commit index
+//! moved on nor whether it still has a leader, so it refuses reads until it
+//! is in contact again; a leader counts as in contact while a quorum
+//! acknowledges it. The lag is returned with the result so callers can
+//! apply a stricter bound of their own.
+
+use crate::state_machine::VfsStateMachine;
+use crate::storage::RocksDbLogStorage;
+use crate::types::RaftNodeId;
+use crate::VRaftRaft;
+use openraft::raft::AppendEntriesResponse;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use vraftls_core::{RaftConfig, Result, VRaftError};
+use vraftls_vfs::{VfsQuery, VfsQueryResponse};
+
+/// Result of a stale read
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StaleRead {
+    /// Query response
+    pub response: VfsQueryResponse,
+
+    /// Log index applied when the query was served
+    pub applied_index: Option<u64>,
+
+    /// Commit index known to this replica
+    pub committed_index: Option<u64>,
+
+    /// Entries between the known commit index and the applied index
+    pub lag: u64,
+
+    /// Milliseconds since the replica last heard from the leader
+    #[serde(default)]
+    pub since_leader_contact_ms: Option<u64>,
+}
+
+/// When a follower last accepted entries or a heartbeat from the leader;
+/// clones share the time
+#[derive(Clone, Debug, Default)]
+pub struct LeaderContact(Arc<Mutex<Option<Instant>>>);
+
+impl LeaderContact {
+    /// Note that the leader was heard from just now
+    pub fn record(&self) {
+        *self.0.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Record contact if an AppendEntries came from the current leader,
+    /// i.e. was not refused for a higher vote
+    pub fn observe(&self, response: &AppendEntriesResponse<RaftNodeId>) {
+        if !matches!(response, AppendEntriesResponse::HigherVote(_)) {
+            self.record();
+        }
+    }
+
+    /// Time since the leader was last heard from; `None` if never
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.0.lock().unwrap().map(|at| at.elapsed())
+    }
+}
+
+/// Serves VFS queries from the local replica with bounded staleness
+#[derive(Clone)]
+pub struct StaleReader {
+    /// Local state machine
+    state_machine: Arc<VfsStateMachine>,
+
+    /// Local log storage, for the commit index
+    log_storage: Arc<RocksDbLogStorage>,
+
+    /// Maximum tolerated lag in entries
+    max_lag: u64,
+
+    /// Longest tolerated time without hearing from the leader
+    max_staleness: Duration,
+
+    /// Last contact with the leader, recorded by the Raft RPC handler
+    contact: LeaderContact,
+
+    /// The group's Raft instance, for the leader's own quorum contact
+    raft: Option<VRaftRaft>,
+}
+
+impl StaleReader {
+    pub fn new(
+        state_machine: Arc<VfsStateMachine>,
+        log_storage: Arc<RocksDbLogStorage>,
+        config: &RaftConfig,
+    ) -> Self {
+        Self {
+            state_machine,
+            log_storage,
+            max_lag: config.max_read_lag,
+            max_staleness: config.max_read_staleness,
+            contact: LeaderContact::default(),
+            raft: None,
+        }
+    }
+
+    /// Count this replica as in contact while it leads `raft` with a quorum
+    pub fn with_raft(mut self, raft: VRaftRaft) -> Self {
+        self.raft = Some(raft);
+        self
+    }
+
+    /// Last contact with the leader, to be recorded on every accepted
+    /// AppendEntries
+    pub fn leader_contact(&self) -> &LeaderContact {
+        &self.contact
+    }
+
+    /// Set the maximum tolerated lag
+    pub fn with_max_lag(mut self, max_lag: u64) -> Self {
+        self.max_lag = max_lag;
+        self
+    }
+
+    /// Time since the leader, or as leader a quorum, was last heard from
+    pub fn since_leader_contact(&self) -> Option<Duration> {
+        if let Some(raft) = &self.raft {
+            let metrics = raft.metrics();
+            let metrics = metrics.borrow();
+            if metrics.current_leader == Some(metrics.id) {
+                return metrics.millis_since_quorum_ack.map(Duration::from_millis);
+            }
+        }
+        self.contact.elapsed()
+    }
+
+    /// Current lag of the local state machine
+    pub async fn lag(&self) -> (Option<u64>, Option<u64>, u64) {
+        let applied = self.state_machine.last_applied().await.map(|l| l.index);
+        let committed = self.log_storage.committed().await.map(|l| l.index);
+        let lag = committed.unwrap_or(0).saturating_sub(applied.unwrap_or(0));
+        (applied, committed, lag)
+    }
+
+    /// Serve a query if the replica is fresh enough
+    pub async fn read(&self, query: VfsQuery) -> Result<StaleRead> {
+        let since_contact = self.since_leader_contact();
+        if since_contact.is_none_or(|since| since > self.max_staleness) {
+            return Err(VRaftError::LeaderContactLost {
+                since_ms: since_contact.map(|since| since.as_millis() as u64),
+                max_ms: self.max_staleness.as_millis() as u64,
+            });
+        }
+
+        let (applied_index, committed_index, lag) = self.lag().await;
+        if lag > self.max_lag {
+            return Err(VRaftError::StaleRead {
+                lag,
+                max_lag: self.max_lag,
+            });
+        }
+
+        Ok(StaleRead {
+            response: self.state_machine.vfs().query(query),
+            applied_index,
+            committed_index,
+            lag,
+            since_leader_contact_ms: since_contact.map(|since| since.as_millis() as u64),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openraft::storage::RaftLogStorage;
+    use openraft::{CommittedLeaderId, LogId};
+    use tempfile::TempDir;
+    use vraftls_core::RaftGroupId;
+    use vraftls_vfs::VfsPath;
+
+    #[tokio::test]
+    async fn test_read_rejected_when_lagging() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut log_storage = Arc::new(RocksDbLogStorage::new(temp_dir.path()).unwrap());
+        let state_machine = Arc::new(VfsStateMachine::new(RaftGroupId::new(1)));
+        let reader = StaleReader::new(state_machine, log_storage.clone(), &RaftConfig::default())
+            .with_max_lag(10);
+
+        let query = || VfsQuery::GetFileByPath(VfsPath::new("/a.rs"));
+        reader.leader_contact().record();
+        let read = reader.read(query()).await.unwrap();
+        assert_eq!(read.lag, 0);
+        assert!(matches!(read.response, VfsQueryResponse::File(None)));
+
+        log_storage
+            .save_committed(Some(LogId::new(CommittedLeaderId::new(1, 1), 50)))
+            .await
+            .unwrap();
+        assert!(matches!(
+            reader.read(query()).await,
+            Err(VRaftError::StaleRead { lag: 50, max_lag: 10 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_rejected_without_leader_contact() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_storage = Arc::new(RocksDbLogStorage::new(temp_dir.path()).unwrap());
+        let state_machine = Arc::new(VfsStateMachine::new(RaftGroupId::new(1)));
+        let config = RaftConfig {
+            max_read_staleness: Duration::from_millis(50),
+            ..RaftConfig::default()
+        };
+        let reader = StaleReader::new(state_machine, log_storage, &config);
+        let query = || VfsQuery::GetFileByPath(VfsPath::new("/a.rs"));
+
+        // Never heard from a leader
+        assert!(matches!(
+            reader.read(query()).await,
+            Err(VRaftError::LeaderContactLost { since_ms: None, max_ms: 50 })
+        ));
+
+        reader.leader_contact().record();
+        assert!(reader.read(query()).await.is_ok());
+
+        // Cut off from the leader for longer than the bound
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(matches!(
+            reader.read(query()).await,
+            Err(VRaftError::LeaderContactLost { since_ms: Some(_), .. })
+        ));
+    }
+}
@@ -3,17 +3,24 @@
 //! The state machine applies committed log entries to the VFS.
 //! This is where the actual file operations happen.
 
-use crate::types::{RaftNodeId, VRaftNode, VRaftTypeConfig, VfsRequest, VfsStateMachineResponse};
+use crate::chunking::{ChunkAssembler, ChunkOutcome, PendingTransfer};
+use crate::session::{SessionEntry, SessionTable};
+use crate::snapshot::{decode_snapshot, SnapshotBuildConfig, VfsSnapshotBuilder};
+use crate::snapshot_store::SnapshotStore;
+use crate::types::{
+    RaftNodeId, VRaftNode, VRaftTypeConfig, VfsRequest, VfsRequestPayload, VfsStateMachineResponse,
+};
 use openraft::storage::{RaftSnapshotBuilder, RaftStateMachine, Snapshot};
 use openraft::{
     Entry, EntryPayload, LogId, OptionalSend, SnapshotMeta, StorageError, StoredMembership,
 };
 use serde::{Deserialize, Serialize};
-use std::io::Cursor;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor};
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use vraftls_core::RaftGroupId;
-use vraftls_vfs::{Vfs, VfsCommand, VfsHandle, VfsResponse};
+use vraftls_core::{FileId, RaftGroupId};
+use vraftls_vfs::{Vfs, VfsCommand, VfsCommandError, VfsHandle, VfsPath, VfsResponse};
 
 /// VFS State Machine
 ///
@@ -26,10 +33,22 @@ pub struct VfsStateMachine {
     last_applied_log: RwLock<Option<LogId<RaftNodeId>>>,
 
     /// Current membership configuration
-    membership: RwLock<StoredMembership<VRaftTypeConfig>>,
+    membership: RwLock<StoredMembership<RaftNodeId, VRaftNode>>,
 
     /// Raft group ID
     group_id: RaftGroupId,
+
+    /// Incremental snapshot settings
+    snapshot_config: SnapshotBuildConfig,
+
+    /// Partially received chunked commands
+    chunks: RwLock<ChunkAssembler>,
+
+    /// Client sessions for write deduplication
+    sessions: RwLock<SessionTable>,
+
+    /// Persistent snapshot storage
+    snapshot_store: Option<Arc<SnapshotStore>>,
 }
 
 impl VfsStateMachine {
@@ -40,6 +59,10 @@ impl VfsStateMachine {
             last_applied_log: RwLock::new(None),
             membership: RwLock::new(StoredMembership::default()),
             group_id,
+            snapshot_config: SnapshotBuildConfig::default(),
+            chunks: RwLock::new(ChunkAssembler::new()),
+            sessions: RwLock::new(SessionTable::new()),
+            snapshot_store: None,
         }
     }
 
@@ -50,13 +73,111 @@ impl VfsStateMachine {
             last_applied_log: RwLock::new(None),
             membership: RwLock::new(StoredMembership::default()),
             group_id,
+            snapshot_config: SnapshotBuildConfig::default(),
+            chunks: RwLock::new(ChunkAssembler::new()),
+            sessions: RwLock::new(SessionTable::new()),
+            snapshot_store: None,
         }
     }
 
+    /// Set the incremental snapshot settings
+    pub fn with_snapshot_config(mut self, config: SnapshotBuildConfig) -> Self {
+        self.snapshot_config = config;
+        self
+    }
+
+    /// Persist snapshots to `store`, restoring the latest one now
+    ///
+    /// Log entries purged after that snapshot are therefore not needed to
+    /// rebuild the VFS after a restart.
+    pub async fn with_snapshot_store(mut self, store: Arc<SnapshotStore>) -> io::Result<Self> {
+        if let Some(snapshot) = store.latest()? {
+            self.restore(decode_snapshot(&snapshot.snapshot.into_inner())?).await;
+        }
+        self.snapshot_store = Some(store);
+        Ok(self)
+    }
+
+    /// Get the Raft group ID
+    pub fn group_id(&self) -> RaftGroupId {
+        self.group_id
+    }
+
     /// Get a reference to the VFS
     pub fn vfs(&self) -> &VfsHandle {
         &self.vfs
     }
+
+    /// Last log id applied to the VFS
+    pub async fn last_applied(&self) -> Option<LogId<RaftNodeId>> {
+        *self.last_applied_log.read().await
+    }
+
+    /// Apply a client request committed at `log_index`
+    async fn apply_request(&self, request: VfsRequest, log_index: u64) -> VfsResponse {
+        let mut sessions = self.sessions.write().await;
+        sessions.expire(log_index);
+
+        // Retried command: answer from the session instead of re-applying
+        if let Some(cached) = request.session.as_ref().and_then(|s| sessions.check(s)) {
+            return cached;
+        }
+
+        let response = match request.payload {
+            VfsRequestPayload::Command(command) => self.vfs.apply(command),
+            // Buffer chunks until the command is complete
+            VfsRequestPayload::Chunk(chunk) => {
+                let outcome = self.chunks.write().await.accept(chunk, log_index);
+                match outcome {
+                    ChunkOutcome::Pending => return VfsResponse::Ok(None),
+                    ChunkOutcome::Complete(command) => self.vfs.apply(command),
+                    ChunkOutcome::Invalid(message) => {
+                        VfsResponse::Error(VfsCommandError::StorageError(message))
+                    }
+                }
+            }
+            VfsRequestPayload::Metadata(_) => VfsResponse::Error(VfsCommandError::StorageError(
+                "metadata command sent to a data group".to_string(),
+            )),
+        };
+
+        if let Some(session) = &request.session {
+            sessions.record(session, &response, log_index);
+        }
+
+        response
+    }
+
+    /// Replace the whole state with a snapshot's
+    ///
+    /// Files and trashed files keep their IDs, so later log entries and
+    /// cached session responses refer to the same files as on the leader.
+    async fn restore(&self, snapshot: VfsSnapshot) {
+        *self.last_applied_log.write().await = snapshot.last_applied_log;
+        *self.membership.write().await = snapshot.membership;
+        *self.chunks.write().await = ChunkAssembler::from_pending(snapshot.pending_chunks);
+        *self.sessions.write().await = SessionTable::from_entries(snapshot.sessions);
+
+        let state = snapshot.vfs_state;
+        self.vfs.reset(state.next_file_id.max(1));
+        for path in state.directories {
+            self.vfs.apply(VfsCommand::CreateDirectory { path, recursive: true });
+        }
+        for trashed in state.trash {
+            self.vfs.insert_trashed(trashed);
+        }
+        for file in state.files {
+            // Snapshots taken before directories were kept only imply them
+            if let Some(parent) = file.path.parent() {
+                self.vfs.apply(VfsCommand::CreateDirectory {
+                    path: parent,
+                    recursive: true,
+                });
+            }
+            self.vfs.insert_file(file);
+        }
+        self.vfs.restore_history(state.history);
+    }
 }
 
 /// Snapshot data structure
@@ -66,10 +187,18 @@ pub struct VfsSnapshot {
     pub last_applied_log: Option<LogId<RaftNodeId>>,
 
     /// Membership configuration
-    pub membership: StoredMembership<VRaftTypeConfig>,
+    pub membership: StoredMembership<RaftNodeId, VRaftNode>,
 
     /// VFS state (serialized files)
     pub vfs_state: VfsSnapshotState,
+
+    /// Chunked commands not yet fully applied
+    #[serde(default)]
+    pub pending_chunks: BTreeMap<u64, PendingTransfer>,
+
+    /// Client sessions for write deduplication
+    #[serde(default)]
+    pub sessions: BTreeMap<u64, SessionEntry>,
 }
 
 /// VFS state in snapshot
@@ -77,62 +206,31 @@ pub struct VfsSnapshot {
 pub struct VfsSnapshotState {
     /// All files in the VFS
     pub files: Vec<vraftls_vfs::VfsFile>,
-}
 
-impl RaftSnapshotBuilder<VRaftTypeConfig> for Arc<VfsStateMachine> {
-    async fn build_snapshot(&mut self) -> Result<Snapshot<VRaftTypeConfig>, StorageError<RaftNodeId>> {
-        // Get current state
-        let last_applied_log = self.last_applied_log.read().await.clone();
-        let membership = self.membership.read().await.clone();
-
-        // Collect all files
-        let file_ids = self.vfs.all_file_ids();
-        let files: Vec<_> = file_ids
-            .into_iter()
-            .filter_map(|id| self.vfs.get_file(id))
-            .collect();
+    /// All directories in the VFS, parents first
+    #[serde(default)]
+    pub directories: Vec<VfsPath>,
 
-        let vfs_state = VfsSnapshotState { files };
+    /// Deleted files kept in the trash
+    #[serde(default)]
+    pub trash: Vec<vraftls_vfs::TrashedFile>,
 
-        let snapshot = VfsSnapshot {
-            last_applied_log,
-            membership: membership.clone(),
-            vfs_state,
-        };
+    /// ID the next created file gets
+    #[serde(default)]
+    pub next_file_id: u64,
 
-        // Serialize snapshot
-        let data = serde_json::to_vec(&snapshot).map_err(|e| {
-            StorageError::from_io_error(
-                openraft::ErrorSubject::StateMachine,
-                openraft::ErrorVerb::Write,
-                e.into(),
-            )
-        })?;
-
-        let snapshot_id = format!(
-            "{}-{}",
-            last_applied_log.map(|l| l.index).unwrap_or(0),
-            chrono::Utc::now().timestamp()
-        );
-
-        Ok(Snapshot {
-            meta: SnapshotMeta {
-                last_log_id: last_applied_log,
-                last_membership: membership,
-                snapshot_id,
-            },
-            snapshot: Box::new(Cursor::new(data)),
-        })
-    }
+    /// Previous versions of files, oldest first
+    #[serde(default)]
+    pub history: HashMap<FileId, Vec<vraftls_vfs::FileRevision>>,
 }
 
 impl RaftStateMachine<VRaftTypeConfig> for Arc<VfsStateMachine> {
-    type SnapshotBuilder = Self;
+    type SnapshotBuilder = VfsSnapshotBuilder;
 
     async fn applied_state(
         &mut self,
-    ) -> Result<(Option<LogId<RaftNodeId>>, StoredMembership<VRaftTypeConfig>), StorageError<RaftNodeId>> {
-        let last_applied = self.last_applied_log.read().await.clone();
+    ) -> Result<(Option<LogId<RaftNodeId>>, StoredMembership<RaftNodeId, VRaftNode>), StorageError<RaftNodeId>> {
+        let last_applied = *self.last_applied_log.read().await;
         let membership = self.membership.read().await.clone();
         Ok((last_applied, membership))
     }
@@ -150,25 +248,18 @@ impl RaftStateMachine<VRaftTypeConfig> for Arc<VfsStateMachine> {
 
             match entry.payload {
                 EntryPayload::Blank => {
-                    responses.push(VfsStateMachineResponse {
-                        response: VfsResponse::Ok(None),
-                    });
+                    responses.push(VfsStateMachineResponse::new(VfsResponse::Ok(None)));
                 }
                 EntryPayload::Normal(request) => {
-                    // Apply the VFS command
-                    let vfs_response = self.vfs.apply(request.command);
-                    responses.push(VfsStateMachineResponse {
-                        response: vfs_response,
-                    });
+                    let vfs_response = self.apply_request(request, entry.log_id.index).await;
+                    responses.push(VfsStateMachineResponse::new(vfs_response));
                 }
                 EntryPayload::Membership(membership) => {
                     *self.membership.write().await = StoredMembership::new(
                         Some(entry.log_id),
                         membership,
                     );
-                    responses.push(VfsStateMachineResponse {
-                        response: VfsResponse::Ok(None),
-                    });
+                    responses.push(VfsStateMachineResponse::new(VfsResponse::Ok(None)));
                 }
             }
         }
@@ -177,7 +268,17 @@ impl RaftStateMachine<VRaftTypeConfig> for Arc<VfsStateMachine> {
     }
 
     async fn get_snapshot_builder(&mut self) -> Self::SnapshotBuilder {
-        self.clone()
+        // Called between applies, so the captured view is consistent with
+        // the last applied log id
+        VfsSnapshotBuilder::new(
+            self.vfs.clone(),
+            *self.last_applied_log.read().await,
+            self.membership.read().await.clone(),
+            self.chunks.read().await.pending().clone(),
+            self.sessions.read().await.entries().clone(),
+            self.snapshot_config.clone(),
+        )
+        .with_store(self.snapshot_store.clone())
     }
 
     async fn begin_receiving_snapshot(&mut self) -> Result<Box<Cursor<Vec<u8>>>, StorageError<RaftNodeId>> {
@@ -186,12 +287,24 @@ impl RaftStateMachine<VRaftTypeConfig> for Arc<VfsStateMachine> {
 
     async fn install_snapshot(
         &mut self,
-        meta: &SnapshotMeta<VRaftTypeConfig>,
+        meta: &SnapshotMeta<RaftNodeId, VRaftNode>,
         snapshot: Box<Cursor<Vec<u8>>>,
     ) -> Result<(), StorageError<RaftNodeId>> {
         // Deserialize snapshot
         let data = snapshot.into_inner();
-        let vfs_snapshot: VfsSnapshot = serde_json::from_slice(&data).map_err(|e| {
+
+        // Keep the received snapshot so it can be served to other nodes
+        if let Some(store) = &self.snapshot_store {
+            store.save(meta, &data).await.map_err(|e| {
+                StorageError::from_io_error(
+                    openraft::ErrorSubject::Snapshot(Some(meta.signature())),
+                    openraft::ErrorVerb::Write,
+                    e,
+                )
+            })?;
+        }
+
+        let vfs_snapshot = decode_snapshot(&data).map_err(|e| {
             StorageError::from_io_error(
                 openraft::ErrorSubject::StateMachine,
                 openraft::ErrorVerb::Read,
@@ -199,28 +312,21 @@ impl RaftStateMachine<VRaftTypeConfig> for Arc<VfsStateMachine> {
             )
         })?;
 
-        // Update state
-        *self.last_applied_log.write().await = vfs_snapshot.last_applied_log;
-        *self.membership.write().await = vfs_snapshot.membership;
-
-        // Restore VFS state
-        // First, we need to recreate the VFS with the snapshot data
-        // For now, we'll just apply all the files
-        for file in vfs_snapshot.vfs_state.files {
-            self.vfs.apply(VfsCommand::CreateFile {
-                path: file.path.clone(),
-                content: file.content.as_str().map(|s| s.to_string()).unwrap_or_default(),
-            });
-        }
-
+        self.restore(vfs_snapshot).await;
         Ok(())
     }
 
     async fn get_current_snapshot(
         &mut self,
     ) -> Result<Option<Snapshot<VRaftTypeConfig>>, StorageError<RaftNodeId>> {
+        if let Some(store) = &self.snapshot_store {
+            return store.latest().map_err(|e| {
+                StorageError::from_io_error(openraft::ErrorSubject::Snapshot(None), openraft::ErrorVerb::Read, e)
+            });
+        }
+
         // Build a snapshot of current state
-        let mut builder = self.clone();
+        let mut builder = self.get_snapshot_builder().await;
         let snapshot = builder.build_snapshot().await?;
         Ok(Some(snapshot))
     }
@@ -229,10 +335,79 @@ impl RaftStateMachine<VRaftTypeConfig> for Arc<VfsStateMachine> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use vraftls_core::{FileVersion, VfsConfig};
 
     #[test]
     fn test_create_state_machine() {
         let sm = VfsStateMachine::new(RaftGroupId::new(1));
         assert_eq!(sm.vfs.file_count(), 0);
     }
+
+    fn create(vfs: &Vfs, path: &str, content: &str) -> FileId {
+        match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new(path),
+            content: content.to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            other => panic!("expected Created, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_install_snapshot_over_existing_files() {
+        let config = VfsConfig {
+            enable_trash: true,
+            history_versions: 2,
+            ..VfsConfig::default()
+        };
+        let leader: VfsHandle = Arc::new(Vfs::new(RaftGroupId::new(1)).with_config(&config));
+        let kept = create(&leader, "/a.rs", "a");
+        let shared = create(&leader, "/b.rs", "b");
+        let trashed = create(&leader, "/old.rs", "old");
+        leader.apply(VfsCommand::DeleteFile { file_id: trashed });
+        leader.apply(VfsCommand::UpdateFile {
+            file_id: kept,
+            content: "a2".to_string(),
+            expected_version: None,
+        });
+
+        let data = VfsSnapshotBuilder::new(
+            leader.clone(),
+            None,
+            StoredMembership::default(),
+            BTreeMap::new(),
+            BTreeMap::new(),
+            SnapshotBuildConfig::default(),
+        )
+        .write_snapshot()
+        .await
+        .unwrap();
+
+        // A lagging follower with a stale copy of one path and a file the
+        // leader no longer has, under different IDs
+        let follower_vfs = Arc::new(Vfs::new(RaftGroupId::new(1)).with_config(&config));
+        let mut follower = Arc::new(VfsStateMachine::with_vfs(RaftGroupId::new(1), follower_vfs));
+        create(follower.vfs(), "/b.rs", "stale");
+        create(follower.vfs(), "/x.rs", "gone");
+
+        let meta = SnapshotMeta {
+            last_log_id: None,
+            last_membership: StoredMembership::default(),
+            snapshot_id: "test".to_string(),
+        };
+        follower.install_snapshot(&meta, Box::new(Cursor::new(data))).await.unwrap();
+
+        let vfs = follower.vfs();
+        assert_eq!(vfs.file_count(), 2);
+        let (restored, original) = (vfs.get_file(kept).unwrap(), leader.get_file(kept).unwrap());
+        assert_eq!((restored.path, restored.version), (original.path, original.version));
+        assert_eq!(vfs.get_content(kept).unwrap(), "a2");
+        let first = vfs.get_file_at_version(kept, FileVersion::initial()).unwrap();
+        assert_eq!(first.content_string().unwrap(), "a");
+        assert_eq!(vfs.file_history(kept).len(), leader.file_history(kept).len());
+        assert_eq!(vfs.get_content(shared).unwrap(), "b");
+        assert!(vfs.get_file_by_path(&VfsPath::new("/x.rs")).is_none());
+        assert_eq!(vfs.trashed_files()[0].file.id, trashed);
+        assert_eq!(vfs.next_file_id(), leader.next_file_id());
+    }
 }
@@ -3,21 +3,30 @@
 //! OpenRaft requires two storage traits:
 //! - RaftLogStorage: for storing log entries
 //! - RaftStateMachine: for applying committed entries (see state_machine.rs)
+//!
+//! RocksDB calls never run on the async runtime; they are executed on an
+//! `IoPool` so a compaction stall cannot freeze Raft timers.
 
-use crate::types::{RaftNodeId, VRaftNode, VRaftTypeConfig, VfsRequest};
+use crate::archive::{ArchiveObject, Archiver};
+use crate::io_pool::IoPool;
+use crate::log_dump::LogDumpRecord;
+use crate::types::{RaftNodeId, VRaftTypeConfig};
 use openraft::storage::{LogFlushed, LogState, RaftLogStorage};
 use openraft::{
-    Entry, EntryPayload, LogId, OptionalSend, RaftLogReader, SnapshotMeta, StorageError,
-    StoredMembership, Vote,
+    Entry, ErrorSubject, ErrorVerb, LogId, OptionalSend, RaftLogReader, StorageError, Vote,
 };
-use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, Options, DB};
-use serde::{Deserialize, Serialize};
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch, DB};
+use serde::de::DeserializeOwned;
 use std::collections::BTreeMap;
 use std::fmt::Debug;
+use std::io::Write;
+use std::ops::Bound;
 use std::ops::RangeBounds;
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use thiserror::Error;
+use tokio::sync::{Mutex, RwLock};
 
 /// Column family names
 const CF_LOGS: &str = "logs";
@@ -27,12 +36,52 @@ const CF_META: &str = "meta";
 const KEY_VOTE: &[u8] = b"vote";
 const KEY_COMMITTED: &[u8] = b"committed";
 const KEY_LAST_PURGED: &[u8] = b"last_purged";
+const KEY_ARCHIVED: &[u8] = b"archived";
+
+/// Wrap any storage-layer error into an OpenRaft `StorageError`
+fn storage_error(
+    subject: ErrorSubject<RaftNodeId>,
+    verb: ErrorVerb,
+    e: impl std::error::Error + Send + Sync + 'static,
+) -> StorageError<RaftNodeId> {
+    StorageError::from_io_error(subject, verb, std::io::Error::other(e))
+}
+
+/// Inconsistency found by a startup scrub of the log
+#[derive(Error, Debug)]
+pub enum LogRecoveryError {
+    #[error("failed to read raft log: {0}")]
+    Read(String),
+
+    #[error("log entry at key {key} cannot be decoded: {reason}")]
+    Undecodable { key: u64, reason: String },
+
+    #[error("log entry at key {key} has index {index}")]
+    KeyMismatch { key: u64, index: u64 },
+
+    #[error("log index {index} follows {prev}; indices must increase by one")]
+    NonMonotonicIndex { prev: u64, index: u64 },
+
+    #[error("log index {index} has term {term}, lower than term {prev_term} before it")]
+    TermRegression { index: u64, term: u64, prev_term: u64 },
+
+    #[error("log entry {index} (term {term}) conflicts with last purged log {purged_index} (term {purged_term})")]
+    PurgedMismatch {
+        index: u64,
+        term: u64,
+        purged_index: u64,
+        purged_term: u64,
+    },
+}
 
 /// RocksDB-backed log storage
 pub struct RocksDbLogStorage {
     /// RocksDB instance
     db: Arc<DB>,
 
+    /// Threads that run RocksDB calls
+    io: Arc<IoPool>,
+
     /// In-memory cache for recent logs (for performance)
     log_cache: RwLock<BTreeMap<u64, Entry<VRaftTypeConfig>>>,
 
@@ -44,11 +93,29 @@ pub struct RocksDbLogStorage {
 
     /// Last purged log id
     last_purged: RwLock<Option<LogId<RaftNodeId>>>,
+
+    /// Serialized size of the entries on disk
+    log_bytes: AtomicU64,
+
+    /// Archive for purged log segments
+    archiver: Option<Archiver>,
+
+    /// Last purged log id whose entries are confirmed archived; purged
+    /// entries after it stay on disk until their upload succeeds
+    archived: RwLock<Option<LogId<RaftNodeId>>>,
+
+    /// Held while a purged segment is being uploaded
+    archive_slot: Arc<Mutex<()>>,
 }
 
 impl RocksDbLogStorage {
-    /// Create a new RocksDB-backed log storage
+    /// Create a new RocksDB-backed log storage with its own I/O threads
     pub fn new(data_dir: impl AsRef<Path>) -> Result<Self, StorageError<RaftNodeId>> {
+        Self::with_io_pool(data_dir, Arc::new(IoPool::default()))
+    }
+
+    /// Create a new RocksDB-backed log storage using a shared I/O pool
+    pub fn with_io_pool(data_dir: impl AsRef<Path>, io: Arc<IoPool>) -> Result<Self, StorageError<RaftNodeId>> {
         let path = data_dir.as_ref().join("raft-log");
 
         let mut opts = Options::default();
@@ -62,73 +129,162 @@ impl RocksDbLogStorage {
         ];
 
         let db = DB::open_cf_descriptors(&opts, &path, cf_descriptors)
-            .map_err(|e| StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Read, e.into()))?;
+            .map_err(|e| storage_error(ErrorSubject::Store, ErrorVerb::Read, e))?;
+
+        // Load metadata from disk
+        let vote = Self::load_meta(&db, KEY_VOTE, ErrorSubject::Vote)?;
+        let committed = Self::load_meta(&db, KEY_COMMITTED, ErrorSubject::Store)?;
+        let last_purged = Self::load_meta(&db, KEY_LAST_PURGED, ErrorSubject::Store)?;
+        let archived = Self::load_meta(&db, KEY_ARCHIVED, ErrorSubject::Store)?;
+        let log_bytes = Self::range_bytes(&db, 0, u64::MAX)?;
 
-        let storage = Self {
+        Ok(Self {
             db: Arc::new(db),
+            io,
             log_cache: RwLock::new(BTreeMap::new()),
-            vote: RwLock::new(None),
-            committed: RwLock::new(None),
-            last_purged: RwLock::new(None),
-        };
+            vote: RwLock::new(vote),
+            committed: RwLock::new(committed),
+            last_purged: RwLock::new(last_purged),
+            log_bytes: AtomicU64::new(log_bytes),
+            archiver: None,
+            archived: RwLock::new(archived),
+            archive_slot: Arc::new(Mutex::new(())),
+        })
+    }
 
-        // Load metadata from disk
-        storage.load_metadata()?;
-
-        Ok(storage)
-    }
-
-    /// Load metadata from RocksDB
-    fn load_metadata(&self) -> Result<(), StorageError<RaftNodeId>> {
-        let cf = self.db.cf_handle(CF_META).ok_or_else(|| {
-            StorageError::from_io_error(
-                openraft::ErrorSubject::Store,
-                openraft::ErrorVerb::Read,
-                std::io::Error::new(std::io::ErrorKind::NotFound, "meta cf not found"),
-            )
-        })?;
-
-        // Load vote
-        if let Some(data) = self.db.get_cf(cf, KEY_VOTE).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Vote, openraft::ErrorVerb::Read, e.into())
-        })? {
-            let vote: Vote<RaftNodeId> = serde_json::from_slice(&data).map_err(|e| {
-                StorageError::from_io_error(openraft::ErrorSubject::Vote, openraft::ErrorVerb::Read, e.into())
-            })?;
-            *self.vote.blocking_write() = Some(vote);
-        }
+    /// Ship log segments to an archive before they are purged
+    pub fn with_archiver(mut self, archiver: Archiver) -> Self {
+        self.archiver = Some(archiver);
+        self
+    }
 
-        // Load committed
-        if let Some(data) = self.db.get_cf(cf, KEY_COMMITTED).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Read, e.into())
-        })? {
-            let committed: LogId<RaftNodeId> = serde_json::from_slice(&data).map_err(|e| {
-                StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Read, e.into())
-            })?;
-            *self.committed.blocking_write() = Some(committed);
-        }
+    /// Serialized size of the log entries currently stored
+    pub fn log_bytes(&self) -> u64 {
+        self.log_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Last committed log id persisted by Raft
+    pub async fn committed(&self) -> Option<LogId<RaftNodeId>> {
+        *self.committed.read().await
+    }
+
+    /// Verify the log column family before the node starts serving
+    ///
+    /// Checks that every entry decodes, that keys match indices, that indices
+    /// increase by one with non-decreasing terms, and that the log continues
+    /// directly after the last purged entry. Purged entries still waiting to
+    /// be archived are skipped. Returns the number of entries checked.
+    pub async fn scrub(&self) -> Result<u64, LogRecoveryError> {
+        let last_purged = *self.last_purged.read().await;
+        let db = self.db.clone();
+
+        let checked = self
+            .io
+            .run(move || Self::scrub_logs(&db, last_purged))
+            .await
+            .map_err(|e| LogRecoveryError::Read(e.to_string()))??;
+
+        tracing::info!(entries = checked, "raft log scrub passed");
+        Ok(checked)
+    }
+
+    fn scrub_logs(db: &DB, last_purged: Option<LogId<RaftNodeId>>) -> Result<u64, LogRecoveryError> {
+        let mut prev = last_purged;
+        let mut checked = 0;
+
+        for item in db.iterator_cf(cf_logs(db), IteratorMode::Start) {
+            let (key, value) = item.map_err(|e| LogRecoveryError::Read(e.to_string()))?;
+            let key = <[u8; 8]>::try_from(&*key)
+                .map(u64::from_be_bytes)
+                .map_err(|_| LogRecoveryError::Read(format!("malformed log key {:?}", key)))?;
+            if last_purged.is_some_and(|purged| key <= purged.index) {
+                continue;
+            }
+
+            let entry: Entry<VRaftTypeConfig> = serde_json::from_slice(&value)
+                .map_err(|e| LogRecoveryError::Undecodable {
+                    key,
+                    reason: e.to_string(),
+                })?;
+            let (index, term) = (entry.log_id.index, entry.log_id.leader_id.term);
+
+            if index != key {
+                return Err(LogRecoveryError::KeyMismatch { key, index });
+            }
 
-        // Load last_purged
-        if let Some(data) = self.db.get_cf(cf, KEY_LAST_PURGED).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Read, e.into())
-        })? {
-            let last_purged: LogId<RaftNodeId> = serde_json::from_slice(&data).map_err(|e| {
-                StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Read, e.into())
-            })?;
-            *self.last_purged.blocking_write() = Some(last_purged);
+            match prev {
+                // The first entry must directly follow the purged prefix
+                Some(purged) if checked == 0 && (index != purged.index + 1 || term < purged.leader_id.term) => {
+                    return Err(LogRecoveryError::PurgedMismatch {
+                        index,
+                        term,
+                        purged_index: purged.index,
+                        purged_term: purged.leader_id.term,
+                    });
+                }
+                Some(prev) if index != prev.index + 1 => {
+                    return Err(LogRecoveryError::NonMonotonicIndex { prev: prev.index, index });
+                }
+                Some(prev) if term < prev.leader_id.term => {
+                    return Err(LogRecoveryError::TermRegression {
+                        index,
+                        term,
+                        prev_term: prev.leader_id.term,
+                    });
+                }
+                _ => {}
+            }
+
+            prev = Some(entry.log_id);
+            checked += 1;
         }
 
-        Ok(())
+        Ok(checked)
     }
 
-    /// Get the logs column family
-    fn cf_logs(&self) -> &ColumnFamily {
-        self.db.cf_handle(CF_LOGS).expect("logs cf must exist")
+    /// Run a RocksDB operation on the I/O pool
+    async fn with_db<R, F>(&self, subject: ErrorSubject<RaftNodeId>, verb: ErrorVerb, f: F) -> Result<R, StorageError<RaftNodeId>>
+    where
+        F: FnOnce(&DB) -> Result<R, StorageError<RaftNodeId>> + Send + 'static,
+        R: Send + 'static,
+    {
+        let db = self.db.clone();
+        self.io
+            .run(move || f(&db))
+            .await
+            .map_err(|e| storage_error(subject, verb, e))?
     }
 
-    /// Get the meta column family
-    fn cf_meta(&self) -> &ColumnFamily {
-        self.db.cf_handle(CF_META).expect("meta cf must exist")
+    /// Load a metadata value from RocksDB
+    fn load_meta<T: DeserializeOwned>(
+        db: &DB,
+        key: &[u8],
+        subject: ErrorSubject<RaftNodeId>,
+    ) -> Result<Option<T>, StorageError<RaftNodeId>> {
+        let data = db
+            .get_cf(cf_meta(db), key)
+            .map_err(|e| storage_error(subject.clone(), ErrorVerb::Read, e))?;
+
+        data.map(|data| serde_json::from_slice(&data))
+            .transpose()
+            .map_err(|e| storage_error(subject, ErrorVerb::Read, e))
+    }
+
+    /// Save a metadata value to RocksDB
+    async fn save_meta<T: serde::Serialize>(
+        &self,
+        key: &'static [u8],
+        value: &T,
+        subject: ErrorSubject<RaftNodeId>,
+    ) -> Result<(), StorageError<RaftNodeId>> {
+        let data = serde_json::to_vec(value)
+            .map_err(|e| storage_error(subject.clone(), ErrorVerb::Write, e))?;
+
+        self.with_db(subject.clone(), ErrorVerb::Write, move |db| {
+            db.put_cf(cf_meta(db), key, data)
+                .map_err(|e| storage_error(subject, ErrorVerb::Write, e))
+        })
+        .await
     }
 
     /// Convert log index to RocksDB key
@@ -136,92 +292,261 @@ impl RocksDbLogStorage {
         index.to_be_bytes()
     }
 
-    /// Save an entry to RocksDB
-    fn save_entry(&self, entry: &Entry<VRaftTypeConfig>) -> Result<(), StorageError<RaftNodeId>> {
-        let key = Self::log_key(entry.log_id.index);
-        let value = serde_json::to_vec(entry).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Logs, openraft::ErrorVerb::Write, e.into())
-        })?;
+    /// Save entries to RocksDB in one write batch
+    async fn save_entries(&self, entries: Vec<Entry<VRaftTypeConfig>>) -> Result<(), StorageError<RaftNodeId>> {
+        let written = self
+            .with_db(ErrorSubject::Logs, ErrorVerb::Write, move |db| {
+                let mut batch = WriteBatch::default();
+                let mut written = 0;
+                for entry in &entries {
+                    let value = serde_json::to_vec(entry)
+                        .map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Write, e))?;
+                    written += value.len() as u64;
+                    batch.put_cf(cf_logs(db), Self::log_key(entry.log_id.index), value);
+                }
 
-        self.db.put_cf(self.cf_logs(), key, value).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Logs, openraft::ErrorVerb::Write, e.into())
-        })?;
+                db.write(batch)
+                    .map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Write, e))?;
+                Ok(written)
+            })
+            .await?;
 
+        self.log_bytes.fetch_add(written, Ordering::Relaxed);
         Ok(())
     }
 
-    /// Load an entry from RocksDB
-    fn load_entry(&self, index: u64) -> Result<Option<Entry<VRaftTypeConfig>>, StorageError<RaftNodeId>> {
-        let key = Self::log_key(index);
+    /// Total serialized size of the entries in `[from, to)`
+    fn range_bytes(db: &DB, from: u64, to: u64) -> Result<u64, StorageError<RaftNodeId>> {
+        let start = Self::log_key(from);
+        let end = Self::log_key(to);
+        let mut bytes = 0;
 
-        match self.db.get_cf(self.cf_logs(), key) {
-            Ok(Some(data)) => {
-                let entry: Entry<VRaftTypeConfig> = serde_json::from_slice(&data).map_err(|e| {
-                    StorageError::from_io_error(openraft::ErrorSubject::Logs, openraft::ErrorVerb::Read, e.into())
-                })?;
-                Ok(Some(entry))
+        for item in db.iterator_cf(cf_logs(db), IteratorMode::From(&start, Direction::Forward)) {
+            let (key, value) = item.map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e))?;
+            if *key >= end[..] {
+                break;
+            }
+            bytes += value.len() as u64;
+        }
+
+        Ok(bytes)
+    }
+
+    /// Load consecutive entries in `[from, to)` from RocksDB, stopping at a gap
+    fn load_range(db: &DB, from: u64, to: u64) -> Result<Vec<Entry<VRaftTypeConfig>>, StorageError<RaftNodeId>> {
+        let start = Self::log_key(from);
+        let mut entries = Vec::new();
+
+        for item in db.iterator_cf(cf_logs(db), IteratorMode::From(&start, Direction::Forward)) {
+            let (_, value) = item.map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e))?;
+            let entry: Entry<VRaftTypeConfig> = serde_json::from_slice(&value)
+                .map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e))?;
+
+            if entry.log_id.index >= to || entry.log_id.index != from + entries.len() as u64 {
+                break;
             }
-            Ok(None) => Ok(None),
-            Err(e) => Err(StorageError::from_io_error(
-                openraft::ErrorSubject::Logs,
-                openraft::ErrorVerb::Read,
-                e.into(),
-            )),
+            entries.push(entry);
+        }
+
+        Ok(entries)
+    }
+
+    /// Load the last entry stored in RocksDB
+    fn load_last_entry(db: &DB) -> Result<Option<Entry<VRaftTypeConfig>>, StorageError<RaftNodeId>> {
+        let mut iter = db.raw_iterator_cf(cf_logs(db));
+        iter.seek_to_last();
+
+        match iter.value() {
+            Some(value) => serde_json::from_slice(value)
+                .map(Some)
+                .map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e)),
+            None => Ok(None),
         }
     }
 
-    /// Delete entries up to (exclusive) the given index
-    fn delete_entries_before(&self, before_index: u64) -> Result<(), StorageError<RaftNodeId>> {
-        let cf = self.cf_logs();
-        let start_key = Self::log_key(0);
-        let end_key = Self::log_key(before_index);
+    /// Export entries in `[from, to]` as JSON lines
+    ///
+    /// Reads straight from RocksDB, so it also covers entries no longer cached.
+    pub async fn dump_logs(&self, from: u64, to: u64) -> Result<Vec<u8>, StorageError<RaftNodeId>> {
+        self.with_db(ErrorSubject::Logs, ErrorVerb::Read, move |db| {
+            let start = Self::log_key(from);
+            let mut out = Vec::new();
+
+            for item in db.iterator_cf(cf_logs(db), IteratorMode::From(&start, Direction::Forward)) {
+                let (_, value) = item.map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e))?;
+                let entry: Entry<VRaftTypeConfig> = serde_json::from_slice(&value)
+                    .map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e))?;
+                if entry.log_id.index > to {
+                    break;
+                }
+
+                serde_json::to_writer(&mut out, &LogDumpRecord::from(&entry))
+                    .map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e))?;
+                out.write_all(b"\n")
+                    .map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e))?;
+            }
+
+            Ok(out)
+        })
+        .await
+    }
+
+    /// Read the raw entries in `[from, to)` as JSON lines
+    ///
+    /// Returns the index of the first stored entry of the range along with
+    /// the lines, or `None` when nothing of the range is stored.
+    async fn read_segment(&self, from: u64, to: u64) -> Result<Option<(u64, Vec<u8>)>, StorageError<RaftNodeId>> {
+        self.with_db(ErrorSubject::Logs, ErrorVerb::Read, move |db| {
+            let start = Self::log_key(from);
+            let end = Self::log_key(to);
+            let mut first = None;
+            let mut out = Vec::new();
+
+            for item in db.iterator_cf(cf_logs(db), IteratorMode::From(&start, Direction::Forward)) {
+                let (key, value) = item.map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Read, e))?;
+                if *key >= end[..] {
+                    break;
+                }
+                if first.is_none() {
+                    let index = <[u8; 8]>::try_from(&*key).map(u64::from_be_bytes).map_err(|_| {
+                        let e = std::io::Error::other(format!("malformed log key {:?}", key));
+                        storage_error(ErrorSubject::Logs, ErrorVerb::Read, e)
+                    })?;
+                    first = Some(index);
+                }
+                out.extend_from_slice(&value);
+                out.push(b'\n');
+            }
+
+            Ok(first.map(|first| (first, out)))
+        })
+        .await
+    }
+
+    /// Offer the purged entries not yet archived to the archiver
+    ///
+    /// The entries are deleted once their upload is confirmed. A segment whose
+    /// upload fails, or that finds the archive queue full, stays on disk and
+    /// is offered again on the next purge, so a store outage neither loses
+    /// log data nor blocks Raft.
+    async fn archive_purged(self: &Arc<Self>, archiver: &Archiver) -> Result<(), StorageError<RaftNodeId>> {
+        let Some(last) = *self.last_purged.read().await else {
+            return Ok(());
+        };
+        // One upload at a time; a purge during it is picked up by the next one
+        let Ok(slot) = self.archive_slot.clone().try_lock_owned() else {
+            return Ok(());
+        };
+
+        let from = self.archived.read().await.map_or(0, |a| a.index + 1);
+        if from > last.index {
+            return Ok(());
+        }
+
+        // Name the segment after the entries it holds, not the range asked for
+        let Some((first, segment)) = self.read_segment(from, last.index + 1).await? else {
+            // Nothing of this range was stored locally (e.g. it was covered by
+            // an installed snapshot)
+            return self.mark_archived(last).await;
+        };
+
+        let Some(outcome) = archiver.try_submit(ArchiveObject::log_segment(first, last.index, segment)) else {
+            tracing::debug!(first, last = last.index, "archive queue full; keeping purged entries");
+            return Ok(());
+        };
+
+        let storage = self.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            match outcome.await {
+                Ok(Ok(())) => {
+                    if let Err(e) = storage.mark_archived(last).await {
+                        tracing::warn!(error = %e, "failed to delete archived log entries");
+                    }
+                }
+                _ => {
+                    tracing::warn!(first, last = last.index, "log segment not archived; keeping it for the next purge");
+                }
+            }
+        });
+
+        Ok(())
+    }
 
-        self.db.delete_range_cf(cf, start_key, end_key).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Logs, openraft::ErrorVerb::Write, e.into())
-        })?;
+    /// Record that purged entries up to `log_id` are archived and delete them
+    async fn mark_archived(&self, log_id: LogId<RaftNodeId>) -> Result<(), StorageError<RaftNodeId>> {
+        self.save_meta(KEY_ARCHIVED, &log_id, ErrorSubject::Store).await?;
+        *self.archived.write().await = Some(log_id);
+        self.delete_entries(0, log_id.index + 1).await
+    }
 
+    /// Delete entries in `[from, to)`
+    async fn delete_entries(&self, from: u64, to: u64) -> Result<(), StorageError<RaftNodeId>> {
+        let removed = self
+            .with_db(ErrorSubject::Logs, ErrorVerb::Write, move |db| {
+                let removed = Self::range_bytes(db, from, to)?;
+                db.delete_range_cf(cf_logs(db), Self::log_key(from), Self::log_key(to))
+                    .map_err(|e| storage_error(ErrorSubject::Logs, ErrorVerb::Write, e))?;
+                Ok(removed)
+            })
+            .await?;
+
+        let _ = self
+            .log_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |b| Some(b.saturating_sub(removed)));
         Ok(())
     }
 }
 
+/// Get the logs column family
+fn cf_logs(db: &DB) -> &ColumnFamily {
+    db.cf_handle(CF_LOGS).expect("logs cf must exist")
+}
+
+/// Get the meta column family
+fn cf_meta(db: &DB) -> &ColumnFamily {
+    db.cf_handle(CF_META).expect("meta cf must exist")
+}
+
 impl RaftLogReader<VRaftTypeConfig> for Arc<RocksDbLogStorage> {
     async fn try_get_log_entries<RB: RangeBounds<u64> + Clone + Debug + OptionalSend>(
         &mut self,
         range: RB,
     ) -> Result<Vec<Entry<VRaftTypeConfig>>, StorageError<RaftNodeId>> {
         let start = match range.start_bound() {
-            std::ops::Bound::Included(&n) => n,
-            std::ops::Bound::Excluded(&n) => n + 1,
-            std::ops::Bound::Unbounded => 0,
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
         };
 
         let end = match range.end_bound() {
-            std::ops::Bound::Included(&n) => n + 1,
-            std::ops::Bound::Excluded(&n) => n,
-            std::ops::Bound::Unbounded => u64::MAX,
+            Bound::Included(&n) => n + 1,
+            Bound::Excluded(&n) => n,
+            Bound::Unbounded => u64::MAX,
         };
 
-        // First check cache
-        let cache = self.log_cache.read().await;
+        // First check cache, falling back to disk from the first miss
         let mut entries = Vec::new();
-
-        for idx in start..end {
-            if let Some(entry) = cache.get(&idx) {
-                entries.push(entry.clone());
-            } else {
-                // Load from disk
-                drop(cache);
-                if let Some(entry) = self.load_entry(idx)? {
-                    entries.push(entry);
-                } else {
-                    break;
+        {
+            let cache = self.log_cache.read().await;
+            for idx in start..end {
+                match cache.get(&idx) {
+                    Some(entry) => entries.push(entry.clone()),
+                    None => break,
                 }
-                // Re-acquire cache
-                let cache = self.log_cache.read().await;
-                continue;
             }
         }
 
+        let next = start + entries.len() as u64;
+        if next < end {
+            let rest = self
+                .with_db(ErrorSubject::Logs, ErrorVerb::Read, move |db| {
+                    RocksDbLogStorage::load_range(db, next, end)
+                })
+                .await?;
+            entries.extend(rest);
+        }
+
         Ok(entries)
     }
 }
@@ -230,29 +555,20 @@ impl RaftLogStorage<VRaftTypeConfig> for Arc<RocksDbLogStorage> {
     type LogReader = Self;
 
     async fn get_log_state(&mut self) -> Result<LogState<VRaftTypeConfig>, StorageError<RaftNodeId>> {
-        let last_purged = self.last_purged.read().await.clone();
+        let last_purged = *self.last_purged.read().await;
 
         // Find the last log entry
-        let cache = self.log_cache.read().await;
-        let last_log_id = if let Some((&idx, entry)) = cache.iter().next_back() {
-            Some(entry.log_id)
-        } else {
+        let cached = self.log_cache.read().await.values().next_back().map(|e| e.log_id);
+        let last_log_id = match cached {
+            Some(log_id) => Some(log_id),
             // Check RocksDB for the last entry
-            let cf = self.cf_logs();
-            let mut iter = self.db.raw_iterator_cf(cf);
-            iter.seek_to_last();
-            if iter.valid() {
-                if let Some(value) = iter.value() {
-                    let entry: Entry<VRaftTypeConfig> = serde_json::from_slice(value).map_err(|e| {
-                        StorageError::from_io_error(openraft::ErrorSubject::Logs, openraft::ErrorVerb::Read, e.into())
-                    })?;
-                    Some(entry.log_id)
-                } else {
-                    last_purged
-                }
-            } else {
-                last_purged
-            }
+            None => self
+                .with_db(ErrorSubject::Logs, ErrorVerb::Read, |db| {
+                    RocksDbLogStorage::load_last_entry(db)
+                })
+                .await?
+                .map(|e| e.log_id)
+                .or(last_purged),
         };
 
         Ok(LogState {
@@ -265,32 +581,23 @@ impl RaftLogStorage<VRaftTypeConfig> for Arc<RocksDbLogStorage> {
         *self.committed.write().await = committed;
 
         if let Some(ref c) = committed {
-            let data = serde_json::to_vec(c).map_err(|e| {
-                StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Write, e.into())
-            })?;
-            self.db.put_cf(self.cf_meta(), KEY_COMMITTED, data).map_err(|e| {
-                StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Write, e.into())
-            })?;
+            self.save_meta(KEY_COMMITTED, c, ErrorSubject::Store).await?;
         }
 
         Ok(())
     }
 
     async fn read_committed(&mut self) -> Result<Option<LogId<RaftNodeId>>, StorageError<RaftNodeId>> {
-        Ok(self.committed.read().await.clone())
+        Ok(*self.committed.read().await)
     }
 
     async fn save_vote(&mut self, vote: &Vote<RaftNodeId>) -> Result<(), StorageError<RaftNodeId>> {
         *self.vote.write().await = Some(*vote);
+        self.save_meta(KEY_VOTE, vote, ErrorSubject::Vote).await
+    }
 
-        let data = serde_json::to_vec(vote).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Vote, openraft::ErrorVerb::Write, e.into())
-        })?;
-        self.db.put_cf(self.cf_meta(), KEY_VOTE, data).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Vote, openraft::ErrorVerb::Write, e.into())
-        })?;
-
-        Ok(())
+    async fn read_vote(&mut self) -> Result<Option<Vote<RaftNodeId>>, StorageError<RaftNodeId>> {
+        Ok(*self.vote.read().await)
     }
 
     async fn append<I>(&mut self, entries: I, callback: LogFlushed<VRaftTypeConfig>) -> Result<(), StorageError<RaftNodeId>>
@@ -298,13 +605,14 @@ impl RaftLogStorage<VRaftTypeConfig> for Arc<RocksDbLogStorage> {
         I: IntoIterator<Item = Entry<VRaftTypeConfig>> + OptionalSend,
         I::IntoIter: OptionalSend,
     {
+        let entries: Vec<_> = entries.into_iter().collect();
         let mut cache = self.log_cache.write().await;
 
-        for entry in entries {
-            // Save to RocksDB
-            self.save_entry(&entry)?;
+        // Save to RocksDB
+        self.save_entries(entries.clone()).await?;
 
-            // Update cache
+        // Update cache
+        for entry in entries {
             cache.insert(entry.log_id.index, entry);
         }
 
@@ -321,36 +629,26 @@ impl RaftLogStorage<VRaftTypeConfig> for Arc<RocksDbLogStorage> {
         cache.split_off(&log_id.index);
 
         // Remove from RocksDB
-        let cf = self.cf_logs();
-        let start_key = Self::log_key(log_id.index);
-        let end_key = Self::log_key(u64::MAX);
-
-        self.db.delete_range_cf(cf, start_key, end_key).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Logs, openraft::ErrorVerb::Write, e.into())
-        })?;
-
-        Ok(())
+        self.delete_entries(log_id.index, u64::MAX).await
     }
 
     async fn purge(&mut self, log_id: LogId<RaftNodeId>) -> Result<(), StorageError<RaftNodeId>> {
         *self.last_purged.write().await = Some(log_id);
 
         // Save last_purged to RocksDB
-        let data = serde_json::to_vec(&log_id).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Write, e.into())
-        })?;
-        self.db.put_cf(self.cf_meta(), KEY_LAST_PURGED, data).map_err(|e| {
-            StorageError::from_io_error(openraft::ErrorSubject::Store, openraft::ErrorVerb::Write, e.into())
-        })?;
+        self.save_meta(KEY_LAST_PURGED, &log_id, ErrorSubject::Store).await?;
 
         // Remove from cache
-        let mut cache = self.log_cache.write().await;
-        cache = cache.split_off(&(log_id.index + 1));
-
-        // Remove from RocksDB
-        self.delete_entries_before(log_id.index + 1)?;
+        {
+            let mut cache = self.log_cache.write().await;
+            *cache = cache.split_off(&(log_id.index + 1));
+        }
 
-        Ok(())
+        // Remove from RocksDB, once archived when there is an archive
+        match self.archiver.clone() {
+            Some(archiver) => self.archive_purged(&archiver).await,
+            None => self.delete_entries(0, log_id.index + 1).await,
+        }
     }
 
     async fn get_log_reader(&mut self) -> Self::LogReader {
@@ -369,4 +667,129 @@ mod tests {
         let storage = RocksDbLogStorage::new(temp_dir.path()).unwrap();
         assert!(storage.vote.read().await.is_none());
     }
+
+    #[tokio::test]
+    async fn test_scrub_detects_gaps() {
+        use openraft::{CommittedLeaderId, EntryPayload};
+
+        let entry = |term, index| Entry::<VRaftTypeConfig> {
+            log_id: LogId::new(CommittedLeaderId::new(term, 1), index),
+            payload: EntryPayload::Blank,
+        };
+
+        let temp_dir = TempDir::new().unwrap();
+        let storage = RocksDbLogStorage::new(temp_dir.path()).unwrap();
+        storage
+            .save_entries(vec![entry(1, 1), entry(1, 2), entry(2, 3)])
+            .await
+            .unwrap();
+        assert_eq!(storage.scrub().await.unwrap(), 3);
+
+        storage.save_entries(vec![entry(2, 5)]).await.unwrap();
+        assert!(matches!(
+            storage.scrub().await,
+            Err(LogRecoveryError::NonMonotonicIndex { prev: 3, index: 5 })
+        ));
+
+        // The first entry after the purged prefix must not go back in term
+        *storage.last_purged.write().await = Some(LogId::new(CommittedLeaderId::new(3, 1), 1));
+        assert!(matches!(
+            storage.scrub().await,
+            Err(LogRecoveryError::PurgedMismatch { index: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_purge_keeps_entries_until_archived() {
+        use crate::archive::{LocalObjectStore, ObjectStore};
+        use openraft::{CommittedLeaderId, EntryPayload};
+        use std::time::Duration;
+        use vraftls_core::ArchiveConfig;
+
+        struct FailingStore;
+
+        impl ObjectStore for FailingStore {
+            async fn put(&self, _key: &str, _data: Vec<u8>) -> std::io::Result<()> {
+                Err(std::io::Error::other("store unavailable"))
+            }
+        }
+
+        let entries = || {
+            (1..=3)
+                .map(|index| Entry::<VRaftTypeConfig> {
+                    log_id: LogId::new(CommittedLeaderId::new(1, 1), index),
+                    payload: EntryPayload::Blank,
+                })
+                .collect::<Vec<_>>()
+        };
+        let purged = LogId::new(CommittedLeaderId::new(1, 1), 2);
+        let mut config = ArchiveConfig::new("unused", "unused");
+        config.max_attempts = 1;
+
+        // A failed upload leaves the purged entries on disk
+        let temp_dir = TempDir::new().unwrap();
+        let (archiver, _task) = Archiver::spawn(FailingStore, &config);
+        let mut storage = Arc::new(RocksDbLogStorage::new(temp_dir.path()).unwrap().with_archiver(archiver));
+        storage.save_entries(entries()).await.unwrap();
+        storage.purge(purged).await.unwrap();
+        drop(storage.archive_slot.lock().await);
+        assert!(storage.read_segment(1, 3).await.unwrap().is_some());
+        assert_eq!(storage.scrub().await.unwrap(), 1);
+
+        // A confirmed upload deletes them
+        let temp_dir = TempDir::new().unwrap();
+        let archive_dir = TempDir::new().unwrap();
+        let (archiver, _task) = Archiver::spawn(LocalObjectStore::new(archive_dir.path()), &config);
+        let mut storage = Arc::new(RocksDbLogStorage::new(temp_dir.path()).unwrap().with_archiver(archiver));
+        storage.save_entries(entries()).await.unwrap();
+        storage.purge(purged).await.unwrap();
+        for _ in 0..100 {
+            if storage.read_segment(0, 3).await.unwrap().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(storage.read_segment(0, 3).await.unwrap().is_none());
+        assert_eq!(*storage.archived.read().await, Some(purged));
+        assert!(archive_dir
+            .path()
+            .join("logs/00000000000000000001-00000000000000000002.jsonl")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn test_dump_logs_range() {
+        use crate::types::VfsRequest;
+        use openraft::{CommittedLeaderId, EntryPayload};
+        use vraftls_core::RaftGroupId;
+        use vraftls_vfs::{VfsCommand, VfsPath};
+
+        let temp_dir = TempDir::new().unwrap();
+        let storage = RocksDbLogStorage::new(temp_dir.path()).unwrap();
+        let entries = (1..=5)
+            .map(|index| Entry {
+                log_id: LogId::new(CommittedLeaderId::new(1, 1), index),
+                payload: EntryPayload::Normal(VfsRequest::new(
+                    RaftGroupId::new(1),
+                    VfsCommand::CreateFile {
+                        path: VfsPath::new(format!("/f{}.rs", index)),
+                        content: String::new(),
+                    },
+                )),
+            })
+            .collect();
+        storage.save_entries(entries).await.unwrap();
+
+        let out = storage.dump_logs(2, 4).await.unwrap();
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["index"], 2);
+        assert_eq!(lines[2]["type"], "normal");
+        assert_eq!(lines[2]["request"]["Command"]["CreateFile"]["path"]["original"], "/f4.rs");
+    }
 }
@@ -0,0 +1,136 @@
+//! W3C trace-context propagation
+//!
+//! A `traceparent` received with a client write is kept in a task-local
+//! while the write is handled, recorded on the proposed log entry, and sent
+//! again with the AppendEntries requests that replicate that entry, so one
+//! write can be followed gateway → leader → followers.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// HTTP header carrying the trace context
+pub const TRACEPARENT: &str = "traceparent";
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// Parsed `traceparent` value (version 00)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// Trace ID shared by every span of the trace
+    pub trace_id: u128,
+
+    /// ID of the span that sent this context
+    pub parent_id: u64,
+
+    /// Trace flags (bit 0: sampled)
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Start a new sampled trace
+    pub fn new_root() -> Self {
+        let trace_id = ((random_u64() as u128) << 64) | random_u64() as u128;
+        Self {
+            trace_id,
+            parent_id: random_u64(),
+            flags: 1,
+        }
+    }
+
+    /// Context for a new span within the same trace
+    pub fn child(&self) -> Self {
+        Self {
+            parent_id: random_u64(),
+            ..*self
+        }
+    }
+
+    /// Parse a `traceparent` header value
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let (version, trace_id, parent_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || parts.next().is_some() || trace_id.len() != 32 || parent_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            parent_id: u64::from_str_radix(parent_id, 16).ok()?,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        };
+        // All-zero IDs are invalid
+        (context.trace_id != 0 && context.parent_id != 0).then_some(context)
+    }
+
+    /// Trace ID as 32 hex digits
+    pub fn trace_id_hex(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    /// Context of the current task, if any
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|c| *c).ok()
+    }
+
+    /// Run `f` with this context as the current one
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{:032x}-{:016x}-{:02x}", self.trace_id, self.parent_id, self.flags)
+    }
+}
+
+impl Serialize for TraceContext {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TraceContext {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::parse(&value).ok_or_else(|| serde::de::Error::custom(format!("invalid traceparent: {}", value)))
+    }
+}
+
+/// Non-cryptographic random ID, never zero
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish().max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_parse_and_scope() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(value).unwrap();
+        assert_eq!(context.to_string(), value);
+        assert_eq!(context.trace_id_hex(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_ne!(child.parent_id, context.parent_id);
+
+        assert!(TraceContext::current().is_none());
+        let current = context.scope(async { TraceContext::current() }).await;
+        assert_eq!(current, Some(context));
+    }
+}
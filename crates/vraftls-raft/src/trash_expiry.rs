@@ -0,0 +1,47 @@
+//! Expiry of trashed files
+//!
+//! Each replica records when it deleted a file, so replicas could disagree
+//! on when a trashed file expires. Instead, the leader checks its own trash
+//! and proposes purging the expired files by ID, and every replica purges
+//! exactly those.
+
+use crate::proposal::VfsProposer;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use vraftls_vfs::{VfsCommand, VfsHandle, VfsResponse};
+
+/// How often the leader looks for expired files
+pub const TRASH_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Purge files kept in the trash of `vfs` for longer than `retention`,
+/// while this replica leads the group
+pub fn spawn_trash_expiry(proposer: VfsProposer, vfs: VfsHandle, retention: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut metrics_rx = proposer.raft().metrics();
+        loop {
+            tokio::time::sleep(TRASH_EXPIRY_INTERVAL).await;
+
+            // Stop once the Raft instance has shut down
+            if metrics_rx.has_changed().is_err() {
+                break;
+            }
+            let is_leader = {
+                let metrics = metrics_rx.borrow_and_update();
+                metrics.current_leader == Some(metrics.id)
+            };
+            if !is_leader {
+                continue;
+            }
+
+            let expired = vfs.expired_trash(retention);
+            if expired.is_empty() {
+                continue;
+            }
+            match proposer.propose(VfsCommand::PurgeTrash { file_ids: Some(expired) }).await {
+                Ok(VfsResponse::Deleted(purged)) => tracing::info!(purged = purged.len(), "purged expired trash"),
+                Ok(response) => tracing::warn!(?response, "unexpected response to trash purge"),
+                Err(e) => tracing::warn!(error = %e, "trash purge failed"),
+            }
+        }
+    })
+}
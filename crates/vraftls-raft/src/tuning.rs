@@ -0,0 +1,120 @@
+//! Runtime tuning of Raft timing
+//!
+//! OpenRaft reads its `Config` once when a Raft instance is created. The
+//! tuner validates timing updates and publishes the resulting `RaftConfig`
+//! on a watch channel; the host rebuilds the group's Raft instance from the
+//! same storage whenever the timing changes.
+
+use crate::openraft_config;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::watch;
+use vraftls_core::{RaftConfig, Result, VRaftError};
+
+/// Timing settings that may be changed at runtime
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TimingUpdate {
+    /// New heartbeat interval in milliseconds
+    #[serde(default)]
+    pub heartbeat_interval_ms: Option<u64>,
+
+    /// New minimum election timeout in milliseconds
+    #[serde(default)]
+    pub election_timeout_min_ms: Option<u64>,
+
+    /// New maximum election timeout in milliseconds
+    #[serde(default)]
+    pub election_timeout_max_ms: Option<u64>,
+}
+
+impl TimingUpdate {
+    /// Apply the update to a copy of `config`
+    pub fn apply_to(&self, config: &RaftConfig) -> RaftConfig {
+        let mut config = config.clone();
+        if let Some(ms) = self.heartbeat_interval_ms {
+            config.heartbeat_interval = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.election_timeout_min_ms {
+            config.election_timeout_min = Duration::from_millis(ms);
+        }
+        if let Some(ms) = self.election_timeout_max_ms {
+            config.election_timeout_max = Duration::from_millis(ms);
+        }
+        config
+    }
+}
+
+/// Check whether two configurations differ in runtime-tunable timing
+pub fn timing_changed(a: &RaftConfig, b: &RaftConfig) -> bool {
+    a.heartbeat_interval != b.heartbeat_interval
+        || a.election_timeout_min != b.election_timeout_min
+        || a.election_timeout_max != b.election_timeout_max
+}
+
+/// Holds the desired Raft configuration of a group
+pub struct RaftTuner {
+    config: watch::Sender<RaftConfig>,
+}
+
+impl RaftTuner {
+    pub fn new(config: RaftConfig) -> Self {
+        let (config, _) = watch::channel(config);
+        Self { config }
+    }
+
+    /// Current desired configuration
+    pub fn current(&self) -> RaftConfig {
+        self.config.borrow().clone()
+    }
+
+    /// Watch for configuration changes
+    pub fn subscribe(&self) -> watch::Receiver<RaftConfig> {
+        self.config.subscribe()
+    }
+
+    /// Validate and publish a timing update
+    pub fn update(&self, update: &TimingUpdate) -> Result<RaftConfig> {
+        let config = update.apply_to(&self.current());
+        openraft_config("validate", &config)
+            .map_err(|e| VRaftError::InvalidConfig(e.to_string()))?;
+
+        tracing::info!(
+            heartbeat_ms = config.heartbeat_interval.as_millis() as u64,
+            election_min_ms = config.election_timeout_min.as_millis() as u64,
+            election_max_ms = config.election_timeout_max.as_millis() as u64,
+            "raft timing updated"
+        );
+
+        self.config.send_replace(config.clone());
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_is_validated() {
+        let tuner = RaftTuner::new(RaftConfig::default());
+        let mut rx = tuner.subscribe();
+
+        let invalid = TimingUpdate {
+            election_timeout_min_ms: Some(1000),
+            election_timeout_max_ms: Some(500),
+            ..Default::default()
+        };
+        assert!(tuner.update(&invalid).is_err());
+        assert!(!rx.has_changed().unwrap());
+
+        let valid = TimingUpdate {
+            heartbeat_interval_ms: Some(200),
+            election_timeout_min_ms: Some(1000),
+            election_timeout_max_ms: Some(2000),
+        };
+        let config = tuner.update(&valid).unwrap();
+        assert!(rx.has_changed().unwrap());
+        assert!(timing_changed(&config, &RaftConfig::default()));
+        assert_eq!(rx.borrow_and_update().heartbeat_interval, Duration::from_millis(200));
+    }
+}
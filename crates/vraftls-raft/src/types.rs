@@ -1,11 +1,14 @@
 //! Raft type definitions for OpenRaft integration
 
-use openraft::BasicNode;
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
-use vraftls_core::{NodeId, RaftGroupId};
+use vraftls_core::RaftGroupId;
 use vraftls_vfs::{VfsCommand, VfsResponse};
 
+use crate::chunking::CommandChunk;
+use crate::session::RequestSession;
+use crate::trace_context::TraceContext;
+
 /// OpenRaft の NodeId 型（u64 を使用）
 pub type RaftNodeId = u64;
 
@@ -14,6 +17,25 @@ pub type RaftNodeId = u64;
 pub struct VRaftNode {
     /// ノードのHTTPアドレス
     pub addr: String,
+    /// リーダー選出の優先度（大きいほど優先、0 は優先なし）
+    #[serde(default)]
+    pub priority: u8,
+}
+
+impl VRaftNode {
+    /// アドレスからノード情報を作成
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            priority: 0,
+        }
+    }
+
+    /// リーダー選出の優先度を設定
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
 }
 
 impl std::fmt::Display for VRaftNode {
@@ -25,10 +47,16 @@ impl std::fmt::Display for VRaftNode {
 /// VRaftLS の型設定
 ///
 /// OpenRaft は generic なので、ここでアプリケーション固有の型を指定する
-#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VRaftTypeConfig;
 
 impl openraft::RaftTypeConfig for VRaftTypeConfig {
+    /// アプリケーションのリクエスト型
+    type D = VfsRequest;
+
+    /// アプリケーションのレスポンス型
+    type R = VfsStateMachineResponse;
+
     /// ノードID型
     type NodeId = RaftNodeId;
 
@@ -53,8 +81,49 @@ impl openraft::RaftTypeConfig for VRaftTypeConfig {
 pub struct VfsRequest {
     /// Raft グループ ID
     pub group_id: RaftGroupId,
+    /// リクエストの中身
+    pub payload: VfsRequestPayload,
+    /// 重複排除用のクライアントセッション
+    #[serde(default)]
+    pub session: Option<RequestSession>,
+    /// 分散トレース用のトレースコンテキスト
+    #[serde(default)]
+    pub trace: Option<TraceContext>,
+}
+
+impl VfsRequest {
+    /// 単一コマンドのリクエストを作成
+    pub fn new(group_id: RaftGroupId, command: VfsCommand) -> Self {
+        Self {
+            group_id,
+            payload: VfsRequestPayload::Command(command),
+            session: None,
+            trace: None,
+        }
+    }
+
+    /// クライアントセッションを付与
+    pub fn with_session(mut self, session: RequestSession) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// トレースコンテキストを付与
+    pub fn with_trace(mut self, trace: TraceContext) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+}
+
+/// ログエントリのペイロード
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum VfsRequestPayload {
     /// VFS コマンド
-    pub command: VfsCommand,
+    Command(VfsCommand),
+    /// 大きすぎるコマンドを分割した断片（状態マシンで再構成される）
+    Chunk(CommandChunk),
+    /// メタデータグループ（グループ 0）のコマンド（メタデータ状態マシンが解釈する）
+    Metadata(serde_json::Value),
 }
 
 /// 状態マシンからのレスポンス
@@ -62,6 +131,19 @@ pub struct VfsRequest {
 pub struct VfsStateMachineResponse {
     /// VFS レスポンス
     pub response: VfsResponse,
+    /// メタデータコマンドの結果
+    #[serde(default)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl VfsStateMachineResponse {
+    /// VFS レスポンスから作成
+    pub fn new(response: VfsResponse) -> Self {
+        Self {
+            response,
+            metadata: None,
+        }
+    }
 }
 
 /// Raft グループのメンバーシップ情報
@@ -78,9 +160,5 @@ pub struct SnapshotMeta {
     /// 最後に適用されたログインデックス
     pub last_applied_log_id: Option<openraft::LogId<RaftNodeId>>,
     /// メンバーシップ設定
-    pub membership: openraft::StoredMembership<VRaftTypeConfig>,
+    pub membership: openraft::StoredMembership<RaftNodeId, VRaftNode>,
 }
-
-// OpenRaft の Entry で使うためのトレイト実装
-impl openraft::AppData for VfsRequest {}
-impl openraft::AppDataResponse for VfsStateMachineResponse {}
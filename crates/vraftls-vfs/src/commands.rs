@@ -20,6 +20,15 @@ pub enum VfsCommand {
         expected_version: Option<u64>,
     },
 
+    /// Change ranges of an existing file's text, sending only the changes
+    ApplyTextEdits {
+        file_id: FileId,
+
+        /// Applied in order, each to the text the previous one left
+        edits: Vec<VfsTextEdit>,
+        expected_version: Option<u64>,
+    },
+
     /// Delete a file
     DeleteFile {
         file_id: FileId,
@@ -31,6 +40,40 @@ pub enum VfsCommand {
         new_path: VfsPath,
     },
 
+    /// Create a directory
+    CreateDirectory {
+        path: VfsPath,
+
+        /// Create missing parents too, and accept an existing directory
+        #[serde(default)]
+        recursive: bool,
+    },
+
+    /// Delete a directory
+    DeleteDirectory {
+        path: VfsPath,
+
+        /// Delete the files and directories in it too; otherwise it must
+        /// be empty
+        #[serde(default)]
+        recursive: bool,
+    },
+
+    /// Delete every file and directory under a path, and the path itself
+    ///
+    /// Unlike `DeleteDirectory`, the path need not be a directory, and
+    /// nothing being under it is not an error. Answered with the IDs of the
+    /// deleted files.
+    DeleteTree { prefix: VfsPath },
+
+    /// Put a file back from the trash at the path it was deleted from
+    RestoreFile { file_id: FileId },
+
+    /// Drop files from the trash for good; all of them with `None`
+    ///
+    /// Answered with the IDs of the purged files.
+    PurgeTrash { file_ids: Option<Vec<FileId>> },
+
     /// Batch create/update files
     BatchWrite {
         operations: Vec<BatchWriteOp>,
@@ -40,6 +83,50 @@ pub enum VfsCommand {
     InvalidateCache {
         file_ids: Vec<FileId>,
     },
+
+    /// Apply several commands atomically (all or nothing)
+    Transaction {
+        commands: Vec<VfsCommand>,
+    },
+
+    /// Force files to the leader's copies (anti-entropy repair)
+    Repair {
+        files: Vec<FileRepair>,
+
+        /// The leader's next file ID; a lower one is raised to it so later
+        /// creates get the same IDs everywhere
+        next_file_id: u64,
+    },
+}
+
+/// One file of a repair
+///
+/// With `content` the file is created or overwritten under this ID, path and
+/// version; without, it is removed if it is still at `path`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileRepair {
+    pub file_id: FileId,
+    pub path: VfsPath,
+    pub content: Option<String>,
+    pub version: u64,
+}
+
+/// Replacement of a range of a file's text
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VfsTextEdit {
+    pub start: TextPosition,
+    pub end: TextPosition,
+    pub new_text: String,
+}
+
+/// Position in a file's text, as LSP counts it: the line, and UTF-16 code
+/// units within the line
+///
+/// Positions past the end of a line or of the file stand for its end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextPosition {
+    pub line: u32,
+    pub character: u32,
 }
 
 /// Operation in a batch write
@@ -62,6 +149,12 @@ pub enum VfsResponse {
     /// Batch operation results
     BatchResults(Vec<VfsBatchResult>),
 
+    /// Responses of a committed transaction, in command order
+    Transaction(Vec<VfsResponse>),
+
+    /// Files a tree delete removed, or a trash purge dropped
+    Deleted(Vec<FileId>),
+
     /// Error occurred
     Error(VfsCommandError),
 }
@@ -78,9 +171,14 @@ pub enum VfsBatchResult {
 pub enum VfsCommandError {
     FileNotFound(FileId),
     FileAlreadyExists(String),
+    DirectoryNotFound(String),
+    DirectoryNotEmpty(String),
     VersionMismatch { expected: u64, actual: u64 },
     InvalidPath(String),
     StorageError(String),
+
+    /// A transaction prepared on the group's leader holds the path
+    Held(String),
 }
 
 impl std::fmt::Display for VfsCommandError {
@@ -88,11 +186,14 @@ impl std::fmt::Display for VfsCommandError {
         match self {
             Self::FileNotFound(id) => write!(f, "file not found: {:?}", id),
             Self::FileAlreadyExists(path) => write!(f, "file already exists: {}", path),
+            Self::DirectoryNotFound(path) => write!(f, "directory not found: {}", path),
+            Self::DirectoryNotEmpty(path) => write!(f, "directory not empty: {}", path),
             Self::VersionMismatch { expected, actual } => {
                 write!(f, "version mismatch: expected {}, got {}", expected, actual)
             }
             Self::InvalidPath(path) => write!(f, "invalid path: {}", path),
             Self::StorageError(msg) => write!(f, "storage error: {}", msg),
+            Self::Held(path) => write!(f, "held by a transaction: {}", path),
         }
     }
 }
@@ -116,6 +217,15 @@ pub enum VfsQuery {
 
     /// Get file content
     GetContent(FileId),
+
+    /// Get a file as it was at a version, current or kept in its history
+    GetFileAtVersion { file_id: FileId, version: u64 },
+
+    /// List the previous versions kept of a file, newest first
+    GetHistory(FileId),
+
+    /// List the files in the trash
+    ListTrash,
 }
 
 /// Response from VFS query
@@ -130,6 +240,12 @@ pub enum VfsQueryResponse {
     /// File content
     Content(Option<String>),
 
+    /// Previous versions of a file
+    History(Vec<crate::history::RevisionInfo>),
+
+    /// Files in the trash
+    Trash(Vec<crate::trash::TrashedFile>),
+
     /// Error
     Error(String),
 }
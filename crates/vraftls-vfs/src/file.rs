@@ -1,14 +1,20 @@
 //! Virtual file representation
 
+use crate::commands::VfsTextEdit;
 use crate::path::VfsPath;
+use crate::rope::TextRope;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 use vraftls_core::{FileId, FileVersion, NodeId, RaftGroupId, Timestamp};
 
 /// Content storage mode
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum FileContent {
     /// Full content stored in memory
-    Loaded(String),
+    Loaded(TextRope),
+
+    /// Content compressed with zstd, with its uncompressed length
+    Compressed { data: Vec<u8>, length: u64 },
 
     /// Content stored on disk, path to file
     OnDisk(String),
@@ -31,9 +37,9 @@ impl FileContent {
     }
 
     /// Get content if loaded
-    pub fn as_str(&self) -> Option<&str> {
+    pub fn text(&self) -> Option<&TextRope> {
         match self {
-            Self::Loaded(s) => Some(s),
+            Self::Loaded(text) => Some(text),
             _ => None,
         }
     }
@@ -42,33 +48,50 @@ impl FileContent {
     pub fn len(&self) -> Option<usize> {
         match self {
             Self::Loaded(s) => Some(s.len()),
+            Self::Compressed { length, .. } => Some(*length as usize),
             Self::Remote { length, .. } => Some(*length as usize),
             _ => None,
         }
     }
 
+    /// Memory the content takes, if it is held in memory
+    pub fn resident_size(&self) -> Option<usize> {
+        match self {
+            Self::Loaded(s) => Some(s.len()),
+            Self::Compressed { data, .. } => Some(data.len()),
+            _ => None,
+        }
+    }
+
     /// Check if content is empty (only valid for loaded content)
     pub fn is_empty(&self) -> bool {
         match self {
             Self::Loaded(s) => s.is_empty(),
+            Self::Compressed { length, .. } => *length == 0,
             _ => false,
         }
     }
 }
 
 /// Checksum for file content verification
+///
+/// 64-bit FNV-1a over the UTF-8 bytes of the content. The algorithm is
+/// fixed, since replicas built by different compilers compare checksums.
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Checksum(pub u64);
 
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
 impl Checksum {
     /// Compute checksum from content
     pub fn compute(content: &str) -> Self {
-        use std::collections::hash_map::DefaultHasher;
-        use std::hash::{Hash, Hasher};
+        Self(fnv1a(FNV_OFFSET_BASIS, content.as_bytes()))
+    }
 
-        let mut hasher = DefaultHasher::new();
-        content.hash(&mut hasher);
-        Self(hasher.finish())
+    /// Compute checksum of a rope, the same as of its text as one string
+    pub fn of_text(text: &TextRope) -> Self {
+        Self(text.chunks().fold(FNV_OFFSET_BASIS, |hash, chunk| fnv1a(hash, chunk.as_bytes())))
     }
 
     /// Verify content matches this checksum
@@ -77,6 +100,13 @@ impl Checksum {
     }
 }
 
+/// Continue an FNV-1a hash over more bytes
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, byte| (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME))
+}
+
 /// A file in the virtual file system
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VfsFile {
@@ -118,7 +148,7 @@ impl VfsFile {
             id,
             path,
             version: FileVersion::initial(),
-            content: FileContent::Loaded(content),
+            content: FileContent::Loaded(content.into()),
             checksum,
             last_modified: Timestamp::now(),
             owning_group,
@@ -129,20 +159,92 @@ impl VfsFile {
     /// Update file content
     pub fn update_content(&mut self, content: String) {
         self.checksum = Checksum::compute(&content);
-        self.content = FileContent::Loaded(content);
+        self.content = FileContent::Loaded(content.into());
+        self.version = self.version.next();
+        self.last_modified = Timestamp::now();
+    }
+
+    /// Change ranges of the loaded content in place, one edit after another
+    ///
+    /// Does nothing if the content is not loaded.
+    pub fn edit_content(&mut self, edits: &[VfsTextEdit]) {
+        let FileContent::Loaded(text) = &mut self.content else {
+            return;
+        };
+        for edit in edits {
+            text.replace(edit.start, edit.end, &edit.new_text);
+        }
+        self.checksum = Checksum::of_text(text);
         self.version = self.version.next();
         self.last_modified = Timestamp::now();
     }
 
+    /// Get content if loaded
+    pub fn text(&self) -> Option<&TextRope> {
+        self.content.text()
+    }
+
     /// Get content as string if loaded
-    pub fn content_str(&self) -> Option<&str> {
-        self.content.as_str()
+    pub fn content_string(&self) -> Option<String> {
+        self.text().map(TextRope::to_string)
     }
 
     /// Check if file is loaded
     pub fn is_loaded(&self) -> bool {
         self.content.is_loaded()
     }
+
+    /// Compress loaded or spilled content; the checksum stays that of the text
+    ///
+    /// Spilled content is compressed as it is read, without loading it
+    /// into memory first.
+    pub fn compress(&mut self) -> std::io::Result<()> {
+        match &self.content {
+            FileContent::Loaded(text) => {
+                let mut encoder = zstd::Encoder::new(Vec::new(), 0)?;
+                for chunk in text.chunks() {
+                    encoder.write_all(chunk.as_bytes())?;
+                }
+                self.content = FileContent::Compressed {
+                    data: encoder.finish()?,
+                    length: text.len() as u64,
+                };
+            }
+            FileContent::OnDisk(path) => {
+                let spilled = std::fs::File::open(path)?;
+                let length = spilled.metadata()?.len();
+                self.content = FileContent::Compressed {
+                    data: zstd::encode_all(spilled, 0)?,
+                    length,
+                };
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Load compressed or spilled content back into memory
+    pub fn load(&mut self) -> std::io::Result<()> {
+        let text = match &self.content {
+            FileContent::Compressed { data, .. } => String::from_utf8(zstd::decode_all(data.as_slice())?)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+            FileContent::OnDisk(path) => std::fs::read_to_string(path)?,
+            _ => return Ok(()),
+        };
+        self.content = FileContent::Loaded(text.into());
+        Ok(())
+    }
+
+    /// The file with its content loaded
+    ///
+    /// Content that fails to load is left not loaded.
+    pub fn loaded(mut self) -> Self {
+        if let Err(e) = self.load() {
+            tracing::error!("Failed to load content of {}: {}", self.path, e);
+            self.content = FileContent::NotLoaded;
+        }
+        self
+    }
 }
 
 /// File metadata
@@ -217,3 +319,22 @@ pub enum FileChangeType {
     Deleted,
     Renamed,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_known_values() {
+        // FNV-1a 64 test vectors
+        assert_eq!(Checksum::compute("").0, 0xcbf2_9ce4_8422_2325);
+        assert_eq!(Checksum::compute("a").0, 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(Checksum::compute("foobar").0, 0x8594_4171_f739_67e8);
+
+        // A rope hashes the same as its text, whatever its chunks
+        let text = "fn main() { println!(\"héllo\"); }\n".repeat(1000);
+        let rope = TextRope::from(text.as_str());
+        assert!(rope.chunks().count() > 1);
+        assert_eq!(Checksum::of_text(&rope), Checksum::compute(&text));
+    }
+}
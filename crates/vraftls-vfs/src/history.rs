@@ -0,0 +1,158 @@
+//! Previous versions of files
+//!
+//! Before a file's content changes, its current version is kept as a
+//! revision, up to a configured number per file. Revisions are full copies of
+//! the content as it was stored, but a rope shares the chunks an edit did not
+//! touch with its copies, so the versions of a file mostly share memory.
+//! History is changed only by applied commands, so it is part of snapshots
+//! like the files themselves.
+
+use crate::file::{Checksum, FileContent, VfsFile};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use vraftls_core::{FileId, FileVersion, Timestamp};
+
+/// A previous version of a file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileRevision {
+    version: FileVersion,
+    checksum: Checksum,
+    last_modified: Timestamp,
+
+    /// Content, in memory
+    content: FileContent,
+}
+
+/// A previous version of a file, without its content
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RevisionInfo {
+    pub version: FileVersion,
+    pub checksum: Checksum,
+    pub last_modified: Timestamp,
+
+    /// Length of the content in bytes
+    pub length: u64,
+}
+
+impl FileRevision {
+    /// The file as it was at this revision
+    pub fn file(&self, current: &VfsFile) -> VfsFile {
+        VfsFile {
+            version: self.version,
+            content: self.content.clone(),
+            checksum: self.checksum,
+            last_modified: self.last_modified,
+            ..current.clone()
+        }
+        .loaded()
+    }
+
+    fn info(&self) -> RevisionInfo {
+        RevisionInfo {
+            version: self.version,
+            checksum: self.checksum,
+            last_modified: self.last_modified,
+            length: self.content.len().unwrap_or(0) as u64,
+        }
+    }
+}
+
+/// Revisions of every file, oldest first
+pub(crate) struct FileHistory {
+    /// Revisions kept per file; none with 0
+    limit: usize,
+    revisions: DashMap<FileId, VecDeque<FileRevision>>,
+}
+
+impl FileHistory {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            revisions: DashMap::new(),
+        }
+    }
+
+    /// Keep the current version of a file about to change
+    ///
+    /// Spilled content is loaded, since its spill file is removed once the
+    /// file changes.
+    pub fn record(&self, file: &VfsFile) {
+        if self.limit == 0 {
+            return;
+        }
+        let content = match &file.content {
+            FileContent::OnDisk(_) => file.clone().loaded().content,
+            content => content.clone(),
+        };
+        let mut revisions = self.revisions.entry(file.id).or_default();
+        revisions.push_back(FileRevision {
+            version: file.version,
+            checksum: file.checksum,
+            last_modified: file.last_modified,
+            content,
+        });
+        while revisions.len() > self.limit {
+            revisions.pop_front();
+        }
+    }
+
+    /// A file's revision at a version
+    pub fn at(&self, file_id: FileId, version: FileVersion) -> Option<FileRevision> {
+        self.revisions
+            .get(&file_id)?
+            .iter()
+            .find(|revision| revision.version == version)
+            .cloned()
+    }
+
+    /// A file's revisions, newest first
+    pub fn list(&self, file_id: FileId) -> Vec<RevisionInfo> {
+        match self.revisions.get(&file_id) {
+            Some(revisions) => revisions.iter().rev().map(FileRevision::info).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// A file's revisions, oldest first
+    pub fn revisions(&self, file_id: FileId) -> Vec<FileRevision> {
+        match self.revisions.get(&file_id) {
+            Some(revisions) => revisions.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Replace a file's revisions with ones taken by [`Self::revisions`]
+    pub fn reset(&self, file_id: FileId, revisions: Vec<FileRevision>) {
+        if revisions.is_empty() {
+            self.revisions.remove(&file_id);
+        } else {
+            self.revisions.insert(file_id, revisions.into());
+        }
+    }
+
+    /// Drop the revisions of a deleted file
+    pub fn forget(&self, file_id: FileId) {
+        self.revisions.remove(&file_id);
+    }
+
+    /// Every file's revisions, oldest first
+    pub fn entries(&self) -> HashMap<FileId, Vec<FileRevision>> {
+        self.revisions
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().iter().cloned().collect()))
+            .collect()
+    }
+
+    /// Replace all revisions, as they were in a snapshot
+    pub fn restore(&self, revisions: HashMap<FileId, Vec<FileRevision>>) {
+        self.revisions.clear();
+        if self.limit == 0 {
+            return;
+        }
+        for (file_id, revisions) in revisions {
+            let skip = revisions.len().saturating_sub(self.limit);
+            self.revisions.insert(file_id, revisions.into_iter().skip(skip).collect());
+        }
+    }
+}
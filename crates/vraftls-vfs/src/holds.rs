@@ -0,0 +1,290 @@
+//! Files held by prepared transactions
+//!
+//! A transaction spanning Raft groups is prepared on each group's leader
+//! before any group commits. Preparing holds the files and paths the group's
+//! part touches: until the coordinator commits or aborts, other writes to
+//! them are refused, so the versions the part was prepared against are still
+//! current at commit time, and a committed part can still be undone.
+//!
+//! Holds are kept by the leader only, not replicated. A leader change drops
+//! them, and the new leader refuses to commit a part it never prepared, which
+//! aborts the transaction. Holds also expire, so a coordinator that goes
+//! away does not block the files for good.
+
+use crate::commands::{BatchWriteOp, VfsCommand, VfsCommandError};
+use crate::path::VfsPath;
+use crate::vfs::Vfs;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use vraftls_core::{FileId, TransactionId};
+
+/// How long a prepared transaction may hold files before it is aborted
+pub const HOLD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Files and paths a write touches
+#[derive(Debug, Default)]
+struct Touched {
+    files: HashSet<FileId>,
+
+    /// Paths written, as normalized components
+    paths: HashSet<Vec<String>>,
+
+    /// Paths everything below which is written
+    prefixes: Vec<Vec<String>>,
+}
+
+impl Touched {
+    fn of(command: &VfsCommand) -> Self {
+        let mut touched = Self::default();
+        touched.add(command);
+        touched
+    }
+
+    fn add(&mut self, command: &VfsCommand) {
+        let path = |path: &VfsPath| path.components().to_vec();
+        match command {
+            VfsCommand::CreateFile { path: p, .. }
+            | VfsCommand::CreateDirectory { path: p, .. } => {
+                self.paths.insert(path(p));
+            }
+            VfsCommand::UpdateFile { file_id, .. }
+            | VfsCommand::ApplyTextEdits { file_id, .. }
+            | VfsCommand::DeleteFile { file_id }
+            | VfsCommand::RestoreFile { file_id } => {
+                self.files.insert(*file_id);
+            }
+            VfsCommand::RenameFile { file_id, new_path } => {
+                self.files.insert(*file_id);
+                self.paths.insert(path(new_path));
+            }
+            VfsCommand::DeleteDirectory { path: p, .. } | VfsCommand::DeleteTree { prefix: p } => {
+                self.prefixes.push(path(p));
+            }
+            VfsCommand::BatchWrite { operations } => {
+                for operation in operations {
+                    match operation {
+                        BatchWriteOp::Create { path: p, .. } => {
+                            self.paths.insert(path(p));
+                        }
+                        BatchWriteOp::Update { file_id, .. } | BatchWriteOp::Delete { file_id } => {
+                            self.files.insert(*file_id);
+                        }
+                    }
+                }
+            }
+            VfsCommand::Transaction { commands } => commands.iter().for_each(|c| self.add(c)),
+            // Repairs force followers to the leader's own files; the others
+            // leave files alone
+            VfsCommand::Repair { .. }
+            | VfsCommand::InvalidateCache { .. }
+            | VfsCommand::PurgeTrash { .. } => {}
+        }
+    }
+
+    /// A path both write to, described for the error
+    fn conflict(&self, other: &Touched) -> Option<String> {
+        if let Some(file_id) = self.files.intersection(&other.files).next() {
+            return Some(format!("file {}", file_id));
+        }
+        let covers = |prefixes: &[Vec<String>], paths: &HashSet<Vec<String>>| {
+            paths
+                .iter()
+                .find(|path| prefixes.iter().any(|prefix| path.starts_with(prefix)))
+                .cloned()
+        };
+        self.paths
+            .intersection(&other.paths)
+            .next()
+            .cloned()
+            .or_else(|| covers(&self.prefixes, &other.paths))
+            .or_else(|| covers(&other.prefixes, &self.paths))
+            .map(|components| format!("/{}", components.join("/")))
+    }
+}
+
+/// A prepared transaction's hold
+#[derive(Debug)]
+struct Hold {
+    touched: Touched,
+    expires_at: Instant,
+}
+
+/// Holds of the transactions prepared on a group's leader
+#[derive(Debug, Default)]
+pub struct WriteHolds {
+    holds: Mutex<HashMap<TransactionId, Hold>>,
+}
+
+impl WriteHolds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hold what `command` touches for a transaction, until `timeout`
+    ///
+    /// The current paths of the files it touches are held too, so deleting
+    /// a directory with a held file in it is refused. Fails if another
+    /// transaction holds any of it; a transaction holding already adds to
+    /// its hold.
+    pub fn hold(
+        &self,
+        transaction: TransactionId,
+        vfs: &Vfs,
+        command: &VfsCommand,
+        timeout: Duration,
+    ) -> Result<(), VfsCommandError> {
+        let mut touched = Touched::of(command);
+        let file_paths: Vec<_> = touched
+            .files
+            .iter()
+            .filter_map(|file_id| vfs.get_file(*file_id))
+            .map(|file| file.path.components().to_vec())
+            .collect();
+        touched.paths.extend(file_paths);
+
+        let mut holds = self.holds.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        holds.retain(|_, hold| hold.expires_at > now);
+        for (other, hold) in holds.iter() {
+            if *other == transaction {
+                continue;
+            }
+            if let Some(path) = hold.touched.conflict(&touched) {
+                return Err(VfsCommandError::Held(path));
+            }
+        }
+
+        let hold = holds.entry(transaction).or_insert_with(|| Hold {
+            touched: Touched::default(),
+            expires_at: now,
+        });
+        hold.touched.files.extend(touched.files);
+        hold.touched.paths.extend(touched.paths);
+        hold.touched.prefixes.extend(touched.prefixes);
+        hold.expires_at = now + timeout;
+        Ok(())
+    }
+
+    /// Refuse a write touching what a transaction holds, other than `owner`
+    pub fn check(
+        &self,
+        command: &VfsCommand,
+        owner: Option<TransactionId>,
+    ) -> Result<(), VfsCommandError> {
+        let holds = self.holds.lock().unwrap_or_else(|e| e.into_inner());
+        if holds.is_empty() {
+            return Ok(());
+        }
+
+        let touched = Touched::of(command);
+        let now = Instant::now();
+        for (transaction, hold) in holds.iter() {
+            if Some(*transaction) == owner || hold.expires_at <= now {
+                continue;
+            }
+            if let Some(path) = hold.touched.conflict(&touched) {
+                return Err(VfsCommandError::Held(path));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a transaction still holds its files
+    pub fn is_held(&self, transaction: TransactionId) -> bool {
+        let holds = self.holds.lock().unwrap_or_else(|e| e.into_inner());
+        holds
+            .get(&transaction)
+            .is_some_and(|hold| hold.expires_at > Instant::now())
+    }
+
+    /// Let go of a transaction's files
+    pub fn release(&self, transaction: TransactionId) {
+        self.holds
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&transaction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::VfsResponse;
+    use vraftls_core::RaftGroupId;
+
+    fn create(vfs: &Vfs, path: &str) -> FileId {
+        let path = VfsPath::new(path);
+        if let Some(parent) = path.parent() {
+            vfs.apply(VfsCommand::CreateDirectory {
+                path: parent,
+                recursive: true,
+            });
+        }
+        match vfs.apply(VfsCommand::CreateFile {
+            path,
+            content: String::new(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        }
+    }
+
+    fn update(file_id: FileId) -> VfsCommand {
+        VfsCommand::UpdateFile {
+            file_id,
+            content: "changed".to_string(),
+            expected_version: None,
+        }
+    }
+
+    #[test]
+    fn test_hold_refuses_other_writes() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        let held = create(&vfs, "/src/a.rs");
+        let free = create(&vfs, "/lib/b.rs");
+        let holds = WriteHolds::new();
+        let transaction = TransactionId::new(1);
+        holds
+            .hold(transaction, &vfs, &update(held), HOLD_TIMEOUT)
+            .unwrap();
+
+        assert!(matches!(
+            holds.check(&update(held), None),
+            Err(VfsCommandError::Held(_))
+        ));
+        assert!(holds.check(&update(held), Some(transaction)).is_ok());
+        assert!(holds.check(&update(free), None).is_ok());
+
+        // The held file's path is covered by a tree delete, even with a
+        // non-canonical prefix
+        let delete = VfsCommand::DeleteTree {
+            prefix: VfsPath::new("/src//"),
+        };
+        assert!(holds.check(&delete, None).is_err());
+        assert!(holds
+            .hold(TransactionId::new(2), &vfs, &delete, HOLD_TIMEOUT)
+            .is_err());
+
+        holds.release(transaction);
+        assert!(!holds.is_held(transaction));
+        assert!(holds.check(&delete, None).is_ok());
+    }
+
+    #[test]
+    fn test_hold_expires() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        let file_id = create(&vfs, "/a.rs");
+        let holds = WriteHolds::new();
+        let transaction = TransactionId::new(1);
+        holds
+            .hold(transaction, &vfs, &update(file_id), Duration::ZERO)
+            .unwrap();
+
+        assert!(!holds.is_held(transaction));
+        assert!(holds.check(&update(file_id), None).is_ok());
+        assert!(holds
+            .hold(TransactionId::new(2), &vfs, &update(file_id), HOLD_TIMEOUT)
+            .is_ok());
+    }
+}
@@ -2,10 +2,24 @@
 
 pub mod commands;
 pub mod file;
+pub mod history;
+pub mod holds;
 pub mod path;
+pub mod rope;
+pub mod search;
+pub mod spill;
+pub mod trash;
 pub mod vfs;
+pub mod view;
 
 pub use commands::*;
 pub use file::*;
+pub use history::*;
+pub use holds::*;
 pub use path::*;
+pub use rope::*;
+pub use search::*;
+pub use spill::*;
+pub use trash::*;
 pub use vfs::*;
+pub use view::*;
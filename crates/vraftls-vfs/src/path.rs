@@ -95,16 +95,17 @@ impl VfsPath {
             .map(|(_, ext)| ext)
     }
 
-    /// Get the parent path
+    /// Get the parent path; `None` for the root and the entries right below it
     pub fn parent(&self) -> Option<VfsPath> {
         if self.components.len() <= 1 {
             return None;
         }
         let parent_components = &self.components[..self.components.len() - 1];
+        let root = if self.original.starts_with('/') { "/" } else { "" };
         Some(VfsPath {
             client_id: self.client_id,
             components: parent_components.to_vec(),
-            original: parent_components.join("/"),
+            original: format!("{}{}", root, parent_components.join("/")),
         })
     }
 
@@ -112,7 +113,7 @@ impl VfsPath {
     pub fn join(&self, other: impl AsRef<str>) -> VfsPath {
         let other_path = Self::new(other);
         let mut new_components = self.components.clone();
-        new_components.extend(other_path.components.into_iter());
+        new_components.extend(other_path.components);
 
         VfsPath {
             client_id: self.client_id,
@@ -213,6 +214,13 @@ mod tests {
         assert_eq!(path.language_id(), Some(LanguageId::TypeScript));
     }
 
+    #[test]
+    fn test_parent() {
+        let path = VfsPath::new("/project/src/main.rs");
+        assert_eq!(path.parent(), Some(VfsPath::new("/project/src")));
+        assert_eq!(VfsPath::new("/main.rs").parent(), None);
+    }
+
     #[test]
     fn test_join() {
         let base = VfsPath::new("/project");
@@ -0,0 +1,158 @@
+//! Rope-backed file text
+//!
+//! File content is kept as a rope, so changing a range of a large file only
+//! touches the chunks around it instead of copying the whole text, and lines
+//! are found by index rather than by scanning from the start. Only `\n`
+//! breaks lines, as in LSP positions. A rope serializes as its plain text,
+//! so snapshots and stored files read the same as before.
+
+use crate::commands::TextPosition;
+use ropey::Rope;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// Text of a file
+#[derive(Clone, Default, PartialEq)]
+pub struct TextRope(Rope);
+
+impl TextRope {
+    /// Length in bytes
+    pub fn len(&self) -> usize {
+        self.0.len_bytes()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.len_bytes() == 0
+    }
+
+    /// Number of lines; text ending in a line break has an empty last line
+    pub fn line_count(&self) -> usize {
+        self.0.len_lines()
+    }
+
+    /// A line, without its line break
+    pub fn line(&self, line: usize) -> Option<String> {
+        if line >= self.0.len_lines() {
+            return None;
+        }
+        Some(without_break(self.0.line(line).to_string()))
+    }
+
+    /// The lines in order, without their line breaks
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        self.0.lines().map(|line| without_break(line.to_string()))
+    }
+
+    /// Character index of a position
+    ///
+    /// Positions past the end of a line or of the text stand for its end.
+    pub fn char_at(&self, position: TextPosition) -> usize {
+        let line = position.line as usize;
+        if line >= self.0.len_lines() {
+            return self.0.len_chars();
+        }
+        let start = self.0.line_to_char(line);
+        let mut end = start + self.0.line(line).len_chars();
+        if end > start && self.0.char(end - 1) == '\n' {
+            end -= 1;
+        }
+
+        let units = self.0.char_to_utf16_cu(start) + position.character as usize;
+        let units = units.min(self.0.char_to_utf16_cu(end));
+        self.0.utf16_cu_to_char(units)
+    }
+
+    /// Replace the text between two positions
+    pub fn replace(&mut self, start: TextPosition, end: TextPosition, text: &str) {
+        let start = self.char_at(start);
+        let end = self.char_at(end).max(start);
+        self.0.remove(start..end);
+        self.0.insert(start, text);
+    }
+
+    /// The text in pieces, in order
+    pub fn chunks(&self) -> impl Iterator<Item = &str> {
+        self.0.chunks()
+    }
+}
+
+fn without_break(mut line: String) -> String {
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    line
+}
+
+impl From<&str> for TextRope {
+    fn from(text: &str) -> Self {
+        Self(Rope::from_str(text))
+    }
+}
+
+impl From<String> for TextRope {
+    fn from(text: String) -> Self {
+        Self(Rope::from(text))
+    }
+}
+
+impl PartialEq<str> for TextRope {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl fmt::Display for TextRope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.chunks().try_for_each(|chunk| f.write_str(chunk))
+    }
+}
+
+impl fmt::Debug for TextRope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.to_string(), f)
+    }
+}
+
+impl Serialize for TextRope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TextRope {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(line: u32, character: u32) -> TextPosition {
+        TextPosition { line, character }
+    }
+
+    #[test]
+    fn test_replace_by_position() {
+        let mut text = TextRope::from("fn main() {\n    let s = \"é😀\";\n}");
+
+        // UTF-16 units: the emoji counts two
+        text.replace(position(1, 16), position(1, 16), "!");
+        assert_eq!(text.line(1).as_deref(), Some("    let s = \"é😀!\";"));
+
+        // Past the end of the line, and of the text
+        text.replace(position(0, 40), position(0, 41), " ");
+        text.replace(position(5, 0), position(9, 9), "\n");
+        assert_eq!(text.to_string(), "fn main() { \n    let s = \"é😀!\";\n}\n");
+        assert_eq!(text.line_count(), 4);
+    }
+
+    #[test]
+    fn test_serializes_as_text() {
+        let text = TextRope::from("a\nb");
+        let json = serde_json::to_string(&text).unwrap();
+        assert_eq!(json, "\"a\\nb\"");
+        assert_eq!(serde_json::from_str::<TextRope>(&json).unwrap(), text);
+    }
+}
@@ -0,0 +1,480 @@
+//! Full-text search over the VFS
+//!
+//! A trigram index maps every three-byte sequence in a file's text to the
+//! files that contain it. ASCII letters are lowercased, so the same index
+//! serves case-insensitive searches. A search first keeps only the files
+//! that contain every trigram the pattern needs. It then matches the pattern
+//! line by line in those files. The index follows the VFS through its change
+//! events.
+
+use crate::file::{FileChangeEvent, FileChangeType};
+use crate::path::VfsPath;
+use crate::rope::TextRope;
+use crate::vfs::VfsHandle;
+use regex::{Regex, RegexBuilder};
+use regex_syntax::hir::{Hir, HirKind};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock, Weak};
+use tokio::sync::broadcast::{self, error::RecvError};
+use vraftls_core::{FileId, Result, VRaftError};
+
+/// How a search pattern is read
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum SearchMode {
+    /// The pattern is plain text
+    #[default]
+    Literal,
+
+    /// The pattern is a regular expression
+    Regex,
+}
+
+/// A content search
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SearchQuery {
+    pub pattern: String,
+    pub mode: SearchMode,
+    pub case_sensitive: bool,
+
+    /// Only search files under this directory
+    pub under: Option<VfsPath>,
+
+    /// Most matches to return; 0 for no limit
+    pub max_matches: usize,
+}
+
+impl SearchQuery {
+    /// Case-sensitive search for plain text
+    pub fn literal(pattern: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            mode: SearchMode::Literal,
+            case_sensitive: true,
+            under: None,
+            max_matches: 0,
+        }
+    }
+
+    /// Case-sensitive search for a regular expression
+    pub fn regex(pattern: impl Into<String>) -> Self {
+        Self {
+            mode: SearchMode::Regex,
+            ..Self::literal(pattern)
+        }
+    }
+
+    pub fn with_case_sensitive(mut self, case_sensitive: bool) -> Self {
+        self.case_sensitive = case_sensitive;
+        self
+    }
+
+    pub fn with_under(mut self, directory: VfsPath) -> Self {
+        self.under = Some(directory);
+        self
+    }
+
+    pub fn with_max_matches(mut self, max_matches: usize) -> Self {
+        self.max_matches = max_matches;
+        self
+    }
+}
+
+/// A match within a line
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SearchMatch {
+    /// Zero-based line of the match
+    pub line: u32,
+
+    /// Start of the match in UTF-16 units, as in LSP positions
+    pub column: u32,
+
+    /// Length of the match in UTF-16 units
+    pub length: u32,
+
+    /// The line, without its line break
+    pub line_text: String,
+}
+
+/// Matches in one file
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileMatches {
+    pub file_id: FileId,
+    pub path: VfsPath,
+
+    /// Rank of the file; higher is better
+    pub score: f64,
+
+    /// Matches in the order they appear
+    pub matches: Vec<SearchMatch>,
+}
+
+/// Extra score for files whose name matches the pattern too
+const FILE_NAME_BONUS: f64 = 10.0;
+
+/// Trigrams and the files containing them
+#[derive(Default)]
+struct TrigramIndex {
+    files: HashMap<FileId, HashSet<u32>>,
+    postings: HashMap<u32, HashSet<FileId>>,
+}
+
+impl TrigramIndex {
+    fn insert(&mut self, file_id: FileId, trigrams: HashSet<u32>) {
+        self.remove(file_id);
+        for trigram in &trigrams {
+            self.postings.entry(*trigram).or_default().insert(file_id);
+        }
+        self.files.insert(file_id, trigrams);
+    }
+
+    fn remove(&mut self, file_id: FileId) {
+        let Some(trigrams) = self.files.remove(&file_id) else {
+            return;
+        };
+        for trigram in trigrams {
+            if let Some(files) = self.postings.get_mut(&trigram) {
+                files.remove(&file_id);
+                if files.is_empty() {
+                    self.postings.remove(&trigram);
+                }
+            }
+        }
+    }
+
+    /// Files containing all the trigrams
+    fn candidates(&self, required: &HashSet<u32>) -> Vec<FileId> {
+        let mut postings: Vec<_> = match required.iter().map(|t| self.postings.get(t)).collect::<Option<Vec<_>>>() {
+            Some(postings) => postings,
+            None => return Vec::new(),
+        };
+        postings.sort_by_key(|files| files.len());
+        match postings.split_first() {
+            Some((smallest, rest)) => smallest
+                .iter()
+                .filter(|id| rest.iter().all(|files| files.contains(id)))
+                .copied()
+                .collect(),
+            None => self.files.keys().copied().collect(),
+        }
+    }
+}
+
+/// Full-text search index of a VFS
+pub struct SearchIndex {
+    vfs: VfsHandle,
+    index: RwLock<TrigramIndex>,
+}
+
+impl SearchIndex {
+    /// Index the files of a VFS; call `update` with its change events to keep
+    /// the index current
+    pub fn new(vfs: VfsHandle) -> Self {
+        let index = Self {
+            vfs,
+            index: RwLock::new(TrigramIndex::default()),
+        };
+        index.rebuild();
+        index
+    }
+
+    /// Index the files of a VFS, and keep the index current from its change
+    /// events until the index is dropped
+    ///
+    /// Must be called within a Tokio runtime.
+    pub fn watch(vfs: VfsHandle) -> Arc<Self> {
+        let events = vfs.subscribe();
+        let index = Arc::new(Self::new(vfs));
+        tokio::spawn(follow(Arc::downgrade(&index), events));
+        index
+    }
+
+    /// Index all files afresh
+    pub fn rebuild(&self) {
+        let mut index = TrigramIndex::default();
+        for file_id in self.vfs.all_file_ids() {
+            if let Some(text) = self.vfs.get_file(file_id).as_ref().and_then(|file| file.text()) {
+                index.insert(file_id, trigrams(text));
+            }
+        }
+        *self.index.write().unwrap() = index;
+    }
+
+    /// Bring the index up to date with a change
+    pub fn update(&self, event: &FileChangeEvent) {
+        match event.change_type {
+            // Paths are read from the VFS when searching
+            FileChangeType::Renamed => {}
+            FileChangeType::Deleted => self.index.write().unwrap().remove(event.file_id),
+            FileChangeType::Created | FileChangeType::Modified => {
+                let trigrams = self.vfs.get_file(event.file_id).and_then(|file| file.text().map(trigrams));
+                let mut index = self.index.write().unwrap();
+                match trigrams {
+                    Some(trigrams) => index.insert(event.file_id, trigrams),
+                    None => index.remove(event.file_id),
+                }
+            }
+        }
+    }
+
+    /// Number of indexed files
+    pub fn file_count(&self) -> usize {
+        self.index.read().unwrap().files.len()
+    }
+
+    /// Search the content of the indexed files
+    ///
+    /// Files are ranked by their number of matches, with a bonus when their
+    /// name matches too; files that rank the same are ordered by path.
+    pub fn search(&self, query: &SearchQuery) -> Result<Vec<FileMatches>> {
+        let pattern = match query.mode {
+            SearchMode::Literal => regex::escape(&query.pattern),
+            SearchMode::Regex => query.pattern.clone(),
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(!query.case_sensitive)
+            .build()
+            .map_err(|e| VRaftError::InvalidSearchPattern(e.to_string()))?;
+
+        let required = required_trigrams(&pattern, query.case_sensitive);
+        let candidates = self.index.read().unwrap().candidates(&required);
+
+        let mut results: Vec<FileMatches> = candidates
+            .into_iter()
+            .filter_map(|file_id| {
+                let file = self.vfs.get_file(file_id)?;
+                if query.under.as_ref().is_some_and(|under| !file.path.starts_with(under)) {
+                    return None;
+                }
+                let matches = find_matches(file.text()?, &regex);
+                if matches.is_empty() {
+                    return None;
+                }
+                let mut score = matches.len() as f64;
+                if file.path.file_name().is_some_and(|name| regex.is_match(name)) {
+                    score += FILE_NAME_BONUS;
+                }
+                Some(FileMatches {
+                    file_id,
+                    path: file.path,
+                    score,
+                    matches,
+                })
+            })
+            .collect();
+
+        results.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.path.as_str().cmp(b.path.as_str())));
+        if query.max_matches > 0 {
+            truncate_matches(&mut results, query.max_matches);
+        }
+        Ok(results)
+    }
+}
+
+/// Update the index from VFS changes until it is dropped
+async fn follow(index: Weak<SearchIndex>, mut events: broadcast::Receiver<FileChangeEvent>) {
+    loop {
+        let event = events.recv().await;
+        let Some(index) = index.upgrade() else {
+            return;
+        };
+        match event {
+            Ok(event) => index.update(&event),
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("Search index missed {} VFS changes, rebuilding", skipped);
+                index.rebuild();
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Keep the first `max_matches` matches of ranked results
+fn truncate_matches(results: &mut Vec<FileMatches>, max_matches: usize) {
+    let mut remaining = max_matches;
+    results.retain_mut(|file| {
+        file.matches.truncate(remaining);
+        remaining -= file.matches.len();
+        !file.matches.is_empty()
+    });
+}
+
+/// Matches of a pattern in a text, line by line
+fn find_matches(text: &TextRope, regex: &Regex) -> Vec<SearchMatch> {
+    let mut matches = Vec::new();
+    for (line, line_text) in text.lines().enumerate() {
+        for found in regex.find_iter(&line_text) {
+            if found.is_empty() {
+                continue;
+            }
+            matches.push(SearchMatch {
+                line: line as u32,
+                column: utf16_len(&line_text[..found.start()]),
+                length: utf16_len(found.as_str()),
+                line_text: line_text.clone(),
+            });
+        }
+    }
+    matches
+}
+
+fn utf16_len(text: &str) -> u32 {
+    text.encode_utf16().count() as u32
+}
+
+/// Trigram of three bytes, with ASCII letters lowercased
+fn trigram(bytes: [u8; 3]) -> u32 {
+    let [a, b, c] = bytes.map(|b| b.to_ascii_lowercase());
+    u32::from_be_bytes([0, a, b, c])
+}
+
+/// Trigrams of a text
+fn trigrams(text: &TextRope) -> HashSet<u32> {
+    let mut trigrams = HashSet::new();
+    let mut window = [0u8; 3];
+    let mut filled = 0;
+    for byte in text.chunks().flat_map(str::bytes) {
+        window = [window[1], window[2], byte];
+        filled += 1;
+        if filled >= 3 {
+            trigrams.insert(trigram(window));
+        }
+    }
+    trigrams
+}
+
+/// Trigrams any text matching a pattern contains
+///
+/// Without case sensitivity only trigrams of ASCII bytes are kept, since
+/// only ASCII letters are lowercased in the index, and not those with `k` or
+/// `s`, which also match the Kelvin sign and the long s.
+fn required_trigrams(pattern: &str, case_sensitive: bool) -> HashSet<u32> {
+    let Ok(hir) = regex_syntax::parse(pattern) else {
+        return HashSet::new();
+    };
+    let mut literals = Vec::new();
+    let mut run = Vec::new();
+    required_literals(&hir, &mut literals, &mut run);
+    literals.push(run);
+
+    literals
+        .iter()
+        .flat_map(|literal| literal.windows(3))
+        .filter(|window| case_sensitive || window.iter().all(|b| b.is_ascii() && !b"kKsS".contains(b)))
+        .map(|window| trigram([window[0], window[1], window[2]]))
+        .collect()
+}
+
+/// Collect the literal byte strings every match of a pattern contains
+///
+/// `run` is the literal being extended; anything that may match different
+/// text ends it.
+fn required_literals(hir: &Hir, literals: &mut Vec<Vec<u8>>, run: &mut Vec<u8>) {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => {}
+        HirKind::Literal(literal) => run.extend_from_slice(&literal.0),
+        HirKind::Capture(capture) => required_literals(&capture.sub, literals, run),
+        HirKind::Concat(subs) => {
+            for sub in subs {
+                required_literals(sub, literals, run);
+            }
+        }
+        HirKind::Repetition(repetition) if repetition.min > 0 => {
+            literals.push(std::mem::take(run));
+            required_literals(&repetition.sub, literals, run);
+            literals.push(std::mem::take(run));
+        }
+        HirKind::Class(_) | HirKind::Repetition(_) | HirKind::Alternation(_) => {
+            literals.push(std::mem::take(run));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{VfsCommand, VfsResponse};
+    use crate::vfs::Vfs;
+    use vraftls_core::RaftGroupId;
+
+    fn create(vfs: &Vfs, path: &str, content: &str) -> FileId {
+        match vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new(path).parent().unwrap(),
+            recursive: true,
+        }) {
+            VfsResponse::Ok(_) => {}
+            _ => panic!("expected Ok"),
+        }
+        match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new(path),
+            content: content.to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        }
+    }
+
+    #[test]
+    fn test_search_modes() {
+        let vfs = Arc::new(Vfs::new(RaftGroupId::new(1)));
+        create(&vfs, "/src/main.rs", "fn main() {\n    let café = Parser::new();\n}\n");
+        create(&vfs, "/src/parser.rs", "pub struct Parser;\n\nimpl Parser {\n    fn new() -> Self { Parser }\n}\n");
+        create(&vfs, "/docs/notes.md", "parsing notes\n");
+        let index = SearchIndex::new(vfs.clone());
+
+        // Ranked by matches, with the file name bonus
+        let results = index.search(&SearchQuery::literal("Parser")).unwrap();
+        let paths: Vec<_> = results.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["/src/parser.rs", "/src/main.rs"]);
+        assert_eq!(results[0].matches.len(), 3);
+
+        // Columns count UTF-16 units
+        let results = index.search(&SearchQuery::literal("parser").with_case_sensitive(false)).unwrap();
+        let main = results.iter().find(|r| r.path.as_str() == "/src/main.rs").unwrap();
+        assert_eq!(main.matches[0], SearchMatch {
+            line: 1,
+            column: 15,
+            length: 6,
+            line_text: "    let café = Parser::new();".to_string(),
+        });
+
+        let results = index
+            .search(&SearchQuery::regex(r"pars(er|ing)\b").with_case_sensitive(false).with_under(VfsPath::new("/docs")))
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].matches[0].column, 0);
+
+        let results = index.search(&SearchQuery::regex(r"fn \w+\(").with_max_matches(1)).unwrap();
+        assert_eq!(results.iter().map(|r| r.matches.len()).sum::<usize>(), 1);
+
+        assert!(matches!(
+            index.search(&SearchQuery::regex("(")),
+            Err(VRaftError::InvalidSearchPattern(_))
+        ));
+    }
+
+    #[test]
+    fn test_index_follows_changes() {
+        let vfs = Arc::new(Vfs::new(RaftGroupId::new(1)));
+        let index = SearchIndex::new(vfs.clone());
+        let mut events = vfs.subscribe();
+
+        let file_id = create(&vfs, "/src/lib.rs", "mod alpha;\n");
+        vfs.apply(VfsCommand::UpdateFile {
+            file_id,
+            content: "mod beta;\n".to_string(),
+            expected_version: None,
+        });
+        while let Ok(event) = events.try_recv() {
+            index.update(&event);
+        }
+        assert!(index.search(&SearchQuery::literal("alpha")).unwrap().is_empty());
+        assert_eq!(index.search(&SearchQuery::literal("beta")).unwrap().len(), 1);
+
+        vfs.apply(VfsCommand::DeleteFile { file_id });
+        while let Ok(event) = events.try_recv() {
+            index.update(&event);
+        }
+        assert_eq!(index.file_count(), 0);
+    }
+}
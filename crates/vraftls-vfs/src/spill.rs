@@ -0,0 +1,237 @@
+//! Spilling file contents to disk
+//!
+//! Contents of large files are moved out of memory into files of a spill
+//! directory when they have not been accessed for a while, or while the
+//! contents in memory exceed a budget, least recently accessed first. A
+//! spilled file reads its content back from disk whenever it is accessed, and
+//! is held in memory again once its content changes. Spill files no longer
+//! referenced are removed after each spill pass, including those left over
+//! from before a restart.
+
+use crate::file::VfsFile;
+use crate::vfs::VfsHandle;
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use vraftls_core::{FileId, VfsConfig};
+
+/// Decides which file contents to spill, and stores them
+pub struct SpillManager {
+    /// Directory of the spilled contents
+    dir: PathBuf,
+
+    /// Files smaller than this are never spilled
+    min_file_size: u64,
+
+    /// Memory file contents may take before large files are spilled
+    memory_budget: u64,
+
+    /// Large files not accessed for this long are spilled
+    cold_after: Duration,
+
+    /// Last access of each file since startup
+    accessed: DashMap<FileId, Instant>,
+}
+
+/// Outcome of a spill pass
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpillReport {
+    /// Files spilled
+    pub spilled: usize,
+
+    /// Memory freed
+    pub bytes: u64,
+}
+
+impl SpillManager {
+    /// Spill into `dir`, with the thresholds of the VFS configuration
+    pub fn new(dir: impl Into<PathBuf>, config: &VfsConfig) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            min_file_size: config.spill_min_file_size,
+            memory_budget: config.spill_memory_budget,
+            cold_after: config.spill_cold_after,
+            accessed: DashMap::new(),
+        })
+    }
+
+    /// Record an access to a file
+    pub(crate) fn touch(&self, file_id: FileId) {
+        self.accessed.insert(file_id, Instant::now());
+    }
+
+    /// Stop tracking a deleted file
+    pub(crate) fn forget(&self, file_id: FileId) {
+        self.accessed.remove(&file_id);
+    }
+
+    /// Files to spill, given the memory the content of each file in memory
+    /// takes
+    ///
+    /// Large files are spilled if they are cold, or for as long as contents
+    /// exceed the memory budget. Files never accessed since startup count as
+    /// cold.
+    pub(crate) fn choose(&self, resident: Vec<(FileId, u64)>) -> Vec<FileId> {
+        let now = Instant::now();
+        let mut remaining: u64 = resident.iter().map(|(_, size)| size).sum();
+
+        let mut candidates: Vec<_> = resident
+            .into_iter()
+            .filter(|(_, size)| *size >= self.min_file_size)
+            .map(|(file_id, size)| (file_id, size, self.accessed.get(&file_id).map(|t| *t)))
+            .collect();
+        candidates.sort_by_key(|(_, _, accessed)| *accessed);
+
+        candidates
+            .into_iter()
+            .filter(|(_, size, accessed)| {
+                let cold = accessed.is_none_or(|t| now.duration_since(t) >= self.cold_after);
+                if cold || remaining > self.memory_budget {
+                    remaining -= size;
+                    true
+                } else {
+                    false
+                }
+            })
+            .map(|(file_id, _, _)| file_id)
+            .collect()
+    }
+
+    /// Write the loaded content of a file to its spill file
+    ///
+    /// Spill files are named by file and checksum, so a file's content never
+    /// overwrites a different one still referenced.
+    pub(crate) fn write(&self, file: &VfsFile) -> io::Result<String> {
+        let Some(text) = file.text() else {
+            return Err(io::Error::other(format!("content of {} not loaded", file.path)));
+        };
+        let path = self.dir.join(format!("{}-{:016x}", file.id, file.checksum.0));
+        let mut out = BufWriter::new(std::fs::File::create(&path)?);
+        for chunk in text.chunks() {
+            out.write_all(chunk.as_bytes())?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(path.to_string_lossy().into_owned())
+    }
+
+    /// Remove spill files not referenced by any file
+    pub(crate) fn sweep(&self, referenced: &HashSet<String>) -> io::Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !referenced.contains(path.to_string_lossy().as_ref()) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run a spill pass on the VFS every `interval`
+pub fn spawn_spillover(vfs: VfsHandle, interval: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+
+            let vfs = vfs.clone();
+            match tokio::task::spawn_blocking(move || vfs.spill()).await {
+                Ok(Ok(report)) if report.spilled > 0 => {
+                    tracing::info!(spilled = report.spilled, freed_bytes = report.bytes, "vfs spill");
+                }
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => tracing::warn!(error = %e, "vfs spill failed"),
+                Err(e) => tracing::warn!(error = %e, "vfs spill task failed"),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{VfsCommand, VfsResponse};
+    use crate::path::VfsPath;
+    use crate::vfs::Vfs;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+    use vraftls_core::RaftGroupId;
+
+    fn spill_manager(dir: &TempDir, memory_budget: u64, cold_after: Duration) -> SpillManager {
+        let config = VfsConfig {
+            enable_compression: false,
+            spill_min_file_size: 100,
+            spill_memory_budget: memory_budget,
+            spill_cold_after: cold_after,
+            ..VfsConfig::default()
+        };
+        SpillManager::new(dir.path(), &config).unwrap()
+    }
+
+    fn create(vfs: &Vfs, path: &str, content: &str) -> FileId {
+        match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new(path),
+            content: content.to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        }
+    }
+
+    fn spill_files(dir: &TempDir) -> usize {
+        std::fs::read_dir(dir.path()).unwrap().count()
+    }
+
+    #[test]
+    fn test_spill_over_budget() {
+        let dir = TempDir::new().unwrap();
+        let vfs = Arc::new(Vfs::new(RaftGroupId::new(1)).with_spill(spill_manager(&dir, 250, Duration::MAX)));
+        let small = create(&vfs, "/small.rs", "fn small() {}");
+        let old = create(&vfs, "/old.rs", &"a".repeat(200));
+        let recent = create(&vfs, "/recent.rs", &"b".repeat(200));
+        vfs.get_file(recent);
+
+        // The least recently accessed large file goes first
+        let report = vfs.spill().unwrap();
+        assert_eq!(report.spilled, 1);
+        assert_eq!(report.bytes, 200);
+        let spilled = std::fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap().file_name();
+        assert!(spilled.to_string_lossy().starts_with(&format!("{}-", old)));
+        assert_eq!(spill_files(&dir), 1);
+        assert_eq!(vfs.spill().unwrap().spilled, 0);
+        assert!(vfs.get_file(small).unwrap().is_loaded());
+
+        // Reads load spilled content transparently
+        assert_eq!(vfs.get_content(old).unwrap(), "a".repeat(200));
+
+        // Changed content returns to memory, and its spill file is removed
+        vfs.apply(VfsCommand::UpdateFile {
+            file_id: old,
+            content: "fn old() {}".to_string(),
+            expected_version: None,
+        });
+        vfs.spill().unwrap();
+        assert_eq!(spill_files(&dir), 0);
+    }
+
+    #[test]
+    fn test_spill_cold_files() {
+        let dir = TempDir::new().unwrap();
+        let vfs = Vfs::new(RaftGroupId::new(1)).with_spill(spill_manager(&dir, u64::MAX, Duration::ZERO));
+        let file_id = create(&vfs, "/big.rs", &"c".repeat(300));
+        let view = vfs.snapshot_view();
+
+        assert_eq!(vfs.spill().unwrap().spilled, 1);
+        vfs.apply(VfsCommand::DeleteFile { file_id });
+        vfs.spill().unwrap();
+
+        // Views keep content whose spill file is gone
+        assert_eq!(spill_files(&dir), 0);
+        let original = view.read(&vfs, file_id).unwrap();
+        assert_eq!(original.content_string().unwrap(), "c".repeat(300));
+    }
+}
@@ -0,0 +1,29 @@
+//! Deleted files kept for restoring
+//!
+//! With the trash enabled, a deleted file is kept with the time it was
+//! deleted, and can be put back at its path with `RestoreFile` until
+//! `PurgeTrash` drops it. Deletion times are local to each replica, so the
+//! group leader decides which files expired and purges them by ID, keeping
+//! every replica's trash the same.
+
+use crate::file::VfsFile;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use vraftls_core::Timestamp;
+
+/// A deleted file in the trash
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TrashedFile {
+    /// The file as it was when deleted
+    pub file: VfsFile,
+
+    /// When the file was deleted
+    pub deleted_at: Timestamp,
+}
+
+impl TrashedFile {
+    /// Whether the file has been in the trash for at least `retention`
+    pub fn is_expired(&self, retention: Duration, now: Timestamp) -> bool {
+        now.0.saturating_sub(self.deleted_at.0) >= retention.as_millis() as u64
+    }
+}
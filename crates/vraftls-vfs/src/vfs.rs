@@ -1,21 +1,35 @@
 //! Virtual File System implementation
 
-use crate::commands::{VfsCommand, VfsCommandError, VfsResponse};
-use crate::file::{FileChangeEvent, FileChangeType, VfsFile};
+use crate::commands::{
+    BatchWriteOp, VfsBatchResult, VfsCommand, VfsCommandError, VfsQuery, VfsQueryResponse,
+    VfsResponse, VfsTextEdit,
+};
+use crate::file::{FileChangeEvent, FileChangeType, FileContent, VfsFile};
+use crate::history::{FileHistory, FileRevision, RevisionInfo};
 use crate::path::VfsPath;
+use crate::spill::{SpillManager, SpillReport};
+use crate::trash::TrashedFile;
+use crate::view::SnapshotView;
 use dashmap::DashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, RwLock, Weak};
+use std::time::Duration;
 use tokio::sync::broadcast;
-use vraftls_core::{FileId, RaftGroupId, Result, Timestamp, VRaftError};
+use vraftls_core::{FileId, FileVersion, RaftGroupId, Result, Timestamp, VRaftError, VfsConfig};
 
 /// In-memory Virtual File System
 pub struct Vfs {
     /// Files indexed by ID
     files: DashMap<FileId, VfsFile>,
 
-    /// Path to file ID mapping
-    path_index: DashMap<VfsPath, FileId>,
+    /// File IDs by path components, so every spelling of a path finds
+    /// the same file
+    path_index: DashMap<Vec<String>, FileId>,
+
+    /// Directories by their path components; the root always exists
+    directories: DashMap<Vec<String>, VfsPath>,
 
     /// Next file ID counter
     next_file_id: AtomicU64,
@@ -25,6 +39,24 @@ pub struct Vfs {
 
     /// File change event broadcaster
     change_tx: broadcast::Sender<FileChangeEvent>,
+
+    /// Open point-in-time views that need pre-images of modified files
+    views: RwLock<Vec<Weak<SnapshotView>>>,
+
+    /// Content of files larger than this is kept compressed
+    compression_threshold: Option<usize>,
+
+    /// Spills file contents to disk
+    spill: Option<SpillManager>,
+
+    /// Previous versions of files
+    history: FileHistory,
+
+    /// Keep deleted files in the trash
+    trash_enabled: bool,
+
+    /// Deleted files by ID, while the trash is enabled
+    trash: DashMap<FileId, TrashedFile>,
 }
 
 impl Vfs {
@@ -34,17 +66,110 @@ impl Vfs {
         Self {
             files: DashMap::new(),
             path_index: DashMap::new(),
+            directories: DashMap::new(),
             next_file_id: AtomicU64::new(1),
             group_id,
             change_tx,
+            views: RwLock::new(Vec::new()),
+            compression_threshold: None,
+            spill: None,
+            history: FileHistory::new(0),
+            trash_enabled: false,
+            trash: DashMap::new(),
+        }
+    }
+
+    /// Apply the VFS configuration
+    pub fn with_config(mut self, config: &VfsConfig) -> Self {
+        self.compression_threshold = config
+            .enable_compression
+            .then_some(config.compression_threshold as usize);
+        self.history = FileHistory::new(config.history_versions);
+        self.trash_enabled = config.enable_trash;
+        self
+    }
+
+    /// Spill file contents to disk, as `spill` chooses, on each `spill` pass
+    pub fn with_spill(mut self, spill: SpillManager) -> Self {
+        self.spill = Some(spill);
+        self
+    }
+
+    /// Record an access to a file
+    fn touch(&self, file_id: FileId) {
+        if let Some(spill) = &self.spill {
+            spill.touch(file_id);
+        }
+    }
+
+    /// Drop what is kept about a deleted file besides the file
+    fn forget(&self, file_id: FileId) {
+        self.history.forget(file_id);
+        if let Some(spill) = &self.spill {
+            spill.forget(file_id);
+        }
+    }
+
+    /// Compress a file's content if it is large enough
+    fn store(&self, file: &mut VfsFile) {
+        self.touch(file.id);
+        let Some(threshold) = self.compression_threshold else {
+            return;
+        };
+        if file.is_loaded() && file.content.len().unwrap_or(0) > threshold {
+            if let Err(e) = file.compress() {
+                tracing::warn!("Failed to compress {}: {}", file.path, e);
+            }
         }
     }
 
+    /// Raft group whose files this VFS holds
+    pub fn group_id(&self) -> RaftGroupId {
+        self.group_id
+    }
+
     /// Subscribe to file change events
     pub fn subscribe(&self) -> broadcast::Receiver<FileChangeEvent> {
         self.change_tx.subscribe()
     }
 
+    /// Take a copy-on-write view of the current files
+    ///
+    /// Must not race with `apply`; the Raft state machine calls it between
+    /// applying entries.
+    pub fn snapshot_view(&self) -> Arc<SnapshotView> {
+        let view = Arc::new(SnapshotView::new(
+            self.all_file_ids(),
+            self.all_directories(),
+            self.trashed_files(),
+            self.next_file_id(),
+            self.history.entries(),
+        ));
+        let mut views = self.views.write().unwrap();
+        views.retain(|v| v.strong_count() > 0);
+        views.push(Arc::downgrade(&view));
+        view
+    }
+
+    /// Hand the pre-image of a file to open views before it changes
+    ///
+    /// Spilled content is loaded first, since its spill file is removed
+    /// once the file changes.
+    fn preserve(&self, file: &VfsFile) {
+        let views = self.views.read().unwrap();
+        let mut views = views.iter().filter_map(Weak::upgrade).peekable();
+        if views.peek().is_none() {
+            return;
+        }
+        let file = match file.content {
+            FileContent::OnDisk(_) => Cow::Owned(file.clone().loaded()),
+            _ => Cow::Borrowed(file),
+        };
+        for view in views {
+            view.preserve(&file);
+        }
+    }
+
     /// Apply a VFS command (used by Raft state machine)
     pub fn apply(&self, command: VfsCommand) -> VfsResponse {
         match command {
@@ -54,35 +179,377 @@ impl Vfs {
                 content,
                 expected_version,
             } => self.update_file(file_id, content, expected_version),
+            VfsCommand::ApplyTextEdits {
+                file_id,
+                edits,
+                expected_version,
+            } => self.apply_text_edits(file_id, &edits, expected_version),
             VfsCommand::DeleteFile { file_id } => self.delete_file(file_id),
             VfsCommand::RenameFile { file_id, new_path } => self.rename_file(file_id, new_path),
+            VfsCommand::CreateDirectory { path, recursive } => match self.create_directory(&path, recursive) {
+                Ok(_) => VfsResponse::Ok(None),
+                Err(e) => VfsResponse::Error(e),
+            },
+            VfsCommand::DeleteDirectory { path, recursive } => self.delete_directory(&path, recursive),
+            VfsCommand::DeleteTree { prefix } => self.delete_tree(&prefix),
+            VfsCommand::RestoreFile { file_id } => self.restore_file(file_id),
+            VfsCommand::PurgeTrash { file_ids } => self.purge_trash(file_ids.as_deref()),
             VfsCommand::BatchWrite { operations } => self.batch_write(operations),
-            VfsCommand::InvalidateCache { file_ids } => {
+            VfsCommand::InvalidateCache { .. } => {
                 // Cache invalidation is handled externally
                 VfsResponse::Ok(None)
             }
+            VfsCommand::Transaction { commands } => self.transaction(commands),
+            VfsCommand::Repair { files, next_file_id } => {
+                for file in files {
+                    self.repair_file(file.file_id, file.path, file.content, FileVersion::new(file.version));
+                }
+                self.next_file_id.fetch_max(next_file_id, Ordering::SeqCst);
+                VfsResponse::Ok(None)
+            }
         }
     }
 
-    /// Create a new file
+    /// Apply commands atomically, rolling back on the first error
+    fn transaction(&self, commands: Vec<VfsCommand>) -> VfsResponse {
+        let mut undo = Vec::new();
+
+        match self.apply_recorded(commands, &mut undo) {
+            Ok(responses) => VfsResponse::Transaction(responses),
+            Err(e) => {
+                self.rollback(undo);
+                VfsResponse::Error(e)
+            }
+        }
+    }
+
+    /// Apply commands, recording how to undo each successful change
+    fn apply_recorded(
+        &self,
+        commands: Vec<VfsCommand>,
+        undo: &mut Vec<Undo>,
+    ) -> std::result::Result<Vec<VfsResponse>, VfsCommandError> {
+        let mut responses = Vec::with_capacity(commands.len());
+
+        for command in commands {
+            let response = match command {
+                VfsCommand::Transaction { commands } => {
+                    VfsResponse::Transaction(self.apply_recorded(commands, undo)?)
+                }
+                VfsCommand::CreateFile { .. } => {
+                    let response = self.apply(command);
+                    if let VfsResponse::Created(file_id) = response {
+                        undo.push(Undo::Remove(file_id));
+                    }
+                    response
+                }
+                VfsCommand::UpdateFile { file_id, .. }
+                | VfsCommand::ApplyTextEdits { file_id, .. }
+                | VfsCommand::DeleteFile { file_id }
+                | VfsCommand::RenameFile { file_id, .. } => {
+                    let original = self.get_file(file_id).map(|file| self.restore_point(file));
+                    let response = self.apply(command);
+                    match original {
+                        Some(original) if !matches!(response, VfsResponse::Error(_)) => undo.push(original),
+                        _ => {}
+                    }
+                    response
+                }
+                VfsCommand::RestoreFile { file_id } => {
+                    let trashed = self.trash.get(&file_id).map(|entry| entry.clone());
+                    let response = self.apply(command);
+                    if let (Some(trashed), VfsResponse::Ok(_)) = (trashed, &response) {
+                        undo.push(Undo::Retrash(vec![trashed]));
+                    }
+                    response
+                }
+                VfsCommand::PurgeTrash { ref file_ids } => {
+                    let purged: Vec<_> = self
+                        .trash
+                        .iter()
+                        .filter(|entry| file_ids.as_ref().is_none_or(|ids| ids.contains(entry.key())))
+                        .map(|entry| entry.value().clone())
+                        .collect();
+                    undo.push(Undo::Retrash(purged));
+                    self.apply(command)
+                }
+                VfsCommand::CreateDirectory { path, recursive } => match self.create_directory(&path, recursive) {
+                    Ok(created) => {
+                        undo.push(Undo::RemoveDirectories(created));
+                        VfsResponse::Ok(None)
+                    }
+                    Err(e) => VfsResponse::Error(e),
+                },
+                VfsCommand::DeleteDirectory { ref path, .. } | VfsCommand::DeleteTree { prefix: ref path } => {
+                    let directories: Vec<_> = self
+                        .directories
+                        .iter()
+                        .filter(|entry| entry.value().starts_with(path))
+                        .map(|entry| entry.value().clone())
+                        .collect();
+                    let files: Vec<_> =
+                        self.list_directory(path).into_iter().map(|file| self.restore_point(file)).collect();
+                    let response = self.apply(command);
+                    if !matches!(response, VfsResponse::Error(_)) {
+                        undo.push(Undo::RestoreDirectories(directories));
+                        undo.extend(files);
+                    }
+                    response
+                }
+                VfsCommand::BatchWrite { ref operations } => {
+                    let originals: Vec<_> = operations
+                        .iter()
+                        .map(|op| match op {
+                            BatchWriteOp::Create { .. } => None,
+                            BatchWriteOp::Update { file_id, .. } | BatchWriteOp::Delete { file_id } => {
+                                self.get_file(*file_id).map(|file| self.restore_point(file))
+                            }
+                        })
+                        .collect();
+                    let response = self.apply(command);
+                    if let VfsResponse::BatchResults(results) = &response {
+                        for (original, result) in originals.into_iter().zip(results) {
+                            match (original, result) {
+                                (Some(original), VfsBatchResult::Success(_)) => undo.push(original),
+                                (None, VfsBatchResult::Success(Some(file_id))) => {
+                                    undo.push(Undo::Remove(*file_id))
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    response
+                }
+                VfsCommand::Repair { ref files, .. } => {
+                    for file in files {
+                        undo.push(match self.get_file(file.file_id) {
+                            Some(original) => self.restore_point(original),
+                            None => Undo::Remove(file.file_id),
+                        });
+                    }
+                    self.apply(command)
+                }
+                VfsCommand::InvalidateCache { .. } => self.apply(command),
+            };
+
+            match &response {
+                VfsResponse::Error(e) => return Err(e.clone()),
+                VfsResponse::BatchResults(results) => {
+                    if let Some(VfsBatchResult::Error(e)) =
+                        results.iter().find(|r| matches!(r, VfsBatchResult::Error(_)))
+                    {
+                        return Err(e.clone());
+                    }
+                }
+                _ => {}
+            }
+            responses.push(response);
+        }
+
+        Ok(responses)
+    }
+
+    /// How to put a file back as it is now, with its history; taken before
+    /// the command changing it runs
+    fn restore_point(&self, file: VfsFile) -> Undo {
+        let revisions = self.history.revisions(file.id);
+        Undo::Restore(Box::new(file), revisions)
+    }
+
+    /// Undo recorded changes in reverse order
+    fn rollback(&self, undo: Vec<Undo>) {
+        for step in undo.into_iter().rev() {
+            match step {
+                Undo::Remove(file_id) => {
+                    if let Some(file) = self.read_file(file_id) {
+                        self.preserve(&file);
+                    }
+                    if let Some((_, file)) = self.files.remove(&file_id) {
+                        self.path_index.remove(file.path.components());
+                        self.forget(file_id);
+                        let _ = self.change_tx.send(FileChangeEvent {
+                            change_type: FileChangeType::Deleted,
+                            file_id,
+                            path: file.path,
+                            version: file.version,
+                            timestamp: Timestamp::now(),
+                        });
+                    }
+                }
+                Undo::Restore(original, revisions) => {
+                    let file_id = original.id;
+                    if let Some(live) = self.get_file(file_id) {
+                        self.preserve(&live);
+                        self.path_index.remove(live.path.components());
+                    }
+                    self.path_index.insert(original.path.components().to_vec(), file_id);
+                    let _ = self.change_tx.send(FileChangeEvent {
+                        change_type: FileChangeType::Modified,
+                        file_id,
+                        path: original.path.clone(),
+                        version: original.version,
+                        timestamp: Timestamp::now(),
+                    });
+                    let mut original = *original;
+                    self.trash.remove(&file_id);
+                    self.history.reset(file_id, revisions);
+                    self.store(&mut original);
+                    self.files.insert(file_id, original);
+                }
+                Undo::RemoveDirectories(directories) => {
+                    for directory in directories {
+                        self.directories.remove(directory.components());
+                    }
+                }
+                Undo::RestoreDirectories(directories) => {
+                    for directory in directories {
+                        self.directories.insert(directory.components().to_vec(), directory);
+                    }
+                }
+                Undo::Retrash(trashed) => {
+                    for entry in trashed {
+                        let file_id = entry.file.id;
+                        if let Some(file) = self.read_file(file_id) {
+                            self.preserve(&file);
+                        }
+                        if let Some((_, file)) = self.files.remove(&file_id) {
+                            self.path_index.remove(file.path.components());
+                            self.forget(file_id);
+                            let _ = self.change_tx.send(FileChangeEvent {
+                                change_type: FileChangeType::Deleted,
+                                file_id,
+                                path: file.path,
+                                version: file.version,
+                                timestamp: Timestamp::now(),
+                            });
+                        }
+                        self.trash.insert(file_id, entry);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Error unless the directory a new entry at `path` goes into exists,
+    /// and nothing is at `path` yet
+    fn check_new_entry(&self, path: &VfsPath) -> std::result::Result<(), VfsCommandError> {
+        if self.path_index.contains_key(path.components()) || self.has_directory(path.components()) {
+            return Err(VfsCommandError::FileAlreadyExists(path.to_string()));
+        }
+        let parent = &path.components()[..path.components().len().saturating_sub(1)];
+        if !self.has_directory(parent) {
+            return Err(VfsCommandError::DirectoryNotFound(directory_path(parent).to_string()));
+        }
+        Ok(())
+    }
+
+    fn has_directory(&self, components: &[String]) -> bool {
+        components.is_empty() || self.directories.contains_key(components)
+    }
+
+    /// Check if a file is at the absolute path given by its components
+    fn has_file(&self, components: &[String]) -> bool {
+        self.path_index.contains_key(components)
+    }
+
+    /// Create a directory, and with `recursive` its missing parents
+    ///
+    /// Returns the directories created, parents first; an existing directory
+    /// is not an error.
+    fn create_directory(&self, path: &VfsPath, recursive: bool) -> std::result::Result<Vec<VfsPath>, VfsCommandError> {
+        if self.has_directory(path.components()) {
+            return Ok(Vec::new());
+        }
+
+        // The directory and its missing parents, checked before any is created
+        let mut missing = vec![path.clone()];
+        loop {
+            let directory = missing.last().unwrap_or(path);
+            if self.has_file(directory.components()) || self.has_directory(directory.components()) {
+                return Err(VfsCommandError::FileAlreadyExists(directory.to_string()));
+            }
+            let parent = &directory.components()[..directory.components().len() - 1];
+            if self.has_directory(parent) {
+                break;
+            }
+            if !recursive {
+                return Err(VfsCommandError::DirectoryNotFound(directory_path(parent).to_string()));
+            }
+            missing.push(directory_path(parent));
+        }
+
+        missing.reverse();
+        for directory in &missing {
+            self.directories.insert(directory.components().to_vec(), directory.clone());
+        }
+        Ok(missing)
+    }
+
+    /// Delete a directory; with `recursive` the files and directories in it
+    /// too
+    fn delete_directory(&self, path: &VfsPath, recursive: bool) -> VfsResponse {
+        if path.components().is_empty() {
+            return VfsResponse::Error(VfsCommandError::InvalidPath("cannot delete the root directory".to_string()));
+        }
+        if !self.has_directory(path.components()) {
+            return VfsResponse::Error(VfsCommandError::DirectoryNotFound(path.to_string()));
+        }
+
+        let files = self.list_directory(path);
+        let subdirectories: Vec<_> = self
+            .directories
+            .iter()
+            .filter(|entry| entry.value().starts_with(path) && entry.key().len() > path.components().len())
+            .map(|entry| entry.key().clone())
+            .collect();
+        if !recursive && (!files.is_empty() || !subdirectories.is_empty()) {
+            return VfsResponse::Error(VfsCommandError::DirectoryNotEmpty(path.to_string()));
+        }
+
+        for file in files {
+            self.delete_file(file.id);
+        }
+        for directory in subdirectories {
+            self.directories.remove(&directory);
+        }
+        self.directories.remove(path.components());
+        VfsResponse::Ok(None)
+    }
+
+    /// Delete every file and directory under a path
+    fn delete_tree(&self, prefix: &VfsPath) -> VfsResponse {
+        let files: Vec<FileId> = self
+            .files
+            .iter()
+            .filter(|entry| entry.path.starts_with(prefix))
+            .map(|entry| *entry.key())
+            .collect();
+        for file_id in &files {
+            self.delete_file(*file_id);
+        }
+        self.directories.retain(|_, directory| !directory.starts_with(prefix));
+        VfsResponse::Deleted(files)
+    }
+
+    /// Create a new file, in an existing directory
     fn create_file(&self, path: VfsPath, content: String) -> VfsResponse {
-        // Check if file already exists
-        if self.path_index.contains_key(&path) {
-            return VfsResponse::Error(VfsCommandError::FileAlreadyExists(path.to_string()));
+        if let Err(e) = self.check_new_entry(&path) {
+            return VfsResponse::Error(e);
         }
 
         let file_id = FileId::new(self.next_file_id.fetch_add(1, Ordering::SeqCst));
-        let file = VfsFile::new(file_id, path.clone(), content, self.group_id);
+        let mut file = VfsFile::new(file_id, path.clone(), content, self.group_id);
+        self.store(&mut file);
 
         self.files.insert(file_id, file);
-        self.path_index.insert(path.clone(), file_id);
+        self.path_index.insert(path.components().to_vec(), file_id);
 
         // Emit change event
         let _ = self.change_tx.send(FileChangeEvent {
             change_type: FileChangeType::Created,
             file_id,
             path,
-            version: vraftls_core::FileVersion::initial(),
+            version: FileVersion::initial(),
             timestamp: Timestamp::now(),
         });
 
@@ -111,8 +578,11 @@ impl Vfs {
             }
         }
 
+        self.preserve(&file);
+        self.history.record(&file);
         let path = file.path.clone();
         file.update_content(content);
+        self.store(&mut file);
         let version = file.version;
 
         // Emit change event
@@ -127,32 +597,142 @@ impl Vfs {
         VfsResponse::Ok(Some(file_id))
     }
 
+    /// Change ranges of a file's text
+    fn apply_text_edits(&self, file_id: FileId, edits: &[VfsTextEdit], expected_version: Option<u64>) -> VfsResponse {
+        let mut file = match self.files.get_mut(&file_id) {
+            Some(f) => f,
+            None => return VfsResponse::Error(VfsCommandError::FileNotFound(file_id)),
+        };
+
+        // Checked before loading, so a stale edit leaves the content as stored
+        if let Some(expected) = expected_version {
+            if file.version.0 != expected {
+                return VfsResponse::Error(VfsCommandError::VersionMismatch {
+                    expected,
+                    actual: file.version.0,
+                });
+            }
+        }
+        if file.load().is_err() || !file.is_loaded() {
+            return VfsResponse::Error(VfsCommandError::StorageError(format!(
+                "content of {} not loaded",
+                file.path
+            )));
+        }
+
+        // The rope is shared with the preserved copy until it is edited
+        self.preserve(&file);
+        self.history.record(&file);
+        let path = file.path.clone();
+        file.edit_content(edits);
+        self.store(&mut file);
+        let version = file.version;
+
+        let _ = self.change_tx.send(FileChangeEvent {
+            change_type: FileChangeType::Modified,
+            file_id,
+            path,
+            version,
+            timestamp: Timestamp::now(),
+        });
+
+        VfsResponse::Ok(Some(file_id))
+    }
+
     /// Delete a file
     fn delete_file(&self, file_id: FileId) -> VfsResponse {
+        if let Some(file) = self.files.get(&file_id) {
+            self.preserve(&file);
+        }
+
         let file = match self.files.remove(&file_id) {
             Some((_, f)) => f,
             None => return VfsResponse::Error(VfsCommandError::FileNotFound(file_id)),
         };
 
-        self.path_index.remove(&file.path);
+        self.path_index.remove(file.path.components());
+        self.forget(file_id);
 
         // Emit change event
         let _ = self.change_tx.send(FileChangeEvent {
             change_type: FileChangeType::Deleted,
             file_id,
-            path: file.path,
+            path: file.path.clone(),
             version: file.version,
             timestamp: Timestamp::now(),
         });
 
+        if self.trash_enabled {
+            // Trashed content stays compressed until restored; the spill
+            // file of spilled content is removed on the next pass
+            let mut file = file;
+            if let Err(e) = file.compress() {
+                tracing::warn!("Failed to compress trashed {}: {}", file.path, e);
+                file = file.loaded();
+            }
+            self.trash.insert(
+                file_id,
+                TrashedFile {
+                    file,
+                    deleted_at: Timestamp::now(),
+                },
+            );
+        }
+
         VfsResponse::Ok(None)
     }
 
-    /// Rename a file
+    /// Put a file back from the trash, at the path it was deleted from
+    fn restore_file(&self, file_id: FileId) -> VfsResponse {
+        let path = match self.trash.get(&file_id) {
+            Some(entry) => entry.file.path.clone(),
+            None => return VfsResponse::Error(VfsCommandError::FileNotFound(file_id)),
+        };
+        if let Err(e) = self.check_new_entry(&path) {
+            return VfsResponse::Error(e);
+        }
+        let Some((_, TrashedFile { file, .. })) = self.trash.remove(&file_id) else {
+            return VfsResponse::Error(VfsCommandError::FileNotFound(file_id));
+        };
+        let mut file = file.loaded();
+
+        self.store(&mut file);
+        let version = file.version;
+        self.files.insert(file_id, file);
+        self.path_index.insert(path.components().to_vec(), file_id);
+
+        let _ = self.change_tx.send(FileChangeEvent {
+            change_type: FileChangeType::Created,
+            file_id,
+            path,
+            version,
+            timestamp: Timestamp::now(),
+        });
+
+        VfsResponse::Ok(Some(file_id))
+    }
+
+    /// Drop files from the trash, all of them with `None`
+    fn purge_trash(&self, file_ids: Option<&[FileId]>) -> VfsResponse {
+        let purged = match file_ids {
+            Some(file_ids) => file_ids
+                .iter()
+                .filter(|file_id| self.trash.remove(file_id).is_some())
+                .copied()
+                .collect(),
+            None => {
+                let purged: Vec<FileId> = self.trash.iter().map(|entry| *entry.key()).collect();
+                self.trash.clear();
+                purged
+            }
+        };
+        VfsResponse::Deleted(purged)
+    }
+
+    /// Rename a file, into an existing directory
     fn rename_file(&self, file_id: FileId, new_path: VfsPath) -> VfsResponse {
-        // Check if new path already exists
-        if self.path_index.contains_key(&new_path) {
-            return VfsResponse::Error(VfsCommandError::FileAlreadyExists(new_path.to_string()));
+        if let Err(e) = self.check_new_entry(&new_path) {
+            return VfsResponse::Error(e);
         }
 
         let mut file = match self.files.get_mut(&file_id) {
@@ -160,13 +740,14 @@ impl Vfs {
             None => return VfsResponse::Error(VfsCommandError::FileNotFound(file_id)),
         };
 
+        self.preserve(&file);
         let old_path = file.path.clone();
-        self.path_index.remove(&old_path);
+        self.path_index.remove(old_path.components());
 
         file.path = new_path.clone();
         file.last_modified = Timestamp::now();
 
-        self.path_index.insert(new_path.clone(), file_id);
+        self.path_index.insert(new_path.components().to_vec(), file_id);
 
         // Emit change event
         let _ = self.change_tx.send(FileChangeEvent {
@@ -180,10 +761,68 @@ impl Vfs {
         VfsResponse::Ok(Some(file_id))
     }
 
-    /// Batch write operations
-    fn batch_write(&self, operations: Vec<crate::commands::BatchWriteOp>) -> VfsResponse {
-        use crate::commands::{BatchWriteOp, VfsBatchResult};
+    /// Force a file to the state found on the group leader
+    ///
+    /// A no-op where the file already matches, which is the case on the
+    /// leader itself.
+    fn repair_file(
+        &self,
+        file_id: FileId,
+        path: VfsPath,
+        content: Option<String>,
+        version: FileVersion,
+    ) -> VfsResponse {
+        let Some(content) = content else {
+            return match self.get_file(file_id) {
+                Some(file) if file.path == path => self.delete_file(file_id),
+                _ => VfsResponse::Ok(None),
+            };
+        };
+
+        let existing = self.get_file(file_id);
+        if let Some(file) = &existing {
+            if file.path == path && file.version == version && file.text().is_some_and(|text| *text == *content) {
+                return VfsResponse::Ok(Some(file_id));
+            }
+        }
+
+        // A different file at the path is a leftover of the divergence
+        let other = self.path_index.get(path.components()).map(|id| *id).filter(|id| *id != file_id);
+        if let Some(other) = other {
+            self.delete_file(other);
+        }
+        if let Some(file) = &existing {
+            self.preserve(file);
+            self.history.record(file);
+            self.path_index.remove(file.path.components());
+        }
+
+        let mut file = VfsFile::new(file_id, path.clone(), content, self.group_id);
+        file.version = version;
+        if let Some(existing) = &existing {
+            file.metadata = existing.metadata.clone();
+        }
+        self.store(&mut file);
+        self.files.insert(file_id, file);
+        self.path_index.insert(path.components().to_vec(), file_id);
 
+        let _ = self.change_tx.send(FileChangeEvent {
+            change_type: if existing.is_some() {
+                FileChangeType::Modified
+            } else {
+                FileChangeType::Created
+            },
+            file_id,
+            path,
+            version,
+            timestamp: Timestamp::now(),
+        });
+
+        VfsResponse::Ok(Some(file_id))
+    }
+
+    /// Batch write operations
+    fn batch_write(&self, operations: Vec<BatchWriteOp>) -> VfsResponse {
         let results: Vec<VfsBatchResult> = operations
             .into_iter()
             .map(|op| match op {
@@ -212,36 +851,152 @@ impl Vfs {
 
     // Query methods (not replicated)
 
-    /// Get a file by ID
+    /// Answer a VFS query from local state
+    pub fn query(&self, query: VfsQuery) -> VfsQueryResponse {
+        match query {
+            VfsQuery::GetFile(file_id) => VfsQueryResponse::File(self.get_file(file_id)),
+            VfsQuery::GetFileByPath(path) => VfsQueryResponse::File(self.get_file_by_path(&path)),
+            VfsQuery::ListDirectory(path) => VfsQueryResponse::Files(self.list_directory(&path)),
+            VfsQuery::FindFiles(pattern) => VfsQueryResponse::Files(self.find_files(&pattern)),
+            VfsQuery::GetContent(file_id) => VfsQueryResponse::Content(self.get_content(file_id).ok()),
+            VfsQuery::GetFileAtVersion { file_id, version } => {
+                VfsQueryResponse::File(self.get_file_at_version(file_id, FileVersion::new(version)))
+            }
+            VfsQuery::GetHistory(file_id) => VfsQueryResponse::History(self.file_history(file_id)),
+            VfsQuery::ListTrash => VfsQueryResponse::Trash(self.trashed_files()),
+        }
+    }
+
+    /// Get a file as it was at a version, if that is its current version or
+    /// one kept in its history
+    pub fn get_file_at_version(&self, file_id: FileId, version: FileVersion) -> Option<VfsFile> {
+        let current = self.get_file(file_id)?;
+        if current.version == version {
+            return Some(current);
+        }
+        Some(self.history.at(file_id, version)?.file(&current))
+    }
+
+    /// List the previous versions kept of a file, newest first
+    pub fn file_history(&self, file_id: FileId) -> Vec<RevisionInfo> {
+        self.history.list(file_id)
+    }
+
+    /// Files in the trash, with their content loaded
+    pub fn trashed_files(&self) -> Vec<TrashedFile> {
+        self.trash
+            .iter()
+            .map(|entry| TrashedFile {
+                file: entry.file.clone().loaded(),
+                deleted_at: entry.deleted_at,
+            })
+            .collect()
+    }
+
+    /// IDs of the files in the trash for at least `retention`
+    pub fn expired_trash(&self, retention: Duration) -> Vec<FileId> {
+        let now = Timestamp::now();
+        self.trash
+            .iter()
+            .filter(|entry| entry.is_expired(retention, now))
+            .map(|entry| *entry.key())
+            .collect()
+    }
+
+    /// Drop every file, directory and trashed file before a snapshot is
+    /// restored, numbering new files from `next_file_id`
+    pub fn reset(&self, next_file_id: u64) {
+        for file_id in self.all_file_ids() {
+            let Some((_, file)) = self.files.remove(&file_id) else {
+                continue;
+            };
+            self.preserve(&file);
+            self.forget(file_id);
+            let _ = self.change_tx.send(FileChangeEvent {
+                change_type: FileChangeType::Deleted,
+                file_id,
+                path: file.path,
+                version: file.version,
+                timestamp: Timestamp::now(),
+            });
+        }
+        self.path_index.clear();
+        self.directories.clear();
+        self.trash.clear();
+        self.history.restore(HashMap::new());
+        self.next_file_id.store(next_file_id, Ordering::SeqCst);
+    }
+
+    /// Replace the previous versions of files with those in a snapshot
+    pub fn restore_history(&self, history: HashMap<FileId, Vec<FileRevision>>) {
+        self.history.restore(history);
+    }
+
+    /// Put a file in place as it was in a snapshot, keeping its ID and
+    /// version
+    pub fn insert_file(&self, file: VfsFile) {
+        let file_id = file.id;
+        self.next_file_id.fetch_max(file_id.0 + 1, Ordering::SeqCst);
+        self.repair_file(file_id, file.path.clone(), file.content_string(), file.version);
+        if let Some(mut live) = self.files.get_mut(&file_id) {
+            live.last_modified = file.last_modified;
+            live.metadata = file.metadata;
+        }
+    }
+
+    /// Put a file into the trash as it was in a snapshot
+    pub fn insert_trashed(&self, trashed: TrashedFile) {
+        let file_id = trashed.file.id;
+        self.next_file_id.fetch_max(file_id.0 + 1, Ordering::SeqCst);
+        self.trash.insert(file_id, trashed);
+    }
+
+    /// Get a file by ID, with its content loaded
     pub fn get_file(&self, file_id: FileId) -> Option<VfsFile> {
-        self.files.get(&file_id).map(|f| f.clone())
+        self.touch(file_id);
+        self.read_file(file_id)
     }
 
-    /// Get a file by path
+    /// Get a file by ID without counting it as an access
+    ///
+    /// The content is loaded while the file is locked, so a spill file is
+    /// not removed while it is read.
+    pub(crate) fn read_file(&self, file_id: FileId) -> Option<VfsFile> {
+        self.files.get(&file_id).map(|f| f.clone().loaded())
+    }
+
+    /// Get a file by path, with its content loaded
     pub fn get_file_by_path(&self, path: &VfsPath) -> Option<VfsFile> {
-        self.path_index
-            .get(path)
-            .and_then(|id| self.files.get(&id).map(|f| f.clone()))
+        let file_id = *self.path_index.get(path.components())?;
+        self.get_file(file_id)
     }
 
     /// Get file content
     pub fn get_content(&self, file_id: FileId) -> Result<String> {
-        let file = self
-            .files
-            .get(&file_id)
-            .ok_or(VRaftError::FileNotFound(file_id))?;
+        let file = self.get_file(file_id).ok_or(VRaftError::FileNotFound(file_id))?;
 
-        file.content_str()
-            .map(|s| s.to_string())
+        file.content_string()
             .ok_or_else(|| VRaftError::Internal("content not loaded".to_string()))
     }
 
-    /// List all files in a directory
+    /// Check if a directory exists; the root always does
+    pub fn is_directory(&self, path: &VfsPath) -> bool {
+        self.has_directory(path.components())
+    }
+
+    /// All directories, parents before their children
+    pub fn all_directories(&self) -> Vec<VfsPath> {
+        let mut directories: Vec<_> = self.directories.iter().map(|entry| entry.value().clone()).collect();
+        directories.sort_by_key(|directory| directory.components().len());
+        directories
+    }
+
+    /// List all files in a directory and below it
     pub fn list_directory(&self, path: &VfsPath) -> Vec<VfsFile> {
         self.files
             .iter()
             .filter(|entry| entry.path.starts_with(path))
-            .map(|entry| entry.value().clone())
+            .map(|entry| entry.value().clone().loaded())
             .collect()
     }
 
@@ -251,7 +1006,7 @@ impl Vfs {
         self.files
             .iter()
             .filter(|entry| entry.path.as_str().contains(pattern))
-            .map(|entry| entry.value().clone())
+            .map(|entry| entry.value().clone().loaded())
             .collect()
     }
 
@@ -264,14 +1019,87 @@ impl Vfs {
     pub fn all_file_ids(&self) -> Vec<FileId> {
         self.files.iter().map(|entry| *entry.key()).collect()
     }
+
+    /// ID the next created file will get
+    pub fn next_file_id(&self) -> u64 {
+        self.next_file_id.load(Ordering::SeqCst)
+    }
+
+    /// Spill file contents to disk as the spill manager chooses, and remove
+    /// spill files no longer referenced
+    ///
+    /// A file that changes while its content is written keeps its new
+    /// content in memory.
+    pub fn spill(&self) -> std::io::Result<SpillReport> {
+        let Some(spill) = &self.spill else {
+            return Ok(SpillReport::default());
+        };
+
+        let resident = self
+            .files
+            .iter()
+            .filter_map(|entry| Some((*entry.key(), entry.content.resident_size()? as u64)))
+            .collect();
+        let mut report = SpillReport::default();
+        for file_id in spill.choose(resident) {
+            let Some(file) = self.files.get(&file_id).map(|f| f.clone()) else {
+                continue;
+            };
+            let bytes = file.content.resident_size().unwrap_or(0) as u64;
+            let path = spill.write(&file.clone().loaded())?;
+            if let Some(mut live) = self.files.get_mut(&file_id) {
+                if live.version == file.version && live.checksum == file.checksum && live.content.resident_size().is_some() {
+                    live.content = FileContent::OnDisk(path);
+                    report.spilled += 1;
+                    report.bytes += bytes;
+                }
+            }
+        }
+
+        let referenced: HashSet<String> = self
+            .files
+            .iter()
+            .filter_map(|entry| match &entry.content {
+                FileContent::OnDisk(path) => Some(path.clone()),
+                _ => None,
+            })
+            .collect();
+        spill.sweep(&referenced)?;
+        Ok(report)
+    }
 }
 
 /// Thread-safe VFS handle
 pub type VfsHandle = Arc<Vfs>;
 
+/// Absolute path of a directory given by its components
+fn directory_path(components: &[String]) -> VfsPath {
+    VfsPath::new(format!("/{}", components.join("/")))
+}
+
+/// How to revert one change made inside a transaction
+enum Undo {
+    /// Remove a file created by the transaction
+    Remove(FileId),
+
+    /// Put back a file, and its previous versions, as they were before the
+    /// transaction changed it
+    Restore(Box<VfsFile>, Vec<FileRevision>),
+
+    /// Remove directories created by the transaction
+    RemoveDirectories(Vec<VfsPath>),
+
+    /// Put back directories the transaction deleted
+    RestoreDirectories(Vec<VfsPath>),
+
+    /// Put back trash entries the transaction restored or purged
+    Retrash(Vec<TrashedFile>),
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::commands::{FileRepair, TextPosition};
 
     #[test]
     fn test_create_and_get_file() {
@@ -291,7 +1119,7 @@ mod tests {
         };
 
         let file = vfs.get_file(file_id).expect("file should exist");
-        assert_eq!(file.content_str(), Some(content.as_str()));
+        assert_eq!(file.content_string().as_deref(), Some(content.as_str()));
     }
 
     #[test]
@@ -315,10 +1143,169 @@ mod tests {
         });
 
         let file = vfs.get_file(file_id).unwrap();
-        assert_eq!(file.content_str(), Some(new_content.as_str()));
+        assert_eq!(file.content_string().as_deref(), Some(new_content.as_str()));
         assert_eq!(file.version.0, 1);
     }
 
+    #[test]
+    fn test_compressed_content() {
+        let config = VfsConfig {
+            compression_threshold: 16,
+            ..VfsConfig::default()
+        };
+        let vfs = Vfs::new(RaftGroupId::new(1)).with_config(&config);
+        let content = "fn main() {}\n".repeat(100);
+        let file_id = match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/main.rs"),
+            content: content.clone(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+
+        let stored = vfs.files.get(&file_id).unwrap().clone();
+        assert!(matches!(stored.content, FileContent::Compressed { length: 1300, .. }));
+        assert!(stored.checksum.verify(&content));
+
+        // Reads and edits see the text
+        assert_eq!(vfs.get_content(file_id).unwrap(), content);
+        let response = vfs.apply(VfsCommand::ApplyTextEdits {
+            file_id,
+            edits: vec![VfsTextEdit {
+                start: TextPosition { line: 0, character: 3 },
+                end: TextPosition { line: 0, character: 7 },
+                new_text: "start".to_string(),
+            }],
+            expected_version: None,
+        });
+        assert!(matches!(response, VfsResponse::Ok(Some(_))));
+        assert!(!vfs.files.get(&file_id).unwrap().is_loaded());
+        assert!(vfs.get_content(file_id).unwrap().starts_with("fn start() {}\nfn main"));
+
+        // A stale edit leaves the content compressed
+        let response = vfs.apply(VfsCommand::ApplyTextEdits {
+            file_id,
+            edits: Vec::new(),
+            expected_version: Some(0),
+        });
+        assert!(matches!(response, VfsResponse::Error(VfsCommandError::VersionMismatch { .. })));
+        assert!(!vfs.files.get(&file_id).unwrap().is_loaded());
+
+        // Small files stay loaded
+        vfs.apply(VfsCommand::UpdateFile {
+            file_id,
+            content: "fn main() {}".to_string(),
+            expected_version: None,
+        });
+        assert!(vfs.files.get(&file_id).unwrap().is_loaded());
+    }
+
+    #[test]
+    fn test_apply_text_edits() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        let file_id = match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/main.rs"),
+            content: "fn main() {\n    let s = \"é\";\n}\n".to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+
+        let edit = |start: (u32, u32), end: (u32, u32), new_text: &str| VfsTextEdit {
+            start: TextPosition { line: start.0, character: start.1 },
+            end: TextPosition { line: end.0, character: end.1 },
+            new_text: new_text.to_string(),
+        };
+        let response = vfs.apply(VfsCommand::ApplyTextEdits {
+            file_id,
+            edits: vec![
+                // After the non-ASCII character, counted in UTF-16 units
+                edit((1, 14), (1, 14), "!"),
+                edit((1, 8), (1, 9), "t"),
+                edit((2, 1), (9, 0), "\n// end\n"),
+            ],
+            expected_version: Some(0),
+        });
+        assert!(matches!(response, VfsResponse::Ok(Some(_))));
+
+        let file = vfs.get_file(file_id).unwrap();
+        assert_eq!(file.content_string().as_deref(), Some("fn main() {\n    let t = \"é!\";\n}\n// end\n"));
+        assert!(file.checksum.verify(&file.content_string().unwrap()));
+        assert_eq!(file.version.0, 1);
+
+        let stale = vfs.apply(VfsCommand::ApplyTextEdits {
+            file_id,
+            edits: vec![edit((0, 0), (0, 0), "x")],
+            expected_version: Some(0),
+        });
+        assert!(matches!(stale, VfsResponse::Error(VfsCommandError::VersionMismatch { .. })));
+    }
+
+    #[test]
+    fn test_repair_file() {
+        let leader = Vfs::new(RaftGroupId::new(1));
+        let replica = Vfs::new(RaftGroupId::new(1));
+        let path = VfsPath::new("/lib.rs");
+        for vfs in [&leader, &replica] {
+            vfs.apply(VfsCommand::CreateFile {
+                path: path.clone(),
+                content: "mod a;".to_string(),
+            });
+        }
+        // The replica silently lost an update and a new file
+        let file_id = leader.get_file_by_path(&path).unwrap().id;
+        leader.apply(VfsCommand::UpdateFile {
+            file_id,
+            content: "mod b;".to_string(),
+            expected_version: None,
+        });
+        let lost = match leader.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/b.rs"),
+            content: String::new(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+        let stray = match replica.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/b.rs"),
+            content: "stray".to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+        replica.apply(VfsCommand::DeleteFile { file_id: stray });
+
+        let repair = VfsCommand::Repair {
+            files: vec![
+                FileRepair {
+                    file_id,
+                    path: path.clone(),
+                    content: Some("mod b;".to_string()),
+                    version: 1,
+                },
+                FileRepair {
+                    file_id: lost,
+                    path: VfsPath::new("/b.rs"),
+                    content: Some(String::new()),
+                    version: 0,
+                },
+            ],
+            next_file_id: leader.next_file_id(),
+        };
+        for vfs in [&leader, &replica] {
+            vfs.apply(repair.clone());
+        }
+
+        for vfs in [&leader, &replica] {
+            let file = vfs.get_file(file_id).unwrap();
+            assert_eq!(file.content_string().as_deref(), Some("mod b;"));
+            assert_eq!(file.version.0, 1);
+            assert_eq!(vfs.get_file_by_path(&VfsPath::new("/b.rs")).unwrap().id, lost);
+            assert_eq!(vfs.file_count(), 2);
+            assert_eq!(vfs.next_file_id(), 3);
+        }
+    }
+
     #[test]
     fn test_delete_file() {
         let vfs = Vfs::new(RaftGroupId::new(1));
@@ -336,4 +1323,369 @@ mod tests {
 
         assert!(vfs.get_file(file_id).is_none());
     }
+
+    #[test]
+    fn test_snapshot_view_preserves_original() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+
+        let file_id = match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/a.rs"),
+            content: "old".to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+
+        let view = vfs.snapshot_view();
+
+        vfs.apply(VfsCommand::UpdateFile {
+            file_id,
+            content: "new".to_string(),
+            expected_version: None,
+        });
+        vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/b.rs"),
+            content: "later".to_string(),
+        });
+
+        assert_eq!(view.file_ids(), &[file_id]);
+        let file = view.read(&vfs, file_id).unwrap();
+        assert_eq!(file.content_string().as_deref(), Some("old"));
+    }
+
+    #[test]
+    fn test_transaction_rolls_back_on_error() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+
+        let file_id = match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/a.rs"),
+            content: "old".to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+
+        let response = vfs.apply(VfsCommand::Transaction {
+            commands: vec![
+                VfsCommand::UpdateFile {
+                    file_id,
+                    content: "new".to_string(),
+                    expected_version: None,
+                },
+                VfsCommand::RenameFile {
+                    file_id,
+                    new_path: VfsPath::new("/b.rs"),
+                },
+                VfsCommand::CreateFile {
+                    path: VfsPath::new("/c.rs"),
+                    content: "c".to_string(),
+                },
+                VfsCommand::DeleteFile {
+                    file_id: FileId::new(999),
+                },
+            ],
+        });
+        assert!(matches!(response, VfsResponse::Error(VfsCommandError::FileNotFound(_))));
+
+        assert_eq!(vfs.file_count(), 1);
+        let file = vfs.get_file_by_path(&VfsPath::new("/a.rs")).unwrap();
+        assert_eq!(file.id, file_id);
+        assert_eq!(file.content_string().as_deref(), Some("old"));
+        assert!(vfs.get_file_by_path(&VfsPath::new("/b.rs")).is_none());
+    }
+
+    #[test]
+    fn test_transaction_rollback_keeps_history() {
+        let config = VfsConfig {
+            history_versions: 2,
+            ..VfsConfig::default()
+        };
+        let vfs = Vfs::new(RaftGroupId::new(1)).with_config(&config);
+
+        let file_id = match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/a.rs"),
+            content: "v1".to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+        vfs.apply(VfsCommand::UpdateFile {
+            file_id,
+            content: "v2".to_string(),
+            expected_version: None,
+        });
+        let versions = |history: Vec<RevisionInfo>| history.into_iter().map(|revision| revision.version).collect::<Vec<_>>();
+        let history = versions(vfs.file_history(file_id));
+        assert_eq!(history.len(), 1);
+
+        let response = vfs.apply(VfsCommand::Transaction {
+            commands: vec![
+                VfsCommand::UpdateFile {
+                    file_id,
+                    content: "v3".to_string(),
+                    expected_version: None,
+                },
+                VfsCommand::DeleteFile { file_id },
+                VfsCommand::DeleteFile {
+                    file_id: FileId::new(999),
+                },
+            ],
+        });
+        assert!(matches!(response, VfsResponse::Error(VfsCommandError::FileNotFound(_))));
+
+        let file = vfs.get_file(file_id).unwrap();
+        assert_eq!(file.content_string().as_deref(), Some("v2"));
+        assert_eq!(versions(vfs.file_history(file_id)), history);
+    }
+
+    #[test]
+    fn test_path_spellings_are_one_file() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new("/a"),
+            recursive: false,
+        });
+        let file_id = match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/a/b.rs"),
+            content: String::new(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+
+        let response = vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/a//b.rs"),
+            content: String::new(),
+        });
+        assert!(matches!(response, VfsResponse::Error(VfsCommandError::FileAlreadyExists(_))));
+        for path in ["/a//b.rs", "/a/./b.rs", "/c/../a/b.rs"] {
+            assert_eq!(vfs.get_file_by_path(&VfsPath::new(path)).map(|f| f.id), Some(file_id));
+        }
+
+        vfs.apply(VfsCommand::RenameFile {
+            file_id,
+            new_path: VfsPath::new("/a//c.rs"),
+        });
+        assert!(vfs.get_file_by_path(&VfsPath::new("/a/b.rs")).is_none());
+        assert_eq!(vfs.get_file_by_path(&VfsPath::new("/a/c.rs")).map(|f| f.id), Some(file_id));
+    }
+
+    #[test]
+    fn test_create_file_needs_directory() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        let create = |path: &str| {
+            vfs.apply(VfsCommand::CreateFile {
+                path: VfsPath::new(path),
+                content: String::new(),
+            })
+        };
+
+        assert!(matches!(create("/src/lib.rs"), VfsResponse::Error(VfsCommandError::DirectoryNotFound(_))));
+
+        let response = vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new("/src/bin"),
+            recursive: false,
+        });
+        assert!(matches!(response, VfsResponse::Error(VfsCommandError::DirectoryNotFound(_))));
+        vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new("/src/bin"),
+            recursive: true,
+        });
+        assert!(vfs.is_directory(&VfsPath::new("/src")));
+        assert_eq!(vfs.all_directories(), vec![VfsPath::new("/src"), VfsPath::new("/src/bin")]);
+
+        assert!(matches!(create("/src/lib.rs"), VfsResponse::Created(_)));
+        assert!(matches!(create("/src/lib.rs/mod.rs"), VfsResponse::Error(VfsCommandError::DirectoryNotFound(_))));
+        assert!(matches!(create("/src"), VfsResponse::Error(VfsCommandError::FileAlreadyExists(_))));
+
+        let create_directory = |path: &str| {
+            vfs.apply(VfsCommand::CreateDirectory {
+                path: VfsPath::new(path),
+                recursive: false,
+            })
+        };
+        assert!(matches!(create_directory("/src/bin"), VfsResponse::Ok(None)));
+        assert!(matches!(create_directory("/src/lib.rs"), VfsResponse::Error(VfsCommandError::FileAlreadyExists(_))));
+        assert!(matches!(create_directory("/src/lib.rs/sub"), VfsResponse::Error(VfsCommandError::DirectoryNotFound(_))));
+    }
+
+    #[test]
+    fn test_delete_directory() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new("/src/bin"),
+            recursive: true,
+        });
+        vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/src/lib.rs"),
+            content: String::new(),
+        });
+
+        let delete = |recursive| {
+            vfs.apply(VfsCommand::DeleteDirectory {
+                path: VfsPath::new("/src"),
+                recursive,
+            })
+        };
+        assert!(matches!(delete(false), VfsResponse::Error(VfsCommandError::DirectoryNotEmpty(_))));
+
+        // Rolled back with the transaction
+        let response = vfs.apply(VfsCommand::Transaction {
+            commands: vec![
+                VfsCommand::DeleteDirectory {
+                    path: VfsPath::new("/src"),
+                    recursive: true,
+                },
+                VfsCommand::DeleteFile {
+                    file_id: FileId::new(999),
+                },
+            ],
+        });
+        assert!(matches!(response, VfsResponse::Error(_)));
+        assert!(vfs.is_directory(&VfsPath::new("/src/bin")));
+        assert_eq!(vfs.file_count(), 1);
+
+        assert!(matches!(delete(true), VfsResponse::Ok(None)));
+        assert!(vfs.all_directories().is_empty());
+        assert_eq!(vfs.file_count(), 0);
+    }
+
+    #[test]
+    fn test_delete_tree() {
+        let vfs = Vfs::new(RaftGroupId::new(1));
+        vfs.apply(VfsCommand::CreateDirectory {
+            path: VfsPath::new("/src/bin"),
+            recursive: true,
+        });
+        let create = |path: &str| match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new(path),
+            content: String::new(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+        let lib = create("/src/lib.rs");
+        let main = create("/src/bin/main.rs");
+        create("/build.rs");
+
+        let response = vfs.apply(VfsCommand::DeleteTree {
+            prefix: VfsPath::new("/src"),
+        });
+        let VfsResponse::Deleted(mut deleted) = response else {
+            panic!("expected Deleted");
+        };
+        deleted.sort_by_key(|id| id.0);
+        assert_eq!(deleted, [lib, main]);
+        assert!(vfs.all_directories().is_empty());
+        assert_eq!(vfs.file_count(), 1);
+
+        // Nothing under the prefix
+        let response = vfs.apply(VfsCommand::DeleteTree {
+            prefix: VfsPath::new("/src"),
+        });
+        assert!(matches!(response, VfsResponse::Deleted(deleted) if deleted.is_empty()));
+    }
+
+    #[test]
+    fn test_file_history() {
+        let config = VfsConfig {
+            history_versions: 2,
+            ..VfsConfig::default()
+        };
+        let vfs = Vfs::new(RaftGroupId::new(1)).with_config(&config);
+        let file_id = match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new("/main.rs"),
+            content: "v0".to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+        for content in ["v1", "v2", "v3"] {
+            vfs.apply(VfsCommand::UpdateFile {
+                file_id,
+                content: content.to_string(),
+                expected_version: None,
+            });
+        }
+
+        // Only the newest previous versions are kept
+        let versions: Vec<_> = vfs.file_history(file_id).iter().map(|r| r.version.0).collect();
+        assert_eq!(versions, [2, 1]);
+        let at = |version| match vfs.query(VfsQuery::GetFileAtVersion { file_id, version }) {
+            VfsQueryResponse::File(file) => file.and_then(|f| f.content_string()),
+            _ => panic!("expected File"),
+        };
+        assert_eq!(at(1).as_deref(), Some("v1"));
+        assert_eq!(at(3).as_deref(), Some("v3"));
+        assert_eq!(at(0), None);
+
+        // A rolled back transaction leaves the history as it was
+        vfs.apply(VfsCommand::Transaction {
+            commands: vec![
+                VfsCommand::UpdateFile {
+                    file_id,
+                    content: "v4".to_string(),
+                    expected_version: None,
+                },
+                VfsCommand::DeleteFile {
+                    file_id: FileId::new(999),
+                },
+            ],
+        });
+        let versions: Vec<_> = vfs.file_history(file_id).iter().map(|r| r.version.0).collect();
+        assert_eq!(versions, [2, 1]);
+        assert_eq!(at(1).as_deref(), Some("v1"));
+        assert_eq!(at(3).as_deref(), Some("v3"));
+
+        vfs.apply(VfsCommand::DeleteFile { file_id });
+        assert!(vfs.file_history(file_id).is_empty());
+    }
+
+    #[test]
+    fn test_trash() {
+        let vfs = Vfs::new(RaftGroupId::new(1)).with_config(&VfsConfig {
+            enable_trash: true,
+            ..VfsConfig::default()
+        });
+        let create = |path: &str| match vfs.apply(VfsCommand::CreateFile {
+            path: VfsPath::new(path),
+            content: path.to_string(),
+        }) {
+            VfsResponse::Created(id) => id,
+            _ => panic!("expected Created"),
+        };
+        let lib = create("/lib.rs");
+        let main = create("/main.rs");
+        vfs.apply(VfsCommand::DeleteFile { file_id: lib });
+        vfs.apply(VfsCommand::DeleteFile { file_id: main });
+        assert_eq!(vfs.trashed_files().len(), 2);
+        assert!(matches!(vfs.trash.get(&lib).unwrap().file.content, FileContent::Compressed { .. }));
+        assert!(vfs.expired_trash(Duration::from_secs(60)).is_empty());
+        assert_eq!(vfs.expired_trash(Duration::ZERO).len(), 2);
+
+        // Restored at its path, unless something took it since
+        let response = vfs.apply(VfsCommand::RestoreFile { file_id: lib });
+        assert!(matches!(response, VfsResponse::Ok(Some(id)) if id == lib));
+        assert_eq!(vfs.get_content(lib).unwrap(), "/lib.rs");
+        create("/main.rs");
+        let response = vfs.apply(VfsCommand::RestoreFile { file_id: main });
+        assert!(matches!(response, VfsResponse::Error(VfsCommandError::FileAlreadyExists(_))));
+
+        // A failed transaction puts purged files back
+        let response = vfs.apply(VfsCommand::Transaction {
+            commands: vec![
+                VfsCommand::PurgeTrash { file_ids: None },
+                VfsCommand::DeleteFile { file_id: FileId::new(99) },
+            ],
+        });
+        assert!(matches!(response, VfsResponse::Error(_)));
+        assert_eq!(vfs.trashed_files().len(), 1);
+
+        let response = vfs.apply(VfsCommand::PurgeTrash {
+            file_ids: Some(vec![main, lib]),
+        });
+        assert!(matches!(response, VfsResponse::Deleted(purged) if purged == [main]));
+        let response = vfs.apply(VfsCommand::RestoreFile { file_id: main });
+        assert!(matches!(response, VfsResponse::Error(VfsCommandError::FileNotFound(_))));
+    }
 }
@@ -0,0 +1,117 @@
+//! Point-in-time views of the VFS
+//!
+//! A view records which files existed when it was taken. While the view is
+//! alive, the VFS preserves the pre-image of any of those files before it is
+//! modified, so readers of the view see consistent content without copying
+//! every file up front.
+
+use crate::file::VfsFile;
+use crate::history::FileRevision;
+use crate::path::VfsPath;
+use crate::trash::TrashedFile;
+use crate::vfs::Vfs;
+use dashmap::{DashMap, DashSet};
+use std::collections::HashMap;
+use vraftls_core::FileId;
+
+/// Copy-on-write view of the VFS at a point in time
+pub struct SnapshotView {
+    /// Files captured by the view that have not been read yet
+    pending: DashSet<FileId>,
+
+    /// Captured file IDs in iteration order
+    file_ids: Vec<FileId>,
+
+    /// Pre-images of captured files modified after the view was taken
+    preserved: DashMap<FileId, VfsFile>,
+
+    /// Directories when the view was taken, parents first
+    directories: Vec<VfsPath>,
+
+    /// Files in the trash when the view was taken
+    trash: Vec<TrashedFile>,
+
+    /// ID the next created file would have got when the view was taken
+    next_file_id: u64,
+
+    /// Previous versions of files when the view was taken
+    history: HashMap<FileId, Vec<FileRevision>>,
+}
+
+impl SnapshotView {
+    pub(crate) fn new(
+        file_ids: Vec<FileId>,
+        directories: Vec<VfsPath>,
+        trash: Vec<TrashedFile>,
+        next_file_id: u64,
+        history: HashMap<FileId, Vec<FileRevision>>,
+    ) -> Self {
+        Self {
+            pending: file_ids.iter().copied().collect(),
+            file_ids,
+            preserved: DashMap::new(),
+            directories,
+            trash,
+            next_file_id,
+            history,
+        }
+    }
+
+    /// Directories captured by this view, parents first
+    pub fn directories(&self) -> &[VfsPath] {
+        &self.directories
+    }
+
+    /// Files in the trash when this view was taken
+    pub fn trash(&self) -> &[TrashedFile] {
+        &self.trash
+    }
+
+    /// ID the next created file would have got when this view was taken
+    pub fn next_file_id(&self) -> u64 {
+        self.next_file_id
+    }
+
+    /// Previous versions of files when this view was taken
+    pub fn history(&self) -> &HashMap<FileId, Vec<FileRevision>> {
+        &self.history
+    }
+
+    /// File IDs captured by this view
+    pub fn file_ids(&self) -> &[FileId] {
+        &self.file_ids
+    }
+
+    /// Number of files captured by this view
+    pub fn len(&self) -> usize {
+        self.file_ids.len()
+    }
+
+    /// Check if the view captured no files
+    pub fn is_empty(&self) -> bool {
+        self.file_ids.is_empty()
+    }
+
+    /// Read a file as it was when the view was taken
+    ///
+    /// Each file should be read once; its pre-image is released afterwards.
+    pub fn read(&self, vfs: &Vfs, file_id: FileId) -> Option<VfsFile> {
+        // Read the live file first: pre-images are stored before the live
+        // copy changes, so a change racing with this read is always caught
+        // by the lookup below.
+        let live = vfs.read_file(file_id);
+        self.pending.remove(&file_id);
+
+        match self.preserved.remove(&file_id) {
+            Some((_, original)) => Some(original.loaded()),
+            None => live,
+        }
+    }
+
+    /// Store the pre-image of a file about to be modified
+    pub(crate) fn preserve(&self, file: &VfsFile) {
+        if self.pending.contains(&file.id) {
+            self.preserved.entry(file.id).or_insert_with(|| file.clone());
+        }
+    }
+}